@@ -0,0 +1,317 @@
+// baselines for performance work on listing and hydration: `cargo bench --bench baseline`.
+// The registry ones work on a scratch key and are skipped where it can't be created
+use criterion::{criterion_group, criterion_main, Criterion};
+use prjfs::sys::PRJ_FILE_BASIC_INFO;
+use std::{
+    ffi::{OsStr, OsString},
+    hint::black_box,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use winapi::shared::winerror::S_OK;
+use winreg::{
+    enums::{HKEY_CURRENT_USER, REG_BINARY},
+    RegKey, RegValue,
+};
+
+use regfs::backend::RegistryBackend;
+use regfs::dirinfo::{DirEntrySink, DirInfo, SinkResult};
+use regfs::memory::MemoryBackend;
+use regfs::opcontext::OpContext;
+use regfs::options::RegFsOptions;
+use regfs::prj_compat::{PrjApi, ProjFsApi};
+use regfs::regfs::RegFs;
+use regfs::regop::{paths::RegPath, Access, RegOps};
+use regfs::search::Search;
+
+// HKEY_CURRENT_USER\Software\regfs-test\bench-<pid>, deleted on drop
+struct ScratchKey {
+    name: String,
+    key: RegKey,
+}
+
+impl ScratchKey {
+    // None where the registry can't be written, and the benchmark is skipped
+    fn create(bench: &str) -> Option<ScratchKey> {
+        let name = format!("Software\\regfs-test\\bench-{}", std::process::id());
+        match RegKey::predef(HKEY_CURRENT_USER).create_subkey(&name) {
+            Ok((key, _)) => Some(ScratchKey { name, key }),
+            Err(e) => {
+                eprintln!("{}: no registry ({}), skipped", bench, e);
+                None
+            }
+        }
+    }
+
+    fn path(&self) -> PathBuf {
+        Path::new("HKEY_CURRENT_USER").join(&self.name)
+    }
+}
+
+impl Drop for ScratchKey {
+    fn drop(&mut self) {
+        let _ = RegKey::predef(HKEY_CURRENT_USER).delete_subkey_all(&self.name);
+    }
+}
+
+fn enumerate_key(c: &mut Criterion) {
+    let scratch = match ScratchKey::create("enumerate_key") {
+        Some(scratch) => scratch,
+        None => return,
+    };
+    for n in 0..10_000u32 {
+        scratch.key.set_value(format!("Value{:05}", n), &n).unwrap();
+    }
+    let ops = RegOps::new();
+    let path: OsString = scratch.path().into();
+
+    c.bench_function("enumerate_key, 10000 values", |b| {
+        b.iter(|| {
+            let entries = ops.enumerate_key(path.clone()).unwrap();
+            assert_eq!(black_box(entries).values.len(), 10_000);
+        })
+    });
+}
+
+fn open_key_by_path(c: &mut Criterion) {
+    let scratch = match ScratchKey::create("open_key_by_path") {
+        Some(scratch) => scratch,
+        None => return,
+    };
+    scratch.key.create_subkey("A\\B\\C\\D").unwrap();
+    let ops = RegOps::new();
+    let deep = RegPath::parse(scratch.path().join("A\\B\\C\\D"));
+
+    // every open walks from the hive down; the handle cache gets its own variant here once
+    // there is one
+    c.bench_function("open_key_by_path, 4 levels", |b| {
+        b.iter(|| {
+            let key = ops.open_key_by_path(&deep, Access::Query, &OpContext::none());
+            assert!(black_box(key).is_some());
+        })
+    });
+}
+
+fn read_value(c: &mut Criterion) {
+    let scratch = match ScratchKey::create("read_value") {
+        Some(scratch) => scratch,
+        None => return,
+    };
+    scratch.key.set_value("Small", &42u32).unwrap();
+    scratch
+        .key
+        .set_raw_value(
+            "Large",
+            &RegValue {
+                vtype: REG_BINARY,
+                bytes: vec![7; 1 << 20],
+            },
+        )
+        .unwrap();
+    let ops = RegOps::new();
+
+    for name in ["Small", "Large"] {
+        let path = scratch.path().join(name);
+        c.bench_function(&format!("read_value, {}", name), |b| {
+            b.iter(|| assert!(black_box(ops.read_value(&path)).is_some()))
+        });
+    }
+}
+
+fn sort_entries(c: &mut Criterion) {
+    let api = ProjFsApi::detect();
+    // not generated in order, like most registry keys
+    let names: Vec<String> = (0..50_000u64)
+        .map(|n| format!("Entry{:08x}", n.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32))
+        .collect();
+
+    c.bench_function("sort_entries_and_mark_filled, 50000 entries", |b| {
+        b.iter(|| {
+            let mut dirinfo = DirInfo::new("HKEY_CURRENT_USER\\Big");
+            for name in &names {
+                dirinfo.fill_file_entry(name.into(), 4, 0);
+            }
+            dirinfo.sort_entries_and_mark_filled(&api);
+            black_box(dirinfo);
+        })
+    });
+}
+
+// ProjFS buffers take a couple hundred entries at a time
+struct Buffer {
+    added: usize,
+}
+
+impl DirEntrySink for Buffer {
+    fn add(&mut self, _: &OsStr, _: &mut PRJ_FILE_BASIC_INFO) -> SinkResult {
+        match self.added {
+            200 => SinkResult::Full,
+            _ => {
+                self.added += 1;
+                SinkResult::Added
+            }
+        }
+    }
+}
+
+fn listing_pipeline(c: &mut Criterion) {
+    let backend = MemoryBackend::new();
+    for n in 0..10_000 {
+        backend.set_value(
+            "HKEY_CURRENT_USER\\Big",
+            format!("Value{:05}", n),
+            4,
+            vec![0; 4],
+        );
+    }
+    let regfs = RegFs::with_backend(&RegFsOptions::default(), Arc::new(backend));
+
+    c.bench_function("populate and fill, 10000 values", |b| {
+        b.iter(|| {
+            let mut dirinfo = DirInfo::new("HKEY_CURRENT_USER\\Big");
+            let populated = regfs.populate_dir_info_for_path(
+                "HKEY_CURRENT_USER\\Big".into(),
+                &mut dirinfo,
+                "*".into(),
+                0,
+            );
+            assert_eq!(populated, Ok(true));
+            dirinfo.sort_entries_and_mark_filled(regfs.projfs());
+
+            let mut listed = 0;
+            loop {
+                let mut buffer = Buffer { added: 0 };
+                assert_eq!(dirinfo.fill(&mut buffer, false, None), S_OK);
+                listed += buffer.added;
+                if buffer.added == 0 {
+                    break;
+                }
+            }
+            assert_eq!(black_box(listed), 10_000);
+        })
+    });
+}
+
+// what populate_dir_info_for_path costs a 2000 subkey key, half of them empty, with and
+// without --hide-empty-keys
+fn hide_empty_keys(c: &mut Criterion) {
+    let backend = Arc::new(MemoryBackend::new());
+    for n in 0..2000 {
+        let key = format!("HKEY_CLASSES_ROOT\\Wide\\Key{:04}", n);
+        match n % 2 {
+            0 => backend.add_key(key),
+            _ => backend.set_value(key, "Value", 4, vec![0; 4]),
+        }
+    }
+    let populate = |regfs: &RegFs| {
+        let mut dirinfo = DirInfo::new("HKEY_CLASSES_ROOT\\Wide");
+        let populated = regfs.populate_dir_info_for_path(
+            "HKEY_CLASSES_ROOT\\Wide".into(),
+            &mut dirinfo,
+            "*".into(),
+            0,
+        );
+        assert_eq!(populated, Ok(true));
+        black_box(dirinfo);
+    };
+
+    let plain = RegFs::with_backend(&RegFsOptions::default(), backend.clone());
+    c.bench_function("hide_empty_keys, 2000 subkeys, off", |b| {
+        b.iter(|| populate(&plain))
+    });
+
+    // one key_info per subkey the first time, then one cache lookup
+    let options = RegFsOptions {
+        hide_empty_keys: true,
+        ..Default::default()
+    };
+    let hiding = RegFs::with_backend(&options, backend);
+    c.bench_function("hide_empty_keys, 2000 subkeys, cold", |b| {
+        b.iter(|| {
+            hiding.forget_empty_keys();
+            populate(&hiding)
+        })
+    });
+    c.bench_function("hide_empty_keys, 2000 subkeys, warm", |b| {
+        b.iter(|| populate(&hiding))
+    });
+}
+
+// a 10000 entry listing as get_dir_enum makes it, with and without --enum-cache
+fn enum_cache(c: &mut Criterion) {
+    let backend = Arc::new(MemoryBackend::new());
+    let big = "HKEY_CURRENT_USER\\Big";
+    for n in 0..5_000u64 {
+        let name = format!("Entry{:08x}", n.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32);
+        backend.add_key(format!("{}\\{}", big, name));
+        backend.set_value(big, name, 4, vec![0; 4]);
+    }
+    let list = |regfs: &RegFs| {
+        let mut dirinfo = DirInfo::new(big);
+        let populated = regfs.populate_dir_info_for_path(big.into(), &mut dirinfo, "*".into(), 0);
+        assert_eq!(populated, Ok(true));
+        dirinfo.sort_entries_and_mark_filled(regfs.projfs());
+        regfs.keep_listing(big.as_ref(), &dirinfo, OsStr::new("*"));
+        black_box(dirinfo);
+    };
+
+    let plain = RegFs::with_backend(&RegFsOptions::default(), backend.clone());
+    c.bench_function("enum_cache, 10000 entries, off", |b| {
+        b.iter(|| list(&plain))
+    });
+
+    let options = RegFsOptions {
+        enum_cache: true,
+        ..Default::default()
+    };
+    let caching = RegFs::with_backend(&options, backend);
+    c.bench_function("enum_cache, 10000 entries, cold", |b| {
+        b.iter(|| {
+            caching.forget_written(None);
+            list(&caching)
+        })
+    });
+    c.bench_function("enum_cache, 10000 entries, warm", |b| {
+        b.iter(|| list(&caching))
+    });
+}
+
+// what a search expression costs per entry: PrjFileNameMatch on each name, or the fast path
+// Search takes for `*` and literal names
+fn search(c: &mut Criterion) {
+    let api = ProjFsApi::detect();
+    let names: Vec<OsString> = (0..20_000)
+        .map(|n| OsString::from(format!("Value{:05}", n)))
+        .collect();
+    let expression: OsString = "*".into();
+
+    c.bench_function("search, 20000 entries, PrjFileNameMatch", |b| {
+        b.iter(|| {
+            let matched = names
+                .iter()
+                .filter(|name| api.file_name_match(name, &expression))
+                .count();
+            assert_eq!(black_box(matched), names.len());
+        })
+    });
+    c.bench_function("search, 20000 entries, fast path", |b| {
+        b.iter(|| {
+            let search = Search::new(&api, &expression);
+            let matched = names.iter().filter(|name| search.matches(name)).count();
+            assert_eq!(black_box(matched), names.len());
+        })
+    });
+}
+
+criterion_group!(
+    benches,
+    enumerate_key,
+    open_key_by_path,
+    read_value,
+    sort_entries,
+    listing_pipeline,
+    hide_empty_keys,
+    enum_cache,
+    search
+);
+criterion_main!(benches);
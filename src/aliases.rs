@@ -0,0 +1,310 @@
+use anyhow::{anyhow, Error, Result};
+use std::{
+    borrow::Cow,
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use crate::backend::{PathKind, RegistryBackend};
+use crate::bookmarks::Bookmark;
+use crate::opcontext::{Cancelled, OpContext};
+use crate::regop::{paths, KeyInfo, RegEntires, RootHive};
+use crate::synthetic;
+use crate::watch::Watchers;
+
+// --hive-alias <hive>=<name>: the hive's directory at the root goes by that name instead
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HiveAlias {
+    pub hive: RootHive,
+    pub name: String,
+}
+
+impl FromStr for HiveAlias {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let (hive, name) = text
+            .split_once('=')
+            .ok_or_else(|| anyhow!("invalid hive alias [{}], expected <hive>=<name>", text))?;
+        let hive = hive.trim().parse::<RootHive>()?;
+        let name = name.trim();
+        if name.is_empty() || name.contains(|c| c == '\\' || c == '/') {
+            return Err(anyhow!("invalid hive alias [{}]", name));
+        }
+        // another hive's name, short or long, or one of our own directories
+        let taken = name.parse::<RootHive>().is_ok()
+            || name.to_ascii_uppercase().starts_with("HKEY_")
+            || synthetic::root_entries()
+                .iter()
+                .any(|entry| entry.eq_ignore_ascii_case(name));
+        if taken {
+            return Err(anyhow!(
+                "hive alias [{}] would hide a root of the mount",
+                name
+            ));
+        }
+
+        Ok(HiveAlias {
+            hive,
+            name: name.to_string(),
+        })
+    }
+}
+
+// at startup: one alias per hive, and no two roots by the same name
+pub fn validate(aliases: &[HiveAlias], bookmarks: &[Bookmark]) -> Result<()> {
+    for (index, alias) in aliases.iter().enumerate() {
+        let earlier = &aliases[..index];
+        if earlier.iter().any(|earlier| earlier.hive == alias.hive) {
+            return Err(anyhow!(
+                "{} is given more than one alias",
+                alias.hive.name()
+            ));
+        }
+        if earlier
+            .iter()
+            .any(|earlier| earlier.name.eq_ignore_ascii_case(&alias.name))
+        {
+            return Err(anyhow!(
+                "hive alias [{}] is used for more than one hive",
+                alias.name
+            ));
+        }
+        if bookmarks
+            .iter()
+            .any(|bookmark| bookmark.name.eq_ignore_ascii_case(&alias.name))
+        {
+            return Err(anyhow!(
+                "hive alias [{}] is also a bookmark's name",
+                alias.name
+            ));
+        }
+    }
+    Ok(())
+}
+
+// the hives listed at the root by their aliases; lookups take either name, and whatever is
+// below only ever sees the hives' own
+pub struct HiveAliases {
+    inner: Arc<dyn RegistryBackend>,
+    aliases: Vec<HiveAlias>,
+}
+
+impl HiveAliases {
+    pub fn new(inner: Arc<dyn RegistryBackend>, aliases: &[HiveAlias]) -> Self {
+        HiveAliases {
+            inner,
+            aliases: aliases.to_vec(),
+        }
+    }
+
+    // the registry path behind a path under an alias
+    pub fn canonical(&self, path: &Path) -> Option<PathBuf> {
+        let parts = paths::components(path);
+        let (first, rest) = parts.split_first()?;
+        let alias = self
+            .aliases
+            .iter()
+            .find(|alias| first.eq_ignore_ascii_case(&alias.name))?;
+        Some(join(alias.hive.name(), rest))
+    }
+
+    // and back, for a change the watchers report by the hive's name
+    pub fn aliased(&self, path: &Path) -> Option<PathBuf> {
+        let parts = paths::components(path);
+        let (first, rest) = parts.split_first()?;
+        let hive = first.to_str()?.parse::<RootHive>().ok()?;
+        let alias = self.aliases.iter().find(|alias| alias.hive == hive)?;
+        Some(join(&alias.name, rest))
+    }
+
+    fn resolved<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        match self.canonical(path) {
+            Some(canonical) => canonical.into(),
+            None => path.into(),
+        }
+    }
+}
+
+fn join(root: &str, rest: &[OsString]) -> PathBuf {
+    paths::join_parts(
+        [OsStr::new(root)]
+            .into_iter()
+            .chain(rest.iter().map(OsString::as_os_str)),
+    )
+}
+
+impl RegistryBackend for HiveAliases {
+    fn enumerate_key_ctx(
+        &self,
+        path: OsString,
+        ctx: &OpContext,
+    ) -> Result<Option<RegEntires>, Cancelled> {
+        if let Some(canonical) = self.canonical(path.as_ref()) {
+            return self.inner.enumerate_key_ctx(canonical.into(), ctx);
+        }
+
+        let mut entries = self.inner.enumerate_key_ctx(path.clone(), ctx)?;
+        if let (true, Some(entries)) = (paths::components(&path).is_empty(), &mut entries) {
+            for subkey in &mut entries.subkeys {
+                let alias = self
+                    .aliases
+                    .iter()
+                    .find(|alias| subkey.name.eq_ignore_ascii_case(alias.hive.name()));
+                if let Some(alias) = alias {
+                    subkey.name = alias.name.clone().into();
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    fn read_typed_value_ctx(
+        &self,
+        path: &Path,
+        ctx: &OpContext,
+    ) -> Result<Option<(u32, Vec<u8>)>, Cancelled> {
+        self.inner.read_typed_value_ctx(&self.resolved(path), ctx)
+    }
+
+    fn read_value_ctx(&self, path: &Path, ctx: &OpContext) -> Result<Option<Vec<u8>>, Cancelled> {
+        self.inner.read_value_ctx(&self.resolved(path), ctx)
+    }
+
+    fn read_all_values(&self, path: &Path) -> Option<Vec<(OsString, u32, Vec<u8>)>> {
+        self.inner.read_all_values(&self.resolved(path))
+    }
+
+    fn does_key_exist(&self, path: &Path) -> bool {
+        self.inner.does_key_exist(&self.resolved(path))
+    }
+
+    fn key_last_write_time(&self, path: &Path) -> Option<i64> {
+        self.inner.key_last_write_time(&self.resolved(path))
+    }
+
+    fn key_info(&self, path: &Path) -> Option<KeyInfo> {
+        self.inner.key_info(&self.resolved(path))
+    }
+
+    fn watchers(&self) -> Option<&Watchers> {
+        self.inner.watchers()
+    }
+
+    // walks go down the mount, where the target's hive is listed by its alias
+    fn link_target(&self, path: &Path) -> Option<PathBuf> {
+        let target = self.inner.link_target(&self.resolved(path))?;
+        Some(self.aliased(&target).unwrap_or(target))
+    }
+
+    fn writable(&self) -> bool {
+        self.inner.writable()
+    }
+
+    fn value_type(&self, path: &Path) -> Option<u32> {
+        self.inner.value_type(&self.resolved(path))
+    }
+
+    fn does_value_exist(&self, path: &Path) -> Option<usize> {
+        self.inner.does_value_exist(&self.resolved(path))
+    }
+
+    fn classify(&self, path: &Path, ctx: &OpContext) -> Result<PathKind, Cancelled> {
+        self.inner.classify(&self.resolved(path), ctx)
+    }
+}
+
+#[test]
+fn test_parse() {
+    let alias: HiveAlias = "HKLM=machine".parse().unwrap();
+    assert_eq!(alias.hive, RootHive::LocalMachine);
+    assert_eq!(alias.name, "machine");
+    let alias: HiveAlias = "hkey_current_user = user".parse().unwrap();
+    assert_eq!(alias.hive, RootHive::CurrentUser);
+    assert_eq!(alias.name, "user");
+
+    assert!("HKLM=HKCU".parse::<HiveAlias>().is_err());
+    assert!("HKLM=hkey_machine".parse::<HiveAlias>().is_err());
+    assert!("HKLM=.regfs".parse::<HiveAlias>().is_err());
+    assert!("HKLM=a\\b".parse::<HiveAlias>().is_err());
+    assert!("HKLM=".parse::<HiveAlias>().is_err());
+    assert!("HKXX=machine".parse::<HiveAlias>().is_err());
+    assert!("machine".parse::<HiveAlias>().is_err());
+}
+
+#[test]
+fn test_validate() {
+    let machine: HiveAlias = "HKLM=machine".parse().unwrap();
+    let user: HiveAlias = "HKCU=user".parse().unwrap();
+    assert!(validate(&[machine.clone(), user.clone()], &[]).is_ok());
+
+    let twice: HiveAlias = "HKLM=computer".parse().unwrap();
+    assert!(validate(&[machine.clone(), twice], &[]).is_err());
+    let shared: HiveAlias = "HKU=MACHINE".parse().unwrap();
+    assert!(validate(&[machine.clone(), shared], &[]).is_err());
+    let bookmark: Bookmark = "User=HKCU\\Environment".parse().unwrap();
+    assert!(validate(&[machine, user], &[bookmark]).is_err());
+}
+
+#[test]
+fn test_aliased_roots() {
+    use crate::memory::MemoryBackend;
+
+    let memory = Arc::new(MemoryBackend::new());
+    memory.set_value("HKEY_LOCAL_MACHINE\\SOFTWARE", "Version", 1, b"1".to_vec());
+    memory.set_value(
+        "HKEY_CURRENT_USER\\Environment",
+        "Path",
+        1,
+        b"C:\\".to_vec(),
+    );
+    let aliases = HiveAliases::new(
+        memory,
+        &[
+            "HKLM=machine".parse().unwrap(),
+            "HKCR=classes".parse().unwrap(),
+        ],
+    );
+
+    assert_eq!(
+        aliases.canonical("Machine\\SOFTWARE".as_ref()),
+        Some("HKEY_LOCAL_MACHINE\\SOFTWARE".into())
+    );
+    assert_eq!(aliases.canonical("HKEY_LOCAL_MACHINE".as_ref()), None);
+    assert_eq!(
+        aliases.aliased("HKEY_LOCAL_MACHINE\\SOFTWARE".as_ref()),
+        Some("machine\\SOFTWARE".into())
+    );
+    assert_eq!(aliases.aliased("HKEY_CURRENT_USER".as_ref()), None);
+
+    // only the alias is listed, the hives without one keep their names
+    let root = aliases.enumerate_key("".into()).unwrap();
+    let names: Vec<_> = root.subkeys.iter().map(|key| key.name.clone()).collect();
+    assert!(names.contains(&"machine".into()));
+    assert!(names.contains(&"HKEY_CURRENT_USER".into()));
+    assert!(!names.contains(&"HKEY_LOCAL_MACHINE".into()));
+
+    // either name finds what's under it
+    for path in [
+        "machine\\SOFTWARE\\Version",
+        "HKEY_LOCAL_MACHINE\\SOFTWARE\\Version",
+    ] {
+        assert_eq!(aliases.read_value(path.as_ref()), Some(b"1".to_vec()));
+    }
+    assert!(aliases.does_key_exist("MACHINE".as_ref()));
+    assert!(aliases.does_key_exist("HKEY_LOCAL_MACHINE\\SOFTWARE".as_ref()));
+    assert_eq!(
+        aliases
+            .enumerate_key("machine\\SOFTWARE".into())
+            .unwrap()
+            .values
+            .len(),
+        1
+    );
+    assert!(matches!(
+        aliases.classify("machine\\SOFTWARE\\Version".as_ref(), &OpContext::none()),
+        Ok(PathKind::Value)
+    ));
+}
@@ -0,0 +1,373 @@
+use anyhow::{Context, Result};
+use log::warn;
+use serde_json::{json, Value};
+use std::{
+    fs::{self, File},
+    io::{LineWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, SyncSender},
+    },
+    thread::{self, JoinHandle},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::events::RegFsEvent;
+use crate::observer::ProviderObserver;
+
+// events waiting for the writer; past this they are dropped and counted like any other
+const QUEUE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuditLimits {
+    // the active file is rotated before it grows past this
+    pub max_size: u64,
+    // rotated files kept next to the active one
+    pub max_files: usize,
+}
+
+impl Default for AuditLimits {
+    fn default() -> Self {
+        AuditLimits {
+            max_size: 10 * 1024 * 1024,
+            max_files: 5,
+        }
+    }
+}
+
+fn now() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+// what changed the registry through the mount, or was refused; the rest isn't audited
+fn record(event: &RegFsEvent) -> Option<Value> {
+    if let RegFsEvent::KeyCopied {
+        path,
+        destination,
+        keys,
+        values,
+        bytes,
+    } = event
+    {
+        return Some(json!({
+            "time": now() as u64,
+            "event": "copy_key",
+            "path": path.to_string_lossy(),
+            "destination": destination.to_string_lossy(),
+            "keys": keys,
+            "values": values,
+            "bytes": bytes,
+        }));
+    }
+    let (kind, path, reason, link) = match event {
+        RegFsEvent::WriteBackApplied { path } => ("write_back", path, None, None),
+        RegFsEvent::OperationDenied { path, reason } => ("denied", path, Some(reason), None),
+        RegFsEvent::HardlinkCreated { path, link } => ("hardlink", path, None, Some(link)),
+        _ => return None,
+    };
+    let mut record = json!({ "time": now() as u64, "event": kind, "path": path.to_string_lossy() });
+    if let Some(reason) = reason {
+        record["reason"] = json!(reason);
+    }
+    if let Some(link) = link {
+        record["link"] = json!(link.to_string_lossy());
+    }
+    Some(record)
+}
+
+// --audit-log as it hears about the mount: only what gets audited is queued for the writer
+pub struct AuditObserver {
+    events: SyncSender<RegFsEvent>,
+    dropped: AtomicBool,
+}
+
+impl AuditObserver {
+    pub fn new(events: SyncSender<RegFsEvent>) -> Self {
+        AuditObserver {
+            events,
+            dropped: AtomicBool::new(false),
+        }
+    }
+}
+
+impl ProviderObserver for AuditObserver {
+    fn on_event(&self, event: &RegFsEvent) {
+        let audited = matches!(
+            event,
+            RegFsEvent::WriteBackApplied { .. }
+                | RegFsEvent::OperationDenied { .. }
+                | RegFsEvent::HardlinkCreated { .. }
+                | RegFsEvent::KeyCopied { .. }
+        );
+        if audited
+            && self.events.try_send(event.clone()).is_err()
+            && !self.dropped.swap(true, Ordering::Relaxed)
+        {
+            warn!(target: "audit", "the writer can't keep up, records are being dropped");
+        }
+    }
+
+    fn observes_callbacks(&self) -> bool {
+        false
+    }
+}
+
+// --audit-log: one JSON line per audited event, written by a single thread so rotating the
+// file never splits or mixes records
+pub struct AuditWriter {
+    path: PathBuf,
+    limits: AuditLimits,
+    out: LineWriter<File>,
+    size: u64,
+}
+
+impl AuditWriter {
+    pub fn create(path: &Path, limits: AuditLimits) -> Result<Self> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("unable to open {:?}", path))?;
+        let size = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+
+        Ok(AuditWriter {
+            path: path.to_path_buf(),
+            limits,
+            out: LineWriter::new(file),
+            size,
+        })
+    }
+
+    pub fn spawn(mut self) -> (SyncSender<RegFsEvent>, JoinHandle<()>) {
+        let (sender, receiver): (_, Receiver<RegFsEvent>) = mpsc::sync_channel(QUEUE);
+        let writer = thread::spawn(move || {
+            for event in receiver {
+                if let Some(record) = record(&event) {
+                    self.write(&record);
+                }
+            }
+        });
+        (sender, writer)
+    }
+
+    pub fn write(&mut self, record: &Value) {
+        let line = format!("{}\n", record);
+        // a record bigger than the limit still gets a file of its own
+        if self.size > 0 && self.size + line.len() as u64 > self.limits.max_size {
+            if let Err(e) = self.rotate() {
+                warn!(target: "audit", "unable to rotate {:?}: {}", self.path, e);
+            }
+        }
+
+        match self.out.write_all(line.as_bytes()) {
+            Ok(()) => self.size += line.len() as u64,
+            Err(e) => warn!(target: "audit", "unable to write to {:?}: {}", self.path, e),
+        }
+    }
+
+    // audit.jsonl becomes audit.<milliseconds>.jsonl and a new audit.jsonl starts with a
+    // record of it
+    fn rotate(&mut self) -> Result<()> {
+        self.out.flush()?;
+        let stamp = now();
+        // after whatever was rotated within the same millisecond
+        let n = self
+            .rotated_files()
+            .iter()
+            .filter(|((millis, _), _)| *millis == stamp)
+            .map(|((_, n), _)| n + 1)
+            .max()
+            .unwrap_or(0);
+        let rotated = self.rotated_name(stamp, n);
+        fs::rename(&self.path, &rotated)
+            .with_context(|| format!("unable to rename it to {:?}", rotated))?;
+
+        *self = AuditWriter::create(&self.path, self.limits)?;
+        self.prune();
+        let rotation = json!({
+            "time": stamp as u64,
+            "event": "rotated",
+            "previous": rotated.file_name().map(|name| name.to_string_lossy().into_owned()),
+        });
+        self.write(&rotation);
+        Ok(())
+    }
+
+    fn rotated_name(&self, stamp: u128, n: usize) -> PathBuf {
+        let stem = self.path.file_stem().unwrap_or_default().to_string_lossy();
+        let stamp = match n {
+            0 => stamp.to_string(),
+            n => format!("{}-{}", stamp, n),
+        };
+        let name = match self.path.extension() {
+            Some(extension) => format!("{}.{}.{}", stem, stamp, extension.to_string_lossy()),
+            None => format!("{}.{}", stem, stamp),
+        };
+        self.path.with_file_name(name)
+    }
+
+    // oldest first
+    pub fn rotated(&self) -> Vec<PathBuf> {
+        self.rotated_files()
+            .into_iter()
+            .map(|(_, path)| path)
+            .collect()
+    }
+
+    fn rotated_files(&self) -> Vec<((u128, usize), PathBuf)> {
+        let stem = self.path.file_stem().unwrap_or_default().to_string_lossy();
+        let prefix = format!("{}.", stem);
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+
+        let mut rotated: Vec<((u128, usize), PathBuf)> = fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let stamp = name.strip_prefix(&prefix)?.split('.').next()?;
+                let (millis, n) = stamp.split_once('-').unwrap_or((stamp, "0"));
+                let order = (millis.parse().ok()?, n.parse().ok()?);
+                Some((order, entry.path()))
+            })
+            .collect();
+        rotated.sort();
+        rotated
+    }
+
+    fn prune(&self) {
+        let rotated = self.rotated();
+        let excess = rotated.len().saturating_sub(self.limits.max_files);
+        for old in &rotated[..excess] {
+            if let Err(e) = fs::remove_file(old) {
+                warn!(target: "audit", "unable to delete {:?}: {}", old, e);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_audit_rotation() {
+    let dir = std::env::temp_dir().join(format!("regfs-audit-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("audit.jsonl");
+    let limits = AuditLimits {
+        max_size: 1024,
+        max_files: 3,
+    };
+
+    let (events, writer) = AuditWriter::create(&path, limits).unwrap().spawn();
+    for n in 0..200 {
+        let path = PathBuf::from(format!("HKEY_CURRENT_USER\\Software\\App\\Value{}", n));
+        let event = match n % 2 {
+            0 => RegFsEvent::WriteBackApplied { path },
+            _ => RegFsEvent::OperationDenied {
+                path,
+                reason: "read-only".into(),
+            },
+        };
+        events.send(event).unwrap();
+        // not audited
+        events
+            .send(RegFsEvent::EnumerationStarted { path: "x".into() })
+            .unwrap();
+    }
+    drop(events);
+    writer.join().unwrap();
+
+    let audit = AuditWriter::create(&path, limits).unwrap();
+    let rotated = audit.rotated();
+    assert_eq!(rotated.len(), 3);
+
+    let lines = |file: &Path| -> Vec<Value> {
+        fs::read_to_string(file)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    };
+    let mut seen = Vec::new();
+    let mut previous: Option<String> = None;
+    for file in rotated.iter().chain([&path]) {
+        assert!(fs::metadata(file).unwrap().len() <= limits.max_size);
+        let records = lines(file);
+        // every file but the oldest one kept starts with the rotation that made it
+        if let Some(previous) = &previous {
+            assert_eq!(records[0]["event"], "rotated");
+            assert_eq!(records[0]["previous"], previous.as_str());
+        }
+        previous = Some(file.file_name().unwrap().to_string_lossy().into_owned());
+
+        for record in records.iter().filter(|record| record["event"] != "rotated") {
+            let path = record["path"].as_str().unwrap();
+            seen.push(
+                path.rsplit("Value")
+                    .next()
+                    .unwrap()
+                    .parse::<usize>()
+                    .unwrap(),
+            );
+        }
+    }
+    // the newest records, in order, none missing; the oldest went with the deleted files
+    assert!(seen.len() > 20);
+    assert_eq!(seen, (200 - seen.len()..200).collect::<Vec<_>>());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_hardlink_record() {
+    let record = record(&RegFsEvent::HardlinkCreated {
+        path: "HKEY_CURRENT_USER\\Console\\FontSize.dword".into(),
+        link: "HKEY_CURRENT_USER\\Console\\Alias.dword".into(),
+    })
+    .unwrap();
+    assert_eq!(record["event"], "hardlink");
+    assert_eq!(record["path"], "HKEY_CURRENT_USER\\Console\\FontSize.dword");
+    assert_eq!(record["link"], "HKEY_CURRENT_USER\\Console\\Alias.dword");
+}
+
+#[test]
+fn test_copy_record() {
+    let record = record(&RegFsEvent::KeyCopied {
+        path: "HKEY_CURRENT_USER\\Software\\App\\Default".into(),
+        destination: "HKEY_CURRENT_USER\\Software\\App\\Copy".into(),
+        keys: 3,
+        values: 2,
+        bytes: 8,
+    })
+    .unwrap();
+    assert_eq!(record["event"], "copy_key");
+    assert_eq!(
+        record["destination"],
+        "HKEY_CURRENT_USER\\Software\\App\\Copy"
+    );
+    assert_eq!(record["keys"], 3);
+}
+
+#[test]
+fn test_audit_observer_queues_audited_events() {
+    let (events, queued) = mpsc::sync_channel(1);
+    let audit = AuditObserver::new(events);
+    let denied = RegFsEvent::OperationDenied {
+        path: "HKEY_CURRENT_USER\\Console".into(),
+        reason: "read-only".into(),
+    };
+
+    audit.on_event(&RegFsEvent::EnumerationStarted {
+        path: "HKEY_CURRENT_USER".into(),
+    });
+    audit.on_event(&denied);
+    // past the queue it's dropped, never blocks the callback
+    audit.on_event(&denied);
+    assert_eq!(queued.try_iter().collect::<Vec<_>>(), vec![denied]);
+    assert!(audit.dropped.load(Ordering::Relaxed));
+}
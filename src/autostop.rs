@@ -0,0 +1,196 @@
+use log::info;
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::eventlog;
+use crate::regfs::RegFs;
+
+// --timeout and --idle-timeout, for scripts that mount, copy a few files out and leave the
+// provider to go away on its own
+pub struct AutoStop {
+    timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    base: Instant,
+    // both in milliseconds since `base`, so a callback only stores a number
+    armed: AtomicU64,
+    last_activity: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Timeout(Duration),
+    Idle(Duration),
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StopReason::Timeout(timeout) => write!(f, "--timeout of {:?} is up", timeout),
+            StopReason::Idle(idle) => write!(f, "no callbacks for {:?} (--idle-timeout)", idle),
+        }
+    }
+}
+
+impl AutoStop {
+    pub fn new(timeout: Option<Duration>, idle_timeout: Option<Duration>) -> Self {
+        AutoStop {
+            timeout,
+            idle_timeout,
+            base: Instant::now(),
+            armed: AtomicU64::new(0),
+            last_activity: AtomicU64::new(0),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.timeout.is_some() || self.idle_timeout.is_some()
+    }
+
+    fn millis(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.base).as_millis() as u64
+    }
+
+    // both timers count from here, once the root is mounted
+    fn arm(&self, now: Instant) {
+        self.armed.store(self.millis(now), Ordering::Relaxed);
+    }
+
+    // from every callback, so only when something is listening
+    pub fn touch(&self) {
+        if self.idle_timeout.is_some() {
+            self.touch_at(Instant::now());
+        }
+    }
+
+    fn touch_at(&self, now: Instant) {
+        self.last_activity
+            .fetch_max(self.millis(now), Ordering::Relaxed);
+    }
+
+    // why the provider should stop by `now`, or else how long until it's worth asking again
+    fn check(&self, now: Instant) -> Result<Duration, StopReason> {
+        let now = self.millis(now);
+        let armed = self.armed.load(Ordering::Relaxed);
+        let mut wait = u64::MAX;
+
+        if let Some(timeout) = self.timeout {
+            match (armed + timeout.as_millis() as u64).saturating_sub(now) {
+                0 => return Err(StopReason::Timeout(timeout)),
+                left => wait = wait.min(left),
+            }
+        }
+        if let Some(idle) = self.idle_timeout {
+            let since = armed.max(self.last_activity.load(Ordering::Relaxed));
+            match (since + idle.as_millis() as u64).saturating_sub(now) {
+                0 => return Err(StopReason::Idle(idle)),
+                left => wait = wait.min(left),
+            }
+        }
+        Ok(Duration::from_millis(wait))
+    }
+}
+
+impl RegFs {
+    // after a successful mount; the stop itself is the same as `quit`
+    pub fn spawn_auto_stop(&self) {
+        if !self.auto_stop().enabled() {
+            return;
+        }
+        self.auto_stop().arm(Instant::now());
+        let regfs = self.clone();
+
+        thread::spawn(move || loop {
+            match regfs.auto_stop().check(Instant::now()) {
+                Ok(wait) => thread::sleep(wait),
+                Err(reason) => {
+                    info!(target: eventlog::LIFECYCLE, "stopping the provider: {}", reason);
+                    regfs.request_quit();
+                    return;
+                }
+            }
+        });
+    }
+}
+
+#[test]
+fn test_timeout() {
+    let stop = AutoStop::new(Some(Duration::from_secs(60)), None);
+    let start = stop.base + Duration::from_secs(5);
+    stop.arm(start);
+
+    assert_eq!(stop.check(start), Ok(Duration::from_secs(60)));
+    // activity doesn't put it off
+    stop.touch_at(start + Duration::from_secs(50));
+    assert_eq!(
+        stop.check(start + Duration::from_secs(50)),
+        Ok(Duration::from_secs(10))
+    );
+    assert_eq!(
+        stop.check(start + Duration::from_secs(60)),
+        Err(StopReason::Timeout(Duration::from_secs(60)))
+    );
+    assert!(!AutoStop::new(None, None).enabled());
+}
+
+#[test]
+fn test_idle_timeout() {
+    let idle = Duration::from_secs(10);
+    let stop = AutoStop::new(None, Some(idle));
+    let start = stop.base + Duration::from_secs(5);
+    stop.arm(start);
+
+    // quiet since the mount
+    assert_eq!(
+        stop.check(start + Duration::from_secs(4)),
+        Ok(Duration::from_secs(6))
+    );
+    // every callback starts it over
+    stop.touch_at(start + Duration::from_secs(8));
+    assert_eq!(
+        stop.check(start + Duration::from_secs(12)),
+        Ok(Duration::from_secs(6))
+    );
+    // a callback that finished late doesn't move it back
+    stop.touch_at(start + Duration::from_secs(1));
+    assert_eq!(
+        stop.check(start + Duration::from_secs(17)),
+        Ok(Duration::from_secs(1))
+    );
+    assert_eq!(
+        stop.check(start + Duration::from_secs(18)),
+        Err(StopReason::Idle(idle))
+    );
+
+    // whichever comes first
+    let stop = AutoStop::new(Some(Duration::from_secs(30)), Some(idle));
+    stop.arm(start);
+    stop.touch_at(start + Duration::from_secs(25));
+    assert_eq!(
+        stop.check(start + Duration::from_secs(26)),
+        Ok(Duration::from_secs(4))
+    );
+    assert_eq!(
+        stop.check(start + Duration::from_secs(30)),
+        Err(StopReason::Timeout(Duration::from_secs(30)))
+    );
+}
+
+#[test]
+fn test_spawn_auto_stop() {
+    use crate::memory::MemoryBackend;
+    use crate::options::RegFsOptions;
+    use std::sync::Arc;
+
+    let options = RegFsOptions {
+        idle_timeout: Some(Duration::from_millis(20)),
+        ..Default::default()
+    };
+    let regfs = RegFs::with_backend(&options, Arc::new(MemoryBackend::new()));
+    regfs.spawn_auto_stop();
+    // returns once the timer asks the provider to quit
+    regfs.wait_for_quit();
+}
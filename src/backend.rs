@@ -0,0 +1,130 @@
+use std::{
+    ffi::OsString,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use crate::opcontext::{Cancelled, OpContext};
+use crate::regop::{paths::RegPath, KeyInfo, RegEntires};
+use crate::watch::Watchers;
+
+// what a path names, for the callbacks that only make sense on a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathKind {
+    // along with its last write time
+    Key(i64),
+    Value,
+    Missing,
+    // a key that's there but can't be opened
+    Denied,
+}
+
+// everything RegFs needs from a registry; RegOps is the live implementation
+pub trait RegistryBackend: Send + Sync {
+    fn enumerate_key_ctx(
+        &self,
+        path: OsString,
+        ctx: &OpContext,
+    ) -> Result<Option<RegEntires>, Cancelled>;
+
+    // the value's type along with its data
+    fn read_typed_value_ctx(
+        &self,
+        path: &Path,
+        ctx: &OpContext,
+    ) -> Result<Option<(u32, Vec<u8>)>, Cancelled>;
+
+    fn read_value_ctx(&self, path: &Path, ctx: &OpContext) -> Result<Option<Vec<u8>>, Cancelled> {
+        Ok(self
+            .read_typed_value_ctx(path, ctx)?
+            .map(|(_, bytes)| bytes))
+    }
+
+    fn enumerate_key(&self, path: OsString) -> Option<RegEntires> {
+        self.enumerate_key_ctx(path, &OpContext::none())
+            .ok()
+            .flatten()
+    }
+
+    fn read_value(&self, path: &Path) -> Option<Vec<u8>> {
+        self.read_value_ctx(path, &OpContext::none()).ok().flatten()
+    }
+
+    fn read_all_values(&self, path: &Path) -> Option<Vec<(OsString, u32, Vec<u8>)>>;
+
+    fn does_key_exist(&self, path: &Path) -> bool;
+
+    // FILETIME of the key's last write, None when the key doesn't exist
+    fn key_last_write_time(&self, path: &Path) -> Option<i64> {
+        self.does_key_exist(path).then_some(0)
+    }
+
+    fn key_info(&self, path: &Path) -> Option<KeyInfo> {
+        let last_write_time = self.key_last_write_time(path)?;
+        let entries = self.enumerate_key(path.into())?;
+
+        Some(KeyInfo {
+            subkeys: entries.subkeys.len() as u32,
+            values: entries.values.len() as u32,
+            last_write_time,
+            ..Default::default()
+        })
+    }
+
+    // backends that can tell when the registry changes under them
+    fn watchers(&self) -> Option<&Watchers> {
+        None
+    }
+
+    // false for backends serving a fixed copy, nothing may change through their mount
+    fn writable(&self) -> bool {
+        true
+    }
+
+    fn value_type(&self, path: &Path) -> Option<u32> {
+        self.read_typed_value_ctx(path, &OpContext::none())
+            .ok()
+            .flatten()
+            .map(|(vtype, _)| vtype)
+    }
+
+    fn does_value_exist(&self, path: &Path) -> Option<usize> {
+        self.read_value(path).map(|bytes| bytes.len())
+    }
+
+    // where a registry symbolic link key points; None for every other key
+    fn link_target(&self, _path: &Path) -> Option<PathBuf> {
+        None
+    }
+
+    // backends that can tell a key they can't open from a missing one say Denied
+    fn classify(&self, path: &Path, ctx: &OpContext) -> Result<PathKind, Cancelled> {
+        classify_by_lookup(self, path, ctx)
+    }
+}
+
+// a key if it has a last write time, or else a value if it can be read; the root of the
+// mount is a key too
+pub fn classify_by_lookup<B: RegistryBackend + ?Sized>(
+    backend: &B,
+    path: &Path,
+    ctx: &OpContext,
+) -> Result<PathKind, Cancelled> {
+    if RegPath::parse(path).is_root() {
+        return Ok(PathKind::Key(0));
+    }
+    if let Some(time) = backend.key_last_write_time(path) {
+        return Ok(PathKind::Key(time));
+    }
+    Ok(match backend.read_typed_value_ctx(path, ctx)? {
+        Some(_) => PathKind::Value,
+        None => PathKind::Missing,
+    })
+}
+
+// so options holding a shared backend can still be printed
+impl fmt::Debug for dyn RegistryBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RegistryBackend")
+    }
+}
@@ -0,0 +1,357 @@
+use anyhow::{anyhow, Error, Result};
+use log::warn;
+use std::{
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use crate::backend::{PathKind, RegistryBackend};
+use crate::opcontext::{Cancelled, OpContext};
+use crate::options::parse_trace_path;
+use crate::regop::{paths, KeyInfo, RegEntires, RegEntry, RootHive};
+use crate::synthetic;
+use crate::virtualstore::strip;
+use crate::watch::Watchers;
+
+// --bookmark <name>=<key>: a directory at the root that is that key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bookmark {
+    pub name: String,
+    pub target: PathBuf,
+}
+
+impl FromStr for Bookmark {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let (name, target) = text
+            .split_once('=')
+            .ok_or_else(|| anyhow!("invalid bookmark [{}], expected <name>=<key>", text))?;
+        let name = name.trim();
+        if name.is_empty() || name.contains(|c| c == '\\' || c == '/') {
+            return Err(anyhow!("invalid bookmark name [{}]", name));
+        }
+        // the hives, their short names, --user-classes's root and our own directories
+        let taken = name.parse::<RootHive>().is_ok()
+            || name.to_ascii_uppercase().starts_with("HKEY_")
+            || synthetic::root_entries()
+                .iter()
+                .any(|entry| entry.eq_ignore_ascii_case(name));
+        if taken {
+            return Err(anyhow!(
+                "bookmark [{}] would hide a root of the mount",
+                name
+            ));
+        }
+
+        let target = parse_trace_path(target.trim())
+            .map_err(|_| anyhow!("invalid bookmark [{}], expected a key", text))?;
+        Ok(Bookmark {
+            name: name.to_string(),
+            target,
+        })
+    }
+}
+
+// at mount time: every name once, and a warning for targets that aren't there (yet)
+pub fn validate(bookmarks: &[Bookmark], backend: &dyn RegistryBackend) -> Result<()> {
+    for (index, bookmark) in bookmarks.iter().enumerate() {
+        if bookmarks[..index]
+            .iter()
+            .any(|earlier| earlier.name.eq_ignore_ascii_case(&bookmark.name))
+        {
+            return Err(anyhow!(
+                "bookmark [{}] is defined more than once",
+                bookmark.name
+            ));
+        }
+        if !backend.does_key_exist(&bookmark.target) {
+            warn!(
+                "bookmark [{}]: [{:?}] isn't a key, the bookmark is empty until it is",
+                bookmark.name, bookmark.target
+            );
+        }
+    }
+    Ok(())
+}
+
+// where ProjFS has symlinks, a bookmark is listed as one to its key; the NUL terminated
+// target, relative to the root
+pub fn symlink_target(bookmarks: &[Bookmark], path: &Path) -> Option<Vec<u16>> {
+    // asked for every placeholder, bookmarks or not
+    if bookmarks.is_empty() {
+        return None;
+    }
+    let parts = paths::components(path);
+    let bookmark = match parts.as_slice() {
+        [name] => bookmarks
+            .iter()
+            .find(|bookmark| name.eq_ignore_ascii_case(&bookmark.name))?,
+        _ => return None,
+    };
+    Some(
+        bookmark
+            .target
+            .as_os_str()
+            .to_string_lossy()
+            .encode_utf16()
+            .chain(Some(0))
+            .collect(),
+    )
+}
+
+// the bookmarks as roots next to the hives, anything under one read from its key; for
+// when they aren't symlinks, and for writes through the mount either way
+pub struct Bookmarks {
+    inner: Arc<dyn RegistryBackend>,
+    bookmarks: Vec<Bookmark>,
+}
+
+impl Bookmarks {
+    pub fn new(inner: Arc<dyn RegistryBackend>, bookmarks: &[Bookmark]) -> Self {
+        Bookmarks {
+            inner,
+            bookmarks: bookmarks.to_vec(),
+        }
+    }
+
+    // the key behind a path under a bookmark
+    pub fn target(&self, path: &Path) -> Option<PathBuf> {
+        let parts = paths::components(path);
+        self.bookmarks.iter().find_map(|bookmark| {
+            let rest = strip(&parts, &[bookmark.name.as_str()])?;
+            let target = bookmark.target.as_os_str();
+            Some(paths::join_parts(
+                [target]
+                    .into_iter()
+                    .chain(rest.iter().map(OsString::as_os_str)),
+            ))
+        })
+    }
+
+    // and back, for a change to a bookmarked key
+    pub fn bookmarked_paths(&self, path: &Path) -> Vec<PathBuf> {
+        let parts = paths::components(path);
+        self.bookmarks
+            .iter()
+            .filter_map(|bookmark| {
+                let target: Vec<String> = paths::components(&bookmark.target)
+                    .iter()
+                    .map(|part| part.to_string_lossy().into_owned())
+                    .collect();
+                let target: Vec<&str> = target.iter().map(String::as_str).collect();
+                let rest = strip(&parts, &target)?;
+                Some(paths::join_parts(
+                    [OsStr::new(&bookmark.name)]
+                        .into_iter()
+                        .chain(rest.iter().map(OsString::as_os_str)),
+                ))
+            })
+            .collect()
+    }
+
+    pub fn is_bookmark(&self, path: &Path) -> bool {
+        match paths::components(path).as_slice() {
+            [name] => self
+                .bookmarks
+                .iter()
+                .any(|bookmark| name.eq_ignore_ascii_case(&bookmark.name)),
+            _ => false,
+        }
+    }
+
+    fn resolved<'a>(&self, path: &'a Path) -> std::borrow::Cow<'a, Path> {
+        match self.target(path) {
+            Some(target) => target.into(),
+            None => path.into(),
+        }
+    }
+}
+
+impl RegistryBackend for Bookmarks {
+    fn enumerate_key_ctx(
+        &self,
+        path: OsString,
+        ctx: &OpContext,
+    ) -> Result<Option<RegEntires>, Cancelled> {
+        if let Some(target) = self.target(path.as_ref()) {
+            return self.inner.enumerate_key_ctx(target.into(), ctx);
+        }
+
+        let mut entries = self.inner.enumerate_key_ctx(path.clone(), ctx)?;
+        if let (true, Some(entries)) = (paths::components(&path).is_empty(), &mut entries) {
+            for bookmark in &self.bookmarks {
+                entries.subkeys.push(RegEntry {
+                    last_write_time: self
+                        .key_last_write_time(bookmark.name.as_ref())
+                        .unwrap_or(0),
+                    ..RegEntry::new(OsStr::new(&bookmark.name), 0)
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    fn read_typed_value_ctx(
+        &self,
+        path: &Path,
+        ctx: &OpContext,
+    ) -> Result<Option<(u32, Vec<u8>)>, Cancelled> {
+        self.inner.read_typed_value_ctx(&self.resolved(path), ctx)
+    }
+
+    fn read_value_ctx(&self, path: &Path, ctx: &OpContext) -> Result<Option<Vec<u8>>, Cancelled> {
+        self.inner.read_value_ctx(&self.resolved(path), ctx)
+    }
+
+    fn read_all_values(&self, path: &Path) -> Option<Vec<(OsString, u32, Vec<u8>)>> {
+        self.inner.read_all_values(&self.resolved(path))
+    }
+
+    // a bookmark whose key is missing is still listed, as an empty directory
+    fn does_key_exist(&self, path: &Path) -> bool {
+        self.is_bookmark(path) || self.inner.does_key_exist(&self.resolved(path))
+    }
+
+    fn key_last_write_time(&self, path: &Path) -> Option<i64> {
+        match self.inner.key_last_write_time(&self.resolved(path)) {
+            None if self.is_bookmark(path) => Some(0),
+            time => time,
+        }
+    }
+
+    fn key_info(&self, path: &Path) -> Option<KeyInfo> {
+        match self.inner.key_info(&self.resolved(path)) {
+            None if self.is_bookmark(path) => Some(KeyInfo::default()),
+            info => info,
+        }
+    }
+
+    fn watchers(&self) -> Option<&Watchers> {
+        self.inner.watchers()
+    }
+
+    // so walks down the tree don't go through the key a second time
+    fn link_target(&self, path: &Path) -> Option<PathBuf> {
+        match self.is_bookmark(path) {
+            true => self.target(path),
+            false => self.inner.link_target(&self.resolved(path)),
+        }
+    }
+
+    fn writable(&self) -> bool {
+        self.inner.writable()
+    }
+
+    fn value_type(&self, path: &Path) -> Option<u32> {
+        self.inner.value_type(&self.resolved(path))
+    }
+
+    fn does_value_exist(&self, path: &Path) -> Option<usize> {
+        self.inner.does_value_exist(&self.resolved(path))
+    }
+
+    fn classify(&self, path: &Path, ctx: &OpContext) -> Result<PathKind, Cancelled> {
+        match self.inner.classify(&self.resolved(path), ctx)? {
+            PathKind::Missing if self.is_bookmark(path) => Ok(PathKind::Key(0)),
+            kind => Ok(kind),
+        }
+    }
+}
+
+#[test]
+fn test_parse() {
+    let bookmark: Bookmark = "WinVer=HKLM\\SOFTWARE\\Microsoft\\Windows NT\\CurrentVersion"
+        .parse()
+        .unwrap();
+    assert_eq!(bookmark.name, "WinVer");
+    assert_eq!(
+        bookmark.target,
+        PathBuf::from("HKEY_LOCAL_MACHINE\\SOFTWARE\\Microsoft\\Windows NT\\CurrentVersion")
+    );
+
+    assert!("HKCU=HKEY_CURRENT_USER\\Software"
+        .parse::<Bookmark>()
+        .is_err());
+    assert!("hkey_users=HKEY_CURRENT_USER\\Software"
+        .parse::<Bookmark>()
+        .is_err());
+    assert!(".regfs=HKEY_CURRENT_USER".parse::<Bookmark>().is_err());
+    assert!("a\\b=HKEY_CURRENT_USER".parse::<Bookmark>().is_err());
+    assert!("Run=Software\\Run".parse::<Bookmark>().is_err());
+    assert!("Run".parse::<Bookmark>().is_err());
+}
+
+#[test]
+fn test_re_rooting() {
+    use crate::memory::MemoryBackend;
+
+    let memory = Arc::new(MemoryBackend::new());
+    let version = Path::new("HKEY_LOCAL_MACHINE\\SOFTWARE\\Microsoft\\Windows NT\\CurrentVersion");
+    memory.set_value(version, "ProductName", 1, b"Windows".to_vec());
+    memory.set_value(version.join("Fonts"), "Arial", 1, b"arial.ttf".to_vec());
+    let bookmark: Bookmark = format!("WinVer={}", version.display()).parse().unwrap();
+    let missing: Bookmark = "Later=HKCU\\Software\\Later".parse().unwrap();
+    let bookmarks = Bookmarks::new(memory.clone(), &[bookmark.clone(), missing.clone()]);
+
+    assert_eq!(
+        bookmarks.target("winver\\Fonts\\Arial".as_ref()),
+        Some(version.join("Fonts\\Arial"))
+    );
+    assert_eq!(bookmarks.target("WinVer".as_ref()), Some(version.into()));
+    assert_eq!(bookmarks.target("WinVerX\\Fonts".as_ref()), None);
+    assert_eq!(
+        bookmarks.target("HKEY_LOCAL_MACHINE\\WinVer".as_ref()),
+        None
+    );
+    assert_eq!(
+        bookmarks.bookmarked_paths(&version.join("Fonts")),
+        [PathBuf::from("WinVer\\Fonts")]
+    );
+    assert_eq!(
+        bookmarks.bookmarked_paths(version),
+        [PathBuf::from("WinVer")]
+    );
+    assert!(bookmarks
+        .bookmarked_paths(
+            "HKEY_LOCAL_MACHINE\\SOFTWARE\\Microsoft\\Windows NT\\CurrentVersionX".as_ref()
+        )
+        .is_empty());
+
+    // listed at the root, read through from anywhere under them
+    let root = bookmarks.enumerate_key("".into()).unwrap();
+    let names: Vec<_> = root.subkeys.iter().map(|key| key.name.clone()).collect();
+    assert!(names.contains(&"WinVer".into()));
+    assert!(names.contains(&"HKEY_LOCAL_MACHINE".into()));
+    assert_eq!(
+        bookmarks.read_value("WinVer\\Fonts\\Arial".as_ref()),
+        Some(b"arial.ttf".to_vec())
+    );
+    let entries = bookmarks.enumerate_key("WinVer".into()).unwrap();
+    assert_eq!(entries.values.len(), 1);
+    assert_eq!(entries.subkeys.len(), 1);
+
+    // once, as a link, for walks
+    assert_eq!(
+        bookmarks.link_target("WinVer".as_ref()),
+        Some(version.to_path_buf())
+    );
+    assert_eq!(bookmarks.link_target("WinVer\\Fonts".as_ref()), None);
+    assert_eq!(
+        symlink_target(&[bookmark.clone()], "WinVer".as_ref()),
+        Some(format!("{}\0", version.display()).encode_utf16().collect())
+    );
+    assert_eq!(symlink_target(&[bookmark], "WinVer\\Fonts".as_ref()), None);
+
+    // a target that isn't there is an empty directory
+    assert!(bookmarks.does_key_exist("Later".as_ref()));
+    assert!(matches!(
+        bookmarks.classify("Later".as_ref(), &OpContext::none()),
+        Ok(PathKind::Key(_))
+    ));
+    assert!(!bookmarks.does_key_exist("Later\\x".as_ref()));
+    assert!(validate(&[missing.clone()], memory.as_ref()).is_ok());
+    assert!(validate(&[missing.clone(), missing], memory.as_ref()).is_err());
+}
@@ -0,0 +1,129 @@
+use anyhow::{anyhow, Error, Result};
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::regop::paths;
+
+// what write-back does with a file whose value changed in the registry since it was projected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    // the save is refused and the registry keeps its value
+    Fail,
+    // last writer wins
+    #[default]
+    Overwrite,
+    // the registry's value is exported to a .reg file first
+    BackupThenOverwrite,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        match text {
+            "fail" => Ok(ConflictPolicy::Fail),
+            "overwrite" => Ok(ConflictPolicy::Overwrite),
+            "backup" => Ok(ConflictPolicy::BackupThenOverwrite),
+            _ => Err(anyhow!(
+                "invalid conflict policy [{}], expected fail, overwrite or backup",
+                text
+            )),
+        }
+    }
+}
+
+// --conflict: one policy for the mount, and more specific ones under some keys
+#[derive(Debug, Clone)]
+pub struct ConflictPolicies {
+    pub default: ConflictPolicy,
+    overrides: Vec<(Vec<String>, ConflictPolicy)>,
+    // where BackupThenOverwrite writes its .reg files
+    pub backups: PathBuf,
+}
+
+impl Default for ConflictPolicies {
+    fn default() -> Self {
+        ConflictPolicies {
+            default: ConflictPolicy::default(),
+            overrides: Vec::new(),
+            backups: std::env::temp_dir().join("regfs-backups"),
+        }
+    }
+}
+
+fn fold(path: &Path) -> Vec<String> {
+    paths::components(path)
+        .iter()
+        .map(|part| part.to_string_lossy().to_lowercase())
+        .collect()
+}
+
+impl ConflictPolicies {
+    // `<policy>` for the whole mount or `<key>=<policy>` for everything under that key
+    pub fn add(&mut self, spec: &str) -> Result<()> {
+        match spec.rsplit_once('=') {
+            Some((prefix, policy)) => {
+                let prefix = paths::normalize(prefix);
+                self.overrides.push((fold(&prefix), policy.parse()?));
+            }
+            None => self.default = spec.parse()?,
+        }
+        Ok(())
+    }
+
+    // the longest prefix that covers the path decides
+    pub fn for_path(&self, path: &Path) -> ConflictPolicy {
+        let path = fold(path);
+        self.overrides
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.default, |(_, policy)| *policy)
+    }
+}
+
+#[test]
+fn test_conflict_policies() {
+    let mut policies = ConflictPolicies::default();
+    assert_eq!(
+        policies.for_path("HKEY_CURRENT_USER\\x".as_ref()),
+        ConflictPolicy::Overwrite
+    );
+
+    policies.add("fail").unwrap();
+    policies.add("HKEY_CURRENT_USER\\Software=backup").unwrap();
+    policies
+        .add("hkey_current_user\\Software\\App=overwrite")
+        .unwrap();
+    policies
+        .add("HKEY_CURRENT_USER\\Software\\App\\Sub=fail")
+        .unwrap();
+
+    let policy = |path: &str| policies.for_path(path.as_ref());
+    assert_eq!(
+        policy("HKEY_LOCAL_MACHINE\\Software\\x"),
+        ConflictPolicy::Fail
+    );
+    assert_eq!(
+        policy("HKEY_CURRENT_USER\\SOFTWARE\\Other\\x"),
+        ConflictPolicy::BackupThenOverwrite
+    );
+    assert_eq!(
+        policy("HKEY_CURRENT_USER\\Software\\App\\x"),
+        ConflictPolicy::Overwrite
+    );
+    assert_eq!(
+        policy("HKEY_CURRENT_USER\\Software\\App\\Sub\\x"),
+        ConflictPolicy::Fail
+    );
+    // a prefix is whole keys, not text
+    assert_eq!(
+        policy("HKEY_CURRENT_USER\\Software\\Apple"),
+        ConflictPolicy::BackupThenOverwrite
+    );
+
+    assert!(policies.add("sometimes").is_err());
+    assert!(policies.add("HKEY_CURRENT_USER=sometimes").is_err());
+}
@@ -0,0 +1,37 @@
+use log::info;
+use std::{
+    io::{self, BufRead, Write},
+    thread,
+};
+
+use crate::control::{self, ControlCommand};
+use crate::regfs::RegFs;
+
+// one line of input, and the text to print back
+pub fn handle(regfs: &RegFs, line: &str) -> String {
+    if matches!(line.trim(), "help" | "?") {
+        return control::HELP.to_string();
+    }
+
+    match ControlCommand::parse(line) {
+        Ok(Some(command)) => regfs.execute(command),
+        Ok(None) => String::new(),
+        Err(e) => format!("{}\n{}", e, control::HELP),
+    }
+}
+
+// reads commands from stdin until the input ends; `quit` is up to whoever waits on it
+pub fn spawn(regfs: RegFs) {
+    thread::spawn(move || {
+        info!(target: "control", "console ready, type `help` for the commands");
+
+        for line in io::stdin().lock().lines().map_while(Result::ok) {
+            let output = handle(&regfs, &line);
+            if !output.is_empty() {
+                let mut stdout = io::stdout().lock();
+                let _ = writeln!(stdout, "{}", output.trim_end());
+                let _ = stdout.flush();
+            }
+        }
+    });
+}
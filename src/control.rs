@@ -0,0 +1,633 @@
+use anyhow::{anyhow, Result};
+use std::path::PathBuf;
+
+use crate::options;
+
+pub const HELP: &str = "\
+commands:
+  stats              print the provider counters
+  status             print the uptime, backend, readonly state, sessions and counters
+  sessions           list the active enumerations and their paths
+  dump               print the sessions, hydrations, cancellations and caches as JSON
+  readonly on|off    allow or refuse renames and deletes
+  suspend            stop answering callbacks and refuse every change, placeholders stay
+  resume             answer them again, and resync the hives
+  resync <path>      bring a subtree back in sync with the registry
+  hydrate <path>     hydrate every file under a subtree
+  dehydrate [path]   turn unmodified files back into placeholders
+  copy-key [--force] <source> <destination>
+                     copy a key with everything under it, into --overlay if any
+  overlay stats      count the changes held by --overlay
+  overlay export <file.reg>
+                     write those changes out as a .reg file
+  overlay dump <file.json>
+                     save them for `regfs overlay export` to convert later
+  overlay reset      throw them away
+  shadow export <file.reg>
+                     write the --shadow-writes staging key out as a .reg file
+  trace [list]       list the keys whose callbacks log at every level
+  trace add <key>    log everything about that key and what's under it
+  trace remove <key> stop doing so
+  trace clear        stop for every key
+  quit               stop the provider";
+
+#[derive(Debug, PartialEq)]
+pub enum ControlCommand {
+    Resync(PathBuf),
+    Dehydrate(PathBuf),
+    Hydrate(PathBuf),
+    Stats,
+    Status,
+    Sessions,
+    Dump,
+    ReadOnly(bool),
+    Suspend,
+    Resume,
+    CopyKey {
+        source: PathBuf,
+        destination: PathBuf,
+        // replaces whatever is at the destination
+        force: bool,
+    },
+    Overlay(OverlayCommand),
+    // `shadow export <file.reg>`
+    ShadowExport(PathBuf),
+    Trace(TraceCommand),
+    Quit,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum OverlayCommand {
+    Stats,
+    Export(PathBuf),
+    Dump(PathBuf),
+    Reset,
+}
+
+// --trace-path, changed at runtime
+#[derive(Debug, PartialEq)]
+pub enum TraceCommand {
+    List,
+    Add(PathBuf),
+    Remove(PathBuf),
+    Clear,
+}
+
+impl TraceCommand {
+    fn parse(action: &str, key: &str) -> Result<TraceCommand> {
+        let key = || options::parse_trace_path(trim_path(key.trim()));
+        match action.to_ascii_lowercase().as_str() {
+            "" | "list" => Ok(TraceCommand::List),
+            "add" => Ok(TraceCommand::Add(key()?)),
+            "remove" => Ok(TraceCommand::Remove(key()?)),
+            "clear" => Ok(TraceCommand::Clear),
+            _ => Err(anyhow!(
+                "trace: expected list, add, remove or clear, got [{}]",
+                action
+            )),
+        }
+    }
+}
+
+impl OverlayCommand {
+    fn parse(action: &str, file: &str) -> Result<OverlayCommand> {
+        let file = file.trim().trim_matches('"');
+        match action.to_ascii_lowercase().as_str() {
+            "export" | "dump" if file.is_empty() => {
+                Err(anyhow!("overlay {}: missing file", action))
+            }
+            "export" => Ok(OverlayCommand::Export(file.into())),
+            "dump" => Ok(OverlayCommand::Dump(file.into())),
+            "stats" => Ok(OverlayCommand::Stats),
+            "reset" => Ok(OverlayCommand::Reset),
+            _ => Err(anyhow!(
+                "overlay: expected stats, export, dump or reset, got [{}]",
+                action
+            )),
+        }
+    }
+}
+
+impl ControlCommand {
+    fn copy_key(source: &str, destination: &str, force: bool) -> Result<ControlCommand> {
+        if source.is_empty() || destination.is_empty() {
+            return Err(anyhow!("copy-key: expected a source and a destination key"));
+        }
+        let key = |path: &str| {
+            options::parse_trace_path(trim_path(path))
+                .map_err(|_| anyhow!("copy-key: expected a key, got [{}]", path))
+        };
+        Ok(ControlCommand::CopyKey {
+            source: key(source)?,
+            destination: key(destination)?,
+            force,
+        })
+    }
+
+    fn shadow(action: &str, file: &str) -> Result<ControlCommand> {
+        let file = file.trim().trim_matches('"');
+        match action.to_ascii_lowercase().as_str() {
+            "export" if file.is_empty() => Err(anyhow!("shadow export: missing file")),
+            "export" => Ok(ControlCommand::ShadowExport(file.into())),
+            _ => Err(anyhow!("shadow: expected export, got [{}]", action)),
+        }
+    }
+
+    // blank lines and lines starting with '#' are not commands
+    pub fn parse(line: &str) -> Result<Option<ControlCommand>> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+
+        let (verb, args) = match line.find(char::is_whitespace) {
+            Some(index) => (&line[..index], line[index..].trim()),
+            None => (line, ""),
+        };
+
+        match verb.to_ascii_lowercase().as_str() {
+            "resync" => {
+                if args.is_empty() {
+                    Err(anyhow!("resync: missing path"))
+                } else {
+                    Ok(Some(ControlCommand::Resync(trim_path(args).into())))
+                }
+            }
+            "dehydrate" => Ok(Some(ControlCommand::Dehydrate(trim_path(args).into()))),
+            "hydrate" => {
+                if args.is_empty() {
+                    Err(anyhow!("hydrate: missing path"))
+                } else {
+                    Ok(Some(ControlCommand::Hydrate(trim_path(args).into())))
+                }
+            }
+            "stats" => Ok(Some(ControlCommand::Stats)),
+            "status" => Ok(Some(ControlCommand::Status)),
+            "sessions" => Ok(Some(ControlCommand::Sessions)),
+            "dump" => Ok(Some(ControlCommand::Dump)),
+            "readonly" => match args.to_ascii_lowercase().as_str() {
+                "on" => Ok(Some(ControlCommand::ReadOnly(true))),
+                "off" => Ok(Some(ControlCommand::ReadOnly(false))),
+                _ => Err(anyhow!("readonly: expected on or off, got [{}]", args)),
+            },
+            "suspend" => Ok(Some(ControlCommand::Suspend)),
+            "resume" => Ok(Some(ControlCommand::Resume)),
+            "copy-key" => {
+                let mut words = split_quoted(args);
+                let force = words.first() == Some(&"--force");
+                if force {
+                    words.remove(0);
+                }
+                match words.as_slice() {
+                    [source, destination] => {
+                        Ok(Some(ControlCommand::copy_key(source, destination, force)?))
+                    }
+                    _ => Err(anyhow!(
+                        "copy-key: expected [--force] <source> <destination>, got [{}]",
+                        args
+                    )),
+                }
+            }
+            "overlay" => {
+                let (action, file) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+                Ok(Some(ControlCommand::Overlay(OverlayCommand::parse(
+                    action, file,
+                )?)))
+            }
+            "shadow" => {
+                let (action, file) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+                Ok(Some(ControlCommand::shadow(action, file)?))
+            }
+            "trace" => {
+                let (action, key) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+                Ok(Some(ControlCommand::Trace(TraceCommand::parse(
+                    action, key,
+                )?)))
+            }
+            "quit" => Ok(Some(ControlCommand::Quit)),
+            _ => Err(anyhow!("unknown control verb [{}]", verb)),
+        }
+    }
+
+    // .regfs\control can be written by anything that can write under the mount, so it only
+    // takes what reads or refreshes the projection: nothing that changes the provider's
+    // state, the overlay, or writes a file elsewhere
+    pub fn parse_control_file(line: &str) -> Result<Option<ControlCommand>> {
+        match ControlCommand::parse(line)? {
+            Some(command) if !command.allowed_in_control_file() => Err(anyhow!(
+                "[{}] can only be run from the console or the control pipe",
+                line.trim()
+            )),
+            command => Ok(command),
+        }
+    }
+
+    fn allowed_in_control_file(&self) -> bool {
+        matches!(
+            self,
+            ControlCommand::Resync(_)
+                | ControlCommand::Dehydrate(_)
+                | ControlCommand::Hydrate(_)
+                | ControlCommand::Stats
+                | ControlCommand::Status
+                | ControlCommand::Sessions
+                | ControlCommand::Dump
+                | ControlCommand::Overlay(OverlayCommand::Stats)
+                | ControlCommand::Trace(TraceCommand::List)
+        )
+    }
+}
+
+impl ControlCommand {
+    // {"cmd": "<verb>"}, plus "path" or "value" for the verbs that take one, "action" for
+    // overlay, shadow and trace, and "destination" and "force" for copy-key
+    pub fn from_json(line: &str) -> Result<ControlCommand> {
+        let request: serde_json::Value =
+            serde_json::from_str(line).map_err(|e| anyhow!("invalid request: {}", e))?;
+        let cmd = request["cmd"]
+            .as_str()
+            .ok_or_else(|| anyhow!("request has no [cmd]"))?;
+        let path = || request["path"].as_str().map(trim_path).unwrap_or("");
+
+        match cmd.to_ascii_lowercase().as_str() {
+            "resync" | "hydrate" if path().is_empty() => Err(anyhow!("{}: missing path", cmd)),
+            "resync" => Ok(ControlCommand::Resync(path().into())),
+            "hydrate" => Ok(ControlCommand::Hydrate(path().into())),
+            "dehydrate" => Ok(ControlCommand::Dehydrate(path().into())),
+            "stats" => Ok(ControlCommand::Stats),
+            "status" => Ok(ControlCommand::Status),
+            "sessions" => Ok(ControlCommand::Sessions),
+            "dump" => Ok(ControlCommand::Dump),
+            "readonly" => match request["value"].as_bool() {
+                Some(readonly) => Ok(ControlCommand::ReadOnly(readonly)),
+                None => Err(anyhow!("readonly: [value] must be true or false")),
+            },
+            "suspend" => Ok(ControlCommand::Suspend),
+            "resume" => Ok(ControlCommand::Resume),
+            "copy-key" => ControlCommand::copy_key(
+                request["path"].as_str().unwrap_or(""),
+                request["destination"].as_str().unwrap_or(""),
+                request["force"].as_bool().unwrap_or(false),
+            ),
+            "overlay" => Ok(ControlCommand::Overlay(OverlayCommand::parse(
+                request["action"].as_str().unwrap_or(""),
+                request["path"].as_str().unwrap_or(""),
+            )?)),
+            "shadow" => ControlCommand::shadow(
+                request["action"].as_str().unwrap_or(""),
+                request["path"].as_str().unwrap_or(""),
+            ),
+            "trace" => Ok(ControlCommand::Trace(TraceCommand::parse(
+                request["action"].as_str().unwrap_or(""),
+                path(),
+            )?)),
+            "quit" => Ok(ControlCommand::Quit),
+            _ => Err(anyhow!("unknown control verb [{}]", cmd)),
+        }
+    }
+}
+
+// the `status` response as two columns, counters last
+pub fn status_table(status: &serde_json::Value) -> String {
+    let uptime = status["uptime"].as_u64().unwrap_or(0);
+    let yes_no = |value: &serde_json::Value| match value.as_bool() {
+        Some(true) => "yes",
+        _ => "no",
+    };
+    let sessions = status["sessions"].as_array().cloned().unwrap_or_default();
+
+    let mut rows = vec![
+        ("root", status["root"].as_str().unwrap_or("").to_string()),
+        (
+            "uptime",
+            format!(
+                "{}h {:02}m {:02}s",
+                uptime / 3600,
+                uptime / 60 % 60,
+                uptime % 60
+            ),
+        ),
+        (
+            "backend",
+            status["backend"].as_str().unwrap_or("").to_string(),
+        ),
+        ("readonly", yes_no(&status["readonly"]).to_string()),
+        ("suspended", yes_no(&status["suspended"]).to_string()),
+        ("sessions", sessions.len().to_string()),
+    ];
+    for session in &sessions {
+        rows.push((
+            "",
+            format!(
+                "{} {}",
+                session["enumeration"].as_str().unwrap_or(""),
+                session["path"].as_str().unwrap_or("")
+            ),
+        ));
+    }
+    if let Some(stats) = status["stats"].as_object() {
+        for (name, value) in stats {
+            rows.push((name.as_str(), value.to_string()));
+        }
+    }
+
+    rows.iter()
+        .map(|(name, value)| format!("{:<24}{}\n", name, value))
+        .collect()
+}
+
+// words separated by whitespace, or in double quotes when they have some
+fn split_quoted(args: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut rest = args.trim_start();
+    while !rest.is_empty() {
+        let (word, after) = match rest.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+            None => rest.split_once(char::is_whitespace).unwrap_or((rest, "")),
+        };
+        words.push(word);
+        rest = after.trim_start();
+    }
+    words
+}
+
+fn trim_path(path: &str) -> &str {
+    path.trim_matches('"').trim_matches('\\')
+}
+
+// enumeration ids are kept as the raw bytes of the GUID
+pub fn guid_string(bytes: &[u8]) -> String {
+    if bytes.len() != 16 {
+        return format!("{:02x?}", bytes);
+    }
+
+    let u32_at =
+        |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+    let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+    let hex = |range: &[u8]| {
+        range
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>()
+    };
+
+    format!(
+        "{{{:08x}-{:04x}-{:04x}-{}-{}}}",
+        u32_at(0),
+        u16_at(4),
+        u16_at(6),
+        hex(&bytes[8..10]),
+        hex(&bytes[10..16])
+    )
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!(ControlCommand::parse("").unwrap(), None);
+    assert_eq!(ControlCommand::parse("  # comment").unwrap(), None);
+    assert_eq!(
+        ControlCommand::parse("resync HKEY_CURRENT_USER\\Control Panel\\").unwrap(),
+        Some(ControlCommand::Resync(
+            "HKEY_CURRENT_USER\\Control Panel".into()
+        ))
+    );
+    assert_eq!(
+        ControlCommand::parse("RESYNC \"HKEY_USERS\"").unwrap(),
+        Some(ControlCommand::Resync("HKEY_USERS".into()))
+    );
+    assert_eq!(
+        ControlCommand::parse("dehydrate").unwrap(),
+        Some(ControlCommand::Dehydrate("".into()))
+    );
+    assert!(ControlCommand::parse("resync").is_err());
+    assert!(ControlCommand::parse("frobnicate HKEY_USERS").is_err());
+}
+
+#[test]
+fn test_parse_console_verbs() {
+    assert_eq!(
+        ControlCommand::parse("stats").unwrap(),
+        Some(ControlCommand::Stats)
+    );
+    assert_eq!(
+        ControlCommand::parse("Sessions").unwrap(),
+        Some(ControlCommand::Sessions)
+    );
+    assert_eq!(
+        ControlCommand::parse("readonly OFF").unwrap(),
+        Some(ControlCommand::ReadOnly(false))
+    );
+    assert_eq!(
+        ControlCommand::parse("readonly on").unwrap(),
+        Some(ControlCommand::ReadOnly(true))
+    );
+    assert_eq!(
+        ControlCommand::parse("hydrate HKEY_CURRENT_USER\\Console").unwrap(),
+        Some(ControlCommand::Hydrate("HKEY_CURRENT_USER\\Console".into()))
+    );
+    assert_eq!(
+        ControlCommand::parse("quit").unwrap(),
+        Some(ControlCommand::Quit)
+    );
+    assert_eq!(
+        ControlCommand::parse("Suspend").unwrap(),
+        Some(ControlCommand::Suspend)
+    );
+    assert_eq!(
+        ControlCommand::from_json(r#"{"cmd":"resume"}"#).unwrap(),
+        ControlCommand::Resume
+    );
+    assert!(ControlCommand::parse("readonly").is_err());
+    assert!(ControlCommand::parse("hydrate").is_err());
+}
+
+#[test]
+fn test_parse_control_file() {
+    assert_eq!(
+        ControlCommand::parse_control_file("resync HKEY_USERS").unwrap(),
+        Some(ControlCommand::Resync("HKEY_USERS".into()))
+    );
+    assert_eq!(
+        ControlCommand::parse_control_file("dehydrate").unwrap(),
+        Some(ControlCommand::Dehydrate("".into()))
+    );
+    assert_eq!(
+        ControlCommand::parse_control_file("overlay stats").unwrap(),
+        Some(ControlCommand::Overlay(OverlayCommand::Stats))
+    );
+    assert_eq!(ControlCommand::parse_control_file("# quit").unwrap(), None);
+
+    // nothing that changes what the mount allows, stops it or writes elsewhere
+    for line in [
+        "readonly off",
+        "readonly on",
+        "quit",
+        "suspend",
+        "resume",
+        "copy-key HKCU\\Software\\A HKCU\\Software\\B",
+        "overlay reset",
+        "overlay export C:\\Windows\\out.reg",
+        "overlay dump out.json",
+        "shadow export out.reg",
+        "trace add HKEY_USERS",
+        "trace clear",
+    ] {
+        assert!(
+            ControlCommand::parse_control_file(line).is_err(),
+            "{}",
+            line
+        );
+        assert!(ControlCommand::parse(line).unwrap().is_some(), "{}", line);
+    }
+    assert!(ControlCommand::parse_control_file("frobnicate").is_err());
+}
+
+#[test]
+fn test_from_json() {
+    assert_eq!(
+        ControlCommand::from_json(r#"{"cmd":"stats"}"#).unwrap(),
+        ControlCommand::Stats
+    );
+    assert_eq!(
+        ControlCommand::from_json(r#"{"cmd":"resync","path":"HKEY_USERS\\.DEFAULT"}"#).unwrap(),
+        ControlCommand::Resync("HKEY_USERS\\.DEFAULT".into())
+    );
+    assert_eq!(
+        ControlCommand::from_json(r#"{"cmd":"readonly","value":false}"#).unwrap(),
+        ControlCommand::ReadOnly(false)
+    );
+    assert!(ControlCommand::from_json(r#"{"cmd":"readonly","value":"off"}"#).is_err());
+    assert!(ControlCommand::from_json(r#"{"cmd":"resync"}"#).is_err());
+    assert!(ControlCommand::from_json(r#"{"verb":"stats"}"#).is_err());
+    assert!(ControlCommand::from_json("stats").is_err());
+}
+
+#[test]
+fn test_parse_overlay() {
+    assert_eq!(
+        ControlCommand::parse("overlay export \"C:\\out dir\\changes.reg\"").unwrap(),
+        Some(ControlCommand::Overlay(OverlayCommand::Export(
+            "C:\\out dir\\changes.reg".into()
+        )))
+    );
+    assert_eq!(
+        ControlCommand::parse("overlay Stats").unwrap(),
+        Some(ControlCommand::Overlay(OverlayCommand::Stats))
+    );
+    assert!(ControlCommand::parse("overlay dump").is_err());
+    assert!(ControlCommand::parse("overlay").is_err());
+    assert_eq!(
+        ControlCommand::from_json(r#"{"cmd":"overlay","action":"reset"}"#).unwrap(),
+        ControlCommand::Overlay(OverlayCommand::Reset)
+    );
+
+    assert_eq!(
+        ControlCommand::parse("shadow export staged.reg").unwrap(),
+        Some(ControlCommand::ShadowExport("staged.reg".into()))
+    );
+    assert!(ControlCommand::parse("shadow export").is_err());
+    assert!(ControlCommand::parse("shadow stats").is_err());
+    assert_eq!(
+        ControlCommand::from_json(r#"{"cmd":"shadow","action":"export","path":"s.reg"}"#).unwrap(),
+        ControlCommand::ShadowExport("s.reg".into())
+    );
+}
+
+#[test]
+fn test_parse_copy_key() {
+    assert_eq!(
+        ControlCommand::parse("copy-key \"HKCU\\Software\\My App\\Default\" HKCU\\Software\\Copy")
+            .unwrap(),
+        Some(ControlCommand::CopyKey {
+            source: "HKEY_CURRENT_USER\\Software\\My App\\Default".into(),
+            destination: "HKEY_CURRENT_USER\\Software\\Copy".into(),
+            force: false,
+        })
+    );
+    assert!(matches!(
+        ControlCommand::parse("COPY-KEY --force HKCU\\a HKCU\\b").unwrap(),
+        Some(ControlCommand::CopyKey { force: true, .. })
+    ));
+    assert!(ControlCommand::parse("copy-key HKCU\\a").is_err());
+    assert!(ControlCommand::parse("copy-key HKCU\\a Software\\b").is_err());
+    assert_eq!(
+        ControlCommand::from_json(
+            r#"{"cmd":"copy-key","path":"HKCU\\a","destination":"HKCU\\b","force":true}"#
+        )
+        .unwrap(),
+        ControlCommand::CopyKey {
+            source: "HKEY_CURRENT_USER\\a".into(),
+            destination: "HKEY_CURRENT_USER\\b".into(),
+            force: true,
+        }
+    );
+    assert!(ControlCommand::from_json(r#"{"cmd":"copy-key","path":"HKCU\\a"}"#).is_err());
+}
+
+#[test]
+fn test_parse_trace() {
+    assert_eq!(
+        ControlCommand::parse("trace").unwrap(),
+        Some(ControlCommand::Trace(TraceCommand::List))
+    );
+    assert_eq!(
+        ControlCommand::parse("trace add \"HKLM\\SOFTWARE\\My App\\\"").unwrap(),
+        Some(ControlCommand::Trace(TraceCommand::Add(
+            "HKEY_LOCAL_MACHINE\\SOFTWARE\\My App".into()
+        )))
+    );
+    assert_eq!(
+        ControlCommand::parse("TRACE clear").unwrap(),
+        Some(ControlCommand::Trace(TraceCommand::Clear))
+    );
+    assert!(ControlCommand::parse("trace add").is_err());
+    assert!(ControlCommand::parse("trace add Software").is_err());
+    assert!(ControlCommand::parse("trace everything").is_err());
+    assert_eq!(
+        ControlCommand::from_json(
+            r#"{"cmd":"trace","action":"remove","path":"HKEY_CURRENT_USER\\Console"}"#
+        )
+        .unwrap(),
+        ControlCommand::Trace(TraceCommand::Remove("HKEY_CURRENT_USER\\Console".into()))
+    );
+}
+
+#[test]
+fn test_guid_string() {
+    let bytes = [
+        0x78, 0x56, 0x34, 0x12, 0x34, 0x12, 0x78, 0x56, 0x9a, 0xbc, 0xde, 0xf0, 0x12, 0x34, 0x56,
+        0x78,
+    ];
+    assert_eq!(
+        guid_string(&bytes),
+        "{12345678-1234-5678-9abc-def012345678}"
+    );
+}
+
+#[test]
+fn test_status_table() {
+    let status = serde_json::json!({
+        "root": "C:\\test",
+        "uptime": 3723,
+        "backend": "overlay > live",
+        "readonly": false,
+        "suspended": true,
+        "sessions": [{"enumeration": "{0000}", "path": "HKEY_USERS"}],
+        "stats": {"dropped_events": 0},
+    });
+    assert_eq!(
+        status_table(&status),
+        "root                    C:\\test\n\
+         uptime                  1h 02m 03s\n\
+         backend                 overlay > live\n\
+         readonly                no\n\
+         suspended               yes\n\
+         sessions                1\n\
+         \x20                       {0000} HKEY_USERS\n\
+         dropped_events          0\n"
+    );
+    assert_eq!(
+        ControlCommand::from_json(r#"{"cmd":"status"}"#).unwrap(),
+        ControlCommand::Status
+    );
+}
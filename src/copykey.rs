@@ -0,0 +1,395 @@
+use log::{info, warn};
+use std::{
+    collections::VecDeque,
+    ffi::OsString,
+    fmt,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+use crate::backend::RegistryBackend;
+use crate::events::RegFsEvent;
+use crate::opcontext::{Cancelled, OpContext};
+use crate::policy::{Decision, MutationKind, MutationRequest};
+use crate::regfs::{path_key, RegFs};
+use crate::regop::{paths::RegPath, rename::CopyLimits, RegOpsError};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CopySummary {
+    pub keys: usize,
+    pub values: usize,
+    pub bytes: u64,
+}
+
+impl fmt::Display for CopySummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} keys, {} values ({} bytes)",
+            self.keys, self.values, self.bytes
+        )
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum CopyError {
+    #[error("copy-key: not mounted with --overlay, nor on a registry it can write to")]
+    NoOverlay,
+    #[error("copy-key: [{0:?}] isn't a key")]
+    NotFound(PathBuf),
+    #[error("copy-key: [{0:?}] already exists, --force replaces it")]
+    Exists(PathBuf),
+    #[error("copy-key: [{0:?}] can't be copied to itself or under itself")]
+    IntoItself(PathBuf),
+    #[error("copy-key: [{path:?}] denied: {reason}")]
+    Denied { path: PathBuf, reason: String },
+    #[error("copy-key: [{path:?}] is too big, {keys} keys and {bytes} bytes of values so far")]
+    TooLarge {
+        path: PathBuf,
+        keys: usize,
+        bytes: u64,
+    },
+    #[error("copy-key: cancelled, nothing was copied")]
+    Cancelled,
+    #[error("copy-key: {0}")]
+    Failed(anyhow::Error),
+}
+
+impl From<Cancelled> for CopyError {
+    fn from(_: Cancelled) -> Self {
+        CopyError::Cancelled
+    }
+}
+
+// one key of the tree being copied, relative to its root
+struct CopiedKey {
+    path: PathBuf,
+    values: Vec<(OsString, u32, Vec<u8>)>,
+}
+
+// every key under `source`, itself first; links are copied as the keys they are, not followed
+fn read_tree(
+    backend: &dyn RegistryBackend,
+    source: &Path,
+    limits: &CopyLimits,
+    ctx: &OpContext,
+) -> Result<(Vec<CopiedKey>, CopySummary), CopyError> {
+    let mut tree = Vec::new();
+    let mut summary = CopySummary::default();
+    let mut queue = VecDeque::from([PathBuf::new()]);
+
+    while let Some(relative) = queue.pop_front() {
+        ctx.check()?;
+        let key = join(source, &relative);
+        let entries = match backend.enumerate_key_ctx(key.clone().into(), ctx)? {
+            Some(entries) => entries,
+            None if tree.is_empty() => return Err(CopyError::NotFound(source.to_path_buf())),
+            // gone since its parent was listed
+            None => continue,
+        };
+        let values = backend.read_all_values(&key).unwrap_or_default();
+
+        summary.keys += 1;
+        summary.values += values.len();
+        summary.bytes += values
+            .iter()
+            .map(|(_, _, data)| data.len() as u64)
+            .sum::<u64>();
+        if summary.keys > limits.max_keys || summary.bytes > limits.max_bytes {
+            return Err(CopyError::TooLarge {
+                path: source.to_path_buf(),
+                keys: summary.keys,
+                bytes: summary.bytes,
+            });
+        }
+
+        for subkey in entries.subkeys {
+            if backend.link_target(&key.join(&subkey.name)).is_none() {
+                queue.push_back(relative.join(&subkey.name));
+            }
+        }
+        tree.push(CopiedKey {
+            path: relative,
+            values,
+        });
+    }
+    Ok((tree, summary))
+}
+
+fn join(key: &Path, relative: &Path) -> PathBuf {
+    match relative.as_os_str().is_empty() {
+        true => key.to_path_buf(),
+        false => key.join(relative),
+    }
+}
+
+impl RegFs {
+    // copy-key: the tree read through the overlay, then written into it at `destination` in
+    // one go, so a copy that fails or is cancelled (after --registry-timeout) leaves nothing.
+    // Without an overlay, RegOps::copy_tree makes it in the registry, with the same guarantee
+    pub fn copy_key(
+        &self,
+        source: &Path,
+        destination: &Path,
+        force: bool,
+    ) -> Result<CopySummary, CopyError> {
+        if self.overlay().is_none() && self.writer().is_none() {
+            return Err(CopyError::NoOverlay);
+        }
+        let (source_key, destination_key) = (path_key(source), path_key(destination));
+        if destination_key == source_key
+            || destination_key.starts_with(&format!("{}\\", source_key))
+        {
+            return Err(CopyError::IntoItself(source.to_path_buf()));
+        }
+
+        let request = MutationRequest {
+            kind: MutationKind::Copy,
+            path: destination,
+            destination: None,
+            process: "".as_ref(),
+            is_directory: true,
+        };
+        let decision = match RegPath::parse(destination).is_hive() {
+            true => Decision::Deny("protected".into()),
+            false => self.decide(&request),
+        };
+        if let Decision::Deny(reason) = decision {
+            self.emit(RegFsEvent::OperationDenied {
+                path: destination.to_path_buf(),
+                reason: reason.to_string(),
+            });
+            return Err(CopyError::Denied {
+                path: destination.to_path_buf(),
+                reason: reason.into_owned(),
+            });
+        }
+        let overlay = match self.overlay() {
+            Some(overlay) => overlay,
+            None => return self.copy_key_in_registry(source, destination, force),
+        };
+        if !force && overlay.does_key_exist(destination) {
+            return Err(CopyError::Exists(destination.to_path_buf()));
+        }
+
+        let from = source.to_path_buf();
+        let read = self.run_on_registry(move |regfs, ctx| match regfs.overlay() {
+            Some(overlay) => Ok(read_tree(overlay, &from, &CopyLimits::default(), ctx)),
+            None => Ok(Err(CopyError::NoOverlay)),
+        });
+        let (tree, summary) = match read.map_err(CopyError::from).and_then(|read| read) {
+            Ok(read) => read,
+            Err(e) => {
+                warn!(target: "overlay", "{}", e);
+                return Err(e);
+            }
+        };
+
+        // whatever was there is replaced, not merged
+        if overlay.does_key_exist(destination) {
+            overlay.delete(destination);
+        }
+        for key in &tree {
+            let path = join(destination, &key.path);
+            overlay.create_key(&path);
+            for (name, vtype, data) in &key.values {
+                overlay.set_value(&path.join(name), *vtype, data.clone());
+            }
+        }
+        self.forget_written(Some(destination));
+        info!(
+            target: "overlay",
+            "copied [{:?}] to [{:?}]: {}",
+            source,
+            destination,
+            summary
+        );
+        self.copied(source, destination, summary)
+    }
+
+    fn copy_key_in_registry(
+        &self,
+        source: &Path,
+        destination: &Path,
+        force: bool,
+    ) -> Result<CopySummary, CopyError> {
+        let (from, to) = (self.write_path(source), self.write_path(destination));
+        if !force && self.regops().does_key_exist(destination) {
+            return Err(CopyError::Exists(destination.to_path_buf()));
+        }
+
+        let copy = self.run_on_registry(move |regfs, ctx| match regfs.writer() {
+            Some(writer) => Ok(writer.copy_tree(&from, &to, force, ctx)),
+            None => Ok(Err(anyhow::anyhow!("nothing to write the copy to"))),
+        });
+        let summary = match copy.map_err(CopyError::from) {
+            Ok(Ok(summary)) => summary,
+            Ok(Err(e)) => {
+                let error = match e.downcast::<RegOpsError>() {
+                    Ok(RegOpsError::KeyNotFound(_)) => CopyError::NotFound(source.to_path_buf()),
+                    Ok(RegOpsError::TooLarge { keys, bytes, .. }) => CopyError::TooLarge {
+                        path: source.to_path_buf(),
+                        keys,
+                        bytes,
+                    },
+                    Ok(RegOpsError::Cancelled(_)) => CopyError::Cancelled,
+                    Ok(e) => CopyError::Failed(e.into()),
+                    Err(e) => CopyError::Failed(e),
+                };
+                warn!(target: "regops", "{}", error);
+                return Err(error);
+            }
+            Err(e) => {
+                warn!(target: "regops", "{}", e);
+                return Err(e);
+            }
+        };
+        self.forget_written(Some(destination));
+        info!(
+            target: "regops",
+            "copied [{:?}] to [{:?}]: {}",
+            source,
+            destination,
+            summary
+        );
+        self.copied(source, destination, summary)
+    }
+
+    fn copied(
+        &self,
+        source: &Path,
+        destination: &Path,
+        summary: CopySummary,
+    ) -> Result<CopySummary, CopyError> {
+        self.emit(RegFsEvent::KeyCopied {
+            path: source.to_path_buf(),
+            destination: destination.to_path_buf(),
+            keys: summary.keys,
+            values: summary.values,
+            bytes: summary.bytes,
+        });
+
+        // the projection of the destination's parent is out of date now
+        if let (Some(parent), false) = (destination.parent(), self.context().is_null()) {
+            self.spawn_resync(parent.to_path_buf());
+        }
+        Ok(summary)
+    }
+}
+
+#[test]
+fn test_copy_key() {
+    use crate::memory::MemoryBackend;
+    use crate::options::{BackendSpec, RegFsOptions};
+    use std::sync::{mpsc, Arc};
+
+    let backend = Arc::new(MemoryBackend::new());
+    let profiles = Path::new("HKEY_CURRENT_USER\\Software\\App\\Profiles");
+    let default = profiles.join("Default");
+    backend.set_value(&default, "(default)", 1, b"d\0".to_vec());
+    backend.set_value(&default, "Size", 4, vec![8, 0, 0, 0]);
+    backend.set_value(default.join("Fonts\\Mono"), "Face", 1, b"Consolas".to_vec());
+    backend.add_key(default.join("Empty"));
+    let (sender, events) = mpsc::sync_channel(16);
+    let options = RegFsOptions {
+        backends: vec![BackendSpec::Overlay],
+        readonly: false,
+        events: Some(sender),
+        ..Default::default()
+    };
+    let regfs = RegFs::with_backend(&options, backend.clone());
+    let overlay = regfs.overlay().unwrap();
+    let export = |key: &Path| {
+        let (tree, _) =
+            read_tree(overlay, key, &CopyLimits::default(), &OpContext::none()).unwrap();
+        let mut tree: Vec<_> = tree
+            .into_iter()
+            .map(|key| {
+                let mut values = key.values;
+                values.sort();
+                (key.path, values)
+            })
+            .collect();
+        tree.sort_by(|a, b| a.0.cmp(&b.0));
+        tree
+    };
+
+    let work = profiles.join("Work");
+    let summary = regfs.copy_key(&default, &work, false).unwrap();
+    assert_eq!(
+        summary,
+        CopySummary {
+            keys: 4,
+            values: 3,
+            bytes: 14
+        }
+    );
+    assert_eq!(export(&work), export(&default));
+    // in the overlay only
+    assert!(!backend.does_key_exist(&work));
+    assert!(matches!(
+        events.try_recv(),
+        Ok(RegFsEvent::KeyCopied { keys: 4, .. })
+    ));
+
+    // over what's there only with --force, which replaces it
+    backend.set_value(profiles.join("Small"), "Only", 4, vec![1, 0, 0, 0]);
+    assert!(matches!(
+        regfs.copy_key(&profiles.join("Small"), &work, false),
+        Err(CopyError::Exists(_))
+    ));
+    assert_eq!(export(&work), export(&default));
+    regfs
+        .copy_key(&profiles.join("Small"), &work, true)
+        .unwrap();
+    assert_eq!(export(&work), export(&profiles.join("Small")));
+
+    assert!(matches!(
+        regfs.copy_key(profiles, &default.join("Nested"), false),
+        Err(CopyError::IntoItself(_))
+    ));
+    assert!(matches!(
+        regfs.copy_key(&profiles.join("Missing"), &profiles.join("New"), false),
+        Err(CopyError::NotFound(_))
+    ));
+    assert!(matches!(
+        regfs.copy_key(&default, "HKEY_USERS".as_ref(), true),
+        Err(CopyError::Denied { .. })
+    ));
+    regfs.execute(crate::control::ControlCommand::ReadOnly(true));
+    assert!(matches!(
+        regfs.copy_key(&default, &profiles.join("New"), false),
+        Err(CopyError::Denied { .. })
+    ));
+
+    // without an overlay, in the registry itself
+    let options = RegFsOptions {
+        readonly: false,
+        ..Default::default()
+    };
+    let writable = RegFs::with_writer(&options, backend.clone(), Some(backend.clone()));
+    let live = profiles.join("Live");
+    assert_eq!(writable.copy_key(&default, &live, false).unwrap(), summary);
+    assert_eq!(
+        backend.read_value(&live.join("Fonts\\Mono\\Face")),
+        Some(b"Consolas".to_vec())
+    );
+    assert!(matches!(
+        writable.copy_key(&profiles.join("Small"), &live, false),
+        Err(CopyError::Exists(_))
+    ));
+    writable
+        .copy_key(&profiles.join("Small"), &live, true)
+        .unwrap();
+    assert!(!backend.does_key_exist(&live.join("Fonts")));
+    assert!(matches!(
+        writable.copy_key(&profiles.join("Missing"), &profiles.join("New"), false),
+        Err(CopyError::NotFound(_))
+    ));
+
+    let plain = RegFs::with_backend(&Default::default(), backend);
+    assert!(matches!(
+        plain.copy_key(&default, &work, false),
+        Err(CopyError::NoOverlay)
+    ));
+}
@@ -0,0 +1,189 @@
+use log::{info, warn};
+use prjfs::conv::WStrExt;
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+use winapi::shared::winerror::S_OK;
+
+use crate::hash;
+use crate::metrics::Metrics;
+use crate::ondisk;
+use crate::opcontext::OpContext;
+use crate::regfs::RegFs;
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DehydrateSummary {
+    pub dehydrated: usize,
+    pub reclaimed_bytes: u64,
+    pub modified: usize,
+    pub failed: usize,
+}
+
+impl fmt::Display for DehydrateSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "dehydrated {} ({} bytes), modified {}, failed {}",
+            self.dehydrated, self.reclaimed_bytes, self.modified, self.failed
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Decision {
+    Dehydrate,
+    Modified,
+    Gone,
+}
+
+pub fn decide(on_disk: &[u8], registry: Option<&[u8]>) -> Decision {
+    match registry {
+        None => Decision::Gone,
+        Some(registry) if registry == on_disk => Decision::Dehydrate,
+        Some(_) => Decision::Modified,
+    }
+}
+
+impl RegFs {
+    pub fn spawn_dehydrate(&self, path: PathBuf) -> JoinHandle<DehydrateSummary> {
+        let regfs = self.clone();
+
+        thread::spawn(move || {
+            info!(target: "dehydrate", "----> dehydrate: [{:?}]", path);
+            let summary = regfs.dehydrate(&path);
+            info!(target: "dehydrate", "<---- dehydrate: [{:?}] {}", path, summary);
+            summary
+        })
+    }
+
+    pub fn spawn_dehydrate_timer(&self, interval: Duration) {
+        let regfs = self.clone();
+
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let summary = regfs.dehydrate(Path::new(""));
+            info!(target: "dehydrate", "periodic dehydrate: {}", summary);
+        });
+    }
+
+    pub fn dehydrate(&self, path: &Path) -> DehydrateSummary {
+        let mut summary = DehydrateSummary::default();
+        self.dehydrate_dir(path, &mut summary);
+
+        Metrics::add(&self.metrics().dehydrated_files, summary.dehydrated as u64);
+        Metrics::add(&self.metrics().reclaimed_bytes, summary.reclaimed_bytes);
+        summary
+    }
+
+    fn dehydrate_dir(&self, relative: &Path, summary: &mut DehydrateSummary) {
+        let dir = match fs::read_dir(self.local_path(relative)) {
+            Ok(dir) => dir,
+            Err(_) => return,
+        };
+
+        for entry in dir.flatten() {
+            let child = relative.join(entry.file_name());
+            if self.synthetic(&child).is_some() {
+                continue;
+            }
+
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => self.dehydrate_dir(&child, summary),
+                Ok(_) => self.dehydrate_file(&child, summary),
+                Err(_) => {}
+            }
+        }
+    }
+
+    fn dehydrate_file(&self, relative: &Path, summary: &mut DehydrateSummary) {
+        let local = self.local_path(relative);
+        let state = ondisk::state(&local);
+        if state & ondisk::HYDRATED_STATES == 0 {
+            return;
+        }
+
+        let on_disk = match fs::read(&local) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                warn!(target: "dehydrate", "unable to read [{:?}]: {}", relative, e);
+                summary.failed += 1;
+                return;
+            }
+        };
+
+        let registry = self
+            .read_projected_value(relative, &OpContext::none())
+            .ok()
+            .flatten();
+        match decide(&on_disk, registry.as_deref()) {
+            Decision::Dehydrate => {}
+            Decision::Modified => {
+                info!(target: "dehydrate", "skipping locally modified [{:?}]", relative);
+                summary.modified += 1;
+                return;
+            }
+            // resync's job, not ours
+            Decision::Gone => return,
+        }
+
+        let mut flags = prjfs::sys::PRJ_UPDATE_NONE;
+        if state & ondisk::MODIFIED_STATES != 0 {
+            // the bytes were just verified to match the registry
+            flags |= prjfs::sys::PRJ_UPDATE_ALLOW_DIRTY_METADATA
+                | prjfs::sys::PRJ_UPDATE_ALLOW_DIRTY_DATA;
+        }
+
+        let mut failure = 0;
+        let result = unsafe {
+            self.projfs().delete_file(
+                self.context(),
+                relative.as_os_str().to_os_string().to_wstr().as_ptr(),
+                flags,
+                &mut failure,
+            )
+        };
+
+        if result != S_OK {
+            warn!(
+                target: "dehydrate",
+                "unable to dehydrate [{:?}]: {:08x} (0x{:x})",
+                relative, result, failure
+            );
+            summary.failed += 1;
+            return;
+        }
+        self.forget_content_id(relative);
+
+        // restore the placeholder right away so the entry doesn't flicker out of listings
+        if let Some(info) = self.placeholder_info(relative) {
+            let content_id = hash::content_id_of(&info);
+            let result = self.write_placeholder_info(
+                relative.as_os_str().to_os_string().to_wstr().as_ptr(),
+                info,
+            );
+            if result == S_OK {
+                self.record_content_id(relative, content_id);
+            }
+        }
+
+        summary.dehydrated += 1;
+        summary.reclaimed_bytes += on_disk.len() as u64;
+    }
+}
+
+#[test]
+fn test_decide() {
+    assert_eq!(decide(b"abc", Some(b"abc")), Decision::Dehydrate);
+    assert_eq!(decide(b"", Some(b"")), Decision::Dehydrate);
+    assert_eq!(decide(b"abc", None), Decision::Gone);
+}
+
+#[test]
+fn test_modified_file_is_not_dehydrated() {
+    assert_eq!(decide(b"abd", Some(b"abc")), Decision::Modified);
+    assert_eq!(decide(b"abc\r\n", Some(b"abc")), Decision::Modified);
+    assert_eq!(decide(b"", Some(b"abc")), Decision::Modified);
+}
@@ -0,0 +1,654 @@
+use anyhow::bail;
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::backend::RegistryBackend;
+use crate::memory::MemoryBackend;
+use crate::opcontext::{Cancelled, OpContext};
+use crate::regfile::RegFile;
+use crate::regop::{paths, RegEntires, RegEntry};
+use crate::watch::Watchers;
+
+// what a differing entry's name starts with
+pub const ADDED: &str = "+ ";
+pub const REMOVED: &str = "- ";
+pub const CHANGED: &str = "~ ";
+
+// how long whether a subtree differs is taken for known, unless its key was written to since;
+// a change further down shows after this long at most
+const KNOWN_FOR: Duration = Duration::from_secs(2);
+// past this many, the answers that are too old go
+const KNOWN_MAX: usize = 10_000;
+
+// where a projected key's entries come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Side {
+    // compared: only the differences show
+    Both,
+    // under an added key, everything is added
+    Live,
+    // under a removed key, everything is removed and read from the baseline
+    Baseline,
+}
+
+fn fold(path: &Path) -> Vec<String> {
+    paths::components(path)
+        .iter()
+        .map(|part| part.to_string_lossy().to_lowercase())
+        .collect()
+}
+
+fn prefixed(prefix: &str, name: &OsStr) -> OsString {
+    let mut prefixed = OsString::from(prefix);
+    prefixed.push(name);
+    prefixed
+}
+
+fn strip_status(name: &OsStr) -> (Option<&'static str>, OsString) {
+    let text = name.to_string_lossy();
+    [ADDED, REMOVED, CHANGED]
+        .iter()
+        .find_map(|status| Some((Some(*status), OsString::from(text.strip_prefix(status)?))))
+        .unwrap_or((None, name.into()))
+}
+
+// an export says what was there; a `[-key]` or `"name"=-` says what wasn't, which can't be
+// told apart from what was never exported, so such a file is refused rather than half read
+pub fn check_baseline(baseline: &RegFile) -> anyhow::Result<()> {
+    for key in &baseline.keys {
+        if key.delete {
+            bail!(
+                "--diff-baseline: [-{}] deletes a key, a baseline can only hold what's there",
+                key.path.display()
+            );
+        }
+        if let Some(value) = key.values.iter().find(|value| value.data.is_none()) {
+            bail!(
+                "--diff-baseline: [{}] deletes the value {:?}, a baseline can only hold what's there",
+                key.path.display(),
+                value.name
+            );
+        }
+    }
+    Ok(())
+}
+
+// whether a subtree differs, with the key's last write time and when it was found out
+struct Known {
+    differs: bool,
+    written: Option<i64>,
+    at: Instant,
+}
+
+// --diff-baseline: only what differs from a .reg file. The keys in the file (and
+// everything under them) are compared, the rest of the registry is left out.
+pub struct DiffBackend {
+    live: Arc<dyn RegistryBackend>,
+    baseline: MemoryBackend,
+    roots: Vec<Vec<String>>,
+    // by case-folded path, so a listing and the lookups after it don't compare it all again
+    known: Mutex<HashMap<String, Known>>,
+}
+
+impl DiffBackend {
+    pub fn new(live: Arc<dyn RegistryBackend>, baseline: &RegFile) -> Self {
+        let memory = MemoryBackend::new();
+        baseline.apply_to(&memory);
+
+        let mut roots: Vec<Vec<String>> = baseline
+            .keys
+            .iter()
+            .filter(|key| !key.delete)
+            .map(|key| fold(&key.path))
+            .collect();
+        roots.sort();
+        // a key exported along with its parent is compared as part of the parent
+        roots.dedup_by(|key, parent| key.starts_with(parent));
+
+        DiffBackend {
+            live,
+            baseline: memory,
+            roots,
+            known: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn in_scope(&self, real: &Path) -> bool {
+        let real = fold(real);
+        self.roots.iter().any(|root| real.starts_with(root))
+    }
+
+    fn leads_to_root(&self, real: &Path) -> bool {
+        let real = fold(real);
+        self.roots
+            .iter()
+            .any(|root| root.len() > real.len() && root.starts_with(&real))
+    }
+
+    // the registry path behind a projected one, and which side it's on
+    fn resolve(&self, projected: &Path) -> (PathBuf, Side) {
+        let mut real = PathBuf::new();
+        let mut side = Side::Both;
+
+        for part in paths::components(projected) {
+            let (status, name) = strip_status(&part);
+            side = match (side, status) {
+                (Side::Both, Some(ADDED)) => Side::Live,
+                (Side::Both, Some(REMOVED)) => Side::Baseline,
+                (side, _) => side,
+            };
+            real.push(name);
+        }
+        (real, side)
+    }
+
+    fn one_side(
+        &self,
+        backend: &dyn RegistryBackend,
+        prefix: &str,
+        real: &Path,
+        ctx: &OpContext,
+    ) -> Result<Option<RegEntires>, Cancelled> {
+        let mut entries = match backend.enumerate_key_ctx(real.into(), ctx)? {
+            Some(entries) => entries,
+            None => return Ok(None),
+        };
+        for entry in entries.subkeys.iter_mut().chain(entries.values.iter_mut()) {
+            entry.name = prefixed(prefix, &entry.name);
+        }
+        Ok(Some(entries))
+    }
+
+    // whether anything at or under `real` differs
+    fn differs(&self, real: &Path, ctx: &OpContext) -> Result<bool, Cancelled> {
+        let key = fold(real).join("\\");
+        let written = self.live.key_last_write_time(real);
+        if let Ok(known) = self.known.lock() {
+            match known.get(&key) {
+                Some(known) if known.written == written && known.at.elapsed() < KNOWN_FOR => {
+                    return Ok(known.differs)
+                }
+                _ => {}
+            }
+        }
+
+        let differs = match self.in_scope(real) {
+            true => self.subtree_differs(real, ctx)?,
+            false => self
+                .path_to_roots(real, ctx)?
+                .map_or(false, |entries| !entries.subkeys.is_empty()),
+        };
+        if let Ok(mut known) = self.known.lock() {
+            if known.len() >= KNOWN_MAX {
+                known.retain(|_, known| known.at.elapsed() < KNOWN_FOR);
+            }
+            known.insert(
+                key,
+                Known {
+                    differs,
+                    written,
+                    at: Instant::now(),
+                },
+            );
+        }
+        Ok(differs)
+    }
+
+    // the same as a non-empty compare, but done at the first difference: the values, then
+    // the keys on one side only, and only then what's under the keys on both
+    fn subtree_differs(&self, real: &Path, ctx: &OpContext) -> Result<bool, Cancelled> {
+        let live = self
+            .live
+            .enumerate_key_ctx(real.into(), ctx)?
+            .unwrap_or_default();
+        let baseline = self.baseline.enumerate_key(real.into()).unwrap_or_default();
+
+        for (name, pair) in pair(live.values, baseline.values) {
+            match pair {
+                (Some(_), Some(_)) if !self.value_differs(&real.join(&name), ctx)? => {}
+                (None, None) => {}
+                _ => return Ok(true),
+            }
+        }
+        let mut both = Vec::new();
+        for (name, pair) in pair(live.subkeys, baseline.subkeys) {
+            match pair {
+                (Some(_), Some(_)) => both.push(name),
+                (None, None) => {}
+                _ => return Ok(true),
+            }
+        }
+        for name in both {
+            if self.differs(&real.join(name), ctx)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn value_differs(&self, path: &Path, ctx: &OpContext) -> Result<bool, Cancelled> {
+        Ok(self.live.read_typed_value_ctx(path, ctx)?
+            != self.baseline.read_typed_value_ctx(path, ctx)?)
+    }
+
+    // the differences directly under `real`
+    fn compare(&self, real: &Path, ctx: &OpContext) -> Result<Option<RegEntires>, Cancelled> {
+        if !self.in_scope(real) {
+            return self.path_to_roots(real, ctx);
+        }
+
+        let live = self
+            .live
+            .enumerate_key_ctx(real.into(), ctx)?
+            .unwrap_or_default();
+        let baseline = self.baseline.enumerate_key(real.into()).unwrap_or_default();
+        let mut entries = RegEntires {
+            partial: live.partial,
+            failed: live.failed,
+            errors: live.errors,
+            ..Default::default()
+        };
+
+        for (name, (live, baseline)) in pair(live.subkeys, baseline.subkeys) {
+            match (live, baseline) {
+                (Some(live), None) => entries.subkeys.push(RegEntry {
+                    name: prefixed(ADDED, &live.name),
+                    ..live
+                }),
+                (None, Some(baseline)) => entries.subkeys.push(RegEntry {
+                    name: prefixed(REMOVED, &baseline.name),
+                    ..baseline
+                }),
+                (Some(live), Some(_)) => {
+                    if self.differs(&real.join(&name), ctx)? {
+                        entries.subkeys.push(live)
+                    }
+                }
+                (None, None) => {}
+            }
+        }
+
+        for (name, (live, baseline)) in pair(live.values, baseline.values) {
+            match (live, baseline) {
+                (Some(live), None) => entries.values.push(RegEntry {
+                    name: prefixed(ADDED, &live.name),
+                    ..live
+                }),
+                (None, Some(baseline)) => entries.values.push(RegEntry {
+                    name: prefixed(REMOVED, &baseline.name),
+                    ..baseline
+                }),
+                (Some(live), Some(_)) => {
+                    if self.value_differs(&real.join(&name), ctx)? {
+                        entries.values.push(RegEntry {
+                            name: prefixed(CHANGED, &live.name),
+                            ..live
+                        })
+                    }
+                }
+                (None, None) => {}
+            }
+        }
+
+        Ok(Some(entries))
+    }
+
+    // above the compared keys: only the way down to them, and only where something differs
+    fn path_to_roots(&self, real: &Path, ctx: &OpContext) -> Result<Option<RegEntires>, Cancelled> {
+        if !self.leads_to_root(real) {
+            return Ok(None);
+        }
+
+        let mut entries = RegEntires::default();
+        let baseline = self.baseline.enumerate_key(real.into()).unwrap_or_default();
+        for subkey in baseline.subkeys {
+            let child = real.join(&subkey.name);
+            if !self.in_scope(&child) && !self.leads_to_root(&child) {
+                continue;
+            }
+
+            if self.in_scope(&child) && !self.live.does_key_exist(&child) {
+                entries.subkeys.push(RegEntry {
+                    name: prefixed(REMOVED, &subkey.name),
+                    ..subkey
+                });
+            } else if self.differs(&child, ctx)? {
+                entries.subkeys.push(RegEntry {
+                    last_write_time: self.live.key_last_write_time(&child).unwrap_or(0),
+                    ..subkey
+                });
+            }
+        }
+        Ok(Some(entries))
+    }
+}
+
+// entries of both sides by case-folded name
+fn pair(
+    live: Vec<RegEntry>,
+    baseline: Vec<RegEntry>,
+) -> BTreeMap<String, (Option<RegEntry>, Option<RegEntry>)> {
+    let mut pairs: BTreeMap<String, (Option<RegEntry>, Option<RegEntry>)> = BTreeMap::new();
+    for entry in live {
+        let name = entry.name.to_string_lossy().to_lowercase();
+        pairs.entry(name).or_default().0 = Some(entry);
+    }
+    for entry in baseline {
+        let name = entry.name.to_string_lossy().to_lowercase();
+        pairs.entry(name).or_default().1 = Some(entry);
+    }
+    pairs
+}
+
+impl RegistryBackend for DiffBackend {
+    fn enumerate_key_ctx(
+        &self,
+        path: OsString,
+        ctx: &OpContext,
+    ) -> Result<Option<RegEntires>, Cancelled> {
+        match self.resolve(path.as_ref()) {
+            (real, Side::Both) => self.compare(&real, ctx),
+            (real, Side::Live) => self.one_side(self.live.as_ref(), ADDED, &real, ctx),
+            (real, Side::Baseline) => self.one_side(&self.baseline, REMOVED, &real, ctx),
+        }
+    }
+
+    fn read_typed_value_ctx(
+        &self,
+        path: &Path,
+        ctx: &OpContext,
+    ) -> Result<Option<(u32, Vec<u8>)>, Cancelled> {
+        let name = match path.file_name() {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+        let (key, side) = match path.parent() {
+            Some(parent) => self.resolve(parent),
+            None => return Ok(None),
+        };
+        let (status, name) = strip_status(name);
+        let real = key.join(name);
+
+        match (side, status) {
+            (Side::Live, _) => self.live.read_typed_value_ctx(&real, ctx),
+            (Side::Baseline, _) => self.baseline.read_typed_value_ctx(&real, ctx),
+            // only the way the listing shows it
+            (Side::Both, Some(status)) if self.in_scope(&key) => {
+                let live = self.live.read_typed_value_ctx(&real, ctx)?;
+                let baseline = self.baseline.read_typed_value_ctx(&real, ctx)?;
+                Ok(match (status, live, baseline) {
+                    (ADDED, Some(live), None) => Some(live),
+                    (REMOVED, None, Some(baseline)) => Some(baseline),
+                    (CHANGED, Some(live), Some(baseline)) if live != baseline => Some(live),
+                    _ => None,
+                })
+            }
+            (Side::Both, _) => Ok(None),
+        }
+    }
+
+    fn read_all_values(&self, path: &Path) -> Option<Vec<(OsString, u32, Vec<u8>)>> {
+        let entries = self.enumerate_key(path.into())?;
+        Some(
+            entries
+                .values
+                .into_iter()
+                .filter_map(|value| {
+                    let (vtype, data) = self
+                        .read_typed_value_ctx(&path.join(&value.name), &OpContext::none())
+                        .ok()??;
+                    Some((value.name, vtype, data))
+                })
+                .collect(),
+        )
+    }
+
+    // whether its parent would list it, decided the same way enumeration does but without
+    // comparing the parent's other subkeys
+    fn does_key_exist(&self, path: &Path) -> bool {
+        let (name, parent) = match (path.file_name(), path.parent()) {
+            (Some(name), Some(parent)) => (name, parent),
+            _ => return true,
+        };
+        let (key, side) = self.resolve(parent);
+        let (status, name) = strip_status(name);
+        let real = key.join(name);
+        let live = || self.live.does_key_exist(&real);
+        let baseline = || self.baseline.does_key_exist(&real);
+
+        match (side, status) {
+            (Side::Live, Some(ADDED)) => live(),
+            (Side::Baseline, Some(REMOVED)) => baseline(),
+            (Side::Both, Some(ADDED)) if self.in_scope(&key) => live() && !baseline(),
+            (Side::Both, Some(REMOVED)) if self.in_scope(&real) => baseline() && !live(),
+            (Side::Both, None) => {
+                let in_scope = self.in_scope(&real);
+                (in_scope || self.leads_to_root(&real))
+                    && baseline()
+                    && (!in_scope || live())
+                    && self.differs(&real, &OpContext::none()).unwrap_or(false)
+            }
+            _ => false,
+        }
+    }
+
+    fn key_last_write_time(&self, path: &Path) -> Option<i64> {
+        if !self.does_key_exist(path) {
+            return None;
+        }
+        match self.resolve(path) {
+            (real, Side::Baseline) => self.baseline.key_last_write_time(&real),
+            (real, _) => Some(self.live.key_last_write_time(&real).unwrap_or(0)),
+        }
+    }
+
+    fn watchers(&self) -> Option<&Watchers> {
+        self.live.watchers()
+    }
+
+    fn writable(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+fn diff_fixture() -> (Arc<MemoryBackend>, DiffBackend) {
+    use crate::regfile::{self, string_data};
+    use winapi::um::winnt::{REG_DWORD, REG_SZ};
+
+    let baseline = regfile::parse(
+        "Windows Registry Editor Version 5.00
+
+[HKEY_CURRENT_USER\\Software\\Scratch]
+\"Same\"=\"x\"
+\"Changed\"=dword:00000001
+\"Removed\"=\"gone\"
+
+[HKEY_CURRENT_USER\\Software\\Scratch\\Unchanged]
+\"Same\"=\"x\"
+
+[HKEY_CURRENT_USER\\Software\\Scratch\\Dropped]
+\"Old\"=\"old\"
+",
+    )
+    .unwrap();
+
+    let live = Arc::new(MemoryBackend::new());
+    let scratch = "HKEY_CURRENT_USER\\Software\\Scratch";
+    live.set_value(scratch, "Same", REG_SZ, string_data("x"));
+    live.set_value(scratch, "Changed", REG_DWORD, vec![2, 0, 0, 0]);
+    live.set_value(scratch, "Added", REG_SZ, string_data("new"));
+    live.set_value(
+        "HKEY_CURRENT_USER\\Software\\Scratch\\Unchanged",
+        "Same",
+        REG_SZ,
+        string_data("x"),
+    );
+    live.set_value(
+        "HKEY_CURRENT_USER\\Software\\Scratch\\Fresh",
+        "New",
+        REG_SZ,
+        string_data("y"),
+    );
+    // outside the baseline, never compared
+    live.add_key("HKEY_CURRENT_USER\\Software\\Elsewhere");
+
+    let diff = DiffBackend::new(live.clone(), &baseline);
+    (live, diff)
+}
+
+#[cfg(test)]
+fn names(entries: &[RegEntry]) -> Vec<String> {
+    let mut names: Vec<_> = entries
+        .iter()
+        .map(|entry| entry.name.to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn test_diff_projection() {
+    use crate::regfile::string_data;
+
+    let (_live, diff) = diff_fixture();
+
+    let root = diff.enumerate_key("".into()).unwrap();
+    assert_eq!(names(&root.subkeys), vec!["HKEY_CURRENT_USER"]);
+    let software = diff
+        .enumerate_key("HKEY_CURRENT_USER\\Software".into())
+        .unwrap();
+    assert_eq!(names(&software.subkeys), vec!["Scratch"]);
+
+    let scratch = diff
+        .enumerate_key("HKEY_CURRENT_USER\\Software\\Scratch".into())
+        .unwrap();
+    assert_eq!(names(&scratch.subkeys), vec!["+ Fresh", "- Dropped"]);
+    assert_eq!(
+        names(&scratch.values),
+        vec!["+ Added", "- Removed", "~ Changed"]
+    );
+
+    let read = |path: &str| diff.read_value(path.as_ref());
+    assert_eq!(
+        read("HKEY_CURRENT_USER\\Software\\Scratch\\+ Added"),
+        Some(string_data("new"))
+    );
+    // removed entries come from the baseline
+    assert_eq!(
+        read("HKEY_CURRENT_USER\\Software\\Scratch\\- Removed"),
+        Some(string_data("gone"))
+    );
+    assert_eq!(
+        read("HKEY_CURRENT_USER\\Software\\Scratch\\~ Changed"),
+        Some(vec![2, 0, 0, 0])
+    );
+    assert_eq!(read("HKEY_CURRENT_USER\\Software\\Scratch\\Same"), None);
+    assert_eq!(read("HKEY_CURRENT_USER\\Software\\Scratch\\~ Same"), None);
+    assert_eq!(
+        read("HKEY_CURRENT_USER\\Software\\Scratch\\- Dropped\\- Old"),
+        Some(string_data("old"))
+    );
+    assert_eq!(
+        read("HKEY_CURRENT_USER\\Software\\Scratch\\+ Fresh\\+ New"),
+        Some(string_data("y"))
+    );
+
+    assert!(diff.does_key_exist("HKEY_CURRENT_USER\\Software\\Scratch\\- Dropped".as_ref()));
+    assert!(!diff.does_key_exist("HKEY_CURRENT_USER\\Software\\Scratch\\Unchanged".as_ref()));
+    assert!(!diff.does_key_exist("HKEY_CURRENT_USER\\Software\\Elsewhere".as_ref()));
+}
+
+#[test]
+fn test_diff_follows_the_live_registry() {
+    use crate::regfile::string_data;
+    use winapi::um::winnt::REG_SZ;
+
+    let (live, diff) = diff_fixture();
+    live.set_value(
+        "HKEY_CURRENT_USER\\Software\\Scratch",
+        "Removed",
+        REG_SZ,
+        string_data("gone"),
+    );
+
+    let scratch = diff
+        .enumerate_key("HKEY_CURRENT_USER\\Software\\Scratch".into())
+        .unwrap();
+    assert_eq!(names(&scratch.values), vec!["+ Added", "~ Changed"]);
+}
+
+#[test]
+fn test_diff_exists_like_listed() {
+    let (_live, diff) = diff_fixture();
+
+    let listed = |parent: &str| {
+        let entries = diff.enumerate_key(parent.into()).unwrap();
+        entries
+            .subkeys
+            .iter()
+            .map(|subkey| format!("{}\\{}", parent, subkey.name.to_string_lossy()))
+            .collect::<Vec<_>>()
+    };
+    for path in listed("HKEY_CURRENT_USER\\Software\\Scratch")
+        .into_iter()
+        .chain(listed("HKEY_CURRENT_USER\\Software"))
+    {
+        assert!(diff.does_key_exist(path.as_ref()), "{}", path);
+    }
+
+    let exists = |path: &str| diff.does_key_exist(path.as_ref());
+    assert!(exists("HKEY_CURRENT_USER"));
+    assert!(exists("HKEY_CURRENT_USER\\Software\\Scratch\\+ Fresh"));
+    assert!(!exists("HKEY_CURRENT_USER\\Software\\Scratch\\Fresh"));
+    assert!(!exists("HKEY_CURRENT_USER\\Software\\Scratch\\- Fresh"));
+    assert!(!exists("HKEY_CURRENT_USER\\Software\\Scratch\\+ Dropped"));
+    assert!(!exists("HKEY_CURRENT_USER\\Software\\Scratch\\+ Unchanged"));
+    assert!(!exists("HKEY_CURRENT_USER\\Software\\+ Elsewhere"));
+}
+
+#[test]
+fn test_diff_knows_per_key() {
+    use crate::regfile::string_data;
+    use winapi::um::winnt::REG_SZ;
+
+    let (live, diff) = diff_fixture();
+    let unchanged = "HKEY_CURRENT_USER\\Software\\Scratch\\Unchanged";
+    assert!(!diff.does_key_exist(unchanged.as_ref()));
+
+    // the answer is kept for as long as the key isn't written to
+    live.set_value(unchanged, "Same", REG_SZ, string_data("y"));
+    assert!(!diff.does_key_exist(unchanged.as_ref()));
+
+    live.set_last_write_time(unchanged, 42);
+    assert!(diff.does_key_exist(unchanged.as_ref()));
+    let scratch = diff
+        .enumerate_key("HKEY_CURRENT_USER\\Software\\Scratch".into())
+        .unwrap();
+    assert_eq!(
+        names(&scratch.subkeys),
+        vec!["+ Fresh", "- Dropped", "Unchanged"]
+    );
+}
+
+#[test]
+fn test_check_baseline() {
+    use crate::regfile;
+
+    let parse = |text: &str| {
+        regfile::parse(&format!("Windows Registry Editor Version 5.00\n\n{}", text)).unwrap()
+    };
+
+    assert!(check_baseline(&parse("[HKEY_CURRENT_USER\\Software\\X]\n\"v\"=\"x\"\n")).is_ok());
+    let error = check_baseline(&parse("[-HKEY_CURRENT_USER\\Software\\X]\n")).unwrap_err();
+    assert!(error
+        .to_string()
+        .contains("[-HKEY_CURRENT_USER\\Software\\X]"));
+    let error = check_baseline(&parse("[HKEY_CURRENT_USER\\Software\\X]\n\"v\"=-\n")).unwrap_err();
+    assert!(error.to_string().contains("deletes the value"));
+}
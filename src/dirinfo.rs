@@ -1,38 +1,162 @@
-use prjfs::conv::{WStr, WStrExt};
+use prjfs::sys::PRJ_FILE_BASIC_INFO;
+use serde_json::{json, Value};
 use std::{
-    cmp::Ordering,
-    ffi::OsString,
+    ffi::{OsStr, OsString},
     path::{Path, PathBuf},
+    time::Instant,
 };
+use winapi::{
+    shared::winerror::{ERROR_INSUFFICIENT_BUFFER, HRESULT_FROM_WIN32, S_OK},
+    um::winnt::HRESULT,
+};
+
+use crate::prj_compat::PrjApi;
+use crate::times;
+use crate::trace::TraceEntry;
+
+// what happened to one entry handed to a DirEntrySink
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SinkResult {
+    Added,
+    // the rest goes in the next get_dir_enum
+    Full,
+    // not even one entry fits, ProjFS has to come back with a bigger buffer
+    FirstEntryTooLarge,
+    Error(HRESULT),
+}
 
-#[derive(Debug)]
-struct DirEntry {
+impl SinkResult {
+    // what PrjFillDirEntryBuffer answered, `added` entries into the buffer
+    pub fn of(hr: HRESULT, added: usize) -> SinkResult {
+        match hr {
+            S_OK => SinkResult::Added,
+            hr if hr == HRESULT_FROM_WIN32(ERROR_INSUFFICIENT_BUFFER) => match added {
+                0 => SinkResult::FirstEntryTooLarge,
+                _ => SinkResult::Full,
+            },
+            hr => SinkResult::Error(hr),
+        }
+    }
+}
+
+// where a listing goes: the ProjFS buffer of one get_dir_enum, or a fake in tests
+pub trait DirEntrySink {
+    fn add(&mut self, name: &OsStr, info: &mut PRJ_FILE_BASIC_INFO) -> SinkResult;
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
     filename: OsString,
     is_directory: bool,
     size: i64,
+    time: i64,
+    // FILE_ATTRIBUTE_*, none unless set_attributes gave some
+    attributes: u32,
+}
+
+impl DirEntry {
+    pub fn filename(&self) -> &OsStr {
+        &self.filename
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.is_directory
+    }
 }
 
 #[derive(Default, Debug)]
 pub struct DirInfo {
-    #[allow(dead_code)]
     path: PathBuf,
     index: usize,
     filled: bool,
     entries: Vec<DirEntry>,
+    // the expression the listing was filled for, kept for `dump`
+    search: Option<OsString>,
+    started: Option<Instant>,
+    // the key's last write time, when start_dir_enum already looked it up
+    key_time: Option<i64>,
+    // the key was gone by the first fill, so it was listed empty; a restart scan looks again
+    vanished: bool,
+    // --enum-cache: the key's last write time from just before it was listed, what the
+    // listing is kept with; and whether the entries are the ones kept from an earlier one
+    listed_time: Option<i64>,
+    cached: bool,
+    // nothing was added since the entries were last sorted
+    sorted: bool,
 }
 
 impl DirInfo {
     pub fn new<T: AsRef<Path>>(path: T) -> Self {
         DirInfo {
             path: path.as_ref().to_owned(),
+            started: Some(Instant::now()),
             ..Default::default()
         }
     }
 
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn with_key_time(self, time: i64) -> Self {
+        DirInfo {
+            key_time: Some(time),
+            ..self
+        }
+    }
+
+    pub fn key_time(&self) -> Option<i64> {
+        self.key_time
+    }
+
+    pub fn listed_time(&self) -> Option<i64> {
+        self.listed_time
+    }
+
+    pub fn set_listed_time(&mut self, time: i64) {
+        self.listed_time = Some(time);
+    }
+
+    pub fn cached(&self) -> bool {
+        self.cached
+    }
+
+    pub fn vanished(&self) -> bool {
+        self.vanished
+    }
+
+    pub fn mark_vanished(&mut self) {
+        self.vanished = true;
+    }
+
     pub fn reset(&mut self) {
         self.index = 0;
         self.filled = false;
         self.entries = Vec::new();
+        self.search = None;
+        // a restart scan may well be about a key that changed since
+        self.key_time = None;
+        self.listed_time = None;
+        self.cached = false;
+        self.sorted = false;
+    }
+
+    pub fn capture_search(&mut self, search_expression: &OsStr) {
+        self.search = Some(search_expression.to_owned());
+    }
+
+    // names and counts only, never an entry, so a dump can't leak what a listing showed
+    pub fn to_json(&self) -> Value {
+        json!({
+            "path": self.path.to_string_lossy(),
+            "filled": self.filled,
+            "vanished": self.vanished,
+            "cached": self.cached,
+            "index": self.index,
+            "entries": self.entries.len(),
+            "search": self.search.as_ref().map(|search| search.to_string_lossy()),
+            "age_ms": self.started.map_or(0, |started| started.elapsed().as_millis() as u64),
+        })
     }
 
     pub fn filled(&self) -> bool {
@@ -43,56 +167,292 @@ impl DirInfo {
         self.index < self.entries.len()
     }
 
-    pub fn current_file_name(&self) -> WStr {
-        self.entries[self.index].filename.to_wstr()
+    pub fn current_file_name(&self) -> &OsStr {
+        &self.entries[self.index].filename
     }
 
-    pub fn current_basic_info(&self) -> prjfs::sys::PRJ_FILE_BASIC_INFO {
-        let mut info = prjfs::sys::PRJ_FILE_BASIC_INFO::default();
+    pub fn current_basic_info(&self) -> PRJ_FILE_BASIC_INFO {
+        let mut info = PRJ_FILE_BASIC_INFO::default();
         info.IsDirectory = self.entries[self.index].is_directory as u8;
         info.FileSize = self.entries[self.index].size;
+        times::set_times(&mut info, self.entries[self.index].time);
+        info.FileAttributes = self.entries[self.index].attributes;
         info
     }
 
+    pub fn current_entry(&self) -> TraceEntry {
+        let entry = &self.entries[self.index];
+        TraceEntry {
+            name: entry.filename.to_string_lossy().into(),
+            is_directory: entry.is_directory,
+            size: entry.size,
+        }
+    }
+
+    // hands the entries from the current one on to the sink until it is full (or after one
+    // entry with `single`), what was added goes to `served` when tracing
+    pub fn fill(
+        &mut self,
+        sink: &mut dyn DirEntrySink,
+        single: bool,
+        mut served: Option<&mut Vec<TraceEntry>>,
+    ) -> HRESULT {
+        while self.current_is_valid() {
+            let mut info = self.current_basic_info();
+            match sink.add(self.current_file_name(), &mut info) {
+                SinkResult::Added => {}
+                SinkResult::Full => break,
+                SinkResult::FirstEntryTooLarge => {
+                    return HRESULT_FROM_WIN32(ERROR_INSUFFICIENT_BUFFER)
+                }
+                SinkResult::Error(hr) => return hr,
+            }
+
+            if let Some(served) = served.as_deref_mut() {
+                served.push(self.current_entry());
+            }
+            self.move_next();
+            if single {
+                break;
+            }
+        }
+        S_OK
+    }
+
     pub fn move_next(&mut self) -> bool {
         self.index += 1;
         self.index < self.entries.len()
     }
 
-    pub fn fill_dir_entry(&mut self, name: OsString) {
-        self.fill_item_entry(name, 0, true);
+    pub fn reserve(&mut self, additional: usize) {
+        self.entries.reserve(additional);
+    }
+
+    pub fn fill_dir_entry(&mut self, name: OsString, time: i64) {
+        self.fill_item_entry(name, 0, time, true);
     }
 
-    pub fn fill_file_entry(&mut self, name: OsString, size: i64) {
-        self.fill_item_entry(name, size, false);
+    pub fn fill_file_entry(&mut self, name: OsString, size: i64, time: i64) {
+        self.fill_item_entry(name, size, time, false);
     }
 
-    fn fill_item_entry(&mut self, filename: OsString, size: i64, is_directory: bool) {
+    // a listing kept by --enum-cache, already sorted
+    pub fn fill_cached(&mut self, entries: Vec<DirEntry>) {
+        self.entries = entries;
+        self.cached = true;
+        self.sorted = true;
+    }
+
+    // the attributes of every entry, by name
+    pub fn set_attributes<F: FnMut(&OsStr) -> u32>(&mut self, mut attributes: F) {
+        for entry in &mut self.entries {
+            entry.attributes = attributes(&entry.filename);
+        }
+    }
+
+    pub fn entries(&self) -> &[DirEntry] {
+        &self.entries
+    }
+
+    pub fn into_entries(self) -> Vec<DirEntry> {
+        self.entries
+    }
+
+    fn fill_item_entry(&mut self, filename: OsString, size: i64, time: i64, is_directory: bool) {
+        self.sorted = false;
         self.entries.push(DirEntry {
             filename,
             size,
+            time,
             is_directory,
+            attributes: 0,
         });
     }
 
-    pub fn sort_entries_and_mark_filled(&mut self) {
+    // keeps the first `max` entries, the ones a sorted listing starts with; how many went
+    pub fn truncate(&mut self, max: usize) -> usize {
+        let dropped = self.entries.len().saturating_sub(max);
+        self.entries.truncate(max);
+        dropped
+    }
+
+    pub fn sort_entries_and_mark_filled(&mut self, api: &dyn PrjApi) {
         self.filled = true;
 
-        self.entries.sort_by(|a, b| {
-            let result = unsafe {
-                prjfs::sys::PrjFileNameCompare(
-                    a.filename.to_wstr().as_ptr(),
-                    b.filename.to_wstr().as_ptr(),
-                )
-            };
-
-            if result < 0 {
-                Ordering::Less
-            } else if result == 0 {
-                Ordering::Equal
-            } else {
-                Ordering::Greater
-            }
-        });
+        if !self.sorted {
+            self.entries
+                .sort_by(|a, b| api.file_name_compare(&a.filename, &b.filename));
+            self.sorted = true;
+        }
+    }
+}
+
+// takes `capacity` entries per get_dir_enum, like a ProjFS buffer of that size
+#[cfg(test)]
+struct FakeSink {
+    capacity: usize,
+    added: Vec<OsString>,
+    fail: Option<HRESULT>,
+}
+
+#[cfg(test)]
+impl FakeSink {
+    fn new(capacity: usize) -> Self {
+        FakeSink {
+            capacity,
+            added: Vec::new(),
+            fail: None,
+        }
     }
+
+    // the next get_dir_enum, with a new buffer
+    fn take(&mut self) -> Vec<OsString> {
+        std::mem::take(&mut self.added)
+    }
+}
+
+#[cfg(test)]
+impl DirEntrySink for FakeSink {
+    fn add(&mut self, name: &OsStr, _info: &mut PRJ_FILE_BASIC_INFO) -> SinkResult {
+        if let Some(hr) = self.fail {
+            return SinkResult::Error(hr);
+        }
+        let hr = match self.added.len() < self.capacity {
+            true => S_OK,
+            false => HRESULT_FROM_WIN32(ERROR_INSUFFICIENT_BUFFER),
+        };
+        let result = SinkResult::of(hr, self.added.len());
+        if result == SinkResult::Added {
+            self.added.push(name.to_owned());
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+fn listing(names: &[&str]) -> DirInfo {
+    let mut dirinfo = DirInfo::new("HKEY_CURRENT_USER\\App");
+    for name in names {
+        dirinfo.fill_file_entry(name.into(), 4, 0);
+    }
+    dirinfo.filled = true;
+    dirinfo
+}
+
+#[test]
+fn test_sink_result() {
+    let full = HRESULT_FROM_WIN32(ERROR_INSUFFICIENT_BUFFER);
+    assert_eq!(SinkResult::of(S_OK, 0), SinkResult::Added);
+    assert_eq!(SinkResult::of(full, 0), SinkResult::FirstEntryTooLarge);
+    assert_eq!(SinkResult::of(full, 3), SinkResult::Full);
+    assert_eq!(
+        SinkResult::of(winapi::shared::winerror::E_INVALIDARG, 1),
+        SinkResult::Error(winapi::shared::winerror::E_INVALIDARG)
+    );
+}
+
+#[test]
+fn test_fill_across_buffers() {
+    let mut dirinfo = listing(&["a", "b", "c", "d", "e"]);
+    let mut sink = FakeSink::new(2);
+    let mut served = Vec::new();
+
+    for expected in [&["a", "b"][..], &["c", "d"], &["e"], &[]] {
+        assert_eq!(dirinfo.fill(&mut sink, false, Some(&mut served)), S_OK);
+        assert_eq!(sink.take(), expected);
+    }
+    // what was traced is what went in the buffers
+    let served: Vec<_> = served.iter().map(|entry| entry.name.as_str()).collect();
+    assert_eq!(served, ["a", "b", "c", "d", "e"]);
+}
+
+#[test]
+fn test_fill_single_entry() {
+    let mut dirinfo = listing(&["a", "b", "c"]);
+    let mut sink = FakeSink::new(10);
+
+    for expected in ["a", "b", "c"] {
+        assert_eq!(dirinfo.fill(&mut sink, true, None), S_OK);
+        assert_eq!(sink.take(), [expected]);
+    }
+    assert_eq!(dirinfo.fill(&mut sink, true, None), S_OK);
+    assert!(sink.take().is_empty());
+}
+
+#[test]
+fn test_fill_first_entry_never_fits() {
+    let mut dirinfo = listing(&["a", "b"]);
+    let mut sink = FakeSink::new(0);
+
+    // ProjFS asks again with a bigger buffer, the entry must still be there
+    for _ in 0..3 {
+        assert_eq!(
+            dirinfo.fill(&mut sink, false, None),
+            HRESULT_FROM_WIN32(ERROR_INSUFFICIENT_BUFFER)
+        );
+        assert!(sink.take().is_empty());
+        assert_eq!(dirinfo.index, 0);
+    }
+
+    sink.capacity = 1;
+    assert_eq!(dirinfo.fill(&mut sink, false, None), S_OK);
+    assert_eq!(sink.take(), ["a"]);
+    sink.capacity = 0;
+    assert_eq!(
+        dirinfo.fill(&mut sink, false, None),
+        HRESULT_FROM_WIN32(ERROR_INSUFFICIENT_BUFFER)
+    );
+}
+
+#[test]
+fn test_fill_error_keeps_the_entry() {
+    let mut dirinfo = listing(&["a", "b"]);
+    let mut sink = FakeSink::new(10);
+
+    sink.fail = Some(winapi::shared::winerror::E_OUTOFMEMORY);
+    assert_eq!(
+        dirinfo.fill(&mut sink, false, None),
+        winapi::shared::winerror::E_OUTOFMEMORY
+    );
+    sink.fail = None;
+    assert_eq!(dirinfo.fill(&mut sink, false, None), S_OK);
+    assert_eq!(sink.take(), ["a", "b"]);
+}
+
+#[test]
+fn test_fill_after_restart() {
+    let mut dirinfo = listing(&["a", "b", "c"]);
+    let mut sink = FakeSink::new(2);
+    assert_eq!(dirinfo.fill(&mut sink, false, None), S_OK);
+    assert_eq!(sink.take(), ["a", "b"]);
+
+    // a restart scan starts the listing over, search expression included
+    dirinfo.capture_search(OsStr::new("b*"));
+    dirinfo.reset();
+    assert!(!dirinfo.filled() && dirinfo.search.is_none());
+    dirinfo.fill_file_entry("b".into(), 4, 0);
+    dirinfo.filled = true;
+    assert_eq!(dirinfo.fill(&mut sink, false, None), S_OK);
+    assert_eq!(sink.take(), ["b"]);
+}
+
+#[test]
+fn test_cached_listing_sorted_again_once_added_to() {
+    let api = crate::prj_compat::MockPrjApi::default();
+    let names = |dirinfo: &DirInfo| -> Vec<String> {
+        dirinfo
+            .entries()
+            .iter()
+            .map(|entry| entry.filename().to_string_lossy().into_owned())
+            .collect()
+    };
+
+    let mut dirinfo = DirInfo::new("HKEY_CURRENT_USER\\App");
+    dirinfo.fill_cached(listing(&["a", "c"]).into_entries());
+    dirinfo.sort_entries_and_mark_filled(&api);
+    assert_eq!(names(&dirinfo), ["a", "c"]);
+    // like the marker --max-entries-per-dir adds
+    dirinfo.fill_file_entry("b".into(), 4, 0);
+    dirinfo.sort_entries_and_mark_filled(&api);
+    assert_eq!(names(&dirinfo), ["a", "b", "c"]);
 }
@@ -0,0 +1,315 @@
+use std::{fmt, io, path::Path, time::Duration};
+use winapi::um::{libloaderapi::GetModuleHandleW, winbase::DRIVE_REMOTE};
+
+use crate::backend::RegistryBackend;
+use crate::impersonate;
+use crate::options::RegFsOptions;
+use crate::pipe::{self, ClientError};
+use crate::prj_compat::{PrjApi, ProjFsApi};
+use crate::regop::{RegOps, RootHive};
+use crate::virtroot::{self, LiveProbe, RootProbe};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verdict {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Verdict::Pass => "pass",
+            Verdict::Warn => "warn",
+            Verdict::Fail => "fail",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub name: &'static str,
+    pub verdict: Verdict,
+    pub detail: String,
+    // what to do about it, for a warning or a failure
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn pass(name: &'static str, detail: impl Into<String>) -> Self {
+        Check {
+            name,
+            verdict: Verdict::Pass,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn warn(name: &'static str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Check {
+            verdict: Verdict::Warn,
+            hint: Some(hint.into()),
+            ..Check::pass(name, detail)
+        }
+    }
+
+    fn fail(name: &'static str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Check {
+            verdict: Verdict::Fail,
+            ..Check::warn(name, detail, hint)
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}: {}", self.verdict, self.name, self.detail)?;
+        match &self.hint {
+            Some(hint) => write!(f, "\n       {}", hint),
+            None => Ok(()),
+        }
+    }
+}
+
+pub fn projfs(loaded: bool, api: &dyn PrjApi) -> Check {
+    if !loaded {
+        return Check::fail(
+            "projfs",
+            "projectedfslib.dll isn't loaded",
+            "enable the feature: Enable-WindowsOptionalFeature -Online -FeatureName Client-ProjFS",
+        );
+    }
+    match api.symlinks_supported() && api.extended_enum_supported() {
+        true => Check::pass("projfs", api.to_string()),
+        false => Check::warn(
+            "projfs",
+            api.to_string(),
+            "registry links are projected as plain keys; Windows 10 2004 or later has the rest",
+        ),
+    }
+}
+
+// `volume` is the file system's name and whether it's on another machine
+pub fn filesystem(root: &Path, volume: io::Result<(String, bool)>) -> Check {
+    match volume {
+        Err(e) => Check::fail(
+            "filesystem",
+            format!("{:?}: {}", root, e),
+            "--root has to be a directory on a local drive",
+        ),
+        Ok((_, true)) => Check::fail(
+            "filesystem",
+            format!("{:?} is on a network share", root),
+            "ProjFS only virtualizes local volumes, pick a --root on a local NTFS drive",
+        ),
+        Ok((name, false)) if name.eq_ignore_ascii_case("NTFS") => {
+            Check::pass("filesystem", format!("{:?} is on NTFS", root))
+        }
+        Ok((name, false)) => Check::fail(
+            "filesystem",
+            format!("{:?} is on {}", root, name),
+            "ProjFS only virtualizes NTFS, pick a --root on an NTFS drive",
+        ),
+    }
+}
+
+// the pipe name a provider left on the root, and whether it still answers there
+pub fn instance(
+    root: &Path,
+    persisted: Result<String, ClientError>,
+    answers: impl Fn(&str) -> bool,
+) -> [Check; 2] {
+    let name = match persisted {
+        Err(ClientError::NotRunning(_)) => {
+            return [
+                Check::pass("instance", "no instance recorded on the root"),
+                Check::pass("running", "no other provider on the root"),
+            ]
+        }
+        Err(e) => {
+            return [
+                Check::warn(
+                    "instance",
+                    e.to_string(),
+                    "the root's regfs.pipe stream can't be read",
+                ),
+                Check::pass("running", "unknown, the instance can't be read"),
+            ]
+        }
+        Ok(name) => name,
+    };
+    let remove = format!("Remove-Item {:?} -Stream regfs.pipe", root);
+    if !is_instance_name(&name) {
+        return [
+            Check::warn(
+                "instance",
+                format!("[{}] isn't a pipe regfs would use", name),
+                format!("delete it: {}", remove),
+            ),
+            Check::pass("running", "no other provider on the root"),
+        ];
+    }
+
+    let recorded = Check::pass("instance", format!("recorded as {}", name));
+    match answers(&name) {
+        true => [
+            recorded,
+            Check::fail(
+                "running",
+                format!("a provider is already running on {:?}", root),
+                "stop it (`quit` on its console) or pick another --root",
+            ),
+        ],
+        false => [
+            Check::warn(
+                "instance",
+                format!("{} was left by a provider that didn't stop cleanly", name),
+                format!("harmless, the next mount replaces it; or {}", remove),
+            ),
+            Check::pass("running", "no other provider on the root"),
+        ],
+    }
+}
+
+// \\.\pipe\regfs-<guid>, as pipe::instance_name() has them
+fn is_instance_name(name: &str) -> bool {
+    let guid = match name.strip_prefix("\\\\.\\pipe\\regfs-") {
+        Some(guid) => guid,
+        None => return false,
+    };
+    let groups: Vec<&str> = guid.split('-').collect();
+    groups.iter().map(|group| group.len()).eq([8, 4, 4, 4, 12])
+        && groups
+            .iter()
+            .all(|group| group.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+pub fn elevation(elevated: io::Result<bool>, hives: &[RootHive]) -> Check {
+    match elevated {
+        Ok(true) => Check::pass("elevation", "running elevated"),
+        Ok(false) if hives.contains(&RootHive::LocalMachine) => Check::warn(
+            "elevation",
+            "not elevated",
+            "keys only administrators can read (SAM, SECURITY and parts of SYSTEM under \
+             HKEY_LOCAL_MACHINE) show up empty; run elevated to see them",
+        ),
+        Ok(false) => Check::pass("elevation", "not elevated, none of the hives need it"),
+        Err(e) => Check::warn(
+            "elevation",
+            format!("unknown: {}", e),
+            "keys only administrators can read may show up empty",
+        ),
+    }
+}
+
+pub fn hives(backend: &dyn RegistryBackend, hives: &[RootHive]) -> Vec<Check> {
+    hives
+        .iter()
+        .map(|hive| match backend.enumerate_key(hive.name().into()) {
+            Some(entries) => Check::pass(
+                "hive",
+                format!(
+                    "{} is readable, {} subkeys",
+                    hive.name(),
+                    entries.subkeys.len()
+                ),
+            ),
+            None => Check::fail(
+                "hive",
+                format!("{} can't be read", hive.name()),
+                "check the account's rights on it, or leave it out of --hives",
+            ),
+        })
+        .collect()
+}
+
+fn projfs_loaded() -> bool {
+    let module: Vec<u16> = "projectedfslib.dll\0".encode_utf16().collect();
+    !unsafe { GetModuleHandleW(module.as_ptr()) }.is_null()
+}
+
+fn volume_of(root: &Path) -> io::Result<(String, bool)> {
+    let root = virtroot::canonicalize(root, &std::env::current_dir()?);
+    let volume = LiveProbe.volume(&root)?;
+    Ok((volume.filesystem, volume.drive_type == DRIVE_REMOTE))
+}
+
+// everything `regfs doctor` looks at, for the mount `options` describe
+pub fn run(options: &RegFsOptions) -> Vec<Check> {
+    let root = &options.root;
+    let mut checks = vec![
+        projfs(projfs_loaded(), &ProjFsApi::detect()),
+        filesystem(root, volume_of(root)),
+    ];
+    checks.extend(instance(root, pipe::find_instance(root), |name| {
+        let status = serde_json::json!({ "cmd": "status" });
+        pipe::request(name, &status, Duration::from_secs(2)).is_ok()
+    }));
+    checks.push(elevation(impersonate::is_elevated(), &options.hives));
+    checks.extend(hives(&RegOps::with_hives(&options.hives), &options.hives));
+    checks
+}
+
+#[test]
+fn test_environment_checks() {
+    use crate::prj_compat::MockPrjApi;
+
+    assert_eq!(projfs(false, &MockPrjApi::default()).verdict, Verdict::Fail);
+    assert_eq!(projfs(true, &MockPrjApi::default()).verdict, Verdict::Pass);
+
+    let root = Path::new("C:\\mnt");
+    let verdict = |volume| filesystem(root, volume).verdict;
+    assert_eq!(verdict(Ok(("NTFS".into(), false))), Verdict::Pass);
+    assert_eq!(verdict(Ok(("FAT32".into(), false))), Verdict::Fail);
+    assert_eq!(verdict(Ok(("NTFS".into(), true))), Verdict::Fail);
+    let missing = io::Error::from(io::ErrorKind::NotFound);
+    assert_eq!(verdict(Err(missing)), Verdict::Fail);
+
+    let hklm = [RootHive::LocalMachine];
+    assert_eq!(elevation(Ok(true), &hklm).verdict, Verdict::Pass);
+    assert_eq!(elevation(Ok(false), &hklm).verdict, Verdict::Warn);
+    assert_eq!(
+        elevation(Ok(false), &[RootHive::CurrentUser]).verdict,
+        Verdict::Pass
+    );
+}
+
+#[test]
+fn test_instance_checks() {
+    let root = Path::new("C:\\mnt");
+    let name = "\\\\.\\pipe\\regfs-0f8fad5b-d9cb-469f-a165-70867728950e";
+    let verdicts = |persisted, answering: bool| {
+        instance(root, persisted, |_| answering).map(|check| check.verdict)
+    };
+
+    let nothing = ClientError::NotRunning("C:\\mnt".into());
+    assert_eq!(verdicts(Err(nothing), false), [Verdict::Pass; 2]);
+    assert_eq!(
+        verdicts(Ok(name.into()), true),
+        [Verdict::Pass, Verdict::Fail]
+    );
+    // a crashed run's
+    assert_eq!(
+        verdicts(Ok(name.into()), false),
+        [Verdict::Warn, Verdict::Pass]
+    );
+    assert_eq!(
+        verdicts(Ok("\\\\.\\pipe\\other".into()), true),
+        [Verdict::Warn, Verdict::Pass]
+    );
+    assert!(!is_instance_name(
+        "\\\\.\\pipe\\regfs-0f8fad5b-d9cb-469f-a165"
+    ));
+}
+
+#[test]
+fn test_hive_checks() {
+    use crate::memory::MemoryBackend;
+
+    let memory = MemoryBackend::new();
+    memory.add_key("HKEY_CURRENT_USER\\Software");
+    let checks = hives(&memory, &[RootHive::CurrentUser, RootHive::Users]);
+    assert_eq!(checks[0].verdict, Verdict::Pass);
+    assert_eq!(checks[1].verdict, Verdict::Fail);
+    assert!(checks[1].to_string().contains("HKEY_USERS can't be read"));
+}
@@ -0,0 +1,251 @@
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::dirinfo::DirEntry;
+use crate::regfs::path_key;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnumCacheLimits {
+    // listings kept, and entries over all of them
+    pub capacity: usize,
+    pub max_entries: usize,
+    // a listing older than this is filled again, whatever its key's last write time says
+    pub ttl: Duration,
+}
+
+impl Default for EnumCacheLimits {
+    fn default() -> Self {
+        EnumCacheLimits {
+            capacity: 1024,
+            max_entries: 200_000,
+            ttl: Duration::from_secs(30),
+        }
+    }
+}
+
+struct Listing {
+    key_time: i64,
+    entries: Vec<DirEntry>,
+    stored: Instant,
+    // when it was last handed out, the least recent goes first
+    used: u64,
+}
+
+#[derive(Default)]
+struct Listings {
+    // by path_key and lowercased search expression
+    listings: HashMap<(String, String), Listing>,
+    entries: usize,
+    clock: u64,
+}
+
+impl Listings {
+    fn remove(&mut self, key: &(String, String)) {
+        if let Some(listing) = self.listings.remove(key) {
+            self.entries -= listing.entries.len();
+        }
+    }
+}
+
+// --enum-cache: sorted listings kept across enumerations, good for as long as the key's last
+// write time doesn't move and `ttl` hasn't passed
+pub struct EnumCache {
+    limits: EnumCacheLimits,
+    listings: Mutex<Listings>,
+}
+
+impl EnumCache {
+    pub fn new(limits: EnumCacheLimits) -> Self {
+        EnumCache {
+            limits,
+            listings: Mutex::new(Default::default()),
+        }
+    }
+
+    fn key(path: &Path, search: &OsStr) -> (String, String) {
+        (path_key(path), search.to_string_lossy().to_lowercase())
+    }
+
+    // the entries listed for `path` when its last write time was `key_time`; a stale one
+    // goes
+    pub fn get(
+        &self,
+        path: &Path,
+        search: &OsStr,
+        key_time: i64,
+        now: Instant,
+    ) -> Option<Vec<DirEntry>> {
+        let key = Self::key(path, search);
+        let mut guard = self.listings.lock().unwrap_or_else(|e| e.into_inner());
+        let listings = &mut *guard;
+        listings.clock += 1;
+
+        match listings.listings.get_mut(&key) {
+            Some(listing)
+                if listing.key_time == key_time
+                    && now.saturating_duration_since(listing.stored) <= self.limits.ttl =>
+            {
+                listing.used = listings.clock;
+                Some(listing.entries.clone())
+            }
+            Some(_) => {
+                listings.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    // a listing bigger than the whole budget isn't kept
+    pub fn insert(
+        &self,
+        path: &Path,
+        search: &OsStr,
+        key_time: i64,
+        entries: &[DirEntry],
+        now: Instant,
+    ) {
+        if self.limits.capacity == 0 || entries.len() > self.limits.max_entries {
+            return;
+        }
+        let key = Self::key(path, search);
+        let mut guard = self.listings.lock().unwrap_or_else(|e| e.into_inner());
+        let listings = &mut *guard;
+        listings.clock += 1;
+
+        listings.remove(&key);
+        listings.entries += entries.len();
+        let used = listings.clock;
+        listings.listings.insert(
+            key,
+            Listing {
+                key_time,
+                entries: entries.to_vec(),
+                stored: now,
+                used,
+            },
+        );
+
+        while listings.listings.len() > self.limits.capacity
+            || listings.entries > self.limits.max_entries
+        {
+            let oldest = listings
+                .listings
+                .iter()
+                .min_by_key(|(_, listing)| listing.used)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(oldest) => listings.remove(&oldest),
+                None => break,
+            }
+        }
+    }
+
+    // the listings of `path`, of the keys under it, and of its parent, which lists it
+    pub fn invalidate(&self, path: &Path) {
+        let key = path_key(path);
+        let children = format!("{}\\", key);
+        let parent = path.parent().map(path_key);
+        let mut guard = self.listings.lock().unwrap_or_else(|e| e.into_inner());
+        let stale: Vec<(String, String)> = guard
+            .listings
+            .keys()
+            .filter(|(cached, _)| {
+                *cached == key || cached.starts_with(&children) || Some(cached) == parent.as_ref()
+            })
+            .cloned()
+            .collect();
+        for stale in &stale {
+            guard.remove(stale);
+        }
+    }
+
+    pub fn clear(&self) {
+        *self.listings.lock().unwrap_or_else(|e| e.into_inner()) = Default::default();
+    }
+
+    // listings and entries kept
+    pub fn sizes(&self) -> (usize, usize) {
+        let guard = self.listings.lock().unwrap_or_else(|e| e.into_inner());
+        (guard.listings.len(), guard.entries)
+    }
+}
+
+#[cfg(test)]
+fn entries(names: &[&str]) -> Vec<DirEntry> {
+    let mut dirinfo = crate::dirinfo::DirInfo::new("");
+    for name in names {
+        dirinfo.fill_file_entry(name.into(), 4, 0);
+    }
+    dirinfo.entries().to_vec()
+}
+
+#[test]
+fn test_enum_cache() {
+    let cache = EnumCache::new(EnumCacheLimits {
+        capacity: 2,
+        max_entries: 5,
+        ttl: Duration::from_secs(30),
+    });
+    let start = Instant::now();
+    let app = Path::new("HKEY_CURRENT_USER\\Software\\App");
+    let all = OsStr::new("*");
+    let names = |listed: Option<Vec<DirEntry>>| {
+        listed.map(|listed| {
+            listed
+                .iter()
+                .map(|entry| entry.filename().to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+        })
+    };
+
+    cache.insert(app, all, 10, &entries(&["a", "b"]), start);
+    assert_eq!(
+        names(cache.get("hkey_current_user\\software\\app".as_ref(), all, 10, start)),
+        Some(vec!["a".to_string(), "b".to_string()])
+    );
+    // per search expression
+    assert!(cache.get(app, OsStr::new("a*"), 10, start).is_none());
+
+    // the key changed since, or the listing is too old
+    assert!(cache.get(app, all, 11, start).is_none());
+    assert_eq!(cache.sizes(), (0, 0));
+    cache.insert(app, all, 10, &entries(&["a", "b"]), start);
+    assert!(cache
+        .get(app, all, 10, start + Duration::from_secs(31))
+        .is_none());
+
+    // past the capacity or the entry budget, the least recently used go
+    let other = app.with_file_name("Other");
+    let third = app.with_file_name("Third");
+    cache.insert(app, all, 10, &entries(&["a", "b"]), start);
+    cache.insert(&other, all, 10, &entries(&["c"]), start);
+    assert!(cache.get(app, all, 10, start).is_some());
+    cache.insert(&third, all, 10, &entries(&["d", "e"]), start);
+    assert_eq!(cache.sizes(), (2, 4));
+    assert!(cache.get(&other, all, 10, start).is_none());
+    cache.insert(&other, all, 10, &entries(&["c", "d", "e", "f"]), start);
+    assert_eq!(cache.sizes(), (1, 4));
+    cache.insert(
+        app,
+        all,
+        10,
+        &entries(&["a", "b", "c", "d", "e", "f"]),
+        start,
+    );
+    assert_eq!(cache.sizes(), (1, 4));
+
+    // a change drops the key, the keys under it and its parent, which lists it
+    let parent = app.parent().unwrap();
+    cache.insert(app, all, 10, &entries(&["a"]), start);
+    cache.insert(parent, all, 10, &entries(&["App"]), start);
+    cache.invalidate(&app.join("Sub"));
+    assert_eq!(cache.sizes(), (1, 1));
+    cache.invalidate(app);
+    assert_eq!(cache.sizes(), (0, 0));
+}
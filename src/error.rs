@@ -0,0 +1,132 @@
+use log::{info, warn};
+use std::{
+    io,
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
+};
+use thiserror::Error;
+use winapi::{
+    shared::winerror::{
+        self, ERROR_DIRECTORY, ERROR_FILE_NOT_FOUND, ERROR_INTERNAL_ERROR, ERROR_OPERATION_ABORTED,
+        HRESULT_FROM_WIN32,
+    },
+    um::winnt::HRESULT,
+};
+
+use crate::opcontext::Cancelled;
+
+// everything a callback can fail with; ProjFS only ever sees `to_hresult()` of it
+#[derive(Debug, Error)]
+pub enum RegFsError {
+    #[error("registry error: {0}")]
+    Registry(#[from] io::Error),
+    #[error("key [{0:?}] doesn't exist")]
+    KeyNotFound(PathBuf),
+    #[error("[{0:?}] is a value, not a key")]
+    NotAKey(PathBuf),
+    #[error("enumeration session doesn't exist")]
+    UnknownEnumeration,
+    #[error("{0} lock is poisoned")]
+    LockPoisoned(&'static str),
+    #[error("operation cancelled")]
+    Cancelled,
+    #[error("internal error: {0}")]
+    Internal(String),
+}
+
+impl From<Cancelled> for RegFsError {
+    fn from(_: Cancelled) -> Self {
+        RegFsError::Cancelled
+    }
+}
+
+impl RegFsError {
+    pub fn to_hresult(&self) -> HRESULT {
+        match self {
+            RegFsError::Registry(e) => match e.raw_os_error() {
+                Some(code) => HRESULT_FROM_WIN32(code as u32),
+                None => winerror::E_FAIL,
+            },
+            RegFsError::KeyNotFound(_) => HRESULT_FROM_WIN32(ERROR_FILE_NOT_FOUND),
+            RegFsError::NotAKey(_) => HRESULT_FROM_WIN32(ERROR_DIRECTORY),
+            RegFsError::UnknownEnumeration => winerror::E_INVALIDARG,
+            RegFsError::LockPoisoned(_) => winerror::E_UNEXPECTED,
+            RegFsError::Cancelled => HRESULT_FROM_WIN32(ERROR_OPERATION_ABORTED),
+            RegFsError::Internal(_) => HRESULT_FROM_WIN32(ERROR_INTERNAL_ERROR),
+        }
+    }
+}
+
+// every callback runs through here, so a failure (or a panic) always reaches
+// ProjFS as a deliberate HRESULT instead of whatever prjfs makes of an error
+pub fn funnel<F>(callback: &str, f: F) -> anyhow::Result<HRESULT>
+where
+    F: FnOnce() -> Result<HRESULT, RegFsError>,
+{
+    let result = panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        Err(RegFsError::Internal(format!("panicked: {}", message)))
+    });
+
+    Ok(result.unwrap_or_else(|e| {
+        let hr = e.to_hresult();
+        match e {
+            RegFsError::Cancelled => info!("<---- {}: {}, return {:08x}", callback, e, hr),
+            _ => warn!("<---- {}: {}, return {:08x}", callback, e, hr),
+        }
+        hr
+    }))
+}
+
+#[test]
+fn test_to_hresult() {
+    let table = [
+        (
+            RegFsError::Registry(io::Error::from_raw_os_error(5)),
+            HRESULT_FROM_WIN32(winerror::ERROR_ACCESS_DENIED),
+        ),
+        (
+            RegFsError::Registry(io::Error::new(io::ErrorKind::Other, "no code")),
+            winerror::E_FAIL,
+        ),
+        (
+            RegFsError::KeyNotFound("HKEY_USERS\\Missing".into()),
+            HRESULT_FROM_WIN32(ERROR_FILE_NOT_FOUND),
+        ),
+        (
+            RegFsError::NotAKey("HKEY_USERS\\Value".into()),
+            HRESULT_FROM_WIN32(ERROR_DIRECTORY),
+        ),
+        (RegFsError::UnknownEnumeration, winerror::E_INVALIDARG),
+        (RegFsError::LockPoisoned("state"), winerror::E_UNEXPECTED),
+        (
+            RegFsError::from(Cancelled),
+            HRESULT_FROM_WIN32(ERROR_OPERATION_ABORTED),
+        ),
+        (
+            RegFsError::Internal("bug".into()),
+            HRESULT_FROM_WIN32(ERROR_INTERNAL_ERROR),
+        ),
+    ];
+
+    for (error, hr) in table {
+        assert_eq!(error.to_hresult(), hr, "{}", error);
+    }
+}
+
+#[test]
+fn test_funnel() {
+    assert_eq!(funnel("ok", || Ok(winerror::S_OK)).unwrap(), winerror::S_OK);
+    assert_eq!(
+        funnel("cancelled", || Err(Cancelled.into())).unwrap(),
+        HRESULT_FROM_WIN32(ERROR_OPERATION_ABORTED)
+    );
+    assert_eq!(
+        funnel("panics", || panic!("bug")).unwrap(),
+        HRESULT_FROM_WIN32(ERROR_INTERNAL_ERROR)
+    );
+}
@@ -0,0 +1,273 @@
+use anyhow::Result;
+use log::{Level, Log, Metadata, Record};
+use prjfs::conv::WStrExt;
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    io,
+    os::windows::ffi::OsStrExt,
+    ptr,
+    sync::Mutex,
+    time::Duration,
+};
+use winapi::um::{
+    winbase::{DeregisterEventSource, RegisterEventSourceW, ReportEventW},
+    winnt::{EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE, HANDLE},
+};
+use winreg::{
+    enums::{RegType, HKEY_LOCAL_MACHINE},
+    RegKey, RegValue,
+};
+
+use crate::ratelimit::RateLimiter;
+
+pub const SOURCE: &str = "RegFs";
+const SOURCE_KEY: &str = "SYSTEM\\CurrentControlSet\\Services\\EventLog\\Application\\RegFs";
+// EventCreate.exe carries a "%1" message for every id in 1..=1000, so the text shows as is
+const MESSAGE_FILE: &str = "%SystemRoot%\\System32\\EventCreate.exe";
+const EVENT_ID: u32 = 1;
+
+// info records on this target (provider start and stop) are forwarded as well
+pub const LIFECYCLE: &str = "regfs::lifecycle";
+
+// the same call site can't log more than once per interval
+const INTERVAL: Duration = Duration::from_secs(60);
+
+pub trait EventSink: Send + Sync {
+    fn report(&self, level: Level, message: &str);
+}
+
+// needs an elevated prompt, which is why it only happens on `install`
+pub fn install() -> Result<()> {
+    let (key, _) = RegKey::predef(HKEY_LOCAL_MACHINE).create_subkey(SOURCE_KEY)?;
+    let bytes = OsStr::new(MESSAGE_FILE)
+        .encode_wide()
+        .chain(Some(0))
+        .flat_map(|c| c.to_le_bytes())
+        .collect();
+
+    key.set_raw_value(
+        "EventMessageFile",
+        &RegValue {
+            bytes,
+            vtype: RegType::REG_EXPAND_SZ,
+        },
+    )?;
+    key.set_value("TypesSupported", &7u32)?;
+    Ok(())
+}
+
+fn is_installed() -> bool {
+    RegKey::predef(HKEY_LOCAL_MACHINE)
+        .open_subkey(SOURCE_KEY)
+        .is_ok()
+}
+
+pub struct WindowsEventLog {
+    handle: HANDLE,
+    installed: bool,
+}
+
+// the handle returned by RegisterEventSourceW can be used from any thread
+unsafe impl Send for WindowsEventLog {}
+unsafe impl Sync for WindowsEventLog {}
+
+impl WindowsEventLog {
+    pub fn open() -> io::Result<Self> {
+        let source = OsString::from(SOURCE).to_wstr();
+        let handle = unsafe { RegisterEventSourceW(ptr::null(), source.as_ptr()) };
+
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(WindowsEventLog {
+            handle,
+            installed: is_installed(),
+        })
+    }
+}
+
+impl EventSink for WindowsEventLog {
+    fn report(&self, level: Level, message: &str) {
+        let event_type = match level {
+            Level::Error => EVENTLOG_ERROR_TYPE,
+            Level::Warn => EVENTLOG_WARNING_TYPE,
+            _ => EVENTLOG_INFORMATION_TYPE,
+        };
+        // without the registered message file the viewer shows its generic "description
+        // cannot be found" text, followed by this string, so it has to stand on its own
+        let message = match self.installed {
+            true => OsString::from(message),
+            false => OsString::from(format!("{}: {}", SOURCE, message)),
+        }
+        .to_wstr();
+        let mut strings = [message.as_ptr()];
+
+        unsafe {
+            ReportEventW(
+                self.handle,
+                event_type,
+                0,
+                EVENT_ID,
+                ptr::null_mut(),
+                1,
+                0,
+                strings.as_mut_ptr(),
+                ptr::null_mut(),
+            );
+        }
+    }
+}
+
+impl Drop for WindowsEventLog {
+    fn drop(&mut self) {
+        unsafe {
+            DeregisterEventSource(self.handle);
+        }
+    }
+}
+
+pub fn format_event(record: &Record, suppressed: u64) -> String {
+    let mut message = format!("[{}] {}", record.target(), record.args());
+    if suppressed > 0 {
+        message += &format!(" ({} similar events suppressed)", suppressed);
+    }
+    message
+}
+
+// logs through `inner` as usual, and copies warnings and errors into `sink`
+pub struct EventLogger<L> {
+    inner: L,
+    sink: Box<dyn EventSink>,
+    limiters: Mutex<HashMap<(String, u32), RateLimiter>>,
+}
+
+impl<L: Log> EventLogger<L> {
+    pub fn new(inner: L, sink: Box<dyn EventSink>) -> Self {
+        EventLogger {
+            inner,
+            sink,
+            limiters: Default::default(),
+        }
+    }
+
+    fn forwards(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Warn || metadata.target() == LIFECYCLE
+    }
+
+    fn allow(&self, record: &Record) -> Option<u64> {
+        let site = (
+            record.file().unwrap_or(record.target()).to_string(),
+            record.line().unwrap_or(0),
+        );
+        let mut limiters = self.limiters.lock().unwrap_or_else(|e| e.into_inner());
+
+        limiters
+            .entry(site)
+            .or_insert_with(|| RateLimiter::new(INTERVAL))
+            .allow()
+    }
+}
+
+impl<L: Log> Log for EventLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.forwards(metadata) || self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.inner.log(record);
+
+        if self.forwards(record.metadata()) {
+            if let Some(suppressed) = self.allow(record) {
+                self.sink
+                    .report(record.level(), &format_event(record, suppressed));
+            }
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+#[test]
+fn test_event_logger() {
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    struct MockSink(Arc<Mutex<Vec<(Level, String)>>>);
+
+    impl EventSink for MockSink {
+        fn report(&self, level: Level, message: &str) {
+            self.0.lock().unwrap().push((level, message.to_string()));
+        }
+    }
+
+    struct Discard;
+
+    impl Log for Discard {
+        fn enabled(&self, _: &Metadata) -> bool {
+            false
+        }
+        fn log(&self, _: &Record) {}
+        fn flush(&self) {}
+    }
+
+    let sink = MockSink::default();
+    let logger = EventLogger::new(Discard, Box::new(sink.clone()));
+    let log = |level: Level, target: &str, line: u32, message: &str| {
+        logger.log(
+            &Record::builder()
+                .level(level)
+                .target(target)
+                .file(Some("src/regfs.rs"))
+                .line(Some(line))
+                .args(format_args!("{}", message))
+                .build(),
+        )
+    };
+
+    log(Level::Info, LIFECYCLE, 1, "provider started");
+    log(Level::Info, "regfs::regfs", 2, "----> get_file_data");
+    // a storm from one call site only gets through once per interval
+    for _ in 0..3 {
+        log(Level::Warn, "regfs::error", 3, "read failed");
+    }
+    log(Level::Error, "regfs::error", 4, "lock poisoned");
+
+    assert_eq!(
+        *sink.0.lock().unwrap(),
+        vec![
+            (
+                Level::Info,
+                "[regfs::lifecycle] provider started".to_string()
+            ),
+            (Level::Warn, "[regfs::error] read failed".to_string()),
+            (Level::Error, "[regfs::error] lock poisoned".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn test_format_event() {
+    let message = "<---- get_file_data: registry error, return 80070005";
+    let format = |suppressed| {
+        format_event(
+            &Record::builder()
+                .target("regfs::error")
+                .args(format_args!("{}", message))
+                .build(),
+            suppressed,
+        )
+    };
+
+    assert_eq!(
+        format(0),
+        "[regfs::error] <---- get_file_data: registry error, return 80070005"
+    );
+    assert_eq!(
+        format(41),
+        "[regfs::error] <---- get_file_data: registry error, return 80070005 (41 similar events suppressed)"
+    );
+}
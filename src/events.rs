@@ -0,0 +1,91 @@
+use std::path::PathBuf;
+
+// what embedders get to see of the filesystem activity, through RegFsOptions::events
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegFsEvent {
+    PlaceholderCreated {
+        path: PathBuf,
+    },
+    Hydrated {
+        path: PathBuf,
+        bytes: u64,
+    },
+    EnumerationStarted {
+        path: PathBuf,
+    },
+    EnumerationEnded {
+        path: PathBuf,
+    },
+    // a change through the mount recorded in the overlay, or written back to the registry
+    WriteBackApplied {
+        path: PathBuf,
+    },
+    OperationDenied {
+        path: PathBuf,
+        reason: String,
+    },
+    // copy-key: the tree at `path` recorded in the overlay again at `destination`
+    KeyCopied {
+        path: PathBuf,
+        destination: PathBuf,
+        keys: usize,
+        values: usize,
+        bytes: u64,
+    },
+    // a second name for `path`, only with --allow-hardlinks
+    HardlinkCreated {
+        path: PathBuf,
+        link: PathBuf,
+    },
+    NotificationReceived {
+        path: PathBuf,
+        notification: prjfs::sys::PRJ_NOTIFICATION,
+    },
+}
+
+impl RegFsEvent {
+    // every path the event carries, through `f`
+    pub fn map_paths(self, f: impl Fn(PathBuf) -> PathBuf) -> RegFsEvent {
+        match self {
+            RegFsEvent::PlaceholderCreated { path } => {
+                RegFsEvent::PlaceholderCreated { path: f(path) }
+            }
+            RegFsEvent::Hydrated { path, bytes } => RegFsEvent::Hydrated {
+                path: f(path),
+                bytes,
+            },
+            RegFsEvent::EnumerationStarted { path } => {
+                RegFsEvent::EnumerationStarted { path: f(path) }
+            }
+            RegFsEvent::EnumerationEnded { path } => RegFsEvent::EnumerationEnded { path: f(path) },
+            RegFsEvent::WriteBackApplied { path } => RegFsEvent::WriteBackApplied { path: f(path) },
+            RegFsEvent::OperationDenied { path, reason } => RegFsEvent::OperationDenied {
+                path: f(path),
+                reason,
+            },
+            RegFsEvent::KeyCopied {
+                path,
+                destination,
+                keys,
+                values,
+                bytes,
+            } => RegFsEvent::KeyCopied {
+                path: f(path),
+                destination: f(destination),
+                keys,
+                values,
+                bytes,
+            },
+            RegFsEvent::HardlinkCreated { path, link } => RegFsEvent::HardlinkCreated {
+                path: f(path),
+                link: f(link),
+            },
+            RegFsEvent::NotificationReceived { path, notification } => {
+                RegFsEvent::NotificationReceived {
+                    path: f(path),
+                    notification,
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use log::warn;
+use std::{
+    collections::VecDeque,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+};
+
+pub const DEFAULT_WORKERS: usize = 4;
+
+type Work = Box<dyn FnOnce() + Send>;
+
+struct Job {
+    command_id: i32,
+    work: Work,
+}
+
+#[derive(Default)]
+struct Queue {
+    jobs: VecDeque<Job>,
+    shutdown: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+}
+
+// runs callback work that was answered with ERROR_IO_PENDING; queued jobs are
+// keyed by CommandId so cancel_command can drop them before they start
+pub struct Executor {
+    shared: Arc<Shared>,
+}
+
+impl Executor {
+    pub fn new(workers: usize) -> Self {
+        let shared = Arc::new(Shared::default());
+
+        for _ in 0..workers.max(1) {
+            let shared = shared.clone();
+            thread::spawn(move || worker(&shared));
+        }
+
+        Executor { shared }
+    }
+
+    pub fn submit<F: FnOnce() + Send + 'static>(&self, command_id: i32, work: F) {
+        let mut queue = self.shared.queue.lock().unwrap_or_else(|e| e.into_inner());
+        queue.jobs.push_back(Job {
+            command_id,
+            work: Box::new(work),
+        });
+        self.shared.ready.notify_one();
+    }
+
+    // true when the job was still queued and will never run
+    pub fn cancel(&self, command_id: i32) -> bool {
+        let mut queue = self.shared.queue.lock().unwrap_or_else(|e| e.into_inner());
+        let before = queue.jobs.len();
+        queue.jobs.retain(|job| job.command_id != command_id);
+        queue.jobs.len() != before
+    }
+
+    pub fn queued(&self) -> usize {
+        let queue = self.shared.queue.lock().unwrap_or_else(|e| e.into_inner());
+        queue.jobs.len()
+    }
+}
+
+impl Drop for Executor {
+    fn drop(&mut self) {
+        let mut queue = self.shared.queue.lock().unwrap_or_else(|e| e.into_inner());
+        queue.shutdown = true;
+        self.shared.ready.notify_all();
+    }
+}
+
+fn worker(shared: &Shared) {
+    loop {
+        let job = {
+            let mut queue = shared.queue.lock().unwrap_or_else(|e| e.into_inner());
+            loop {
+                if queue.shutdown {
+                    return;
+                }
+                if let Some(job) = queue.jobs.pop_front() {
+                    break job;
+                }
+                queue = shared.ready.wait(queue).unwrap_or_else(|e| e.into_inner());
+            }
+        };
+
+        // a panicking job must not take the worker down with it
+        if panic::catch_unwind(AssertUnwindSafe(job.work)).is_err() {
+            warn!("executor: job for command {} panicked", job.command_id);
+        }
+    }
+}
+
+#[test]
+fn test_cancel_drops_queued_job() {
+    use std::sync::{atomic::AtomicBool, atomic::Ordering, mpsc, Barrier};
+
+    let executor = Executor::new(1);
+    let gate = Arc::new(Barrier::new(2));
+    let ran = Arc::new(AtomicBool::new(false));
+    let (done, finished) = mpsc::channel();
+
+    // keep the only worker busy so the next jobs stay queued
+    let busy = gate.clone();
+    executor.submit(1, move || {
+        busy.wait();
+    });
+
+    let flag = ran.clone();
+    executor.submit(2, move || flag.store(true, Ordering::SeqCst));
+    executor.submit(3, move || done.send(()).unwrap());
+
+    assert!(executor.cancel(2));
+    assert!(!executor.cancel(2));
+    gate.wait();
+
+    finished.recv().unwrap();
+    assert!(!ran.load(Ordering::SeqCst));
+    assert_eq!(executor.queued(), 0);
+}
+
+#[test]
+fn test_mixed_sync_and_async_stress() {
+    use std::sync::{atomic::AtomicUsize, atomic::Ordering, mpsc};
+
+    const THREADS: usize = 8;
+    const CALLS: usize = 200;
+
+    let executor = Arc::new(Executor::new(DEFAULT_WORKERS));
+    let served = Arc::new(AtomicUsize::new(0));
+    let (done, finished) = mpsc::channel();
+
+    let callers: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let executor = executor.clone();
+            let served = served.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                for call in 0..CALLS {
+                    let command_id = (t * CALLS + call) as i32;
+                    let served = served.clone();
+                    let work = move || {
+                        served.fetch_add(1, Ordering::SeqCst);
+                    };
+
+                    // same as a callback: odd commands go async, even ones run inline
+                    if call % 2 == 1 {
+                        let done = done.clone();
+                        executor.submit(command_id, move || {
+                            work();
+                            done.send(command_id).unwrap();
+                        });
+                    } else {
+                        work();
+                    }
+
+                    if call % 50 == 0 {
+                        executor.submit(-1, || panic!("job failure"));
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for caller in callers {
+        caller.join().unwrap();
+    }
+
+    let mut completed: Vec<i32> = (0..THREADS * CALLS / 2)
+        .map(|_| finished.recv().unwrap())
+        .collect();
+    completed.sort_unstable();
+    completed.dedup();
+
+    assert_eq!(completed.len(), THREADS * CALLS / 2);
+    assert_eq!(served.load(Ordering::SeqCst), THREADS * CALLS);
+}
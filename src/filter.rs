@@ -0,0 +1,20 @@
+use std::{ffi::OsStr, fmt, path::Path};
+
+// an entry as it is about to be listed, before anything else is read for it
+#[derive(Debug, Clone, Copy)]
+pub struct ProjectedEntry<'a> {
+    pub name: &'a OsStr,
+    pub is_directory: bool,
+    pub size: u64,
+}
+
+// decides what shows up in the mount; runs for every listed entry, so keep it cheap
+pub trait EntryFilter: Send + Sync {
+    fn include(&self, parent: &Path, entry: &ProjectedEntry) -> bool;
+}
+
+impl fmt::Debug for dyn EntryFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EntryFilter")
+    }
+}
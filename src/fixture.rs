@@ -0,0 +1,54 @@
+use prjfs::conv::WStrExt;
+use std::{
+    ffi::OsStr,
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
+use winapi::um::projectedfslib::PRJ_CALLBACK_DATA;
+
+use crate::memory::MemoryBackend;
+
+// what a test projects: `keys`, and `values` as (key, name, type, data)
+pub fn seeded(keys: &[&str], values: &[(&str, &str, u32, Vec<u8>)]) -> Arc<MemoryBackend> {
+    let backend = Arc::new(MemoryBackend::new());
+    for key in keys {
+        backend.add_key(key);
+    }
+    for (key, name, vtype, data) in values {
+        backend.set_value(key, *name, *vtype, data.clone());
+    }
+    backend
+}
+
+// the data a callback about `path` is given; it holds the wide path it points to, and the
+// rest (a stream, a triggering process) is set through it
+pub struct Callback {
+    data: PRJ_CALLBACK_DATA,
+    _path: Vec<u16>,
+}
+
+pub fn callback_data<P: AsRef<OsStr>>(path: P, command_id: i32) -> Callback {
+    let path = path.as_ref().to_os_string().to_wstr();
+    Callback {
+        data: PRJ_CALLBACK_DATA {
+            FilePathName: path.as_ptr(),
+            CommandId: command_id,
+            ..Default::default()
+        },
+        _path: path,
+    }
+}
+
+impl Deref for Callback {
+    type Target = PRJ_CALLBACK_DATA;
+
+    fn deref(&self) -> &PRJ_CALLBACK_DATA {
+        &self.data
+    }
+}
+
+impl DerefMut for Callback {
+    fn deref_mut(&mut self) -> &mut PRJ_CALLBACK_DATA {
+        &mut self.data
+    }
+}
@@ -0,0 +1,180 @@
+// seeded random inputs for the tests that throw garbage at the parsers, starting from the
+// names in fixtures/fuzz/names.txt; every choice comes from FUZZ_SEED, so a failure can be
+// replayed, and FUZZ_ITERATIONS runs them for longer
+
+const SEED: u64 = 0xfeed_f00d_dead_beef;
+const ITERATIONS: usize = 2000;
+
+// what paths, .reg files and MULTI_SZ text give a meaning to, plus what never belongs in a name
+const INTERESTING: [u16; 24] = [
+    b'\\' as u16,
+    b'/' as u16,
+    b'.' as u16,
+    b'?' as u16,
+    b'*' as u16,
+    b':' as u16,
+    b'"' as u16,
+    b'[' as u16,
+    b']' as u16,
+    b'=' as u16,
+    b'@' as u16,
+    b',' as u16,
+    b'-' as u16,
+    b';' as u16,
+    b'0' as u16,
+    b' ' as u16,
+    b'\n' as u16,
+    b'\r' as u16,
+    0,
+    0xfeff,
+    0xd800,
+    0xdc00,
+    0x00df,
+    0xfffd,
+];
+
+pub fn seed() -> u64 {
+    std::env::var("FUZZ_SEED")
+        .ok()
+        .and_then(|seed| seed.parse().ok())
+        .unwrap_or(SEED)
+}
+
+pub fn iterations() -> usize {
+    std::env::var("FUZZ_ITERATIONS")
+        .ok()
+        .and_then(|iterations| iterations.parse().ok())
+        .unwrap_or(ITERATIONS)
+}
+
+// splitmix64, one stream per thread
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64, thread: u32) -> Rng {
+        Rng(seed ^ (thread as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    pub fn chance(&mut self, percent: u64) -> bool {
+        self.below(100) < percent
+    }
+
+    pub fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len() as u64) as usize]
+    }
+
+    // mostly ASCII letters, often something interesting, now and then any unit at all
+    pub fn unit(&mut self) -> u16 {
+        match self.below(10) {
+            0..=4 => b'a' as u16 + self.below(26) as u16,
+            5..=8 => *self.pick(&INTERESTING),
+            _ => self.next_u64() as u16,
+        }
+    }
+
+    // `seed` with a few units inserted, removed or replaced, or a piece of `other` spliced in
+    pub fn mutate(&mut self, seed: &[u16], other: &[u16]) -> Vec<u16> {
+        let mut units = seed.to_vec();
+        for _ in 0..=self.below(3) {
+            let at = self.below(units.len() as u64 + 1) as usize;
+            match self.below(4) {
+                0 => units.insert(at, self.unit()),
+                1 if at < units.len() => {
+                    units.remove(at);
+                }
+                2 if at < units.len() => units[at] = self.unit(),
+                _ => {
+                    let from = self.below(other.len() as u64 + 1) as usize;
+                    let to = from + self.below((other.len() - from) as u64 + 1) as usize;
+                    units.splice(at..at, other[from..to].iter().copied());
+                }
+            }
+        }
+        units
+    }
+
+    pub fn text(&mut self, seed: &str, other: &str) -> String {
+        let seed: Vec<u16> = seed.encode_utf16().collect();
+        let other: Vec<u16> = other.encode_utf16().collect();
+        String::from_utf16_lossy(&self.mutate(&seed, &other))
+    }
+}
+
+// one name per line; `\u{xxxx}` stands for that unit, so the file can hold NULs, line breaks
+// and unpaired surrogates, and lines starting with `#` are comments
+pub fn corpus() -> Vec<Vec<u16>> {
+    include_str!("../fixtures/fuzz/names.txt")
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .map(unescape)
+        .collect()
+}
+
+fn unescape(line: &str) -> Vec<u16> {
+    let mut units = Vec::new();
+    let mut rest = line;
+    while let Some(start) = rest.find("\\u{") {
+        units.extend(rest[..start].encode_utf16());
+        let escape = &rest[start + 3..];
+        match escape
+            .split_once('}')
+            .and_then(|(hex, after)| Some((u16::from_str_radix(hex, 16).ok()?, after)))
+        {
+            Some((unit, after)) => {
+                units.push(unit);
+                rest = after;
+            }
+            None => {
+                units.extend(rest[start..start + 3].encode_utf16());
+                rest = escape;
+            }
+        }
+    }
+    units.extend(rest.encode_utf16());
+    units
+}
+
+// the corpus names that are valid UTF-16, for what only takes a str
+pub fn corpus_strings() -> Vec<String> {
+    corpus()
+        .iter()
+        .filter_map(|name| String::from_utf16(name).ok())
+        .collect()
+}
+
+#[test]
+fn test_corpus() {
+    assert_eq!(unescape("a\\u{0}b"), [b'a' as u16, 0, b'b' as u16]);
+    assert_eq!(unescape("\\u{d800}"), [0xd800]);
+    // anything else is left as it is
+    assert_eq!(
+        String::from_utf16(&unescape("C:\\u{zz}\\x")).unwrap(),
+        "C:\\u{zz}\\x"
+    );
+
+    let corpus = corpus();
+    assert!(corpus.len() > 20);
+    assert!(corpus
+        .iter()
+        .any(|name| char::decode_utf16(name.iter().copied()).any(|c| c.is_err())));
+    assert!(corpus.iter().any(|name| name.contains(&0)));
+
+    let mut rng = Rng::new(seed(), 0);
+    for _ in 0..iterations() {
+        let (seed, other) = (rng.pick(&corpus), rng.pick(&corpus));
+        // never more than four changes, each no longer than `other`
+        assert!(rng.mutate(seed, other).len() <= seed.len() + 4 * other.len().max(1));
+    }
+}
@@ -0,0 +1,33 @@
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+// stable across runs and builds, unlike std's DefaultHasher
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+pub fn content_id(bytes: &[u8]) -> [u8; prjfs::sys::PRJ_PLACEHOLDER_ID_LENGTH as usize] {
+    let mut id = [0u8; prjfs::sys::PRJ_PLACEHOLDER_ID_LENGTH as usize];
+    id[..8].copy_from_slice(&fnv1a(bytes).to_le_bytes());
+    // keep the length in the id too so a zero-filled id never matches real content
+    id[8..16].copy_from_slice(&(bytes.len() as u64 + 1).to_le_bytes());
+    id
+}
+
+pub fn content_id_of(info: &prjfs::sys::PRJ_PLACEHOLDER_INFO) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&info.VersionInfo.ContentID[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+#[test]
+fn test_fnv1a() {
+    assert_eq!(fnv1a(b""), FNV_OFFSET_BASIS);
+    assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+    assert_ne!(
+        content_id(b""),
+        [0u8; prjfs::sys::PRJ_PLACEHOLDER_ID_LENGTH as usize]
+    );
+}
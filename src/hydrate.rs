@@ -0,0 +1,98 @@
+use log::{info, warn};
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    thread::{self, JoinHandle},
+};
+
+use crate::links::LinkWalk;
+use crate::ondisk;
+use crate::regfs::RegFs;
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HydrateSummary {
+    pub hydrated: usize,
+    pub bytes: u64,
+    pub failed: usize,
+    // keys left out because a registry link led back up the tree
+    pub cycles: usize,
+}
+
+impl fmt::Display for HydrateSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "hydrated {} ({} bytes), failed {}, link cycles {}",
+            self.hydrated, self.bytes, self.failed, self.cycles
+        )
+    }
+}
+
+impl RegFs {
+    pub fn spawn_hydrate(&self, path: PathBuf) -> JoinHandle<HydrateSummary> {
+        let regfs = self.clone();
+
+        thread::spawn(move || {
+            info!(target: "hydrate", "----> hydrate: [{:?}]", path);
+            let summary = regfs.hydrate(&path);
+            info!(target: "hydrate", "<---- hydrate: [{:?}] {}", path, summary);
+            summary
+        })
+    }
+
+    // reading through the mount is what makes ProjFS ask us for the content
+    pub fn hydrate(&self, path: &Path) -> HydrateSummary {
+        let mut summary = HydrateSummary::default();
+        self.hydrate_dir(path, &mut LinkWalk::default(), &mut summary);
+        summary
+    }
+
+    fn hydrate_dir(&self, relative: &Path, walk: &mut LinkWalk, summary: &mut HydrateSummary) {
+        if let Err(cycle) = walk.enter(self.regops(), relative) {
+            warn!(target: "hydrate", "skipping [{:?}]: {}", relative, cycle);
+            summary.cycles += 1;
+            return;
+        }
+        let dir = match fs::read_dir(self.local_path(relative)) {
+            Ok(dir) => dir,
+            Err(e) => {
+                warn!(target: "hydrate", "unable to list [{:?}]: {}", relative, e);
+                walk.leave();
+                return;
+            }
+        };
+
+        for entry in dir.flatten() {
+            let child = relative.join(entry.file_name());
+            // a bookmark's key is hydrated where it really is
+            if self.synthetic(&child).is_some() || self.is_bookmark(&child) {
+                continue;
+            }
+
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => self.hydrate_dir(&child, walk, summary),
+                Ok(_) => self.hydrate_file(&child, summary),
+                Err(_) => {}
+            }
+        }
+        walk.leave();
+    }
+
+    fn hydrate_file(&self, relative: &Path, summary: &mut HydrateSummary) {
+        let local = self.local_path(relative);
+        if ondisk::state(&local) & ondisk::HYDRATED_STATES != 0 {
+            return;
+        }
+
+        match fs::read(&local) {
+            Ok(bytes) => {
+                summary.hydrated += 1;
+                summary.bytes += bytes.len() as u64;
+            }
+            Err(e) => {
+                warn!(target: "hydrate", "unable to read [{:?}]: {}", relative, e);
+                summary.failed += 1;
+            }
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// streams that never reach the end of the file (cancelled reads) must not pin bytes forever
+const STALE_AFTER: Duration = Duration::from_secs(60);
+
+type Bytes = Option<Arc<Vec<u8>>>;
+
+// the DataStreamId, as regfs::guid_key has it
+pub type StreamId = [u8; 16];
+
+struct Slot {
+    // None until a read went through; a failed one leaves it for the next stream to try
+    bytes: Arc<Mutex<Option<Bytes>>>,
+    streams: HashSet<StreamId>,
+    touched: Instant,
+}
+
+// one registry read per path no matter how many data streams are hydrating it;
+// the slot lives until every stream that joined has been served to the end
+#[derive(Default)]
+pub struct HydrationCache {
+    slots: Mutex<HashMap<String, Slot>>,
+}
+
+impl HydrationCache {
+    pub fn read<F, E>(&self, key: &str, stream: &StreamId, read: F) -> Result<Bytes, E>
+    where
+        F: FnOnce() -> Result<Option<Vec<u8>>, E>,
+    {
+        let bytes = {
+            let mut slots = self.slots.lock().unwrap_or_else(|e| e.into_inner());
+            let now = Instant::now();
+            slots.retain(|_, slot| now.duration_since(slot.touched) < STALE_AFTER);
+
+            // looked up first, so the chunks after the first don't copy the key
+            if !slots.contains_key(key) {
+                let slot = Slot {
+                    bytes: Default::default(),
+                    streams: HashSet::new(),
+                    touched: now,
+                };
+                slots.insert(key.to_owned(), slot);
+            }
+            let slot = slots.get_mut(key).expect("the slot is there");
+            slot.streams.insert(*stream);
+            slot.touched = now;
+            slot.bytes.clone()
+        };
+
+        // concurrent callers block here until the first one has read the value
+        let mut bytes = bytes.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(bytes) = &*bytes {
+            return Ok(bytes.clone());
+        }
+        let read = read()?.map(Arc::new);
+        *bytes = Some(read.clone());
+        Ok(read)
+    }
+
+    pub fn complete(&self, key: &str, stream: &StreamId) {
+        let mut slots = self.slots.lock().unwrap_or_else(|e| e.into_inner());
+
+        if let Some(slot) = slots.get_mut(key) {
+            slot.streams.remove(stream);
+            if slot.streams.is_empty() {
+                slots.remove(key);
+            }
+        }
+    }
+
+    pub fn pending(&self) -> usize {
+        self.slots.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    // the size of what was read, not the bytes
+    pub fn to_json(&self) -> Value {
+        let slots = self.slots.lock().unwrap_or_else(|e| e.into_inner());
+        let mut keys: Vec<&String> = slots.keys().collect();
+        keys.sort();
+        keys.into_iter()
+            .map(|key| {
+                let slot = &slots[key];
+                // a slot still being read isn't waited for
+                let bytes = slot.bytes.try_lock().ok();
+                let read = bytes.as_ref().and_then(|bytes| bytes.as_ref());
+                json!({
+                    "key": key,
+                    "streams": slot.streams.len(),
+                    "read": read.is_some(),
+                    "size": read.and_then(|bytes| bytes.as_ref().map(|bytes| bytes.len())),
+                    "age_ms": slot.touched.elapsed().as_millis() as u64,
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReadShape {
+    Empty,
+    Overflow,
+    // exclusive end of the requested range
+    Range(u64),
+}
+
+impl ReadShape {
+    pub fn of(offset: u64, length: u32) -> ReadShape {
+        match offset.checked_add(length as u64) {
+            _ if length == 0 => ReadShape::Empty,
+            None => ReadShape::Overflow,
+            Some(end) => ReadShape::Range(end),
+        }
+    }
+
+    // true once a stream has been handed everything up to the end of the value
+    pub fn reaches_end(&self, size: usize) -> bool {
+        match self {
+            ReadShape::Range(end) => *end >= size as u64,
+            ReadShape::Empty | ReadShape::Overflow => true,
+        }
+    }
+}
+
+// the part of a file's content a read asks for, cut short at the end of the file
+pub fn file_range(bytes: &[u8], offset: u64, length: u32) -> &[u8] {
+    let start = offset.min(bytes.len() as u64) as usize;
+    let end = offset.saturating_add(length as u64).min(bytes.len() as u64) as usize;
+    &bytes[start..end]
+}
+
+// the most PrjWriteFileData is handed at once; values of several megabytes go in pieces
+pub const MAX_WRITE: usize = 1 << 20;
+
+// the part of a range of `len` bytes each write takes; every piece but the last is a multiple
+// of the alignment, so the next one starts on it
+pub fn write_chunks(len: usize, alignment: u32) -> impl Iterator<Item = Range<usize>> {
+    let alignment = (alignment as usize).max(1);
+    let chunk = (MAX_WRITE / alignment).max(1) * alignment;
+    (0..len)
+        .step_by(chunk)
+        .map(move |start| start..(start + chunk).min(len))
+}
+
+#[test]
+fn test_read_shape() {
+    assert_eq!(ReadShape::of(0, 0), ReadShape::Empty);
+    assert_eq!(ReadShape::of(u64::MAX, 0), ReadShape::Empty);
+    assert_eq!(ReadShape::of(u64::MAX, 1), ReadShape::Overflow);
+    assert_eq!(ReadShape::of(u64::MAX - 1, 1), ReadShape::Range(u64::MAX));
+    assert_eq!(ReadShape::of(4096, 4096), ReadShape::Range(8192));
+}
+
+#[test]
+fn test_read_shape_reaches_end() {
+    // exactly the end of the file
+    assert!(ReadShape::of(4096, 4096).reaches_end(8192));
+    assert!(!ReadShape::of(0, 4096).reaches_end(8192));
+    // past the end, ProjFS rounds lengths up to the sector size
+    assert!(ReadShape::of(4096, 8192).reaches_end(8192));
+    assert!(ReadShape::of(0, 0).reaches_end(8192));
+}
+
+#[test]
+fn test_file_range() {
+    let bytes: Vec<u8> = (0..100).collect();
+    assert_eq!(file_range(&bytes, 10, 40), &bytes[10..50]);
+    assert_eq!(file_range(&bytes, 90, 4096), &bytes[90..]);
+    assert_eq!(file_range(&bytes, 100, 1), b"");
+    assert_eq!(file_range(&bytes, u64::MAX, u32::MAX), b"");
+}
+
+#[test]
+fn test_concurrent_hydrations_share_one_read() {
+    use crate::backend::RegistryBackend;
+    use crate::memory::MemoryBackend;
+    use std::path::Path;
+
+    let backend = Arc::new(MemoryBackend::new());
+    backend.set_value("HKEY_CURRENT_USER\\Big", "Blob", 3, vec![7; 1 << 20]);
+    let cache = Arc::new(HydrationCache::default());
+    let path = Path::new("HKEY_CURRENT_USER\\Big\\Blob");
+
+    let threads: Vec<_> = (0..8u8)
+        .map(|stream| {
+            let backend = backend.clone();
+            let cache = cache.clone();
+            std::thread::spawn(move || {
+                cache.read("big", &[stream; 16], || {
+                    std::thread::sleep(Duration::from_millis(100));
+                    Ok::<_, ()>(backend.read_value(path))
+                })
+            })
+        })
+        .collect();
+
+    for thread in threads {
+        assert_eq!(thread.join().unwrap().unwrap().unwrap().len(), 1 << 20);
+    }
+    assert_eq!(backend.reads(), 1);
+
+    for stream in 0..8u8 {
+        assert_eq!(cache.pending(), 1);
+        cache.complete("big", &[stream; 16]);
+    }
+    assert_eq!(cache.pending(), 0);
+
+    cache
+        .read("big", &[0; 16], || Ok::<_, ()>(backend.read_value(path)))
+        .unwrap();
+    assert_eq!(backend.reads(), 2);
+}
+
+#[test]
+fn test_cancelled_hydration_is_read_again() {
+    use crate::opcontext::Cancelled;
+
+    let cache = HydrationCache::default();
+    let (first, second) = ([1; 16], [2; 16]);
+
+    // the first stream's read was cancelled; the second one, sharing the slot, reads
+    // the value itself instead of being told the file is missing
+    assert_eq!(
+        cache.read("value", &first, || Err(Cancelled)),
+        Err(Cancelled)
+    );
+    assert_eq!(
+        cache.read("value", &second, || Ok(Some(b"data".to_vec()))),
+        Ok(Some(Arc::new(b"data".to_vec())))
+    );
+    // and from then on it's the cache's
+    assert_eq!(
+        cache.read("value", &first, || Err(Cancelled)),
+        Ok(Some(Arc::new(b"data".to_vec())))
+    );
+    assert_eq!(cache.pending(), 1);
+}
+
+#[test]
+fn test_write_chunks() {
+    let chunks = |len, alignment| write_chunks(len, alignment).collect::<Vec<_>>();
+    assert!(chunks(0, 4096).is_empty());
+    assert_eq!(chunks(10, 0), [0..10]);
+    assert_eq!(chunks(MAX_WRITE, 4096), [0..MAX_WRITE]);
+    assert_eq!(
+        chunks(2 * MAX_WRITE + 5, 4096),
+        [
+            0..MAX_WRITE,
+            MAX_WRITE..2 * MAX_WRITE,
+            2 * MAX_WRITE..2 * MAX_WRITE + 5
+        ]
+    );
+
+    // an alignment that doesn't divide the most a write takes rounds the pieces down to it
+    let aligned = chunks(MAX_WRITE + 1, 3000);
+    assert_eq!(aligned.len(), 2);
+    assert_eq!(aligned[0].end % 3000, 0);
+    assert_eq!(aligned[1].end, MAX_WRITE + 1);
+    // and one bigger than it is a piece on its own
+    assert_eq!(
+        chunks(3 * MAX_WRITE, 2 * MAX_WRITE as u32),
+        [0..2 * MAX_WRITE, 2 * MAX_WRITE..3 * MAX_WRITE]
+    );
+}
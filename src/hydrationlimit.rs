@@ -0,0 +1,192 @@
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    path::Path,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HydrationLimits {
+    // get_file_data flows serving at once; 0 for no limit
+    pub max: usize,
+    // how long one waits for a turn before it's turned away to come back later
+    pub wait: Duration,
+    // reads of up to this many bytes go ahead whatever is running
+    pub small: u32,
+}
+
+impl Default for HydrationLimits {
+    fn default() -> Self {
+        HydrationLimits {
+            max: 16,
+            wait: Duration::from_secs(1),
+            small: 4096,
+        }
+    }
+}
+
+pub enum Admission {
+    Go(HydrationPermit),
+    // after waiting that long for a turn
+    Waited(HydrationPermit, Duration),
+    Rejected,
+}
+
+#[derive(Default)]
+struct Slots {
+    running: usize,
+    // waited and rejected, by image file name
+    processes: HashMap<String, (u64, u64)>,
+}
+
+// --max-hydrations: a scanner opening hundreds of files at once gets them served a few at a
+// time, instead of all of them holding buffers and registry handles together
+pub struct HydrationGate {
+    limits: HydrationLimits,
+    slots: Mutex<Slots>,
+    freed: Condvar,
+}
+
+// a turn, given back when dropped
+pub struct HydrationPermit(Option<Arc<HydrationGate>>);
+
+impl Drop for HydrationPermit {
+    fn drop(&mut self) {
+        if let Some(gate) = self.0.take() {
+            gate.lock().running -= 1;
+            gate.freed.notify_one();
+        }
+    }
+}
+
+fn image_name(process: &OsStr) -> String {
+    Path::new(process)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+impl HydrationGate {
+    pub fn new(limits: HydrationLimits) -> Arc<Self> {
+        Arc::new(HydrationGate {
+            limits,
+            slots: Default::default(),
+            freed: Condvar::new(),
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Slots> {
+        self.slots.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // a turn for a read of `length` bytes, waiting for one up to `limits.wait`; counts the
+    // waits and rejections against the process
+    pub fn admit(self: &Arc<Self>, process: &OsStr, length: u32) -> Admission {
+        if self.limits.max == 0 || length <= self.limits.small {
+            return Admission::Go(HydrationPermit(None));
+        }
+
+        let mut slots = self.lock();
+        if slots.running < self.limits.max {
+            slots.running += 1;
+            return Admission::Go(HydrationPermit(Some(self.clone())));
+        }
+
+        let start = Instant::now();
+        let (mut slots, timeout) = self
+            .freed
+            .wait_timeout_while(slots, self.limits.wait, |slots| {
+                slots.running >= self.limits.max
+            })
+            .unwrap_or_else(|e| e.into_inner());
+        let counts = slots.processes.entry(image_name(process)).or_default();
+        if timeout.timed_out() {
+            counts.1 += 1;
+            return Admission::Rejected;
+        }
+        counts.0 += 1;
+        slots.running += 1;
+        Admission::Waited(HydrationPermit(Some(self.clone())), start.elapsed())
+    }
+
+    pub fn running(&self) -> usize {
+        self.lock().running
+    }
+
+    // {"<process>": {"waited": n, "rejected": n}}
+    pub fn to_json(&self) -> Value {
+        self.lock()
+            .processes
+            .iter()
+            .map(|(process, (waited, rejected))| {
+                (
+                    process.clone(),
+                    json!({ "waited": waited, "rejected": rejected }),
+                )
+            })
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+}
+
+#[test]
+fn test_hydration_gate() {
+    let gate = HydrationGate::new(HydrationLimits {
+        max: 2,
+        wait: Duration::from_millis(200),
+        small: 100,
+    });
+    let scanner = OsStr::new("C:\\Tools\\Scanner.exe");
+    let permit = |admission| match admission {
+        Admission::Go(permit) => permit,
+        _ => panic!("not admitted right away"),
+    };
+
+    let first = permit(gate.admit(scanner, 4096));
+    let second = permit(gate.admit(scanner, 4096));
+    assert_eq!(gate.running(), 2);
+
+    // a tiny read still goes, and doesn't take a turn
+    let tiny = permit(gate.admit(scanner, 100));
+    assert_eq!(gate.running(), 2);
+    drop(tiny);
+    assert_eq!(gate.running(), 2);
+
+    assert!(matches!(gate.admit(scanner, 4096), Admission::Rejected));
+
+    // a turn given back goes to whoever waits for it
+    let waiter = {
+        let gate = gate.clone();
+        std::thread::spawn(move || {
+            matches!(
+                gate.admit(OsStr::new("explorer.exe"), 4096),
+                Admission::Waited(..)
+            )
+        })
+    };
+    std::thread::sleep(Duration::from_millis(50));
+    drop(first);
+    assert!(waiter.join().unwrap());
+    drop(second);
+    assert_eq!(gate.running(), 0);
+
+    assert_eq!(
+        gate.to_json(),
+        json!({
+            "scanner.exe": { "waited": 0, "rejected": 1 },
+            "explorer.exe": { "waited": 1, "rejected": 0 },
+        })
+    );
+
+    // no limit
+    let gate = HydrationGate::new(HydrationLimits {
+        max: 0,
+        ..Default::default()
+    });
+    let permits: Vec<_> = (0..100).map(|_| gate.admit(scanner, 1 << 20)).collect();
+    assert!(permits
+        .iter()
+        .all(|admission| matches!(admission, Admission::Go(_))));
+}
@@ -0,0 +1,193 @@
+use log::info;
+use std::{
+    collections::HashMap,
+    io, ptr,
+    sync::{Arc, Mutex},
+};
+use winapi::{
+    shared::{minwindef::FALSE, sddl::ConvertSidToStringSidW, winerror::ERROR_SUCCESS},
+    um::{
+        handleapi::CloseHandle,
+        processthreadsapi::{GetCurrentProcess, OpenProcess, OpenProcessToken},
+        securitybaseapi::{GetTokenInformation, ImpersonateLoggedOnUser, RevertToSelf},
+        winbase::LocalFree,
+        winnt::{
+            TokenElevation, TokenUser, HANDLE, KEY_READ, PROCESS_QUERY_LIMITED_INFORMATION,
+            TOKEN_DUPLICATE, TOKEN_ELEVATION, TOKEN_IMPERSONATE, TOKEN_QUERY, TOKEN_USER,
+        },
+        winreg::RegOpenCurrentUser,
+    },
+};
+use winreg::RegKey;
+
+// which HKEY_CURRENT_USER a registry call sees, carried in its OpContext
+#[derive(Clone, Copy)]
+pub enum UserHive<'a> {
+    // the triggering process's, while running as its user
+    Process(&'a RegKey),
+    // impersonation failed, nothing may be read
+    Denied,
+}
+
+struct Handle(HANDLE);
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.0) };
+    }
+}
+
+struct Revert;
+
+impl Drop for Revert {
+    fn drop(&mut self) {
+        unsafe { RevertToSelf() };
+    }
+}
+
+// the calling thread runs as another user until this is dropped
+pub struct Impersonation {
+    pub hive: Arc<RegKey>,
+    _revert: Revert,
+}
+
+// HKEY_CURRENT_USER of every user seen so far, keyed by SID, so each one is only opened once
+#[derive(Default)]
+pub struct UserHives {
+    hives: Mutex<HashMap<String, Arc<RegKey>>>,
+}
+
+impl UserHives {
+    pub fn impersonate(&self, process_id: u32) -> io::Result<Impersonation> {
+        if process_id == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no triggering process",
+            ));
+        }
+
+        let process = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE, process_id) };
+        if process.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let process = Handle(process);
+
+        let mut token = ptr::null_mut();
+        let access = TOKEN_QUERY | TOKEN_DUPLICATE | TOKEN_IMPERSONATE;
+        if unsafe { OpenProcessToken(process.0, access, &mut token) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let token = Handle(token);
+        let sid = token_sid(&token)?;
+
+        if unsafe { ImpersonateLoggedOnUser(token.0) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // reverts on the error paths below too
+        let revert = Revert;
+
+        let mut hives = self.hives.lock().unwrap_or_else(|e| e.into_inner());
+        let hive = match hives.get(&sid) {
+            Some(hive) => hive.clone(),
+            None => {
+                let mut hkey = ptr::null_mut();
+                let result = unsafe { RegOpenCurrentUser(KEY_READ, &mut hkey) } as u32;
+                if result != ERROR_SUCCESS {
+                    return Err(io::Error::from_raw_os_error(result as i32));
+                }
+                info!("impersonate: opened HKEY_CURRENT_USER of {}", sid);
+                let hive = Arc::new(RegKey::predef(hkey as _));
+                hives.insert(sid.clone(), hive.clone());
+                hive
+            }
+        };
+
+        Ok(Impersonation {
+            hive,
+            _revert: revert,
+        })
+    }
+}
+
+fn token_sid(token: &Handle) -> io::Result<String> {
+    let mut size = 0;
+    unsafe { GetTokenInformation(token.0, TokenUser, ptr::null_mut(), 0, &mut size) };
+    let mut buffer = vec![0u8; size as usize];
+    let queried = unsafe {
+        GetTokenInformation(
+            token.0,
+            TokenUser,
+            buffer.as_mut_ptr() as *mut _,
+            size,
+            &mut size,
+        )
+    };
+    if queried == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let user = unsafe { &*(buffer.as_ptr() as *const TOKEN_USER) };
+    let mut string = ptr::null_mut();
+    if unsafe { ConvertSidToStringSidW(user.User.Sid, &mut string) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let len = (0..)
+        .take_while(|i| unsafe { *string.add(*i) } != 0)
+        .count();
+    let sid = String::from_utf16_lossy(unsafe { std::slice::from_raw_parts(string, len) });
+    unsafe { LocalFree(string as _) };
+
+    Ok(sid)
+}
+
+// UAC's elevation of this process, for `regfs doctor`
+pub fn is_elevated() -> io::Result<bool> {
+    let mut token = ptr::null_mut();
+    if unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let token = Handle(token);
+
+    let mut elevation = TOKEN_ELEVATION::default();
+    let mut size = std::mem::size_of::<TOKEN_ELEVATION>() as u32;
+    let queried = unsafe {
+        GetTokenInformation(
+            token.0,
+            TokenElevation,
+            &mut elevation as *mut TOKEN_ELEVATION as *mut _,
+            size,
+            &mut size,
+        )
+    };
+    match queried {
+        0 => Err(io::Error::last_os_error()),
+        _ => Ok(elevation.TokenIsElevated != 0),
+    }
+}
+
+#[test]
+fn test_impersonation_fails_closed() {
+    use winapi::um::processthreadsapi::{GetCurrentThread, OpenThreadToken};
+
+    let hives = UserHives::default();
+    assert!(hives.impersonate(0).is_err());
+    // process ids are multiples of 4
+    assert!(hives.impersonate(u32::MAX - 2).is_err());
+
+    let thread_token = || {
+        let mut token = ptr::null_mut();
+        let opened = unsafe { OpenThreadToken(GetCurrentThread(), TOKEN_QUERY, 1, &mut token) };
+        (opened != 0).then(|| Handle(token))
+    };
+    {
+        let user = hives.impersonate(std::process::id()).unwrap();
+        assert!(thread_token().is_some());
+        assert!(user.hive.open_subkey("Software").is_ok());
+    }
+    assert!(thread_token().is_none());
+
+    // the second time comes from the cache
+    let first = hives.impersonate(std::process::id()).unwrap().hive;
+    let second = hives.impersonate(std::process::id()).unwrap().hive;
+    assert!(Arc::ptr_eq(&first, &second));
+}
@@ -0,0 +1,78 @@
+// the provider as a library, for embedders (an observer, a policy, a backend of their own) and
+// the benches; the binary in main.rs is built on it
+pub mod aliases;
+pub mod audit;
+pub mod autostop;
+pub mod backend;
+#[cfg(test)]
+mod bench;
+pub mod bookmarks;
+pub mod conflict;
+pub mod console;
+pub mod control;
+pub mod copykey;
+pub mod dehydrate;
+pub mod diff;
+pub mod dirinfo;
+pub mod doctor;
+pub mod enumcache;
+pub mod error;
+pub mod eventlog;
+pub mod events;
+pub mod executor;
+pub mod filter;
+#[cfg(test)]
+mod fixture;
+#[cfg(test)]
+mod fuzz;
+pub mod hash;
+pub mod hydrate;
+pub mod hydration;
+pub mod hydrationlimit;
+pub mod impersonate;
+pub mod links;
+pub mod longnames;
+pub mod materialize;
+pub mod memory;
+pub mod metrics;
+pub mod notifymap;
+pub mod observer;
+pub mod ondisk;
+pub mod opcontext;
+pub mod options;
+pub mod overlay;
+pub mod pipe;
+pub mod policy;
+pub mod pool;
+pub mod privileges;
+pub mod prj_compat;
+pub mod ratelimit;
+pub mod recent;
+pub mod redact;
+pub mod regfile;
+pub mod regfs;
+pub mod regop;
+pub mod render;
+pub mod resync;
+pub mod retry;
+#[cfg(test)]
+mod scratchdir;
+pub mod search;
+pub mod searchdir;
+pub mod selftest;
+pub mod shadow;
+pub mod snapshot;
+#[cfg(test)]
+mod stress;
+pub mod suspend;
+pub mod synthetic;
+pub mod times;
+pub mod trace;
+pub mod tracepath;
+pub mod transform;
+pub mod userclasses;
+pub mod valuecache;
+pub mod virtroot;
+pub mod virtualstore;
+pub mod watch;
+pub mod writeback;
@@ -0,0 +1,162 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+use crate::backend::RegistryBackend;
+use crate::regfs::path_key;
+use crate::regop::paths;
+
+// links one path may go through, and links a walk may be under at once
+pub const MAX_LINKS: usize = 32;
+
+// registry symbolic links can point back up their own ancestry
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("registry links loop at [{at:?}], through {chain:?}")]
+pub struct LinkCycle {
+    pub at: PathBuf,
+    // the links followed on the way there
+    pub chain: Vec<PathBuf>,
+}
+
+fn join(key: &Path, name: &OsString) -> PathBuf {
+    match key.as_os_str().is_empty() {
+        true => PathBuf::from(name),
+        false => paths::join_parts([key.as_os_str(), name]),
+    }
+}
+
+// the key `path` ends up at with every link on the way followed; going through the same
+// link twice, or through more than MAX_LINKS of them, is a cycle
+pub fn resolve(backend: &dyn RegistryBackend, path: &Path) -> Result<PathBuf, LinkCycle> {
+    let mut rest: VecDeque<OsString> = paths::components(path).into();
+    let mut resolved = PathBuf::new();
+    let mut chain = Vec::new();
+    let mut seen = HashSet::new();
+
+    while let Some(name) = rest.pop_front() {
+        let next = join(&resolved, &name);
+        let target = match backend.link_target(&next) {
+            Some(target) => target,
+            None => {
+                resolved = next;
+                continue;
+            }
+        };
+        if !seen.insert(path_key(&next)) || chain.len() >= MAX_LINKS {
+            return Err(LinkCycle { at: next, chain });
+        }
+        chain.push(next);
+
+        // the target may go through links itself
+        let mut parts: VecDeque<OsString> = paths::components(&target).into();
+        parts.extend(rest.drain(..));
+        rest = parts;
+        resolved = PathBuf::new();
+    }
+    Ok(resolved)
+}
+
+// for walks down the tree: the keys on the way down from where it started, as resolved
+// keys, so a link back to one of them is caught before the walk goes round again
+#[derive(Default)]
+pub struct LinkWalk {
+    // and for the links among them, where they were entered from
+    resolved: Vec<(String, Option<PathBuf>)>,
+}
+
+impl LinkWalk {
+    // before going into `path`, a subkey of the last key entered; leave() once done with
+    // it, unless this failed
+    pub fn enter(&mut self, backend: &dyn RegistryBackend, path: &Path) -> Result<(), LinkCycle> {
+        let link = backend.link_target(path).is_some();
+        let resolved = match (link, self.resolved.last(), path.file_name()) {
+            (false, Some((parent, _)), Some(name)) => {
+                path_key(&join(Path::new(parent), &name.to_os_string()))
+            }
+            _ => path_key(&resolve(backend, path)?),
+        };
+
+        let mut chain: Vec<PathBuf> = self
+            .resolved
+            .iter()
+            .filter_map(|(_, link)| link.clone())
+            .collect();
+        if self.resolved.iter().any(|(key, _)| *key == resolved) || chain.len() >= MAX_LINKS {
+            chain.push(path.to_path_buf());
+            return Err(LinkCycle {
+                at: path.to_path_buf(),
+                chain,
+            });
+        }
+        self.resolved
+            .push((resolved, link.then(|| path.to_path_buf())));
+        Ok(())
+    }
+
+    pub fn leave(&mut self) {
+        self.resolved.pop();
+    }
+}
+
+#[cfg(test)]
+fn cyclic() -> crate::memory::MemoryBackend {
+    let memory = crate::memory::MemoryBackend::new();
+    let scratch = Path::new("HKEY_CURRENT_USER\\Software\\Scratch");
+    memory.set_value(scratch.join("Real"), "Setting", 4, vec![1, 0, 0, 0]);
+    memory.add_link(scratch.join("Alias"), scratch.join("Real"));
+    // a pair pointing at each other
+    memory.add_link(scratch.join("Ping"), scratch.join("Pong"));
+    memory.add_link(scratch.join("Pong"), scratch.join("Ping"));
+    // and one back up its own ancestry
+    memory.add_link(scratch.join("Real\\Up"), scratch);
+    memory
+}
+
+#[test]
+fn test_resolve() {
+    let memory = cyclic();
+    let scratch = Path::new("HKEY_CURRENT_USER\\Software\\Scratch");
+
+    assert_eq!(
+        resolve(&memory, &scratch.join("Alias\\Setting")),
+        Ok(scratch.join("Real\\Setting"))
+    );
+    assert_eq!(
+        resolve(&memory, &scratch.join("Real")),
+        Ok(scratch.join("Real"))
+    );
+    assert_eq!(
+        resolve(&memory, &scratch.join("Real\\Up\\Alias")),
+        Ok(scratch.join("Real"))
+    );
+
+    let cycle = resolve(&memory, &scratch.join("Ping\\Deeper")).unwrap_err();
+    assert_eq!(cycle.at, scratch.join("Ping"));
+    assert_eq!(cycle.chain, [scratch.join("Ping"), scratch.join("Pong")]);
+    // the same link twice
+    let cycle = resolve(&memory, &scratch.join("Real\\Up\\Real\\Up")).unwrap_err();
+    assert_eq!(cycle.at, scratch.join("Real\\Up"));
+}
+
+#[test]
+fn test_link_walk() {
+    let memory = cyclic();
+    let scratch = Path::new("HKEY_CURRENT_USER\\Software\\Scratch");
+    let mut walk = LinkWalk::default();
+
+    walk.enter(&memory, scratch).unwrap();
+    walk.enter(&memory, &scratch.join("Real")).unwrap();
+    // back to where the walk already is
+    let cycle = walk.enter(&memory, &scratch.join("Real\\Up")).unwrap_err();
+    assert_eq!(cycle.at, scratch.join("Real\\Up"));
+    assert_eq!(cycle.chain, [scratch.join("Real\\Up")]);
+    walk.leave();
+
+    // an alias of a key that isn't on the way down is fine
+    walk.enter(&memory, &scratch.join("Alias")).unwrap();
+    walk.leave();
+    assert!(walk.enter(&memory, &scratch.join("Ping")).is_err());
+}
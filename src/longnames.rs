@@ -0,0 +1,448 @@
+use log::{debug, warn};
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    os::windows::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use crate::backend::{PathKind, RegistryBackend};
+use crate::hash;
+use crate::opcontext::{Cancelled, OpContext};
+use crate::prj_compat::PrjApi;
+use crate::regfs::path_key;
+use crate::regop::{paths, KeyInfo, RegEntires};
+use crate::watch::Watchers;
+
+// the longest file name NTFS takes, in UTF-16 units
+pub const MAX_COMPONENT: usize = 255;
+// `…~` and 8 hex digits
+const SUFFIX: usize = 10;
+// then 8 hex digits, after a value whose name another one of its key has but for case
+const CASE_MARKER: &str = "~case-";
+
+// the name a value longer than MAX_COMPONENT is projected as: as much of its start as fits,
+// then `…~` and the low half of the whole name's hash, so it's the same on every mount
+pub fn project(name: &OsStr) -> Option<OsString> {
+    // never fewer bytes than units
+    if name.len() <= MAX_COMPONENT {
+        return None;
+    }
+    let units: Vec<u16> = name.encode_wide().collect();
+    if units.len() <= MAX_COMPONENT {
+        return None;
+    }
+
+    let mut keep = MAX_COMPONENT - SUFFIX;
+    // not half of a surrogate pair
+    if (0xd800..0xdc00).contains(&units[keep - 1]) {
+        keep -= 1;
+    }
+    let bytes: Vec<u8> = units.iter().flat_map(|unit| unit.to_le_bytes()).collect();
+    let mut projected = OsString::from_wide(&units[..keep]);
+    projected.push(format!("…~{:08x}", hash::fnv1a(&bytes) as u32));
+    Some(projected)
+}
+
+// the name a value is projected as when an earlier subkey or value of its key has the same
+// name but for case: the name, `~case-` and the low half of its hash, the same on every mount
+pub fn disambiguate(name: &OsStr) -> OsString {
+    let bytes: Vec<u8> = name
+        .encode_wide()
+        .flat_map(|unit| unit.to_le_bytes())
+        .collect();
+    let mut projected = name.to_os_string();
+    projected.push(format!("{}{:08x}", CASE_MARKER, hash::fnv1a(&bytes) as u32));
+    // a long one is cut like any other
+    project(&projected).unwrap_or(projected)
+}
+
+// whether a name could be one disambiguate() made
+fn is_disambiguated(name: &OsStr) -> bool {
+    name.to_string_lossy()
+        .rsplit_once(CASE_MARKER)
+        .map_or(false, |(name, hash)| {
+            !name.is_empty() && hash.len() == 8 && hash.bytes().all(|b| b.is_ascii_hexdigit())
+        })
+}
+
+// whether a name could be one project() made
+fn is_projected(name: &OsStr) -> bool {
+    if name.len() < MAX_COMPONENT - 1 {
+        return false;
+    }
+    name.to_string_lossy()
+        .rsplit_once("…~")
+        .map_or(false, |(_, hash)| {
+            hash.len() == 8 && hash.bytes().all(|b| b.is_ascii_hexdigit())
+        })
+}
+
+// value names can be up to 16 383 characters, so listings show the longer ones as project()
+// has them, and the ones the file system can't tell from an earlier name as disambiguate()
+// has them; this remembers which value each of those stands for, and every path on its way
+// down to `inner` goes through resolve()
+pub struct LongNames {
+    inner: Arc<dyn RegistryBackend>,
+    // per key (its path_key), projected name in lowercase -> the value's name
+    table: RwLock<HashMap<String, HashMap<String, OsString>>>,
+    // how ProjFS compares names
+    projfs: Arc<dyn PrjApi>,
+}
+
+impl LongNames {
+    pub fn new(inner: Arc<dyn RegistryBackend>, projfs: Arc<dyn PrjApi>) -> Self {
+        LongNames {
+            inner,
+            table: Default::default(),
+            projfs,
+        }
+    }
+
+    // the registry path behind a path of the mount
+    pub fn resolve(&self, path: &Path) -> PathBuf {
+        let (key, name) = match (path.parent(), path.file_name()) {
+            (Some(key), Some(name)) if is_projected(name) || is_disambiguated(name) => (key, name),
+            _ => return path.to_path_buf(),
+        };
+        if let Some(full) = self.lookup(key, name) {
+            return paths::join_parts([key.as_os_str(), &full]);
+        }
+
+        // a placeholder from an earlier mount, or a path typed in: listing the key fills in
+        // the table
+        debug!("resolve: [{:?}] not listed yet, listing its key", path);
+        self.enumerate_key(key.into());
+        match self.lookup(key, name) {
+            Some(full) => paths::join_parts([key.as_os_str(), &full]),
+            None => path.to_path_buf(),
+        }
+    }
+
+    fn lookup(&self, key: &Path, name: &OsStr) -> Option<OsString> {
+        let table = self.table.read().unwrap_or_else(|e| e.into_inner());
+        table
+            .get(&path_key(key))?
+            .get(&name.to_string_lossy().to_lowercase())
+            .cloned()
+    }
+
+    fn project_values(&self, key: &Path, entries: &mut RegEntires) {
+        let mut mapped = Vec::new();
+        for value in &mut entries.values {
+            if let Some(projected) = project(&value.name) {
+                let full = std::mem::replace(&mut value.name, projected.clone());
+                mapped.push((projected, full));
+            }
+        }
+        self.remember(key, mapped);
+    }
+
+    // a value whose name a subkey or an earlier value has but for case would be one file with
+    // the other under ProjFS, so it's listed as disambiguate() has it
+    fn disambiguate_values(&self, key: &Path, entries: &mut RegEntires) {
+        let mut first: HashMap<String, &OsStr> =
+            HashMap::with_capacity(entries.subkeys.len() + entries.values.len());
+        for subkey in &entries.subkeys {
+            first
+                .entry(subkey.name.to_string_lossy().to_lowercase())
+                .or_insert(&subkey.name);
+        }
+        let mut colliding = Vec::new();
+        for (index, value) in entries.values.iter().enumerate() {
+            let folded = value.name.to_string_lossy().to_lowercase();
+            match first.get(&folded) {
+                Some(earlier)
+                    if !is_projected(&value.name)
+                        && self.projfs.file_name_compare(earlier, &value.name)
+                            == Ordering::Equal =>
+                {
+                    colliding.push(index)
+                }
+                Some(_) => {}
+                None => {
+                    first.insert(folded, &value.name);
+                }
+            }
+        }
+        if colliding.is_empty() {
+            return;
+        }
+
+        let mut mapped = Vec::new();
+        for index in colliding {
+            let value = &mut entries.values[index];
+            let projected = disambiguate(&value.name);
+            let full = std::mem::replace(&mut value.name, projected.clone());
+            mapped.push((projected, full));
+        }
+        // once per key, a listing after that finds them mapped already
+        for (projected, full) in self.remember(key, mapped) {
+            warn!(
+                "[{:?}] has another entry named [{:?}] but for case, listed as [{:?}]",
+                key, full, projected
+            );
+        }
+    }
+
+    // the names that weren't mapped yet
+    fn remember(&self, key: &Path, mapped: Vec<(OsString, OsString)>) -> Vec<(OsString, OsString)> {
+        if mapped.is_empty() {
+            return mapped;
+        }
+
+        let mut fresh = Vec::new();
+        let mut table = self.table.write().unwrap_or_else(|e| e.into_inner());
+        let names = table.entry(path_key(key)).or_default();
+        for (projected, full) in mapped {
+            let folded = projected.to_string_lossy().to_lowercase();
+            match names.get(&folded) {
+                Some(known) if *known != full => warn!(
+                    "[{:?}] has two values projected as [{:?}], only one of them can be opened",
+                    key, projected
+                ),
+                Some(_) => {}
+                None => {
+                    names.insert(folded, full.clone());
+                    fresh.push((projected, full));
+                }
+            }
+        }
+        fresh
+    }
+}
+
+impl RegistryBackend for LongNames {
+    fn enumerate_key_ctx(
+        &self,
+        path: OsString,
+        ctx: &OpContext,
+    ) -> Result<Option<RegEntires>, Cancelled> {
+        let mut entries = self.inner.enumerate_key_ctx(path.clone(), ctx)?;
+        if let Some(entries) = &mut entries {
+            self.project_values(path.as_ref(), entries);
+            self.disambiguate_values(path.as_ref(), entries);
+        }
+        Ok(entries)
+    }
+
+    fn read_typed_value_ctx(
+        &self,
+        path: &Path,
+        ctx: &OpContext,
+    ) -> Result<Option<(u32, Vec<u8>)>, Cancelled> {
+        self.inner.read_typed_value_ctx(&self.resolve(path), ctx)
+    }
+
+    fn read_value_ctx(&self, path: &Path, ctx: &OpContext) -> Result<Option<Vec<u8>>, Cancelled> {
+        self.inner.read_value_ctx(&self.resolve(path), ctx)
+    }
+
+    // with their real names, e.g. for _values.json
+    fn read_all_values(&self, path: &Path) -> Option<Vec<(OsString, u32, Vec<u8>)>> {
+        self.inner.read_all_values(path)
+    }
+
+    // key names are never over 255 characters, so keys are left alone
+    fn does_key_exist(&self, path: &Path) -> bool {
+        self.inner.does_key_exist(path)
+    }
+
+    fn key_last_write_time(&self, path: &Path) -> Option<i64> {
+        self.inner.key_last_write_time(path)
+    }
+
+    fn key_info(&self, path: &Path) -> Option<KeyInfo> {
+        self.inner.key_info(path)
+    }
+
+    fn watchers(&self) -> Option<&Watchers> {
+        self.inner.watchers()
+    }
+
+    fn link_target(&self, path: &Path) -> Option<PathBuf> {
+        self.inner.link_target(path)
+    }
+
+    fn writable(&self) -> bool {
+        self.inner.writable()
+    }
+
+    fn value_type(&self, path: &Path) -> Option<u32> {
+        self.inner.value_type(&self.resolve(path))
+    }
+
+    fn does_value_exist(&self, path: &Path) -> Option<usize> {
+        self.inner.does_value_exist(&self.resolve(path))
+    }
+
+    // a name resolve() maps is always a value's, even when a subkey has it too but for case
+    fn classify(&self, path: &Path, ctx: &OpContext) -> Result<PathKind, Cancelled> {
+        let resolved = self.resolve(path);
+        if resolved == path {
+            return self.inner.classify(path, ctx);
+        }
+        Ok(match self.inner.read_typed_value_ctx(&resolved, ctx)? {
+            Some(_) => PathKind::Value,
+            None => PathKind::Missing,
+        })
+    }
+}
+
+#[test]
+fn test_project() {
+    let long = |prefix: &str, len: usize| OsString::from(prefix.repeat(len));
+
+    assert_eq!(project(&long("a", 255)), None);
+    let projected = project(&long("a", 256)).unwrap();
+    assert_eq!(projected.encode_wide().count(), MAX_COMPONENT);
+    assert!(projected.to_string_lossy().starts_with(&"a".repeat(245)));
+    assert!(is_projected(&projected));
+    // the same every time, and not the same for a name that only differs past the cut
+    assert_eq!(project(&long("a", 256)), Some(projected.clone()));
+    assert_ne!(project(&long("a", 300)), Some(projected));
+
+    // a surrogate pair stays whole
+    let mut name: Vec<u16> = "a".repeat(244).encode_utf16().collect();
+    name.extend("😀".repeat(40).encode_utf16());
+    let projected = project(&OsString::from_wide(&name)).unwrap();
+    assert_eq!(projected.encode_wide().count(), MAX_COMPONENT - 1);
+    assert!(projected.to_str().is_some());
+
+    assert!(!is_projected(OsStr::new("Name…~0123abcd")));
+    assert!(!is_projected(&long("a", 300)));
+}
+
+#[test]
+fn test_long_names() {
+    use crate::memory::MemoryBackend;
+
+    let memory = Arc::new(MemoryBackend::new());
+    let full = format!("{}End", "Long".repeat(80));
+    memory.set_value("HKEY_CURRENT_USER\\App", &full, 1, vec![1]);
+    memory.set_value("HKEY_CURRENT_USER\\App", "Short", 1, vec![2]);
+    let projected = project(full.as_ref()).unwrap();
+    let key = Path::new("HKEY_CURRENT_USER\\App");
+
+    // asked for by name before any listing, as after a restart
+    let projfs = Arc::new(crate::prj_compat::MockPrjApi::default());
+    let names = LongNames::new(memory.clone(), projfs.clone());
+    assert_eq!(
+        names.read_value(&key.join(&projected)),
+        Some(vec![1]),
+        "{:?}",
+        projected
+    );
+
+    let names = LongNames::new(memory, projfs);
+    let entries = names.enumerate_key(key.into()).unwrap();
+    let listed: Vec<&OsString> = entries.values.iter().map(|value| &value.name).collect();
+    assert!(listed.contains(&&projected));
+    assert!(listed.contains(&&OsString::from("Short")));
+    assert_eq!(names.resolve(&key.join(&projected)), key.join(&full));
+    // case-insensitive, like the file system
+    let upper = projected.to_string_lossy().to_uppercase();
+    assert_eq!(names.resolve(&key.join(&upper)), key.join(&full));
+    assert_eq!(
+        names.classify(&key.join(&projected), &OpContext::none()),
+        Ok(PathKind::Value)
+    );
+    assert_eq!(names.read_value(&key.join("Short")), Some(vec![2]));
+
+    // the real name is what the key's values report
+    let values = names.read_all_values(key).unwrap();
+    assert!(values.iter().any(|(name, _, _)| *name == *full));
+    assert_eq!(names.read_value(&key.join("Missing…~0123abcd")), None);
+}
+
+// a value named like another of its key but for case, which neither the registry nor
+// MemoryBackend keep apart; it's only listed and read by its exact name
+#[cfg(test)]
+struct CaseSensitive {
+    memory: crate::memory::MemoryBackend,
+    key: PathBuf,
+    extra: (OsString, Vec<u8>),
+}
+
+#[cfg(test)]
+impl RegistryBackend for CaseSensitive {
+    fn enumerate_key_ctx(
+        &self,
+        path: OsString,
+        ctx: &OpContext,
+    ) -> Result<Option<RegEntires>, Cancelled> {
+        let mut entries = self.memory.enumerate_key_ctx(path.clone(), ctx)?;
+        if let (Some(entries), true) = (&mut entries, Path::new(&path) == self.key) {
+            let (name, data) = &self.extra;
+            entries
+                .values
+                .push(crate::regop::RegEntry::new(name, data.len() as u64));
+        }
+        Ok(entries)
+    }
+
+    fn read_typed_value_ctx(
+        &self,
+        path: &Path,
+        ctx: &OpContext,
+    ) -> Result<Option<(u32, Vec<u8>)>, Cancelled> {
+        let (name, data) = &self.extra;
+        match path.parent() == Some(self.key.as_path())
+            && path.file_name() == Some(name.as_os_str())
+        {
+            true => Ok(Some((3, data.clone()))),
+            false => self.memory.read_typed_value_ctx(path, ctx),
+        }
+    }
+
+    fn read_all_values(&self, path: &Path) -> Option<Vec<(OsString, u32, Vec<u8>)>> {
+        self.memory.read_all_values(path)
+    }
+
+    fn does_key_exist(&self, path: &Path) -> bool {
+        self.memory.does_key_exist(path)
+    }
+}
+
+#[test]
+fn test_names_differing_by_case() {
+    use crate::memory::MemoryBackend;
+
+    let key = Path::new("HKEY_CURRENT_USER\\App");
+    let memory = MemoryBackend::new();
+    memory.add_key(key.join("Settings"));
+    memory.set_value(key, "settings", 3, vec![1]);
+    memory.set_value(key, "Foo", 3, vec![2]);
+    let backend = Arc::new(CaseSensitive {
+        memory,
+        key: key.to_path_buf(),
+        extra: ("FOO".into(), vec![3]),
+    });
+    let names = LongNames::new(backend, Arc::new(crate::prj_compat::MockPrjApi::default()));
+
+    // the subkey, then the first of the values, keep their names
+    let entries = names.enumerate_key(key.into()).unwrap();
+    let listed: Vec<&OsString> = entries.values.iter().map(|value| &value.name).collect();
+    let (settings, foo) = (
+        disambiguate("settings".as_ref()),
+        disambiguate("FOO".as_ref()),
+    );
+    assert_eq!(listed, [&OsString::from("Foo"), &settings, &foo]);
+    assert_ne!(disambiguate("Foo".as_ref()), foo);
+    assert!(is_disambiguated(&foo) && !is_disambiguated(OsStr::new("Foo~case-12")));
+
+    // each reaches its own value, and the same once listed again
+    assert_eq!(names.read_value(&key.join("Foo")), Some(vec![2]));
+    assert_eq!(names.read_value(&key.join(&foo)), Some(vec![3]));
+    assert_eq!(names.read_value(&key.join(&settings)), Some(vec![1]));
+    assert_eq!(
+        names.classify(&key.join(&settings), &OpContext::none()),
+        Ok(PathKind::Value)
+    );
+    names.enumerate_key(key.into()).unwrap();
+    assert_eq!(names.resolve(&key.join(&foo)), key.join("FOO"));
+    let upper = foo.to_string_lossy().to_uppercase();
+    assert_eq!(names.resolve(&key.join(&upper)), key.join("FOO"));
+}
@@ -1,21 +1,285 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use log::{info, warn, LevelFilter};
 use prjfs::provider::{Provider, ProviderT};
 use prjfs::{NotificationType, OptionBuilder};
+use std::{path::Path, time::Duration};
 
-mod dirinfo;
-mod regfs;
-mod regop;
+use regfs::eventlog::{self, EventLogger, WindowsEventLog};
+use regfs::options::{self, RegFsOptions};
+use regfs::pipe::{self, PipeServer};
+use regfs::regfs::RegFs;
+use regfs::tracepath::ScopedLogger;
+use regfs::{
+    bookmarks, console, control, doctor, materialize, notifymap, overlay, privileges, redact,
+    trace, virtroot,
+};
 
-use crate::regfs::RegFs;
+fn init_logging(event_log: bool) {
+    let logger = env_logger::Builder::from_default_env().build();
+    let level = logger.filter();
+    let installed = match event_log {
+        false => ScopedLogger::install(logger, level),
+        true => match WindowsEventLog::open() {
+            Ok(sink) => ScopedLogger::install(
+                EventLogger::new(logger, Box::new(sink)),
+                level.max(LevelFilter::Info),
+            ),
+            Err(e) => {
+                ScopedLogger::install(logger, level).expect("logger is only set once");
+                warn!("event log is unavailable: {}", e);
+                return;
+            }
+        },
+    };
+    installed.expect("logger is only set once");
+}
+
+// the rest of the arguments pick the backend, as they would for a mount
+fn replay(path: &Path, args: &[String]) -> Result<()> {
+    let records = trace::read(path)?;
+    let options = RegFsOptions::from_args(args.to_vec())?.build()?;
+    let report = trace::replay(options, &records);
+
+    for divergence in &report.divergences {
+        println!("{}", divergence);
+    }
+    println!("{}", report);
+    match report.divergences.is_empty() {
+        true => Ok(()),
+        false => Err(anyhow!("{:?} didn't replay the same", path)),
+    }
+}
+
+// exits with 2 when nothing is running on the root and 3 when it doesn't answer
+fn status(root: &Path, json: bool) -> Result<()> {
+    let response = pipe::find_instance(root).and_then(|name| {
+        pipe::request(
+            &name,
+            &serde_json::json!({ "cmd": "status" }),
+            Duration::from_secs(5),
+        )
+    });
+
+    match response {
+        Ok(response) if json => println!("{}", response["status"]),
+        Ok(response) => print!("{}", control::status_table(&response["status"])),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(e.exit_code());
+        }
+    }
+    Ok(())
+}
+
+// `doctor [root] [options]`, exits with 1 when any check fails
+fn doctor(args: &[String]) -> Result<()> {
+    let (root, rest) = match args {
+        [root, rest @ ..] if !root.starts_with("--") => (Some(root), rest),
+        rest => (None, rest),
+    };
+    let mut options = RegFsOptions::from_args(rest.to_vec())?;
+    if let Some(root) = root {
+        options.root = root.into();
+    }
+
+    let checks = doctor::run(&options);
+    for check in &checks {
+        println!("{}", check);
+    }
+    if checks
+        .iter()
+        .any(|check| check.verdict == doctor::Verdict::Fail)
+    {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+// `materialize <key> <output dir> [--max-depth <n>] [--max-bytes <n>] [options]`, exits with 1
+// when any entry couldn't be written
+fn materialize(key: &str, output: &Path, args: &[String]) -> Result<()> {
+    let key = options::parse_trace_path(key)?;
+    let (limits, rest) = materialize::parse_limits(args)?;
+    let options = RegFsOptions::from_args(rest)?.build()?;
+
+    let report = RegFs::new(&options).materialize(&key, output, limits)?;
+    for error in &report.errors {
+        println!("{}", error);
+    }
+    println!("{}, failed {}", report.summary, report.errors.len());
+    if !report.errors.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
 
 fn main() -> Result<()> {
-    env_logger::init();
-    let options = OptionBuilder::new().add_root_notification(
-        NotificationType::FILE_OPENED | NotificationType::PRE_RENAME | NotificationType::PRE_DELETE,
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("install") {
+        env_logger::init();
+        return eventlog::install();
+    }
+    if let [command, action, dump, output] = args.as_slice() {
+        if command == "overlay" && action == "export" {
+            env_logger::init();
+            return overlay::export_dump(dump.as_ref(), output.as_ref());
+        }
+    }
+
+    if let [command, root, rest @ ..] = args.as_slice() {
+        if command == "status" {
+            env_logger::init();
+            return status(root.as_ref(), rest.iter().any(|arg| arg == "--json"));
+        }
+    }
+
+    if let [command, rest @ ..] = args.as_slice() {
+        if command == "doctor" {
+            env_logger::init();
+            return doctor(rest);
+        }
+    }
+
+    if let [command, key, output, rest @ ..] = args.as_slice() {
+        if command == "materialize" {
+            env_logger::init();
+            return materialize(key, output.as_ref(), rest);
+        }
+    }
+
+    if let [command, trace, rest @ ..] = args.as_slice() {
+        if command == "replay" {
+            env_logger::init();
+            return replay(trace.as_ref(), rest);
+        }
+    }
+
+    let mut regfs_options = RegFsOptions::from_args(args)?.build()?;
+    init_logging(regfs_options.event_log);
+    // what gets logged, and what the instance stream goes on for `status`
+    regfs_options.root = virtroot::resolve(
+        &regfs_options.root,
+        &std::env::current_dir()?,
+        &virtroot::LiveProbe,
+    )?;
+    redact::allow_unsafe_values(regfs_options.log_unsafe_values);
+    if regfs_options.log_unsafe_values {
+        warn!(target: redact::TARGET, "value contents are logged at trace level");
+    }
+    let notifications = NotificationType::FILE_OPENED
+        | NotificationType::PRE_RENAME
+        | NotificationType::PRE_DELETE
+        | NotificationType::FILE_PRE_CONVERT_TO_FULL
+        | NotificationType::FILE_HANDLE_CLOSED_NO_MODIFICATION
+        | NotificationType::FILE_HANDLE_CLOSED_FILE_MODIFIED
+        // refused unless --allow-hardlinks, and audited when allowed; asked for even when
+        // read-only, since that can be switched off while mounted
+        | NotificationType::PRE_SET_HARDLINK
+        | NotificationType::HARDLINK_CREATED;
+    let regfs = RegFs::new(&regfs_options);
+    // the overlay has to hear about every change that went through, wherever it is, and
+    // write-back about new keys and files that were emptied
+    let always = match (regfs_options.overlay(), regfs.writer().is_some()) {
+        (true, _) => {
+            NotificationType::NEW_FILE_CREATED
+                | NotificationType::FILE_OVERWRITTEN
+                | NotificationType::FILE_RENAMED
+                | NotificationType::FILE_HANDLE_CLOSED_FILE_DELETED
+        }
+        (false, true) => NotificationType::NEW_FILE_CREATED | NotificationType::FILE_OVERWRITTEN,
+        (false, false) => NotificationType::empty(),
+    };
+    notifymap::validate(&regfs_options.notify_maps, regfs.regops())?;
+    bookmarks::validate(&regfs_options.bookmarks, regfs.regops())?;
+    let mappings = notifymap::mappings(
+        &regfs_options.notify_maps,
+        notifications.bits(),
+        always.bits(),
     );
-    let regfs: Box<dyn ProviderT> = Box::new(RegFs::new());
+    let options = mappings
+        .into_iter()
+        .fold(OptionBuilder::new(), |options, (path, mask)| {
+            let mask = NotificationType::from_bits_truncate(mask);
+            match path.as_os_str().is_empty() {
+                true => options.add_root_notification(mask),
+                false => options.add_notification(path, mask),
+            }
+        });
+
+    if let Some(interval) = regfs_options.dehydrate_interval {
+        regfs.spawn_dehydrate_timer(interval);
+    }
+    if let Some(interval) = regfs_options.activity_summary {
+        regfs.spawn_activity_summary(interval);
+    }
+
+    let provider = Provider::new(
+        regfs_options.root.clone(),
+        options,
+        Box::new(regfs.clone()) as Box<dyn ProviderT>,
+    )?;
+    info!(
+        target: eventlog::LIFECYCLE,
+        "provider started on {:?}, backend {}",
+        regfs_options.root,
+        regfs_options.backend_stack()
+    );
+    info!(target: eventlog::LIFECYCLE, "ProjFS: {}", regfs.projfs());
+    if let Some(record) = &regfs_options.record {
+        info!(target: "trace", "recording callbacks to {:?}", record);
+    }
+
+    // the hives are open by now, and ProjFS is started
+    if regfs_options.drop_privileges || regfs_options.lower_integrity {
+        let keep: Vec<&str> = match regfs_options.drop_privileges {
+            false => privileges::DANGEROUS.to_vec(),
+            true => [
+                // --impersonate can't act as another user without it
+                (regfs_options.impersonate, "SeImpersonatePrivilege"),
+                // nor --backup-semantics open the keys only a backup may
+                (regfs_options.backup_semantics, privileges::BACKUP),
+            ]
+            .into_iter()
+            .filter_map(|(needed, privilege)| needed.then_some(privilege))
+            .collect(),
+        };
+        if let Err(e) = privileges::drop_process_privileges(&keep, regfs_options.lower_integrity) {
+            warn!(target: "privileges", "privileges not fully dropped: {}", e);
+        }
+    }
+
+    // the mount is up either way; a hive that doesn't work is better found out now than as
+    // an empty directory in Explorer
+    if regfs_options.selftest {
+        let selftest = regfs.selftest();
+        selftest.log();
+        if regfs_options.strict_selftest && selftest.verdict() == doctor::Verdict::Fail {
+            return Err(anyhow!("selftest: {}", selftest));
+        }
+    }
 
-    let _provider = Provider::new("../test".into(), options, regfs)?;
+    regfs.spawn_auto_stop();
+    console::spawn(regfs.clone());
+    let pipe = match regfs_options.control_pipe {
+        true => Some(PipeServer::start(regfs.clone(), pipe::instance_name())?),
+        false => None,
+    };
+    if let Some(pipe) = &pipe {
+        if let Err(e) = pipe::persist_instance(&regfs_options.root, pipe.name()) {
+            warn!(target: "control", "`regfs status` won't find the pipe: {}", e);
+        }
+    }
+    regfs.wait_for_quit();
+    // whatever happened since the last one
+    if regfs_options.activity_summary.is_some() {
+        regfs.log_activity_summary();
+    }
 
-    loop {}
+    if pipe.is_some() {
+        pipe::forget_instance(&regfs_options.root);
+    }
+    drop(pipe);
+    drop(provider);
+    info!(target: eventlog::LIFECYCLE, "provider on {:?} stopped", regfs_options.root);
+    Ok(())
 }
@@ -0,0 +1,353 @@
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+use crate::links::LinkWalk;
+use crate::opcontext::OpContext;
+use crate::regfs::RegFs;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaterializeLimits {
+    // levels of subkeys under the key materialized
+    pub max_depth: usize,
+    // over every file written; a file that would go past it is left out
+    pub max_bytes: u64,
+}
+
+impl Default for MaterializeLimits {
+    fn default() -> Self {
+        MaterializeLimits {
+            max_depth: 32,
+            max_bytes: 256 << 20,
+        }
+    }
+}
+
+// `--max-depth <n>` and `--max-bytes <n>` out of the arguments, the rest are the mount's
+pub fn parse_limits(args: &[String]) -> Result<(MaterializeLimits, Vec<String>)> {
+    let mut limits = MaterializeLimits::default();
+    let mut rest = Vec::new();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| anyhow!("missing value for [{}]", arg))
+        };
+        match arg.as_str() {
+            "--max-depth" => {
+                limits.max_depth = value()?
+                    .parse()
+                    .map_err(|_| anyhow!("invalid depth for [{}]", arg))?
+            }
+            "--max-bytes" => match value()?.parse() {
+                Ok(max) if max > 0 => limits.max_bytes = max,
+                _ => return Err(anyhow!("invalid byte count for [{}]", arg)),
+            },
+            _ => rest.push(arg.clone()),
+        }
+    }
+    Ok((limits, rest))
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaterializeSummary {
+    pub dirs: usize,
+    pub files: usize,
+    pub bytes: u64,
+    // keys and values past --max-depth or --max-bytes
+    pub skipped: usize,
+    // keys left out because a registry link led back up the tree
+    pub cycles: usize,
+}
+
+impl fmt::Display for MaterializeSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "wrote {} directories and {} files ({} bytes), over the limits {}, link cycles {}",
+            self.dirs, self.files, self.bytes, self.skipped, self.cycles
+        )
+    }
+}
+
+// one entry that couldn't be written, the rest went on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryError {
+    pub path: PathBuf,
+    pub error: String,
+}
+
+impl fmt::Display for EntryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{:?}]: {}", self.path, self.error)
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct MaterializeReport {
+    pub summary: MaterializeSummary,
+    pub errors: Vec<EntryError>,
+}
+
+impl MaterializeReport {
+    fn failed(&mut self, path: &Path, error: impl fmt::Display) {
+        warn!(target: "materialize", "[{:?}]: {}", path, error);
+        self.errors.push(EntryError {
+            path: path.to_path_buf(),
+            error: error.to_string(),
+        });
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum MaterializeError {
+    #[error("materialize: [{0:?}] isn't a key")]
+    NotFound(PathBuf),
+    #[error("materialize: [{0:?}] isn't empty")]
+    NotEmpty(PathBuf),
+    #[error("materialize: unable to create [{path:?}]: {source}")]
+    Output { path: PathBuf, source: io::Error },
+}
+
+impl RegFs {
+    // what the mount would show under `key`, written out to `output` as real directories
+    // and files; an entry that fails is reported at the end, the rest is still written
+    pub fn materialize(
+        &self,
+        key: &Path,
+        output: &Path,
+        limits: MaterializeLimits,
+    ) -> Result<MaterializeReport, MaterializeError> {
+        if !self.regops().does_key_exist(key) {
+            return Err(MaterializeError::NotFound(key.to_path_buf()));
+        }
+        // never mixed with whatever was there
+        if fs::read_dir(output).map_or(false, |mut dir| dir.next().is_some()) {
+            return Err(MaterializeError::NotEmpty(output.to_path_buf()));
+        }
+        fs::create_dir_all(output).map_err(|source| MaterializeError::Output {
+            path: output.to_path_buf(),
+            source,
+        })?;
+
+        info!(target: "materialize", "----> materialize: [{:?}] to {:?}", key, output);
+        let mut report = MaterializeReport::default();
+        self.materialize_dir(
+            key,
+            output,
+            0,
+            &limits,
+            &mut LinkWalk::default(),
+            &mut report,
+        );
+        info!(
+            target: "materialize",
+            "<---- materialize: [{:?}] {}, failed {}",
+            key,
+            report.summary,
+            report.errors.len()
+        );
+        Ok(report)
+    }
+
+    fn materialize_dir(
+        &self,
+        key: &Path,
+        dir: &Path,
+        depth: usize,
+        limits: &MaterializeLimits,
+        walk: &mut LinkWalk,
+        report: &mut MaterializeReport,
+    ) {
+        if let Err(cycle) = walk.enter(self.regops(), key) {
+            warn!(target: "materialize", "skipping [{:?}]: {}", key, cycle);
+            report.summary.cycles += 1;
+            return;
+        }
+        let entries = match self.list_projected(key) {
+            Ok(Some(entries)) => entries,
+            Ok(None) => {
+                report.failed(key, "gone before it could be read");
+                walk.leave();
+                return;
+            }
+            Err(cancelled) => {
+                report.failed(key, cancelled);
+                walk.leave();
+                return;
+            }
+        };
+
+        for entry in entries {
+            let path = key.join(entry.filename());
+            // only in the projection, never written out as if it were registry data
+            if self.synthetic(&path).is_some() {
+                continue;
+            }
+            let local = dir.join(entry.filename());
+            if entry.is_directory() {
+                if depth >= limits.max_depth {
+                    report.summary.skipped += 1;
+                    continue;
+                }
+                match fs::create_dir(&local) {
+                    Ok(()) => report.summary.dirs += 1,
+                    Err(e) => {
+                        report.failed(&path, e);
+                        continue;
+                    }
+                }
+                self.materialize_dir(&path, &local, depth + 1, limits, walk, report);
+                continue;
+            }
+
+            let content = match self.read_projected_value(&path, &OpContext::none()) {
+                Ok(Some(content)) => content,
+                Ok(None) => {
+                    report.failed(&path, "gone before it could be read");
+                    continue;
+                }
+                Err(cancelled) => {
+                    report.failed(&path, cancelled);
+                    continue;
+                }
+            };
+            if report.summary.bytes + content.len() as u64 > limits.max_bytes {
+                report.summary.skipped += 1;
+                continue;
+            }
+            match fs::write(&local, &content) {
+                Ok(()) => {
+                    report.summary.files += 1;
+                    report.summary.bytes += content.len() as u64;
+                }
+                Err(e) => report.failed(&path, e),
+            }
+        }
+        walk.leave();
+    }
+}
+
+#[test]
+fn test_parse_limits() {
+    let args = |text: &str| -> Vec<String> { text.split(' ').map(String::from).collect() };
+
+    let (limits, rest) =
+        parse_limits(&args("--max-depth 2 --values-json --max-bytes 1024")).unwrap();
+    assert_eq!(
+        limits,
+        MaterializeLimits {
+            max_depth: 2,
+            max_bytes: 1024
+        }
+    );
+    assert_eq!(rest, ["--values-json"]);
+    assert!(parse_limits(&args("--max-bytes 0")).is_err());
+    assert!(parse_limits(&args("--max-depth")).is_err());
+}
+
+#[test]
+fn test_materialize() {
+    use crate::memory::MemoryBackend;
+    use crate::options::RegFsOptions;
+    use crate::transform::HexDump;
+    use std::sync::Arc;
+
+    let backend = Arc::new(MemoryBackend::new());
+    let app = Path::new("HKEY_LOCAL_MACHINE\\SOFTWARE\\MyApp");
+    backend.set_value(app, "Name", 1, b"MyApp\0".to_vec());
+    backend.set_value(app, "Blob", 3, vec![0xde, 0xad]);
+    backend.set_value(app.join("Settings"), "Level", 4, vec![3, 0, 0, 0]);
+    backend.set_value(app.join("Settings\\Deep"), "Far", 1, b"x\0".to_vec());
+    backend.add_key(app.join("Empty"));
+    let mut options = RegFsOptions {
+        values_json: true,
+        ..Default::default()
+    };
+    options.transformers.add(
+        "HKEY_LOCAL_MACHINE\\SOFTWARE\\MyApp\\Blob",
+        Arc::new(HexDump),
+    );
+    let regfs = RegFs::with_backend(&options, backend);
+
+    let output = std::env::temp_dir().join(format!("regfs-materialize-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&output);
+    // every file under `dir` and its content, in path order
+    let files = |dir: &Path| {
+        let mut files = Vec::new();
+        let mut pending = vec![dir.to_path_buf()];
+        while let Some(next) = pending.pop() {
+            for entry in fs::read_dir(&next).unwrap().flatten() {
+                match entry.file_type().unwrap().is_dir() {
+                    true => pending.push(entry.path()),
+                    false => files.push((
+                        entry.path().strip_prefix(dir).unwrap().to_path_buf(),
+                        fs::read(entry.path()).unwrap(),
+                    )),
+                }
+            }
+        }
+        files.sort();
+        files
+    };
+
+    let report = regfs
+        .materialize(app, &output, MaterializeLimits::default())
+        .unwrap();
+    assert!(report.errors.is_empty());
+    assert_eq!(
+        (report.summary.dirs, report.summary.files),
+        (3, 4),
+        "{}",
+        report.summary
+    );
+    let written = files(&output);
+    let names: Vec<&Path> = written.iter().map(|(path, _)| path.as_path()).collect();
+    let expected: Vec<PathBuf> = ["Blob", "Name", "Settings\\Deep\\Far", "Settings\\Level"]
+        .iter()
+        .map(|name| name.split('\\').collect())
+        .collect();
+    assert_eq!(names, expected);
+    // what the mount would serve, transformers included, but never the generated files
+    assert_eq!(
+        written[0].1,
+        regfs
+            .read_projected_value(&app.join("Blob"), &OpContext::none())
+            .unwrap()
+            .unwrap()
+    );
+    assert_ne!(written[0].1, [0xde, 0xad]);
+    assert_eq!(written[1].1, b"MyApp\0");
+    assert!(output.join("Empty").is_dir());
+    assert_eq!(
+        report.summary.bytes,
+        written.iter().map(|(_, data)| data.len() as u64).sum()
+    );
+
+    // only into an empty directory
+    assert!(matches!(
+        regfs.materialize(app, &output, MaterializeLimits::default()),
+        Err(MaterializeError::NotEmpty(_))
+    ));
+    fs::remove_dir_all(&output).unwrap();
+
+    // past the limits, entries are counted and left out
+    let limits = MaterializeLimits {
+        max_depth: 1,
+        max_bytes: 1 << 20,
+    };
+    let report = regfs.materialize(app, &output, limits).unwrap();
+    assert_eq!(report.summary.skipped, 1);
+    assert!(!output.join("Settings\\Deep").exists());
+    fs::remove_dir_all(&output).unwrap();
+
+    assert!(matches!(
+        regfs.materialize(&app.join("Missing"), &output, limits),
+        Err(MaterializeError::NotFound(_))
+    ));
+}
@@ -0,0 +1,653 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsString,
+    io,
+    os::windows::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        RwLock,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::backend::{self, PathKind, RegistryBackend};
+use crate::opcontext::{Cancelled, OpContext};
+use crate::regop::{paths, paths::RegPath, EntryError, RegEntires, RegEntry, MAX_ENTRY_ERRORS};
+use crate::watch::Watchers;
+
+#[derive(Default, Debug)]
+struct MemoryKey {
+    name: OsString,
+    last_write_time: i64,
+    subkeys: BTreeMap<Folded, MemoryKey>,
+    values: BTreeMap<Folded, (OsString, u32, Vec<u8>)>,
+    // values known only by type, size and hash until someone fetches their data
+    lazy: BTreeMap<Folded, LazyValue>,
+    // subkeys and values that fail to enumerate
+    failing: BTreeSet<Folded>,
+    // values that get longer by this much every time they're read
+    growing: BTreeMap<Folded, Vec<u8>>,
+    // there, but can't be opened
+    denied: bool,
+    // a symbolic link, to this key; nothing follows it here
+    link: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LazyValue {
+    pub name: OsString,
+    pub vtype: u32,
+    pub size: u64,
+    pub hash: u64,
+}
+
+// in-memory registry, used by tests and anywhere the live registry must not be touched
+#[derive(Default, Debug)]
+pub struct MemoryBackend {
+    root: RwLock<MemoryKey>,
+    reads: AtomicUsize,
+    // value reads in progress, the most there were at once, and how long each one takes
+    reading: AtomicUsize,
+    peak_reading: AtomicUsize,
+    read_delay_us: AtomicU64,
+    // the same for listings
+    listing: AtomicUsize,
+    peak_listing: AtomicUsize,
+    listing_delay_us: AtomicU64,
+    watchers: Watchers,
+}
+
+// one value read (or listing) in progress, until dropped
+struct Reading<'a>(&'a AtomicUsize);
+
+impl Drop for Reading<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// a name the way it's looked up: registry names are case-insensitive, but an unpaired
+// surrogate is kept as it is, so two names that differ only there don't collide
+type Folded = Vec<u16>;
+
+fn fold(name: &OsString) -> Folded {
+    let mut folded = Vec::new();
+    for c in char::decode_utf16(name.encode_wide()) {
+        match c {
+            Ok(c) => {
+                for lower in c.to_lowercase() {
+                    folded.extend_from_slice(lower.encode_utf16(&mut [0; 2]));
+                }
+            }
+            Err(e) => folded.push(e.unpaired_surrogate()),
+        }
+    }
+    folded
+}
+
+fn entry_error(is_subkey: bool, name: &OsString) -> EntryError {
+    EntryError {
+        is_subkey,
+        name: Some(name.clone()),
+        // ERROR_ACCESS_DENIED
+        error: io::Error::from_raw_os_error(5),
+    }
+}
+
+fn components(path: &Path) -> Vec<OsString> {
+    let path = RegPath::parse(path);
+    path.hive.into_iter().chain(path.keys).collect()
+}
+
+impl MemoryKey {
+    fn find(&self, parts: &[OsString]) -> Option<&MemoryKey> {
+        parts
+            .iter()
+            .try_fold(self, |key, part| key.subkeys.get(&fold(part)))
+    }
+
+    fn find_mut(&mut self, parts: &[OsString]) -> Option<&mut MemoryKey> {
+        parts
+            .iter()
+            .try_fold(self, |key, part| key.subkeys.get_mut(&fold(part)))
+    }
+
+    fn find_or_create(&mut self, parts: &[OsString]) -> &mut MemoryKey {
+        parts.iter().fold(self, |key, part| {
+            key.subkeys.entry(fold(part)).or_insert_with(|| MemoryKey {
+                name: part.clone(),
+                ..Default::default()
+            })
+        })
+    }
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn add_key<T: AsRef<Path>>(&self, path: T) {
+        let parts = components(path.as_ref());
+        self.root.write().unwrap().find_or_create(&parts);
+        self.watchers.notify(path.as_ref());
+    }
+
+    pub fn set_value<T: AsRef<Path>, N: Into<OsString>>(
+        &self,
+        key: T,
+        name: N,
+        vtype: u32,
+        data: Vec<u8>,
+    ) {
+        let parts = components(key.as_ref());
+        let name = name.into();
+        let mut root = self.root.write().unwrap();
+        let memory_key = root.find_or_create(&parts);
+        memory_key.lazy.remove(&fold(&name));
+        memory_key.values.insert(fold(&name), (name, vtype, data));
+        drop(root);
+        self.watchers.notify(key.as_ref());
+    }
+
+    // listed with its size, but reads of it return nothing until set_value fills it in
+    pub fn set_lazy_value<T: AsRef<Path>>(&self, key: T, value: LazyValue) {
+        let parts = components(key.as_ref());
+        let mut root = self.root.write().unwrap();
+        let memory_key = root.find_or_create(&parts);
+        memory_key.values.remove(&fold(&value.name));
+        memory_key.lazy.insert(fold(&value.name), value);
+    }
+
+    // `tail` is appended to the value after every read of it, like a log written to while
+    // it's projected
+    pub fn grow_on_read<T: AsRef<Path>, N: Into<OsString>>(&self, key: T, name: N, tail: Vec<u8>) {
+        let parts = components(key.as_ref());
+        self.root
+            .write()
+            .unwrap()
+            .find_or_create(&parts)
+            .growing
+            .insert(fold(&name.into()), tail);
+    }
+
+    pub fn lazy_value(&self, path: &Path) -> Option<LazyValue> {
+        let mut parts = components(path);
+        let name = parts.pop()?;
+
+        let root = self.root.read().unwrap();
+        root.find(&parts)?.lazy.get(&fold(&name)).cloned()
+    }
+
+    pub fn lazy_values(&self, key: &Path) -> Vec<LazyValue> {
+        let root = self.root.read().unwrap();
+        root.find(&components(key))
+            .map(|key| key.lazy.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    // the key or value at `path`, whichever there is; false when neither
+    pub fn remove<T: AsRef<Path>>(&self, path: T) -> bool {
+        let mut parts = components(path.as_ref());
+        let name = match parts.pop() {
+            Some(name) => fold(&name),
+            None => return false,
+        };
+
+        let mut root = self.root.write().unwrap();
+        let key = match root.find_mut(&parts) {
+            Some(key) => key,
+            None => return false,
+        };
+        let removed = key.subkeys.remove(&name).is_some()
+            | key.values.remove(&name).is_some()
+            | key.lazy.remove(&name).is_some();
+        drop(root);
+
+        if removed {
+            self.watchers.notify(&paths::join_parts(&parts));
+        }
+        removed
+    }
+
+    pub fn clear(&self) {
+        *self.root.write().unwrap() = MemoryKey::default();
+    }
+
+    pub fn set_last_write_time<T: AsRef<Path>>(&self, path: T, time: i64) {
+        let parts = components(path.as_ref());
+        self.root
+            .write()
+            .unwrap()
+            .find_or_create(&parts)
+            .last_write_time = time;
+    }
+
+    // the subkey or value `name` of `key` will fail to enumerate with access denied
+    pub fn fail_entry<T: AsRef<Path>, N: Into<OsString>>(&self, key: T, name: N) {
+        let parts = components(key.as_ref());
+        self.root
+            .write()
+            .unwrap()
+            .find_or_create(&parts)
+            .failing
+            .insert(fold(&name.into()));
+    }
+
+    // the key can't be opened, as one whose DACL shuts us out: listing it, its last write
+    // time and its values all fail
+    pub fn deny_key<T: AsRef<Path>>(&self, path: T) {
+        let parts = components(path.as_ref());
+        self.root.write().unwrap().find_or_create(&parts).denied = true;
+    }
+
+    pub fn add_link<T: AsRef<Path>, U: Into<PathBuf>>(&self, path: T, target: U) {
+        let parts = components(path.as_ref());
+        self.root.write().unwrap().find_or_create(&parts).link = Some(target.into());
+    }
+
+    // number of value reads served, so tests can tell cache hits from backend reads
+    pub fn reads(&self) -> usize {
+        self.reads.load(Ordering::SeqCst)
+    }
+
+    // every value read takes at least `delay`, so reads overlap the way slow ones would
+    pub fn slow_reads(&self, delay: Duration) {
+        self.read_delay_us
+            .store(delay.as_micros() as u64, Ordering::SeqCst);
+    }
+
+    // the most value reads that were ever in progress at once
+    pub fn peak_concurrent_reads(&self) -> usize {
+        self.peak_reading.load(Ordering::SeqCst)
+    }
+
+    // every listing takes at least `delay`
+    pub fn slow_listings(&self, delay: Duration) {
+        self.listing_delay_us
+            .store(delay.as_micros() as u64, Ordering::SeqCst);
+    }
+
+    // the most listings that were ever in progress at once
+    pub fn peak_concurrent_listings(&self) -> usize {
+        self.peak_listing.load(Ordering::SeqCst)
+    }
+
+    fn start_read(&self) -> Reading<'_> {
+        start(&self.reading, &self.peak_reading, &self.read_delay_us)
+    }
+
+    fn start_listing(&self) -> Reading<'_> {
+        start(&self.listing, &self.peak_listing, &self.listing_delay_us)
+    }
+}
+
+fn start<'a>(count: &'a AtomicUsize, peak: &AtomicUsize, delay_us: &AtomicU64) -> Reading<'a> {
+    let now = count.fetch_add(1, Ordering::SeqCst) + 1;
+    peak.fetch_max(now, Ordering::SeqCst);
+    match delay_us.load(Ordering::SeqCst) {
+        0 => {}
+        delay => thread::sleep(Duration::from_micros(delay)),
+    }
+    Reading(count)
+}
+
+impl RegistryBackend for MemoryBackend {
+    fn enumerate_key_ctx(
+        &self,
+        path: OsString,
+        ctx: &OpContext,
+    ) -> Result<Option<RegEntires>, Cancelled> {
+        let _listing = self.start_listing();
+        let root = self.root.read().unwrap();
+        let key = match root.find(&components(path.as_ref())) {
+            Some(key) if !key.denied => key,
+            _ => return Ok(None),
+        };
+
+        // most keys have nothing failing, and their entries needn't be folded to find out
+        let failing =
+            |name: &OsString| !key.failing.is_empty() && key.failing.contains(&fold(name));
+        let mut entries = RegEntires::default();
+        ctx.paginate(key.subkeys.values(), |subkey| {
+            if failing(&subkey.name) {
+                entries.record_error(entry_error(true, &subkey.name));
+            } else {
+                entries.subkeys.push(RegEntry {
+                    last_write_time: subkey.last_write_time,
+                    ..RegEntry::new(subkey.name.clone(), 0)
+                })
+            }
+        })?;
+        ctx.paginate(key.values.values(), |(name, _, data)| {
+            if failing(name) {
+                entries.record_error(entry_error(false, name));
+            } else {
+                entries
+                    .values
+                    .push(RegEntry::new(name.clone(), data.len() as u64))
+            }
+        })?;
+        ctx.paginate(key.lazy.values(), |value| {
+            entries
+                .values
+                .push(RegEntry::new(value.name.clone(), value.size))
+        })?;
+        paths::name_default_value(&mut entries.values, |entry| &mut entry.name);
+
+        Ok(Some(entries))
+    }
+
+    fn read_typed_value_ctx(
+        &self,
+        path: &Path,
+        ctx: &OpContext,
+    ) -> Result<Option<(u32, Vec<u8>)>, Cancelled> {
+        let mut parts = components(path);
+        let name = match parts.pop() {
+            Some(name) if !parts.is_empty() => name,
+            _ => return Ok(None),
+        };
+
+        ctx.check()?;
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        let _reading = self.start_read();
+        let root = self.root.read().unwrap();
+        let key = match root.find(&parts) {
+            Some(key) if !key.denied => key,
+            _ => return Ok(None),
+        };
+        let value = key.values.get(&fold(&name)).or_else(|| {
+            let unnamed = paths::default_value_fallback(&name)?;
+            key.values.get(&fold(&unnamed))
+        });
+        let value = value.map(|(_, vtype, data)| (*vtype, data.clone()));
+        let grows = key.growing.contains_key(&fold(&name));
+        drop(root);
+
+        if grows {
+            let mut root = self.root.write().unwrap();
+            if let Some(key) = root.find_mut(&parts) {
+                if let (Some(tail), Some((_, _, data))) = (
+                    key.growing.get(&fold(&name)),
+                    key.values.get_mut(&fold(&name)),
+                ) {
+                    data.extend_from_slice(tail);
+                }
+            }
+        }
+        Ok(value)
+    }
+
+    fn read_all_values(&self, path: &Path) -> Option<Vec<(OsString, u32, Vec<u8>)>> {
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        let root = self.root.read().unwrap();
+        let mut values: Vec<_> = root
+            .find(&components(path))
+            .filter(|key| !key.denied)?
+            .values
+            .values()
+            .cloned()
+            .collect();
+        paths::name_default_value(&mut values, |(name, _, _)| name);
+        Some(values)
+    }
+
+    fn does_key_exist(&self, path: &Path) -> bool {
+        self.key_last_write_time(path).is_some()
+    }
+
+    // sized without counting as a read, the way RegOps sizes a value without reading it
+    fn does_value_exist(&self, path: &Path) -> Option<usize> {
+        let mut parts = components(path);
+        let name = parts.pop().filter(|_| !parts.is_empty())?;
+        let root = self.root.read().unwrap();
+        let key = root.find(&parts).filter(|key| !key.denied)?;
+        let value = key.values.get(&fold(&name)).or_else(|| {
+            let unnamed = paths::default_value_fallback(&name)?;
+            key.values.get(&fold(&unnamed))
+        });
+        value.map(|(_, _, data)| data.len())
+    }
+
+    fn watchers(&self) -> Option<&Watchers> {
+        Some(&self.watchers)
+    }
+
+    fn key_last_write_time(&self, path: &Path) -> Option<i64> {
+        let parts = components(path);
+        if parts.is_empty() {
+            return None;
+        }
+
+        let root = self.root.read().unwrap();
+        root.find(&parts)
+            .filter(|key| !key.denied)
+            .map(|key| key.last_write_time)
+    }
+
+    fn link_target(&self, path: &Path) -> Option<PathBuf> {
+        let parts = components(path);
+        if parts.is_empty() {
+            return None;
+        }
+        let root = self.root.read().unwrap();
+        root.find(&parts)?.link.clone()
+    }
+
+    fn classify(&self, path: &Path, ctx: &OpContext) -> Result<PathKind, Cancelled> {
+        let parts = components(path);
+        let denied = {
+            let root = self.root.read().unwrap();
+            !parts.is_empty() && root.find(&parts).map_or(false, |key| key.denied)
+        };
+        match denied {
+            true => Ok(PathKind::Denied),
+            false => backend::classify_by_lookup(self, path, ctx),
+        }
+    }
+}
+
+#[test]
+fn test_memory_backend() {
+    let backend = MemoryBackend::new();
+    backend.set_value("HKEY_CURRENT_USER\\Software\\App", "Name", 1, vec![1, 2]);
+    backend.add_key("HKEY_CURRENT_USER\\Software\\Other");
+
+    assert!(backend.does_key_exist("HKEY_CURRENT_USER\\software".as_ref()));
+    assert!(!backend.does_key_exist("HKEY_CURRENT_USER\\Missing".as_ref()));
+    assert_eq!(
+        backend.read_value("HKEY_CURRENT_USER\\Software\\App\\name".as_ref()),
+        Some(vec![1, 2])
+    );
+    assert_eq!(backend.read_value("HKEY_CURRENT_USER".as_ref()), None);
+
+    let entries = backend
+        .enumerate_key("HKEY_CURRENT_USER\\Software".into())
+        .unwrap();
+    let names: Vec<_> = entries.subkeys.iter().map(|e| e.name.clone()).collect();
+    assert_eq!(names, vec![OsString::from("App"), OsString::from("Other")]);
+
+    let root = backend.enumerate_key("".into()).unwrap();
+    assert_eq!(root.subkeys.len(), 1);
+
+    assert!(backend.remove("HKEY_CURRENT_USER\\Software\\App\\NAME"));
+    assert!(backend.remove("HKEY_CURRENT_USER\\Software\\Other"));
+    assert!(!backend.remove("HKEY_CURRENT_USER\\Software\\Other"));
+    assert!(backend.does_key_exist("HKEY_CURRENT_USER\\Software\\App".as_ref()));
+    assert_eq!(
+        backend.read_value("HKEY_CURRENT_USER\\Software\\App\\Name".as_ref()),
+        None
+    );
+}
+
+#[test]
+fn test_classify() {
+    let backend = MemoryBackend::new();
+    backend.set_value("HKEY_CURRENT_USER\\App", "Name", 1, vec![]);
+    backend.set_last_write_time("HKEY_CURRENT_USER\\App", 42);
+    backend.set_value("HKEY_CURRENT_USER\\Locked", "Secret", 1, vec![]);
+    backend.deny_key("HKEY_CURRENT_USER\\Locked");
+    let classify = |path: &str| backend.classify(path.as_ref(), &OpContext::none()).unwrap();
+
+    assert_eq!(classify(""), PathKind::Key(0));
+    assert_eq!(classify("HKEY_CURRENT_USER\\app"), PathKind::Key(42));
+    assert_eq!(classify("HKEY_CURRENT_USER\\App\\NAME"), PathKind::Value);
+    assert_eq!(classify("HKEY_CURRENT_USER\\App\\Other"), PathKind::Missing);
+    assert_eq!(classify("HKEY_CURRENT_USER\\Locked"), PathKind::Denied);
+    // what's under it can't be read either
+    assert_eq!(
+        classify("HKEY_CURRENT_USER\\Locked\\Secret"),
+        PathKind::Missing
+    );
+    assert!(backend
+        .enumerate_key("HKEY_CURRENT_USER\\Locked".into())
+        .is_none());
+}
+
+#[test]
+fn test_partial_enumeration() {
+    let backend = MemoryBackend::new();
+    backend.add_key("HKEY_LOCAL_MACHINE\\SOFTWARE\\Locked");
+    backend.add_key("HKEY_LOCAL_MACHINE\\SOFTWARE\\Open");
+    for i in 0..MAX_ENTRY_ERRORS + 2 {
+        let name = format!("Secret{}", i);
+        backend.set_value("HKEY_LOCAL_MACHINE\\SOFTWARE", name.as_str(), 1, vec![]);
+        backend.fail_entry("HKEY_LOCAL_MACHINE\\SOFTWARE", name);
+    }
+    backend.set_value("HKEY_LOCAL_MACHINE\\SOFTWARE", "Public", 1, vec![]);
+    backend.fail_entry("HKEY_LOCAL_MACHINE\\SOFTWARE", "locked");
+
+    let entries = backend
+        .enumerate_key("HKEY_LOCAL_MACHINE\\SOFTWARE".into())
+        .unwrap();
+    assert!(entries.partial);
+    assert_eq!(entries.subkeys.len(), 1);
+    assert_eq!(entries.values.len(), 1);
+    assert_eq!(entries.failed, MAX_ENTRY_ERRORS + 3);
+    assert_eq!(entries.errors.len(), MAX_ENTRY_ERRORS);
+    assert!(entries.errors[0].is_subkey);
+    assert_eq!(entries.errors[0].name, Some("Locked".into()));
+    assert_eq!(entries.errors[0].error.raw_os_error(), Some(5));
+
+    let entries = backend
+        .enumerate_key("HKEY_LOCAL_MACHINE\\SOFTWARE\\Open".into())
+        .unwrap();
+    assert!(!entries.partial);
+    assert_eq!(entries.failed, 0);
+}
+
+#[test]
+fn test_fuzz_value_names() {
+    use crate::fuzz::{self, Rng};
+    use std::os::windows::ffi::OsStringExt;
+
+    let key = Path::new("HKEY_CURRENT_USER\\Names");
+    let corpus = fuzz::corpus();
+    let mut rng = Rng::new(fuzz::seed(), 0);
+
+    for _ in 0..fuzz::iterations() / 10 {
+        let backend = MemoryBackend::new();
+        backend.add_key(key);
+        // what each name should read back as: the last value set under it, whatever its case
+        let mut expected: BTreeMap<Folded, (OsString, Vec<u8>)> = BTreeMap::new();
+
+        for n in 0..rng.below(8) as u32 {
+            let units: Vec<u16> = match rng.below(5) {
+                0 => Vec::new(),
+                1 => rng.text(paths::DEFAULT_VALUE, "").encode_utf16().collect(),
+                // longer than any file name the file system allows
+                2 => (0..256 + rng.below(512))
+                    .map(|_| b'a' as u16 + rng.below(26) as u16)
+                    .collect(),
+                _ => {
+                    let (seed, other) = (rng.pick(&corpus), rng.pick(&corpus));
+                    rng.mutate(seed, other)
+                }
+            };
+            // nothing maps a separator, a NUL or `.` to a file name, so those can't be
+            // reached through a path at all
+            if units
+                .iter()
+                .any(|unit| [0, b'\\' as u16, b'/' as u16].contains(unit))
+                || units == [b'.' as u16]
+            {
+                continue;
+            }
+            let name = OsString::from_wide(&units);
+            let data = n.to_le_bytes().to_vec();
+            backend.set_value(key, name.clone(), 3, data.clone());
+            expected.insert(fold(&name), (name, data));
+        }
+
+        let read = |name: &OsString| {
+            backend
+                .read_typed_value_ctx(
+                    &paths::join_parts([key.as_os_str(), name.as_os_str()]),
+                    &OpContext::none(),
+                )
+                .unwrap()
+                .map(|(_, data)| data)
+        };
+        let listed: Vec<OsString> = backend
+            .enumerate_key(key.into())
+            .unwrap()
+            .values
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+
+        // the same names every time, none of them colliding
+        let again: Vec<OsString> = backend
+            .enumerate_key(key.into())
+            .unwrap()
+            .values
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(listed, again);
+        let folded: BTreeSet<Folded> = listed.iter().map(fold).collect();
+        assert_eq!(folded.len(), listed.len(), "{:?}", listed);
+
+        // every name listed reads back as the value it stands for
+        let unnamed = expected.get(&Vec::new());
+        let default_taken = expected.contains_key(&fold(&paths::DEFAULT_VALUE.into()));
+        for name in &listed {
+            assert!(!name.is_empty());
+            let value = match expected.get(&fold(name)) {
+                Some((_, data)) => data,
+                None => {
+                    &unnamed
+                        .expect("only the unnamed value is listed as another name")
+                        .1
+                }
+            };
+            assert_eq!(read(name).as_ref(), Some(value), "{:?}", name);
+        }
+
+        // and every value is listed, apart from an unnamed one a real `(default)` hides
+        let hidden = usize::from(unnamed.is_some() && default_taken);
+        assert_eq!(listed.len(), expected.len() - hidden, "{:?}", listed);
+        for (name, data) in expected.values().filter(|(name, _)| !name.is_empty()) {
+            assert_eq!(read(name).as_ref(), Some(data), "{:?}", name);
+        }
+    }
+}
+
+#[test]
+fn test_names_differing_in_a_surrogate() {
+    use std::os::windows::ffi::OsStringExt;
+
+    let backend = MemoryBackend::new();
+    let high = OsString::from_wide(&[b'a' as u16, 0xd800]);
+    let low = OsString::from_wide(&[b'a' as u16, 0xdc00]);
+    backend.set_value("HKEY_CURRENT_USER\\Names", high.clone(), 3, vec![1]);
+    backend.set_value("HKEY_CURRENT_USER\\Names", low.clone(), 3, vec![2]);
+
+    let key = Path::new("HKEY_CURRENT_USER\\Names");
+    assert_eq!(backend.read_value(&key.join(&high)), Some(vec![1]));
+    assert_eq!(backend.read_value(&key.join(&low)), Some(vec![2]));
+    assert_eq!(backend.enumerate_key(key.into()).unwrap().values.len(), 2);
+}
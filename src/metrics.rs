@@ -0,0 +1,479 @@
+use log::info;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::events::RegFsEvent;
+use crate::observer::ProviderObserver;
+use crate::regfs::RegFs;
+use crate::regop::paths;
+
+// distinct keys the busiest-paths counter keeps track of
+const BUSY_PATHS: usize = 32;
+
+#[derive(Default, Debug)]
+pub struct Metrics {
+    pub dehydrated_files: AtomicU64,
+    pub reclaimed_bytes: AtomicU64,
+    pub cancelled_operations: AtomicU64,
+    pub partial_enumerations: AtomicU64,
+    pub registry_changes: AtomicU64,
+    pub dropped_events: AtomicU64,
+    pub registry_retries: AtomicU64,
+    pub denied_operations: AtomicU64,
+    pub callbacks: AtomicU64,
+    pub hydrated_bytes: AtomicU64,
+    pub enumerations: AtomicU64,
+    pub cache_hits: AtomicU64,
+    pub cache_misses: AtomicU64,
+    // --rate-limit, over every listed process; RegFs::status has them per process
+    pub throttle_delays: AtomicU64,
+    pub throttle_rejections: AtomicU64,
+    // --search-dir: searches run, rather than answered from a session
+    pub searches: AtomicU64,
+    // --value-cache
+    pub value_cache_hits: AtomicU64,
+    pub value_cache_misses: AtomicU64,
+    pub value_cache_evictions: AtomicU64,
+    // --max-hydrations, over every process; RegFs::status has them per process
+    pub hydration_waits: AtomicU64,
+    pub hydration_rejections: AtomicU64,
+    // a ProviderObserver that panicked, once per call it panicked in
+    pub observer_panics: AtomicU64,
+    pub busy_paths: BusyPaths,
+    // what the last activity summary was taken against
+    summarized: Mutex<MetricsSnapshot>,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub dehydrated_files: u64,
+    pub reclaimed_bytes: u64,
+    pub cancelled_operations: u64,
+    pub partial_enumerations: u64,
+    pub registry_changes: u64,
+    pub dropped_events: u64,
+    pub registry_retries: u64,
+    pub denied_operations: u64,
+    pub callbacks: u64,
+    pub hydrated_bytes: u64,
+    pub enumerations: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub throttle_delays: u64,
+    pub throttle_rejections: u64,
+    pub searches: u64,
+    pub value_cache_hits: u64,
+    pub value_cache_misses: u64,
+    pub value_cache_evictions: u64,
+    pub hydration_waits: u64,
+    pub hydration_rejections: u64,
+    pub observer_panics: u64,
+    // registry jobs that panicked, filled in by RegFs from its registry pool
+    pub registry_panics: u64,
+    // gauges, filled in by RegFs from its registry pool, its search sessions and whether
+    // it's suspended
+    pub registry_queue_depth: u64,
+    pub search_sessions: u64,
+    pub suspended: u64,
+}
+
+impl Metrics {
+    pub fn add(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            dehydrated_files: self.dehydrated_files.load(Ordering::Relaxed),
+            reclaimed_bytes: self.reclaimed_bytes.load(Ordering::Relaxed),
+            cancelled_operations: self.cancelled_operations.load(Ordering::Relaxed),
+            partial_enumerations: self.partial_enumerations.load(Ordering::Relaxed),
+            registry_changes: self.registry_changes.load(Ordering::Relaxed),
+            dropped_events: self.dropped_events.load(Ordering::Relaxed),
+            registry_retries: self.registry_retries.load(Ordering::Relaxed),
+            denied_operations: self.denied_operations.load(Ordering::Relaxed),
+            callbacks: self.callbacks.load(Ordering::Relaxed),
+            hydrated_bytes: self.hydrated_bytes.load(Ordering::Relaxed),
+            enumerations: self.enumerations.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_misses: self.cache_misses.load(Ordering::Relaxed),
+            throttle_delays: self.throttle_delays.load(Ordering::Relaxed),
+            throttle_rejections: self.throttle_rejections.load(Ordering::Relaxed),
+            searches: self.searches.load(Ordering::Relaxed),
+            value_cache_hits: self.value_cache_hits.load(Ordering::Relaxed),
+            value_cache_misses: self.value_cache_misses.load(Ordering::Relaxed),
+            value_cache_evictions: self.value_cache_evictions.load(Ordering::Relaxed),
+            hydration_waits: self.hydration_waits.load(Ordering::Relaxed),
+            hydration_rejections: self.hydration_rejections.load(Ordering::Relaxed),
+            observer_panics: self.observer_panics.load(Ordering::Relaxed),
+            registry_panics: 0,
+            registry_queue_depth: 0,
+            search_sessions: 0,
+            suspended: 0,
+        }
+    }
+
+    // one line of what happened since the last one, for --activity-summary
+    pub fn activity_summary(&self, sessions: usize) -> String {
+        let now = self.snapshot();
+        let delta = {
+            let mut summarized = self.summarized.lock().unwrap_or_else(|e| e.into_inner());
+            let delta = now.since(&summarized);
+            *summarized = now;
+            delta
+        };
+        let lookups = delta.cache_hits + delta.cache_misses;
+        let hit_rate = match lookups {
+            0 => "-".to_string(),
+            _ => format!("{}%", delta.cache_hits * 100 / lookups),
+        };
+        let busiest: Vec<String> = self
+            .busy_paths
+            .take_top(3)
+            .iter()
+            .map(|(path, count)| format!("{} ({})", path, count))
+            .collect();
+
+        format!(
+            "callbacks {}, hydrated {} bytes, enumerations {}, denied {}, cache hit rate {}, \
+             sessions {}, busiest: {}",
+            delta.callbacks,
+            delta.hydrated_bytes,
+            delta.enumerations,
+            delta.denied_operations,
+            hit_rate,
+            sessions,
+            match busiest.is_empty() {
+                true => "-".to_string(),
+                false => busiest.join(", "),
+            }
+        )
+    }
+}
+
+// callbacks per key two levels down (e.g. `HKEY_CURRENT_USER\Software`); once full, a new
+// key takes the place of the least busy one and starts from its count, so memory stays
+// fixed and a busy key that shows up late still makes it to the top
+#[derive(Default, Debug)]
+pub struct BusyPaths(Mutex<HashMap<String, u64>>);
+
+impl BusyPaths {
+    #[cfg(test)]
+    pub fn hit(&self, path: &std::path::Path) {
+        use std::os::windows::ffi::OsStrExt;
+
+        self.hit_wide(path.as_os_str().encode_wide());
+    }
+
+    // the key is made in a buffer kept by the thread, so a callback on a key that's already
+    // counted allocates nothing
+    pub fn hit_wide(&self, path: impl IntoIterator<Item = u16>) {
+        thread_local! {
+            static BUFFERS: RefCell<(Vec<u16>, String)> = RefCell::default();
+        }
+
+        BUFFERS.with(|buffers| {
+            let (units, key) = &mut *buffers.borrow_mut();
+            units.clear();
+            units.extend(path);
+            key.clear();
+            for (index, part) in paths::wide_parts(units).take(2).enumerate() {
+                if index > 0 {
+                    key.push('\\');
+                }
+                for c in char::decode_utf16(part.iter().copied()) {
+                    let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+                    // hive names are looked up case-insensitively
+                    key.push(match index {
+                        0 => c.to_ascii_uppercase(),
+                        _ => c,
+                    });
+                }
+            }
+            if !key.is_empty() {
+                self.count(key);
+            }
+        });
+    }
+
+    fn count(&self, key: &str) {
+        let mut counts = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = counts.get_mut(key) {
+            *count += 1;
+            return;
+        }
+
+        let mut start = 0;
+        if counts.len() >= BUSY_PATHS {
+            let least = counts
+                .iter()
+                .min_by_key(|(_, count)| **count)
+                .map(|(key, count)| (key.clone(), *count));
+            if let Some((least, count)) = least {
+                counts.remove(&least);
+                start = count;
+            }
+        }
+        counts.insert(key.to_owned(), start + 1);
+    }
+
+    // the busiest first, and starts counting again
+    pub fn take_top(&self, n: usize) -> Vec<(String, u64)> {
+        let counts = std::mem::take(&mut *self.0.lock().unwrap_or_else(|e| e.into_inner()));
+        let mut top: Vec<(String, u64)> = counts.into_iter().collect();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(n);
+        top
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+}
+
+impl RegFs {
+    pub fn log_activity_summary(&self) {
+        let summary = self.metrics().activity_summary(self.sessions().len());
+        info!(target: "activity", "{}", summary);
+    }
+
+    pub fn spawn_activity_summary(&self, interval: Duration) {
+        let regfs = self.clone();
+
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            regfs.log_activity_summary();
+        });
+    }
+}
+
+// the counters that follow from what a callback did rather than from it being called
+impl ProviderObserver for Metrics {
+    fn on_event(&self, event: &RegFsEvent) {
+        match event {
+            RegFsEvent::EnumerationStarted { .. } => Metrics::add(&self.enumerations, 1),
+            RegFsEvent::Hydrated { bytes, .. } => Metrics::add(&self.hydrated_bytes, *bytes),
+            RegFsEvent::OperationDenied { .. } => Metrics::add(&self.denied_operations, 1),
+            _ => {}
+        }
+    }
+
+    fn observes_callbacks(&self) -> bool {
+        false
+    }
+}
+
+impl MetricsSnapshot {
+    // in the order they are printed
+    pub fn fields(&self) -> [(&'static str, u64); 26] {
+        [
+            ("dehydrated_files", self.dehydrated_files),
+            ("reclaimed_bytes", self.reclaimed_bytes),
+            ("cancelled_operations", self.cancelled_operations),
+            ("partial_enumerations", self.partial_enumerations),
+            ("registry_changes", self.registry_changes),
+            ("dropped_events", self.dropped_events),
+            ("registry_retries", self.registry_retries),
+            ("denied_operations", self.denied_operations),
+            ("callbacks", self.callbacks),
+            ("hydrated_bytes", self.hydrated_bytes),
+            ("enumerations", self.enumerations),
+            ("cache_hits", self.cache_hits),
+            ("cache_misses", self.cache_misses),
+            ("throttle_delays", self.throttle_delays),
+            ("throttle_rejections", self.throttle_rejections),
+            ("searches", self.searches),
+            ("value_cache_hits", self.value_cache_hits),
+            ("value_cache_misses", self.value_cache_misses),
+            ("value_cache_evictions", self.value_cache_evictions),
+            ("hydration_waits", self.hydration_waits),
+            ("hydration_rejections", self.hydration_rejections),
+            ("observer_panics", self.observer_panics),
+            ("registry_panics", self.registry_panics),
+            ("registry_queue_depth", self.registry_queue_depth),
+            ("search_sessions", self.search_sessions),
+            ("suspended", self.suspended),
+        ]
+    }
+
+    // counters as they grew since `earlier`; the gauges are as they are now
+    pub fn since(&self, earlier: &MetricsSnapshot) -> MetricsSnapshot {
+        MetricsSnapshot {
+            dehydrated_files: self
+                .dehydrated_files
+                .saturating_sub(earlier.dehydrated_files),
+            reclaimed_bytes: self.reclaimed_bytes.saturating_sub(earlier.reclaimed_bytes),
+            cancelled_operations: self
+                .cancelled_operations
+                .saturating_sub(earlier.cancelled_operations),
+            partial_enumerations: self
+                .partial_enumerations
+                .saturating_sub(earlier.partial_enumerations),
+            registry_changes: self
+                .registry_changes
+                .saturating_sub(earlier.registry_changes),
+            dropped_events: self.dropped_events.saturating_sub(earlier.dropped_events),
+            registry_retries: self
+                .registry_retries
+                .saturating_sub(earlier.registry_retries),
+            denied_operations: self
+                .denied_operations
+                .saturating_sub(earlier.denied_operations),
+            callbacks: self.callbacks.saturating_sub(earlier.callbacks),
+            hydrated_bytes: self.hydrated_bytes.saturating_sub(earlier.hydrated_bytes),
+            enumerations: self.enumerations.saturating_sub(earlier.enumerations),
+            cache_hits: self.cache_hits.saturating_sub(earlier.cache_hits),
+            cache_misses: self.cache_misses.saturating_sub(earlier.cache_misses),
+            throttle_delays: self.throttle_delays.saturating_sub(earlier.throttle_delays),
+            throttle_rejections: self
+                .throttle_rejections
+                .saturating_sub(earlier.throttle_rejections),
+            searches: self.searches.saturating_sub(earlier.searches),
+            value_cache_hits: self
+                .value_cache_hits
+                .saturating_sub(earlier.value_cache_hits),
+            value_cache_misses: self
+                .value_cache_misses
+                .saturating_sub(earlier.value_cache_misses),
+            value_cache_evictions: self
+                .value_cache_evictions
+                .saturating_sub(earlier.value_cache_evictions),
+            hydration_waits: self.hydration_waits.saturating_sub(earlier.hydration_waits),
+            hydration_rejections: self
+                .hydration_rejections
+                .saturating_sub(earlier.hydration_rejections),
+            observer_panics: self.observer_panics.saturating_sub(earlier.observer_panics),
+            registry_panics: self.registry_panics.saturating_sub(earlier.registry_panics),
+            registry_queue_depth: self.registry_queue_depth,
+            search_sessions: self.search_sessions,
+            suspended: self.suspended,
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        self.fields()
+            .iter()
+            .map(|(name, value)| (name.to_string(), (*value).into()))
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+}
+
+impl fmt::Display for MetricsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, value) in self.fields() {
+            writeln!(f, "{} {}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_snapshot() {
+    let metrics = Metrics::default();
+    Metrics::add(&metrics.dehydrated_files, 2);
+    Metrics::add(&metrics.reclaimed_bytes, 4096);
+
+    let snapshot = metrics.snapshot();
+    assert_eq!(snapshot.dehydrated_files, 2);
+    assert_eq!(snapshot.reclaimed_bytes, 4096);
+    assert_eq!(
+        snapshot.to_string(),
+        "dehydrated_files 2\nreclaimed_bytes 4096\ncancelled_operations 0\npartial_enumerations 0\nregistry_changes 0\ndropped_events 0\nregistry_retries 0\ndenied_operations 0\ncallbacks 0\nhydrated_bytes 0\nenumerations 0\ncache_hits 0\ncache_misses 0\nthrottle_delays 0\nthrottle_rejections 0\nsearches 0\nvalue_cache_hits 0\nvalue_cache_misses 0\nvalue_cache_evictions 0\nhydration_waits 0\nhydration_rejections 0\nobserver_panics 0\nregistry_panics 0\nregistry_queue_depth 0\nsearch_sessions 0\nsuspended 0\n"
+    );
+    assert_eq!(snapshot.to_json()["reclaimed_bytes"], 4096);
+    assert_eq!(snapshot.to_json()["registry_queue_depth"], 0);
+}
+
+#[test]
+fn test_snapshot_delta() {
+    let metrics = Metrics::default();
+    Metrics::add(&metrics.callbacks, 10);
+    Metrics::add(&metrics.hydrated_bytes, 100);
+    let earlier = metrics.snapshot();
+    Metrics::add(&metrics.callbacks, 5);
+    Metrics::add(&metrics.cache_hits, 3);
+    Metrics::add(&metrics.cache_misses, 1);
+
+    let delta = MetricsSnapshot {
+        registry_queue_depth: 7,
+        ..metrics.snapshot()
+    }
+    .since(&earlier);
+    assert_eq!(
+        delta,
+        MetricsSnapshot {
+            callbacks: 5,
+            cache_hits: 3,
+            cache_misses: 1,
+            registry_queue_depth: 7,
+            ..Default::default()
+        }
+    );
+    // counters never go backwards, but a snapshot taken out of order mustn't underflow
+    assert_eq!(earlier.since(&metrics.snapshot()).callbacks, 0);
+
+    metrics
+        .busy_paths
+        .hit("HKEY_CURRENT_USER\\Software\\App\\Name".as_ref());
+    assert_eq!(
+        metrics.activity_summary(2),
+        "callbacks 15, hydrated 100 bytes, enumerations 0, denied 0, cache hit rate 75%, \
+         sessions 2, busiest: HKEY_CURRENT_USER\\Software (1)"
+    );
+    // the next one only has what happened since
+    Metrics::add(&metrics.callbacks, 1);
+    assert_eq!(
+        metrics.activity_summary(0),
+        "callbacks 1, hydrated 0 bytes, enumerations 0, denied 0, cache hit rate -, \
+         sessions 0, busiest: -"
+    );
+}
+
+#[test]
+fn test_busy_paths_bounded() {
+    let busy = BusyPaths::default();
+    for _ in 0..10 {
+        busy.hit("HKEY_LOCAL_MACHINE\\SOFTWARE\\Microsoft".as_ref());
+        busy.hit("hkey_local_machine\\SOFTWARE".as_ref());
+    }
+    for n in 0..100 {
+        busy.hit(format!("HKEY_USERS\\S-1-5-{}\\Software", n).as_ref());
+        assert!(busy.len() <= BUSY_PATHS);
+    }
+    for _ in 0..5 {
+        busy.hit("HKEY_CURRENT_USER\\Console".as_ref());
+    }
+    busy.hit("".as_ref());
+
+    let top = busy.take_top(3);
+    assert_eq!(top[0], ("HKEY_LOCAL_MACHINE\\SOFTWARE".to_string(), 20));
+    assert_eq!(top[1].0, "HKEY_CURRENT_USER\\Console");
+    assert_eq!(top.len(), 3);
+    assert_eq!(busy.len(), 0);
+}
+
+#[test]
+fn test_busy_paths_hit_without_allocating() {
+    let busy = BusyPaths::default();
+    let path = std::path::Path::new("\\\\?\\hkey_current_user\\Software\\App\\Name");
+    busy.hit(path);
+    let (allocations, ()) = crate::bench::allocations(|| busy.hit(path));
+
+    // once a key is counted, and the thread's buffers have grown
+    assert_eq!(allocations, 0);
+    assert_eq!(
+        busy.take_top(1),
+        [("HKEY_CURRENT_USER\\Software".to_string(), 2)]
+    );
+}
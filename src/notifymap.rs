@@ -0,0 +1,239 @@
+use anyhow::{anyhow, Error, Result};
+use prjfs::sys::{
+    PRJ_NOTIFY_FILE_HANDLE_CLOSED_FILE_DELETED, PRJ_NOTIFY_FILE_HANDLE_CLOSED_FILE_MODIFIED,
+    PRJ_NOTIFY_FILE_HANDLE_CLOSED_NO_MODIFICATION, PRJ_NOTIFY_FILE_OPENED,
+    PRJ_NOTIFY_FILE_OVERWRITTEN, PRJ_NOTIFY_FILE_PRE_CONVERT_TO_FULL, PRJ_NOTIFY_FILE_RENAMED,
+    PRJ_NOTIFY_HARDLINK_CREATED, PRJ_NOTIFY_NEW_FILE_CREATED, PRJ_NOTIFY_PRE_DELETE,
+    PRJ_NOTIFY_PRE_RENAME, PRJ_NOTIFY_PRE_SET_HARDLINK,
+};
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::backend::RegistryBackend;
+use crate::options::parse_trace_path;
+use crate::regfs::path_key;
+
+// the names NotificationType has for them
+const MASKS: [(&str, u32); 12] = [
+    ("FILE_OPENED", PRJ_NOTIFY_FILE_OPENED),
+    ("NEW_FILE_CREATED", PRJ_NOTIFY_NEW_FILE_CREATED),
+    ("FILE_OVERWRITTEN", PRJ_NOTIFY_FILE_OVERWRITTEN),
+    ("PRE_DELETE", PRJ_NOTIFY_PRE_DELETE),
+    ("PRE_RENAME", PRJ_NOTIFY_PRE_RENAME),
+    ("PRE_SET_HARDLINK", PRJ_NOTIFY_PRE_SET_HARDLINK),
+    ("FILE_RENAMED", PRJ_NOTIFY_FILE_RENAMED),
+    ("HARDLINK_CREATED", PRJ_NOTIFY_HARDLINK_CREATED),
+    (
+        "FILE_HANDLE_CLOSED_NO_MODIFICATION",
+        PRJ_NOTIFY_FILE_HANDLE_CLOSED_NO_MODIFICATION,
+    ),
+    (
+        "FILE_HANDLE_CLOSED_FILE_MODIFIED",
+        PRJ_NOTIFY_FILE_HANDLE_CLOSED_FILE_MODIFIED,
+    ),
+    (
+        "FILE_HANDLE_CLOSED_FILE_DELETED",
+        PRJ_NOTIFY_FILE_HANDLE_CLOSED_FILE_DELETED,
+    ),
+    (
+        "FILE_PRE_CONVERT_TO_FULL",
+        PRJ_NOTIFY_FILE_PRE_CONVERT_TO_FULL,
+    ),
+];
+
+// `FILE_OPENED|PRE_DELETE`, or a number such as `0x4`; `0` for none at all
+pub fn parse_mask(text: &str) -> Result<u32> {
+    let text = text.trim();
+    if let Some(hex) = text.strip_prefix("0x") {
+        return u32::from_str_radix(hex, 16).map_err(|_| anyhow!("invalid mask [{}]", text));
+    }
+    if let Ok(mask) = text.parse() {
+        return Ok(mask);
+    }
+
+    text.split('|').try_fold(0, |mask, name| {
+        MASKS
+            .iter()
+            .find(|(known, _)| known.eq_ignore_ascii_case(name.trim()))
+            .map(|(_, bit)| mask | bit)
+            .ok_or_else(|| anyhow!("unknown notification [{}] in [{}]", name.trim(), text))
+    })
+}
+
+// --notify-map <path>=<mask>: the notifications ProjFS sends for a subtree, in place of
+// the ones for the whole root. An empty path is the root itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NotifyMap {
+    pub path: PathBuf,
+    pub mask: u32,
+}
+
+impl FromStr for NotifyMap {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let (path, mask) = text
+            .rsplit_once('=')
+            .ok_or_else(|| anyhow!("invalid mapping [{}], expected <path>=<mask>", text))?;
+        let path = match path.trim_matches(|c| c == '\\' || c == '/') {
+            "" => PathBuf::new(),
+            // spelled the way trace paths are
+            path => parse_trace_path(path)
+                .map_err(|_| anyhow!("invalid mapping [{}], expected a key", text))?,
+        };
+        Ok(NotifyMap {
+            path,
+            mask: parse_mask(mask)?,
+        })
+    }
+}
+
+impl NotifyMap {
+    pub fn is_root(&self) -> bool {
+        self.path.as_os_str().is_empty()
+    }
+
+    fn covers(&self, path: &Path) -> bool {
+        let key = path_key(&self.path);
+        let path = path_key(path);
+        self.is_root() || path == key || path.starts_with(&format!("{}\\", key))
+    }
+}
+
+// at mount time: every mapped key or value is there, and mapped once
+pub fn validate(maps: &[NotifyMap], backend: &dyn RegistryBackend) -> Result<()> {
+    for (index, map) in maps.iter().enumerate() {
+        if maps[..index]
+            .iter()
+            .any(|earlier| path_key(&earlier.path) == path_key(&map.path))
+        {
+            return Err(anyhow!("[{:?}] is mapped more than once", map.path));
+        }
+        if !map.is_root()
+            && !backend.does_key_exist(&map.path)
+            && backend.does_value_exist(&map.path).is_none()
+        {
+            return Err(anyhow!(
+                "[{:?}] has a notification mapping but isn't in the registry",
+                map.path
+            ));
+        }
+    }
+    Ok(())
+}
+
+// what PrjStartVirtualizing is given: the root first, with `root_mask` unless it's mapped,
+// then the subtrees, each one before those inside it. `always` is added to every mask
+pub fn mappings(maps: &[NotifyMap], root_mask: u32, always: u32) -> Vec<(PathBuf, u32)> {
+    let mut mappings = Vec::new();
+    if !maps.iter().any(NotifyMap::is_root) {
+        mappings.push((PathBuf::new(), root_mask | always));
+    }
+    let mut maps: Vec<&NotifyMap> = maps.iter().collect();
+    maps.sort_by_key(|map| map.path.components().count());
+    mappings.extend(maps.iter().map(|map| (map.path.clone(), map.mask | always)));
+    mappings
+}
+
+// the mapping a notification for `path` came in under, None for the root's default
+pub fn mapping_for<'a>(maps: &'a [NotifyMap], path: &Path) -> Option<&'a NotifyMap> {
+    maps.iter()
+        .filter(|map| map.covers(path))
+        .max_by_key(|map| map.path.components().count())
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!(
+        parse_mask("pre_rename|PRE_DELETE").unwrap(),
+        PRJ_NOTIFY_PRE_RENAME | PRJ_NOTIFY_PRE_DELETE
+    );
+    assert_eq!(parse_mask("0x4").unwrap(), 4);
+    assert_eq!(parse_mask("0").unwrap(), 0);
+    assert!(parse_mask("PRE_EVERYTHING").is_err());
+
+    let map: NotifyMap = "HKCU\\Software\\MyApp=PRE_RENAME|PRE_DELETE"
+        .parse()
+        .unwrap();
+    assert_eq!(
+        map.path,
+        PathBuf::from("HKEY_CURRENT_USER\\Software\\MyApp")
+    );
+    assert_eq!(map.mask, PRJ_NOTIFY_PRE_RENAME | PRJ_NOTIFY_PRE_DELETE);
+    let root: NotifyMap = "=FILE_OPENED".parse().unwrap();
+    assert!(root.is_root());
+    assert!("HKCU\\Software".parse::<NotifyMap>().is_err());
+    assert!("HKXX\\Software=FILE_OPENED".parse::<NotifyMap>().is_err());
+}
+
+#[test]
+fn test_mappings() {
+    let app: NotifyMap = "HKCU\\Software\\MyApp=PRE_RENAME|PRE_DELETE"
+        .parse()
+        .unwrap();
+    let inner: NotifyMap = "HKCU\\Software\\MyApp\\Cache=0".parse().unwrap();
+    let maps = [inner.clone(), app.clone()];
+
+    // the root keeps the default, every mask gets what the overlay needs
+    let always = PRJ_NOTIFY_FILE_RENAMED;
+    assert_eq!(
+        mappings(&maps, PRJ_NOTIFY_FILE_OPENED, always),
+        [
+            (PathBuf::new(), PRJ_NOTIFY_FILE_OPENED | always),
+            (app.path.clone(), app.mask | always),
+            (inner.path.clone(), always),
+        ]
+    );
+    let root: NotifyMap = "=FILE_OPENED".parse().unwrap();
+    assert_eq!(
+        mappings(&[root.clone()], PRJ_NOTIFY_PRE_DELETE, 0),
+        [(PathBuf::new(), PRJ_NOTIFY_FILE_OPENED)]
+    );
+
+    let under = |path: &str| mapping_for(&maps, path.as_ref()).map(|map| map.path.clone());
+    assert_eq!(
+        under("hkey_current_user\\software\\myapp\\x"),
+        Some(app.path.clone())
+    );
+    assert_eq!(
+        under("HKEY_CURRENT_USER\\Software\\MyApp\\Cache\\x"),
+        Some(inner.path)
+    );
+    assert_eq!(under("HKEY_CURRENT_USER\\Software\\MyApplication"), None);
+    assert_eq!(under("HKEY_CURRENT_USER\\Software"), None);
+    assert_eq!(
+        mapping_for(&[root, app], "HKEY_LOCAL_MACHINE".as_ref()).map(NotifyMap::is_root),
+        Some(true)
+    );
+}
+
+#[test]
+fn test_validate() {
+    use crate::memory::MemoryBackend;
+
+    let memory = MemoryBackend::new();
+    memory.set_value(
+        "HKEY_CURRENT_USER\\Software\\MyApp",
+        "Setting",
+        4,
+        vec![0; 4],
+    );
+    let map = |text: &str| text.parse::<NotifyMap>().unwrap();
+
+    assert!(validate(&[map("HKCU\\Software\\MyApp=PRE_DELETE")], &memory).is_ok());
+    // a single value can be mapped too
+    assert!(validate(&[map("HKCU\\Software\\MyApp\\Setting=PRE_DELETE")], &memory).is_ok());
+    assert!(validate(&[map("=FILE_OPENED")], &memory).is_ok());
+    let missing = validate(&[map("HKCU\\Software\\Other=PRE_DELETE")], &memory).unwrap_err();
+    assert!(missing.to_string().contains("isn't in the registry"));
+    assert!(validate(
+        &[
+            map("HKCU\\Software\\MyApp=PRE_DELETE"),
+            map("hkey_current_user\\software\\myapp=FILE_OPENED")
+        ],
+        &memory
+    )
+    .is_err());
+}
@@ -0,0 +1,274 @@
+use log::warn;
+use std::{
+    ffi::OsString,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use winapi::um::winnt::HRESULT;
+
+use crate::events::RegFsEvent;
+use crate::metrics::Metrics;
+use crate::trace::{Call, CallKind, Response};
+
+// what every callback was asked and answered; the path as the mount has it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallbackEvent {
+    pub path: PathBuf,
+    // image file name, may be empty
+    pub process: OsString,
+    // from the callback being called to its result, wherever it was served
+    pub elapsed: Duration,
+    pub hr: HRESULT,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlaceholderEvent {
+    pub callback: CallbackEvent,
+    // only when a placeholder was written
+    pub is_directory: Option<bool>,
+    pub size: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnumPhase {
+    Start,
+    Fill { search: String },
+    End,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnumEvent {
+    pub callback: CallbackEvent,
+    pub enumeration: Vec<u8>,
+    pub phase: EnumPhase,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadEvent {
+    pub callback: CallbackEvent,
+    pub offset: u64,
+    pub length: u32,
+    // the whole file's, when it could be read
+    pub size: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NotifyEvent {
+    pub callback: CallbackEvent,
+    pub notification: u32,
+    pub is_directory: bool,
+    // a rename's new name or a hard link's, empty otherwise
+    pub destination: PathBuf,
+}
+
+// told about every callback once its result is known, on whichever thread served it; a
+// panic is caught, logged and counted, and the callback answers as it would have anyway
+pub trait ProviderObserver: Send + Sync {
+    fn on_placeholder(&self, _event: &PlaceholderEvent) {}
+    fn on_enum(&self, _event: &EnumEvent) {}
+    fn on_read(&self, _event: &ReadEvent) {}
+    fn on_notify(&self, _event: &NotifyEvent) {}
+    // what RegFsOptions::events gets too
+    fn on_event(&self, _event: &RegFsEvent) {}
+
+    // an observer of events only says no, and the callbacks aren't described for it
+    fn observes_callbacks(&self) -> bool {
+        true
+    }
+}
+
+impl fmt::Debug for dyn ProviderObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ProviderObserver")
+    }
+}
+
+// a callback that's being observed: what it was asked, and since when
+#[derive(Debug)]
+pub struct PendingCall {
+    pub call: Call,
+    started: Instant,
+}
+
+impl PendingCall {
+    pub fn new(call: Call) -> Self {
+        PendingCall {
+            call,
+            started: Instant::now(),
+        }
+    }
+}
+
+// the built-in ones first, then the embedder's
+pub struct Observers {
+    observers: Vec<Arc<dyn ProviderObserver>>,
+    callbacks: bool,
+    metrics: Arc<Metrics>,
+}
+
+impl Observers {
+    pub fn new(metrics: Arc<Metrics>, observers: &[Arc<dyn ProviderObserver>]) -> Self {
+        let observers: Vec<Arc<dyn ProviderObserver>> =
+            [metrics.clone() as Arc<dyn ProviderObserver>]
+                .into_iter()
+                .chain(observers.iter().cloned())
+                .collect();
+
+        Observers {
+            callbacks: observers
+                .iter()
+                .any(|observer| observer.observes_callbacks()),
+            observers,
+            metrics,
+        }
+    }
+
+    // nothing is described, or even timed, unless this is true
+    pub fn observes_callbacks(&self) -> bool {
+        self.callbacks
+    }
+
+    fn each<F: Fn(&dyn ProviderObserver)>(&self, name: &str, f: F) {
+        for observer in &self.observers {
+            if panic::catch_unwind(AssertUnwindSafe(|| f(observer.as_ref()))).is_err() {
+                Metrics::add(&self.metrics.observer_panics, 1);
+                warn!(target: "observer", "an observer panicked in {}, ignored", name);
+            }
+        }
+    }
+
+    pub fn event(&self, event: &RegFsEvent) {
+        self.each("on_event", |observer| observer.on_event(event));
+    }
+
+    pub fn callback(&self, pending: &PendingCall, response: &Response) {
+        if !self.callbacks {
+            return;
+        }
+        let call = &pending.call;
+        let callback = CallbackEvent {
+            path: PathBuf::from(&call.path),
+            process: OsString::from(&call.process),
+            elapsed: pending.started.elapsed(),
+            hr: response.hr,
+        };
+
+        match &call.kind {
+            CallKind::GetPlaceholderInfo => {
+                let event = PlaceholderEvent {
+                    callback,
+                    is_directory: response.is_directory,
+                    size: response.size,
+                };
+                self.each("on_placeholder", |observer| observer.on_placeholder(&event));
+            }
+            CallKind::StartDirEnum { enumeration }
+            | CallKind::GetDirEnum { enumeration, .. }
+            | CallKind::EndDirEnum { enumeration } => {
+                let phase = match &call.kind {
+                    CallKind::StartDirEnum { .. } => EnumPhase::Start,
+                    CallKind::GetDirEnum { search, .. } => EnumPhase::Fill {
+                        search: search.clone(),
+                    },
+                    _ => EnumPhase::End,
+                };
+                let event = EnumEvent {
+                    callback,
+                    enumeration: enumeration.clone(),
+                    phase,
+                };
+                self.each("on_enum", |observer| observer.on_enum(&event));
+            }
+            CallKind::GetFileData { offset, length, .. } => {
+                let event = ReadEvent {
+                    callback,
+                    offset: *offset,
+                    length: *length,
+                    size: response.size,
+                };
+                self.each("on_read", |observer| observer.on_read(&event));
+            }
+            CallKind::Notify {
+                is_directory,
+                notification,
+                destination,
+            } => {
+                let event = NotifyEvent {
+                    callback,
+                    notification: *notification,
+                    is_directory: *is_directory,
+                    destination: PathBuf::from(destination),
+                };
+                self.each("on_notify", |observer| observer.on_notify(&event));
+            }
+            // a lookup by name, nothing an observer is told about
+            CallKind::QueryFileName => {}
+        }
+    }
+}
+
+#[cfg(test)]
+#[derive(Default)]
+pub struct Recorder {
+    pub placeholders: std::sync::Mutex<Vec<PlaceholderEvent>>,
+    pub enums: std::sync::Mutex<Vec<EnumEvent>>,
+    pub reads: std::sync::Mutex<Vec<ReadEvent>>,
+    pub notifies: std::sync::Mutex<Vec<NotifyEvent>>,
+    pub events: std::sync::Mutex<Vec<RegFsEvent>>,
+}
+
+#[cfg(test)]
+impl ProviderObserver for Recorder {
+    fn on_placeholder(&self, event: &PlaceholderEvent) {
+        self.placeholders.lock().unwrap().push(event.clone());
+    }
+
+    fn on_enum(&self, event: &EnumEvent) {
+        self.enums.lock().unwrap().push(event.clone());
+    }
+
+    fn on_read(&self, event: &ReadEvent) {
+        self.reads.lock().unwrap().push(event.clone());
+    }
+
+    fn on_notify(&self, event: &NotifyEvent) {
+        self.notifies.lock().unwrap().push(event.clone());
+    }
+
+    fn on_event(&self, event: &RegFsEvent) {
+        self.events.lock().unwrap().push(event.clone());
+    }
+}
+
+#[test]
+fn test_observer_panics_are_counted() {
+    struct Panicking;
+
+    impl ProviderObserver for Panicking {
+        fn on_event(&self, _event: &RegFsEvent) {
+            panic!("observer");
+        }
+    }
+
+    let metrics = Arc::new(Metrics::default());
+    let recorder = Arc::new(Recorder::default());
+    let observed: Vec<Arc<dyn ProviderObserver>> = vec![Arc::new(Panicking), recorder.clone()];
+    let observers = Observers::new(metrics.clone(), &observed);
+    let event = RegFsEvent::EnumerationStarted {
+        path: "HKEY_CURRENT_USER".into(),
+    };
+    observers.event(&event);
+    observers.event(&event);
+
+    // the ones after it still hear about it, and the metrics count it like any other
+    assert_eq!(metrics.snapshot().observer_panics, 2);
+    assert_eq!(recorder.events.lock().unwrap().len(), 2);
+    assert_eq!(metrics.snapshot().enumerations, 2);
+    assert!(observers.observes_callbacks());
+
+    // the metrics alone don't need the callbacks described
+    assert!(!Observers::new(metrics, &[]).observes_callbacks());
+}
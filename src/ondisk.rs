@@ -0,0 +1,101 @@
+use prjfs::conv::WStrExt;
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    path::{Component, Path, PathBuf, Prefix},
+};
+use winapi::shared::winerror::S_OK;
+
+pub const HYDRATED_STATES: u32 = prjfs::sys::PRJ_FILE_STATE_HYDRATED_PLACEHOLDER
+    | prjfs::sys::PRJ_FILE_STATE_DIRTY_PLACEHOLDER
+    | prjfs::sys::PRJ_FILE_STATE_FULL;
+
+pub const MODIFIED_STATES: u32 =
+    prjfs::sys::PRJ_FILE_STATE_DIRTY_PLACEHOLDER | prjfs::sys::PRJ_FILE_STATE_FULL;
+
+// 0 when the file is not known to ProjFS (or doesn't exist at all)
+pub fn state(path: &Path) -> u32 {
+    let mut state = 0;
+    let result = unsafe {
+        prjfs::sys::PrjGetOnDiskFileState(
+            path.as_os_str().to_os_string().to_wstr().as_ptr(),
+            &mut state,
+        )
+    };
+
+    if result == S_OK {
+        state
+    } else {
+        0
+    }
+}
+
+// `path` in its `\\?\` form, which Win32 takes past MAX_PATH: a key a dozen levels down
+// is already longer than that under the mount. that form is taken as it is, so this makes
+// it absolute and deals with `.`, `..` and `/` first
+pub fn extended(path: &Path) -> PathBuf {
+    let absolute = match path.is_absolute() {
+        true => path.to_path_buf(),
+        false => match env::current_dir() {
+            Ok(dir) => dir.join(path),
+            Err(_) => return path.to_path_buf(),
+        },
+    };
+
+    let mut extended = OsString::new();
+    let mut parts: Vec<&OsStr> = Vec::new();
+    for component in absolute.components() {
+        match component {
+            Component::Prefix(prefix) => match prefix.kind() {
+                Prefix::Disk(letter) => extended.push(format!("\\\\?\\{}:", letter as char)),
+                Prefix::UNC(server, share) => {
+                    extended.push("\\\\?\\UNC\\");
+                    extended.push(server);
+                    extended.push("\\");
+                    extended.push(share);
+                }
+                // already verbatim, or a device
+                _ => return absolute,
+            },
+            Component::ParentDir => {
+                parts.pop();
+            }
+            Component::Normal(part) => parts.push(part),
+            Component::RootDir | Component::CurDir => {}
+        }
+    }
+    if extended.is_empty() {
+        return absolute;
+    }
+
+    if parts.is_empty() {
+        extended.push("\\");
+    }
+    for part in parts {
+        extended.push("\\");
+        extended.push(part);
+    }
+    PathBuf::from(extended)
+}
+
+#[test]
+fn test_extended() {
+    for (path, expected) in [
+        ("C:\\mnt\\regfs", "\\\\?\\C:\\mnt\\regfs"),
+        ("C:/mnt/./regfs/../reg/", "\\\\?\\C:\\mnt\\reg"),
+        ("C:\\", "\\\\?\\C:\\"),
+        (
+            "\\\\server\\share\\regfs",
+            "\\\\?\\UNC\\server\\share\\regfs",
+        ),
+        ("\\\\?\\C:\\mnt\\regfs", "\\\\?\\C:\\mnt\\regfs"),
+        ("\\\\?\\UNC\\server\\share", "\\\\?\\UNC\\server\\share"),
+    ] {
+        assert_eq!(extended(path.as_ref()), PathBuf::from(expected), "{}", path);
+    }
+
+    // relative to where we run
+    let relative = extended("mnt\\regfs".as_ref());
+    assert!(relative.to_string_lossy().starts_with("\\\\?\\"));
+    assert!(relative.ends_with("mnt\\regfs"));
+}
@@ -0,0 +1,174 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use winapi::{
+    shared::winerror::{ERROR_OPERATION_ABORTED, HRESULT_FROM_WIN32},
+    um::winnt::HRESULT,
+};
+
+use crate::impersonate::UserHive;
+use crate::metrics::Metrics;
+
+// how many entries an enumeration handles between cancellation checks
+pub const PAGE_SIZE: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl Cancelled {
+    pub fn to_hresult(self) -> HRESULT {
+        HRESULT_FROM_WIN32(ERROR_OPERATION_ABORTED)
+    }
+}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "operation cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+// carried from a callback into the backend so long registry work can give up early
+#[derive(Default, Clone)]
+pub struct OpContext<'a> {
+    cancelled: Option<Arc<AtomicBool>>,
+    deadline: Option<Instant>,
+    metrics: Option<&'a Metrics>,
+    // only with --impersonate
+    user: Option<UserHive<'a>>,
+}
+
+impl<'a> OpContext<'a> {
+    pub fn new(
+        cancelled: Arc<AtomicBool>,
+        deadline: Option<Instant>,
+        metrics: &'a Metrics,
+    ) -> Self {
+        OpContext {
+            cancelled: Some(cancelled),
+            deadline,
+            metrics: Some(metrics),
+            user: None,
+        }
+    }
+
+    pub fn with_user(self, user: UserHive<'a>) -> Self {
+        OpContext {
+            user: Some(user),
+            ..self
+        }
+    }
+
+    pub fn user(&self) -> Option<UserHive<'a>> {
+        self.user
+    }
+
+    // never cancelled, for tests and background work
+    pub fn none() -> Self {
+        Default::default()
+    }
+
+    pub fn check(&self) -> Result<(), Cancelled> {
+        let cancelled = self
+            .cancelled
+            .as_ref()
+            .map(|flag| flag.load(Ordering::Acquire))
+            .unwrap_or(false);
+        let expired = self
+            .deadline
+            .map(|deadline| Instant::now() >= deadline)
+            .unwrap_or(false);
+
+        if cancelled || expired {
+            if let Some(metrics) = self.metrics {
+                Metrics::add(&metrics.cancelled_operations, 1);
+            }
+            return Err(Cancelled);
+        }
+
+        Ok(())
+    }
+
+    // whether there's still time to wait `delay` and nobody cancelled the operation
+    pub fn can_wait(&self, delay: Duration) -> bool {
+        let cancelled = self
+            .cancelled
+            .as_ref()
+            .map(|flag| flag.load(Ordering::Acquire))
+            .unwrap_or(false);
+        let expires = self
+            .deadline
+            .map(|deadline| Instant::now() + delay >= deadline)
+            .unwrap_or(false);
+
+        !cancelled && !expires
+    }
+
+    pub fn metrics(&self) -> Option<&'a Metrics> {
+        self.metrics
+    }
+
+    // feeds the items to `f`, checking for cancellation before each page
+    pub fn paginate<I, F>(&self, items: I, mut f: F) -> Result<(), Cancelled>
+    where
+        I: IntoIterator,
+        F: FnMut(I::Item),
+    {
+        for (index, item) in items.into_iter().enumerate() {
+            if index % PAGE_SIZE == 0 {
+                self.check()?;
+            }
+            f(item);
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_cancel_stops_paginated_enumeration() {
+    use crate::backend::RegistryBackend;
+    use crate::memory::MemoryBackend;
+
+    let backend = MemoryBackend::new();
+    for i in 0..PAGE_SIZE * 4 {
+        backend.set_value("HKEY_CURRENT_USER\\Big", format!("v{:04}", i), 3, vec![0]);
+    }
+
+    let metrics = Metrics::default();
+    let flag = Arc::new(AtomicBool::new(false));
+    let ctx = OpContext::new(flag.clone(), None, &metrics);
+
+    let mut seen = 0;
+    let result = ctx.paginate(0..PAGE_SIZE * 4, |_| {
+        seen += 1;
+        if seen == PAGE_SIZE + 10 {
+            flag.store(true, Ordering::Release);
+        }
+    });
+    assert_eq!(result, Err(Cancelled));
+    // the page in flight finishes, the next one never starts
+    assert_eq!(seen, PAGE_SIZE * 2);
+    assert_eq!(metrics.snapshot().cancelled_operations, 1);
+
+    assert!(backend
+        .enumerate_key_ctx("HKEY_CURRENT_USER\\Big".into(), &ctx)
+        .is_err());
+    let entries = backend
+        .enumerate_key("HKEY_CURRENT_USER\\Big".into())
+        .unwrap();
+    assert_eq!(entries.values.len(), PAGE_SIZE * 4);
+
+    let expired = OpContext {
+        deadline: Some(Instant::now()),
+        ..OpContext::none()
+    };
+    assert_eq!(expired.check(), Err(Cancelled));
+    assert_eq!(OpContext::none().check(), Ok(()));
+}
@@ -0,0 +1,1137 @@
+use anyhow::{anyhow, Error, Result};
+use std::{
+    ffi::OsString,
+    fmt, iter,
+    path::PathBuf,
+    str::FromStr,
+    sync::{mpsc::SyncSender, Arc},
+    time::Duration,
+};
+
+use crate::aliases::{self, HiveAlias};
+use crate::audit::{AuditLimits, AuditObserver, AuditWriter};
+use crate::backend::RegistryBackend;
+use crate::bookmarks::Bookmark;
+use crate::conflict::ConflictPolicies;
+use crate::diff;
+use crate::enumcache::EnumCacheLimits;
+use crate::events::RegFsEvent;
+use crate::filter::EntryFilter;
+use crate::hydrationlimit::HydrationLimits;
+use crate::notifymap::NotifyMap;
+use crate::observer::ProviderObserver;
+use crate::policy::MutationPolicy;
+use crate::pool;
+use crate::prj_compat::PrjApi;
+use crate::recent::RecentLimits;
+use crate::regfile::{self, RegFile};
+use crate::regop::{paths, paths::RegPath, RootHive};
+use crate::searchdir::SearchLimits;
+use crate::shadow::StagingRegistry;
+use crate::snapshot::SnapshotLimits;
+use crate::times::ValueTimes;
+use crate::trace::{Recorder, Tracer};
+use crate::transform::{HexDump, MultiSzText, NumberText, TextEncoding, Transformers};
+use crate::valuecache::ValueCacheLimits;
+
+#[derive(Debug, Clone)]
+pub struct RegFsOptions {
+    pub root: PathBuf,
+    pub readonly: bool,
+    pub dehydrate_interval: Option<Duration>,
+    pub activity_summary: Option<Duration>,
+    // the provider stops on its own this long after mounting, or after this long without a
+    // callback
+    pub timeout: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+    pub values_json: bool,
+    pub values_json_max_size: usize,
+    // listings of a key stop there, with a marker for the rest
+    pub max_entries_per_dir: Option<usize>,
+    // listings leave out subkeys with nothing in them; they still open by path
+    pub hide_empty_keys: bool,
+    // synthetic entries are listed without the hidden and system attributes
+    pub show_synthetic: bool,
+    // each hive is probed once mounted, and with `strict_selftest` one that fails stops it
+    pub selftest: bool,
+    pub strict_selftest: bool,
+    pub value_times: ValueTimes,
+    pub async_callbacks: bool,
+    // a listing whose session is gone starts over even without a restart scan
+    pub recover_enumerations: bool,
+    pub registry_threads: usize,
+    pub registry_timeout: Duration,
+    pub hives: Vec<RootHive>,
+    pub hive_summary: bool,
+    // a `_search` directory at the root whose subdirectories list what their name finds
+    pub search_dir: bool,
+    pub search_limits: SearchLimits,
+    // a `_recent` directory at the root with the last changes the watchers reported
+    pub recent_dir: bool,
+    pub recent_limits: RecentLimits,
+    // listings kept across enumerations, for as long as their key doesn't change
+    pub enum_cache: bool,
+    pub enum_cache_limits: EnumCacheLimits,
+    // small values' file content kept across hydrations, until they change
+    pub value_cache: bool,
+    pub value_cache_limits: ValueCacheLimits,
+    // HKLM\SOFTWARE with the user's VirtualStore laid over it, writes go there too
+    pub merge_virtualstore: bool,
+    // a SID whose HKEY_CLASSES_ROOT is shown as a root of its own, read-only
+    pub user_classes: Option<String>,
+    // directories at the root that are those keys
+    pub bookmarks: Vec<Bookmark>,
+    // names the hives are listed by at the root, instead of their own
+    pub hive_aliases: Vec<HiveAlias>,
+    // notifications asked for under a subtree, instead of the root's
+    pub notify_maps: Vec<NotifyMap>,
+    // several mounts can share one backend, and with it its watchers
+    pub backend: Option<Arc<dyn RegistryBackend>>,
+    // --shadow-writes: the overlay keeps its writes under this key instead of in memory
+    pub shadow_writes: Option<PathBuf>,
+    // the registry the staging key is written to; the live one when unset
+    pub staging_registry: Option<Arc<dyn StagingRegistry>>,
+    pub event_log: bool,
+    pub control_pipe: bool,
+    // never blocks a callback: events that don't fit are dropped and counted
+    pub events: Option<SyncSender<RegFsEvent>>,
+    // asked after the built-in checks (read-only, protected hives) let a mutation through
+    pub policy: Option<Arc<dyn MutationPolicy>>,
+    // told about every callback and event, in the order they were added; see observer()
+    pub observers: Vec<Arc<dyn ProviderObserver>>,
+    pub allowed_processes: Vec<OsString>,
+    // --rate-limit: operations a second by image file name; anything else is unlimited
+    pub rate_limits: Vec<(String, u32)>,
+    // --max-hydrations: file reads served at once, past the smallest ones
+    pub hydration_limits: HydrationLimits,
+    // --trace-path: keys whose callbacks log at every level, whatever RUST_LOG says
+    pub trace_paths: Vec<PathBuf>,
+    pub transformers: Transformers,
+    // hides entries from listings and lookups alike
+    pub filter: Option<Arc<dyn EntryFilter>>,
+    // top first; build() checks how they stack and settles on one base at the bottom
+    pub backends: Vec<BackendSpec>,
+    pub snapshot_limits: SnapshotLimits,
+    // parsed up front so a bad file fails the start, with its line number
+    pub diff_baseline: Option<Arc<RegFile>>,
+    // the regfile backend's file, loaded by build()
+    pub reg_file: Option<Arc<RegFile>>,
+    // --record: build() opens the trace file into `tracer`
+    pub record: Option<PathBuf>,
+    // leaves the file content served out of the trace
+    pub record_redact: bool,
+    pub tracer: Option<Arc<Tracer>>,
+    // write-back keeps a value's type unless this is set and the data no longer fits it
+    pub allow_type_change: bool,
+    // hard links to files of the mount are refused otherwise
+    pub allow_hardlinks: bool,
+    // reads run as the user of the process that triggered them, with its HKEY_CURRENT_USER
+    pub impersonate: bool,
+    // keys are opened with SeBackupPrivilege, whatever their DACL, and nothing can be changed
+    pub backup_semantics: bool,
+    // once mounted, the token's dangerous privileges are removed and its integrity lowered
+    pub drop_privileges: bool,
+    pub lower_integrity: bool,
+    pub conflict: ConflictPolicies,
+    // --audit-log: build() starts its writer as one of the `observers`
+    pub audit_log: Option<PathBuf>,
+    pub audit_limits: AuditLimits,
+    // the ProjFS calls; the ones the running Windows exports when unset
+    pub prj_api: Option<Arc<dyn PrjApi>>,
+    // value contents in the logs, on the `values` target at trace level
+    pub log_unsafe_values: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BackendSpec {
+    Live,
+    // a copy of the live registry taken at mount time
+    Snapshot,
+    // writable, every change stays in memory on top of the backends below
+    Overlay,
+    RegFile(PathBuf),
+    // an empty in-memory registry, to try the mount out
+    Memory,
+}
+
+impl BackendSpec {
+    fn is_base(&self) -> bool {
+        matches!(
+            self,
+            BackendSpec::Live | BackendSpec::RegFile(_) | BackendSpec::Memory
+        )
+    }
+
+    // where it goes in the stack, top first
+    fn rank(&self) -> u8 {
+        match self {
+            BackendSpec::Overlay => 0,
+            BackendSpec::Snapshot => 1,
+            _ => 2,
+        }
+    }
+}
+
+impl FromStr for BackendSpec {
+    type Err = Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let spec = spec.trim();
+        let (name, path) = spec.split_once(':').unwrap_or((spec, ""));
+
+        match name.to_ascii_lowercase().as_str() {
+            "regfile" if path.is_empty() => Err(anyhow!("regfile: missing path")),
+            "regfile" => Ok(BackendSpec::RegFile(path.into())),
+            _ if !path.is_empty() => Err(anyhow!("[{}] doesn't take a path", name)),
+            "live" => Ok(BackendSpec::Live),
+            "snapshot" => Ok(BackendSpec::Snapshot),
+            "overlay" => Ok(BackendSpec::Overlay),
+            "memory" => Ok(BackendSpec::Memory),
+            _ => Err(anyhow!(
+                "unknown backend [{}], expected live, snapshot, overlay, regfile:<path> or memory",
+                spec
+            )),
+        }
+    }
+}
+
+impl fmt::Display for BackendSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendSpec::Live => write!(f, "live"),
+            BackendSpec::Snapshot => write!(f, "snapshot"),
+            BackendSpec::Overlay => write!(f, "overlay"),
+            BackendSpec::RegFile(path) => write!(f, "regfile:{}", path.display()),
+            BackendSpec::Memory => write!(f, "memory"),
+        }
+    }
+}
+
+impl Default for RegFsOptions {
+    fn default() -> Self {
+        RegFsOptions {
+            root: "../test".into(),
+            readonly: true,
+            dehydrate_interval: None,
+            activity_summary: None,
+            timeout: None,
+            idle_timeout: None,
+            values_json: false,
+            values_json_max_size: 1 << 20,
+            max_entries_per_dir: None,
+            hide_empty_keys: false,
+            show_synthetic: false,
+            selftest: true,
+            strict_selftest: false,
+            value_times: ValueTimes::default(),
+            async_callbacks: false,
+            recover_enumerations: false,
+            registry_threads: pool::DEFAULT_THREADS,
+            registry_timeout: Duration::from_secs(30),
+            hives: RootHive::ALL.to_vec(),
+            hive_summary: false,
+            search_dir: false,
+            search_limits: SearchLimits::default(),
+            recent_dir: false,
+            recent_limits: RecentLimits::default(),
+            enum_cache: false,
+            enum_cache_limits: EnumCacheLimits::default(),
+            value_cache: false,
+            value_cache_limits: ValueCacheLimits::default(),
+            merge_virtualstore: false,
+            user_classes: None,
+            bookmarks: Vec::new(),
+            hive_aliases: Vec::new(),
+            notify_maps: Vec::new(),
+            backend: None,
+            shadow_writes: None,
+            staging_registry: None,
+            event_log: false,
+            control_pipe: false,
+            events: None,
+            policy: None,
+            observers: Vec::new(),
+            allowed_processes: Vec::new(),
+            rate_limits: Vec::new(),
+            hydration_limits: Default::default(),
+            trace_paths: Vec::new(),
+            transformers: Transformers::default(),
+            filter: None,
+            backends: Vec::new(),
+            snapshot_limits: SnapshotLimits::default(),
+            diff_baseline: None,
+            reg_file: None,
+            record: None,
+            record_redact: false,
+            tracer: None,
+            allow_type_change: false,
+            allow_hardlinks: false,
+            impersonate: false,
+            backup_semantics: false,
+            drop_privileges: false,
+            lower_integrity: false,
+            conflict: ConflictPolicies::default(),
+            audit_log: None,
+            audit_limits: AuditLimits::default(),
+            prj_api: None,
+            log_unsafe_values: false,
+        }
+    }
+}
+
+impl RegFsOptions {
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Self> {
+        let mut options = RegFsOptions::default();
+        let mut args = args.into_iter();
+        let mut multi_sz_as_text = false;
+
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| anyhow!("missing value for [{}]", arg))
+            };
+
+            match arg.as_str() {
+                "--root" => options.root = value()?.into(),
+                "--dehydrate-interval" => {
+                    options.dehydrate_interval = Some(parse_duration(&value()?)?)
+                }
+                "--activity-summary" => options.activity_summary = Some(parse_duration(&value()?)?),
+                "--timeout" => options.timeout = Some(parse_duration(&value()?)?),
+                "--idle-timeout" => options.idle_timeout = Some(parse_duration(&value()?)?),
+                "--values-json" => options.values_json = true,
+                "--hive-summary" => options.hive_summary = true,
+                "--search-dir" => options.search_dir = true,
+                "--search-depth" => {
+                    options.search_limits.max_depth = value()?
+                        .parse()
+                        .map_err(|_| anyhow!("invalid depth for [{}]", arg))?;
+                    options.search_dir = true;
+                }
+                "--search-max-results" => match value()?.parse() {
+                    Ok(max) if max > 0 => {
+                        options.search_limits.max_results = max;
+                        options.search_dir = true;
+                    }
+                    _ => return Err(anyhow!("invalid result count for [{}]", arg)),
+                },
+                "--recent-dir" => options.recent_dir = true,
+                "--recent-max" => match value()?.parse() {
+                    Ok(max) if max > 0 => {
+                        options.recent_limits.max_entries = max;
+                        options.recent_dir = true;
+                    }
+                    _ => return Err(anyhow!("invalid entry count for [{}]", arg)),
+                },
+                "--recent-max-age" => {
+                    options.recent_limits.max_age = parse_duration(&value()?)?;
+                    options.recent_dir = true;
+                }
+                "--enum-cache" => options.enum_cache = true,
+                "--enum-cache-capacity" => match value()?.parse() {
+                    Ok(capacity) if capacity > 0 => {
+                        options.enum_cache_limits.capacity = capacity;
+                        options.enum_cache = true;
+                    }
+                    _ => return Err(anyhow!("invalid listing count for [{}]", arg)),
+                },
+                "--enum-cache-entries" => match value()?.parse() {
+                    Ok(max) if max > 0 => {
+                        options.enum_cache_limits.max_entries = max;
+                        options.enum_cache = true;
+                    }
+                    _ => return Err(anyhow!("invalid entry count for [{}]", arg)),
+                },
+                "--enum-cache-ttl" => {
+                    options.enum_cache_limits.ttl = parse_duration(&value()?)?;
+                    options.enum_cache = true;
+                }
+                "--value-cache" => options.value_cache = true,
+                "--value-cache-size" => match value()?.parse() {
+                    Ok(max) if max > 0 => {
+                        options.value_cache_limits.max_bytes = max;
+                        options.value_cache = true;
+                    }
+                    _ => return Err(anyhow!("invalid byte count for [{}]", arg)),
+                },
+                "--value-cache-max-value" => match value()?.parse() {
+                    Ok(max) if max > 0 => {
+                        options.value_cache_limits.max_value_size = max;
+                        options.value_cache = true;
+                    }
+                    _ => return Err(anyhow!("invalid byte count for [{}]", arg)),
+                },
+                "--merge-virtualstore" => options.merge_virtualstore = true,
+                "--user-classes" => options.user_classes = Some(parse_sid(&value()?)?),
+                "--bookmark" => options.bookmarks.push(value()?.parse()?),
+                "--hive-alias" => options.hive_aliases.push(value()?.parse()?),
+                "--hide-empty-keys" => options.hide_empty_keys = true,
+                "--show-synthetic" => options.show_synthetic = true,
+                "--no-selftest" => options.selftest = false,
+                "--strict-selftest" => options.strict_selftest = true,
+                "--max-entries-per-dir" => match value()?.parse() {
+                    Ok(max) if max > 0 => options.max_entries_per_dir = Some(max),
+                    _ => return Err(anyhow!("invalid entry count for [{}]", arg)),
+                },
+                "--values-json-max-size" => {
+                    options.values_json_max_size = value()?
+                        .parse()
+                        .map_err(|_| anyhow!("invalid size for [{}]", arg))?
+                }
+                "--value-times" => options.value_times = value()?.parse()?,
+                "--async-callbacks" => options.async_callbacks = true,
+                "--recover-enumerations" => options.recover_enumerations = true,
+                "--event-log" => options.event_log = true,
+                "--control-pipe" => options.control_pipe = true,
+                "--allow-process" => options.allowed_processes.push(value()?.into()),
+                "--rate-limit" => options.rate_limits.push(parse_rate_limit(&value()?)?),
+                "--max-hydrations" => {
+                    options.hydration_limits.max = value()?
+                        .parse()
+                        .map_err(|_| anyhow!("invalid hydration count for [{}]", arg))?
+                }
+                "--hydration-wait" => options.hydration_limits.wait = parse_duration(&value()?)?,
+                "--hydration-small" => match value()?.parse() {
+                    Ok(small) => options.hydration_limits.small = small,
+                    _ => return Err(anyhow!("invalid byte count for [{}]", arg)),
+                },
+                "--trace-path" => options.trace_paths.push(parse_trace_path(&value()?)?),
+                "--notify-map" => options.notify_maps.push(value()?.parse()?),
+                "--hex-dump" => options.transformers.add(&value()?, Arc::new(HexDump)),
+                "--numbers-as-text" => options.transformers.add(&value()?, Arc::new(NumberText)),
+                "--multi-sz-as-text" => {
+                    options.transformers.add(&value()?, Arc::new(MultiSzText));
+                    multi_sz_as_text = true;
+                }
+                "--text-encoding" => {
+                    let encoding: TextEncoding = value()?.parse()?;
+                    match options.transformers.text_encoding() {
+                        Some(set) if set != encoding => {
+                            return Err(anyhow!(
+                                "--text-encoding is for the whole mount, got {} and {}",
+                                set,
+                                encoding
+                            ))
+                        }
+                        _ => options.transformers.set_text_encoding(encoding),
+                    }
+                }
+                "--backend" => {
+                    for spec in value()?.split(',') {
+                        options.backends.push(spec.parse()?);
+                    }
+                }
+                // the flags from before --backend, still accepted
+                "--snapshot" => options.backends.push(BackendSpec::Snapshot),
+                "--overlay" => options.backends.push(BackendSpec::Overlay),
+                "--shadow-writes" => {
+                    let text = value()?;
+                    let root = parse_trace_path(&text)
+                        .ok()
+                        .filter(|root| paths::components(root).len() > 1)
+                        .ok_or_else(|| {
+                            anyhow!(
+                                "invalid staging key [{}], expected a key under a hive",
+                                text
+                            )
+                        })?;
+                    options.shadow_writes = Some(root);
+                    options.backends.push(BackendSpec::Overlay);
+                }
+                "--reg-file" => options.backends.push(BackendSpec::RegFile(value()?.into())),
+                "--snapshot-depth" => {
+                    options.snapshot_limits.max_depth = value()?
+                        .parse()
+                        .map_err(|_| anyhow!("invalid depth for [{}]", arg))?;
+                    options.backends.push(BackendSpec::Snapshot);
+                }
+                "--snapshot-max-bytes" => {
+                    options.snapshot_limits.max_bytes = value()?
+                        .parse()
+                        .map_err(|_| anyhow!("invalid size for [{}]", arg))?;
+                    options.backends.push(BackendSpec::Snapshot);
+                }
+                "--writable" => options.readonly = false,
+                "--allow-type-change" => options.allow_type_change = true,
+                "--allow-hardlinks" => options.allow_hardlinks = true,
+                "--conflict" => options.conflict.add(&value()?)?,
+                "--conflict-backups" => options.conflict.backups = value()?.into(),
+                "--audit-log" => options.audit_log = Some(value()?.into()),
+                "--audit-max-size" => {
+                    options.audit_limits.max_size = value()?
+                        .parse()
+                        .map_err(|_| anyhow!("invalid size for [{}]", arg))?
+                }
+                "--audit-max-files" => {
+                    options.audit_limits.max_files = value()?
+                        .parse()
+                        .map_err(|_| anyhow!("invalid count for [{}]", arg))?
+                }
+                "--impersonate" => options.impersonate = true,
+                "--backup-semantics" => options.backup_semantics = true,
+                "--drop-privileges" => options.drop_privileges = true,
+                "--lower-integrity" => options.lower_integrity = true,
+                "--record" => options.record = Some(value()?.into()),
+                "--record-redact" => options.record_redact = true,
+                "--log-unsafe-values" => options.log_unsafe_values = true,
+                "--diff-baseline" => {
+                    let baseline = regfile::read(value()?.as_ref())?;
+                    diff::check_baseline(&baseline)?;
+                    options.diff_baseline = Some(Arc::new(baseline))
+                }
+                "--registry-threads" => {
+                    options.registry_threads = value()?
+                        .parse()
+                        .map_err(|_| anyhow!("invalid thread count for [{}]", arg))?
+                }
+                "--registry-timeout" => options.registry_timeout = parse_duration(&value()?)?,
+                "--hives" => {
+                    options.hives = value()?
+                        .split(',')
+                        .map(|hive| hive.trim().parse())
+                        .collect::<Result<_>>()?
+                }
+                _ => return Err(anyhow!("unknown argument [{}]", arg)),
+            }
+        }
+
+        // a multi-string is either lines or text in the mount's encoding, never both
+        if multi_sz_as_text && options.transformers.text_encoding().is_some() {
+            return Err(anyhow!(
+                "--multi-sz-as-text can't be used with --text-encoding"
+            ));
+        }
+        Ok(options)
+    }
+
+    // the backend stack checked and put in order, and its .reg file loaded
+    pub fn build(mut self) -> Result<Self> {
+        let mut backends = std::mem::take(&mut self.backends);
+        backends.sort_by_key(BackendSpec::rank);
+        backends.dedup();
+
+        let bases: Vec<&BackendSpec> = backends.iter().filter(|spec| spec.is_base()).collect();
+        let base = match bases.as_slice() {
+            [] => BackendSpec::Live,
+            [base] => (*base).clone(),
+            _ => {
+                let names: Vec<String> = bases.iter().map(|base| base.to_string()).collect();
+                return Err(anyhow!(
+                    "only one of live, regfile and memory can be used, got {}",
+                    names.join(" and ")
+                ));
+            }
+        };
+        backends.retain(|spec| !spec.is_base());
+
+        // a snapshot is taken of the registry; a .reg file or memory doesn't change anyway
+        if backends.contains(&BackendSpec::Snapshot) && base != BackendSpec::Live {
+            return Err(anyhow!(
+                "snapshot applies to the live registry only, not {}",
+                base
+            ));
+        }
+
+        // anything captured up front was read as whoever started the provider
+        if self.impersonate && (base != BackendSpec::Live || !backends.is_empty()) {
+            return Err(anyhow!(
+                "--impersonate needs the live registry with nothing on top of it"
+            ));
+        }
+
+        // what a backup can read stays read-only, overlay or not
+        if self.backup_semantics {
+            if base != BackendSpec::Live || self.impersonate {
+                return Err(anyhow!(
+                    "--backup-semantics needs the live registry, read as whoever started the \
+                     provider"
+                ));
+            }
+            if !self.readonly || backends.contains(&BackendSpec::Overlay) {
+                return Err(anyhow!(
+                    "--backup-semantics keeps the mount read-only, it can't be used with \
+                     --writable or an overlay"
+                ));
+            }
+        }
+
+        // the merged root reads both, whatever the root shows
+        if self.user_classes.is_some()
+            && !(self.hives.contains(&RootHive::Users)
+                && self.hives.contains(&RootHive::LocalMachine))
+        {
+            return Err(anyhow!(
+                "--user-classes needs HKEY_USERS and HKEY_LOCAL_MACHINE in --hives"
+            ));
+        }
+
+        aliases::validate(&self.hive_aliases, &self.bookmarks)?;
+
+        // the overlay wraps whatever is below it, and is what makes the mount writable
+        let overlay = backends.contains(&BackendSpec::Overlay);
+        let read_only = match &base {
+            BackendSpec::RegFile(_) => Some(base.to_string()),
+            _ if backends.contains(&BackendSpec::Snapshot) => Some("snapshot".to_string()),
+            _ if self.diff_baseline.is_some() => Some("--diff-baseline".to_string()),
+            _ => None,
+        };
+        if let (false, false, Some(read_only)) = (self.readonly, overlay, &read_only) {
+            return Err(anyhow!(
+                "--writable can't be used with {}, unless an overlay takes the writes \
+                 (--backend overlay,...)",
+                read_only
+            ));
+        }
+        if overlay {
+            self.readonly = false;
+        }
+
+        if let BackendSpec::RegFile(path) = &base {
+            self.reg_file = Some(Arc::new(regfile::read(path)?));
+        }
+        backends.push(base);
+        self.backends = backends;
+
+        if let Some(path) = &self.record {
+            let recorder = Recorder::create(path, self.record_redact)?;
+            self.tracer = Some(Arc::new(Tracer::Record(recorder)));
+        }
+        if let Some(path) = &self.audit_log {
+            let (events, _) = AuditWriter::create(path, self.audit_limits)?.spawn();
+            self.observers
+                .insert(0, Arc::new(AuditObserver::new(events)));
+        }
+        Ok(self)
+    }
+
+    pub fn observer(mut self, observer: Arc<dyn ProviderObserver>) -> Self {
+        self.observers.push(observer);
+        self
+    }
+
+    pub fn base(&self) -> &BackendSpec {
+        self.backends
+            .iter()
+            .find(|spec| spec.is_base())
+            .unwrap_or(&BackendSpec::Live)
+    }
+
+    pub fn snapshot(&self) -> Option<SnapshotLimits> {
+        self.backends
+            .contains(&BackendSpec::Snapshot)
+            .then_some(self.snapshot_limits)
+    }
+
+    pub fn overlay(&self) -> bool {
+        self.backends.contains(&BackendSpec::Overlay)
+    }
+
+    // for the startup banner, e.g. "overlay > snapshot > live"
+    pub fn backend_stack(&self) -> String {
+        let mut layers: Vec<String> = self.backends.iter().map(BackendSpec::to_string).collect();
+        if self.diff_baseline.is_some() {
+            // it wraps everything but the overlay
+            let at = self.overlay() as usize;
+            layers.insert(at, "diff".to_string());
+        }
+        if self.backends.iter().all(|spec| !spec.is_base()) {
+            layers.push(BackendSpec::Live.to_string());
+        }
+        layers.join(" > ")
+    }
+}
+
+// plain seconds, or a number with an s/m/h suffix
+pub fn parse_duration(text: &str) -> Result<Duration> {
+    let text = text.trim();
+    let (number, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => (&text[..index], &text[index..]),
+        None => (text, "s"),
+    };
+
+    let number: u64 = number
+        .parse()
+        .map_err(|_| anyhow!("invalid duration [{}]", text))?;
+
+    match unit {
+        "s" => Ok(Duration::from_secs(number)),
+        "m" => Ok(Duration::from_secs(number * 60)),
+        "h" => Ok(Duration::from_secs(number * 60 * 60)),
+        _ => Err(anyhow!("invalid duration unit in [{}]", text)),
+    }
+}
+
+// `<process>=<operations a second>`
+pub fn parse_rate_limit(text: &str) -> Result<(String, u32)> {
+    let (process, rate) = text.rsplit_once('=').ok_or_else(|| {
+        anyhow!(
+            "invalid rate limit [{}], expected <process>=<ops/sec>",
+            text
+        )
+    })?;
+    match rate.trim().parse() {
+        Ok(rate) if rate > 0 && !process.is_empty() => Ok((process.to_string(), rate)),
+        _ => Err(anyhow!(
+            "invalid rate limit [{}], expected <process>=<ops/sec>",
+            text
+        )),
+    }
+}
+
+// a hive or a key below one, the hive spelled the way the mount lists it
+pub fn parse_trace_path(text: &str) -> Result<PathBuf> {
+    let path = RegPath::parse(text);
+    // RUST_LOG already covers the whole mount
+    let hive: RootHive = match &path.hive {
+        Some(hive) => hive.to_string_lossy().parse()?,
+        None => return Err(anyhow!("invalid trace path [{}], expected a key", text)),
+    };
+    Ok(paths::join_parts(
+        iter::once(OsString::from(hive.name())).chain(path.keys),
+    ))
+}
+
+// `S-1-5-21-…`, the name of the user's key under HKEY_USERS
+pub fn parse_sid(text: &str) -> Result<String> {
+    let sid = text.trim().to_ascii_uppercase();
+    let parts: Vec<&str> = sid.split('-').collect();
+    match parts.as_slice() {
+        ["S", revision, rest @ ..]
+            if !rest.is_empty()
+                && iter::once(revision)
+                    .chain(rest)
+                    .all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit())) =>
+        {
+            Ok(sid)
+        }
+        _ => Err(anyhow!("invalid SID [{}], expected S-1-5-21-...", text)),
+    }
+}
+
+#[cfg(test)]
+fn args(line: &str) -> Vec<String> {
+    line.split_whitespace().map(String::from).collect()
+}
+
+#[test]
+fn test_from_args() {
+    let options = RegFsOptions::from_args(args("")).unwrap();
+    assert_eq!(options.root, PathBuf::from("../test"));
+    assert_eq!(options.dehydrate_interval, None);
+
+    let options = RegFsOptions::from_args(args("--root C:\\mnt --dehydrate-interval 10m")).unwrap();
+    assert_eq!(options.root, PathBuf::from("C:\\mnt"));
+    assert_eq!(options.dehydrate_interval, Some(Duration::from_secs(600)));
+    assert_eq!((options.timeout, options.idle_timeout), (None, None));
+
+    let options = RegFsOptions::from_args(args("--timeout 5m --idle-timeout 30s")).unwrap();
+    assert_eq!(options.timeout, Some(Duration::from_secs(300)));
+    assert_eq!(options.idle_timeout, Some(Duration::from_secs(30)));
+    assert!(RegFsOptions::from_args(args("--idle-timeout")).is_err());
+
+    let options =
+        RegFsOptions::from_args(args("--values-json --values-json-max-size 4096")).unwrap();
+    assert!(options.values_json);
+    assert_eq!(options.values_json_max_size, 4096);
+    assert_eq!(options.max_entries_per_dir, None);
+
+    let options = RegFsOptions::from_args(args("--max-entries-per-dir 1000")).unwrap();
+    assert_eq!(options.max_entries_per_dir, Some(1000));
+    assert!(RegFsOptions::from_args(args("--max-entries-per-dir 0")).is_err());
+
+    let options = RegFsOptions::from_args(args("--value-times mount")).unwrap();
+    assert_eq!(options.value_times, ValueTimes::Mount);
+    assert!(!options.async_callbacks);
+    assert!(!options.recover_enumerations);
+
+    let options =
+        RegFsOptions::from_args(args("--async-callbacks --event-log --control-pipe")).unwrap();
+    assert!(options.async_callbacks);
+    assert!(options.event_log);
+    assert!(options.control_pipe);
+    let options = RegFsOptions::from_args(args("--recover-enumerations")).unwrap();
+    assert!(options.recover_enumerations);
+
+    let options =
+        RegFsOptions::from_args(args("--allow-process regedit.exe --allow-process reg.exe"))
+            .unwrap();
+    assert_eq!(options.allowed_processes, vec!["regedit.exe", "reg.exe"]);
+    assert!(options.rate_limits.is_empty());
+
+    let options = RegFsOptions::from_args(args(
+        "--rate-limit SearchIndexer.exe=50 --rate-limit MsMpEng.exe=5",
+    ))
+    .unwrap();
+    assert_eq!(
+        options.rate_limits,
+        vec![
+            ("SearchIndexer.exe".to_string(), 50),
+            ("MsMpEng.exe".to_string(), 5)
+        ]
+    );
+    for bad in ["indexer.exe", "indexer.exe=0", "indexer.exe=fast", "=5"] {
+        assert!(RegFsOptions::from_args(args(&format!("--rate-limit {}", bad))).is_err());
+    }
+
+    assert_eq!(options.hydration_limits, HydrationLimits::default());
+    let options = RegFsOptions::from_args(args(
+        "--max-hydrations 4 --hydration-wait 2s --hydration-small 512",
+    ))
+    .unwrap();
+    assert_eq!(
+        options.hydration_limits,
+        HydrationLimits {
+            max: 4,
+            wait: Duration::from_secs(2),
+            small: 512,
+        }
+    );
+    assert!(RegFsOptions::from_args(args("--max-hydrations many")).is_err());
+
+    let options = RegFsOptions::from_args(args(
+        "--trace-path hklm\\SOFTWARE\\MyApp\\ --trace-path HKEY_CURRENT_USER/Console",
+    ))
+    .unwrap();
+    assert_eq!(
+        options.trace_paths,
+        [
+            PathBuf::from("HKEY_LOCAL_MACHINE\\SOFTWARE\\MyApp"),
+            PathBuf::from("HKEY_CURRENT_USER\\Console")
+        ]
+    );
+    for bad in ["\\", "Software\\MyApp"] {
+        assert!(RegFsOptions::from_args(args(&format!("--trace-path {}", bad))).is_err());
+    }
+
+    let options = RegFsOptions::from_args(args(
+        "--notify-map HKCU\\Software\\MyApp=PRE_RENAME|PRE_DELETE --notify-map =FILE_OPENED",
+    ))
+    .unwrap();
+    assert_eq!(options.notify_maps.len(), 2);
+    assert_eq!(
+        options.notify_maps[0].path,
+        PathBuf::from("HKEY_CURRENT_USER\\Software\\MyApp")
+    );
+    assert!(options.notify_maps[1].is_root());
+    assert!(RegFsOptions::from_args(args("--notify-map HKCU\\Software")).is_err());
+
+    let options =
+        RegFsOptions::from_args(args("--registry-threads 0 --registry-timeout 5")).unwrap();
+    assert_eq!(options.registry_threads, 0);
+    assert_eq!(options.registry_timeout, Duration::from_secs(5));
+    assert_eq!(options.hives.len(), 5);
+
+    let options = RegFsOptions::from_args(args("--hives HKLM,hkey_current_user")).unwrap();
+    assert_eq!(
+        options.hives,
+        vec![RootHive::LocalMachine, RootHive::CurrentUser]
+    );
+    assert!(RegFsOptions::from_args(args("--hives HKLM,HKXX")).is_err());
+
+    let options = RegFsOptions::from_args(args("--hive-summary")).unwrap();
+    assert!(options.hive_summary);
+    assert!(!options.search_dir);
+    let options = RegFsOptions::from_args(args("--search-depth 3")).unwrap();
+    assert!(options.search_dir);
+    assert_eq!(options.search_limits.max_depth, 3);
+    assert_eq!(
+        options.search_limits.max_results,
+        SearchLimits::default().max_results
+    );
+    assert!(RegFsOptions::from_args(args("--search-max-results 0")).is_err());
+    assert!(!options.recent_dir);
+    let options = RegFsOptions::from_args(args("--recent-max-age 30s")).unwrap();
+    assert!(options.recent_dir);
+    assert_eq!(options.recent_limits.max_age, Duration::from_secs(30));
+    assert_eq!(
+        options.recent_limits.max_entries,
+        RecentLimits::default().max_entries
+    );
+    assert!(RegFsOptions::from_args(args("--recent-max 0")).is_err());
+    assert!(!options.enum_cache);
+    let options = RegFsOptions::from_args(args("--enum-cache-ttl 5s")).unwrap();
+    assert!(options.enum_cache);
+    assert_eq!(
+        options.enum_cache_limits,
+        EnumCacheLimits {
+            ttl: Duration::from_secs(5),
+            ..Default::default()
+        }
+    );
+    let options = RegFsOptions::from_args(args("--enum-cache-entries 5000")).unwrap();
+    assert_eq!(options.enum_cache_limits.max_entries, 5000);
+    assert!(RegFsOptions::from_args(args("--enum-cache-capacity 0")).is_err());
+    assert!(!options.value_cache);
+    let options = RegFsOptions::from_args(args("--value-cache-max-value 4096")).unwrap();
+    assert!(options.value_cache);
+    assert_eq!(
+        options.value_cache_limits,
+        ValueCacheLimits {
+            max_value_size: 4096,
+            ..Default::default()
+        }
+    );
+    assert!(RegFsOptions::from_args(args("--value-cache-size 0")).is_err());
+    assert!(!options.hide_empty_keys);
+    let options = RegFsOptions::from_args(args("--hide-empty-keys")).unwrap();
+    assert!(options.hide_empty_keys);
+    assert!(!options.show_synthetic);
+    let options = RegFsOptions::from_args(args("--show-synthetic")).unwrap();
+    assert!(options.show_synthetic);
+    assert!(options.selftest && !options.strict_selftest);
+    let options = RegFsOptions::from_args(args("--no-selftest")).unwrap();
+    assert!(!options.selftest);
+    let options = RegFsOptions::from_args(args("--strict-selftest")).unwrap();
+    assert!(options.selftest && options.strict_selftest);
+    assert!(!options.merge_virtualstore);
+    let options = RegFsOptions::from_args(args("--merge-virtualstore")).unwrap();
+    assert!(options.merge_virtualstore);
+    assert_eq!(options.user_classes, None);
+    let options = RegFsOptions::from_args(args("--user-classes s-1-5-21-1-2-3-1001")).unwrap();
+    assert_eq!(options.user_classes.as_deref(), Some("S-1-5-21-1-2-3-1001"));
+    assert!(RegFsOptions::from_args(args("--user-classes S-1-5-21-1001_Classes")).is_err());
+    assert!(RegFsOptions::from_args(args("--user-classes HKCU")).is_err());
+    assert!(options.bookmarks.is_empty());
+    let options = RegFsOptions::from_args(args(
+        "--bookmark Fonts=HKLM\\SOFTWARE\\Fonts --bookmark Env=HKCU\\Environment",
+    ))
+    .unwrap();
+    assert_eq!(options.bookmarks.len(), 2);
+    assert_eq!(options.bookmarks[1].name, "Env");
+    assert_eq!(
+        options.bookmarks[1].target,
+        PathBuf::from("HKEY_CURRENT_USER\\Environment")
+    );
+    assert!(RegFsOptions::from_args(args("--bookmark HKLM=HKCU\\Environment")).is_err());
+    assert!(options.hive_aliases.is_empty());
+    let options = RegFsOptions::from_args(args("--hive-alias HKLM=machine --hive-alias HKCU=user"))
+        .unwrap()
+        .build()
+        .unwrap();
+    assert_eq!(options.hive_aliases.len(), 2);
+    assert_eq!(options.hive_aliases[1].hive, RootHive::CurrentUser);
+    assert!(RegFsOptions::from_args(args("--hive-alias HKLM=HKCU")).is_err());
+    let collides =
+        RegFsOptions::from_args(args("--hive-alias HKLM=machine --hive-alias HKU=Machine"))
+            .unwrap()
+            .build();
+    assert!(collides.is_err());
+
+    assert_eq!(options.snapshot(), None);
+    let options = RegFsOptions::from_args(args("--snapshot-depth 4")).unwrap();
+    assert_eq!(options.snapshot().unwrap().max_depth, 4);
+    assert_eq!(
+        options.snapshot().unwrap().max_bytes,
+        SnapshotLimits::default().max_bytes
+    );
+    assert!(RegFsOptions::from_args(args("--snapshot-max-bytes lots")).is_err());
+
+    let options =
+        RegFsOptions::from_args(args("--shadow-writes HKCU\\Software\\RegFsStaging")).unwrap();
+    assert!(options.overlay());
+    assert_eq!(
+        options.shadow_writes,
+        Some(PathBuf::from("HKEY_CURRENT_USER\\Software\\RegFsStaging"))
+    );
+    assert!(RegFsOptions::from_args(args("--shadow-writes HKCU")).is_err());
+    assert!(RegFsOptions::from_args(args("--shadow-writes Software\\RegFsStaging")).is_err());
+
+    assert_eq!(options.transformers.text_encoding(), None);
+    let options = RegFsOptions::from_args(args("--text-encoding utf16")).unwrap();
+    assert_eq!(
+        options.transformers.text_encoding(),
+        Some(TextEncoding::Utf16)
+    );
+    assert!(RegFsOptions::from_args(args("--text-encoding utf16 --text-encoding utf16")).is_ok());
+    assert!(RegFsOptions::from_args(args("--text-encoding utf8 --text-encoding utf16")).is_err());
+    assert!(RegFsOptions::from_args(args("--text-encoding latin1")).is_err());
+    assert!(RegFsOptions::from_args(args("--multi-sz-as-text ** --text-encoding utf8")).is_err());
+
+    let options = RegFsOptions::from_args(args("--overlay")).unwrap();
+    assert!(options.overlay());
+    let options = options.build().unwrap();
+    assert!(!options.readonly);
+
+    assert!(!options.drop_privileges);
+    let options = RegFsOptions::from_args(args("--drop-privileges --lower-integrity")).unwrap();
+    assert!(options.drop_privileges && options.lower_integrity);
+
+    assert!(RegFsOptions::from_args(args("--root")).is_err());
+    assert!(RegFsOptions::from_args(args("--bogus")).is_err());
+}
+
+#[test]
+fn test_parse_duration() {
+    assert_eq!(parse_duration("30").unwrap(), Duration::from_secs(30));
+    assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(7200));
+    assert!(parse_duration("").is_err());
+    assert!(parse_duration("5d").is_err());
+}
+
+#[test]
+fn test_parse_backend_spec() {
+    assert_eq!("live".parse::<BackendSpec>().unwrap(), BackendSpec::Live);
+    assert_eq!(
+        " Overlay".parse::<BackendSpec>().unwrap(),
+        BackendSpec::Overlay
+    );
+    assert_eq!(
+        "regfile:C:\\exports\\a.reg".parse::<BackendSpec>().unwrap(),
+        BackendSpec::RegFile("C:\\exports\\a.reg".into())
+    );
+    assert_eq!(
+        BackendSpec::RegFile("a.reg".into()).to_string(),
+        "regfile:a.reg"
+    );
+    assert!("regfile".parse::<BackendSpec>().is_err());
+    assert!("regfile:".parse::<BackendSpec>().is_err());
+    assert!("memory:x".parse::<BackendSpec>().is_err());
+    assert!("hive".parse::<BackendSpec>().is_err());
+
+    let args = |line: &str| line.split(' ').map(String::from).collect::<Vec<_>>();
+    let options = RegFsOptions::from_args(args("--backend overlay,snapshot")).unwrap();
+    assert_eq!(
+        options.backends,
+        vec![BackendSpec::Overlay, BackendSpec::Snapshot]
+    );
+    // the old flags are the same layers
+    let options = RegFsOptions::from_args(args("--snapshot --overlay --reg-file a.reg")).unwrap();
+    assert_eq!(
+        options.backends,
+        vec![
+            BackendSpec::Snapshot,
+            BackendSpec::Overlay,
+            BackendSpec::RegFile("a.reg".into())
+        ]
+    );
+    assert!(RegFsOptions::from_args(args("--backend live,disk")).is_err());
+}
+
+#[test]
+fn test_backend_composition() {
+    let build = |backends: &[&str], writable: bool| {
+        RegFsOptions {
+            backends: backends.iter().map(|spec| spec.parse().unwrap()).collect(),
+            readonly: !writable,
+            ..Default::default()
+        }
+        .build()
+        .map(|options| options.backend_stack())
+    };
+
+    let stacks = [
+        (&[][..], "live"),
+        (&["live"][..], "live"),
+        (&["memory"][..], "memory"),
+        (&["snapshot"][..], "snapshot > live"),
+        (&["overlay"][..], "overlay > live"),
+        (
+            &["live", "snapshot", "overlay"][..],
+            "overlay > snapshot > live",
+        ),
+        (&["memory", "overlay"][..], "overlay > memory"),
+        (&["overlay", "overlay"][..], "overlay > live"),
+    ];
+    for (backends, stack) in stacks {
+        assert_eq!(build(backends, false).unwrap(), stack, "{:?}", backends);
+    }
+
+    // one base only
+    assert!(build(&["live", "memory"], false).is_err());
+    assert!(build(&["regfile:a.reg", "regfile:b.reg"], false).is_err());
+    // snapshot only over the live registry
+    assert!(build(&["snapshot", "memory"], false).is_err());
+    assert!(build(&["snapshot", "regfile:a.reg"], false).is_err());
+    // nothing read-only is writable without an overlay on top
+    assert!(build(&["snapshot"], true).is_err());
+    assert!(build(&["live"], true).is_ok());
+    assert_eq!(
+        build(&["snapshot", "overlay"], true).unwrap(),
+        "overlay > snapshot > live"
+    );
+
+    let impersonate = |backends: &[&str]| {
+        RegFsOptions {
+            backends: backends.iter().map(|spec| spec.parse().unwrap()).collect(),
+            impersonate: true,
+            ..Default::default()
+        }
+        .build()
+    };
+    assert!(impersonate(&["live"]).is_ok());
+    assert!(impersonate(&["snapshot"]).is_err());
+    assert!(impersonate(&["overlay"]).is_err());
+    assert!(impersonate(&["memory"]).is_err());
+
+    let backup = |backends: &[&str], readonly| {
+        RegFsOptions {
+            backends: backends.iter().map(|spec| spec.parse().unwrap()).collect(),
+            backup_semantics: true,
+            readonly,
+            ..Default::default()
+        }
+        .build()
+    };
+    assert!(backup(&["live"], true).is_ok());
+    assert!(backup(&["snapshot"], true).is_ok());
+    assert!(backup(&["live"], false).is_err());
+    assert!(backup(&["overlay"], true).is_err());
+    assert!(backup(&["memory"], true).is_err());
+
+    let user_classes = |hives: &[RootHive]| {
+        RegFsOptions {
+            user_classes: Some("S-1-5-21-1001".into()),
+            hives: hives.to_vec(),
+            ..Default::default()
+        }
+        .build()
+    };
+    assert!(user_classes(&RootHive::ALL).is_ok());
+    assert!(user_classes(&[RootHive::Users, RootHive::LocalMachine]).is_ok());
+    assert!(user_classes(&[RootHive::ClassesRoot]).is_err());
+
+    let options = RegFsOptions {
+        backends: vec![BackendSpec::Overlay],
+        ..Default::default()
+    }
+    .build()
+    .unwrap();
+    assert!(!options.readonly);
+    assert_eq!(options.base(), &BackendSpec::Live);
+}
+
+#[test]
+fn test_backend_regfile_is_loaded() {
+    let path = std::env::temp_dir().join(format!("regfs-backend-{}.reg", std::process::id()));
+    std::fs::write(&path, include_str!("../fixtures/all_types.reg")).unwrap();
+    let spec = BackendSpec::RegFile(path.clone());
+
+    let options = RegFsOptions {
+        backends: vec![spec.clone()],
+        ..Default::default()
+    }
+    .build()
+    .unwrap();
+    assert_eq!(options.base(), &spec);
+    assert!(options.reg_file.is_some());
+
+    let writable = RegFsOptions {
+        backends: vec![spec.clone()],
+        readonly: false,
+        ..Default::default()
+    }
+    .build()
+    .unwrap_err();
+    assert!(writable.to_string().contains("--writable"));
+
+    // the overlay takes the writes instead
+    let options = RegFsOptions {
+        backends: vec![BackendSpec::Overlay, spec],
+        readonly: false,
+        ..Default::default()
+    }
+    .build()
+    .unwrap();
+    assert!(options.overlay());
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,1197 @@
+use log::{info, warn};
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+use winapi::um::winnt::{
+    REG_BINARY, REG_DWORD, REG_DWORD_BIG_ENDIAN, REG_EXPAND_SZ, REG_LINK, REG_MULTI_SZ, REG_QWORD,
+    REG_SZ,
+};
+
+use crate::backend::RegistryBackend;
+use crate::conflict::ConflictPolicy;
+use crate::control::OverlayCommand;
+use crate::events::RegFsEvent;
+use crate::hash;
+use crate::memory::MemoryBackend;
+use crate::opcontext::{Cancelled, OpContext};
+use crate::redact::Redacted;
+use crate::regfile::{self, RegFile, RegFileKey, RegFileValue};
+use crate::regfs::RegFs;
+use crate::regop::{paths, RegEntires, RegEntry};
+use crate::render;
+use crate::watch::Watchers;
+
+fn fold(path: &Path) -> Vec<String> {
+    paths::components(path)
+        .iter()
+        .map(|part| part.to_string_lossy().to_lowercase())
+        .collect()
+}
+
+// `(default)` goes back to `@`; a key that really has a value called that can't tell the two
+// apart once they're listed, so the unnamed one is assumed
+fn export_name(name: OsString) -> OsString {
+    paths::default_value_fallback(&name).unwrap_or(name)
+}
+
+fn fold_name(entry: &RegEntry) -> String {
+    entry.name.to_string_lossy().to_lowercase()
+}
+
+// where an overlay keeps what was written over the registry below: memory, or with
+// --shadow-writes a staging key
+pub trait OverlayStore: RegistryBackend {
+    fn put_key(&self, path: &Path);
+
+    fn put_value(&self, path: &Path, vtype: u32, data: Vec<u8>);
+
+    // the key with everything under it, or the value
+    fn remove_entry(&self, path: &Path);
+
+    // deletions the store keeps itself, loaded when the overlay is created
+    fn tombstones(&self) -> Vec<(PathBuf, bool)> {
+        Vec::new()
+    }
+
+    fn add_tombstone(&self, _path: &Path, _is_key: bool) {}
+
+    // writes and tombstones alike
+    fn discard(&self);
+}
+
+impl OverlayStore for MemoryBackend {
+    fn put_key(&self, path: &Path) {
+        self.add_key(path);
+    }
+
+    fn put_value(&self, path: &Path, vtype: u32, data: Vec<u8>) {
+        match (path.parent(), path.file_name()) {
+            (Some(key), Some(name)) => self.set_value(key, name, vtype, data),
+            _ => warn!(target: "overlay", "[{:?}] can't hold a value", path),
+        }
+    }
+
+    fn remove_entry(&self, path: &Path) {
+        self.remove(path);
+    }
+
+    fn discard(&self) {
+        self.clear();
+    }
+}
+
+// --overlay: writes land here and shadow the registry below, which is never touched
+pub struct OverlayBackend {
+    lower: Arc<dyn RegistryBackend>,
+    upper: Arc<dyn OverlayStore>,
+    // deleted keys and values by case-folded path; each hides everything under it in the
+    // lower layer
+    tombstones: RwLock<BTreeMap<Vec<String>, Tombstone>>,
+}
+
+#[derive(Debug, Clone)]
+struct Tombstone {
+    path: PathBuf,
+    is_key: bool,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OverlayStats {
+    pub keys: usize,
+    pub values: usize,
+    pub deletions: usize,
+    pub bytes: u64,
+}
+
+impl OverlayStats {
+    // counted from the changes themselves, so a dump gives the same numbers
+    pub fn of(changes: &RegFile) -> Self {
+        let mut stats = OverlayStats::default();
+        for key in &changes.keys {
+            match key.delete {
+                true => stats.deletions += 1,
+                false => stats.keys += 1,
+            }
+            for value in &key.values {
+                match &value.data {
+                    Some((_, data)) => {
+                        stats.values += 1;
+                        stats.bytes += data.len() as u64;
+                    }
+                    None => stats.deletions += 1,
+                }
+            }
+        }
+        stats
+    }
+}
+
+impl fmt::Display for OverlayStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} keys, {} values ({} bytes), {} deletions",
+            self.keys, self.values, self.bytes, self.deletions
+        )
+    }
+}
+
+impl OverlayBackend {
+    pub fn new(lower: Arc<dyn RegistryBackend>) -> Self {
+        OverlayBackend::with_store(lower, Arc::new(MemoryBackend::new()))
+    }
+
+    pub fn with_store(lower: Arc<dyn RegistryBackend>, upper: Arc<dyn OverlayStore>) -> Self {
+        let tombstones = upper
+            .tombstones()
+            .into_iter()
+            .map(|(path, is_key)| (fold(&path), Tombstone { path, is_key }))
+            .collect();
+        OverlayBackend {
+            lower,
+            upper,
+            tombstones: RwLock::new(tombstones),
+        }
+    }
+
+    fn shadowed(&self, path: &Path) -> bool {
+        let path = fold(path);
+        let tombstones = self.tombstones.read().unwrap();
+        tombstones
+            .keys()
+            .any(|tombstone| path.starts_with(tombstone))
+    }
+
+    pub fn create_key(&self, path: &Path) {
+        self.upper.put_key(path);
+    }
+
+    pub fn set_value(&self, path: &Path, vtype: u32, data: Vec<u8>) {
+        self.upper.put_value(path, vtype, data);
+    }
+
+    pub fn delete(&self, path: &Path) {
+        let tombstone = Tombstone {
+            path: path.to_path_buf(),
+            is_key: self.does_key_exist(path),
+        };
+        self.upper.remove_entry(path);
+        self.upper.add_tombstone(path, tombstone.is_key);
+        self.tombstones
+            .write()
+            .unwrap()
+            .insert(fold(path), tombstone);
+    }
+
+    // back to the registry as it is
+    pub fn reset(&self) {
+        self.upper.discard();
+        self.tombstones.write().unwrap().clear();
+    }
+
+    // what importing the result into the registry below would take to match the overlay:
+    // deleted keys first, then every key with its deleted and set values, in path order
+    pub fn changes(&self) -> RegFile {
+        let tombstones = self.tombstones.read().unwrap().clone();
+        let deleted_keys: Vec<&Vec<String>> = tombstones
+            .iter()
+            .filter(|(_, tombstone)| tombstone.is_key)
+            .map(|(folded, _)| folded)
+            .collect();
+        // anything under a deleted key went with it
+        let covered = |folded: &[String]| {
+            deleted_keys
+                .iter()
+                .any(|key| folded.len() > key.len() && folded.starts_with(key))
+        };
+
+        let mut changes = RegFile::default();
+        for (folded, tombstone) in &tombstones {
+            if tombstone.is_key && !covered(folded) {
+                changes.keys.push(RegFileKey {
+                    path: tombstone.path.clone(),
+                    delete: true,
+                    values: Vec::new(),
+                });
+            }
+        }
+
+        let mut keys: BTreeMap<Vec<String>, RegFileKey> = BTreeMap::new();
+        for (folded, tombstone) in &tombstones {
+            let (parent, name) = match (tombstone.path.parent(), tombstone.path.file_name()) {
+                (Some(parent), Some(name)) => (parent, name),
+                _ => continue,
+            };
+            if tombstone.is_key
+                || covered(folded)
+                || self.upper.read_value(&tombstone.path).is_some()
+            {
+                continue;
+            }
+            keys.entry(fold(parent))
+                .or_insert_with(|| RegFileKey {
+                    path: parent.to_path_buf(),
+                    delete: false,
+                    values: Vec::new(),
+                })
+                .values
+                .push(RegFileValue {
+                    name: export_name(name.into()),
+                    data: None,
+                });
+        }
+        self.collect_upper(Path::new(""), &mut keys);
+
+        changes.keys.extend(keys.into_values());
+        changes
+    }
+
+    fn collect_upper(&self, path: &Path, keys: &mut BTreeMap<Vec<String>, RegFileKey>) {
+        let entries = match self.upper.enumerate_key(path.into()) {
+            Some(entries) => entries,
+            None => return,
+        };
+
+        if !path.as_os_str().is_empty() {
+            let values = self.upper.read_all_values(path).unwrap_or_default();
+            // keys the overlay only holds on the way to something else aren't changes
+            let created = self.shadowed(path) || !self.lower.does_key_exist(path);
+            if created || !values.is_empty() {
+                let key = keys.entry(fold(path)).or_insert_with(|| RegFileKey {
+                    path: path.to_path_buf(),
+                    delete: false,
+                    values: Vec::new(),
+                });
+                key.values
+                    .extend(values.into_iter().map(|(name, vtype, data)| RegFileValue {
+                        name: export_name(name),
+                        data: Some((vtype, data)),
+                    }));
+            }
+        }
+
+        for subkey in entries.subkeys {
+            self.collect_upper(&path.join(&subkey.name), keys);
+        }
+    }
+
+    pub fn stats(&self) -> OverlayStats {
+        OverlayStats::of(&self.changes())
+    }
+
+    // moves a key with everything under it, or a single value
+    pub fn rename(&self, from: &Path, to: &Path) {
+        if self.does_key_exist(from) {
+            self.copy_key(from, to);
+        } else if let Some((vtype, data)) = self.read_typed_value(from) {
+            self.delete(to);
+            self.set_value(to, vtype, data);
+        } else {
+            warn!(target: "overlay", "nothing to rename at [{:?}]", from);
+            return;
+        }
+        self.delete(from);
+    }
+
+    fn copy_key(&self, from: &Path, to: &Path) {
+        // whatever was at the destination is replaced, not merged
+        self.delete(to);
+        self.create_key(to);
+
+        for (name, vtype, data) in self.read_all_values(from).unwrap_or_default() {
+            self.set_value(&to.join(name), vtype, data);
+        }
+        for subkey in self
+            .enumerate_key(from.into())
+            .map(|entries| entries.subkeys)
+            .unwrap_or_default()
+        {
+            self.copy_key(&from.join(&subkey.name), &to.join(&subkey.name));
+        }
+    }
+
+    fn read_typed_value(&self, path: &Path) -> Option<(u32, Vec<u8>)> {
+        self.read_typed_value_ctx(path, &OpContext::none())
+            .ok()
+            .flatten()
+    }
+}
+
+impl RegistryBackend for OverlayBackend {
+    fn enumerate_key_ctx(
+        &self,
+        path: OsString,
+        ctx: &OpContext,
+    ) -> Result<Option<RegEntires>, Cancelled> {
+        let key = Path::new(&path);
+        let upper = self.upper.enumerate_key_ctx(path.clone(), ctx)?;
+        let lower = match self.shadowed(key) {
+            true => None,
+            false => self.lower.enumerate_key_ctx(path.clone(), ctx)?,
+        };
+
+        let (upper, lower) = match (upper, lower) {
+            (None, None) => return Ok(None),
+            (upper, lower) => (upper.unwrap_or_default(), lower.unwrap_or_default()),
+        };
+
+        // the overlay wins; keys it only mirrors keep the registry's time
+        let mut subkeys: BTreeMap<String, RegEntry> = upper
+            .subkeys
+            .into_iter()
+            .map(|entry| (fold_name(&entry), entry))
+            .collect();
+        for entry in lower.subkeys {
+            if self.shadowed(&key.join(&entry.name)) {
+                continue;
+            }
+            let time = entry.last_write_time;
+            let merged = subkeys.entry(fold_name(&entry)).or_insert(entry);
+            merged.last_write_time = merged.last_write_time.max(time);
+        }
+
+        let mut values: BTreeMap<String, RegEntry> = upper
+            .values
+            .into_iter()
+            .map(|entry| (fold_name(&entry), entry))
+            .collect();
+        for entry in lower.values {
+            if !self.shadowed(&key.join(&entry.name)) {
+                values.entry(fold_name(&entry)).or_insert(entry);
+            }
+        }
+
+        Ok(Some(RegEntires {
+            subkeys: subkeys.into_values().collect(),
+            values: values.into_values().collect(),
+            ..lower
+        }))
+    }
+
+    fn read_typed_value_ctx(
+        &self,
+        path: &Path,
+        ctx: &OpContext,
+    ) -> Result<Option<(u32, Vec<u8>)>, Cancelled> {
+        if let Some(value) = self.upper.read_typed_value_ctx(path, ctx)? {
+            return Ok(Some(value));
+        }
+        match self.shadowed(path) {
+            true => Ok(None),
+            false => self.lower.read_typed_value_ctx(path, ctx),
+        }
+    }
+
+    fn read_all_values(&self, path: &Path) -> Option<Vec<(OsString, u32, Vec<u8>)>> {
+        let entries = self.enumerate_key(path.into())?;
+        Some(
+            entries
+                .values
+                .into_iter()
+                .filter_map(|value| {
+                    let (vtype, data) = self.read_typed_value(&path.join(&value.name))?;
+                    Some((value.name, vtype, data))
+                })
+                .collect(),
+        )
+    }
+
+    fn does_key_exist(&self, path: &Path) -> bool {
+        self.upper.does_key_exist(path) || (!self.shadowed(path) && self.lower.does_key_exist(path))
+    }
+
+    fn key_last_write_time(&self, path: &Path) -> Option<i64> {
+        let upper = self.upper.key_last_write_time(path);
+        let lower = match self.shadowed(path) {
+            true => None,
+            false => self.lower.key_last_write_time(path),
+        };
+        upper.max(lower)
+    }
+
+    fn watchers(&self) -> Option<&Watchers> {
+        self.lower.watchers()
+    }
+
+    // links can't be made through the mount
+    fn link_target(&self, path: &Path) -> Option<PathBuf> {
+        self.lower.link_target(path)
+    }
+}
+
+// what a notification changed in the mount
+#[derive(Debug, Clone, Copy)]
+pub enum Change<'a> {
+    Created { path: &'a Path, is_directory: bool },
+    Modified(&'a Path),
+    // modified after being opened to be truncated or replaced: the new content is the whole
+    // value, whatever was there before, so it isn't checked for conflicts
+    Overwritten(&'a Path),
+    Deleted(&'a Path),
+    // `to` is empty when the file moved out of the mount
+    Renamed { from: &'a Path, to: &'a Path },
+}
+
+impl<'a> Change<'a> {
+    fn paths(&self) -> Vec<&'a Path> {
+        match *self {
+            Change::Created { path, .. }
+            | Change::Modified(path)
+            | Change::Overwritten(path)
+            | Change::Deleted(path) => vec![path],
+            Change::Renamed { from, to } => vec![from, to],
+        }
+    }
+}
+
+impl RegFs {
+    pub fn overlay_command(&self, command: OverlayCommand) -> String {
+        let overlay = match self.overlay() {
+            Some(overlay) => overlay,
+            None => return "overlay: not mounted with --overlay".to_string(),
+        };
+
+        match command {
+            OverlayCommand::Stats => overlay.stats().to_string(),
+            OverlayCommand::Export(file) => {
+                let changes = overlay.changes();
+                match regfile::write(&file, &changes) {
+                    Ok(()) => format!("exported {} to {:?}", OverlayStats::of(&changes), file),
+                    Err(e) => format!("overlay export: {}", e),
+                }
+            }
+            OverlayCommand::Dump(file) => {
+                let changes = overlay.changes();
+                match fs::write(&file, changes.to_json().to_string()) {
+                    Ok(()) => format!("dumped {} to {:?}", OverlayStats::of(&changes), file),
+                    Err(e) => format!("overlay dump: unable to write [{:?}]: {}", file, e),
+                }
+            }
+            OverlayCommand::Reset => {
+                let stats = overlay.stats();
+                overlay.reset();
+                // the hive summaries may have counted what's gone now
+                self.clear_synthetic_content();
+                self.forget_written(None);
+                format!("discarded {}", stats)
+            }
+        }
+    }
+
+    // keeps the overlay in step with the files; without one, changes stay on disk only
+    pub fn record_change(&self, change: Change) {
+        let overlay = match self.overlay() {
+            Some(overlay) => overlay,
+            None => return,
+        };
+        info!(target: "overlay", "----> record_change: {:?}", change);
+        // what only the projection has never reaches the overlay, nor an export of it
+        if change
+            .paths()
+            .iter()
+            .any(|path| self.synthetic(path).is_some())
+        {
+            info!(target: "overlay", "<---- record_change: synthetic, ignored");
+            return;
+        }
+
+        let path = match change {
+            Change::Created {
+                path,
+                is_directory: true,
+            } => {
+                overlay.create_key(&self.write_path(path));
+                path
+            }
+            Change::Created { path, .. } => {
+                overlay.set_value(&self.write_path(path), REG_BINARY, Vec::new());
+                path
+            }
+            Change::Modified(path) | Change::Overwritten(path) => match self.written_value(
+                path,
+                overlay
+                    .value_type(&self.registry_path(path))
+                    .unwrap_or(REG_BINARY),
+            ) {
+                Some(_)
+                    if matches!(change, Change::Modified(_))
+                        && !self.resolve_conflict(overlay, path) =>
+                {
+                    return
+                }
+                Some((vtype, data)) => {
+                    info!(
+                        target: "overlay",
+                        "[{:?}] written back as a {}: {}",
+                        path,
+                        render::type_name(vtype),
+                        Redacted(&data)
+                    );
+                    overlay.set_value(&self.write_path(path), vtype, data);
+                    // what the file was saved against from now on
+                    if let Ok(Some(projected)) = self.read_projected_value(path, &OpContext::none())
+                    {
+                        self.record_content_id(path, hash::fnv1a(&projected));
+                    }
+                    path
+                }
+                None => return,
+            },
+            Change::Deleted(path) => {
+                overlay.delete(&self.write_path(path));
+                path
+            }
+            Change::Renamed { from, to } if to.as_os_str().is_empty() => {
+                overlay.delete(&self.write_path(from));
+                from
+            }
+            Change::Renamed { from, to } => {
+                overlay.rename(&self.write_path(from), &self.write_path(to));
+                self.forget_written(Some(from));
+                to
+            }
+        };
+
+        self.forget_written(Some(path));
+        self.emit(RegFsEvent::WriteBackApplied {
+            path: path.to_path_buf(),
+        });
+    }
+
+    // whether the save can go ahead; the value changed under it when it's no longer what the
+    // file was projected from
+    pub fn resolve_conflict(&self, backend: &dyn RegistryBackend, path: &Path) -> bool {
+        let projected_from = match self.recorded_content_id(path) {
+            Some(content_id) => content_id,
+            None => return true,
+        };
+        let current = self
+            .read_projected_value(path, &OpContext::none())
+            .ok()
+            .flatten();
+        if current.as_deref().map(hash::fnv1a) == Some(projected_from) {
+            return true;
+        }
+
+        match self.options().conflict.for_path(path) {
+            ConflictPolicy::Fail => {
+                warn!(
+                    target: "overlay",
+                    "[{:?}] changed in the registry since it was read, not applied",
+                    path
+                );
+                self.emit(RegFsEvent::OperationDenied {
+                    path: path.to_path_buf(),
+                    reason: "changed in the registry".into(),
+                });
+                false
+            }
+            ConflictPolicy::Overwrite => {
+                info!(target: "overlay", "[{:?}] changed in the registry, overwritten", path);
+                true
+            }
+            ConflictPolicy::BackupThenOverwrite => match self.backup_value(backend, path) {
+                Ok(backup) => {
+                    info!(
+                        target: "overlay",
+                        "[{:?}] changed in the registry, overwritten after a backup to {:?}",
+                        path,
+                        backup
+                    );
+                    true
+                }
+                Err(e) => {
+                    warn!(target: "overlay", "[{:?}] not applied: {}", path, e);
+                    false
+                }
+            },
+        }
+    }
+
+    // the value as the registry has it now, as a .reg file that puts it back
+    fn backup_value(&self, backend: &dyn RegistryBackend, path: &Path) -> anyhow::Result<PathBuf> {
+        let value = self.registry_path(path);
+        let (key, name) = match (value.parent(), value.file_name()) {
+            (Some(key), Some(name)) => (key, name),
+            _ => anyhow::bail!("[{:?}] is not a value", path),
+        };
+        let backup = RegFile {
+            keys: vec![RegFileKey {
+                path: key.to_path_buf(),
+                delete: false,
+                values: vec![RegFileValue {
+                    name: export_name(name.into()),
+                    // a value deleted in the meantime is backed up as its deletion
+                    data: backend
+                        .read_typed_value_ctx(&value, &OpContext::none())
+                        .ok()
+                        .flatten(),
+                }],
+            }],
+        };
+
+        let backups = &self.options().conflict.backups;
+        fs::create_dir_all(backups)
+            .map_err(|e| anyhow::anyhow!("unable to create [{:?}]: {}", backups, e))?;
+        let stamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        // a file name can't be over 255 characters, a value's name can
+        let label: String = name.to_string_lossy().chars().take(200).collect();
+        let file = backups.join(format!("{}-{}.reg", stamp, label));
+        regfile::write(&file, &backup)?;
+        Ok(file)
+    }
+
+    // the file on disk as registry data of the value's type (REG_BINARY for a new one), back
+    // through its transformer
+    pub fn written_value(&self, path: &Path, vtype: u32) -> Option<(u32, Vec<u8>)> {
+        let (vtype, data) = self.projected_value(path, vtype)?;
+        if fits(vtype, &data) {
+            return Some((vtype, data));
+        }
+
+        if !self.options().allow_type_change {
+            warn!(
+                target: "overlay",
+                "[{:?}] doesn't hold a {} anymore, not applied (--allow-type-change turns it into a {}): {}",
+                path,
+                render::type_name(vtype),
+                render::type_name(REG_BINARY),
+                Redacted(&data)
+            );
+            return None;
+        }
+        warn!(
+            target: "overlay",
+            "[{:?}] changed type: {} -> {}",
+            path,
+            render::type_name(vtype),
+            render::type_name(REG_BINARY)
+        );
+        Some((REG_BINARY, data))
+    }
+
+    fn projected_value(&self, path: &Path, vtype: u32) -> Option<(u32, Vec<u8>)> {
+        let projected = match fs::read(self.local_path(path)) {
+            Ok(projected) => projected,
+            Err(e) => {
+                warn!(target: "overlay", "unable to read [{:?}]: {}", path, e);
+                return None;
+            }
+        };
+        match self.options().transformers.find_for(path, vtype) {
+            Some(transformer) => match transformer.inverse(path, vtype, &projected) {
+                Some(raw) => Some((vtype, raw)),
+                None => {
+                    warn!(target: "overlay", "[{:?}] can't be turned back into its value", path);
+                    None
+                }
+            },
+            None => Some((vtype, projected)),
+        }
+    }
+}
+
+// whether data still makes sense as a value of this type
+fn fits(vtype: u32, data: &[u8]) -> bool {
+    match vtype {
+        REG_DWORD | REG_DWORD_BIG_ENDIAN => data.len() == 4,
+        REG_QWORD => data.len() == 8,
+        REG_SZ | REG_EXPAND_SZ | REG_MULTI_SZ | REG_LINK => data.len() % 2 == 0,
+        _ => true,
+    }
+}
+
+// `regfs overlay export <dump.json> <output.reg>`, for a dump taken with `overlay dump`
+pub fn export_dump(dump: &Path, output: &Path) -> anyhow::Result<()> {
+    let text = fs::read_to_string(dump)
+        .map_err(|e| anyhow::anyhow!("unable to read [{:?}]: {}", dump, e))?;
+    let json: serde_json::Value = serde_json::from_str(&text)
+        .map_err(|e| anyhow::anyhow!("[{:?}] is not an overlay dump: {}", dump, e))?;
+    let changes = RegFile::from_json(&json)?;
+
+    regfile::write(output, &changes)?;
+    println!("exported {} to {:?}", OverlayStats::of(&changes), output);
+    Ok(())
+}
+
+#[cfg(test)]
+fn overlay_fixture() -> (Arc<MemoryBackend>, OverlayBackend) {
+    let lower = Arc::new(MemoryBackend::new());
+    lower.set_value("HKEY_CURRENT_USER\\Software\\App", "Name", 1, vec![1]);
+    lower.set_value("HKEY_CURRENT_USER\\Software\\App\\Sub", "Inner", 1, vec![2]);
+    lower.add_key("HKEY_CURRENT_USER\\Software\\Other");
+    let overlay = OverlayBackend::new(lower.clone());
+    (lower, overlay)
+}
+
+#[cfg(test)]
+fn names(entries: &[RegEntry]) -> Vec<String> {
+    entries
+        .iter()
+        .map(|entry| entry.name.to_string_lossy().into_owned())
+        .collect()
+}
+
+#[test]
+fn test_overlay_create_then_list() {
+    let (lower, overlay) = overlay_fixture();
+    overlay.create_key("HKEY_CURRENT_USER\\Software\\App\\New".as_ref());
+    overlay.set_value(
+        "HKEY_CURRENT_USER\\Software\\App\\Added".as_ref(),
+        3,
+        vec![9],
+    );
+
+    let app = overlay
+        .enumerate_key("HKEY_CURRENT_USER\\Software\\App".into())
+        .unwrap();
+    assert_eq!(names(&app.subkeys), vec!["New", "Sub"]);
+    assert_eq!(names(&app.values), vec!["Added", "Name"]);
+
+    // the registry below never sees any of it
+    assert!(!lower.does_key_exist("HKEY_CURRENT_USER\\Software\\App\\New".as_ref()));
+    assert_eq!(
+        lower.read_value("HKEY_CURRENT_USER\\Software\\App\\Added".as_ref()),
+        None
+    );
+}
+
+#[test]
+fn test_overlay_modify_then_read() {
+    let (lower, overlay) = overlay_fixture();
+    let name = Path::new("HKEY_CURRENT_USER\\Software\\App\\Name");
+    overlay.set_value(name, 1, vec![5, 5]);
+
+    assert_eq!(overlay.read_value(name), Some(vec![5, 5]));
+    assert_eq!(lower.read_value(name), Some(vec![1]));
+    let app = overlay
+        .enumerate_key("HKEY_CURRENT_USER\\Software\\App".into())
+        .unwrap();
+    assert_eq!(app.values.len(), 1);
+    assert_eq!(app.values[0].size, 2);
+}
+
+#[test]
+fn test_overlay_delete_then_stat() {
+    let (lower, overlay) = overlay_fixture();
+    let app = Path::new("HKEY_CURRENT_USER\\Software\\App");
+    overlay.delete(app);
+
+    assert!(!overlay.does_key_exist(app));
+    assert_eq!(overlay.key_last_write_time(app), None);
+    assert_eq!(overlay.read_value(&app.join("Sub\\Inner")), None);
+    let software = overlay
+        .enumerate_key("HKEY_CURRENT_USER\\Software".into())
+        .unwrap();
+    assert_eq!(names(&software.subkeys), vec!["Other"]);
+    assert!(lower.does_key_exist(app));
+
+    // created again, it starts out empty instead of bringing the old contents back
+    overlay.create_key(app);
+    let recreated = overlay.enumerate_key(app.into()).unwrap();
+    assert!(recreated.subkeys.is_empty() && recreated.values.is_empty());
+}
+
+#[test]
+fn test_overlay_rename() {
+    let (lower, overlay) = overlay_fixture();
+    let software = Path::new("HKEY_CURRENT_USER\\Software");
+
+    overlay.rename(&software.join("App"), &software.join("Moved"));
+    assert!(!overlay.does_key_exist(&software.join("App")));
+    assert_eq!(
+        overlay.read_value(&software.join("Moved\\Sub\\Inner")),
+        Some(vec![2])
+    );
+
+    // a value, then back over a key that's there already
+    overlay.rename(
+        &software.join("Moved\\Name"),
+        &software.join("Moved\\Renamed"),
+    );
+    assert_eq!(overlay.read_value(&software.join("Moved\\Name")), None);
+    assert_eq!(
+        overlay.read_value(&software.join("Moved\\Renamed")),
+        Some(vec![1])
+    );
+    overlay.rename(&software.join("Moved"), &software.join("Other"));
+    let other = overlay
+        .enumerate_key(software.join("Other").into())
+        .unwrap();
+    assert_eq!(names(&other.subkeys), vec!["Sub"]);
+    assert_eq!(names(&other.values), vec!["Renamed"]);
+
+    let listed = overlay.enumerate_key(software.into()).unwrap();
+    assert_eq!(names(&listed.subkeys), vec!["Other"]);
+    assert!(lower.does_key_exist(&software.join("App\\Sub")));
+}
+
+#[test]
+fn test_changes_reach_the_overlay() {
+    use crate::options::{BackendSpec, RegFsOptions};
+
+    let (lower, _) = overlay_fixture();
+    let options = RegFsOptions {
+        backends: vec![BackendSpec::Overlay],
+        values_json: true,
+        ..Default::default()
+    };
+    let regfs = RegFs::with_backend(&options, lower.clone());
+    let software = Path::new("HKEY_CURRENT_USER\\Software");
+
+    // generated files aren't registry data, whatever is done to them
+    regfs.record_change(Change::Modified(&software.join("App\\_values.json")));
+    regfs.record_change(Change::Renamed {
+        from: &software.join("App\\Name"),
+        to: &software.join("_values.json"),
+    });
+    assert_eq!(regfs.overlay().unwrap().stats(), OverlayStats::default());
+
+    regfs.record_change(Change::Created {
+        path: &software.join("Fresh"),
+        is_directory: true,
+    });
+    regfs.record_change(Change::Renamed {
+        from: &software.join("Other"),
+        to: &software.join("Fresh\\Other"),
+    });
+    regfs.record_change(Change::Deleted(&software.join("App\\Name")));
+
+    assert!(regfs
+        .regops()
+        .does_key_exist(&software.join("Fresh\\Other")));
+    assert!(!regfs.regops().does_key_exist(&software.join("Other")));
+    assert_eq!(regfs.regops().read_value(&software.join("App\\Name")), None);
+    assert_eq!(lower.read_value(&software.join("App\\Name")), Some(vec![1]));
+}
+
+#[cfg(test)]
+fn flatten(backend: &dyn RegistryBackend, path: &Path, into: &mut Vec<String>) {
+    let entries = match backend.enumerate_key(path.into()) {
+        Some(entries) => entries,
+        None => return,
+    };
+    into.push(path.to_string_lossy().to_lowercase());
+    for value in entries.values {
+        let value = path.join(&value.name);
+        let data = backend
+            .read_typed_value_ctx(&value, &OpContext::none())
+            .ok()
+            .flatten();
+        into.push(format!(
+            "{} = {:?}",
+            value.to_string_lossy().to_lowercase(),
+            data
+        ));
+    }
+    for subkey in entries.subkeys {
+        flatten(backend, &path.join(&subkey.name), into);
+    }
+}
+
+#[test]
+fn test_export_round_trip() {
+    let (_, overlay) = overlay_fixture();
+    let software = Path::new("HKEY_CURRENT_USER\\Software");
+    overlay.set_value(&software.join("App\\Name"), 1, vec![7, 7]);
+    overlay.set_value(&software.join("Fresh\\Deep\\Value"), 4, vec![1, 0, 0, 0]);
+    overlay.delete(&software.join("App\\Sub"));
+    overlay.delete(&software.join("Other"));
+    overlay.create_key(&software.join("Other"));
+    overlay.rename(&software.join("App\\Name"), &software.join("App\\Renamed"));
+
+    let changes = overlay.changes();
+    assert_eq!(
+        overlay.stats(),
+        OverlayStats {
+            keys: 4,
+            values: 2,
+            deletions: 3,
+            bytes: 6,
+        }
+    );
+    // the same every time
+    assert_eq!(overlay.changes().to_text(), changes.to_text());
+
+    // imported into a fresh copy of the registry below, it looks like the overlay
+    let text = changes.to_text();
+    let (scratch, _) = overlay_fixture();
+    regfile::parse(&text).unwrap().apply_to(&scratch);
+
+    let (mut expected, mut imported) = (Vec::new(), Vec::new());
+    flatten(&overlay, software, &mut expected);
+    flatten(scratch.as_ref(), software, &mut imported);
+    assert_eq!(imported, expected);
+
+    overlay.reset();
+    assert_eq!(overlay.stats(), OverlayStats::default());
+    assert_eq!(
+        overlay.read_value(&software.join("App\\Name")),
+        Some(vec![1])
+    );
+}
+
+#[test]
+fn test_write_back_keeps_the_value_type() {
+    use crate::options::{BackendSpec, RegFsOptions};
+
+    let root = std::env::temp_dir().join(format!("regfs-types-{}", std::process::id()));
+    let app = Path::new("HKEY_CURRENT_USER\\Software\\App");
+    fs::create_dir_all(root.join(app)).unwrap();
+
+    let lower = Arc::new(MemoryBackend::new());
+    lower.set_value(app, "Path", REG_EXPAND_SZ, regfile::string_data("%TEMP%"));
+    lower.set_value(app, "Count", REG_DWORD, vec![1, 0, 0, 0]);
+    let options = RegFsOptions {
+        root: root.clone(),
+        backends: vec![BackendSpec::Overlay],
+        ..Default::default()
+    };
+    let regfs = RegFs::with_backend(&options, lower.clone());
+
+    let edited = regfile::string_data("%WINDIR%\\Temp");
+    fs::write(root.join(app).join("Path"), &edited).unwrap();
+    regfs.record_change(Change::Modified(&app.join("Path")));
+    let overlay = regfs.overlay().unwrap();
+    assert_eq!(
+        overlay.read_typed_value(&app.join("Path")),
+        Some((REG_EXPAND_SZ, edited))
+    );
+
+    // three bytes aren't a DWORD anymore
+    fs::write(root.join(app).join("Count"), [1, 2, 3]).unwrap();
+    regfs.record_change(Change::Modified(&app.join("Count")));
+    assert_eq!(
+        overlay.read_typed_value(&app.join("Count")),
+        Some((REG_DWORD, vec![1, 0, 0, 0]))
+    );
+
+    let regfs = RegFs::with_backend(
+        &RegFsOptions {
+            allow_type_change: true,
+            ..options
+        },
+        lower,
+    );
+    regfs.record_change(Change::Modified(&app.join("Count")));
+    assert_eq!(
+        regfs
+            .overlay()
+            .unwrap()
+            .read_typed_value(&app.join("Count")),
+        Some((REG_BINARY, vec![1, 2, 3]))
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_write_back_text_encoding() {
+    use crate::options::{BackendSpec, RegFsOptions};
+    use crate::transform::TextEncoding;
+
+    let root = std::env::temp_dir().join(format!("regfs-text-{}", std::process::id()));
+    let app = Path::new("HKEY_CURRENT_USER\\Software\\App");
+    fs::create_dir_all(root.join(app)).unwrap();
+
+    let lower = Arc::new(MemoryBackend::new());
+    lower.set_value(app, "Name", REG_SZ, regfile::string_data("old"));
+    lower.set_value(app, "Path", REG_EXPAND_SZ, regfile::string_data("%TEMP%"));
+    let mut options = RegFsOptions {
+        root: root.clone(),
+        backends: vec![BackendSpec::Overlay],
+        ..Default::default()
+    };
+    options.transformers.set_text_encoding(TextEncoding::Utf16);
+    let regfs = RegFs::with_backend(&options, lower);
+    let overlay = regfs.overlay().unwrap();
+
+    // what an editor saves a UTF-16 file as, BOM first and no NUL at the end
+    let utf16: Vec<u8> = [0xff, 0xfe]
+        .into_iter()
+        .chain("Grüße".encode_utf16().flat_map(|unit| unit.to_le_bytes()))
+        .collect();
+    fs::write(root.join(app).join("Name"), &utf16).unwrap();
+    regfs.record_change(Change::Modified(&app.join("Name")));
+    assert_eq!(
+        overlay.read_typed_value(&app.join("Name")),
+        Some((REG_SZ, regfile::string_data("Grüße")))
+    );
+
+    // or as UTF-8, which the BOM tells apart from the mount's UTF-16
+    fs::write(root.join(app).join("Path"), "\u{feff}%WINDIR%\\Temp").unwrap();
+    regfs.record_change(Change::Modified(&app.join("Path")));
+    assert_eq!(
+        overlay.read_typed_value(&app.join("Path")),
+        Some((REG_EXPAND_SZ, regfile::string_data("%WINDIR%\\Temp")))
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_write_back_default_value() {
+    use crate::options::{BackendSpec, RegFsOptions};
+
+    let root = std::env::temp_dir().join(format!("regfs-default-{}", std::process::id()));
+    let app = Path::new("HKEY_CURRENT_USER\\Software\\App");
+    let empty = Path::new("HKEY_CURRENT_USER\\Software\\Empty");
+    let taken = Path::new("HKEY_CURRENT_USER\\Software\\Taken");
+    for key in [app, empty, taken] {
+        fs::create_dir_all(root.join(key)).unwrap();
+    }
+
+    let lower = Arc::new(MemoryBackend::new());
+    lower.set_value(app, "", REG_SZ, regfile::string_data("old"));
+    lower.add_key(empty);
+    lower.set_value(taken, "", REG_SZ, regfile::string_data("unnamed"));
+    lower.set_value(taken, "(default)", REG_SZ, regfile::string_data("named"));
+    let options = RegFsOptions {
+        root: root.clone(),
+        backends: vec![BackendSpec::Overlay],
+        ..Default::default()
+    };
+    let regfs = RegFs::with_backend(&options, lower.clone());
+    let overlay = regfs.overlay().unwrap();
+    let default = |key: &Path| key.join(paths::DEFAULT_VALUE);
+    let values = |key: &Path| names(&overlay.enumerate_key(key.into()).unwrap().values);
+
+    // edit
+    assert_eq!(values(app), ["(default)"]);
+    let edited = regfile::string_data("new");
+    fs::write(root.join(default(app)), &edited).unwrap();
+    regfs.record_change(Change::Modified(&default(app)));
+    assert_eq!(
+        overlay.read_typed_value(&default(app)),
+        Some((REG_SZ, edited.clone()))
+    );
+    assert_eq!(values(app), ["(default)"]);
+
+    // create, in a key that had none
+    assert!(values(empty).is_empty());
+    regfs.record_change(Change::Created {
+        path: &default(empty),
+        is_directory: false,
+    });
+    fs::write(root.join(default(empty)), [1, 2]).unwrap();
+    regfs.record_change(Change::Modified(&default(empty)));
+    assert_eq!(values(empty), ["(default)"]);
+    assert_eq!(
+        overlay.read_typed_value(&default(empty)),
+        Some((REG_BINARY, vec![1, 2]))
+    );
+
+    // both go back out as `@`
+    let changes = overlay.changes();
+    let exported: Vec<_> = changes
+        .keys
+        .iter()
+        .flat_map(|key| key.values.iter().map(move |value| (&key.path, &value.name)))
+        .collect();
+    assert_eq!(
+        exported,
+        [
+            (&app.to_path_buf(), &OsString::new()),
+            (&empty.to_path_buf(), &OsString::new())
+        ]
+    );
+
+    // delete
+    regfs.record_change(Change::Deleted(&default(app)));
+    assert!(values(app).is_empty());
+    assert_eq!(overlay.read_typed_value(&default(app)), None);
+    assert!(overlay
+        .changes()
+        .to_text()
+        .contains("[HKEY_CURRENT_USER\\Software\\App]\r\n@=-\r\n"));
+
+    // a value really called `(default)` is the one the file stands for
+    assert_eq!(values(taken), ["(default)"]);
+    assert_eq!(
+        overlay.read_typed_value(&default(taken)),
+        Some((REG_SZ, regfile::string_data("named")))
+    );
+    assert_eq!(
+        lower.read_typed_value_ctx(&default(taken), &OpContext::none()),
+        Ok(Some((REG_SZ, regfile::string_data("named"))))
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_write_back_conflicts() {
+    use crate::conflict::ConflictPolicies;
+    use crate::options::{BackendSpec, RegFsOptions};
+
+    let root = std::env::temp_dir().join(format!("regfs-conflict-{}", std::process::id()));
+    let backups = root.join("backups");
+    let app = Path::new("HKEY_CURRENT_USER\\Software\\App");
+    let name = app.join("Name");
+    fs::create_dir_all(root.join(app)).unwrap();
+
+    let saved = regfile::string_data("saved");
+    let concurrent = regfile::string_data("concurrent");
+    let write_back = |policy: &str| {
+        let lower = Arc::new(MemoryBackend::new());
+        lower.set_value(app, "Name", REG_SZ, regfile::string_data("read"));
+        let mut conflict = ConflictPolicies {
+            backups: backups.clone(),
+            ..Default::default()
+        };
+        conflict.add(policy).unwrap();
+        let options = RegFsOptions {
+            root: root.clone(),
+            backends: vec![BackendSpec::Overlay],
+            conflict,
+            ..Default::default()
+        };
+        let regfs = RegFs::with_backend(&options, lower.clone());
+
+        // projected, then changed by someone else before the edit is saved
+        regfs.record_content_id(&name, hash::fnv1a(&regfile::string_data("read")));
+        lower.set_value(app, "Name", REG_SZ, concurrent.clone());
+        fs::write(root.join(&name), &saved).unwrap();
+        regfs.record_change(Change::Modified(&name));
+
+        let value = regfs.overlay().unwrap().read_typed_value(&name).unwrap().1;
+        (value, regfs.metrics_snapshot().denied_operations)
+    };
+
+    assert_eq!(write_back("fail"), (concurrent.clone(), 1));
+    assert!(!backups.exists());
+    assert_eq!(write_back("overwrite"), (saved.clone(), 0));
+    assert!(!backups.exists());
+
+    assert_eq!(write_back("backup"), (saved.clone(), 0));
+    let backup = fs::read_dir(&backups)
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .path();
+    let backup = regfile::read(&backup).unwrap();
+    assert_eq!(backup.keys[0].path, app);
+    assert_eq!(
+        backup.keys[0].values,
+        [RegFileValue {
+            name: "Name".into(),
+            data: Some((REG_SZ, concurrent)),
+        }]
+    );
+
+    // a prefix overrides the mount's policy
+    assert_eq!(
+        write_back("HKEY_CURRENT_USER\\Software=fail"),
+        (regfile::string_data("concurrent"), 1)
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
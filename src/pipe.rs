@@ -0,0 +1,486 @@
+use log::{info, warn};
+use prjfs::conv::WStrExt;
+use prjfs::guid::guid_to_bytes;
+use serde_json::json;
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    mem,
+    os::windows::io::{AsRawHandle, FromRawHandle},
+    path::{Path, PathBuf},
+    ptr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use winapi::{
+    shared::{
+        guiddef::GUID,
+        sddl::{ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1},
+        winerror::{ERROR_PIPE_BUSY, ERROR_PIPE_CONNECTED, S_OK},
+    },
+    um::{
+        combaseapi::CoCreateGuid,
+        errhandlingapi::GetLastError,
+        fileapi::FlushFileBuffers,
+        handleapi::INVALID_HANDLE_VALUE,
+        ioapiset::CancelSynchronousIo,
+        minwinbase::SECURITY_ATTRIBUTES,
+        namedpipeapi::{ConnectNamedPipe, DisconnectNamedPipe},
+        winbase::{
+            CreateNamedPipeW, LocalFree, FILE_FLAG_FIRST_PIPE_INSTANCE, PIPE_ACCESS_DUPLEX,
+            PIPE_READMODE_BYTE, PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_BYTE,
+            PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+        },
+        winnt::PSECURITY_DESCRIPTOR,
+    },
+};
+
+use crate::control::{self, ControlCommand};
+use crate::regfs::RegFs;
+
+// full control for the owner (whoever started the provider) and administrators, nobody else
+const SDDL: &str = "D:P(A;;GA;;;OW)(A;;GA;;;BA)";
+const BUFFER_SIZE: u32 = 4096;
+// how long dropping the server waits for the clients' threads to go
+const STOP_TIMEOUT: Duration = Duration::from_secs(2);
+
+// \\.\pipe\regfs-<guid>, different for every run
+pub fn instance_name() -> String {
+    let mut guid = GUID::default();
+    if unsafe { CoCreateGuid(&mut guid) } != S_OK {
+        // only has to be unique on this machine
+        guid.Data1 = std::process::id();
+    }
+
+    let guid = control::guid_string(&guid_to_bytes(&guid));
+    format!(
+        "\\\\.\\pipe\\regfs-{}",
+        guid.trim_matches(|c| c == '{' || c == '}')
+    )
+}
+
+pub // an NTFS stream on the root directory holds the pipe name of the provider mounted there,
+// without showing up in the mount
+const INSTANCE_STREAM: &str = "regfs.pipe";
+
+fn instance_stream(root: &Path) -> io::Result<PathBuf> {
+    let mut stream = fs::canonicalize(root)?.into_os_string();
+    stream.push(":");
+    stream.push(INSTANCE_STREAM);
+    Ok(stream.into())
+}
+
+pub fn persist_instance(root: &Path, name: &str) -> io::Result<()> {
+    fs::write(instance_stream(root)?, name)
+}
+
+pub fn forget_instance(root: &Path) {
+    if let Ok(stream) = instance_stream(root) {
+        let _ = fs::remove_file(stream);
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("no provider is running on {0}")]
+    NotRunning(String),
+    #[error("the provider on [{0}] isn't answering")]
+    Unresponsive(String),
+    #[error("{0}")]
+    Failed(String),
+}
+
+impl ClientError {
+    // for scripts to tell the cases apart
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ClientError::Failed(_) => 1,
+            ClientError::NotRunning(_) => 2,
+            ClientError::Unresponsive(_) => 3,
+        }
+    }
+}
+
+pub fn find_instance(root: &Path) -> Result<String, ClientError> {
+    let not_running = || {
+        ClientError::NotRunning(format!(
+            "{:?} (or it was started without --control-pipe)",
+            root
+        ))
+    };
+    let stream = instance_stream(root).map_err(|_| not_running())?;
+    match fs::read_to_string(stream) {
+        Ok(name) => Ok(name.trim().to_string()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(not_running()),
+        Err(e) => Err(ClientError::Failed(format!("{:?}: {}", root, e))),
+    }
+}
+
+// sends one request and waits for its response, for at most `timeout` overall
+pub fn request(
+    name: &str,
+    request: &serde_json::Value,
+    timeout: Duration,
+) -> Result<serde_json::Value, ClientError> {
+    let deadline = Instant::now() + timeout;
+    // busy until the server has the next instance up
+    let client = loop {
+        match OpenOptions::new().read(true).write(true).open(name) {
+            Ok(client) => break client,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(ClientError::NotRunning(format!("[{}]", name)))
+            }
+            Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY as i32) => {
+                if Instant::now() >= deadline {
+                    return Err(ClientError::Unresponsive(name.to_string()));
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+            Err(e) => return Err(ClientError::Failed(format!("[{}]: {}", name, e))),
+        }
+    };
+
+    let mut writer = &client;
+    writeln!(writer, "{}", request)
+        .map_err(|e| ClientError::Failed(format!("[{}]: {}", name, e)))?;
+
+    // a read on the pipe can't time out by itself
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut line = String::new();
+        let _ = sender.send(BufReader::new(&client).read_line(&mut line).map(|_| line));
+    });
+    let line = match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        Ok(Ok(line)) if !line.trim().is_empty() => line,
+        Ok(Ok(_)) => return Err(ClientError::Failed(format!("[{}] hung up", name))),
+        Ok(Err(e)) => return Err(ClientError::Failed(format!("[{}]: {}", name, e))),
+        Err(_) => return Err(ClientError::Unresponsive(name.to_string())),
+    };
+
+    let response: serde_json::Value = serde_json::from_str(&line)
+        .map_err(|e| ClientError::Failed(format!("invalid response: {}", e)))?;
+    match response["ok"].as_bool() {
+        Some(true) => Ok(response),
+        _ => Err(ClientError::Failed(
+            response["error"]
+                .as_str()
+                .unwrap_or("request failed")
+                .to_string(),
+        )),
+    }
+}
+
+pub struct SecurityDescriptor(pub PSECURITY_DESCRIPTOR);
+
+// only ever read, and freed once
+unsafe impl Send for SecurityDescriptor {}
+
+impl SecurityDescriptor {
+    pub fn from_sddl(sddl: &str) -> io::Result<Self> {
+        let mut descriptor = ptr::null_mut();
+        let converted = unsafe {
+            ConvertStringSecurityDescriptorToSecurityDescriptorW(
+                OsString::from(sddl).to_wstr().as_ptr(),
+                SDDL_REVISION_1 as u32,
+                &mut descriptor,
+                ptr::null_mut(),
+            )
+        };
+
+        if converted == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(SecurityDescriptor(descriptor))
+    }
+}
+
+impl Drop for SecurityDescriptor {
+    fn drop(&mut self) {
+        unsafe {
+            LocalFree(self.0);
+        }
+    }
+}
+
+fn create_instance(name: &str, security: &SecurityDescriptor, first: bool) -> io::Result<File> {
+    let mut attributes = SECURITY_ATTRIBUTES {
+        nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+        lpSecurityDescriptor: security.0,
+        bInheritHandle: 0,
+    };
+
+    let handle = unsafe {
+        CreateNamedPipeW(
+            OsString::from(name).to_wstr().as_ptr(),
+            // the first instance also makes sure nobody else squatted on the name
+            PIPE_ACCESS_DUPLEX
+                | if first {
+                    FILE_FLAG_FIRST_PIPE_INSTANCE
+                } else {
+                    0
+                },
+            PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+            PIPE_UNLIMITED_INSTANCES,
+            BUFFER_SIZE,
+            BUFFER_SIZE,
+            0,
+            &mut attributes,
+        )
+    };
+
+    if handle == INVALID_HANDLE_VALUE {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_handle(handle as _) })
+}
+
+// blocks until a client opens the pipe
+fn connect(pipe: &File) -> io::Result<()> {
+    let connected = unsafe { ConnectNamedPipe(pipe.as_raw_handle() as _, ptr::null_mut()) };
+    // the client can beat us to it between CreateNamedPipe and ConnectNamedPipe
+    if connected == 0 && unsafe { GetLastError() } != ERROR_PIPE_CONNECTED {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// the response to one line, and whether the client asked the provider to quit
+pub fn respond(regfs: &RegFs, line: &str) -> (serde_json::Value, bool) {
+    match ControlCommand::from_json(line) {
+        Ok(command) => {
+            let stats = command == ControlCommand::Stats;
+            let status = command == ControlCommand::Status;
+            let dump = command == ControlCommand::Dump;
+            let quit = command == ControlCommand::Quit;
+            let mut response = json!({ "ok": true, "output": regfs.execute(command) });
+            if stats {
+                response["stats"] = regfs.metrics_snapshot().to_json();
+            }
+            if status {
+                response["status"] = regfs.status();
+            }
+            if dump {
+                response["dump"] = regfs.dump();
+            }
+            (response, quit)
+        }
+        Err(e) => (json!({ "ok": false, "error": e.to_string() }), false),
+    }
+}
+
+// one client's session, on its own thread; a read cancelled on shutdown ends it
+fn serve(regfs: &RegFs, pipe: &File, stop: &AtomicBool) {
+    let mut writer = pipe;
+    for line in BufReader::new(pipe).lines().map_while(Result::ok) {
+        if stop.load(Ordering::Acquire) {
+            break;
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let (response, quit) = respond(regfs, &line);
+        if writeln!(writer, "{}", response).is_err() || quit {
+            break;
+        }
+    }
+
+    unsafe {
+        FlushFileBuffers(pipe.as_raw_handle() as _);
+        DisconnectNamedPipe(pipe.as_raw_handle() as _);
+    }
+}
+
+// newline-delimited JSON requests, every client on its own thread, until dropped
+pub struct PipeServer {
+    name: String,
+    stop: Arc<AtomicBool>,
+    sessions: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl PipeServer {
+    pub fn start(regfs: RegFs, name: String) -> io::Result<PipeServer> {
+        let security = SecurityDescriptor::from_sddl(SDDL)?;
+        // created up front so a name that's taken fails the start, not the thread
+        let mut next = Some(create_instance(&name, &security, true)?);
+        let stop = Arc::new(AtomicBool::new(false));
+        let sessions: Arc<Mutex<Vec<JoinHandle<()>>>> = Arc::default();
+
+        let thread_name = name.clone();
+        let thread_stop = stop.clone();
+        let thread_sessions = sessions.clone();
+        thread::spawn(move || loop {
+            let pipe = match next.take() {
+                Some(pipe) => pipe,
+                None => match create_instance(&thread_name, &security, false) {
+                    Ok(pipe) => pipe,
+                    Err(e) => {
+                        warn!(target: "control", "unable to create [{}]: {}", thread_name, e);
+                        return;
+                    }
+                },
+            };
+
+            let connected = connect(&pipe);
+            if thread_stop.load(Ordering::Acquire) {
+                return;
+            }
+            if let Err(e) = connected {
+                warn!(target: "control", "unable to accept a client: {}", e);
+                continue;
+            }
+
+            let mut sessions = thread_sessions.lock().unwrap();
+            // checked again under the lock, so a session never starts after the drop took them
+            if thread_stop.load(Ordering::Acquire) {
+                return;
+            }
+            sessions.retain(|session| !session.is_finished());
+            let regfs = regfs.clone();
+            let stop = thread_stop.clone();
+            sessions.push(thread::spawn(move || serve(&regfs, &pipe, &stop)));
+        });
+
+        info!(target: "control", "control pipe listening on [{}]", name);
+        Ok(PipeServer {
+            name,
+            stop,
+            sessions,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Drop for PipeServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        // wakes the server up if it's waiting for a client
+        let _ = OpenOptions::new().read(true).write(true).open(&self.name);
+
+        // and cancels the read each connected client's thread is waiting in; one that's busy
+        // with a request only gets to its next read after, so it's cancelled until it's gone
+        let sessions = mem::take(&mut *self.sessions.lock().unwrap());
+        let deadline = Instant::now() + STOP_TIMEOUT;
+        for session in sessions {
+            while !session.is_finished() && Instant::now() < deadline {
+                unsafe {
+                    CancelSynchronousIo(session.as_raw_handle() as _);
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn open_client(name: &str) -> File {
+    // the next instance is created right after the previous client connects
+    for _ in 0..50 {
+        if let Ok(client) = OpenOptions::new().read(true).write(true).open(name) {
+            return client;
+        }
+        thread::sleep(std::time::Duration::from_millis(20));
+    }
+    panic!("unable to connect to [{}]", name);
+}
+
+#[test]
+fn test_stats_round_trip() {
+    use crate::{memory::MemoryBackend, options::RegFsOptions};
+
+    let regfs = RegFs::with_backend(&RegFsOptions::default(), Arc::new(MemoryBackend::new()));
+    let server = PipeServer::start(regfs, instance_name()).unwrap();
+    assert!(server.name().starts_with("\\\\.\\pipe\\regfs-"));
+
+    // one client after the other, each with its own session
+    for _ in 0..2 {
+        let mut client = open_client(server.name());
+        writeln!(client, "{}", r#"{"cmd":"stats"}"#).unwrap();
+        writeln!(client, "{}", r#"{"cmd":"frobnicate"}"#).unwrap();
+
+        let mut lines = BufReader::new(&client).lines();
+        let response: serde_json::Value =
+            serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+        assert_eq!(response["ok"], true);
+        assert_eq!(response["stats"]["partial_enumerations"], 0);
+
+        let response: serde_json::Value =
+            serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+        assert_eq!(response["ok"], false);
+        assert_eq!(response["error"], "unknown control verb [frobnicate]");
+    }
+}
+
+#[test]
+fn test_status_client() {
+    use crate::{memory::MemoryBackend, options::RegFsOptions};
+
+    let timeout = Duration::from_secs(2);
+    let status = json!({ "cmd": "status" });
+    let regfs = RegFs::with_backend(&RegFsOptions::default(), Arc::new(MemoryBackend::new()));
+    let server = PipeServer::start(regfs, instance_name()).unwrap();
+
+    let root = std::env::temp_dir().join(format!("regfs-status-{}", std::process::id()));
+    fs::create_dir_all(&root).unwrap();
+    assert_eq!(find_instance(&root).unwrap_err().exit_code(), 2);
+    persist_instance(&root, server.name()).unwrap();
+    // the stream doesn't show up as a file in the root
+    assert_eq!(fs::read_dir(&root).unwrap().count(), 0);
+
+    let name = find_instance(&root).unwrap();
+    let response = request(&name, &status, timeout).unwrap();
+    assert_eq!(response["status"]["backend"], "live");
+    assert_eq!(response["status"]["readonly"], true);
+    assert_eq!(response["status"]["stats"]["dropped_events"], 0);
+
+    // gone, or there but never answering
+    drop(server);
+    let gone = request(&instance_name(), &status, timeout).unwrap_err();
+    assert_eq!(gone.exit_code(), 2);
+    let security = SecurityDescriptor::from_sddl(SDDL).unwrap();
+    let silent = instance_name();
+    let _pipe = create_instance(&silent, &security, true).unwrap();
+    let hung = request(&silent, &status, Duration::from_millis(200)).unwrap_err();
+    assert_eq!(hung.exit_code(), 3);
+
+    forget_instance(&root);
+    assert_eq!(find_instance(&root).unwrap_err().exit_code(), 2);
+    fs::remove_dir(&root).unwrap();
+}
+
+#[test]
+fn test_idle_client() {
+    use crate::{memory::MemoryBackend, options::RegFsOptions};
+
+    let regfs = RegFs::with_backend(&RegFsOptions::default(), Arc::new(MemoryBackend::new()));
+    let server = PipeServer::start(regfs, instance_name()).unwrap();
+
+    // connected and never sending anything doesn't keep the next client waiting
+    let idle = open_client(server.name());
+    let response = request(
+        server.name(),
+        &json!({ "cmd": "stats" }),
+        Duration::from_secs(2),
+    )
+    .unwrap();
+    assert_eq!(response["stats"]["partial_enumerations"], 0);
+
+    // nor the provider from shutting down, and the idle client is hung up on
+    let started = Instant::now();
+    drop(server);
+    assert!(started.elapsed() < STOP_TIMEOUT);
+    let mut line = String::new();
+    assert!(matches!(
+        BufReader::new(&idle).read_line(&mut line),
+        Ok(0) | Err(_)
+    ));
+}
@@ -0,0 +1,405 @@
+use std::{
+    borrow::Cow,
+    ffi::{OsStr, OsString},
+    fmt,
+    path::Path,
+};
+
+use crate::bookmarks::Bookmark;
+use crate::regop::{paths::RegPath, RootHive};
+use crate::synthetic;
+use crate::transform::Transformers;
+#[cfg(test)]
+use crate::transform::ValueTransformer;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MutationKind {
+    Rename,
+    Delete,
+    ConvertToFull,
+    // a hard link to a file of the mount; `destination` is the new link
+    Hardlink,
+    // a key copied by copy-key; `path` is the copy
+    Copy,
+    // a saved file written back to the registry, or a key created there; `is_directory` for
+    // the key
+    WriteBack,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MutationRequest<'a> {
+    pub kind: MutationKind,
+    pub path: &'a Path,
+    // only for renames
+    pub destination: Option<&'a Path>,
+    // image file name of the process that triggered the callback, may be empty
+    pub process: &'a OsStr,
+    pub is_directory: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    Allow,
+    Deny(Cow<'static, str>),
+}
+
+impl Decision {
+    pub fn is_deny(&self) -> bool {
+        matches!(self, Decision::Deny(_))
+    }
+}
+
+// "should this mutation be allowed?", asked before every rename, delete or write
+pub trait MutationPolicy: Send + Sync {
+    fn decide(&self, request: &MutationRequest) -> Decision;
+}
+
+impl fmt::Debug for dyn MutationPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MutationPolicy")
+    }
+}
+
+// the first policy that denies wins
+pub struct Chain<'a>(pub Vec<&'a dyn MutationPolicy>);
+
+impl MutationPolicy for Chain<'_> {
+    fn decide(&self, request: &MutationRequest) -> Decision {
+        self.0
+            .iter()
+            .map(|policy| policy.decide(request))
+            .find(Decision::is_deny)
+            .unwrap_or(Decision::Allow)
+    }
+}
+
+// --readonly: the files can still be written, but nothing is renamed or deleted
+pub struct ReadOnly(pub bool);
+
+impl MutationPolicy for ReadOnly {
+    fn decide(&self, request: &MutationRequest) -> Decision {
+        match request.kind {
+            MutationKind::ConvertToFull => Decision::Allow,
+            _ if self.0 => Decision::Deny("read-only".into()),
+            _ => Decision::Allow,
+        }
+    }
+}
+
+// --snapshot and other backends that can't be written: nothing changes, files included
+pub struct Frozen(pub bool);
+
+impl MutationPolicy for Frozen {
+    fn decide(&self, _request: &MutationRequest) -> Decision {
+        match self.0 {
+            true => Decision::Deny("read-only backend".into()),
+            false => Decision::Allow,
+        }
+    }
+}
+
+// `suspend`: nothing changes until `resume`
+pub struct Suspended(pub bool);
+
+impl MutationPolicy for Suspended {
+    fn decide(&self, _request: &MutationRequest) -> Decision {
+        match self.0 {
+            true => Decision::Deny("suspended".into()),
+            false => Decision::Allow,
+        }
+    }
+}
+
+// the hive directories at the root can't go away or move, whatever the mode
+pub struct ProtectedHives<'a>(pub &'a [RootHive]);
+
+impl MutationPolicy for ProtectedHives<'_> {
+    fn decide(&self, request: &MutationRequest) -> Decision {
+        if !matches!(request.kind, MutationKind::Rename | MutationKind::Delete) {
+            return Decision::Allow;
+        }
+
+        let path = RegPath::parse(request.path);
+        let protected = path.is_hive()
+            && self
+                .0
+                .iter()
+                .any(|hive| path.hive.as_deref() == Some(OsStr::new(hive.name())));
+
+        match protected {
+            true => Decision::Deny("protected".into()),
+            false => Decision::Allow,
+        }
+    }
+}
+
+// a hard link is a second name for a value file that write-back can't tell apart from the
+// first, so there are none unless --allow-hardlinks
+pub struct Hardlinks(pub bool);
+
+impl MutationPolicy for Hardlinks {
+    fn decide(&self, request: &MutationRequest) -> Decision {
+        match (request.kind, self.0) {
+            (MutationKind::Hardlink, false) => Decision::Deny("hard link".into()),
+            _ => Decision::Allow,
+        }
+    }
+}
+
+// --user-classes: the root it adds only shows other keys merged, there's no one place a
+// change to it would go
+pub struct MergedRoot(pub Option<String>);
+
+impl MutationPolicy for MergedRoot {
+    fn decide(&self, request: &MutationRequest) -> Decision {
+        let root = match &self.0 {
+            Some(root) => root,
+            None => return Decision::Allow,
+        };
+        let under = |path: &Path| {
+            RegPath::parse(path).hive.map_or(false, |hive| {
+                hive.to_string_lossy().eq_ignore_ascii_case(root)
+            })
+        };
+        match under(request.path) || request.destination.map_or(false, under) {
+            true => Decision::Deny("merged view".into()),
+            false => Decision::Allow,
+        }
+    }
+}
+
+// --bookmark: deleting or moving one would do that to its key, renaming onto one would
+// replace it
+pub struct BookmarkRoots<'a>(pub &'a [Bookmark]);
+
+impl MutationPolicy for BookmarkRoots<'_> {
+    fn decide(&self, request: &MutationRequest) -> Decision {
+        if !matches!(request.kind, MutationKind::Rename | MutationKind::Delete) {
+            return Decision::Allow;
+        }
+
+        let is_bookmark = |path: &Path| {
+            let path = RegPath::parse(path);
+            path.is_hive()
+                && self.0.iter().any(|bookmark| {
+                    path.hive.as_deref().map_or(false, |name| {
+                        name.to_string_lossy().eq_ignore_ascii_case(&bookmark.name)
+                    })
+                })
+        };
+        match is_bookmark(request.path) || request.destination.map_or(false, is_bookmark) {
+            true => Decision::Deny("bookmark".into()),
+            false => Decision::Allow,
+        }
+    }
+}
+
+// the enabled ones of --search-dir and --recent-dir: what's in them only names registry
+// paths, there's nothing behind it to change
+pub struct GeneratedDirs(pub Vec<&'static str>);
+
+impl MutationPolicy for GeneratedDirs {
+    fn decide(&self, request: &MutationRequest) -> Decision {
+        let under = |path: &Path| {
+            RegPath::parse(path).hive.and_then(|first| {
+                self.0
+                    .iter()
+                    .find(|dir| first.to_string_lossy().eq_ignore_ascii_case(dir))
+                    .copied()
+            })
+        };
+        match under(request.path).or_else(|| request.destination.and_then(under)) {
+            Some(dir) => Decision::Deny(format!("generated ({})", dir).into()),
+            None => Decision::Allow,
+        }
+    }
+}
+
+// a transformed value can't be written unless its transformer can be undone
+pub struct Transformed<'a>(pub &'a Transformers);
+
+impl MutationPolicy for Transformed<'_> {
+    fn decide(&self, request: &MutationRequest) -> Decision {
+        if !matches!(
+            request.kind,
+            MutationKind::ConvertToFull | MutationKind::WriteBack
+        ) {
+            return Decision::Allow;
+        }
+
+        match self.0.find(request.path) {
+            Some(transformer) if !transformer.invertible() => {
+                Decision::Deny("transformed value".into())
+            }
+            _ => Decision::Allow,
+        }
+    }
+}
+
+// --allow-process: only these processes (by image file name) may change anything
+pub struct ProcessAllowlist<'a>(pub &'a [OsString]);
+
+impl MutationPolicy for ProcessAllowlist<'_> {
+    fn decide(&self, request: &MutationRequest) -> Decision {
+        let image = Path::new(request.process)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_lowercase());
+        let allowed = self
+            .0
+            .iter()
+            .any(|process| Some(process.to_string_lossy().to_lowercase()) == image);
+
+        match allowed {
+            true => Decision::Allow,
+            false => Decision::Deny("process not allowed".into()),
+        }
+    }
+}
+
+#[cfg(test)]
+fn request<'a>(kind: MutationKind, path: &'a str, process: &'a str) -> MutationRequest<'a> {
+    MutationRequest {
+        kind,
+        path: Path::new(path),
+        destination: None,
+        process: OsStr::new(process),
+        is_directory: false,
+    }
+}
+
+#[test]
+fn test_built_in_policies() {
+    let delete = request(MutationKind::Delete, "HKEY_USERS", "");
+    let write = request(MutationKind::ConvertToFull, "HKEY_USERS\\.DEFAULT\\x", "");
+
+    let save = request(MutationKind::WriteBack, "HKEY_USERS\\.DEFAULT\\x", "");
+    assert!(ReadOnly(true).decide(&delete).is_deny());
+    assert!(!ReadOnly(true).decide(&write).is_deny());
+    assert!(ReadOnly(true).decide(&save).is_deny());
+    assert!(!ReadOnly(false).decide(&delete).is_deny());
+    assert!(Frozen(true).decide(&write).is_deny());
+    assert!(!Frozen(false).decide(&delete).is_deny());
+    assert!(Suspended(true).decide(&write).is_deny());
+    assert!(!Suspended(false).decide(&delete).is_deny());
+
+    let hives = [RootHive::Users];
+    assert!(ProtectedHives(&hives).decide(&delete).is_deny());
+    assert!(!ProtectedHives(&hives).decide(&write).is_deny());
+    assert!(!ProtectedHives(&[RootHive::CurrentUser])
+        .decide(&delete)
+        .is_deny());
+
+    let link = request(MutationKind::Hardlink, "HKEY_USERS\\.DEFAULT\\x", "");
+    assert!(Hardlinks(false).decide(&link).is_deny());
+    assert!(!Hardlinks(true).decide(&link).is_deny());
+    assert!(!Hardlinks(false).decide(&delete).is_deny());
+    assert!(ReadOnly(true).decide(&link).is_deny());
+
+    let merged = MergedRoot(Some("HKEY_CLASSES_ROOT (user S-1-5-21-1001)".into()));
+    let in_merged = request(
+        MutationKind::ConvertToFull,
+        "HKEY_CLASSES_ROOT (USER S-1-5-21-1001)\\.txt\\x",
+        "",
+    );
+    assert!(merged.decide(&in_merged).is_deny());
+    assert!(!merged.decide(&write).is_deny());
+    assert!(!MergedRoot(None).decide(&in_merged).is_deny());
+
+    let hit = request(MutationKind::Delete, "_SEARCH\\Adobe", "");
+    let generated = GeneratedDirs(vec![synthetic::SEARCH_DIR, synthetic::RECENT_DIR]);
+    assert!(generated.decide(&hit).is_deny());
+    assert!(!generated.decide(&delete).is_deny());
+    assert!(!GeneratedDirs(vec![synthetic::RECENT_DIR])
+        .decide(&hit)
+        .is_deny());
+
+    let bookmarks: Vec<Bookmark> = vec!["WinVer=HKLM\\SOFTWARE".parse().unwrap()];
+    let bookmarked = BookmarkRoots(&bookmarks);
+    assert!(bookmarked
+        .decide(&request(MutationKind::Delete, "winver", ""))
+        .is_deny());
+    assert!(!bookmarked
+        .decide(&request(MutationKind::Delete, "WinVer\\x", ""))
+        .is_deny());
+    let onto = MutationRequest {
+        destination: Some(Path::new("WinVer")),
+        ..request(MutationKind::Rename, "HKEY_USERS\\x", "")
+    };
+    assert!(bookmarked.decide(&onto).is_deny());
+
+    let allowed = ["regedit.exe".into()];
+    let allowlist = ProcessAllowlist(&allowed);
+    let regedit = request(
+        MutationKind::Delete,
+        "HKEY_USERS\\x",
+        "C:\\Windows\\RegEdit.exe",
+    );
+    let cmd = request(
+        MutationKind::Delete,
+        "HKEY_USERS\\x",
+        "C:\\Windows\\cmd.exe",
+    );
+    assert_eq!(allowlist.decide(&regedit), Decision::Allow);
+    assert!(allowlist.decide(&cmd).is_deny());
+
+    struct Reverse;
+
+    impl ValueTransformer for Reverse {
+        fn transform<'a>(&self, _: &Path, _: u32, raw: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+            Some(raw.iter().rev().copied().collect())
+        }
+    }
+
+    let mut transformers = Transformers::default();
+    transformers.add("HKEY_USERS\\.DEFAULT\\*", std::sync::Arc::new(Reverse));
+    assert!(Transformed(&transformers).decide(&write).is_deny());
+    assert!(Transformed(&transformers).decide(&save).is_deny());
+    assert!(!Transformed(&transformers).decide(&delete).is_deny());
+}
+
+#[test]
+fn test_chain_with_custom_policy() {
+    struct DenyKey(&'static str);
+
+    impl MutationPolicy for DenyKey {
+        fn decide(&self, request: &MutationRequest) -> Decision {
+            let touches = |path: &Path| {
+                path.to_string_lossy()
+                    .to_lowercase()
+                    .starts_with(&self.0.to_lowercase())
+            };
+            match touches(request.path) || request.destination.map_or(false, touches) {
+                true => Decision::Deny(format!("{} is off limits", self.0).into()),
+                false => Decision::Allow,
+            }
+        }
+    }
+
+    let readonly = ReadOnly(false);
+    let protected = ProtectedHives(&RootHive::ALL);
+    let deny_key = DenyKey("HKEY_CURRENT_USER\\Secret");
+    let policies: Vec<&dyn MutationPolicy> = vec![&readonly, &protected, &deny_key];
+    let chain = Chain(policies);
+
+    let rename = |from, to| MutationRequest {
+        destination: Some(Path::new(to)),
+        ..request(MutationKind::Rename, from, "")
+    };
+
+    assert_eq!(
+        chain.decide(&rename("HKEY_CURRENT_USER\\a", "HKEY_CURRENT_USER\\b")),
+        Decision::Allow
+    );
+    assert_eq!(
+        chain.decide(&rename(
+            "HKEY_CURRENT_USER\\a",
+            "HKEY_CURRENT_USER\\Secret\\a"
+        )),
+        Decision::Deny("HKEY_CURRENT_USER\\Secret is off limits".into())
+    );
+    // the built-in policies come first
+    assert_eq!(
+        chain.decide(&rename("HKEY_CURRENT_USER", "HKEY_CURRENT_USER\\Secret")),
+        Decision::Deny("protected".into())
+    );
+}
@@ -0,0 +1,310 @@
+use log::error;
+use std::{
+    collections::VecDeque,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Condvar, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+pub const DEFAULT_THREADS: usize = 4;
+// callbacks give up well before this many jobs pile up anyway
+const CAPACITY: usize = 1024;
+
+type Work = Box<dyn FnOnce() + Send>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoolError {
+    ShuttingDown,
+    Full,
+    Timeout,
+    Panicked,
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolError::ShuttingDown => write!(f, "registry pool is shutting down"),
+            PoolError::Full => write!(f, "registry pool queue is full"),
+            PoolError::Timeout => write!(f, "registry operation timed out"),
+            PoolError::Panicked => write!(f, "registry operation panicked"),
+        }
+    }
+}
+
+impl std::error::Error for PoolError {}
+
+#[derive(Default)]
+struct Queue {
+    jobs: VecDeque<Work>,
+    shutdown: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+    panics: AtomicU64,
+}
+
+// keeps slow registry calls off the ProjFS callback threads; with zero threads
+// every job just runs inline on the caller
+pub struct RegistryPool {
+    shared: Option<Arc<Shared>>,
+}
+
+impl RegistryPool {
+    pub fn new(threads: usize) -> Self {
+        if threads == 0 {
+            return RegistryPool { shared: None };
+        }
+
+        let shared = Arc::new(Shared::default());
+        for _ in 0..threads {
+            let shared = shared.clone();
+            thread::spawn(move || worker(&shared));
+        }
+
+        RegistryPool {
+            shared: Some(shared),
+        }
+    }
+
+    // the job keeps running after a timeout, callers must be able to walk away from it
+    pub fn run<T, F>(&self, timeout: Duration, f: F) -> Result<T, PoolError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let shared = match &self.shared {
+            Some(shared) => shared,
+            None => return Ok(f()),
+        };
+
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let counted = shared.clone();
+        // a panic would take the worker down with it; the caller hears of it instead of
+        // waiting out the timeout
+        queue(
+            shared,
+            Box::new(move || {
+                let result = panic::catch_unwind(AssertUnwindSafe(f)).map_err(|_| {
+                    counted.panics.fetch_add(1, Ordering::Relaxed);
+                    error!(target: "pool", "a registry job panicked, the worker carries on");
+                    PoolError::Panicked
+                });
+                let _ = sender.send(result);
+            }),
+        )?;
+
+        receiver
+            .recv_timeout(timeout)
+            .map_err(|e| match e {
+                mpsc::RecvTimeoutError::Timeout => PoolError::Timeout,
+                // dropped unrun by a shutdown
+                mpsc::RecvTimeoutError::Disconnected => PoolError::ShuttingDown,
+            })
+            .and_then(|result| result)
+    }
+
+    // the same for a job nobody waits for; with zero threads it runs inline all the same
+    pub fn submit<F: FnOnce() + Send + 'static>(&self, f: F) -> Result<(), PoolError> {
+        let shared = match &self.shared {
+            Some(shared) => shared,
+            None => {
+                f();
+                return Ok(());
+            }
+        };
+
+        let counted = shared.clone();
+        queue(
+            shared,
+            Box::new(move || {
+                if panic::catch_unwind(AssertUnwindSafe(f)).is_err() {
+                    counted.panics.fetch_add(1, Ordering::Relaxed);
+                    error!(target: "pool", "a registry job panicked, the worker carries on");
+                }
+            }),
+        )
+    }
+
+    pub fn queued(&self) -> usize {
+        match &self.shared {
+            Some(shared) => shared
+                .queue
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .jobs
+                .len(),
+            None => 0,
+        }
+    }
+
+    // jobs that panicked since the pool started; their worker went on to the next one
+    pub fn panics(&self) -> u64 {
+        self.shared
+            .as_ref()
+            .map_or(0, |shared| shared.panics.load(Ordering::Relaxed))
+    }
+
+    pub fn shutdown(&self) {
+        if let Some(shared) = &self.shared {
+            let mut queue = shared.queue.lock().unwrap_or_else(|e| e.into_inner());
+            queue.shutdown = true;
+            queue.jobs.clear();
+            shared.ready.notify_all();
+        }
+    }
+}
+
+impl Drop for RegistryPool {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn queue(shared: &Shared, work: Work) -> Result<(), PoolError> {
+    let mut queue = shared.queue.lock().unwrap_or_else(|e| e.into_inner());
+    if queue.shutdown {
+        return Err(PoolError::ShuttingDown);
+    }
+    if queue.jobs.len() >= CAPACITY {
+        return Err(PoolError::Full);
+    }
+    queue.jobs.push_back(work);
+    shared.ready.notify_one();
+    Ok(())
+}
+
+fn worker(shared: &Shared) {
+    loop {
+        let job = {
+            let mut queue = shared.queue.lock().unwrap_or_else(|e| e.into_inner());
+            loop {
+                if queue.shutdown {
+                    return;
+                }
+                if let Some(job) = queue.jobs.pop_front() {
+                    break job;
+                }
+                queue = shared.ready.wait(queue).unwrap_or_else(|e| e.into_inner());
+            }
+        };
+
+        job();
+    }
+}
+
+#[test]
+fn test_inline_and_shutdown() {
+    let inline = RegistryPool::new(0);
+    let caller = thread::current().id();
+    assert_eq!(
+        inline.run(Duration::from_secs(1), move || thread::current().id()
+            == caller),
+        Ok(true)
+    );
+
+    let pool = RegistryPool::new(2);
+    assert_eq!(pool.run(Duration::from_secs(5), || 40 + 2), Ok(42));
+    assert_eq!(pool.queued(), 0);
+
+    pool.shutdown();
+    assert_eq!(
+        pool.run(Duration::from_secs(5), || 0),
+        Err(PoolError::ShuttingDown)
+    );
+}
+
+#[test]
+fn test_panicking_job_keeps_the_worker() {
+    let pool = RegistryPool::new(1);
+
+    let result = pool.run(Duration::from_secs(5), || -> u32 { panic!("registry job") });
+    assert_eq!(result, Err(PoolError::Panicked));
+    assert_eq!(pool.panics(), 1);
+    // the only worker is still there for the next one
+    assert_eq!(pool.run(Duration::from_secs(5), || 40 + 2), Ok(42));
+}
+
+#[test]
+fn test_submit() {
+    let pool = RegistryPool::new(1);
+    let (done, wait) = mpsc::channel();
+
+    pool.submit(|| panic!("registry job")).unwrap();
+    pool.submit(move || done.send(40 + 2).unwrap()).unwrap();
+    assert_eq!(wait.recv_timeout(Duration::from_secs(5)), Ok(42));
+    assert_eq!(pool.panics(), 1);
+
+    pool.shutdown();
+    assert_eq!(pool.submit(|| {}), Err(PoolError::ShuttingDown));
+}
+
+#[test]
+fn test_timeout_leaves_job_running() {
+    let pool = RegistryPool::new(1);
+    let (started, wait) = mpsc::channel();
+
+    let result = pool.run(Duration::from_millis(10), move || {
+        thread::sleep(Duration::from_millis(100));
+        started.send(()).unwrap();
+    });
+    assert_eq!(result, Err(PoolError::Timeout));
+    wait.recv().unwrap();
+}
+
+#[test]
+fn test_callback_latency_under_load() {
+    use std::time::Instant;
+
+    const CALLBACK_THREADS: usize = 8;
+    const CALLS: usize = 20;
+    const SLOW: Duration = Duration::from_millis(50);
+    const TIMEOUT: Duration = Duration::from_millis(10);
+
+    // every fourth registry call is a slow HKCR-style walk
+    fn p99(pool: Arc<RegistryPool>) -> Duration {
+        let callers: Vec<_> = (0..CALLBACK_THREADS)
+            .map(|_| {
+                let pool = pool.clone();
+                thread::spawn(move || {
+                    (0..CALLS)
+                        .map(|call| {
+                            let start = Instant::now();
+                            let _ = pool.run(TIMEOUT, move || {
+                                if call % 4 == 0 {
+                                    thread::sleep(SLOW);
+                                }
+                            });
+                            start.elapsed()
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        let mut latencies: Vec<Duration> = callers
+            .into_iter()
+            .flat_map(|caller| caller.join().unwrap())
+            .collect();
+        latencies.sort_unstable();
+        latencies[latencies.len() * 99 / 100]
+    }
+
+    let inline = p99(Arc::new(RegistryPool::new(0)));
+    let pooled = p99(Arc::new(RegistryPool::new(DEFAULT_THREADS)));
+
+    assert!(inline >= SLOW);
+    assert!(
+        pooled < SLOW,
+        "pooled p99 {:?} vs inline p99 {:?}",
+        pooled,
+        inline
+    );
+}
@@ -0,0 +1,413 @@
+use log::{debug, info, warn};
+use std::{
+    fmt, io, ptr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+};
+use winapi::{
+    shared::{minwindef::FALSE, sddl::ConvertStringSidToSidW, winerror::ERROR_SUCCESS},
+    um::{
+        errhandlingapi::GetLastError,
+        handleapi::CloseHandle,
+        processthreadsapi::{GetCurrentProcess, OpenProcessToken},
+        securitybaseapi::{AdjustTokenPrivileges, GetTokenInformation, SetTokenInformation},
+        winbase::{LocalFree, LookupPrivilegeNameW, LookupPrivilegeValueW},
+        winnt::{
+            TokenIntegrityLevel, TokenPrivileges, HANDLE, LUID, LUID_AND_ATTRIBUTES,
+            SE_GROUP_INTEGRITY, SE_PRIVILEGE_ENABLED, SE_PRIVILEGE_REMOVED, SID_AND_ATTRIBUTES,
+            TOKEN_ADJUST_DEFAULT, TOKEN_ADJUST_PRIVILEGES, TOKEN_MANDATORY_LABEL, TOKEN_PRIVILEGES,
+            TOKEN_QUERY,
+        },
+    },
+};
+
+// what an elevated token has that reading the registry through open handles never needs
+pub const DANGEROUS: [&str; 12] = [
+    "SeAssignPrimaryTokenPrivilege",
+    "SeBackupPrivilege",
+    "SeCreateTokenPrivilege",
+    "SeDebugPrivilege",
+    "SeImpersonatePrivilege",
+    "SeLoadDriverPrivilege",
+    "SeManageVolumePrivilege",
+    "SeRestorePrivilege",
+    "SeSecurityPrivilege",
+    "SeSystemEnvironmentPrivilege",
+    "SeTakeOwnershipPrivilege",
+    "SeTcbPrivilege",
+];
+
+// --backup-semantics opens keys with it, whatever their DACL says
+pub const BACKUP: &str = "SeBackupPrivilege";
+
+// the process token calls, so tests can see what would be asked of it
+pub trait TokenApi {
+    // the names of the privileges the token has, enabled or not
+    fn privileges(&self) -> io::Result<Vec<String>>;
+
+    // one the token has but that's disabled, as most are until asked for
+    fn enable(&self, privilege: &str) -> io::Result<()>;
+
+    // gone for good, AdjustTokenPrivileges can't give it back
+    fn remove(&self, privilege: &str) -> io::Result<()>;
+
+    // from high to medium
+    fn lower_integrity(&self) -> io::Result<()>;
+}
+
+// after --drop-privileges, the ones removed
+static DROPPED: OnceLock<Vec<String>> = OnceLock::new();
+static EXPLAINED: AtomicBool = AtomicBool::new(false);
+
+pub fn dropped() -> Option<&'static [String]> {
+    DROPPED.get().map(Vec::as_slice)
+}
+
+// removes whichever of DANGEROUS the token has, except `keep`; the names of those that were
+// removed, including the ones before a failure
+pub fn drop_privileges(
+    token: &dyn TokenApi,
+    keep: &[&str],
+    lower_integrity: bool,
+) -> (Vec<String>, io::Result<()>) {
+    let mut dropped = Vec::new();
+    let held = match token.privileges() {
+        Ok(held) => held,
+        Err(e) => return (dropped, Err(e)),
+    };
+
+    let unwanted = held.into_iter().filter(|privilege| {
+        let named = |names: &[&str]| {
+            names
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(privilege))
+        };
+        named(&DANGEROUS) && !named(keep)
+    });
+    for privilege in unwanted {
+        if let Err(e) = token.remove(&privilege) {
+            return (dropped, Err(e));
+        }
+        dropped.push(privilege);
+    }
+    if lower_integrity {
+        if let Err(e) = token.lower_integrity() {
+            return (dropped, Err(e));
+        }
+    }
+    (dropped, Ok(()))
+}
+
+// --drop-privileges, on this process once the provider is up
+pub fn drop_process_privileges(keep: &[&str], lower_integrity: bool) -> io::Result<()> {
+    let token = ProcessToken::open()?;
+    let (dropped, result) = drop_privileges(&token, keep, lower_integrity);
+    match dropped.is_empty() {
+        true => info!(target: "privileges", "none of the dangerous privileges were held"),
+        false => info!(target: "privileges", "dropped {}", dropped.join(", ")),
+    }
+    if lower_integrity && result.is_ok() {
+        info!(target: "privileges", "integrity lowered to medium");
+    }
+    let _ = DROPPED.set(dropped);
+    result
+}
+
+// enables BACKUP where the token has it; false when it doesn't
+pub fn enable_backup(token: &dyn TokenApi) -> io::Result<bool> {
+    let held = token
+        .privileges()?
+        .iter()
+        .any(|privilege| privilege.eq_ignore_ascii_case(BACKUP));
+    if held {
+        token.enable(BACKUP)?;
+    }
+    Ok(held)
+}
+
+// --backup-semantics, on this process before the hives are opened; without the privilege
+// keys are opened as usual, and the ones only a backup may read stay out of reach
+pub fn enable_process_backup() -> bool {
+    match ProcessToken::open().and_then(|token| enable_backup(&token)) {
+        Ok(true) => {
+            info!(
+                target: "privileges",
+                "{} enabled, keys are opened with backup semantics and the mount is read-only",
+                BACKUP
+            );
+            true
+        }
+        Ok(false) => {
+            warn!(
+                target: "privileges",
+                "--backup-semantics: {} isn't held (is the provider elevated?), keys are opened \
+                 as usual",
+                BACKUP
+            );
+            false
+        }
+        Err(e) => {
+            warn!(
+                target: "privileges",
+                "--backup-semantics: unable to enable {}, keys are opened as usual: {}",
+                BACKUP,
+                e
+            );
+            false
+        }
+    }
+}
+
+// for an operation refused with access denied after --drop-privileges: says what was
+// dropped, in full the first time
+pub fn explain_denial(what: &dyn fmt::Debug, error: &io::Error) {
+    let dropped = match dropped() {
+        Some(dropped) if error.kind() == io::ErrorKind::PermissionDenied => dropped,
+        _ => return,
+    };
+    match EXPLAINED.swap(true, Ordering::Relaxed) {
+        false => warn!(
+            target: "privileges",
+            "access to [{:?}] denied; the provider was started with --drop-privileges, which \
+             removed [{}], so anything that needs them fails from now on",
+            what,
+            dropped.join(", ")
+        ),
+        true => debug!(
+            target: "privileges",
+            "access to [{:?}] denied, possibly by --drop-privileges",
+            what
+        ),
+    }
+}
+
+struct ProcessToken(HANDLE);
+
+impl Drop for ProcessToken {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.0) };
+    }
+}
+
+impl ProcessToken {
+    fn open() -> io::Result<Self> {
+        let mut token = ptr::null_mut();
+        let rights = TOKEN_QUERY | TOKEN_ADJUST_PRIVILEGES | TOKEN_ADJUST_DEFAULT;
+        match unsafe { OpenProcessToken(GetCurrentProcess(), rights, &mut token) } {
+            0 => Err(io::Error::last_os_error()),
+            _ => Ok(ProcessToken(token)),
+        }
+    }
+
+    fn adjust(&self, privilege: &str, attributes: u32) -> io::Result<()> {
+        let mut luid = LUID::default();
+        let name = wide(privilege);
+        if unsafe { LookupPrivilegeValueW(ptr::null(), name.as_ptr(), &mut luid) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut privileges = TOKEN_PRIVILEGES {
+            PrivilegeCount: 1,
+            Privileges: [LUID_AND_ATTRIBUTES {
+                Luid: luid,
+                Attributes: attributes,
+            }],
+        };
+        let adjusted = unsafe {
+            AdjustTokenPrivileges(
+                self.0,
+                FALSE,
+                &mut privileges,
+                0,
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        // it succeeds without doing anything for a privilege the token doesn't have
+        match (adjusted, unsafe { GetLastError() }) {
+            (0, _) => Err(io::Error::last_os_error()),
+            (_, ERROR_SUCCESS) => Ok(()),
+            (_, error) => Err(io::Error::from_raw_os_error(error as i32)),
+        }
+    }
+}
+
+fn wide(text: &str) -> Vec<u16> {
+    text.encode_utf16().chain(Some(0)).collect()
+}
+
+impl TokenApi for ProcessToken {
+    fn privileges(&self) -> io::Result<Vec<String>> {
+        let mut size = 0;
+        unsafe { GetTokenInformation(self.0, TokenPrivileges, ptr::null_mut(), 0, &mut size) };
+        let mut buffer = vec![0u64; (size as usize + 7) / 8];
+        let queried = unsafe {
+            GetTokenInformation(
+                self.0,
+                TokenPrivileges,
+                buffer.as_mut_ptr() as *mut _,
+                size,
+                &mut size,
+            )
+        };
+        if queried == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let privileges = unsafe { &*(buffer.as_ptr() as *const TOKEN_PRIVILEGES) };
+        let all = unsafe {
+            std::slice::from_raw_parts(
+                privileges.Privileges.as_ptr(),
+                privileges.PrivilegeCount as usize,
+            )
+        };
+        let mut names = Vec::new();
+        for privilege in all {
+            let mut luid = privilege.Luid;
+            let mut name = [0u16; 64];
+            let mut len = name.len() as u32;
+            let found = unsafe {
+                LookupPrivilegeNameW(ptr::null(), &mut luid, name.as_mut_ptr(), &mut len)
+            };
+            if found != 0 {
+                names.push(String::from_utf16_lossy(&name[..len as usize]));
+            }
+        }
+        Ok(names)
+    }
+
+    fn enable(&self, privilege: &str) -> io::Result<()> {
+        self.adjust(privilege, SE_PRIVILEGE_ENABLED)
+    }
+
+    fn remove(&self, privilege: &str) -> io::Result<()> {
+        self.adjust(privilege, SE_PRIVILEGE_REMOVED)
+    }
+
+    fn lower_integrity(&self) -> io::Result<()> {
+        // the medium mandatory level
+        let medium = wide("S-1-16-8192");
+        let mut sid = ptr::null_mut();
+        if unsafe { ConvertStringSidToSidW(medium.as_ptr(), &mut sid) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut label = TOKEN_MANDATORY_LABEL {
+            Label: SID_AND_ATTRIBUTES {
+                Sid: sid,
+                Attributes: SE_GROUP_INTEGRITY,
+            },
+        };
+        let set = unsafe {
+            SetTokenInformation(
+                self.0,
+                TokenIntegrityLevel,
+                &mut label as *mut TOKEN_MANDATORY_LABEL as *mut _,
+                std::mem::size_of::<TOKEN_MANDATORY_LABEL>() as u32,
+            )
+        };
+        let result = match set {
+            0 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        };
+        unsafe { LocalFree(sid as _) };
+        result
+    }
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct MockToken {
+    held: Vec<&'static str>,
+    calls: std::cell::RefCell<Vec<String>>,
+    // remove() fails for this one
+    fail: Option<&'static str>,
+}
+
+#[cfg(test)]
+impl TokenApi for MockToken {
+    fn privileges(&self) -> io::Result<Vec<String>> {
+        Ok(self.held.iter().map(|name| name.to_string()).collect())
+    }
+
+    fn enable(&self, privilege: &str) -> io::Result<()> {
+        self.calls
+            .borrow_mut()
+            .push(format!("enable {}", privilege));
+        Ok(())
+    }
+
+    fn remove(&self, privilege: &str) -> io::Result<()> {
+        self.calls
+            .borrow_mut()
+            .push(format!("remove {}", privilege));
+        match self.fail == Some(privilege) {
+            true => Err(io::Error::from(io::ErrorKind::PermissionDenied)),
+            false => Ok(()),
+        }
+    }
+
+    fn lower_integrity(&self) -> io::Result<()> {
+        self.calls.borrow_mut().push("lower integrity".into());
+        Ok(())
+    }
+}
+
+#[test]
+fn test_drop_privileges() {
+    let token = MockToken {
+        held: vec![
+            "SeBackupPrivilege",
+            "SeChangeNotifyPrivilege",
+            "SeDebugPrivilege",
+            "SeImpersonatePrivilege",
+        ],
+        ..Default::default()
+    };
+
+    // the harmless ones stay, and so does what --impersonate needs
+    let (dropped, result) = drop_privileges(&token, &["SeImpersonatePrivilege"], true);
+    result.unwrap();
+    assert_eq!(dropped, ["SeBackupPrivilege", "SeDebugPrivilege"]);
+    assert_eq!(
+        *token.calls.borrow(),
+        [
+            "remove SeBackupPrivilege",
+            "remove SeDebugPrivilege",
+            "lower integrity"
+        ]
+    );
+
+    // what went before a failure is still reported
+    let token = MockToken {
+        fail: Some("SeDebugPrivilege"),
+        ..token
+    };
+    token.calls.borrow_mut().clear();
+    let (dropped, result) = drop_privileges(&token, &[], false);
+    assert!(result.is_err());
+    assert_eq!(dropped, ["SeBackupPrivilege"]);
+    assert!(!token
+        .calls
+        .borrow()
+        .contains(&"lower integrity".to_string()));
+}
+
+#[test]
+fn test_enable_backup() {
+    let token = MockToken {
+        held: vec!["SeChangeNotifyPrivilege", "SeBackupPrivilege"],
+        ..Default::default()
+    };
+    assert!(enable_backup(&token).unwrap());
+    assert_eq!(*token.calls.borrow(), ["enable SeBackupPrivilege"]);
+
+    // not elevated: nothing is asked of the token
+    let token = MockToken {
+        held: vec!["SeChangeNotifyPrivilege"],
+        ..Default::default()
+    };
+    assert!(!enable_backup(&token).unwrap());
+    assert!(token.calls.borrow().is_empty());
+}
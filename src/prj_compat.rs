@@ -0,0 +1,716 @@
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    ffi::{c_void, OsStr},
+    fmt, mem,
+    os::windows::ffi::OsStrExt,
+};
+use winapi::{
+    shared::{guiddef::GUID, ntdef::TRUE, winerror::S_OK},
+    um::{
+        libloaderapi::{GetModuleHandleW, GetProcAddress},
+        projectedfslib::{
+            PRJ_DIR_ENTRY_BUFFER_HANDLE, PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT, PRJ_PLACEHOLDER_INFO,
+        },
+        winnt::{HRESULT, PCWSTR},
+    },
+};
+
+use prjfs::sys::{PRJ_EXTENDED_INFO, PRJ_EXT_INFO_TYPE_SYMLINK, PRJ_FILE_BASIC_INFO};
+
+// the ProjFS calls the callbacks make, so they can run against a fake without a mount
+pub trait PrjApi: Send + Sync + fmt::Display {
+    fn symlinks_supported(&self) -> bool;
+
+    fn extended_enum_supported(&self) -> bool;
+
+    // a symlink to `target` (NUL terminated) where supported, otherwise a plain placeholder
+    // for the same entry
+    unsafe fn write_placeholder(
+        &self,
+        context: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
+        path: PCWSTR,
+        info: &PRJ_PLACEHOLDER_INFO,
+        target: Option<&[u16]>,
+    ) -> HRESULT;
+
+    // the listing's side of write_placeholder
+    unsafe fn fill_dir_entry(
+        &self,
+        handle: PRJ_DIR_ENTRY_BUFFER_HANDLE,
+        name: PCWSTR,
+        info: &mut PRJ_FILE_BASIC_INFO,
+        target: Option<&[u16]>,
+    ) -> HRESULT;
+
+    // what write_file_data's offsets and lengths are multiples of, but for the last write of
+    // a file; 0 when the instance can't say
+    fn write_alignment(&self, context: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT) -> u32;
+
+    // null when out of memory
+    fn allocate_aligned_buffer(
+        &self,
+        context: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
+        size: usize,
+    ) -> *mut c_void;
+
+    unsafe fn write_file_data(
+        &self,
+        context: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
+        stream_id: &GUID,
+        buffer: *mut c_void,
+        offset: u64,
+        length: u32,
+    ) -> HRESULT;
+
+    unsafe fn free_aligned_buffer(&self, buffer: *mut c_void);
+
+    // `failure` is what PRJ_UPDATE_FAILURE_CAUSES says kept the file as it was
+    unsafe fn update_file_if_needed(
+        &self,
+        context: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
+        path: PCWSTR,
+        info: &PRJ_PLACEHOLDER_INFO,
+        flags: u32,
+        failure: &mut u32,
+    ) -> HRESULT;
+
+    // `failure` the same as update_file_if_needed's
+    unsafe fn delete_file(
+        &self,
+        context: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
+        path: PCWSTR,
+        flags: u32,
+        failure: &mut u32,
+    ) -> HRESULT;
+
+    // finishes a callback that answered ERROR_IO_PENDING, an enumeration's with the buffer
+    // it was given
+    unsafe fn complete_command(
+        &self,
+        context: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
+        command_id: i32,
+        hr: HRESULT,
+        handle: Option<PRJ_DIR_ENTRY_BUFFER_HANDLE>,
+    ) -> HRESULT;
+
+    fn file_name_match(&self, name: &OsStr, pattern: &OsStr) -> bool;
+
+    fn file_name_compare(&self, a: &OsStr, b: &OsStr) -> Ordering;
+
+    fn contains_wildcards(&self, name: &OsStr) -> bool;
+}
+
+impl fmt::Debug for dyn PrjApi {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PrjApi({})", self)
+    }
+}
+
+type WritePlaceholderInfo = unsafe extern "system" fn(
+    PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
+    PCWSTR,
+    *const PRJ_PLACEHOLDER_INFO,
+    u32,
+) -> HRESULT;
+type WritePlaceholderInfo2 = unsafe extern "system" fn(
+    PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
+    PCWSTR,
+    *const PRJ_PLACEHOLDER_INFO,
+    u32,
+    *const PRJ_EXTENDED_INFO,
+) -> HRESULT;
+type FillDirEntryBuffer = unsafe extern "system" fn(
+    PCWSTR,
+    *mut PRJ_FILE_BASIC_INFO,
+    PRJ_DIR_ENTRY_BUFFER_HANDLE,
+) -> HRESULT;
+type FillDirEntryBuffer2 = unsafe extern "system" fn(
+    PRJ_DIR_ENTRY_BUFFER_HANDLE,
+    PCWSTR,
+    *mut PRJ_FILE_BASIC_INFO,
+    *mut PRJ_EXTENDED_INFO,
+) -> HRESULT;
+
+// the exports every ProjFS has; linking to them is fine
+unsafe extern "system" fn write_placeholder_info(
+    context: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
+    path: PCWSTR,
+    info: *const PRJ_PLACEHOLDER_INFO,
+    size: u32,
+) -> HRESULT {
+    prjfs::sys::PrjWritePlaceholderInfo(context, path, info, size)
+}
+
+unsafe extern "system" fn fill_dir_entry_buffer(
+    name: PCWSTR,
+    info: *mut PRJ_FILE_BASIC_INFO,
+    handle: PRJ_DIR_ENTRY_BUFFER_HANDLE,
+) -> HRESULT {
+    prjfs::sys::PrjFillDirEntryBuffer(name, info, handle)
+}
+
+// the ProjFS calls that only newer Windows builds export (symlinks came with 2004) are looked
+// up at runtime, so the binary still loads on older ones and falls back to the plain calls
+#[derive(Clone, Copy)]
+pub struct ProjFsApi {
+    write_placeholder_info: WritePlaceholderInfo,
+    fill_dir_entry_buffer: FillDirEntryBuffer,
+    write_placeholder_info2: Option<WritePlaceholderInfo2>,
+    fill_dir_entry_buffer2: Option<FillDirEntryBuffer2>,
+}
+
+impl ProjFsApi {
+    pub fn detect() -> Self {
+        let module: Vec<u16> = "projectedfslib.dll\0".encode_utf16().collect();
+        // already loaded, the plain calls are linked to it
+        let module = unsafe { GetModuleHandleW(module.as_ptr()) };
+        Self::resolve(|name| match module.is_null() {
+            true => None,
+            false => {
+                let name = format!("{}\0", name);
+                let address = unsafe { GetProcAddress(module, name.as_ptr() as *const i8) };
+                (!address.is_null()).then_some(address as *const c_void)
+            }
+        })
+    }
+
+    fn resolve<F>(lookup: F) -> Self
+    where
+        F: Fn(&str) -> Option<*const c_void>,
+    {
+        unsafe {
+            ProjFsApi {
+                write_placeholder_info,
+                fill_dir_entry_buffer,
+                write_placeholder_info2: lookup("PrjWritePlaceholderInfo2")
+                    .map(|address| mem::transmute::<_, WritePlaceholderInfo2>(address)),
+                fill_dir_entry_buffer2: lookup("PrjFillDirEntryBuffer2")
+                    .map(|address| mem::transmute::<_, FillDirEntryBuffer2>(address)),
+            }
+        }
+    }
+}
+
+impl PrjApi for ProjFsApi {
+    fn symlinks_supported(&self) -> bool {
+        self.write_placeholder_info2.is_some()
+    }
+
+    fn extended_enum_supported(&self) -> bool {
+        self.fill_dir_entry_buffer2.is_some()
+    }
+
+    unsafe fn write_placeholder(
+        &self,
+        context: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
+        path: PCWSTR,
+        info: &PRJ_PLACEHOLDER_INFO,
+        target: Option<&[u16]>,
+    ) -> HRESULT {
+        let size = mem::size_of_val(info) as u32;
+        match (self.write_placeholder_info2, target) {
+            (Some(write2), Some(target)) => {
+                let extended = symlink_info(target);
+                write2(context, path, info, size, &extended)
+            }
+            _ => (self.write_placeholder_info)(context, path, info, size),
+        }
+    }
+
+    unsafe fn fill_dir_entry(
+        &self,
+        handle: PRJ_DIR_ENTRY_BUFFER_HANDLE,
+        name: PCWSTR,
+        info: &mut PRJ_FILE_BASIC_INFO,
+        target: Option<&[u16]>,
+    ) -> HRESULT {
+        match (self.fill_dir_entry_buffer2, target) {
+            (Some(fill2), Some(target)) => {
+                let mut extended = symlink_info(target);
+                fill2(handle, name, info, &mut extended)
+            }
+            _ => (self.fill_dir_entry_buffer)(name, info, handle),
+        }
+    }
+
+    fn write_alignment(&self, context: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT) -> u32 {
+        let mut info: prjfs::sys::PRJ_VIRTUALIZATION_INSTANCE_INFO = unsafe { mem::zeroed() };
+        match unsafe { prjfs::sys::PrjGetVirtualizationInstanceInfo(context, &mut info) } {
+            S_OK => info.WriteAlignment,
+            _ => 0,
+        }
+    }
+
+    fn allocate_aligned_buffer(
+        &self,
+        context: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
+        size: usize,
+    ) -> *mut c_void {
+        unsafe { prjfs::sys::PrjAllocateAlignedBuffer(context, size) }
+    }
+
+    unsafe fn write_file_data(
+        &self,
+        context: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
+        stream_id: &GUID,
+        buffer: *mut c_void,
+        offset: u64,
+        length: u32,
+    ) -> HRESULT {
+        prjfs::sys::PrjWriteFileData(context, stream_id, buffer, offset, length)
+    }
+
+    unsafe fn free_aligned_buffer(&self, buffer: *mut c_void) {
+        prjfs::sys::PrjFreeAlignedBuffer(buffer)
+    }
+
+    unsafe fn update_file_if_needed(
+        &self,
+        context: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
+        path: PCWSTR,
+        info: &PRJ_PLACEHOLDER_INFO,
+        flags: u32,
+        failure: &mut u32,
+    ) -> HRESULT {
+        prjfs::sys::PrjUpdateFileIfNeeded(
+            context,
+            path,
+            info,
+            mem::size_of_val(info) as u32,
+            flags,
+            failure,
+        )
+    }
+
+    unsafe fn delete_file(
+        &self,
+        context: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
+        path: PCWSTR,
+        flags: u32,
+        failure: &mut u32,
+    ) -> HRESULT {
+        prjfs::sys::PrjDeleteFile(context, path, flags, failure)
+    }
+
+    unsafe fn complete_command(
+        &self,
+        context: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
+        command_id: i32,
+        hr: HRESULT,
+        handle: Option<PRJ_DIR_ENTRY_BUFFER_HANDLE>,
+    ) -> HRESULT {
+        match handle {
+            Some(handle) => {
+                let mut parameters =
+                    prjfs::sys::PRJ_COMPLETE_COMMAND_EXTENDED_PARAMETERS::default();
+                parameters.CommandType = prjfs::sys::PRJ_COMPLETE_COMMAND_TYPE_ENUMERATION;
+                parameters
+                    .Enumeration
+                    .Enumeration_mut()
+                    .DirEntryBufferHandle = handle;
+                prjfs::sys::PrjCompleteCommand(context, command_id, hr, &mut parameters)
+            }
+            None => prjfs::sys::PrjCompleteCommand(context, command_id, hr, std::ptr::null_mut()),
+        }
+    }
+
+    fn file_name_match(&self, name: &OsStr, pattern: &OsStr) -> bool {
+        with_wide(name, pattern, |name, pattern| unsafe {
+            prjfs::sys::PrjFileNameMatch(name, pattern) == TRUE
+        })
+    }
+
+    fn file_name_compare(&self, a: &OsStr, b: &OsStr) -> Ordering {
+        with_wide(a, b, |a, b| unsafe { prjfs::sys::PrjFileNameCompare(a, b) }).cmp(&0)
+    }
+
+    fn contains_wildcards(&self, name: &OsStr) -> bool {
+        with_wide(name, OsStr::new(""), |name, _| unsafe {
+            prjfs::sys::PrjDoesNameContainWildCards(name) == TRUE
+        })
+    }
+}
+
+// the names NUL terminated, in buffers the thread keeps; sorting a listing compares every
+// name a few times over, and shouldn't allocate for each
+fn with_wide<R>(a: &OsStr, b: &OsStr, f: impl FnOnce(PCWSTR, PCWSTR) -> R) -> R {
+    thread_local! {
+        static BUFFERS: RefCell<(Vec<u16>, Vec<u16>)> = RefCell::default();
+    }
+
+    BUFFERS.with(|buffers| {
+        let (wide_a, wide_b) = &mut *buffers.borrow_mut();
+        for (wide, name) in [(&mut *wide_a, a), (&mut *wide_b, b)] {
+            wide.clear();
+            wide.extend(name.encode_wide());
+            wide.push(0);
+        }
+        f(wide_a.as_ptr(), wide_b.as_ptr())
+    })
+}
+
+fn symlink_info(target: &[u16]) -> PRJ_EXTENDED_INFO {
+    let mut extended = PRJ_EXTENDED_INFO::default();
+    extended.InfoType = PRJ_EXT_INFO_TYPE_SYMLINK;
+    unsafe { extended.Symlink.Symlink_mut().TargetName = target.as_ptr() };
+    extended
+}
+
+impl fmt::Display for ProjFsApi {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let yes_no = |supported: bool| if supported { "yes" } else { "no" };
+        write!(
+            f,
+            "symlinks {}, extended enumeration {}",
+            yes_no(self.symlinks_supported()),
+            yes_no(self.extended_enum_supported())
+        )
+    }
+}
+
+// ProjFS for tests without a mount: records what it was asked, answers the name
+// calls with case-insensitive `*` and `?`, and can be told to fail allocations and writes
+#[cfg(test)]
+#[derive(Default)]
+pub struct MockPrjApi {
+    pub calls: std::sync::Mutex<Vec<String>>,
+    pub fail_allocation: std::sync::atomic::AtomicBool,
+    // what write_file_data answers, S_OK by default
+    pub write_result: std::sync::atomic::AtomicI32,
+    // what write_alignment answers, 0 (unknown) by default
+    pub write_alignment: std::sync::atomic::AtomicU32,
+    // name and FileAttributes of every fill_dir_entry
+    pub filled: std::sync::Mutex<Vec<(String, u32)>>,
+    // offset and content of every write_file_data
+    pub written: std::sync::Mutex<Vec<(u64, Vec<u8>)>>,
+    // command id, result and enumeration buffer of every complete_command
+    pub completed: std::sync::Mutex<Vec<(i32, HRESULT, Option<usize>)>>,
+    // address and size of the buffers not freed yet
+    buffers: std::sync::Mutex<std::collections::HashMap<usize, usize>>,
+}
+
+#[cfg(test)]
+impl MockPrjApi {
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    pub fn outstanding_buffers(&self) -> usize {
+        self.buffers.lock().unwrap().len()
+    }
+
+    fn call(&self, call: String) {
+        self.calls.lock().unwrap().push(call);
+    }
+}
+
+#[cfg(test)]
+fn wildcard_match(name: &[char], pattern: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|skip| wildcard_match(&name[skip..], rest)),
+        Some((&expected, rest)) => match name.split_first() {
+            Some((&actual, name)) => {
+                (expected == '?' || expected == actual) && wildcard_match(name, rest)
+            }
+            None => false,
+        },
+    }
+}
+
+#[cfg(test)]
+impl fmt::Display for MockPrjApi {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "mock")
+    }
+}
+
+#[cfg(test)]
+impl PrjApi for MockPrjApi {
+    fn symlinks_supported(&self) -> bool {
+        true
+    }
+
+    fn extended_enum_supported(&self) -> bool {
+        true
+    }
+
+    unsafe fn write_placeholder(
+        &self,
+        _context: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
+        path: PCWSTR,
+        _info: &PRJ_PLACEHOLDER_INFO,
+        target: Option<&[u16]>,
+    ) -> HRESULT {
+        use prjfs::conv::RawWStrExt;
+
+        let kind = if target.is_some() {
+            "symlink"
+        } else {
+            "placeholder"
+        };
+        self.call(format!("{} {}", kind, path.to_os().to_string_lossy()));
+        0
+    }
+
+    unsafe fn fill_dir_entry(
+        &self,
+        _handle: PRJ_DIR_ENTRY_BUFFER_HANDLE,
+        name: PCWSTR,
+        info: &mut PRJ_FILE_BASIC_INFO,
+        _target: Option<&[u16]>,
+    ) -> HRESULT {
+        use prjfs::conv::RawWStrExt;
+
+        let name = name.to_os().to_string_lossy().into_owned();
+        self.call(format!("fill {}", name));
+        self.filled
+            .lock()
+            .unwrap()
+            .push((name, info.FileAttributes));
+        0
+    }
+
+    fn write_alignment(&self, _context: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT) -> u32 {
+        self.write_alignment
+            .load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    fn allocate_aligned_buffer(
+        &self,
+        _context: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
+        size: usize,
+    ) -> *mut c_void {
+        self.call(format!("allocate {}", size));
+        if self
+            .fail_allocation
+            .load(std::sync::atomic::Ordering::SeqCst)
+        {
+            return std::ptr::null_mut();
+        }
+        // never empty, so every buffer has an address of its own
+        let size = size.max(1);
+        let buffer = Box::into_raw(vec![0u8; size].into_boxed_slice()) as *mut u8;
+        self.buffers.lock().unwrap().insert(buffer as usize, size);
+        buffer as *mut c_void
+    }
+
+    unsafe fn write_file_data(
+        &self,
+        _context: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
+        _stream_id: &GUID,
+        buffer: *mut c_void,
+        offset: u64,
+        length: u32,
+    ) -> HRESULT {
+        self.call(format!("write {} {}", offset, length));
+        let content = std::slice::from_raw_parts(buffer as *const u8, length as usize);
+        self.written
+            .lock()
+            .unwrap()
+            .push((offset, content.to_vec()));
+        self.write_result.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    unsafe fn free_aligned_buffer(&self, buffer: *mut c_void) {
+        self.call("free".into());
+        let size = self
+            .buffers
+            .lock()
+            .unwrap()
+            .remove(&(buffer as usize))
+            .expect("freeing a buffer that wasn't allocated");
+        drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+            buffer as *mut u8,
+            size,
+        )));
+    }
+
+    unsafe fn update_file_if_needed(
+        &self,
+        _context: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
+        path: PCWSTR,
+        info: &PRJ_PLACEHOLDER_INFO,
+        _flags: u32,
+        _failure: &mut u32,
+    ) -> HRESULT {
+        use prjfs::conv::RawWStrExt;
+
+        self.call(format!(
+            "update {} {}",
+            path.to_os().to_string_lossy(),
+            info.FileBasicInfo.FileSize
+        ));
+        0
+    }
+
+    unsafe fn delete_file(
+        &self,
+        _context: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
+        path: PCWSTR,
+        _flags: u32,
+        _failure: &mut u32,
+    ) -> HRESULT {
+        use prjfs::conv::RawWStrExt;
+
+        self.call(format!("delete {}", path.to_os().to_string_lossy()));
+        0
+    }
+
+    unsafe fn complete_command(
+        &self,
+        _context: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
+        command_id: i32,
+        hr: HRESULT,
+        handle: Option<PRJ_DIR_ENTRY_BUFFER_HANDLE>,
+    ) -> HRESULT {
+        self.call(format!("complete {} {:08x}", command_id, hr));
+        self.completed
+            .lock()
+            .unwrap()
+            .push((command_id, hr, handle.map(|handle| handle as usize)));
+        0
+    }
+
+    fn file_name_match(&self, name: &OsStr, pattern: &OsStr) -> bool {
+        let fold =
+            |text: &OsStr| -> Vec<char> { text.to_string_lossy().to_lowercase().chars().collect() };
+        wildcard_match(&fold(name), &fold(pattern))
+    }
+
+    fn file_name_compare(&self, a: &OsStr, b: &OsStr) -> Ordering {
+        let fold = |text: &OsStr| text.to_string_lossy().to_lowercase();
+        fold(a).cmp(&fold(b))
+    }
+
+    fn contains_wildcards(&self, name: &OsStr) -> bool {
+        name.to_string_lossy().contains(['*', '?'])
+    }
+}
+
+// what the mocks were called with, and whether the call carried a symlink
+#[cfg(test)]
+static CALLS: std::sync::Mutex<Vec<(&str, bool)>> = std::sync::Mutex::new(Vec::new());
+
+#[cfg(test)]
+fn called(name: &'static str, extended: *const PRJ_EXTENDED_INFO) -> HRESULT {
+    let symlink =
+        !extended.is_null() && unsafe { (*extended).InfoType } == PRJ_EXT_INFO_TYPE_SYMLINK;
+    CALLS.lock().unwrap().push((name, symlink));
+    0
+}
+
+#[cfg(test)]
+unsafe extern "system" fn mock_write(
+    _: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
+    _: PCWSTR,
+    _: *const PRJ_PLACEHOLDER_INFO,
+    _: u32,
+) -> HRESULT {
+    called("write", std::ptr::null())
+}
+
+#[cfg(test)]
+unsafe extern "system" fn mock_write2(
+    _: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
+    _: PCWSTR,
+    _: *const PRJ_PLACEHOLDER_INFO,
+    _: u32,
+    extended: *const PRJ_EXTENDED_INFO,
+) -> HRESULT {
+    called("write2", extended)
+}
+
+#[cfg(test)]
+unsafe extern "system" fn mock_fill(
+    _: PCWSTR,
+    _: *mut PRJ_FILE_BASIC_INFO,
+    _: PRJ_DIR_ENTRY_BUFFER_HANDLE,
+) -> HRESULT {
+    called("fill", std::ptr::null())
+}
+
+#[cfg(test)]
+unsafe extern "system" fn mock_fill2(
+    _: PRJ_DIR_ENTRY_BUFFER_HANDLE,
+    _: PCWSTR,
+    _: *mut PRJ_FILE_BASIC_INFO,
+    extended: *mut PRJ_EXTENDED_INFO,
+) -> HRESULT {
+    called("fill2", extended)
+}
+
+#[test]
+fn test_dispatch() {
+    use std::ptr;
+
+    let target: Vec<u16> = "Keyboard\0".encode_utf16().collect();
+    let info = PRJ_PLACEHOLDER_INFO::default();
+    let mut basic = PRJ_FILE_BASIC_INFO::default();
+    let mut calls = |api: ProjFsApi| {
+        CALLS.lock().unwrap().clear();
+        unsafe {
+            api.write_placeholder(ptr::null_mut(), ptr::null(), &info, Some(&target));
+            api.write_placeholder(ptr::null_mut(), ptr::null(), &info, None);
+            api.fill_dir_entry(ptr::null_mut(), ptr::null(), &mut basic, Some(&target));
+            api.fill_dir_entry(ptr::null_mut(), ptr::null(), &mut basic, None);
+        }
+        std::mem::take(&mut *CALLS.lock().unwrap())
+    };
+    let mocked = |newer: bool| {
+        let mut api = ProjFsApi::resolve(|name| match (newer, name) {
+            (true, "PrjWritePlaceholderInfo2") => Some(mock_write2 as *const c_void),
+            (true, "PrjFillDirEntryBuffer2") => Some(mock_fill2 as *const c_void),
+            _ => None,
+        });
+        api.write_placeholder_info = mock_write;
+        api.fill_dir_entry_buffer = mock_fill;
+        api
+    };
+
+    let newer = mocked(true);
+    assert!(newer.symlinks_supported() && newer.extended_enum_supported());
+    assert_eq!(
+        calls(newer),
+        [
+            ("write2", true),
+            ("write", false),
+            ("fill2", true),
+            ("fill", false)
+        ]
+    );
+
+    let older = mocked(false);
+    assert_eq!(older.to_string(), "symlinks no, extended enumeration no");
+    assert_eq!(
+        calls(older),
+        [
+            ("write", false),
+            ("write", false),
+            ("fill", false),
+            ("fill", false)
+        ]
+    );
+}
+
+#[test]
+fn test_mock_names() {
+    let mock = MockPrjApi::default();
+    let name = |text: &str| OsStr::new(text).to_owned();
+
+    assert!(mock.contains_wildcards(&name("Con*")));
+    assert!(!mock.contains_wildcards(&name("Console")));
+    assert!(mock.file_name_match(&name("CONSOLE"), &name("con*")));
+    assert!(mock.file_name_match(&name("Console"), &name("C?ns*e")));
+    assert!(!mock.file_name_match(&name("Keyboard"), &name("Con*")));
+    assert!(!mock.file_name_match(&name("Con"), &name("Con?")));
+    assert_eq!(
+        mock.file_name_compare(&name("alpha"), &name("BETA")),
+        Ordering::Less
+    );
+    assert_eq!(
+        mock.file_name_compare(&name("Same"), &name("sAME")),
+        Ordering::Equal
+    );
+}
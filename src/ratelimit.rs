@@ -0,0 +1,268 @@
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+#[derive(Default)]
+struct Window {
+    last: Option<Instant>,
+    suppressed: u64,
+}
+
+// lets one event through per interval and counts the ones it swallowed in between
+pub struct RateLimiter {
+    interval: Duration,
+    window: Mutex<Window>,
+}
+
+impl RateLimiter {
+    pub fn new(interval: Duration) -> Self {
+        RateLimiter {
+            interval,
+            window: Default::default(),
+        }
+    }
+
+    // Some(number of events suppressed since the last one let through)
+    pub fn allow(&self) -> Option<u64> {
+        self.allow_at(Instant::now())
+    }
+
+    fn allow_at(&self, now: Instant) -> Option<u64> {
+        let mut window = self.window.lock().unwrap_or_else(|e| e.into_inner());
+
+        match window.last {
+            Some(last) if now.duration_since(last) < self.interval => {
+                window.suppressed += 1;
+                None
+            }
+            _ => {
+                window.last = Some(now);
+                Some(std::mem::take(&mut window.suppressed))
+            }
+        }
+    }
+}
+
+// the longest a throttled callback is held before it's turned away instead
+pub const MAX_DELAY: Duration = Duration::from_millis(250);
+
+struct Bucket {
+    // negative once callers are queued behind the ones already waiting
+    tokens: f64,
+    refilled: Instant,
+}
+
+// `rate` operations a second on average, in bursts of up to a second's worth
+pub struct TokenBucket {
+    rate: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32, now: Instant) -> Self {
+        let rate = rate.max(1) as f64;
+        TokenBucket {
+            rate,
+            bucket: Mutex::new(Bucket {
+                tokens: rate,
+                refilled: now,
+            }),
+        }
+    }
+
+    // how long to wait for the operation's turn, which it then has; None when that's longer
+    // than `max_wait`, and nothing is taken
+    pub fn acquire_at(&self, now: Instant, max_wait: Duration) -> Option<Duration> {
+        let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        let elapsed = now.saturating_duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.rate);
+        bucket.refilled = now;
+
+        let wait = match bucket.tokens >= 1.0 {
+            true => Duration::ZERO,
+            false => Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate),
+        };
+        if wait > max_wait {
+            return None;
+        }
+        bucket.tokens -= 1.0;
+        Some(wait)
+    }
+}
+
+struct ProcessLimit {
+    bucket: TokenBucket,
+    delayed: AtomicU64,
+    rejected: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Throttle {
+    Go,
+    Delayed(Duration),
+    Rejected,
+}
+
+// --rate-limit: a bucket per listed process, by image file name; built once, so a callback
+// from any other process costs a lookup in a map nobody writes to
+#[derive(Default)]
+pub struct ProcessLimits(HashMap<String, ProcessLimit>);
+
+fn image_name(process: &OsStr) -> Option<String> {
+    Path::new(process)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_lowercase())
+}
+
+impl ProcessLimits {
+    pub fn new(limits: &[(String, u32)]) -> Self {
+        let now = Instant::now();
+        ProcessLimits(
+            limits
+                .iter()
+                .map(|(process, rate)| {
+                    let limit = ProcessLimit {
+                        bucket: TokenBucket::new(*rate, now),
+                        delayed: AtomicU64::new(0),
+                        rejected: AtomicU64::new(0),
+                    };
+                    (process.to_lowercase(), limit)
+                })
+                .collect(),
+        )
+    }
+
+    // no --rate-limit, so the callback needn't even name its process
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // counts what it delayed or rejected; the caller does the waiting
+    pub fn check(&self, process: &OsStr) -> Throttle {
+        self.check_at(process, Instant::now())
+    }
+
+    fn check_at(&self, process: &OsStr, now: Instant) -> Throttle {
+        if self.0.is_empty() {
+            return Throttle::Go;
+        }
+        let limit = match image_name(process).and_then(|name| self.0.get(&name)) {
+            Some(limit) => limit,
+            None => return Throttle::Go,
+        };
+
+        match limit.bucket.acquire_at(now, MAX_DELAY) {
+            Some(wait) if wait.is_zero() => Throttle::Go,
+            Some(wait) => {
+                limit.delayed.fetch_add(1, Ordering::Relaxed);
+                Throttle::Delayed(wait)
+            }
+            None => {
+                limit.rejected.fetch_add(1, Ordering::Relaxed);
+                Throttle::Rejected
+            }
+        }
+    }
+
+    // {"<process>": {"delayed": n, "rejected": n}}
+    pub fn to_json(&self) -> Value {
+        self.0
+            .iter()
+            .map(|(process, limit)| {
+                let counts = json!({
+                    "delayed": limit.delayed.load(Ordering::Relaxed),
+                    "rejected": limit.rejected.load(Ordering::Relaxed),
+                });
+                (process.clone(), counts)
+            })
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+}
+
+#[test]
+fn test_rate_limiter() {
+    let limiter = RateLimiter::new(Duration::from_secs(10));
+    let start = Instant::now();
+
+    assert_eq!(limiter.allow_at(start), Some(0));
+    assert_eq!(limiter.allow_at(start + Duration::from_secs(1)), None);
+    assert_eq!(limiter.allow_at(start + Duration::from_secs(9)), None);
+    assert_eq!(limiter.allow_at(start + Duration::from_secs(10)), Some(2));
+    assert_eq!(limiter.allow_at(start + Duration::from_secs(11)), None);
+}
+
+#[test]
+fn test_token_bucket() {
+    let start = Instant::now();
+    let bucket = TokenBucket::new(10, start);
+    let at = |millis: u64| start + Duration::from_millis(millis);
+    let no_wait = Duration::ZERO;
+
+    // a second's worth right away, then nothing without waiting
+    for _ in 0..10 {
+        assert_eq!(bucket.acquire_at(start, no_wait), Some(Duration::ZERO));
+    }
+    assert_eq!(bucket.acquire_at(start, no_wait), None);
+
+    // one every 100ms
+    assert_eq!(bucket.acquire_at(at(50), no_wait), None);
+    assert_eq!(bucket.acquire_at(at(100), no_wait), Some(Duration::ZERO));
+    assert_eq!(bucket.acquire_at(at(100), no_wait), None);
+
+    // waiting queues: each caller is told to wait for the turn after the previous one
+    let max_wait = Duration::from_millis(250);
+    let waits: Vec<Option<u64>> = (0..3)
+        .map(|_| {
+            bucket
+                .acquire_at(at(100), max_wait)
+                .map(|wait| wait.as_millis() as u64)
+        })
+        .collect();
+    assert_eq!(waits, [Some(100), Some(200), None]);
+
+    // never more than a burst, however long it was idle
+    let later = at(60_000);
+    for _ in 0..10 {
+        assert_eq!(bucket.acquire_at(later, no_wait), Some(Duration::ZERO));
+    }
+    assert_eq!(bucket.acquire_at(later, no_wait), None);
+}
+
+#[test]
+fn test_process_limits() {
+    let limits = ProcessLimits::new(&[("SearchIndexer.exe".into(), 10)]);
+    let start = Instant::now();
+    let indexer = OsStr::new("C:\\Windows\\System32\\searchindexer.exe");
+    let explorer = OsStr::new("C:\\Windows\\explorer.exe");
+
+    let checks: Vec<Throttle> = (0..14).map(|_| limits.check_at(indexer, start)).collect();
+    assert!(checks[..10].iter().all(|check| *check == Throttle::Go));
+    let delays: Vec<u128> = checks[10..12]
+        .iter()
+        .map(|check| match check {
+            Throttle::Delayed(wait) => wait.as_millis(),
+            other => panic!("{:?}", other),
+        })
+        .collect();
+    assert_eq!(delays, [100, 200]);
+    // past MAX_DELAY
+    assert_eq!(checks[12..], [Throttle::Rejected, Throttle::Rejected]);
+    // anything not listed goes, always
+    for _ in 0..1000 {
+        assert_eq!(limits.check_at(explorer, start), Throttle::Go);
+    }
+    assert_eq!(limits.check_at(OsStr::new(""), start), Throttle::Go);
+    assert_eq!(
+        limits.to_json(),
+        json!({ "searchindexer.exe": { "delayed": 2, "rejected": 2 } })
+    );
+}
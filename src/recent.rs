@@ -0,0 +1,198 @@
+use std::{
+    collections::VecDeque,
+    ffi::{OsStr, OsString},
+    fmt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::regfs::path_key;
+use crate::searchdir;
+
+// entry names start with this minus the change's number, so the newest sorts first
+const NEWEST: u64 = 9_999_999_999;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecentLimits {
+    pub max_entries: usize,
+    // changes older than this are dropped from the listing
+    pub max_age: Duration,
+}
+
+impl Default for RecentLimits {
+    fn default() -> Self {
+        RecentLimits {
+            max_entries: 100,
+            max_age: Duration::from_secs(600),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecentKind {
+    Modified,
+    Deleted,
+}
+
+impl fmt::Display for RecentKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RecentKind::Modified => "modified",
+            RecentKind::Deleted => "deleted",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecentChange {
+    pub number: u64,
+    pub path: PathBuf,
+    pub kind: RecentKind,
+    pub time: SystemTime,
+}
+
+impl RecentChange {
+    // `<order>~<path as _search names it>`; a path that changes again gets a new name, so
+    // a placeholder never has content other than what its name was listed with
+    pub fn name(&self) -> OsString {
+        let mut name = OsString::from(format!("{:010}~", NEWEST - self.number % NEWEST));
+        name.push(searchdir::hit_name(&self.path));
+        name
+    }
+
+    pub fn content(&self) -> Vec<u8> {
+        let millis = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        format!(
+            "path {}\nchange {}\ntime {}\n",
+            self.path.to_string_lossy(),
+            self.kind,
+            millis
+        )
+        .into_bytes()
+    }
+}
+
+// --recent-dir: the last registry changes the watchers reported, newest last, one per path
+pub struct RecentChanges {
+    limits: RecentLimits,
+    changes: Mutex<(u64, VecDeque<RecentChange>)>,
+}
+
+impl RecentChanges {
+    pub fn new(limits: RecentLimits) -> Self {
+        RecentChanges {
+            limits,
+            changes: Mutex::new((0, VecDeque::new())),
+        }
+    }
+
+    // returns the entries it replaced or pushed out
+    pub fn record(&self, path: &Path, kind: RecentKind, now: SystemTime) -> Vec<RecentChange> {
+        let mut guard = self.changes.lock().unwrap_or_else(|e| e.into_inner());
+        let (next, changes) = &mut *guard;
+        *next += 1;
+
+        let key = path_key(path);
+        let mut dropped = Vec::new();
+        if let Some(index) = changes
+            .iter()
+            .position(|change| path_key(&change.path) == key)
+        {
+            dropped.extend(changes.remove(index));
+        }
+        changes.push_back(RecentChange {
+            number: *next,
+            path: path.to_owned(),
+            kind,
+            time: now,
+        });
+        while changes.len() > self.limits.max_entries {
+            dropped.extend(changes.pop_front());
+        }
+        dropped
+    }
+
+    // drops the ones older than max_age and returns them
+    pub fn expire(&self, now: SystemTime) -> Vec<RecentChange> {
+        let mut guard = self.changes.lock().unwrap_or_else(|e| e.into_inner());
+        let changes = &mut guard.1;
+        let mut expired = Vec::new();
+        while let Some(oldest) = changes.front() {
+            match now.duration_since(oldest.time) {
+                Ok(age) if age > self.limits.max_age => expired.extend(changes.pop_front()),
+                _ => break,
+            }
+        }
+        expired
+    }
+
+    // newest first
+    pub fn list(&self) -> Vec<RecentChange> {
+        let guard = self.changes.lock().unwrap_or_else(|e| e.into_inner());
+        guard.1.iter().rev().cloned().collect()
+    }
+
+    pub fn find(&self, name: &OsStr) -> Option<RecentChange> {
+        let guard = self.changes.lock().unwrap_or_else(|e| e.into_inner());
+        guard
+            .1
+            .iter()
+            .find(|change| change.name().eq_ignore_ascii_case(name))
+            .cloned()
+    }
+}
+
+#[test]
+fn test_recent_changes() {
+    let recent = RecentChanges::new(RecentLimits {
+        max_entries: 2,
+        max_age: Duration::from_secs(60),
+    });
+    let start = UNIX_EPOCH + Duration::from_secs(1_000);
+    let app = Path::new("HKEY_CURRENT_USER\\Software\\App");
+    let other = Path::new("HKEY_CURRENT_USER\\Software\\Other");
+
+    assert!(recent.record(app, RecentKind::Modified, start).is_empty());
+    assert!(recent.record(other, RecentKind::Deleted, start).is_empty());
+    let names: Vec<OsString> = recent.list().iter().map(RecentChange::name).collect();
+    assert_eq!(
+        names,
+        [
+            "9999999997~HKEY_CURRENT_USER~Software~Other",
+            "9999999998~HKEY_CURRENT_USER~Software~App"
+        ]
+    );
+    assert_eq!(
+        String::from_utf8(recent.list()[0].content()).unwrap(),
+        "path HKEY_CURRENT_USER\\Software\\Other\nchange deleted\ntime 1000000\n"
+    );
+
+    // the same path again takes the place of its last change, under a new name
+    let later = start + Duration::from_secs(30);
+    let replaced = recent.record(
+        "hkey_current_user\\software\\app".as_ref(),
+        RecentKind::Modified,
+        later,
+    );
+    assert_eq!(replaced[0].number, 1);
+    assert_eq!(recent.list()[0].number, 3);
+    assert!(recent.find(&names[1]).is_none());
+    assert!(recent.find(&names[0]).is_some());
+
+    // past max_entries, the oldest go
+    let third = Path::new("HKEY_CURRENT_USER\\Software\\Third");
+    let pushed = recent.record(third, RecentKind::Modified, later);
+    assert_eq!(pushed[0].path, other);
+
+    // and past max_age
+    let expired = recent.expire(start + Duration::from_secs(80));
+    assert!(expired.is_empty());
+    let expired = recent.expire(start + Duration::from_secs(100));
+    assert_eq!(expired.len(), 2);
+    assert!(recent.list().is_empty());
+}
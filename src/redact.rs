@@ -0,0 +1,135 @@
+use log::Level;
+use std::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::hash;
+
+// the target a value's content can be logged under, e.g. RUST_LOG=values=trace
+pub const TARGET: &str = "values";
+
+// --log-unsafe-values
+static UNSAFE_VALUES: AtomicBool = AtomicBool::new(false);
+
+pub fn allow_unsafe_values(allow: bool) {
+    UNSAFE_VALUES.store(allow, Ordering::Relaxed);
+}
+
+// value data on its way to a log: registry values hold passwords and tokens too, so only
+// its size and a short hash are written, unless --log-unsafe-values is set and the
+// `values` target is at trace level
+pub struct Redacted<'a>(pub &'a [u8]);
+
+impl Redacted<'_> {
+    fn shows_content() -> bool {
+        UNSAFE_VALUES.load(Ordering::Relaxed) && log::log_enabled!(target: TARGET, Level::Trace)
+    }
+
+    fn write(&self, f: &mut fmt::Formatter<'_>, content: bool) -> fmt::Result {
+        // the low half is plenty to tell two versions apart
+        write!(
+            f,
+            "<{} bytes, fnv1a {:08x}",
+            self.0.len(),
+            hash::fnv1a(self.0) as u32
+        )?;
+        if content {
+            write!(f, ": \"{}\"", self.0.escape_ascii())?;
+        }
+        write!(f, ">")
+    }
+}
+
+impl fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, Redacted::shows_content())
+    }
+}
+
+// so `{:?}` is just as safe
+impl fmt::Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+// every record any test logs, for the ones checking what never makes it into a log
+#[cfg(test)]
+pub mod capture {
+    use log::{LevelFilter, Log, Metadata, Record};
+    use std::sync::{Mutex, Once};
+
+    static LINES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    // the ones logged inside a --trace-path scope
+    static TRACED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    struct Capture;
+
+    impl Log for Capture {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            let line = format!("[{}] {}", record.target(), record.args());
+            if crate::tracepath::in_scope() {
+                TRACED
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .push(line.clone());
+            }
+            LINES.lock().unwrap_or_else(|e| e.into_inner()).push(line);
+        }
+
+        fn flush(&self) {}
+    }
+
+    // from here on, at every level; once per test process
+    pub fn start() {
+        static START: Once = Once::new();
+        START.call_once(|| {
+            log::set_logger(&Capture).expect("no other test sets a logger");
+            log::set_max_level(LevelFilter::Trace);
+        });
+    }
+
+    pub fn lines() -> Vec<String> {
+        LINES.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn traced_lines() -> Vec<String> {
+        TRACED.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+#[test]
+fn test_redacted() {
+    struct Shown<'a>(Redacted<'a>, bool);
+
+    impl fmt::Display for Shown<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            self.0.write(f, self.1)
+        }
+    }
+
+    let secret = b"hunter2\n";
+    let hidden = Shown(Redacted(secret), false).to_string();
+    assert_eq!(
+        hidden,
+        format!("<8 bytes, fnv1a {:08x}>", hash::fnv1a(secret) as u32)
+    );
+    assert_eq!(
+        Shown(Redacted(secret), true).to_string(),
+        format!(
+            "<8 bytes, fnv1a {:08x}: \"hunter2\\n\">",
+            hash::fnv1a(secret) as u32
+        )
+    );
+
+    // trace level alone shows nothing
+    capture::start();
+    assert!(log::log_enabled!(target: TARGET, Level::Trace));
+    assert_eq!(Redacted(secret).to_string(), hidden);
+    assert_eq!(format!("{:?}", Redacted(secret)), hidden);
+}
@@ -0,0 +1,703 @@
+use anyhow::{anyhow, Result};
+use serde_json::json;
+use std::{
+    ffi::OsString,
+    fs, iter,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+use winapi::um::winnt::{REG_BINARY, REG_DWORD, REG_SZ};
+
+use crate::backend::RegistryBackend;
+use crate::memory::MemoryBackend;
+use crate::opcontext::{Cancelled, OpContext};
+use crate::regop::{paths, RegEntires, RootHive};
+
+pub const HEADER: &str = "Windows Registry Editor Version 5.00";
+const HEADER_REGEDIT4: &str = "REGEDIT4";
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("line {line}: {message}")]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegFileValue {
+    // empty for the default value, `@`
+    pub name: OsString,
+    // None for `"name"=-`, which deletes the value
+    pub data: Option<(u32, Vec<u8>)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegFileKey {
+    pub path: PathBuf,
+    // `[-HKEY_...]` deletes the key and everything under it
+    pub delete: bool,
+    pub values: Vec<RegFileValue>,
+}
+
+// a .reg file as written by regedit, keys in file order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RegFile {
+    pub keys: Vec<RegFileKey>,
+}
+
+fn error<T>(line: usize, message: impl Into<String>) -> Result<T, ParseError> {
+    Err(ParseError {
+        line,
+        message: message.into(),
+    })
+}
+
+// REG_SZ data the way the registry keeps it: UTF-16LE with a terminating NUL
+pub fn string_data(text: &str) -> Vec<u8> {
+    text.encode_utf16()
+        .chain(iter::once(0))
+        .flat_map(u16::to_le_bytes)
+        .collect()
+}
+
+// the full hive name, whichever spelling the file used
+fn key_path(line: usize, text: &str) -> Result<PathBuf, ParseError> {
+    let mut parts = text.split('\\').filter(|part| !part.is_empty());
+    let hive: RootHive = match parts.next().map(str::parse) {
+        Some(Ok(hive)) => hive,
+        Some(Err(e)) => return error(line, e.to_string()),
+        None => return error(line, "empty key name"),
+    };
+
+    Ok(paths::join_parts(iter::once(hive.name()).chain(parts)))
+}
+
+// a quoted string starting at `text`, and what follows its closing quote
+fn quoted(line: usize, text: &str) -> Result<(String, &str), ParseError> {
+    let mut result = String::new();
+    let mut chars = text.char_indices().skip(1);
+
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '"' => return Ok((result, &text[index + 1..])),
+            // regedit only ever escapes `\` and `"`
+            '\\' => match chars.next() {
+                Some((_, escaped)) => result.push(escaped),
+                None => break,
+            },
+            c => result.push(c),
+        }
+    }
+    error(line, "unterminated string")
+}
+
+fn hex_bytes(line: usize, text: &str) -> Result<Vec<u8>, ParseError> {
+    text.split(',')
+        .map(str::trim)
+        .filter(|byte| !byte.is_empty())
+        .map(|byte| {
+            u8::from_str_radix(byte, 16)
+                .or_else(|_| error(line, format!("invalid byte [{}]", byte)))
+        })
+        .collect()
+}
+
+fn value_data(line: usize, text: &str) -> Result<Option<(u32, Vec<u8>)>, ParseError> {
+    let text = text.trim();
+
+    if text == "-" {
+        return Ok(None);
+    }
+    if text.starts_with('"') {
+        let (string, rest) = quoted(line, text)?;
+        if !rest.trim().is_empty() {
+            return error(
+                line,
+                format!("unexpected [{}] after the string", rest.trim()),
+            );
+        }
+        return Ok(Some((REG_SZ, string_data(&string))));
+    }
+    if let Some(dword) = text.strip_prefix("dword:") {
+        return match u32::from_str_radix(dword, 16) {
+            Ok(dword) => Ok(Some((REG_DWORD, dword.to_le_bytes().to_vec()))),
+            Err(_) => error(line, format!("invalid dword [{}]", dword)),
+        };
+    }
+    if let Some(bytes) = text.strip_prefix("hex:") {
+        return Ok(Some((REG_BINARY, hex_bytes(line, bytes)?)));
+    }
+    if let Some(rest) = text.strip_prefix("hex(") {
+        let (vtype, bytes) = match rest.split_once("):") {
+            Some(split) => split,
+            None => return error(line, "invalid hex value"),
+        };
+        let vtype = match u32::from_str_radix(vtype, 16) {
+            Ok(vtype) => vtype,
+            Err(_) => return error(line, format!("invalid value type [{}]", vtype)),
+        };
+        return Ok(Some((vtype, hex_bytes(line, bytes)?)));
+    }
+
+    error(line, format!("invalid value data [{}]", text))
+}
+
+fn value(line: usize, text: &str) -> Result<RegFileValue, ParseError> {
+    let (name, rest) = match text.strip_prefix('@') {
+        Some(rest) => (String::new(), rest),
+        None if text.starts_with('"') => quoted(line, text)?,
+        None => return error(line, format!("expected a key or a value, found [{}]", text)),
+    };
+
+    match rest.trim_start().strip_prefix('=') {
+        Some(data) => Ok(RegFileValue {
+            name: name.into(),
+            data: value_data(line, data)?,
+        }),
+        None => error(line, "expected [=] after the value name"),
+    }
+}
+
+// physical lines joined at a trailing `\`, each with the number of its first line
+fn logical_lines(text: &str) -> Vec<(usize, String)> {
+    let mut lines = Vec::new();
+    let mut pending: Option<(usize, String)> = None;
+
+    for (index, line) in text.lines().enumerate() {
+        let (number, mut joined) = match pending.take() {
+            Some((number, joined)) => (number, joined + line.trim_start()),
+            None => (index + 1, line.to_string()),
+        };
+
+        if joined.trim_end().ends_with('\\') {
+            joined.truncate(joined.trim_end().len() - 1);
+            pending = Some((number, joined));
+        } else {
+            lines.push((number, joined));
+        }
+    }
+    lines.extend(pending);
+    lines
+}
+
+pub fn parse(text: &str) -> Result<RegFile, ParseError> {
+    let mut file = RegFile::default();
+    let mut header = false;
+
+    for (number, line) in logical_lines(text.trim_start_matches('\u{feff}')) {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+
+        if !header {
+            if line != HEADER && line != HEADER_REGEDIT4 {
+                return error(number, format!("expected [{}]", HEADER));
+            }
+            header = true;
+            continue;
+        }
+
+        if let Some(key) = line.strip_prefix('[') {
+            let key = match key.strip_suffix(']') {
+                Some(key) => key,
+                None => return error(number, "missing [']'] after the key name"),
+            };
+            let (delete, key) = match key.strip_prefix('-') {
+                Some(key) => (true, key),
+                None => (false, key),
+            };
+            file.keys.push(RegFileKey {
+                path: key_path(number, key)?,
+                delete,
+                values: Vec::new(),
+            });
+            continue;
+        }
+
+        match file.keys.last_mut() {
+            Some(key) if !key.delete => key.values.push(value(number, line)?),
+            Some(_) => return error(number, "value under a deleted key"),
+            None => return error(number, "value outside of a key"),
+        }
+    }
+
+    if !header {
+        return error(1, format!("expected [{}]", HEADER));
+    }
+    Ok(file)
+}
+
+// regedit writes UTF-16LE with a BOM, but UTF-8 files are common too
+pub fn read(path: &Path) -> Result<RegFile> {
+    let bytes = fs::read(path).map_err(|e| anyhow!("unable to read [{:?}]: {}", path, e))?;
+
+    let text = match bytes.strip_prefix(&[0xff, 0xfe]) {
+        Some(utf16) => {
+            let units: Vec<u16> = utf16
+                .chunks_exact(2)
+                .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        None => String::from_utf8_lossy(&bytes).into_owned(),
+    };
+
+    parse(&text).map_err(|e| anyhow!("[{:?}] {}", path, e))
+}
+
+// with the BOM, the way regedit writes it
+pub fn write(path: &Path, file: &RegFile) -> Result<()> {
+    let bytes: Vec<u8> = [0xff, 0xfe]
+        .into_iter()
+        .chain(file.to_text().encode_utf16().flat_map(u16::to_le_bytes))
+        .collect();
+    fs::write(path, bytes).map_err(|e| anyhow!("unable to write [{:?}]: {}", path, e))
+}
+
+fn quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// a REG_SZ that reads back as the same bytes, if the data is one
+fn as_string(data: &[u8]) -> Option<String> {
+    if data.len() % 2 != 0 {
+        return None;
+    }
+    let units: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .collect();
+    match units.split_last() {
+        // a line break inside the quotes would end the line
+        Some((&0, text)) if !text.contains(&0) => String::from_utf16(text)
+            .ok()
+            .filter(|text| !text.contains(['\n', '\r'])),
+        _ => None,
+    }
+}
+
+fn hex_list(data: &[u8]) -> String {
+    let bytes: Vec<String> = data.iter().map(|byte| format!("{:02x}", byte)).collect();
+    bytes.join(",")
+}
+
+fn data_text(vtype: u32, data: &[u8]) -> String {
+    match vtype {
+        REG_SZ => match as_string(data) {
+            Some(text) => quote(&text),
+            None => format!("hex(1):{}", hex_list(data)),
+        },
+        REG_DWORD if data.len() == 4 => format!(
+            "dword:{:08x}",
+            u32::from_le_bytes([data[0], data[1], data[2], data[3]])
+        ),
+        REG_BINARY => format!("hex:{}", hex_list(data)),
+        vtype => format!("hex({:x}):{}", vtype, hex_list(data)),
+    }
+}
+
+pub fn hex_string(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn from_hex_string(text: &str) -> Option<Vec<u8>> {
+    (0..text.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(text.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+impl RegFile {
+    // Registry Editor 5.00 syntax, keys and values in the order they're in
+    pub fn to_text(&self) -> String {
+        let mut text = format!("{}\r\n", HEADER);
+
+        for key in &self.keys {
+            let delete = if key.delete { "-" } else { "" };
+            text += &format!("\r\n[{}{}]\r\n", delete, key.path.display());
+            for value in &key.values {
+                let name = match value.name.is_empty() {
+                    true => "@".to_string(),
+                    false => quote(&value.name.to_string_lossy()),
+                };
+                let data = match &value.data {
+                    Some((vtype, data)) => data_text(*vtype, data),
+                    None => "-".to_string(),
+                };
+                text += &format!("{}={}\r\n", name, data);
+            }
+        }
+        text
+    }
+
+    // the file imported the way regedit would: deletions first within each key, in order
+    pub fn apply_to(&self, backend: &MemoryBackend) {
+        for key in &self.keys {
+            if key.delete {
+                backend.remove(&key.path);
+                continue;
+            }
+
+            backend.add_key(&key.path);
+            for value in &key.values {
+                match &value.data {
+                    Some((vtype, data)) => {
+                        backend.set_value(&key.path, value.name.clone(), *vtype, data.clone())
+                    }
+                    None => {
+                        backend.remove(key.path.join(&value.name));
+                    }
+                }
+            }
+        }
+    }
+
+    // {"keys": [{"path", "delete", "values": [{"name", "type", "data"}]}]}, data in hex
+    // or null for a deletion
+    pub fn to_json(&self) -> serde_json::Value {
+        let keys: Vec<serde_json::Value> = self
+            .keys
+            .iter()
+            .map(|key| {
+                let values: Vec<serde_json::Value> = key
+                    .values
+                    .iter()
+                    .map(|value| match &value.data {
+                        Some((vtype, data)) => json!({
+                            "name": value.name.to_string_lossy(),
+                            "type": vtype,
+                            "data": hex_string(data),
+                        }),
+                        None => json!({ "name": value.name.to_string_lossy(), "data": null }),
+                    })
+                    .collect();
+                json!({
+                    "path": key.path.to_string_lossy(),
+                    "delete": key.delete,
+                    "values": values,
+                })
+            })
+            .collect();
+        json!({ "keys": keys })
+    }
+
+    pub fn from_json(json: &serde_json::Value) -> Result<RegFile> {
+        let invalid = |what: &str| anyhow!("invalid {} in the dump", what);
+        let mut file = RegFile::default();
+
+        for key in json["keys"].as_array().ok_or_else(|| invalid("[keys]"))? {
+            let mut values = Vec::new();
+            for value in key["values"]
+                .as_array()
+                .ok_or_else(|| invalid("[values]"))?
+            {
+                let name = value["name"]
+                    .as_str()
+                    .ok_or_else(|| invalid("value name"))?;
+                let data = match &value["data"] {
+                    serde_json::Value::Null => None,
+                    data => {
+                        let vtype = value["type"]
+                            .as_u64()
+                            .ok_or_else(|| invalid("value type"))?;
+                        let data = data
+                            .as_str()
+                            .and_then(from_hex_string)
+                            .ok_or_else(|| invalid("value data"))?;
+                        Some((vtype as u32, data))
+                    }
+                };
+                values.push(RegFileValue {
+                    name: name.into(),
+                    data,
+                });
+            }
+
+            file.keys.push(RegFileKey {
+                path: key["path"]
+                    .as_str()
+                    .ok_or_else(|| invalid("key path"))?
+                    .into(),
+                delete: key["delete"].as_bool().unwrap_or(false),
+                values,
+            });
+        }
+        Ok(file)
+    }
+}
+
+// --reg-file: a .reg export browsed as if it were the registry, never written
+pub struct RegFileBackend(MemoryBackend);
+
+impl RegFileBackend {
+    pub fn new(file: &RegFile) -> Self {
+        let memory = MemoryBackend::new();
+        file.apply_to(&memory);
+        RegFileBackend(memory)
+    }
+}
+
+impl RegistryBackend for RegFileBackend {
+    fn enumerate_key_ctx(
+        &self,
+        path: OsString,
+        ctx: &OpContext,
+    ) -> Result<Option<RegEntires>, Cancelled> {
+        self.0.enumerate_key_ctx(path, ctx)
+    }
+
+    fn read_typed_value_ctx(
+        &self,
+        path: &Path,
+        ctx: &OpContext,
+    ) -> Result<Option<(u32, Vec<u8>)>, Cancelled> {
+        self.0.read_typed_value_ctx(path, ctx)
+    }
+
+    fn read_all_values(&self, path: &Path) -> Option<Vec<(OsString, u32, Vec<u8>)>> {
+        self.0.read_all_values(path)
+    }
+
+    fn does_key_exist(&self, path: &Path) -> bool {
+        self.0.does_key_exist(path)
+    }
+
+    fn key_last_write_time(&self, path: &Path) -> Option<i64> {
+        self.0.key_last_write_time(path)
+    }
+
+    fn writable(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+const SAMPLE: &str = "\u{feff}Windows Registry Editor Version 5.00
+
+; comments are skipped
+[HKEY_CURRENT_USER\\Software\\App]
+@=\"default\"
+\"Path\"=\"C:\\\\Program Files\\\\\\\"App\\\"\"
+\"Count\"=dword:0000001f
+\"Blob\"=hex:00,01,\\
+  02,ff
+\"Expand\"=hex(2):25,00,00,00
+\"Big\"=hex(b):01,00,00,00,00,00,00,00
+\"Gone\"=-
+
+[-HKLM\\Software\\Old]
+";
+
+#[test]
+fn test_parse() {
+    let file = parse(SAMPLE).unwrap();
+    assert_eq!(file.keys.len(), 2);
+
+    let app = &file.keys[0];
+    assert_eq!(app.path, Path::new("HKEY_CURRENT_USER\\Software\\App"));
+    assert!(!app.delete);
+    let values: Vec<_> = app
+        .values
+        .iter()
+        .map(|value| {
+            (
+                value.name.to_string_lossy().into_owned(),
+                value.data.clone(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        values,
+        vec![
+            ("".into(), Some((REG_SZ, string_data("default")))),
+            (
+                "Path".into(),
+                Some((REG_SZ, string_data("C:\\Program Files\\\"App\"")))
+            ),
+            ("Count".into(), Some((REG_DWORD, vec![0x1f, 0, 0, 0]))),
+            ("Blob".into(), Some((REG_BINARY, vec![0, 1, 2, 0xff]))),
+            ("Expand".into(), Some((2, vec![0x25, 0, 0, 0]))),
+            ("Big".into(), Some((0xb, vec![1, 0, 0, 0, 0, 0, 0, 0]))),
+            ("Gone".into(), None),
+        ]
+    );
+
+    assert_eq!(
+        file.keys[1].path,
+        Path::new("HKEY_LOCAL_MACHINE\\Software\\Old")
+    );
+    assert!(file.keys[1].delete);
+}
+
+#[test]
+fn test_parse_errors() {
+    let parse_error = |text: &str| parse(text).unwrap_err();
+
+    assert_eq!(parse_error("REGEDIT5").line, 1);
+    assert_eq!(
+        parse_error("REGEDIT4\n\n\"Name\"=\"x\"").to_string(),
+        "line 3: value outside of a key"
+    );
+    assert_eq!(parse_error("REGEDIT4\n[HKXX\\Software]").line, 2);
+    assert_eq!(
+        parse_error("REGEDIT4\n[HKCU\\Software]\n\"Count\"=dword:xyz").to_string(),
+        "line 3: invalid dword [xyz]"
+    );
+    // a continued value is reported at the line it starts on
+    assert_eq!(
+        parse_error("REGEDIT4\n[HKCU\\Software]\n\"Blob\"=hex:00,\\\n  0g").line,
+        3
+    );
+    assert_eq!(
+        parse_error("REGEDIT4\n[HKCU\\Software]\n\"Name=\"x\"").to_string(),
+        "line 3: expected [=] after the value name"
+    );
+}
+
+#[test]
+fn test_text_round_trip() {
+    let file = parse(SAMPLE).unwrap();
+    assert_eq!(parse(&file.to_text()).unwrap(), file);
+    assert_eq!(RegFile::from_json(&file.to_json()).unwrap(), file);
+
+    let text = file.to_text();
+    assert!(text.starts_with("Windows Registry Editor Version 5.00\r\n"));
+    assert!(text.contains("\"Count\"=dword:0000001f\r\n"));
+    assert!(text.contains("\"Path\"=\"C:\\\\Program Files\\\\\\\"App\\\"\"\r\n"));
+    assert!(text.contains("\"Gone\"=-\r\n"));
+    assert!(text.contains("[-HKEY_LOCAL_MACHINE\\Software\\Old]\r\n"));
+}
+
+#[test]
+fn test_reg_file_backend() {
+    use crate::options::RegFsOptions;
+    use crate::regfs::RegFs;
+    use std::sync::Arc;
+    use winapi::um::winnt::{REG_MULTI_SZ, REG_QWORD};
+
+    let file = parse(include_str!("../fixtures/all_types.reg")).unwrap();
+    let regfs = RegFs::with_backend(
+        &RegFsOptions::default(),
+        Arc::new(RegFileBackend::new(&file)),
+    );
+
+    let root = regfs.regops().enumerate_key("".into()).unwrap();
+    let hives: Vec<_> = root.subkeys.iter().map(|key| key.name.clone()).collect();
+    assert_eq!(
+        hives,
+        vec![
+            OsString::from("HKEY_CURRENT_USER"),
+            "HKEY_LOCAL_MACHINE".into()
+        ]
+    );
+
+    let fixture = Path::new("HKEY_CURRENT_USER\\Software\\Fixture");
+    let entries = regfs.regops().enumerate_key(fixture.into()).unwrap();
+    assert_eq!(entries.subkeys.len(), 1);
+    assert_eq!(entries.values.len(), 12);
+
+    let types: Vec<u32> = regfs
+        .regops()
+        .read_all_values(fixture)
+        .unwrap()
+        .iter()
+        .map(|(_, vtype, _)| *vtype)
+        .collect();
+    let mut sorted = types.clone();
+    sorted.sort();
+    assert_eq!(sorted, (0..=REG_QWORD).collect::<Vec<_>>());
+
+    let read = |name: &str| {
+        regfs
+            .read_projected_value(&fixture.join(name), &OpContext::none())
+            .unwrap()
+    };
+    assert_eq!(read("String"), Some(string_data("hello")));
+    assert_eq!(read("Dword"), Some(vec![0x2a, 0, 0, 0]));
+    assert_eq!(read("Binary"), Some(vec![0xde, 0xad, 0xbe, 0xef]));
+    assert_eq!(read("None"), Some(vec![]));
+    assert_eq!(read("Child\\Inner"), Some(vec![1, 0, 0, 0]));
+    assert_eq!(
+        regfs
+            .regops()
+            .read_typed_value_ctx(&fixture.join("Multi"), &OpContext::none()),
+        Ok(Some((
+            REG_MULTI_SZ,
+            vec![0x61, 0, 0, 0, 0x62, 0, 0, 0, 0, 0]
+        )))
+    );
+
+    let placeholder = regfs.placeholder_info(&fixture.join("Qword")).unwrap();
+    assert_eq!(placeholder.FileBasicInfo.FileSize, 8);
+    assert_eq!(placeholder.FileBasicInfo.IsDirectory, 0);
+    assert!(regfs.placeholder_info(&fixture.join("Child")).is_some());
+    assert!(regfs.placeholder_info(&fixture.join("Missing")).is_none());
+
+    // nothing goes through to a file
+    assert!(!regfs.regops().writable());
+}
+
+#[test]
+fn test_malformed_file_fails_with_its_line() {
+    let path = std::env::temp_dir().join(format!("regfs-malformed-{}.reg", std::process::id()));
+    fs::write(
+        &path,
+        "REGEDIT4\n\n[HKCU\\Software]\n\"Count\"=dword:nope\n",
+    )
+    .unwrap();
+
+    let error = read(&path).unwrap_err().to_string();
+    fs::remove_file(&path).unwrap();
+    assert!(error.ends_with("line 4: invalid dword [nope]"), "{}", error);
+}
+
+#[test]
+fn test_fuzz_parse() {
+    use crate::fuzz::{self, Rng};
+
+    let lines: Vec<&str> = include_str!("../fixtures/all_types.reg").lines().collect();
+    let corpus = fuzz::corpus_strings();
+    let mut rng = Rng::new(fuzz::seed(), 0);
+
+    let mut parsed = 0;
+    for _ in 0..fuzz::iterations() {
+        // a key, then fixture lines in any order, some of them with a name spliced in
+        let mut text = format!("{}\r\n[HKEY_CURRENT_USER\\Fuzz]\r\n", HEADER);
+        for _ in 0..rng.below(8) {
+            let line = *rng.pick(&lines);
+            let line = match rng.chance(30) {
+                true => {
+                    let name = rng.pick(&corpus);
+                    rng.text(line, name)
+                }
+                false => line.to_string(),
+            };
+            text += &line;
+            text += if rng.chance(50) { "\r\n" } else { "\n" };
+        }
+
+        // anything that parses writes out as what it was, in not much more text
+        if let Ok(file) = parse(&text) {
+            parsed += 1;
+            let written = file.to_text();
+            assert!(written.len() <= 4 * text.len(), "{:?}", text);
+            assert_eq!(parse(&written), Ok(file), "{:?}", text);
+        }
+    }
+    // or this isn't testing much
+    assert!(parsed > fuzz::iterations() / 10);
+}
+
+#[test]
+fn test_line_breaks_in_strings() {
+    let mut file = parse("REGEDIT4\n[HKCU\\Software]\n").unwrap();
+    file.keys[0].values.push(RegFileValue {
+        name: "Lines".into(),
+        data: Some((REG_SZ, string_data("one\r\ntwo"))),
+    });
+
+    let text = file.to_text();
+    assert!(text.contains("\"Lines\"=hex(1):"), "{}", text);
+    assert_eq!(parse(&text).unwrap(), file);
+}
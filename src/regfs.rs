@@ -1,14 +1,27 @@
-use anyhow::{anyhow, Result};
-use log::{info, warn};
+use anyhow::Result;
+use log::{debug, error, info, warn};
 use prjfs::conv::{RawWStrExt, WStrExt};
 use prjfs::guid::guid_to_bytes;
+use prjfs::sys::PRJ_FILE_BASIC_INFO;
 use prjfs::ProviderT;
-use prjfs::sys::PRJ_EXT_INFO_TYPE_SYMLINK;
-use std::{collections::HashMap, ffi::OsString, sync::Mutex};
+use serde_json::json;
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::{c_void, OsStr, OsString},
+    fmt, fs, io,
+    ops::Deref,
+    os::windows::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicPtr, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, Weak,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
 use winapi::{
     shared::{
         guiddef::GUID,
-        ntdef::TRUE,
         winerror::{self, HRESULT_FROM_WIN32, S_OK},
     },
     um::{
@@ -20,355 +33,5687 @@ use winapi::{
     },
 };
 
-use crate::dirinfo::DirInfo;
-use crate::regop::RegOps;
+use crate::aliases::HiveAliases;
+use crate::autostop::AutoStop;
+use crate::backend::{PathKind, RegistryBackend};
+use crate::bookmarks::{self, Bookmark, Bookmarks};
+use crate::control::{self, ControlCommand, TraceCommand};
+use crate::diff::DiffBackend;
+use crate::dirinfo::{DirEntry, DirEntrySink, DirInfo, SinkResult};
+use crate::enumcache::EnumCache;
+use crate::error::{self, RegFsError};
+use crate::events::RegFsEvent;
+use crate::executor::{self, Executor};
+use crate::filter::ProjectedEntry;
+use crate::hash;
+use crate::hydration::{self, HydrationCache, ReadShape};
+use crate::hydrationlimit::{Admission, HydrationGate, HydrationPermit};
+use crate::impersonate::{UserHive, UserHives};
+use crate::longnames::LongNames;
+use crate::metrics::{Metrics, MetricsSnapshot};
+use crate::notifymap;
+use crate::observer::{Observers, PendingCall};
+use crate::ondisk;
+use crate::opcontext::{Cancelled, OpContext};
+use crate::options::{BackendSpec, RegFsOptions};
+use crate::overlay::{Change, OverlayBackend};
+use crate::policy::{self, Chain, Decision, MutationKind, MutationPolicy, MutationRequest};
+use crate::pool::RegistryPool;
+use crate::privileges;
+use crate::prj_compat::{PrjApi, ProjFsApi};
+use crate::ratelimit::{ProcessLimits, RateLimiter, Throttle};
+use crate::recent::{RecentChange, RecentChanges, RecentKind};
+use crate::redact::Redacted;
+use crate::regfile::RegFileBackend;
+use crate::regop::{paths, paths::RegPath, RegEntires, RegOps, RootHive};
+use crate::render;
+use crate::search::Search;
+use crate::searchdir::{self, Pattern, SearchSessions};
+use crate::shadow::{self, ShadowStore, StagingRegistry};
+use crate::snapshot::SnapshotBackend;
+use crate::suspend::{self, Suspension};
+use crate::synthetic::{self, Synthetic};
+use crate::times::{self, ValueTimes};
+use crate::trace::{self, Call, CallKind, Response, Tracer};
+use crate::tracepath::{self, Scope, TracePaths};
+use crate::userclasses::{root_name, UserClasses};
+use crate::valuecache::ValueCache;
+use crate::virtualstore::{self, VirtualStore};
+
+// --hide-empty-keys answers kept before the cache starts over
+const EMPTY_KEYS: usize = 100_000;
+
+// one enumeration. It's filled under its own lock, so a listing waiting on the registry
+// doesn't hold up the state, nor the other listings
+pub struct EnumSession {
+    path: PathBuf,
+    dirinfo: Mutex<DirInfo>,
+}
+
+impl EnumSession {
+    fn new(dirinfo: DirInfo) -> Arc<EnumSession> {
+        Arc::new(EnumSession {
+            path: dirinfo.path().to_owned(),
+            dirinfo: Mutex::new(dirinfo),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // one being filled is only named
+    fn to_json(&self) -> serde_json::Value {
+        match self.dirinfo.try_lock() {
+            Ok(dirinfo) => dirinfo.to_json(),
+            Err(_) => json!({ "path": self.path.to_string_lossy(), "busy": true }),
+        }
+    }
+}
 
 #[derive(Default)]
 pub struct State {
-    enum_sessions: HashMap<Vec<u8>, DirInfo>,
+    // keyed by guid_key, the enumeration id as it came
+    enum_sessions: HashMap<[u8; 16], Arc<EnumSession>>,
+    // content ids of the value placeholders we handed out, keyed by path_key
+    content_ids: HashMap<String, u64>,
+    // and the sizes they told the file system about
+    sizes: HashMap<String, u64>,
+    // files opened to be overwritten or truncated, until their handle is closed
+    overwritten: HashSet<String>,
+    // keys a listing found deleted after it started, whose placeholders may still be there
+    vanished: HashMap<String, PathBuf>,
 }
 
-pub struct RegFs {
+impl State {
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut sessions: Vec<_> = self
+            .enum_sessions
+            .iter()
+            .map(|(guid, session)| (control::guid_string(guid), session))
+            .collect();
+        sessions.sort_by(|a, b| a.0.cmp(&b.0));
+        let sessions: Vec<serde_json::Value> = sessions
+            .into_iter()
+            .map(|(guid, session)| {
+                let mut session = session.to_json();
+                session["enumeration"] = json!(guid);
+                session
+            })
+            .collect();
+
+        json!({
+            "sessions": sessions,
+            "content_ids": self.content_ids.len(),
+            "vanished": self.vanished.len(),
+        })
+    }
+}
+
+pub struct RegFsInner {
     state: Mutex<State>,
-    regops: RegOps,
-    readonly: bool,
-    context: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
+    state_recovered: AtomicBool,
+    // kept apart from `state`, so reading it doesn't wait on the others
+    synthetic_content: Mutex<HashMap<String, Vec<u8>>>,
+    // --hide-empty-keys: whether a listed subkey is empty, with the last write time it was
+    // seen with, keyed by path_key
+    empty_keys: Mutex<HashMap<String, (i64, bool)>>,
+    // --enum-cache and --value-cache, not with --impersonate: what was read is only good for
+    // the user it was read for
+    enum_cache: Option<EnumCache>,
+    value_cache: Option<ValueCache>,
+    // cancellation flags of the callbacks in flight, keyed by CommandId
+    commands: Mutex<HashMap<i32, Arc<AtomicBool>>>,
+    // with --impersonate, the process behind each of them
+    processes: Mutex<HashMap<i32, u32>>,
+    user_hives: Option<UserHives>,
+    // only with --async-callbacks
+    executor: Option<Executor>,
+    pool: RegistryPool,
+    partial_warnings: RateLimiter,
+    rate_limits: ProcessLimits,
+    hydration_gate: Arc<HydrationGate>,
+    trace_paths: TracePaths,
+    regops: Arc<dyn RegistryBackend>,
+    // the same backend as `regops`, for the registry paths behind long value names
+    long_names: Arc<LongNames>,
+    // with --merge-virtualstore, the one under `long_names`, for __meta__.json
+    virtual_store: Option<Arc<VirtualStore>>,
+    // with --user-classes, for changes under either of the keys it merges
+    user_classes: Option<Arc<UserClasses>>,
+    // with --bookmark, for the keys behind them and changes to those
+    bookmarks: Option<Arc<Bookmarks>>,
+    // the same, their symlinks' targets under the root as it's listed
+    bookmark_links: Vec<Bookmark>,
+    // with --hive-alias, for the registry paths behind the aliases and back
+    aliases: Option<Arc<HiveAliases>>,
+    // the one under those with --overlay, kept for its write API
+    overlay: Option<Arc<OverlayBackend>>,
+    // with --shadow-writes, where the overlay keeps its writes, for `shadow export`
+    shadow: Option<Arc<ShadowStore>>,
+    // the registry below everything else, where a writable mount without an overlay saves
+    // what's written to its files; None when that can't be written to
+    writer: Option<Arc<dyn StagingRegistry>>,
+    hydrations: HydrationCache,
+    // --search-dir results, by pattern directory
+    searches: SearchSessions,
+    // --recent-dir
+    recent: RecentChanges,
+    metrics: Arc<Metrics>,
+    // the metrics' and the audit log's, then RegFsOptions::observers
+    observers: Observers,
+    auto_stop: AutoStop,
+    options: RegFsOptions,
+    mount_time: i64,
+    // the console and control verbs can flip it while mounted
+    readonly: AtomicBool,
+    // `suspend`; asked by every callback, so it's kept out of the state lock
+    suspension: Suspension,
+    quit: (Mutex<bool>, Condvar),
+    context: AtomicPtr<c_void>,
+    subscriptions: Vec<u64>,
+    projfs: Arc<dyn PrjApi>,
 }
 
-impl RegFs {
-    pub fn new() -> Self {
-        RegFs {
-            state: Mutex::new(Default::default()),
-            regops: RegOps::new(),
-            readonly: true,
-            context: std::ptr::null_mut(),
+impl Drop for RegFsInner {
+    fn drop(&mut self) {
+        if let Some(watchers) = self.regops.watchers() {
+            for id in &self.subscriptions {
+                watchers.unsubscribe(*id);
+            }
         }
     }
 }
 
+// the buffer stays valid until the command is completed, which happens on the executor
+struct DirEntryBuffer(PRJ_DIR_ENTRY_BUFFER_HANDLE);
+
+unsafe impl Send for DirEntryBuffer {}
+
+impl DirEntryBuffer {
+    fn get(&self) -> PRJ_DIR_ENTRY_BUFFER_HANDLE {
+        self.0
+    }
+}
+
+// cheap handle so background work (control verbs, resync) can outlive a callback
+#[derive(Clone)]
+pub struct RegFs {
+    inner: Arc<RegFsInner>,
+}
+
+impl Deref for RegFs {
+    type Target = RegFsInner;
+
+    fn deref(&self) -> &RegFsInner {
+        &self.inner
+    }
+}
+
 impl RegFs {
-    fn write_placeholder_info(&self, filepath: LPCWSTR, mut info: PRJ_PLACEHOLDER_INFO) -> HRESULT {
-        if filepath.to_os().to_string_lossy().ends_with("bruh") {
-            info!(target: "placeholder", "about to do something dangerous");
-            return unsafe {
-                let target_name = "Keyboard\0".encode_utf16().collect::<Vec<u16>>();
-                info.FileBasicInfo.FileSize = 0;
-                info.FileBasicInfo.IsDirectory = true as u8;
-                let mut extended_info = prjfs::sys::PRJ_EXTENDED_INFO::default();
-                extended_info.InfoType = PRJ_EXT_INFO_TYPE_SYMLINK;
-                extended_info.Symlink.Symlink_mut().TargetName = target_name.as_ptr();
-                prjfs::sys::PrjWritePlaceholderInfo2(
-                    self.context,
-                    filepath,
-                    &info,
-                    std::mem::size_of_val(&info) as u32,
-                    &extended_info,
-                )
+    pub fn new(options: &RegFsOptions) -> Self {
+        // build() loaded the regfile base into reg_file
+        let (backend, writer): (Arc<dyn RegistryBackend>, Option<Arc<dyn StagingRegistry>>) =
+            match (&options.backend, &options.reg_file) {
+                (Some(backend), _) => (backend.clone(), None),
+                (None, Some(file)) => (Arc::new(RegFileBackend::new(file)), None),
+                (None, None) if *options.base() == BackendSpec::Memory => {
+                    let memory = Arc::new(crate::memory::MemoryBackend::new());
+                    (memory.clone(), Some(memory))
+                }
+                (None, None) if options.backup_semantics => (
+                    Arc::new(match privileges::enable_process_backup() {
+                        true => RegOps::with_backup_semantics(&options.hives),
+                        false => RegOps::with_hives(&options.hives),
+                    }),
+                    None,
+                ),
+                (None, None) => {
+                    let regops = Arc::new(RegOps::with_hives(&options.hives));
+                    (regops.clone(), Some(regops))
+                }
             };
+        // nothing is written through a copy, nor as the provider for an impersonated caller
+        let writer = writer.filter(|_| {
+            options.snapshot().is_none() && options.diff_baseline.is_none() && !options.impersonate
+        });
+        let backend: Arc<dyn RegistryBackend> = match options.snapshot() {
+            Some(limits) => Arc::new(SnapshotBackend::capture(backend, &options.hives, limits)),
+            None => backend,
+        };
+        let backend: Arc<dyn RegistryBackend> = match &options.diff_baseline {
+            Some(baseline) => Arc::new(DiffBackend::new(backend, baseline)),
+            None => backend,
+        };
+
+        Self::with_writer(options, backend, writer)
+    }
+
+    pub fn with_backend(options: &RegFsOptions, backend: Arc<dyn RegistryBackend>) -> Self {
+        Self::with_writer(options, backend, None)
+    }
+
+    // `writer` is the registry `backend` reads, for write-back
+    pub fn with_writer(
+        options: &RegFsOptions,
+        backend: Arc<dyn RegistryBackend>,
+        writer: Option<Arc<dyn StagingRegistry>>,
+    ) -> Self {
+        let shadow = options.shadow_writes.as_ref().map(|root| {
+            let registry = options
+                .staging_registry
+                .clone()
+                .unwrap_or_else(|| shadow::live_registry(root));
+            Arc::new(ShadowStore::new(registry, root))
+        });
+        let overlay = options.overlay().then(|| {
+            Arc::new(match &shadow {
+                Some(shadow) => OverlayBackend::with_store(backend.clone(), shadow.clone()),
+                None => OverlayBackend::new(backend.clone()),
+            })
+        });
+        let backend: Arc<dyn RegistryBackend> = match &overlay {
+            Some(overlay) => overlay.clone(),
+            None => backend,
+        };
+        let virtual_store = options
+            .merge_virtualstore
+            .then(|| Arc::new(VirtualStore::new(backend.clone())));
+        let backend: Arc<dyn RegistryBackend> = match &virtual_store {
+            Some(virtual_store) => virtual_store.clone(),
+            None => backend,
+        };
+        let user_classes = options
+            .user_classes
+            .as_ref()
+            .map(|sid| Arc::new(UserClasses::new(backend.clone(), sid)));
+        let backend: Arc<dyn RegistryBackend> = match &user_classes {
+            Some(user_classes) => user_classes.clone(),
+            None => backend,
+        };
+        let bookmarks = (!options.bookmarks.is_empty())
+            .then(|| Arc::new(Bookmarks::new(backend.clone(), &options.bookmarks)));
+        let backend: Arc<dyn RegistryBackend> = match &bookmarks {
+            Some(bookmarks) => bookmarks.clone(),
+            None => backend,
+        };
+        let aliases = (!options.hive_aliases.is_empty())
+            .then(|| Arc::new(HiveAliases::new(backend.clone(), &options.hive_aliases)));
+        let backend: Arc<dyn RegistryBackend> = match &aliases {
+            Some(aliases) => aliases.clone(),
+            None => backend,
+        };
+        let bookmark_links = options
+            .bookmarks
+            .iter()
+            .map(|bookmark| Bookmark {
+                target: aliases
+                    .as_ref()
+                    .and_then(|aliases| aliases.aliased(&bookmark.target))
+                    .unwrap_or_else(|| bookmark.target.clone()),
+                ..bookmark.clone()
+            })
+            .collect();
+        let projfs = options
+            .prj_api
+            .clone()
+            .unwrap_or_else(|| Arc::new(ProjFsApi::detect()));
+        let long_names = Arc::new(LongNames::new(backend, projfs.clone()));
+        let backend: Arc<dyn RegistryBackend> = long_names.clone();
+
+        // the watchers only hold a weak reference, the backend may outlive this mount
+        let watched = backend.clone();
+        let subscriptions = |inner: &Weak<RegFsInner>| match watched.watchers() {
+            Some(watchers) => options
+                .hives
+                .iter()
+                .map(|hive| {
+                    let inner = inner.clone();
+                    watchers.subscribe(
+                        hive.name().as_ref(),
+                        Arc::new(move |path| {
+                            if let Some(inner) = inner.upgrade() {
+                                RegFs { inner }.registry_changed(path);
+                            }
+                        }),
+                    )
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+
+        let metrics = Arc::new(Metrics::default());
+        RegFs {
+            inner: Arc::new_cyclic(|inner| RegFsInner {
+                subscriptions: subscriptions(inner),
+                state: Mutex::new(Default::default()),
+                state_recovered: AtomicBool::new(false),
+                synthetic_content: Mutex::new(Default::default()),
+                empty_keys: Mutex::new(Default::default()),
+                enum_cache: (options.enum_cache && !options.impersonate)
+                    .then(|| EnumCache::new(options.enum_cache_limits)),
+                value_cache: (options.value_cache && !options.impersonate)
+                    .then(|| ValueCache::new(options.value_cache_limits)),
+                commands: Mutex::new(Default::default()),
+                processes: Mutex::new(Default::default()),
+                user_hives: options.impersonate.then(Default::default),
+                executor: options
+                    .async_callbacks
+                    .then(|| Executor::new(executor::DEFAULT_WORKERS)),
+                pool: RegistryPool::new(options.registry_threads),
+                partial_warnings: RateLimiter::new(Duration::from_secs(10)),
+                rate_limits: ProcessLimits::new(&options.rate_limits),
+                hydration_gate: HydrationGate::new(options.hydration_limits),
+                trace_paths: TracePaths::new(&options.trace_paths),
+                regops: backend,
+                long_names,
+                virtual_store,
+                user_classes,
+                bookmarks,
+                bookmark_links,
+                aliases,
+                overlay,
+                shadow,
+                writer,
+                hydrations: Default::default(),
+                searches: Default::default(),
+                recent: RecentChanges::new(options.recent_limits),
+                observers: Observers::new(metrics.clone(), &options.observers),
+                metrics,
+                auto_stop: AutoStop::new(options.timeout, options.idle_timeout),
+                options: options.clone(),
+                mount_time: times::to_filetime(SystemTime::now()),
+                readonly: AtomicBool::new(options.readonly),
+                suspension: Default::default(),
+                quit: Default::default(),
+                context: AtomicPtr::new(std::ptr::null_mut()),
+                projfs,
+            }),
         }
-        unsafe {
-            prjfs::sys::PrjWritePlaceholderInfo(
-                self.context,
-                filepath,
-                &info,
-                std::mem::size_of_val(&info) as u32,
-            )
+    }
+
+    pub fn context(&self) -> PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT {
+        self.context.load(Ordering::Acquire) as _
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.options.root
+    }
+
+    // where a path of the mount is on disk, in a form that isn't cut off at MAX_PATH
+    pub fn local_path(&self, relative: &Path) -> PathBuf {
+        ondisk::extended(&self.root().join(relative))
+    }
+
+    pub fn regops(&self) -> &dyn RegistryBackend {
+        self.regops.as_ref()
+    }
+
+    pub fn overlay(&self) -> Option<&OverlayBackend> {
+        self.overlay.as_deref()
+    }
+
+    pub fn shadow(&self) -> Option<&ShadowStore> {
+        self.shadow.as_deref()
+    }
+
+    // with no overlay to take them, where saved files are written back
+    pub fn writer(&self) -> Option<&dyn StagingRegistry> {
+        match &self.overlay {
+            Some(_) => None,
+            None => self.writer.as_deref(),
         }
     }
 
-    fn populate_dir_info_for_path(
-        &self,
-        path: OsString,
-        dirinfo: &mut DirInfo,
-        search_expression: OsString,
-    ) -> bool {
-        let entries = if let Some(entries) = self.regops.enumerate_key(path) {
-            entries
-        } else {
-            return false;
-        };
+    pub fn options(&self) -> &RegFsOptions {
+        &self.options
+    }
 
-        for subkey in entries.subkeys {
-            let result = unsafe {
-                prjfs::sys::PrjFileNameMatch(
-                    subkey.name.to_wstr().as_ptr(),
-                    search_expression.to_wstr().as_ptr(),
-                )
-            };
+    pub fn tracer(&self) -> Option<&Tracer> {
+        self.options.tracer.as_deref()
+    }
+
+    // never started on a virtualization root (a replay, or a test) and no stand-in for
+    // ProjFS: nothing goes to it
+    fn dry_run(&self) -> bool {
+        self.context().is_null() && self.options.prj_api.is_none()
+    }
+
+    fn trace_call<F>(&self, data: &PRJ_CALLBACK_DATA, kind: F) -> Option<PendingCall>
+    where
+        F: FnOnce() -> CallKind,
+    {
+        // every callback starts here
+        Metrics::add(&self.metrics.callbacks, 1);
+        self.auto_stop.touch();
+        self.metrics
+            .busy_paths
+            .hit_wide(wide_units(data.FilePathName).iter().copied());
+        let observed = self.tracer().is_some() || self.observers.observes_callbacks();
+        observed.then(|| PendingCall::new(Call::of(data, kind())))
+    }
+
+    // --trace-path: until it's dropped, what the callback logs is logged at every level if
+    // it's about a traced key
+    fn trace_scope(&self, data: &PRJ_CALLBACK_DATA, destination: PCWSTR) -> Scope {
+        if self.trace_paths.is_empty() {
+            return tracepath::untraced();
+        }
+        self.trace_paths.enter(
+            &wstr_or_empty(data.FilePathName),
+            Some(&wstr_or_empty(destination)),
+        )
+    }
 
-            if result == TRUE {
-                dirinfo.fill_dir_entry(subkey.name);
+    // what the callback answered goes to the observers and the tracer along with the call,
+    // if there are any
+    fn traced<F>(&self, call: Option<PendingCall>, f: F) -> Result<HRESULT, RegFsError>
+    where
+        F: FnOnce(&mut Response) -> Result<HRESULT, RegFsError>,
+    {
+        let mut response = Response::default();
+        let result = f(&mut response);
+        if let Some(pending) = call {
+            response.hr = trace::hresult_of(&result);
+            self.observers.callback(&pending, &response);
+            if let Some(tracer) = self.tracer() {
+                tracer.record(pending.call, response);
             }
         }
+        result
+    }
 
-        for value in entries.values {
-            let result = unsafe {
-                prjfs::sys::PrjFileNameMatch(
-                    value.name.to_wstr().as_ptr(),
-                    search_expression.to_wstr().as_ptr(),
-                )
-            };
+    // where a path of the mount is read from in the registry; long value names differ, and
+    // with --merge-virtualstore whatever has a VirtualStore copy
+    pub fn registry_path(&self, path: &Path) -> PathBuf {
+        let path = self.bookmarked(self.unaliased(self.long_names.resolve(path)));
+        match &self.virtual_store {
+            Some(virtual_store) => virtual_store.resolve(&path),
+            None => path,
+        }
+    }
 
-            if result == TRUE {
-                dirinfo.fill_file_entry(value.name, value.size as i64);
-            }
+    // and where a change to it is written: with --merge-virtualstore, anything under
+    // HKLM\SOFTWARE goes to the VirtualStore, as it would for those apps
+    pub fn write_path(&self, path: &Path) -> PathBuf {
+        let path = self.bookmarked(self.unaliased(self.long_names.resolve(path)));
+        match &self.virtual_store {
+            Some(_) => virtualstore::redirect(&path).unwrap_or(path),
+            None => path,
         }
+    }
 
-        true
+    // a path under a --bookmark is the key's
+    fn bookmarked(&self, path: PathBuf) -> PathBuf {
+        self.bookmarks
+            .as_ref()
+            .and_then(|bookmarks| bookmarks.target(&path))
+            .unwrap_or(path)
     }
-}
 
-impl ProviderT for RegFs {
-    fn get_context_mut(&mut self) -> Option<*mut prjfs::sys::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT> {
-        Some(&mut self.context)
+    // a path under a --hive-alias is the hive's
+    fn unaliased(&self, path: PathBuf) -> PathBuf {
+        self.aliases
+            .as_ref()
+            .and_then(|aliases| aliases.canonical(&path))
+            .unwrap_or(path)
     }
 
-    fn start_dir_enum(
-        &self,
-        callback_data: &PRJ_CALLBACK_DATA,
-        enumeration_id: &GUID,
-    ) -> Result<HRESULT> {
-        let filepath = callback_data.FilePathName.to_os();
-        info!(
-            "----> start_dir_enum: Path [{:?}] triggered by [{:?}]",
-            filepath,
-            callback_data.TriggeringProcessImageFileName.to_os()
-        );
+    // the registry path of a path of the mount, as far as the hive's name goes: what gets
+    // reported anywhere outside the mount
+    pub fn canonical(&self, path: &Path) -> PathBuf {
+        self.unaliased(path.to_path_buf())
+    }
 
-        let guid = guid_to_bytes(enumeration_id);
-        self.state
-            .lock()
-            .unwrap()
-            .enum_sessions
-            .insert(guid, DirInfo::new(filepath));
+    // and the other way, for paths the watchers report
+    pub fn mounted(&self, path: &Path) -> PathBuf {
+        self.aliases
+            .as_ref()
+            .and_then(|aliases| aliases.aliased(path))
+            .unwrap_or_else(|| path.to_path_buf())
+    }
 
-        info!("<---- start_dir_enum: return 0x0");
+    // the bookmarks themselves, like synthetic entries, are skipped by walks down the
+    // tree: what they show is walked where it really is
+    pub fn is_bookmark(&self, path: &Path) -> bool {
+        self.bookmarks
+            .as_ref()
+            .is_some_and(|bookmarks| bookmarks.is_bookmark(path))
+    }
 
-        Ok(0)
+    pub fn projfs(&self) -> &dyn PrjApi {
+        self.projfs.as_ref()
     }
 
-    fn end_dir_enum(
-        &self,
-        _callback_data: &PRJ_CALLBACK_DATA,
-        enumeration_id: &GUID,
-    ) -> Result<HRESULT> {
-        info!("----> end_dir_enum");
+    pub fn symlinks_supported(&self) -> bool {
+        self.projfs.symlinks_supported()
+    }
 
-        let guid = guid_to_bytes(enumeration_id);
-        let mut state = self
-            .state
-            .lock()
-            .map_err(|_| anyhow!("unable to acquire state"))?;
+    pub fn extended_enum_supported(&self) -> bool {
+        self.projfs.extended_enum_supported()
+    }
 
-        state.enum_sessions.remove(&guid);
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
 
-        info!("<---- end_dir_enum: return 0x0");
-        Ok(0)
+    pub fn auto_stop(&self) -> &AutoStop {
+        &self.auto_stop
     }
 
-    fn get_dir_enum(
-        &self,
-        data: &PRJ_CALLBACK_DATA,
-        enumeration_id: &GUID,
-        search_expression: PCWSTR,
-        handle: PRJ_DIR_ENTRY_BUFFER_HANDLE,
-    ) -> Result<HRESULT> {
-        let path = data.FilePathName.to_os();
-        let search_expression = search_expression.to_os();
-        info!(
-            "----> get_dir_enum: Path [{:?}] SearchExpression: [{:?}]",
-            path, search_expression
-        );
+    pub fn readonly(&self) -> bool {
+        self.readonly.load(Ordering::Acquire)
+    }
 
-        let guid = guid_to_bytes(enumeration_id);
-        let mut state = self
-            .state
-            .lock()
-            .map_err(|_| anyhow!("unable to acquire state"))?;
+    fn set_readonly(&self, readonly: bool) {
+        self.readonly.store(readonly, Ordering::Release);
+        // the hive summaries report the policy
+        self.clear_synthetic_content();
+    }
 
-        let dirinfo = match state.enum_sessions.get_mut(&guid) {
-            Some(session) => session,
-            None => return Ok(winerror::E_INVALIDARG),
-        };
+    pub fn suspended(&self) -> bool {
+        self.suspension.is_suspended()
+    }
 
-        if data.Flags & prjfs::sys::PRJ_CB_DATA_FLAG_ENUM_RESTART_SCAN != 0 {
-            dirinfo.reset();
+    fn suspend(&self) -> String {
+        match self.suspension.suspend() {
+            true => {
+                info!(target: "control", "suspended, callbacks are turned away until `resume`");
+                "suspended".to_string()
+            }
+            false => "already suspended".to_string(),
         }
+    }
 
-        if !dirinfo.filled() {
-            if !self.populate_dir_info_for_path(path, dirinfo, search_expression) {
-                return Err(anyhow!("failed to get key"));
-            }
+    // whatever changed meanwhile was never told to ProjFS, so every hive is brought back in
+    // sync with it
+    fn resume(&self) -> String {
+        if !self.suspension.resume() {
+            return "not suspended".to_string();
+        }
+        info!(target: "control", "resumed");
+        if self.context().is_null() {
+            return "resumed".to_string();
+        }
+        for hive in &self.options.hives {
+            self.spawn_resync(hive.name().into());
+        }
+        "resumed, resync started".to_string()
+    }
 
-            dirinfo.sort_entries_and_mark_filled();
+    pub fn clear_synthetic_content(&self) {
+        if let Ok(mut cache) = self.synthetic_content.lock() {
+            cache.clear();
         }
+    }
 
-        while dirinfo.current_is_valid() {
-            let result = unsafe {
-                prjfs::sys::PrjFillDirEntryBuffer(
-                    dirinfo.current_file_name().as_ptr(),
-                    &mut dirinfo.current_basic_info(),
-                    handle,
-                )
-            };
+    // --hide-empty-keys: every key is queried again the next time it's listed
+    pub fn forget_empty_keys(&self) {
+        if let Ok(mut cache) = self.empty_keys.lock() {
+            cache.clear();
+        }
+    }
 
-            if result != S_OK {
-                break;
-            }
+    // --enum-cache: the listings of `path`, of what's under it and of its parent; None for all
+    fn forget_listings(&self, path: Option<&Path>) {
+        match (&self.enum_cache, path) {
+            (Some(cache), Some(path)) => cache.invalidate(path),
+            (Some(cache), None) => cache.clear(),
+            (None, _) => {}
+        }
+    }
 
-            dirinfo.move_next();
+    // --value-cache: the value at `path`, or the values at and under the key there
+    fn forget_values(&self, path: Option<&Path>) {
+        match (&self.value_cache, path) {
+            (Some(cache), Some(path)) => cache.invalidate(path),
+            (Some(cache), None) => cache.clear(),
+            (None, _) => {}
         }
+    }
 
-        info!("<---- get_dir_enum: return {:08x}", 0);
-        Ok(S_OK)
+    // after a write through the mount, what was kept of `path`; None after all of them
+    pub fn forget_written(&self, path: Option<&Path>) {
+        self.forget_listings(path);
+        self.forget_values(path);
     }
 
-    fn get_placeholder_info(&self, data: &PRJ_CALLBACK_DATA) -> Result<HRESULT> {
-        let path = data.FilePathName.to_os();        
-        info!(
-            target: "placeholder",
-            "----> get_placeholder_info: Path [{:?}] triggered by {:?}]",
-            path,
-            data.TriggeringProcessImageFileName.to_os()
-        );
+    // enumeration ids and paths of the listings in progress
+    pub fn sessions(&self) -> Vec<(String, PathBuf)> {
+        let mut sessions: Vec<_> = self
+            .lock_state()
+            .enum_sessions
+            .iter()
+            .map(|(guid, session)| (control::guid_string(guid), session.path().to_owned()))
+            .collect();
+        sessions.sort();
+        sessions
+    }
 
-        let size: Option<i64> = if self.regops.does_key_exist(path.as_ref()) {
-            None
-        } else if let Some(size) = self.regops.does_value_exist(path.as_ref()) {
-            Some(size as i64)
-        } else {
-            info!(
-                "<---- get_place_holder_info: return {:08x}",
-                winerror::ERROR_FILE_NOT_FOUND
-            );
-            return Ok(winerror::HRESULT_FROM_WIN32(winerror::ERROR_FILE_NOT_FOUND));
-        };
+    // what `regfs status` shows, uptime in seconds
+    pub fn status(&self) -> serde_json::Value {
+        let uptime = (times::to_filetime(SystemTime::now()) - self.mount_time).max(0) / 10_000_000;
+        let sessions: Vec<serde_json::Value> = self
+            .sessions()
+            .into_iter()
+            .map(|(guid, path)| json!({ "enumeration": guid, "path": path.to_string_lossy() }))
+            .collect();
 
-        let mut placeholder = prjfs::sys::PRJ_PLACEHOLDER_INFO::default();
-        if let Some(size) = size {
-            placeholder.FileBasicInfo.IsDirectory = false as u8;
-            placeholder.FileBasicInfo.FileSize = size;
-        } else {
-            placeholder.FileBasicInfo.IsDirectory = true as u8;
-            placeholder.FileBasicInfo.FileSize = 0;
-        }
+        json!({
+            "root": self.root().to_string_lossy(),
+            "uptime": uptime,
+            "backend": self.options.backend_stack(),
+            "readonly": self.readonly(),
+            "suspended": self.suspended(),
+            "sessions": sessions,
+            "stats": self.metrics_snapshot().to_json(),
+            "throttled": self.rate_limits.to_json(),
+            "hydrations": self.hydration_gate.to_json(),
+        })
+    }
 
-        let result = self.write_placeholder_info(data.FilePathName, placeholder);
+    // `dump`: the provider's bookkeeping, for debugging a stuck mount; paths and counts
+    // only, no value data
+    pub fn dump(&self) -> serde_json::Value {
+        let state = self.lock_state().to_json();
+        let mut commands: Vec<(i32, bool)> = self
+            .commands
+            .lock()
+            .map(|commands| {
+                commands
+                    .iter()
+                    .map(|(id, cancelled)| (*id, cancelled.load(Ordering::Acquire)))
+                    .collect()
+            })
+            .unwrap_or_default();
+        commands.sort();
+        let commands: Vec<serde_json::Value> = commands
+            .into_iter()
+            .map(|(id, cancelled)| json!({ "command": id, "cancelled": cancelled }))
+            .collect();
+        let watchers: Vec<serde_json::Value> = match self.regops.watchers() {
+            Some(watchers) => self
+                .subscriptions
+                .iter()
+                .map(|id| json!({ "id": id, "subtree": watchers.subtree(*id) }))
+                .collect(),
+            None => Vec::new(),
+        };
+        let synthetic = self
+            .synthetic_content
+            .lock()
+            .map_or(0, |synthetic| synthetic.len());
+        let empty_keys = self.empty_keys.lock().map_or(0, |keys| keys.len());
+        let (listings, listed_entries) = self
+            .enum_cache
+            .as_ref()
+            .map_or((0, 0), |cache| cache.sizes());
+        let (values, value_bytes) = self
+            .value_cache
+            .as_ref()
+            .map_or((0, 0), |cache| cache.sizes());
 
-        info!(target: "placeholder", "<---- get_placeholder_info: {:08x}", result);
+        json!({
+            "sessions": state["sessions"],
+            "hydrations": self.hydrations.to_json(),
+            "commands": commands,
+            "caches": {
+                "synthetic_content": synthetic,
+                "empty_keys": empty_keys,
+                "listings": listings,
+                "listed_entries": listed_entries,
+                "values": values,
+                "value_bytes": value_bytes,
+                "content_ids": state["content_ids"],
+                "vanished_keys": state["vanished"],
+                "hydrations": self.hydrations.pending(),
+            },
+            "watchers": watchers,
+        })
+    }
 
-        Ok(result)
+    pub fn request_quit(&self) {
+        let (quit, condvar) = &self.quit;
+        *quit.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        condvar.notify_all();
     }
 
-    fn get_file_data(&self, data: &PRJ_CALLBACK_DATA, offset: u64, length: u32) -> Result<HRESULT> {
-        let path = data.FilePathName.to_os();
-        let process = data.TriggeringProcessImageFileName.to_os();
-        info!(
-            "----> get_file_data: Path[{:?}] triggered by [{:?}]",
-            path, process
+    pub fn wait_for_quit(&self) {
+        let (quit, condvar) = &self.quit;
+        let quit = quit.lock().unwrap_or_else(|e| e.into_inner());
+        drop(
+            condvar
+                .wait_while(quit, |quit| !*quit)
+                .unwrap_or_else(|e| e.into_inner()),
         );
+    }
 
-        let rawbuffer =
-            unsafe { prjfs::sys::PrjAllocateAlignedBuffer(self.context, length as usize) };
-        if rawbuffer.is_null() {
-            warn!("<---- get_file_data: Could not allocate write buffer.");
-            return Ok(winerror::E_OUTOFMEMORY);
-        }
-        let buffer =
-            unsafe { std::slice::from_raw_parts_mut(rawbuffer as *mut u8, length as usize) };
-
-        let hr = if let Some(bytes) = self.regops.read_value(path.as_ref()) {
-            buffer.copy_from_slice(&bytes);
-            unsafe {
-                prjfs::sys::PrjWriteFileData(
-                    self.context,
-                    &data.DataStreamId,
-                    rawbuffer,
-                    offset,
-                    length,
-                )
+    // registered before async work is queued, so a cancel that arrives first still counts
+    fn track_command(&self, command_id: i32) -> Arc<AtomicBool> {
+        match self.commands.lock() {
+            Ok(mut commands) => commands.entry(command_id).or_default().clone(),
+            Err(_) => Default::default(),
+        }
+    }
+
+    fn track_process(&self, data: &PRJ_CALLBACK_DATA) {
+        if self.user_hives.is_some() {
+            if let Ok(mut processes) = self.processes.lock() {
+                processes.insert(data.CommandId, data.TriggeringProcessId);
             }
-        } else {
-            winerror::HRESULT_FROM_WIN32(winerror::ERROR_FILE_NOT_FOUND)
-        };
+        }
+    }
 
-        unsafe {
-            prjfs::sys::PrjFreeAlignedBuffer(rawbuffer);
+    // --rate-limit: holds the callback for its turn, or turns it away when that's too far off
+    fn throttle(&self, data: &PRJ_CALLBACK_DATA) -> Result<(), HRESULT> {
+        if self.rate_limits.is_empty() {
+            return Ok(());
+        }
+        let process = wstr_or_empty(data.TriggeringProcessImageFileName);
+        match self.rate_limits.check(&process) {
+            Throttle::Go => Ok(()),
+            Throttle::Delayed(wait) => {
+                Metrics::add(&self.metrics.throttle_delays, 1);
+                thread::sleep(wait);
+                Ok(())
+            }
+            Throttle::Rejected => {
+                Metrics::add(&self.metrics.throttle_rejections, 1);
+                info!("throttled [{:?}]", process);
+                Err(HRESULT_FROM_WIN32(winerror::ERROR_RETRY))
+            }
         }
-        info!("<---- get_file_data: return {:08x}", hr);
-        Ok(hr)
     }
 
-    fn notify(
-        &self,
-        data: &PRJ_CALLBACK_DATA,
-        _is_directory: bool,
-        notification_type: prjfs::sys::PRJ_NOTIFICATION,
-        destination_file_name: PCWSTR,
-        _parameters: &PRJ_NOTIFICATION_PARAMETERS,
-    ) -> Result<HRESULT> {
-        let filepath = data.FilePathName.to_os();
-        let process = data.TriggeringProcessImageFileName.to_os();
-        info!(
-            "---> notify: Path [{:?}] triggered by [{:?}]",
-            filepath, process
-        );
-        info!("--- Notification: 0x{:08x}", notification_type);
+    // `suspend`: held for a short while in case it's resumed, then turned away to come back later
+    fn check_suspended(&self) -> Result<(), HRESULT> {
+        match self.suspension.wait_for_resume(suspend::WAIT) {
+            true => Ok(()),
+            false => Err(HRESULT_FROM_WIN32(winerror::ERROR_RETRY)),
+        }
+    }
 
-        match notification_type {
-            prjfs::sys::PRJ_NOTIFICATION_FILE_OPENED => Ok(S_OK),
-            prjfs::sys::PRJ_NOTIFICATION_FILE_HANDLE_CLOSED_FILE_MODIFIED
-            | prjfs::sys::PRJ_NOTIFICATION_FILE_OVERWRITTEN => {
-                info!(" ----- [{:?}] was modified", filepath);
-                Ok(S_OK)
+    // --max-hydrations: held for a turn, or turned away when none comes soon enough
+    fn admit_hydration(&self, process: &OsStr, length: u32) -> Result<HydrationPermit, HRESULT> {
+        match self.hydration_gate.admit(process, length) {
+            Admission::Go(permit) => Ok(permit),
+            Admission::Waited(permit, wait) => {
+                Metrics::add(&self.metrics.hydration_waits, 1);
+                debug!("[{:?}] waited {:?} for a hydration", process, wait);
+                Ok(permit)
             }
-            prjfs::sys::PRJ_NOTIFY_NEW_FILE_CREATED => {
-                info!(" ----- [{:?}] was created", filepath);
-                Ok(S_OK)
-            }
-            prjfs::sys::PRJ_NOTIFY_FILE_RENAMED => {
-                info!(
-                    " ----- [{:?}] -> [{:?}]",
-                    filepath,
-                    destination_file_name.to_os()
-                );
-                Ok(S_OK)
-            }
-            prjfs::sys::PRJ_NOTIFY_FILE_HANDLE_CLOSED_FILE_DELETED => {
-                info!(" ----- [{:?}] was deleted", filepath);
-                Ok(S_OK)
-            }
-            prjfs::sys::PRJ_NOTIFICATION_PRE_RENAME => {
-                if self.readonly {
-                    info!(" ----- rename request for [{:?}] was rejected", filepath);
-                    Ok(HRESULT_FROM_WIN32(winerror::ERROR_ACCESS_DENIED))
-                } else {
-                    info!(" ----- rename request for [{:?}]", filepath);
-                    Ok(S_OK)
-                }
+            Admission::Rejected => {
+                Metrics::add(&self.metrics.hydration_rejections, 1);
+                info!("too many hydrations, [{:?}] turned away", process);
+                Err(HRESULT_FROM_WIN32(winerror::ERROR_RETRY))
             }
-            prjfs::sys::PRJ_NOTIFICATION_PRE_DELETE => {
-                if self.readonly {
-                    info!(" ----- delete request for [{:?}] was rejected", filepath);
-                    Ok(HRESULT_FROM_WIN32(winerror::ERROR_ACCESS_DENIED))
-                } else {
-                    info!(" ----- delete request for [{:?}]", filepath);
-                    Ok(S_OK)
+        }
+    }
+
+    // registry work from callbacks runs on the pool; giving up on it cancels the job too
+    fn on_registry<T, F>(&self, command_id: i32, f: F) -> Result<T, Cancelled>
+    where
+        T: Send + 'static,
+        F: FnOnce(&RegFs, &OpContext) -> Result<T, Cancelled> + Send + 'static,
+    {
+        let regfs = self.clone();
+        let flag = self.track_command(command_id);
+        let cancel = flag.clone();
+        let process = match self.processes.lock() {
+            Ok(processes) => processes.get(&command_id).copied().unwrap_or(0),
+            Err(_) => 0,
+        };
+
+        let result = self.pool.run(self.options.registry_timeout, move || {
+            let ctx = OpContext::new(flag, None, &regfs.metrics);
+            let users = match &regfs.user_hives {
+                Some(users) => users,
+                None => return f(&regfs, &ctx),
+            };
+
+            // runs as the process's user until `user` is dropped, and fails closed
+            match users.impersonate(process) {
+                Ok(user) => f(&regfs, &ctx.with_user(UserHive::Process(&user.hive))),
+                Err(e) => {
+                    warn!(
+                        "on_registry: command {}: can't impersonate process {}: {}",
+                        command_id, process, e
+                    );
+                    f(&regfs, &ctx.with_user(UserHive::Denied))
                 }
             }
-            prjfs::sys::PRJ_NOTIFICATION_FILE_PRE_CONVERT_TO_FULL => Ok(S_OK),
-            t => {
-                warn!("notify: Unexpected notification: 0x{:08x}", t);
-                Ok(S_OK)
+        });
+
+        result.unwrap_or_else(|e| {
+            warn!("on_registry: command {}: {}", command_id, e);
+            cancel.store(true, Ordering::Release);
+            Err(Cancelled)
+        })
+    }
+
+    // the same for control verbs, which have no command to be cancelled with: given up on
+    // after --registry-timeout
+    pub fn run_on_registry<T, F>(&self, f: F) -> Result<T, Cancelled>
+    where
+        T: Send + 'static,
+        F: FnOnce(&RegFs, &OpContext) -> Result<T, Cancelled> + Send + 'static,
+    {
+        let regfs = self.clone();
+        let flag = Arc::new(AtomicBool::new(false));
+        let cancel = flag.clone();
+
+        let result = self.pool.run(self.options.registry_timeout, move || {
+            f(&regfs, &OpContext::new(flag, None, &regfs.metrics))
+        });
+        result.unwrap_or_else(|e| {
+            warn!("run_on_registry: {}", e);
+            cancel.store(true, Ordering::Release);
+            Err(Cancelled)
+        })
+    }
+
+    pub fn metrics_snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            registry_panics: self.pool.panics(),
+            registry_queue_depth: self.pool.queued() as u64,
+            search_sessions: self.searches.len() as u64,
+            suspended: self.suspended() as u64,
+            ..self.metrics.snapshot()
+        }
+    }
+
+    pub fn emit(&self, event: RegFsEvent) {
+        let event = match &self.aliases {
+            Some(_) => event.map_paths(|path| self.canonical(&path)),
+            None => event,
+        };
+        self.observers.event(&event);
+        if let Some(events) = &self.options.events {
+            if events.try_send(event).is_err() {
+                Metrics::add(&self.metrics.dropped_events, 1);
             }
         }
     }
 
-    fn query_file_name(&self, _data: &PRJ_CALLBACK_DATA) -> Result<HRESULT> {
-        Ok(S_OK)
+    fn end_command(&self, command_id: i32) {
+        if let Ok(mut commands) = self.commands.lock() {
+            commands.remove(&command_id);
+        }
+        if let Ok(mut processes) = self.processes.lock() {
+            processes.remove(&command_id);
+        }
     }
+}
 
-    fn cancel_command(&self, _data: &PRJ_CALLBACK_DATA) -> Result<()> {
-        Ok(())
+// the enumeration buffer of one get_dir_enum
+struct ProjFsSink<'a> {
+    projfs: &'a dyn PrjApi,
+    handle: PRJ_DIR_ENTRY_BUFFER_HANDLE,
+    // the ones in the directory listed, listed as symlinks
+    bookmarks: &'a [Bookmark],
+    added: usize,
+}
+
+impl<'a> ProjFsSink<'a> {
+    fn new(
+        projfs: &'a dyn PrjApi,
+        handle: PRJ_DIR_ENTRY_BUFFER_HANDLE,
+        bookmarks: &'a [Bookmark],
+    ) -> Self {
+        ProjFsSink {
+            projfs,
+            handle,
+            bookmarks,
+            added: 0,
+        }
+    }
+}
+
+impl DirEntrySink for ProjFsSink<'_> {
+    fn add(&mut self, name: &OsStr, info: &mut PRJ_FILE_BASIC_INFO) -> SinkResult {
+        let target = symlink_target(name)
+            .or_else(|| bookmarks::symlink_target(self.bookmarks, Path::new(name)));
+        // listed the way write_placeholder_info projects it
+        if target.is_some() && self.projfs.extended_enum_supported() {
+            info.FileSize = 0;
+            info.IsDirectory = true as u8;
+        }
+        let name = name.to_os_string().to_wstr();
+        let hr = unsafe {
+            self.projfs
+                .fill_dir_entry(self.handle, name.as_ptr(), info, target.as_deref())
+        };
+
+        let result = SinkResult::of(hr, self.added);
+        if result == SinkResult::Added {
+            self.added += 1;
+        }
+        result
+    }
+}
+
+// no buffer without ProjFS; a replay's is as big as the recorded one was
+struct DryRunSink<'a> {
+    tracer: Option<&'a Tracer>,
+    added: usize,
+}
+
+impl DirEntrySink for DryRunSink<'_> {
+    fn add(&mut self, _name: &OsStr, _info: &mut PRJ_FILE_BASIC_INFO) -> SinkResult {
+        let hr = self
+            .tracer
+            .map_or(S_OK, |tracer| tracer.fill_dir_entry(self.added));
+
+        let result = SinkResult::of(hr, self.added);
+        if result == SinkResult::Added {
+            self.added += 1;
+        }
+        result
+    }
+}
+
+// values named `bruh` are projected as a symlink to the `Keyboard` next to them, where
+// ProjFS has symlinks; the NUL terminated target
+fn symlink_target(path: &OsStr) -> Option<Vec<u16>> {
+    path.to_string_lossy()
+        .ends_with("bruh")
+        .then(|| "Keyboard\0".encode_utf16().collect())
+}
+
+// what paths::normalize has, lowercased; made from the parts as they are, since it's
+// taken for most callbacks
+pub fn path_key(path: &Path) -> String {
+    let units: Vec<u16> = path.as_os_str().encode_wide().collect();
+    let mut key = String::with_capacity(units.len());
+    for (index, part) in paths::wide_parts(&units).enumerate() {
+        if index > 0 {
+            key.push('\\');
+        }
+        key.extend(
+            char::decode_utf16(part.iter().copied())
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)),
+        );
     }
+    key.to_lowercase()
+}
+
+// the bytes guid_to_bytes has, without a Vec for every callback that only looks a session up
+pub fn guid_key(guid: &GUID) -> [u8; 16] {
+    let mut key = [0; 16];
+    key[..4].copy_from_slice(&guid.Data1.to_le_bytes());
+    key[4..6].copy_from_slice(&guid.Data2.to_le_bytes());
+    key[6..8].copy_from_slice(&guid.Data3.to_le_bytes());
+    key[8..].copy_from_slice(&guid.Data4);
+    key
+}
+
+impl RegFs {
+    pub fn write_placeholder_info(&self, filepath: LPCWSTR, info: PRJ_PLACEHOLDER_INFO) -> HRESULT {
+        self.write_placeholder(filepath, &filepath.to_os(), info)
+    }
+
+    // `path` is `filepath`, for a callback that has it already
+    fn write_placeholder(
+        &self,
+        filepath: LPCWSTR,
+        path: &OsStr,
+        mut info: PRJ_PLACEHOLDER_INFO,
+    ) -> HRESULT {
+        let target = symlink_target(path)
+            .or_else(|| bookmarks::symlink_target(&self.bookmark_links, Path::new(path)));
+        if target.is_some() {
+            info!(target: "placeholder", "about to do something dangerous");
+            info.FileBasicInfo.FileSize = 0;
+            info.FileBasicInfo.IsDirectory = true as u8;
+        }
+        let result = match self.dry_run() {
+            true => S_OK,
+            false => unsafe {
+                self.projfs
+                    .write_placeholder(self.context(), filepath, &info, target.as_deref())
+            },
+        };
+
+        if result == S_OK && info.FileBasicInfo.IsDirectory == 0 {
+            self.record_size(path.as_ref(), info.FileBasicInfo.FileSize as u64);
+        }
+        result
+    }
+
+    // None for what doesn't exist, and for what the entry filter hides
+    pub fn placeholder_info(&self, path: &Path) -> Option<PRJ_PLACEHOLDER_INFO> {
+        let placeholder = self.unfiltered_placeholder_info(path)?;
+        (!self.is_filtered(path, &placeholder)).then_some(placeholder)
+    }
+
+    // our own files are never filtered
+    fn is_filtered(&self, path: &Path, placeholder: &PRJ_PLACEHOLDER_INFO) -> bool {
+        let (filter, name) = match (&self.options.filter, path.file_name()) {
+            (Some(filter), Some(name)) if self.synthetic(path).is_none() => (filter, name),
+            _ => return false,
+        };
+        let entry = ProjectedEntry {
+            name,
+            is_directory: placeholder.FileBasicInfo.IsDirectory != 0,
+            size: placeholder.FileBasicInfo.FileSize as u64,
+        };
+
+        !filter.include(path.parent().unwrap_or(Path::new("")), &entry)
+    }
+
+    fn unfiltered_placeholder_info(&self, path: &Path) -> Option<PRJ_PLACEHOLDER_INFO> {
+        let mut placeholder = PRJ_PLACEHOLDER_INFO::default();
+
+        if let Some(synthetic) = self.synthetic(path) {
+            placeholder.FileBasicInfo.IsDirectory = synthetic.is_directory() as u8;
+            placeholder.FileBasicInfo.FileSize =
+                self.synthetic_content(path, synthetic).len() as i64;
+            placeholder.FileBasicInfo.FileAttributes = self.synthetic_attributes(path);
+        } else if let Some(last_write_time) = self.regops.key_last_write_time(path) {
+            placeholder.FileBasicInfo.IsDirectory = true as u8;
+            placeholder.FileBasicInfo.FileSize = 0;
+            let time = self.options.value_times.key_time(last_write_time);
+            times::set_times(&mut placeholder.FileBasicInfo, time);
+        } else if let Ok(Some(bytes)) = self.read_projected_value(path, &OpContext::none()) {
+            placeholder.FileBasicInfo.IsDirectory = false as u8;
+            placeholder.FileBasicInfo.FileSize = bytes.len() as i64;
+            placeholder.VersionInfo.ContentID = hash::content_id(&bytes);
+            times::set_times(&mut placeholder.FileBasicInfo, self.value_time(path));
+        } else {
+            return None;
+        }
+
+        Some(placeholder)
+    }
+
+    // what the filter decides on, whether it's a directory and its size, and nothing else:
+    // only a value a transformer may change is read for it, the way listings size them
+    fn sized_placeholder_info(&self, path: &Path) -> Option<PRJ_PLACEHOLDER_INFO> {
+        let mut placeholder = PRJ_PLACEHOLDER_INFO::default();
+
+        if let Some(synthetic) = self.synthetic(path) {
+            placeholder.FileBasicInfo.IsDirectory = synthetic.is_directory() as u8;
+            placeholder.FileBasicInfo.FileSize =
+                self.synthetic_content(path, synthetic).len() as i64;
+        } else if RegPath::parse(path).is_root() || self.regops.does_key_exist(path) {
+            placeholder.FileBasicInfo.IsDirectory = true as u8;
+        } else if let Some(size) = self.regops.does_value_exist(path) {
+            placeholder.FileBasicInfo.FileSize = self.transformed_size(path, size as u64) as i64;
+        } else {
+            return None;
+        }
+
+        Some(placeholder)
+    }
+
+    // query_file_name: a wildcard in the last part matches what a listing of the parent with
+    // it as the search expression has, the entries only the projection has included
+    fn query_exists(&self, path: &Path, command_id: i32) -> Result<bool, Cancelled> {
+        let name = match path.file_name() {
+            Some(name) if self.projfs.contains_wildcards(name) => name.to_os_string(),
+            _ => {
+                let path = path.to_path_buf();
+                return self.on_registry(command_id, move |regfs, _| Ok(regfs.path_exists(&path)));
+            }
+        };
+        let parent = path.parent().unwrap_or(Path::new(""));
+        let mut dirinfo = DirInfo::new(parent);
+        let listed =
+            self.populate_dir_info_for_path(parent.into(), &mut dirinfo, name, command_id)?;
+        Ok(listed && !dirinfo.entries().is_empty())
+    }
+
+    // without reading the value; what the filter hides isn't there by name either
+    fn path_exists(&self, path: &Path) -> bool {
+        if self.options.filter.is_some() {
+            return self
+                .sized_placeholder_info(path)
+                .is_some_and(|placeholder| !self.is_filtered(path, &placeholder));
+        }
+        RegPath::parse(path).is_root()
+            || self.synthetic(path).is_some()
+            || self.regops.does_key_exist(path)
+            || self.regops.does_value_exist(path).is_some()
+    }
+
+    // --value-cache: what an earlier hydration read, or a read that's kept for the next ones
+    fn read_cached_value(
+        &self,
+        path: &Path,
+        command_id: i32,
+    ) -> Result<Option<Vec<u8>>, Cancelled> {
+        let mode = self.options.transformers.scope_of(path);
+        let generation = match &self.value_cache {
+            Some(cache) => match cache.get(path, mode, &self.metrics) {
+                Some(bytes) => return Ok(Some(bytes.to_vec())),
+                None => cache.generation(),
+            },
+            None => 0,
+        };
+
+        let file = path.to_path_buf();
+        let bytes = self.on_registry(command_id, move |regfs, ctx| {
+            regfs.read_projected_value(&file, ctx)
+        })?;
+        if let (Some(cache), Some(bytes)) = (&self.value_cache, &bytes) {
+            cache.insert(
+                path,
+                mode,
+                Arc::new(bytes.clone()),
+                generation,
+                &self.metrics,
+            );
+        }
+        Ok(bytes)
+    }
+
+    // what a listing of `path` shows, sorted, without ProjFS asking; None when the key is gone
+    pub fn list_projected(&self, path: &Path) -> Result<Option<Vec<DirEntry>>, Cancelled> {
+        let mut dirinfo = DirInfo::new(path);
+        if !self.populate_dir_info_for_path(path.into(), &mut dirinfo, "*".into(), 0)? {
+            return Ok(None);
+        }
+        dirinfo.sort_entries_and_mark_filled(self.projfs());
+        Ok(Some(dirinfo.into_entries()))
+    }
+
+    // a value's file content: its data, or what a transformer in scope makes of it
+    pub fn read_projected_value(
+        &self,
+        path: &Path,
+        ctx: &OpContext,
+    ) -> Result<Option<Vec<u8>>, Cancelled> {
+        let value = self.regops.read_typed_value_ctx(path, ctx)?;
+        Ok(value.map(|(vtype, raw)| self.options.transformers.apply(path, vtype, raw)))
+    }
+
+    // must agree with the times populate_dir_info_for_path hands out
+    fn value_time(&self, path: &Path) -> i64 {
+        let value_times = self.options.value_times;
+        let parent_time = match value_times {
+            ValueTimes::Parent => RegPath::parse(path)
+                .parent()
+                .and_then(|parent| self.regops.key_last_write_time(&parent.to_path()))
+                .unwrap_or(0),
+            ValueTimes::Mount | ValueTimes::None => 0,
+        };
+
+        value_times.value_time(parent_time, self.mount_time)
+    }
+
+    // a callback that panicked while holding the lock may have left any enumeration
+    // half filled, so those are dropped instead of served; the rest is kept
+    fn lock_state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|poisoned| {
+            if !self.state_recovered.swap(true, Ordering::AcqRel) {
+                error!(
+                    "!!!! state lock was poisoned by a panicking callback, dropping every \
+                     enumeration session and recovering"
+                );
+            } else {
+                warn!("state lock was poisoned again, dropping every enumeration session");
+            }
+
+            let mut state = poisoned.into_inner();
+            state.enum_sessions.clear();
+            self.state.clear_poison();
+            state
+        })
+    }
+
+    // the built-in policies first, then whatever the embedder registered
+    pub fn decide(&self, request: &MutationRequest) -> Decision {
+        // policies, the embedder's too, only ever see the hives' own names
+        let path = self.canonical(request.path);
+        let destination = request
+            .destination
+            .map(|destination| self.canonical(destination));
+        let request = &MutationRequest {
+            path: &path,
+            destination: destination.as_deref(),
+            ..*request
+        };
+        let frozen = policy::Frozen(!self.regops.writable());
+        let suspended = policy::Suspended(self.suspended());
+        let readonly = policy::ReadOnly(self.readonly());
+        let protected = policy::ProtectedHives(&self.options.hives);
+        let merged = policy::MergedRoot(self.options.user_classes.as_deref().map(root_name));
+        let generated = policy::GeneratedDirs(
+            [
+                (self.options.search_dir, synthetic::SEARCH_DIR),
+                (self.options.recent_dir, synthetic::RECENT_DIR),
+            ]
+            .into_iter()
+            .filter_map(|(enabled, dir)| enabled.then_some(dir))
+            .collect(),
+        );
+        let bookmarks = policy::BookmarkRoots(&self.options.bookmarks);
+        let hardlinks = policy::Hardlinks(self.options.allow_hardlinks);
+        let transformed = policy::Transformed(&self.options.transformers);
+        let allowlist = policy::ProcessAllowlist(&self.options.allowed_processes);
+        let mut policies: Vec<&dyn MutationPolicy> = vec![
+            &frozen,
+            &suspended,
+            &readonly,
+            &protected,
+            &merged,
+            &bookmarks,
+            &generated,
+            &hardlinks,
+            &transformed,
+        ];
+        if !self.options.allowed_processes.is_empty() {
+            policies.push(&allowlist);
+        }
+        policies.extend(self.options.policy.as_deref());
+
+        Chain(policies).decide(request)
+    }
+
+    pub fn pre_mutation(&self, request: &MutationRequest) -> HRESULT {
+        match self.decide(request) {
+            Decision::Allow => {
+                info!(
+                    " ----- {:?} request for [{:?}] (directory: {})",
+                    request.kind, request.path, request.is_directory
+                );
+                S_OK
+            }
+            Decision::Deny(reason) => {
+                info!(
+                    " ----- {:?} request for [{:?}] (directory: {}) was rejected: {}",
+                    request.kind, request.path, request.is_directory, reason
+                );
+                self.emit(RegFsEvent::OperationDenied {
+                    path: request.path.to_owned(),
+                    reason: reason.into_owned(),
+                });
+                HRESULT_FROM_WIN32(winerror::ERROR_ACCESS_DENIED)
+            }
+        }
+    }
+
+    pub fn recorded_content_id(&self, path: &Path) -> Option<u64> {
+        self.lock_state().content_ids.get(&path_key(path)).copied()
+    }
+
+    pub fn record_content_id(&self, path: &Path, content_id: u64) {
+        self.lock_state()
+            .content_ids
+            .insert(path_key(path), content_id);
+    }
+
+    pub fn forget_content_id(&self, path: &Path) {
+        let key = path_key(path);
+        let mut state = self.lock_state();
+        state.content_ids.remove(&key);
+        state.sizes.remove(&key);
+    }
+
+    // the size a value's placeholder was written with, which is all ProjFS will take of it
+    pub fn advertised_size(&self, path: &Path) -> Option<u64> {
+        self.lock_state().sizes.get(&path_key(path)).copied()
+    }
+
+    pub fn record_size(&self, path: &Path, size: u64) {
+        self.lock_state().sizes.insert(path_key(path), size);
+    }
+
+    pub fn mark_overwritten(&self, path: &Path) {
+        self.lock_state().overwritten.insert(path_key(path));
+    }
+
+    // whether the file was overwritten since it was opened, forgotten once asked
+    pub fn take_overwritten(&self, path: &Path) -> bool {
+        self.lock_state().overwritten.remove(&path_key(path))
+    }
+
+    pub fn synthetic(&self, path: &Path) -> Option<Synthetic> {
+        match Synthetic::from_path(path)? {
+            Synthetic::ValuesJson if !self.options.values_json => None,
+            Synthetic::Truncated if self.options.max_entries_per_dir.is_none() => None,
+            Synthetic::MetaJson
+                if self.virtual_store.is_none()
+                    || virtualstore::redirect(path.parent()?).is_none() =>
+            {
+                None
+            }
+            Synthetic::HiveSummary
+                if !self.options.hive_summary || !self.regops.does_key_exist(path.parent()?) =>
+            {
+                None
+            }
+            Synthetic::SearchDir | Synthetic::SearchQuery if !self.options.search_dir => None,
+            Synthetic::SearchHit if !self.options.search_dir || !self.is_search_hit(path) => None,
+            Synthetic::RecentDir if !self.options.recent_dir => None,
+            Synthetic::RecentChange
+                if !self.options.recent_dir || self.recent.find(path.file_name()?).is_none() =>
+            {
+                None
+            }
+            synthetic => Some(synthetic),
+        }
+    }
+
+    // a hit's name is the path it stands for, which has to be there still and be something
+    // its pattern finds
+    fn is_search_hit(&self, path: &Path) -> bool {
+        let (pattern, target) = match (
+            path.parent().and_then(Path::file_name),
+            path.file_name().and_then(searchdir::hit_path),
+        ) {
+            (Some(pattern), Some(target)) => (pattern, target),
+            _ => return false,
+        };
+        let found = target.file_name().map_or(false, |name| {
+            Pattern::new(self.projfs(), pattern).matches(name)
+        });
+        found
+            && (self.regops.does_key_exist(&target)
+                || self.regops.does_value_exist(&target).is_some())
+    }
+
+    // --search-dir: the hits for a pattern directory, searched for once while it's listed
+    fn search_results(
+        &self,
+        path: &OsStr,
+        command_id: i32,
+    ) -> Result<Arc<searchdir::SearchResults>, Cancelled> {
+        let key = path_key(path.as_ref());
+        if let Some(results) = self.searches.get(&key) {
+            return Ok(results);
+        }
+
+        let pattern = Path::new(path).file_name().unwrap_or_default().to_owned();
+        let limits = self.options.search_limits;
+        let results = self.on_registry(command_id, move |regfs, ctx| {
+            let pattern = Pattern::new(regfs.projfs(), &pattern);
+            searchdir::find(regfs.regops(), &pattern, limits, ctx)
+        })?;
+        Metrics::add(&self.metrics.searches, 1);
+        if results.truncated {
+            info!(
+                "search for [{:?}] stopped at {} results",
+                path, limits.max_results
+            );
+        }
+        Ok(self.searches.insert(key, results))
+    }
+
+    // the last enumeration of a pattern directory is done with its results
+    fn end_search(&self, path: &Path) {
+        let key = path_key(path);
+        let listed = self
+            .lock_state()
+            .enum_sessions
+            .values()
+            .any(|session| path_key(session.path()) == key);
+        if !listed {
+            self.searches.remove(&key);
+        }
+    }
+
+    // --recent-dir: the watchers saw `path` change
+    fn note_recent(&self, path: &Path) {
+        let kind = match self.regops.does_key_exist(path) {
+            true => RecentKind::Modified,
+            false => RecentKind::Deleted,
+        };
+        let now = SystemTime::now();
+        let mut dropped = self.recent.record(path, kind, now);
+        dropped.extend(self.recent.expire(now));
+        self.forget_recent(&dropped);
+    }
+
+    // their names are never listed again, so their placeholders have to go
+    fn forget_recent(&self, changes: &[RecentChange]) {
+        if self.context().is_null() {
+            return;
+        }
+        for change in changes {
+            self.refresh_synthetic(&Path::new(synthetic::RECENT_DIR).join(change.name()));
+        }
+    }
+
+    fn render_synthetic(&self, path: &Path, synthetic: Synthetic) -> Vec<u8> {
+        match synthetic {
+            Synthetic::StatsFile => self.metrics_snapshot().to_string().into_bytes(),
+            Synthetic::ValuesJson => {
+                let key = path.parent().unwrap_or_else(|| Path::new(""));
+                let values = self.regops.read_all_values(key).unwrap_or_default();
+                render::values_json(&values, self.options.values_json_max_size)
+            }
+            Synthetic::HiveSummary => {
+                let hive = path.parent().unwrap_or_else(|| Path::new(""));
+                let info = self.regops.key_info(hive).unwrap_or_default();
+                let hive = self.canonical(hive);
+                render::hive_summary_json(&hive.to_string_lossy(), &info, self.readonly())
+            }
+            Synthetic::MetaJson => {
+                let key = path.parent().unwrap_or_else(|| Path::new(""));
+                let merged = self
+                    .virtual_store
+                    .as_ref()
+                    .and_then(|virtual_store| virtual_store.merge(key, &OpContext::none()).ok())
+                    .flatten()
+                    .map(|(_, merged)| merged)
+                    .unwrap_or_default();
+                let store = virtualstore::redirect(key).unwrap_or_default();
+                render::meta_json(&store, &merged)
+            }
+            Synthetic::Truncated => {
+                let more = path
+                    .file_name()
+                    .and_then(synthetic::truncated_count)
+                    .unwrap_or(0);
+                format!(
+                    "{} more entries aren't listed (--max-entries-per-dir {}), \
+                     they can still be opened by name\n",
+                    more,
+                    self.options.max_entries_per_dir.unwrap_or(0)
+                )
+                .into_bytes()
+            }
+            Synthetic::SearchHit => path
+                .file_name()
+                .and_then(searchdir::hit_path)
+                .map(|target| target.to_string_lossy().into_owned().into_bytes())
+                .unwrap_or_default(),
+            Synthetic::RecentChange => path
+                .file_name()
+                .and_then(|name| self.recent.find(name))
+                .map(|change| change.content())
+                .unwrap_or_default(),
+            Synthetic::ControlDir
+            | Synthetic::ControlFile
+            | Synthetic::SearchDir
+            | Synthetic::SearchQuery
+            | Synthetic::RecentDir => Vec::new(),
+        }
+    }
+
+    pub fn synthetic_content(&self, path: &Path, synthetic: Synthetic) -> Vec<u8> {
+        if !synthetic.is_dynamic() {
+            return self.render_synthetic(path, synthetic);
+        }
+
+        match self.synthetic_content.lock() {
+            Ok(mut cache) => cache
+                .entry(path_key(path))
+                .or_insert_with(|| self.render_synthetic(path, synthetic))
+                .clone(),
+            Err(_) => self.render_synthetic(path, synthetic),
+        }
+    }
+
+    // generated files about the changed key are stale now; resync takes care of the rest
+    fn registry_changed(&self, path: &Path) {
+        Metrics::add(&self.metrics.registry_changes, 1);
+
+        // the registry's path, listed under the hive's alias
+        let mounted = self.mounted(path);
+        self.forget_cached(&mounted);
+        if self.options.recent_dir {
+            self.note_recent(path);
+        }
+        // only HKEY_USERS itself is told when a hive under it comes or goes
+        if path_key(path) == path_key(RootHive::Users.name().as_ref()) && !self.context().is_null()
+        {
+            self.spawn_resync_users();
+        }
+        // the VirtualStore copy is part of what HKLM\SOFTWARE shows
+        if self.virtual_store.is_some() {
+            if let Some(machine) = virtualstore::machine_path(path) {
+                self.forget_cached(&self.mounted(&machine));
+            }
+        }
+        if let Some(merged) = self
+            .user_classes
+            .as_ref()
+            .and_then(|user_classes| user_classes.merged_path(path))
+        {
+            self.forget_cached(&merged);
+        }
+        if let Some(bookmarks) = &self.bookmarks {
+            for bookmarked in bookmarks.bookmarked_paths(path) {
+                self.forget_cached(&bookmarked);
+            }
+        }
+
+        // a key a listing found gone still has its placeholder, under a parent that has
+        // changed now
+        let vanished = self.take_vanished(&mounted);
+        if !vanished.is_empty() && !self.context().is_null() {
+            for parent in vanished.iter().filter_map(|key| key.parent()) {
+                self.spawn_resync(parent.to_path_buf());
+            }
+        }
+    }
+
+    // the vanished keys at or under `path`
+    fn take_vanished(&self, path: &Path) -> Vec<PathBuf> {
+        let key = path_key(path);
+        let children = format!("{}\\", key);
+        let mut state = self.lock_state();
+        let taken: Vec<String> = state
+            .vanished
+            .keys()
+            .filter(|vanished| **vanished == key || vanished.starts_with(&children))
+            .cloned()
+            .collect();
+        taken
+            .iter()
+            .filter_map(|vanished| state.vanished.remove(vanished))
+            .collect()
+    }
+
+    fn forget_cached(&self, path: &Path) {
+        self.forget_listings(Some(path));
+        self.forget_values(Some(path));
+        let key = path_key(path);
+        let children = format!("{}\\", key);
+        if let Ok(mut cache) = self.synthetic_content.lock() {
+            cache.retain(|cached, _| *cached != key && !cached.starts_with(&children));
+        }
+        // the key and whatever holds it may not be empty anymore, or be empty now
+        if let Ok(mut cache) = self.empty_keys.lock() {
+            cache.retain(|cached, _| {
+                *cached != key
+                    && !cached.starts_with(&children)
+                    && !key.starts_with(&format!("{}\\", cached))
+            });
+        }
+    }
+
+    // --hide-empty-keys: no subkeys and no values, not even a default one; a key that can't
+    // be queried is shown. Adding or removing either moves the key's last write time, so
+    // the answer is kept for as long as that doesn't change
+    fn is_empty_key(&self, path: &Path, last_write_time: i64) -> bool {
+        let key = path_key(path);
+        if let Ok(cache) = self.empty_keys.lock() {
+            match cache.get(&key) {
+                Some((time, empty)) if *time == last_write_time => return *empty,
+                _ => {}
+            }
+        }
+
+        let info = match self.regops.key_info(path) {
+            Some(info) => info,
+            None => return false,
+        };
+        let empty = info.subkeys == 0 && info.values == 0;
+        if let Ok(mut cache) = self.empty_keys.lock() {
+            if cache.len() >= EMPTY_KEYS {
+                cache.clear();
+            }
+            cache.insert(key, (last_write_time, empty));
+        }
+        empty
+    }
+
+    fn refresh_synthetic(&self, path: &Path) {
+        if let Ok(mut cache) = self.synthetic_content.lock() {
+            cache.remove(&path_key(path));
+        }
+
+        // drop the placeholder so the next open asks for fresh content; done on the pool,
+        // off the notification thread, since the file system is still processing the close
+        let regfs = self.clone();
+        let deleted = path.to_owned();
+        let submitted = self.pool.submit(move || {
+            let path = deleted.as_os_str().to_os_string().to_wstr();
+            let mut failure = 0;
+            let result = unsafe {
+                regfs.projfs.delete_file(
+                    regfs.context(),
+                    path.as_ptr(),
+                    prjfs::sys::PRJ_UPDATE_NONE,
+                    &mut failure,
+                )
+            };
+
+            if result != S_OK {
+                warn!(
+                    "refresh_synthetic: unable to delete [{:?}]: {:08x} (0x{:x})",
+                    deleted, result, failure
+                );
+            }
+        });
+        if let Err(e) = submitted {
+            warn!("refresh_synthetic: [{:?}] kept: {}", path, e);
+        }
+    }
+
+    // returns what to show whoever sent the command
+    pub fn execute(&self, command: ControlCommand) -> String {
+        info!(target: "control", "executing {:?}", command);
+
+        match command {
+            ControlCommand::Resync(path) => {
+                self.spawn_resync(path);
+                "resync started".to_string()
+            }
+            ControlCommand::Dehydrate(path) => {
+                self.spawn_dehydrate(path);
+                "dehydrate started".to_string()
+            }
+            ControlCommand::Hydrate(path) => {
+                self.spawn_hydrate(path);
+                "hydrate started".to_string()
+            }
+            ControlCommand::Stats => self.metrics_snapshot().to_string(),
+            ControlCommand::Status => control::status_table(&self.status()),
+            ControlCommand::Dump => {
+                let dump = serde_json::to_string_pretty(&self.dump()).unwrap_or_default();
+                info!(target: "control", "dump: {}", dump);
+                dump
+            }
+            ControlCommand::Sessions => {
+                let sessions = self.sessions();
+                if sessions.is_empty() {
+                    return "no enumerations in progress".to_string();
+                }
+
+                sessions
+                    .iter()
+                    .map(|(guid, path)| format!("{} {:?}\n", guid, path))
+                    .collect()
+            }
+            ControlCommand::ReadOnly(false) if self.options.backup_semantics => {
+                "readonly: --backup-semantics keeps the mount read-only".to_string()
+            }
+            ControlCommand::ReadOnly(readonly) => {
+                self.set_readonly(readonly);
+                format!("readonly {}", if readonly { "on" } else { "off" })
+            }
+            ControlCommand::Suspend => self.suspend(),
+            ControlCommand::Resume => self.resume(),
+            ControlCommand::CopyKey {
+                source,
+                destination,
+                force,
+            } => match self.copy_key(&source, &destination, force) {
+                Ok(summary) => format!("copied {:?} to {:?}: {}", source, destination, summary),
+                Err(e) => e.to_string(),
+            },
+            ControlCommand::Overlay(command) => self.overlay_command(command),
+            ControlCommand::ShadowExport(file) => self.shadow_export(&file),
+            ControlCommand::Trace(command) => self.trace_command(command),
+            ControlCommand::Quit => {
+                self.request_quit();
+                "quitting".to_string()
+            }
+        }
+    }
+
+    fn trace_command(&self, command: TraceCommand) -> String {
+        match command {
+            TraceCommand::List => {
+                let traced = self.trace_paths.list();
+                if traced.is_empty() {
+                    return "no keys traced".to_string();
+                }
+                traced
+                    .iter()
+                    .map(|key| format!("{}\n", key.display()))
+                    .collect()
+            }
+            TraceCommand::Add(key) => match self.trace_paths.add(&key) {
+                true => format!("tracing {:?}", key),
+                false => format!("{:?} is traced already", key),
+            },
+            TraceCommand::Remove(key) => match self.trace_paths.remove(&key) {
+                true => format!("no longer tracing {:?}", key),
+                false => format!("{:?} isn't traced", key),
+            },
+            TraceCommand::Clear => {
+                self.trace_paths.clear();
+                "no keys traced".to_string()
+            }
+        }
+    }
+
+    fn run_control_file(&self) {
+        let path =
+            self.local_path(&Path::new(synthetic::CONTROL_DIR).join(synthetic::CONTROL_FILE));
+        let regfs = self.clone();
+
+        // never block the notification callback on the command itself
+        thread::spawn(move || {
+            let contents = match fs::read_to_string(&path) {
+                Ok(contents) => contents,
+                Err(e) => {
+                    warn!(target: "control", "unable to read [{:?}]: {}", path, e);
+                    return;
+                }
+            };
+
+            for line in contents.lines() {
+                match ControlCommand::parse_control_file(line) {
+                    Ok(Some(command)) => {
+                        let output = regfs.execute(command);
+                        info!(target: "control", "{}", output.trim_end());
+                    }
+                    Ok(None) => {}
+                    Err(e) => warn!(target: "control", "{}", e),
+                }
+            }
+        });
+    }
+
+    // the session for a directory that can be listed
+    fn classify_dir(&self, command_id: i32, path: &OsStr) -> Result<DirInfo, RegFsError> {
+        let dirinfo = DirInfo::new(path);
+        match self.synthetic(path.as_ref()) {
+            Some(synthetic) if synthetic.is_directory() => return Ok(dirinfo),
+            Some(_) => return Err(RegFsError::NotAKey(path.into())),
+            None => {}
+        }
+
+        let key = PathBuf::from(path);
+        let kind = self.on_registry(command_id, move |regfs, ctx| {
+            regfs.regops.classify(&key, ctx)
+        });
+        self.end_command(command_id);
+
+        match kind? {
+            PathKind::Key(time) => Ok(dirinfo.with_key_time(time)),
+            PathKind::Value => Err(RegFsError::NotAKey(path.into())),
+            PathKind::Missing => Err(RegFsError::KeyNotFound(path.into())),
+            PathKind::Denied => Err(RegFsError::Registry(io::Error::from_raw_os_error(
+                winerror::ERROR_ACCESS_DENIED as i32,
+            ))),
+        }
+    }
+
+    // the session of an enumeration id, or a fresh one where it's missing and can be recovered
+    fn enum_session(
+        &self,
+        guid: &[u8; 16],
+        path: &OsStr,
+        restart: bool,
+    ) -> Result<Arc<EnumSession>, RegFsError> {
+        let mut state = self.lock_state();
+        match state.enum_sessions.get(guid) {
+            Some(session) if !session.dirinfo.is_poisoned() => return Ok(session.clone()),
+            // a callback panicked while filling it, it may be half filled
+            Some(_) => {
+                warn!(
+                    "get_dir_enum: dropping the session {} for [{:?}], a panicking callback \
+                     left it half filled",
+                    control::guid_string(guid),
+                    path
+                );
+                state.enum_sessions.remove(guid);
+            }
+            None => {}
+        }
+
+        // the provider restarted (or the state was dropped) while a handle stayed open; a
+        // restart scan starts from nothing anyway, but a continuation would list the first
+        // entries again
+        if !restart && !self.options.recover_enumerations {
+            return Err(RegFsError::UnknownEnumeration);
+        }
+        info!(
+            "get_dir_enum: recovered the session {} for [{:?}]",
+            control::guid_string(guid),
+            path
+        );
+        let session = EnumSession::new(DirInfo::new(path));
+        state.enum_sessions.insert(*guid, session.clone());
+        Ok(session)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn fill_dir_enum(
+        &self,
+        command_id: i32,
+        guid: &[u8; 16],
+        path: OsString,
+        search_expression: OsString,
+        flags: u32,
+        handle: PRJ_DIR_ENTRY_BUFFER_HANDLE,
+        call: Option<PendingCall>,
+    ) -> Result<HRESULT, RegFsError> {
+        self.traced(call, |response| {
+            let restart = flags & prjfs::sys::PRJ_CB_DATA_FLAG_ENUM_RESTART_SCAN != 0;
+            // the state is only locked to find the session; the registry is read under the
+            // session's own lock
+            let session = self.enum_session(guid, &path, restart)?;
+            let mut dirinfo = match session.dirinfo.lock() {
+                Ok(dirinfo) => dirinfo,
+                // a panic while it was filled, since it was looked up
+                Err(_) => return Err(RegFsError::UnknownEnumeration),
+            };
+
+            if restart {
+                // gone at the last fill; maybe back now, or a value by that name
+                if dirinfo.vanished() {
+                    match self.classify_dir(command_id, &path) {
+                        Ok(fresh) => *dirinfo = fresh,
+                        Err(RegFsError::KeyNotFound(_)) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                dirinfo.reset();
+            }
+
+            if !dirinfo.filled() {
+                dirinfo.capture_search(&search_expression);
+                let populated = self.populate_dir_info_for_path(
+                    path.clone(),
+                    &mut dirinfo,
+                    search_expression.clone(),
+                    command_id,
+                );
+                self.end_command(command_id);
+
+                match populated {
+                    Ok(true) => {}
+                    // deleted since start_dir_enum: the handle is still good, there's just
+                    // nothing in it anymore
+                    Ok(false) => {
+                        info!(
+                            "get_dir_enum: [{:?}] was deleted after the listing started, \
+                             listed as empty",
+                            path
+                        );
+                        dirinfo.mark_vanished();
+                        self.lock_state()
+                            .vanished
+                            .insert(path_key(path.as_ref()), PathBuf::from(&path));
+                    }
+                    Err(cancelled) => {
+                        // whatever was filled before the cancellation is not a listing
+                        dirinfo.reset();
+                        return Err(cancelled.into());
+                    }
+                }
+
+                // a cached listing was kept with its attributes
+                if !dirinfo.cached() {
+                    self.mark_synthetic(path.as_ref(), &mut dirinfo);
+                }
+                dirinfo.sort_entries_and_mark_filled(self.projfs());
+                self.keep_listing(path.as_ref(), &dirinfo, &search_expression);
+                if let Some(max) = self.options.max_entries_per_dir {
+                    self.cap_listing(&path, &mut dirinfo, &search_expression, max);
+                }
+            }
+
+            let tracer = self.tracer();
+            let mut served = Vec::new();
+            let single = flags & prjfs::sys::PRJ_CB_DATA_FLAG_ENUM_RETURN_SINGLE_ENTRY != 0;
+            // bookmarks are only at the root
+            let bookmarks = match paths::components(&path).is_empty() {
+                true => self.bookmark_links.as_slice(),
+                false => &[],
+            };
+            let mut projfs_sink = ProjFsSink::new(self.projfs(), handle, bookmarks);
+            let mut dry_run_sink = DryRunSink { tracer, added: 0 };
+            let sink: &mut dyn DirEntrySink = match self.dry_run() {
+                true => &mut dry_run_sink,
+                false => &mut projfs_sink,
+            };
+            let hr = dirinfo.fill(sink, single, tracer.map(|_| &mut served));
+            response.entries = tracer.map(|_| served);
+
+            Ok(hr)
+        })
+    }
+
+    fn serve_file_data(
+        &self,
+        path: OsString,
+        command_id: i32,
+        stream_id: &GUID,
+        offset: u64,
+        length: u32,
+        call: Option<PendingCall>,
+    ) -> HRESULT {
+        let result = self.traced(call, |response| {
+            Ok(self.serve_file_content(path, command_id, stream_id, offset, length, response))
+        });
+        trace::hresult_of(&result)
+    }
+
+    fn serve_file_content(
+        &self,
+        path: OsString,
+        command_id: i32,
+        stream_id: &GUID,
+        offset: u64,
+        length: u32,
+        response: &mut Response,
+    ) -> HRESULT {
+        let shape = ReadShape::of(offset, length);
+        match shape {
+            ReadShape::Empty => {
+                info!("get_file_data: empty request");
+                return S_OK;
+            }
+            ReadShape::Overflow => {
+                warn!("get_file_data: offset + length overflows");
+                return winerror::E_INVALIDARG;
+            }
+            ReadShape::Range(_) => {}
+        }
+
+        let key = path_key(path.as_ref());
+        let stream = guid_key(stream_id);
+        // without a stream id there is nothing to tie chunks together, so skip the cache
+        let cacheable = stream.iter().any(|byte| *byte != 0);
+        if !cacheable {
+            warn!("get_file_data: no DataStreamId for [{:?}]", path);
+        }
+
+        let read_value = || self.read_cached_value(Path::new(&path), command_id);
+
+        let synthetic = self.synthetic(path.as_ref());
+        let bytes = match synthetic {
+            Some(synthetic) => Ok(Some(Arc::new(
+                self.synthetic_content(path.as_ref(), synthetic),
+            ))),
+            None if cacheable => {
+                let mut missed = false;
+                let bytes = self.hydrations.read(&key, &stream, || {
+                    missed = true;
+                    read_value()
+                });
+                let (counter, decision) = match missed {
+                    true => (&self.metrics.cache_misses, "read from the backend"),
+                    false => (&self.metrics.cache_hits, "served from the cache"),
+                };
+                Metrics::add(counter, 1);
+                debug!("get_file_data: [{:?}] {}", path, decision);
+                bytes
+            }
+            None => read_value().map(|bytes| bytes.map(Arc::new)),
+        };
+        self.end_command(command_id);
+
+        let bytes = match bytes {
+            Ok(bytes) => bytes,
+            Err(cancelled) => {
+                if cacheable {
+                    self.hydrations.complete(&key, &stream);
+                }
+                info!("get_file_data: [{:?}] {}", path, cancelled);
+                return cancelled.to_hresult();
+            }
+        };
+
+        // the value changed size since its placeholder was written: the file is as long as
+        // the placeholder says until the placeholder is updated, which the next open sees
+        let advertised = match synthetic {
+            Some(_) => None,
+            None => self.advertised_size(path.as_ref()),
+        };
+        let bytes = match (bytes, advertised) {
+            (Some(read), Some(size)) if read.len() as u64 != size => {
+                warn!(
+                    "get_file_data: [{:?}] is {} bytes now, its placeholder says {}, \
+                     served as that and updated",
+                    path,
+                    read.len(),
+                    size
+                );
+                if !self.dry_run() {
+                    self.spawn_update(PathBuf::from(&path));
+                }
+                let served = read.len().min(size as usize);
+                Some(Arc::new(read[..served].to_vec()))
+            }
+            (bytes, _) => bytes,
+        };
+
+        response.size = bytes.as_ref().map(|bytes| bytes.len() as u64);
+        if self.tracer().is_some() {
+            response.data = bytes.as_ref().map(|bytes| render::base64(bytes));
+        }
+
+        let hr = match &bytes {
+            Some(bytes) => {
+                info!("get_file_data: [{:?}] is {}", path, Redacted(bytes));
+                self.write_file_data(stream_id, bytes, offset, length)
+            }
+            None => winerror::HRESULT_FROM_WIN32(winerror::ERROR_FILE_NOT_FOUND),
+        };
+
+        if hr == S_OK {
+            // what the file had in the range, not what ProjFS asked for past its end
+            let served = bytes.as_ref().map_or(0, |bytes| {
+                hydration::file_range(bytes, offset, length).len() as u64
+            });
+            self.emit(RegFsEvent::Hydrated {
+                path: PathBuf::from(&path),
+                bytes: served,
+            });
+        }
+
+        let served_to_end = bytes
+            .map(|bytes| shape.reaches_end(bytes.len()))
+            .unwrap_or(true);
+        if cacheable && (hr != S_OK || served_to_end) {
+            self.hydrations.complete(&key, &stream);
+        }
+
+        hr
+    }
+
+    fn write_file_data(&self, stream_id: &GUID, bytes: &[u8], offset: u64, length: u32) -> HRESULT {
+        if self.dry_run() {
+            return S_OK;
+        }
+
+        // a read at or past the end of the value (ProjFS rounds lengths up) has nothing left
+        let range = hydration::file_range(bytes, offset, length);
+        if range.is_empty() {
+            return S_OK;
+        }
+        let alignment = self.projfs.write_alignment(self.context());
+        // the first piece is the biggest, and the buffer is reused for the rest
+        let size = hydration::write_chunks(range.len(), alignment)
+            .next()
+            .map_or(0, |chunk| chunk.len());
+        let rawbuffer = self.projfs.allocate_aligned_buffer(self.context(), size);
+        if rawbuffer.is_null() {
+            warn!("get_file_data: Could not allocate write buffer.");
+            return winerror::E_OUTOFMEMORY;
+        }
+        let buffer = unsafe { std::slice::from_raw_parts_mut(rawbuffer as *mut u8, size) };
+
+        let mut hr = S_OK;
+        for chunk in hydration::write_chunks(range.len(), alignment) {
+            let piece = &range[chunk.clone()];
+            buffer[..piece.len()].copy_from_slice(piece);
+            hr = unsafe {
+                self.projfs.write_file_data(
+                    self.context(),
+                    stream_id,
+                    rawbuffer,
+                    offset + chunk.start as u64,
+                    piece.len() as u32,
+                )
+            };
+            if hr != S_OK {
+                warn!(
+                    "get_file_data: writing {} bytes at {} failed: {:08x}",
+                    piece.len(),
+                    offset + chunk.start as u64,
+                    hr
+                );
+                break;
+            }
+        }
+
+        unsafe {
+            self.projfs.free_aligned_buffer(rawbuffer);
+        }
+        hr
+    }
+
+    fn complete_command(
+        &self,
+        command_id: i32,
+        hr: HRESULT,
+        handle: Option<PRJ_DIR_ENTRY_BUFFER_HANDLE>,
+    ) {
+        // ProjFS has already given up on cancelled commands
+        if hr == Cancelled.to_hresult() {
+            info!(
+                "complete_command: abandoning cancelled command {}",
+                command_id
+            );
+            return;
+        }
+
+        let result = unsafe {
+            self.projfs
+                .complete_command(self.context(), command_id, hr, handle)
+        };
+
+        if result != S_OK {
+            warn!(
+                "complete_command: command {} ({:08x}) failed: {:08x}",
+                command_id, hr, result
+            );
+        }
+    }
+
+    // the listing is still served, minus whatever couldn't be read
+    fn report_partial(&self, path: &OsStr, entries: &RegEntires) {
+        Metrics::add(&self.metrics.partial_enumerations, 1);
+
+        if let Some(suppressed) = self.partial_warnings.allow() {
+            warn!(
+                "populate: listing of [{:?}] is partial, {} entries failed: {:?} \
+                 ({} similar warnings suppressed)",
+                path, entries.failed, entries.errors, suppressed
+            );
+        }
+    }
+
+    fn fill_entry(&self, dirinfo: &mut DirInfo, parent: &Path, entry: &ProjectedEntry, time: i64) {
+        if let Some(filter) = &self.options.filter {
+            if !filter.include(parent, entry) {
+                return;
+            }
+        }
+
+        let name = entry.name.to_os_string();
+        if entry.is_directory {
+            dirinfo.fill_dir_entry(name, time);
+        } else {
+            dirinfo.fill_file_entry(name, entry.size as i64, time);
+        }
+    }
+
+    // --max-entries-per-dir: the first `max` of the sorted (and searched) listing, and a
+    // marker saying how many more there are
+    fn cap_listing(
+        &self,
+        path: &OsStr,
+        dirinfo: &mut DirInfo,
+        search_expression: &OsStr,
+        max: usize,
+    ) {
+        if RegPath::parse(path).is_root() || self.synthetic(path.as_ref()).is_some() {
+            return;
+        }
+        let more = dirinfo.truncate(max);
+        if more == 0 {
+            return;
+        }
+        info!("listing of [{:?}] capped, {} more entries", path, more);
+
+        let marker = synthetic::truncated_marker(more);
+        if Search::new(self.projfs(), search_expression).matches(&marker) {
+            let file = Path::new(path).join(&marker);
+            let size = self.render_synthetic(&file, Synthetic::Truncated).len();
+            dirinfo.fill_file_entry(marker, size as i64, 0);
+            self.mark_synthetic(path.as_ref(), dirinfo);
+            dirinfo.sort_entries_and_mark_filled(self.projfs());
+        }
+    }
+
+    // hidden, the control directory a system one too, unless --show-synthetic
+    fn synthetic_attributes(&self, path: &Path) -> u32 {
+        match self.options.show_synthetic {
+            true => 0,
+            false => self.synthetic(path).map_or(0, Synthetic::attributes),
+        }
+    }
+
+    fn mark_synthetic(&self, path: &Path, dirinfo: &mut DirInfo) {
+        if !self.options.show_synthetic {
+            dirinfo.set_attributes(|name| self.synthetic_attributes(&path.join(name)));
+        }
+    }
+
+    // --enum-cache: not the root, with its synthetic directories and bookmarks, nor a hive
+    // whose summary counts what's under it
+    fn lists_cached(&self, path: &Path) -> bool {
+        let path = RegPath::parse(path);
+        self.enum_cache.is_some()
+            && !path.is_root()
+            && !(self.options.hive_summary && path.is_hive())
+    }
+
+    // --enum-cache: a listing just read from the registry, for the next ones
+    pub fn keep_listing(&self, path: &Path, dirinfo: &DirInfo, search_expression: &OsStr) {
+        if let (Some(cache), Some(time)) = (&self.enum_cache, dirinfo.listed_time()) {
+            if !dirinfo.cached() && !dirinfo.vanished() {
+                cache.insert(
+                    path,
+                    search_expression,
+                    time,
+                    dirinfo.entries(),
+                    Instant::now(),
+                );
+            }
+        }
+    }
+
+    // only transformed values cost a read, the size has to match the content
+    fn transformed_size(&self, file: &Path, size: u64) -> u64 {
+        match self.options.transformers.may_transform(file) {
+            true => match self.read_projected_value(file, &OpContext::none()) {
+                Ok(Some(bytes)) => bytes.len() as u64,
+                _ => size,
+            },
+            false => size,
+        }
+    }
+
+    pub fn populate_dir_info_for_path(
+        &self,
+        path: OsString,
+        dirinfo: &mut DirInfo,
+        search_expression: OsString,
+        command_id: i32,
+    ) -> Result<bool, Cancelled> {
+        let search = Search::new(self.projfs(), &search_expression);
+
+        match self.synthetic(path.as_ref()) {
+            Some(Synthetic::ControlDir) => {
+                for (name, synthetic) in synthetic::control_dir_entries() {
+                    if search.matches(&name) {
+                        let file = Path::new(&path).join(&name);
+                        let size = self.render_synthetic(&file, synthetic).len();
+                        dirinfo.fill_file_entry(name, size as i64, 0);
+                    }
+                }
+
+                return Ok(true);
+            }
+            // a pattern is only there once it's named
+            Some(Synthetic::SearchDir) => return Ok(true),
+            Some(Synthetic::SearchQuery) => {
+                for hit in &self.search_results(&path, command_id)?.hits {
+                    let name = searchdir::hit_name(&hit.path);
+                    if search.matches(&name) {
+                        let size = hit.path.to_string_lossy().len();
+                        dirinfo.fill_file_entry(name, size as i64, 0);
+                    }
+                }
+                return Ok(true);
+            }
+            // listed anew on every restart scan
+            Some(Synthetic::RecentDir) => {
+                let expired = self.recent.expire(SystemTime::now());
+                self.forget_recent(&expired);
+                for change in self.recent.list() {
+                    let name = change.name();
+                    if search.matches(&name) {
+                        let size = change.content().len();
+                        let time = times::to_filetime(change.time);
+                        dirinfo.fill_file_entry(name, size as i64, time);
+                    }
+                }
+                return Ok(true);
+            }
+            _ => {}
+        }
+
+        // taken before the key is listed, so a change meanwhile makes the listing stale
+        let listed_time = match self.lists_cached(Path::new(&path)) {
+            true => self.regops.key_last_write_time(path.as_ref()),
+            false => None,
+        };
+        if let (Some(cache), Some(time)) = (&self.enum_cache, listed_time) {
+            if let Some(entries) =
+                cache.get(path.as_ref(), &search_expression, time, Instant::now())
+            {
+                dirinfo.fill_cached(entries);
+                return Ok(true);
+            }
+        }
+
+        let key = path.clone();
+        let entries = match self.on_registry(command_id, move |regfs, ctx| {
+            regfs.regops.enumerate_key_ctx(key, ctx)
+        })? {
+            Some(entries) => entries,
+            None => return Ok(false),
+        };
+
+        if entries.partial {
+            self.report_partial(&path, &entries);
+        } else if let Some(time) = listed_time {
+            dirinfo.set_listed_time(time);
+        }
+        dirinfo.reserve(entries.subkeys.len() + entries.values.len());
+
+        let value_times = self.options.value_times;
+        let value_time = if value_times == ValueTimes::Parent {
+            let parent_time = dirinfo
+                .key_time()
+                .or_else(|| self.regops.key_last_write_time(path.as_ref()));
+            value_times.value_time(parent_time.unwrap_or(0), self.mount_time)
+        } else {
+            value_times.value_time(0, self.mount_time)
+        };
+
+        let parent = Path::new(&path);
+        for subkey in entries.subkeys {
+            if search.matches(&subkey.name) {
+                if self.options.hide_empty_keys
+                    && self.is_empty_key(&parent.join(&subkey.name), subkey.last_write_time)
+                {
+                    continue;
+                }
+                let time = value_times.key_time(subkey.last_write_time);
+                let entry = ProjectedEntry {
+                    name: &subkey.name,
+                    is_directory: true,
+                    size: 0,
+                };
+                self.fill_entry(dirinfo, parent, &entry, time);
+            }
+        }
+
+        for value in entries.values {
+            if search.matches(&value.name) {
+                // without transformers the value's path isn't even made
+                let size = match self.options.transformers.is_empty() {
+                    true => value.size,
+                    false => self.transformed_size(&parent.join(&value.name), value.size),
+                };
+                let entry = ProjectedEntry {
+                    name: &value.name,
+                    is_directory: false,
+                    size,
+                };
+                self.fill_entry(dirinfo, parent, &entry, value_time);
+            }
+        }
+
+        if RegPath::parse(&path).is_root() {
+            for name in synthetic::root_entries() {
+                if search.matches(&name) && self.synthetic(name.as_ref()).is_some() {
+                    dirinfo.fill_dir_entry(name, 0);
+                }
+            }
+        } else if self.options.values_json {
+            let name: OsString = synthetic::VALUES_JSON_FILE.into();
+            if search.matches(&name) {
+                let file = Path::new(&path).join(&name);
+                let size = self.render_synthetic(&file, Synthetic::ValuesJson).len();
+                dirinfo.fill_file_entry(name, size as i64, value_time);
+            }
+        }
+
+        if self.virtual_store.is_some() && virtualstore::redirect(Path::new(&path)).is_some() {
+            let name: OsString = synthetic::META_JSON_FILE.into();
+            if search.matches(&name) {
+                let file = Path::new(&path).join(&name);
+                let size = self.synthetic_content(&file, Synthetic::MetaJson).len();
+                dirinfo.fill_file_entry(name, size as i64, value_time);
+            }
+        }
+
+        if self.options.hive_summary && RegPath::parse(&path).is_hive() {
+            let name: OsString = synthetic::HIVE_SUMMARY_FILE.into();
+            if search.matches(&name) {
+                let file = Path::new(&path).join(&name);
+                let size = self.synthetic_content(&file, Synthetic::HiveSummary).len();
+                dirinfo.fill_file_entry(name, size as i64, value_time);
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+// ProjFS leaves some of the optional strings in the callback data null
+pub fn wstr_or_empty(value: PCWSTR) -> OsString {
+    if value.is_null() {
+        OsString::new()
+    } else {
+        value.to_os()
+    }
+}
+
+// a callback's string in a log line, only converted when the line is written
+struct Logged(PCWSTR);
+
+impl fmt::Debug for Logged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&wstr_or_empty(self.0), f)
+    }
+}
+
+// the same string, borrowed, for what's only looked at
+fn wide_units<'a>(value: PCWSTR) -> &'a [u16] {
+    if value.is_null() {
+        return &[];
+    }
+    unsafe {
+        let mut len = 0;
+        while *value.add(len) != 0 {
+            len += 1;
+        }
+        std::slice::from_raw_parts(value, len)
+    }
+}
+
+impl ProviderT for RegFs {
+    fn get_context_mut(&mut self) -> Option<*mut prjfs::sys::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT> {
+        Some(self.inner.context.as_ptr() as *mut prjfs::sys::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT)
+    }
+
+    fn start_dir_enum(
+        &self,
+        callback_data: &PRJ_CALLBACK_DATA,
+        enumeration_id: &GUID,
+    ) -> Result<HRESULT> {
+        let _scope = self.trace_scope(callback_data, std::ptr::null());
+        error::funnel("start_dir_enum", || {
+            let filepath = callback_data.FilePathName.to_os();
+            info!(
+                "----> start_dir_enum: Path [{:?}] triggered by [{:?}]",
+                filepath,
+                Logged(callback_data.TriggeringProcessImageFileName)
+            );
+
+            let guid = guid_key(enumeration_id);
+            let call = self.trace_call(callback_data, || CallKind::StartDirEnum {
+                enumeration: guid.to_vec(),
+            });
+            self.traced(call, |_| {
+                if let Err(hr) = self.throttle(callback_data) {
+                    return Ok(hr);
+                }
+                // only a key can be listed, and better to say so now than on the first fill
+                let dirinfo = self.classify_dir(callback_data.CommandId, &filepath)?;
+                self.lock_state()
+                    .enum_sessions
+                    .insert(guid, EnumSession::new(dirinfo));
+                self.emit(RegFsEvent::EnumerationStarted {
+                    path: filepath.into(),
+                });
+
+                info!("<---- start_dir_enum: return 0x0");
+
+                Ok(0)
+            })
+        })
+    }
+
+    fn end_dir_enum(
+        &self,
+        callback_data: &PRJ_CALLBACK_DATA,
+        enumeration_id: &GUID,
+    ) -> Result<HRESULT> {
+        let _scope = self.trace_scope(callback_data, std::ptr::null());
+        error::funnel("end_dir_enum", || {
+            info!("----> end_dir_enum");
+
+            let guid = guid_key(enumeration_id);
+            let call = self.trace_call(callback_data, || CallKind::EndDirEnum {
+                enumeration: guid.to_vec(),
+            });
+            self.traced(call, |_| {
+                let session = self.lock_state().enum_sessions.remove(&guid);
+                if let Some(session) = session {
+                    if self.synthetic(session.path()) == Some(Synthetic::SearchQuery) {
+                        self.end_search(session.path());
+                    }
+                    self.emit(RegFsEvent::EnumerationEnded {
+                        path: session.path().to_owned(),
+                    });
+                }
+
+                info!("<---- end_dir_enum: return 0x0");
+                Ok(0)
+            })
+        })
+    }
+
+    fn get_dir_enum(
+        &self,
+        data: &PRJ_CALLBACK_DATA,
+        enumeration_id: &GUID,
+        search_expression: PCWSTR,
+        handle: PRJ_DIR_ENTRY_BUFFER_HANDLE,
+    ) -> Result<HRESULT> {
+        let _scope = self.trace_scope(data, std::ptr::null());
+        error::funnel("get_dir_enum", || {
+            let path = data.FilePathName.to_os();
+            let search_expression = search_expression.to_os();
+            info!(
+                "----> get_dir_enum: Path [{:?}] SearchExpression: [{:?}]",
+                path, search_expression
+            );
+
+            let guid = guid_key(enumeration_id);
+            let flags = data.Flags;
+            let call = self.trace_call(data, || CallKind::GetDirEnum {
+                enumeration: guid.to_vec(),
+                search: search_expression.to_string_lossy().into(),
+            });
+            if let Err(hr) = self.check_suspended() {
+                info!("<---- get_dir_enum: suspended, return {:08x}", hr);
+                return self.traced(call, |_| Ok(hr));
+            }
+            self.track_process(data);
+
+            if let Some(executor) = &self.executor {
+                let regfs = self.clone();
+                let command_id = data.CommandId;
+                let handle = DirEntryBuffer(handle);
+                self.track_command(command_id);
+
+                executor.submit(command_id, move || {
+                    // the scope is per thread, and this is another one
+                    let _scope = regfs.trace_paths.enter(&path, None);
+                    let handle = handle.get();
+                    let hr = regfs
+                        .fill_dir_enum(
+                            command_id,
+                            &guid,
+                            path,
+                            search_expression,
+                            flags,
+                            handle,
+                            call,
+                        )
+                        .unwrap_or_else(|e| {
+                            warn!("get_dir_enum: {}", e);
+                            e.to_hresult()
+                        });
+                    regfs.complete_command(command_id, hr, Some(handle));
+                });
+
+                info!("<---- get_dir_enum: pending");
+                return Ok(HRESULT_FROM_WIN32(winerror::ERROR_IO_PENDING));
+            }
+
+            let hr = self.fill_dir_enum(
+                data.CommandId,
+                &guid,
+                path,
+                search_expression,
+                flags,
+                handle,
+                call,
+            )?;
+
+            info!("<---- get_dir_enum: return {:08x}", hr);
+            Ok(hr)
+        })
+    }
+
+    fn get_placeholder_info(&self, data: &PRJ_CALLBACK_DATA) -> Result<HRESULT> {
+        let _scope = self.trace_scope(data, std::ptr::null());
+        error::funnel("get_placeholder_info", || {
+            let path = data.FilePathName.to_os();
+            info!(
+                target: "placeholder",
+                "----> get_placeholder_info: Path [{:?}] triggered by {:?}]",
+                path,
+                Logged(data.TriggeringProcessImageFileName)
+            );
+
+            let call = self.trace_call(data, || CallKind::GetPlaceholderInfo);
+            if let Err(hr) = self.check_suspended() {
+                info!(target: "placeholder", "<---- get_placeholder_info: suspended, return {:08x}", hr);
+                return self.traced(call, |_| Ok(hr));
+            }
+            self.track_process(data);
+            self.traced(call, |response| {
+                let key = PathBuf::from(&path);
+                let placeholder = self.on_registry(data.CommandId, move |regfs, _| {
+                    Ok(regfs.placeholder_info(&key))
+                });
+                self.end_command(data.CommandId);
+
+                let placeholder = match placeholder {
+                    Ok(Some(placeholder)) => placeholder,
+                    Err(cancelled) => return Err(cancelled.into()),
+                    Ok(None) => {
+                        info!(
+                            "<---- get_place_holder_info: return {:08x}",
+                            winerror::ERROR_FILE_NOT_FOUND
+                        );
+                        return Ok(winerror::HRESULT_FROM_WIN32(winerror::ERROR_FILE_NOT_FOUND));
+                    }
+                };
+                let is_value = placeholder.FileBasicInfo.IsDirectory == 0;
+                let content_id = hash::content_id_of(&placeholder);
+                response.is_directory = Some(!is_value);
+                response.size = Some(placeholder.FileBasicInfo.FileSize as u64);
+
+                let result = self.write_placeholder(data.FilePathName, &path, placeholder);
+                if result == S_OK && is_value {
+                    self.record_content_id(path.as_ref(), content_id);
+                }
+                if result == S_OK {
+                    self.emit(RegFsEvent::PlaceholderCreated { path: path.into() });
+                }
+
+                info!(target: "placeholder", "<---- get_placeholder_info: {:08x}", result);
+
+                Ok(result)
+            })
+        })
+    }
+
+    fn get_file_data(&self, data: &PRJ_CALLBACK_DATA, offset: u64, length: u32) -> Result<HRESULT> {
+        let _scope = self.trace_scope(data, std::ptr::null());
+        error::funnel("get_file_data", || {
+            let path = data.FilePathName.to_os();
+            let process = wstr_or_empty(data.TriggeringProcessImageFileName);
+            info!(
+                "----> get_file_data: Path[{:?}] triggered by [{:?}] offset {} length {}",
+                path, process, offset, length
+            );
+
+            let call = self.trace_call(data, || CallKind::GetFileData {
+                stream: guid_to_bytes(&data.DataStreamId),
+                offset,
+                length,
+            });
+            if let Err(hr) = self.check_suspended() {
+                info!("<---- get_file_data: suspended, return {:08x}", hr);
+                return self.traced(call, |_| Ok(hr));
+            }
+            if let Err(hr) = self.throttle(data) {
+                info!("<---- get_file_data: return {:08x}", hr);
+                return self.traced(call, |_| Ok(hr));
+            }
+            // held until the read is served, on whichever thread that is
+            let permit = match self.admit_hydration(&process, length) {
+                Ok(permit) => permit,
+                Err(hr) => {
+                    info!("<---- get_file_data: return {:08x}", hr);
+                    return self.traced(call, |_| Ok(hr));
+                }
+            };
+            self.track_process(data);
+
+            if let Some(executor) = &self.executor {
+                let regfs = self.clone();
+                let command_id = data.CommandId;
+                let stream_id = data.DataStreamId;
+                self.track_command(command_id);
+
+                executor.submit(command_id, move || {
+                    let _scope = regfs.trace_paths.enter(&path, None);
+                    let hr =
+                        regfs.serve_file_data(path, command_id, &stream_id, offset, length, call);
+                    drop(permit);
+                    regfs.complete_command(command_id, hr, None);
+                });
+
+                info!("<---- get_file_data: pending");
+                return Ok(HRESULT_FROM_WIN32(winerror::ERROR_IO_PENDING));
+            }
+
+            let hr = self.serve_file_data(
+                path,
+                data.CommandId,
+                &data.DataStreamId,
+                offset,
+                length,
+                call,
+            );
+            drop(permit);
+
+            info!("<---- get_file_data: return {:08x}", hr);
+            Ok(hr)
+        })
+    }
+
+    fn notify(
+        &self,
+        data: &PRJ_CALLBACK_DATA,
+        is_directory: bool,
+        notification_type: prjfs::sys::PRJ_NOTIFICATION,
+        destination_file_name: PCWSTR,
+        _parameters: &PRJ_NOTIFICATION_PARAMETERS,
+    ) -> Result<HRESULT> {
+        let _scope = self.trace_scope(data, destination_file_name);
+        error::funnel("notify", || {
+            let filepath = data.FilePathName.to_os();
+            let process = wstr_or_empty(data.TriggeringProcessImageFileName);
+            info!(
+                "---> notify: Path [{:?}] triggered by [{:?}]",
+                filepath, process
+            );
+            info!(
+                "--- Notification: 0x{:08x} under [{:?}]",
+                notification_type,
+                notifymap::mapping_for(&self.options.notify_maps, filepath.as_ref())
+                    .map_or(Path::new(""), |map| &map.path)
+            );
+            self.emit(RegFsEvent::NotificationReceived {
+                path: PathBuf::from(&filepath),
+                notification: notification_type,
+            });
+            let request = |kind| MutationRequest {
+                kind,
+                path: filepath.as_ref(),
+                destination: None,
+                process: &process,
+                is_directory,
+            };
+
+            let call = self.trace_call(data, || CallKind::Notify {
+                is_directory,
+                notification: notification_type,
+                destination: wstr_or_empty(destination_file_name)
+                    .to_string_lossy()
+                    .into(),
+            });
+
+            self.traced(call, |_| match notification_type {
+                prjfs::sys::PRJ_NOTIFICATION_FILE_OPENED => Ok(S_OK),
+                prjfs::sys::PRJ_NOTIFICATION_FILE_HANDLE_CLOSED_NO_MODIFICATION => {
+                    match self.synthetic(filepath.as_ref()) {
+                        Some(synthetic) if synthetic.is_dynamic() => {
+                            self.refresh_synthetic(filepath.as_ref());
+                            Ok(S_OK)
+                        }
+                        // truncated, and nothing written after
+                        None if self.take_overwritten(filepath.as_ref()) => {
+                            info!(" ----- [{:?}] was emptied", filepath);
+                            Ok(self.file_saved(
+                                filepath.as_ref(),
+                                true,
+                                &process,
+                                data.TriggeringProcessId,
+                            ))
+                        }
+                        _ => Ok(S_OK),
+                    }
+                }
+                prjfs::sys::PRJ_NOTIFICATION_FILE_HANDLE_CLOSED_FILE_MODIFIED => {
+                    info!(" ----- [{:?}] was modified", filepath);
+                    match self.synthetic(filepath.as_ref()) {
+                        Some(Synthetic::ControlFile) => self.run_control_file(),
+                        Some(_) => {}
+                        None if !is_directory => {
+                            let path = filepath.as_ref();
+                            let overwritten = self.take_overwritten(path);
+                            return Ok(self.file_saved(
+                                path,
+                                overwritten,
+                                &process,
+                                data.TriggeringProcessId,
+                            ));
+                        }
+                        None => {}
+                    }
+                    Ok(S_OK)
+                }
+                // on open: whatever is in the file by the time it's closed replaces the value
+                prjfs::sys::PRJ_NOTIFICATION_FILE_OVERWRITTEN => {
+                    info!(" ----- [{:?}] was overwritten", filepath);
+                    if !is_directory && self.synthetic(filepath.as_ref()).is_none() {
+                        self.mark_overwritten(filepath.as_ref());
+                    }
+                    Ok(S_OK)
+                }
+                prjfs::sys::PRJ_NOTIFY_NEW_FILE_CREATED => {
+                    info!(" ----- [{:?}] was created", filepath);
+                    Ok(self.file_created(
+                        filepath.as_ref(),
+                        is_directory,
+                        &process,
+                        data.TriggeringProcessId,
+                    ))
+                }
+                prjfs::sys::PRJ_NOTIFY_FILE_RENAMED => {
+                    let destination = wstr_or_empty(destination_file_name);
+                    info!(" ----- [{:?}] -> [{:?}]", filepath, destination);
+                    self.record_change(Change::Renamed {
+                        from: filepath.as_ref(),
+                        to: destination.as_ref(),
+                    });
+                    Ok(S_OK)
+                }
+                prjfs::sys::PRJ_NOTIFY_FILE_HANDLE_CLOSED_FILE_DELETED => {
+                    info!(" ----- [{:?}] was deleted", filepath);
+                    self.take_overwritten(filepath.as_ref());
+                    self.record_change(Change::Deleted(filepath.as_ref()));
+                    Ok(S_OK)
+                }
+                prjfs::sys::PRJ_NOTIFICATION_PRE_RENAME => {
+                    let destination = wstr_or_empty(destination_file_name);
+                    match self.pre_mutation(&MutationRequest {
+                        destination: Some(destination.as_ref()),
+                        ..request(MutationKind::Rename)
+                    }) {
+                        S_OK => Ok(self.rename_back(
+                            filepath.as_ref(),
+                            destination.as_ref(),
+                            is_directory,
+                            data.TriggeringProcessId,
+                        )),
+                        denied => Ok(denied),
+                    }
+                }
+                prjfs::sys::PRJ_NOTIFICATION_PRE_DELETE => {
+                    Ok(self.pre_mutation(&request(MutationKind::Delete)))
+                }
+                // a placeholder about to be written to, refused before anything is when the
+                // save would go to the registry and may not
+                prjfs::sys::PRJ_NOTIFICATION_FILE_PRE_CONVERT_TO_FULL => {
+                    match !is_directory && self.writes_back(filepath.as_ref()) {
+                        true => Ok(self.pre_mutation(&request(MutationKind::WriteBack))),
+                        false => Ok(self.pre_mutation(&request(MutationKind::ConvertToFull))),
+                    }
+                }
+                prjfs::sys::PRJ_NOTIFICATION_PRE_SET_HARDLINK => {
+                    let link = wstr_or_empty(destination_file_name);
+                    Ok(self.pre_mutation(&MutationRequest {
+                        destination: Some(link.as_ref()),
+                        ..request(MutationKind::Hardlink)
+                    }))
+                }
+                // only once it went through, so with --allow-hardlinks
+                prjfs::sys::PRJ_NOTIFICATION_HARDLINK_CREATED => {
+                    let link = wstr_or_empty(destination_file_name);
+                    info!(" ----- [{:?}] hard linked as [{:?}]", filepath, link);
+                    self.emit(RegFsEvent::HardlinkCreated {
+                        path: PathBuf::from(&filepath),
+                        link: PathBuf::from(&link),
+                    });
+                    Ok(S_OK)
+                }
+                t => {
+                    warn!("notify: Unexpected notification: 0x{:08x}", t);
+                    Ok(S_OK)
+                }
+            })
+        })
+    }
+
+    fn query_file_name(&self, data: &PRJ_CALLBACK_DATA) -> Result<HRESULT> {
+        let _scope = self.trace_scope(data, std::ptr::null());
+        error::funnel("query_file_name", || {
+            let path = PathBuf::from(data.FilePathName.to_os());
+            info!("----> query_file_name: Path [{:?}]", path);
+
+            let call = self.trace_call(data, || CallKind::QueryFileName);
+            self.track_process(data);
+            self.traced(call, |_| {
+                let exists = self.query_exists(&path, data.CommandId);
+                self.end_command(data.CommandId);
+                let hr = match exists? {
+                    true => S_OK,
+                    false => HRESULT_FROM_WIN32(winerror::ERROR_FILE_NOT_FOUND),
+                };
+                info!("<---- query_file_name: return {:08x}", hr);
+                Ok(hr)
+            })
+        })
+    }
+
+    fn cancel_command(&self, data: &PRJ_CALLBACK_DATA) -> Result<()> {
+        info!("----> cancel_command: CommandId {}", data.CommandId);
+
+        let mut commands = match self.commands.lock() {
+            Ok(commands) => commands,
+            Err(_) => {
+                warn!("cancel_command: {}", RegFsError::LockPoisoned("commands"));
+                return Ok(());
+            }
+        };
+
+        let dequeued = self
+            .executor
+            .as_ref()
+            .map(|executor| executor.cancel(data.CommandId))
+            .unwrap_or(false);
+
+        if dequeued {
+            commands.remove(&data.CommandId);
+        } else if let Some(flag) = commands.get(&data.CommandId) {
+            flag.store(true, Ordering::Release);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+use crate::fixture::{callback_data, seeded};
+
+#[test]
+fn test_recovers_from_poisoned_state() {
+    let backend = seeded(&["HKEY_CURRENT_USER\\Empty"], &[]);
+    let regfs = RegFs::with_backend(&RegFsOptions::default(), backend);
+
+    let star = OsString::from("*").to_wstr();
+    let data = callback_data("HKEY_CURRENT_USER\\Empty", 0);
+    let stale = GUID {
+        Data1: 1,
+        ..Default::default()
+    };
+    let fresh = GUID {
+        Data1: 2,
+        ..Default::default()
+    };
+
+    assert_eq!(regfs.start_dir_enum(&data, &stale).unwrap(), S_OK);
+
+    let poisoner = regfs.clone();
+    let result = thread::spawn(move || {
+        let _state = poisoner.state.lock().unwrap();
+        panic!("callback bug");
+    })
+    .join();
+    assert!(result.is_err());
+    assert!(regfs.state.is_poisoned());
+
+    assert_eq!(regfs.start_dir_enum(&data, &fresh).unwrap(), S_OK);
+    assert!(!regfs.state.is_poisoned());
+    let handle = std::ptr::null_mut();
+    assert_eq!(
+        regfs
+            .get_dir_enum(&data, &fresh, star.as_ptr(), handle)
+            .unwrap(),
+        S_OK
+    );
+
+    // the session that lived through the panic is gone rather than served half filled
+    assert_eq!(
+        regfs
+            .get_dir_enum(&data, &stale, star.as_ptr(), handle)
+            .unwrap(),
+        winerror::E_INVALIDARG
+    );
+}
+
+#[test]
+fn test_poisoned_session_is_dropped() {
+    let backend = seeded(&["HKEY_CURRENT_USER\\Empty"], &[]);
+    let regfs = RegFs::with_backend(&RegFsOptions::default(), backend);
+
+    let star = OsString::from("*").to_wstr();
+    let data = callback_data("HKEY_CURRENT_USER\\Empty", 0);
+    let guid = GUID::default();
+    assert_eq!(regfs.start_dir_enum(&data, &guid).unwrap(), S_OK);
+
+    let session = regfs.lock_state().enum_sessions[&guid_key(&guid)].clone();
+    let result = thread::spawn(move || {
+        let _dirinfo = session.dirinfo.lock().unwrap();
+        panic!("callback bug");
+    })
+    .join();
+    assert!(result.is_err());
+
+    // only that session goes, the state lock is fine
+    let handle = std::ptr::null_mut();
+    assert_eq!(
+        regfs
+            .get_dir_enum(&data, &guid, star.as_ptr(), handle)
+            .unwrap(),
+        winerror::E_INVALIDARG
+    );
+    assert!(!regfs.state.is_poisoned());
+    assert!(regfs.sessions().is_empty());
+}
+
+#[test]
+fn test_enumerations_overlap() {
+    let backend = seeded(
+        &["HKEY_CURRENT_USER\\Slow0", "HKEY_CURRENT_USER\\Slow1"],
+        &[],
+    );
+    let regfs = RegFs::with_backend(&RegFsOptions::default(), backend.clone());
+
+    let enumerate = |regfs: &RegFs, n: u32, fill: bool| {
+        let star = OsString::from("*").to_wstr();
+        let data = callback_data(format!("HKEY_CURRENT_USER\\Slow{}", n), 0);
+        let guid = GUID {
+            Data1: n,
+            ..Default::default()
+        };
+        match fill {
+            false => regfs.start_dir_enum(&data, &guid).unwrap(),
+            true => regfs
+                .get_dir_enum(&data, &guid, star.as_ptr(), std::ptr::null_mut())
+                .unwrap(),
+        }
+    };
+    for n in 0..2 {
+        assert_eq!(enumerate(&regfs, n, false), S_OK);
+    }
+
+    backend.slow_listings(Duration::from_millis(300));
+    let fills: Vec<_> = (0..2)
+        .map(|n| {
+            let regfs = regfs.clone();
+            thread::spawn(move || enumerate(&regfs, n, true))
+        })
+        .collect();
+
+    // neither holds the state while it waits on the registry
+    thread::sleep(Duration::from_millis(100));
+    let started = Instant::now();
+    assert_eq!(regfs.sessions().len(), 2);
+    assert_eq!(regfs.dump()["sessions"][0]["busy"], true);
+    assert!(started.elapsed() < Duration::from_millis(150));
+
+    for fill in fills {
+        assert_eq!(fill.join().unwrap(), S_OK);
+    }
+    assert_eq!(backend.peak_concurrent_listings(), 2);
+}
+
+#[test]
+fn test_async_completions() {
+    use crate::{executor, prj_compat::MockPrjApi};
+
+    let workers = executor::DEFAULT_WORKERS;
+    let backend = seeded(
+        &[],
+        &[("HKEY_CURRENT_USER\\App", "Blob", 3, b"0123456789".to_vec())],
+    );
+    for n in 0..=workers {
+        backend.add_key(format!("HKEY_CURRENT_USER\\Slow{}", n));
+    }
+    let mock = Arc::new(MockPrjApi::default());
+    let options = RegFsOptions {
+        async_callbacks: true,
+        prj_api: Some(mock.clone()),
+        ..Default::default()
+    };
+    let regfs = RegFs::with_backend(&options, backend.clone());
+    let pending = HRESULT_FROM_WIN32(winerror::ERROR_IO_PENDING);
+
+    // command n lists Slow<n> into the buffer n + 1
+    let star = OsString::from("*").to_wstr();
+    let enumerate = |n: usize, fill: bool| {
+        let data = callback_data(format!("HKEY_CURRENT_USER\\Slow{}", n), n as i32);
+        let guid = GUID {
+            Data1: n as u32,
+            ..Default::default()
+        };
+        match fill {
+            false => regfs.start_dir_enum(&data, &guid).unwrap(),
+            true => regfs
+                .get_dir_enum(&data, &guid, star.as_ptr(), (n + 1) as _)
+                .unwrap(),
+        }
+    };
+    for n in 0..=workers {
+        assert_eq!(enumerate(n, false), S_OK);
+    }
+
+    // every worker busy with a listing, and one more queued behind them and cancelled
+    backend.slow_listings(Duration::from_millis(300));
+    for n in 0..=workers {
+        assert_eq!(enumerate(n, true), pending);
+    }
+    let cancel = PRJ_CALLBACK_DATA {
+        CommandId: workers as i32,
+        ..Default::default()
+    };
+    regfs.cancel_command(&cancel).unwrap();
+
+    let read = callback_data("HKEY_CURRENT_USER\\App\\Blob", 100);
+    assert_eq!(regfs.get_file_data(&read, 0, 10).unwrap(), pending);
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while mock.completed.lock().unwrap().len() < workers + 1 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    // the cancelled one is never completed, nor started
+    thread::sleep(Duration::from_millis(100));
+    let mut completed = mock.completed.lock().unwrap().clone();
+    completed.sort();
+    let mut expected: Vec<_> = (0..workers)
+        .map(|n| (n as i32, S_OK, Some(n + 1)))
+        .collect();
+    expected.push((100, S_OK, None));
+    assert_eq!(completed, expected);
+    assert_eq!(mock.written.lock().unwrap()[0], (0, b"0123456789".to_vec()));
+}
+
+#[test]
+fn test_partial_listing_is_counted() {
+    let backend = seeded(&[], &[("HKEY_CURRENT_USER\\Locked", "Secret", 1, vec![])]);
+    backend.fail_entry("HKEY_CURRENT_USER\\Locked", "Secret");
+    let regfs = RegFs::with_backend(&RegFsOptions::default(), backend);
+
+    let star = OsString::from("*").to_wstr();
+    let data = callback_data("HKEY_CURRENT_USER\\Locked", 0);
+    let guid = GUID::default();
+
+    assert_eq!(regfs.start_dir_enum(&data, &guid).unwrap(), S_OK);
+    assert_eq!(
+        regfs
+            .get_dir_enum(&data, &guid, star.as_ptr(), std::ptr::null_mut())
+            .unwrap(),
+        S_OK
+    );
+    assert_eq!(regfs.metrics_snapshot().partial_enumerations, 1);
+}
+
+#[test]
+fn test_key_deleted_after_listing_started() {
+    use crate::prj_compat::MockPrjApi;
+
+    let key = Path::new("HKEY_CURRENT_USER\\Gone");
+    let backend = seeded(
+        &[],
+        &[("HKEY_CURRENT_USER\\Gone", "Setting", 4, vec![1, 0, 0, 0])],
+    );
+    let mock = Arc::new(MockPrjApi::default());
+    let options = RegFsOptions {
+        prj_api: Some(mock.clone()),
+        ..Default::default()
+    };
+    let regfs = RegFs::with_backend(&options, backend.clone());
+
+    let star = OsString::from("*").to_wstr();
+    let data = callback_data(key, 0);
+    let guid = GUID::default();
+    let get = |data: &PRJ_CALLBACK_DATA| {
+        regfs
+            .get_dir_enum(data, &guid, star.as_ptr(), std::ptr::null_mut())
+            .unwrap()
+    };
+
+    // between the two callbacks
+    assert_eq!(regfs.start_dir_enum(&data, &guid).unwrap(), S_OK);
+    assert!(backend.remove(key));
+    assert_eq!(get(&data), S_OK);
+    assert!(mock.calls().iter().all(|call| !call.starts_with("fill")));
+    assert_eq!(regfs.dump()["sessions"][0]["vanished"], json!(true));
+
+    // back by the restart scan
+    backend.set_value(key, "Setting", 4, vec![1, 0, 0, 0]);
+    let restart = PRJ_CALLBACK_DATA {
+        Flags: prjfs::sys::PRJ_CB_DATA_FLAG_ENUM_RESTART_SCAN,
+        ..*data
+    };
+    assert_eq!(get(&restart), S_OK);
+    assert!(mock.calls().contains(&"fill Setting".to_string()));
+    assert_eq!(regfs.dump()["sessions"][0]["vanished"], json!(false));
+
+    // the watcher's note about it picks up the placeholder left behind
+    assert_eq!(regfs.take_vanished(Path::new("HKEY_CURRENT_USER")), [key]);
+    assert!(regfs
+        .take_vanished(Path::new("HKEY_CURRENT_USER"))
+        .is_empty());
+}
+
+#[test]
+fn test_search_dir() {
+    use crate::prj_compat::MockPrjApi;
+
+    let backend = seeded(
+        &["HKEY_CURRENT_USER\\Software\\Other"],
+        &[(
+            "HKEY_CURRENT_USER\\Software\\Adobe\\Reader",
+            "Path",
+            1,
+            vec![0; 2],
+        )],
+    );
+    let mock = Arc::new(MockPrjApi::default());
+    let options = RegFsOptions {
+        search_dir: true,
+        prj_api: Some(mock.clone()),
+        ..Default::default()
+    };
+    let regfs = RegFs::with_backend(&options, backend.clone());
+
+    let star = OsString::from("*").to_wstr();
+    let data = callback_data("_search\\adobe", 0);
+    let guid = GUID::default();
+    let get = |data: &PRJ_CALLBACK_DATA| {
+        regfs
+            .get_dir_enum(data, &guid, star.as_ptr(), std::ptr::null_mut())
+            .unwrap()
+    };
+
+    assert_eq!(regfs.start_dir_enum(&data, &guid).unwrap(), S_OK);
+    assert_eq!(get(&data), S_OK);
+    assert!(mock
+        .calls()
+        .contains(&"fill HKEY_CURRENT_USER~Software~Adobe".to_string()));
+    assert!(mock.calls().iter().all(|call| !call.contains("Other")));
+
+    // a restart scan lists the same session's results
+    let restart = PRJ_CALLBACK_DATA {
+        Flags: prjfs::sys::PRJ_CB_DATA_FLAG_ENUM_RESTART_SCAN,
+        ..*data
+    };
+    assert_eq!(get(&restart), S_OK);
+    let stats = regfs.metrics_snapshot();
+    assert_eq!((stats.searches, stats.search_sessions), (1, 1));
+
+    // a hit holds the path, and is gone with the key
+    let hit = Path::new("_search\\adobe\\HKEY_CURRENT_USER~Software~Adobe");
+    assert_eq!(regfs.synthetic(hit), Some(Synthetic::SearchHit));
+    assert_eq!(
+        regfs.synthetic_content(hit, Synthetic::SearchHit),
+        b"HKEY_CURRENT_USER\\Software\\Adobe"
+    );
+    assert_eq!(
+        regfs.synthetic(&hit.with_file_name("HKEY_CURRENT_USER~Software~Other")),
+        None
+    );
+
+    assert_eq!(regfs.end_dir_enum(&data, &guid).unwrap(), S_OK);
+    assert_eq!(regfs.metrics_snapshot().search_sessions, 0);
+    assert!(backend.remove("HKEY_CURRENT_USER\\Software\\Adobe"));
+    assert_eq!(regfs.synthetic(hit), None);
+
+    let without = RegFs::with_backend(&Default::default(), backend);
+    assert_eq!(without.synthetic(Path::new("_search\\adobe")), None);
+}
+
+#[test]
+fn test_recent_dir() {
+    use crate::prj_compat::MockPrjApi;
+
+    let backend = seeded(&["HKEY_CURRENT_USER\\Software\\App"], &[]);
+    let mock = Arc::new(MockPrjApi::default());
+    let options = RegFsOptions {
+        recent_dir: true,
+        prj_api: Some(mock.clone()),
+        ..Default::default()
+    };
+    let regfs = RegFs::with_backend(&options, backend.clone());
+    let watchers = backend.watchers().unwrap();
+    watchers.notify("HKEY_CURRENT_USER\\Software\\App".as_ref());
+    watchers.notify("HKEY_CURRENT_USER\\Software\\Gone".as_ref());
+
+    let star = OsString::from("*").to_wstr();
+    let data = callback_data("_recent", 0);
+    let guid = GUID::default();
+    let fills = || -> Vec<String> {
+        mock.calls()
+            .into_iter()
+            .filter(|call| call.starts_with("fill "))
+            .collect()
+    };
+
+    assert_eq!(regfs.start_dir_enum(&data, &guid).unwrap(), S_OK);
+    regfs
+        .get_dir_enum(&data, &guid, star.as_ptr(), std::ptr::null_mut())
+        .unwrap();
+    let newest = "9999999998~HKEY_CURRENT_USER~Software~Gone";
+    assert_eq!(
+        fills(),
+        [
+            format!("fill {}", newest),
+            "fill 9999999999~HKEY_CURRENT_USER~Software~App".to_string()
+        ]
+    );
+    let file = Path::new("_recent").join(newest);
+    assert_eq!(regfs.synthetic(&file), Some(Synthetic::RecentChange));
+    let content = String::from_utf8(regfs.synthetic_content(&file, Synthetic::RecentChange));
+    assert!(content
+        .unwrap()
+        .starts_with("path HKEY_CURRENT_USER\\Software\\Gone\nchange deleted\ntime "));
+
+    // the restart scan has what changed since, under new names
+    watchers.notify("HKEY_CURRENT_USER\\Software\\App".as_ref());
+    let restart = PRJ_CALLBACK_DATA {
+        Flags: prjfs::sys::PRJ_CB_DATA_FLAG_ENUM_RESTART_SCAN,
+        ..*data
+    };
+    let before = fills().len();
+    regfs
+        .get_dir_enum(&restart, &guid, star.as_ptr(), std::ptr::null_mut())
+        .unwrap();
+    assert_eq!(
+        fills()[before..],
+        [
+            "fill 9999999997~HKEY_CURRENT_USER~Software~App".to_string(),
+            format!("fill {}", newest),
+        ]
+    );
+    assert_eq!(
+        regfs.synthetic(Path::new(
+            "_recent\\9999999999~HKEY_CURRENT_USER~Software~App"
+        )),
+        None
+    );
+
+    let without = RegFs::with_backend(&Default::default(), backend);
+    assert_eq!(without.synthetic(Path::new("_recent")), None);
+}
+
+#[test]
+fn test_mounts_share_one_backend() {
+    let shared = seeded(
+        &[],
+        &[(
+            "HKEY_CURRENT_USER\\Console",
+            "FontSize",
+            4,
+            vec![14, 0, 0, 0],
+        )],
+    );
+    let options = RegFsOptions {
+        values_json: true,
+        hives: vec![RootHive::CurrentUser],
+        backend: Some(shared.clone()),
+        ..Default::default()
+    };
+    let first = RegFs::new(&options);
+    let second = RegFs::new(&options);
+
+    let file = Path::new("HKEY_CURRENT_USER\\Console\\_values.json");
+    let before = first.synthetic_content(file, Synthetic::ValuesJson);
+    assert_eq!(
+        second.synthetic_content(file, Synthetic::ValuesJson),
+        before
+    );
+    let reads = shared.reads();
+
+    // both caches are dropped by the one change, so both re-read the backend
+    shared.set_value(
+        "HKEY_CURRENT_USER\\Console",
+        "QuickEdit",
+        4,
+        vec![1, 0, 0, 0],
+    );
+    assert_eq!(first.metrics_snapshot().registry_changes, 1);
+    assert_eq!(second.metrics_snapshot().registry_changes, 1);
+    assert_ne!(first.synthetic_content(file, Synthetic::ValuesJson), before);
+    assert_ne!(
+        second.synthetic_content(file, Synthetic::ValuesJson),
+        before
+    );
+    assert!(shared.reads() > reads);
+
+    // changes outside the mounted hives go unnoticed
+    shared.add_key("HKEY_LOCAL_MACHINE\\SOFTWARE");
+    assert_eq!(first.metrics_snapshot().registry_changes, 1);
+
+    drop(second);
+    let watchers = shared.watchers().unwrap();
+    assert_eq!(watchers.notify("HKEY_CURRENT_USER\\Console".as_ref()), 1);
+}
+
+#[test]
+fn test_backup_semantics_stay_read_only() {
+    use crate::{console, memory::MemoryBackend};
+
+    let options = RegFsOptions {
+        backup_semantics: true,
+        ..Default::default()
+    };
+    let regfs = RegFs::with_backend(&options, Arc::new(MemoryBackend::new()));
+    assert_eq!(
+        console::handle(&regfs, "readonly off"),
+        "readonly: --backup-semantics keeps the mount read-only"
+    );
+    assert!(regfs.readonly());
+    assert_eq!(console::handle(&regfs, "readonly on"), "readonly on");
+}
+
+#[test]
+fn test_console_dispatch() {
+    use crate::console;
+
+    let backend = seeded(&["HKEY_CURRENT_USER\\Console"], &[]);
+    let regfs = RegFs::with_backend(&RegFsOptions::default(), backend);
+
+    assert!(console::handle(&regfs, "stats").contains("partial_enumerations 0\n"));
+    assert!(console::handle(&regfs, "frobnicate").ends_with(control::HELP));
+    assert_eq!(console::handle(&regfs, "  "), "");
+
+    assert!(regfs.readonly());
+    assert_eq!(console::handle(&regfs, "readonly off"), "readonly off");
+    assert!(!regfs.readonly());
+
+    let data = callback_data("HKEY_CURRENT_USER\\Console", 0);
+    let guid = GUID {
+        Data1: 0x12345678,
+        ..Default::default()
+    };
+    assert_eq!(
+        console::handle(&regfs, "sessions"),
+        "no enumerations in progress"
+    );
+    assert_eq!(regfs.start_dir_enum(&data, &guid).unwrap(), S_OK);
+    assert_eq!(
+        console::handle(&regfs, "sessions"),
+        "{12345678-0000-0000-0000-000000000000} \"HKEY_CURRENT_USER\\\\Console\"\n"
+    );
+
+    let waiter = regfs.clone();
+    let quit = thread::spawn(move || waiter.wait_for_quit());
+    assert_eq!(console::handle(&regfs, "quit"), "quitting");
+    quit.join().unwrap();
+}
+
+#[test]
+fn test_dump() {
+    let password = "hunter2\0".encode_utf16().flat_map(u16::to_le_bytes);
+    let backend = seeded(
+        &["HKEY_CURRENT_USER\\Dump\\Sub"],
+        &[("HKEY_CURRENT_USER\\Dump", "Password", 1, password.collect())],
+    );
+    let regfs = RegFs::with_backend(&RegFsOptions::default(), backend);
+
+    let search = OsString::from("*").to_wstr();
+    let data = callback_data("HKEY_CURRENT_USER\\Dump", 7);
+    let listed = GUID {
+        Data1: 1,
+        ..Default::default()
+    };
+    let started = GUID {
+        Data1: 2,
+        ..Default::default()
+    };
+
+    assert_eq!(regfs.start_dir_enum(&data, &listed).unwrap(), S_OK);
+    assert_eq!(regfs.start_dir_enum(&data, &started).unwrap(), S_OK);
+    assert_eq!(
+        regfs
+            .get_dir_enum(&data, &listed, search.as_ptr(), std::ptr::null_mut())
+            .unwrap(),
+        S_OK
+    );
+    regfs.track_command(9);
+    regfs
+        .cancel_command(&PRJ_CALLBACK_DATA {
+            CommandId: 9,
+            ..Default::default()
+        })
+        .unwrap();
+
+    let dump = regfs.dump();
+    let sessions = dump["sessions"].as_array().unwrap();
+    assert_eq!(sessions.len(), 2);
+    assert_eq!(
+        sessions[0]["enumeration"],
+        "{00000001-0000-0000-0000-000000000000}"
+    );
+    assert_eq!(sessions[0]["path"], "HKEY_CURRENT_USER\\Dump");
+    assert_eq!(sessions[0]["filled"], true);
+    assert_eq!(sessions[0]["entries"], 2);
+    assert!(sessions[0]["index"].as_u64().unwrap() <= 2);
+    assert_eq!(sessions[0]["search"], "*");
+    assert_eq!(sessions[1]["filled"], false);
+    assert_eq!(sessions[1]["search"], serde_json::Value::Null);
+    assert_eq!(
+        dump["commands"],
+        json!([{ "command": 9, "cancelled": true }])
+    );
+    assert_eq!(dump["caches"]["hydrations"], 0);
+
+    // the same document over the control verb, and never a value's data
+    let text = regfs.execute(ControlCommand::Dump);
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&text).unwrap()["sessions"],
+        dump["sessions"]
+    );
+    assert!(!text.contains("hunter2"));
+}
+
+#[test]
+fn test_event_sequence() {
+    use std::sync::mpsc;
+
+    let backend = seeded(&["HKEY_CURRENT_USER\\Console"], &[]);
+    let (sender, events) = mpsc::sync_channel(16);
+    let options = RegFsOptions {
+        events: Some(sender),
+        ..Default::default()
+    };
+    let regfs = RegFs::with_backend(&options, backend);
+
+    let data = callback_data("HKEY_CURRENT_USER\\Console", 0);
+    let guid = GUID::default();
+    let parameters = unsafe { std::mem::zeroed() };
+
+    assert_eq!(regfs.start_dir_enum(&data, &guid).unwrap(), S_OK);
+    assert_eq!(regfs.end_dir_enum(&data, &guid).unwrap(), S_OK);
+    assert_eq!(
+        regfs
+            .notify(
+                &data,
+                true,
+                prjfs::sys::PRJ_NOTIFICATION_PRE_DELETE,
+                std::ptr::null(),
+                &parameters
+            )
+            .unwrap(),
+        HRESULT_FROM_WIN32(winerror::ERROR_ACCESS_DENIED)
+    );
+
+    let console = PathBuf::from("HKEY_CURRENT_USER\\Console");
+    assert_eq!(
+        events.try_iter().collect::<Vec<_>>(),
+        vec![
+            RegFsEvent::EnumerationStarted {
+                path: console.clone()
+            },
+            RegFsEvent::EnumerationEnded {
+                path: console.clone()
+            },
+            RegFsEvent::NotificationReceived {
+                path: console.clone(),
+                notification: prjfs::sys::PRJ_NOTIFICATION_PRE_DELETE,
+            },
+            RegFsEvent::OperationDenied {
+                path: console,
+                reason: "read-only".to_string(),
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_observer_sees_callbacks() {
+    use crate::observer::{EnumPhase, ProviderObserver, Recorder};
+
+    struct Panicking;
+
+    impl ProviderObserver for Panicking {
+        fn on_read(&self, _event: &crate::observer::ReadEvent) {
+            panic!("observer");
+        }
+    }
+
+    let backend = seeded(
+        &[],
+        &[(
+            "HKEY_CURRENT_USER\\Console",
+            "FontSize",
+            4,
+            vec![1, 2, 3, 4],
+        )],
+    );
+    let recorder = Arc::new(Recorder::default());
+    let options = RegFsOptions::default()
+        .observer(Arc::new(Panicking))
+        .observer(recorder.clone());
+    let regfs = RegFs::with_backend(&options, backend);
+
+    let process = OsString::from("regedit.exe").to_wstr();
+    let call = |path: &str, f: &dyn Fn(&PRJ_CALLBACK_DATA) -> HRESULT| {
+        let mut data = callback_data(path, 0);
+        data.TriggeringProcessImageFileName = process.as_ptr();
+        f(&data)
+    };
+    let console = "HKEY_CURRENT_USER\\Console";
+    let value = "HKEY_CURRENT_USER\\Console\\FontSize";
+    let guid = GUID {
+        Data1: 7,
+        ..Default::default()
+    };
+    let search = OsString::from("*").to_wstr();
+    let parameters = unsafe { std::mem::zeroed() };
+
+    let not_found = HRESULT_FROM_WIN32(winerror::ERROR_FILE_NOT_FOUND);
+    let denied = HRESULT_FROM_WIN32(winerror::ERROR_ACCESS_DENIED);
+    call(console, &|data| regfs.start_dir_enum(data, &guid).unwrap());
+    call(console, &|data| {
+        regfs
+            .get_dir_enum(data, &guid, search.as_ptr(), std::ptr::null_mut())
+            .unwrap()
+    });
+    call(console, &|data| regfs.end_dir_enum(data, &guid).unwrap());
+    call(value, &|data| regfs.get_placeholder_info(data).unwrap());
+    call("HKEY_CURRENT_USER\\Missing", &|data| {
+        regfs.get_placeholder_info(data).unwrap()
+    });
+    call(value, &|data| regfs.get_file_data(data, 0, 4).unwrap());
+    call(value, &|data| {
+        regfs
+            .notify(
+                data,
+                false,
+                prjfs::sys::PRJ_NOTIFICATION_PRE_DELETE,
+                std::ptr::null(),
+                &parameters,
+            )
+            .unwrap()
+    });
+
+    let enums = recorder.enums.lock().unwrap().clone();
+    assert_eq!(
+        enums
+            .iter()
+            .map(|event| (event.phase.clone(), event.callback.hr))
+            .collect::<Vec<_>>(),
+        vec![
+            (EnumPhase::Start, S_OK),
+            (EnumPhase::Fill { search: "*".into() }, S_OK),
+            (EnumPhase::End, S_OK),
+        ]
+    );
+    assert!(enums.iter().all(|event| {
+        event.enumeration == guid_to_bytes(&guid)
+            && event.callback.path == Path::new(console)
+            && event.callback.process == "regedit.exe"
+    }));
+
+    let placeholders = recorder.placeholders.lock().unwrap().clone();
+    assert_eq!(placeholders.len(), 2);
+    assert_eq!(placeholders[0].callback.path, Path::new(value));
+    assert_eq!(placeholders[0].callback.hr, S_OK);
+    assert_eq!(
+        (placeholders[0].is_directory, placeholders[0].size),
+        (Some(false), Some(4))
+    );
+    assert_eq!(placeholders[1].callback.hr, not_found);
+    assert_eq!(
+        (placeholders[1].is_directory, placeholders[1].size),
+        (None, None)
+    );
+
+    // the observer before it panicked, and it still heard about the read
+    let reads = recorder.reads.lock().unwrap().clone();
+    assert_eq!(reads.len(), 1);
+    assert_eq!(
+        (reads[0].offset, reads[0].length, reads[0].size),
+        (0, 4, Some(4))
+    );
+    assert_eq!(reads[0].callback.hr, S_OK);
+
+    let notifies = recorder.notifies.lock().unwrap().clone();
+    assert_eq!(notifies.len(), 1);
+    assert_eq!(
+        notifies[0].notification,
+        prjfs::sys::PRJ_NOTIFICATION_PRE_DELETE
+    );
+    assert!(!notifies[0].is_directory);
+    assert_eq!(notifies[0].callback.hr, denied);
+
+    // what the built-in metrics made of the same events
+    let snapshot = regfs.metrics_snapshot();
+    assert_eq!(snapshot.observer_panics, 1);
+    assert_eq!(snapshot.enumerations, 1);
+    assert_eq!(snapshot.hydrated_bytes, 4);
+    assert_eq!(snapshot.denied_operations, 1);
+    assert!(recorder
+        .events
+        .lock()
+        .unwrap()
+        .contains(&RegFsEvent::OperationDenied {
+            path: value.into(),
+            reason: "read-only".into(),
+        }));
+}
+
+#[test]
+fn test_slow_consumer_drops_events() {
+    use std::sync::mpsc;
+
+    let (sender, events) = mpsc::sync_channel(1);
+    let options = RegFsOptions {
+        events: Some(sender),
+        ..Default::default()
+    };
+    let regfs = RegFs::with_backend(&options, seeded(&["HKEY_USERS"], &[]));
+
+    let data = callback_data("HKEY_USERS", 0);
+    let guid = GUID::default();
+
+    // nobody reads, so only the first event fits and the callbacks still return right away
+    assert_eq!(regfs.start_dir_enum(&data, &guid).unwrap(), S_OK);
+    assert_eq!(regfs.end_dir_enum(&data, &guid).unwrap(), S_OK);
+    assert_eq!(regfs.start_dir_enum(&data, &guid).unwrap(), S_OK);
+    assert_eq!(regfs.metrics_snapshot().dropped_events, 2);
+    assert_eq!(events.try_iter().count(), 1);
+}
+
+#[test]
+fn test_custom_policy_runs_after_built_ins() {
+    use crate::memory::MemoryBackend;
+
+    struct DenySecret;
+
+    impl MutationPolicy for DenySecret {
+        fn decide(&self, request: &MutationRequest) -> Decision {
+            let secret = Path::new("HKEY_CURRENT_USER\\Secret");
+            match request.path.starts_with(secret)
+                || request
+                    .destination
+                    .map_or(false, |to| to.starts_with(secret))
+            {
+                true => Decision::Deny("secret".into()),
+                false => Decision::Allow,
+            }
+        }
+    }
+
+    let options = RegFsOptions {
+        readonly: false,
+        policy: Some(Arc::new(DenySecret)),
+        ..Default::default()
+    };
+    let regfs = RegFs::with_backend(&options, Arc::new(MemoryBackend::new()));
+    let parameters = unsafe { std::mem::zeroed() };
+    let notify = |path: &str, notification, destination: &str| {
+        let data = callback_data(path, 0);
+        let destination = OsString::from(destination).to_wstr();
+        regfs
+            .notify(
+                &data,
+                false,
+                notification,
+                destination.as_ptr(),
+                &parameters,
+            )
+            .unwrap()
+    };
+    let denied = HRESULT_FROM_WIN32(winerror::ERROR_ACCESS_DENIED);
+
+    let rename = prjfs::sys::PRJ_NOTIFICATION_PRE_RENAME;
+    let delete = prjfs::sys::PRJ_NOTIFICATION_PRE_DELETE;
+    let write = prjfs::sys::PRJ_NOTIFICATION_FILE_PRE_CONVERT_TO_FULL;
+    assert_eq!(
+        notify("HKEY_CURRENT_USER\\a", rename, "HKEY_CURRENT_USER\\b"),
+        S_OK
+    );
+    assert_eq!(
+        notify(
+            "HKEY_CURRENT_USER\\a",
+            rename,
+            "HKEY_CURRENT_USER\\Secret\\a"
+        ),
+        denied
+    );
+    assert_eq!(notify("HKEY_CURRENT_USER\\Secret\\x.sz", write, ""), denied);
+    assert_eq!(notify("HKEY_CURRENT_USER\\Other\\x.sz", write, ""), S_OK);
+    assert_eq!(notify("HKEY_CURRENT_USER\\Secret", delete, ""), denied);
+    // protected by the built-ins, whatever the custom policy says
+    assert_eq!(notify("HKEY_CURRENT_USER", delete, ""), denied);
+}
+
+#[test]
+fn test_hardlinks() {
+    use crate::memory::MemoryBackend;
+    use std::sync::mpsc;
+
+    let (sender, events) = mpsc::sync_channel(16);
+    let regfs = |readonly, allow_hardlinks| {
+        let options = RegFsOptions {
+            readonly,
+            allow_hardlinks,
+            events: Some(sender.clone()),
+            ..Default::default()
+        };
+        RegFs::with_backend(&options, Arc::new(MemoryBackend::new()))
+    };
+    let parameters = unsafe { std::mem::zeroed() };
+    let notify = |regfs: &RegFs, notification| {
+        let data = callback_data("HKEY_CURRENT_USER\\Console\\FontSize.dword", 0);
+        let link = OsString::from("HKEY_CURRENT_USER\\Console\\Alias.dword").to_wstr();
+        regfs
+            .notify(&data, false, notification, link.as_ptr(), &parameters)
+            .unwrap()
+    };
+    let denied = HRESULT_FROM_WIN32(winerror::ERROR_ACCESS_DENIED);
+    let pre = prjfs::sys::PRJ_NOTIFICATION_PRE_SET_HARDLINK;
+    let created = prjfs::sys::PRJ_NOTIFICATION_HARDLINK_CREATED;
+
+    assert_eq!(notify(&regfs(true, false), pre), denied);
+    assert_eq!(notify(&regfs(false, false), pre), denied);
+    assert_eq!(notify(&regfs(true, true), pre), denied);
+    let allowing = regfs(false, true);
+    assert_eq!(notify(&allowing, pre), S_OK);
+    let _ = events.try_iter().count();
+
+    // and audited with both names
+    assert_eq!(notify(&allowing, created), S_OK);
+    let path = PathBuf::from("HKEY_CURRENT_USER\\Console\\FontSize.dword");
+    assert!(events.try_iter().any(|event| event
+        == RegFsEvent::HardlinkCreated {
+            path: path.clone(),
+            link: path.with_file_name("Alias.dword"),
+        }));
+}
+
+#[test]
+fn test_overwritten_write_back() {
+    use crate::{conflict::ConflictPolicies, hash, regfile, scratchdir::ScratchDir};
+    use winapi::um::winnt::REG_SZ;
+
+    let root = ScratchDir::new("overwritten");
+    let app = Path::new("HKEY_CURRENT_USER\\Software\\App");
+    let name = app.join("Name");
+    fs::create_dir_all(root.join(app)).unwrap();
+    let parameters = unsafe { std::mem::zeroed() };
+
+    // projected, then changed under the mount, then handled as `notifications` say
+    let write_back = |content: &[u8], notifications: &[prjfs::sys::PRJ_NOTIFICATION]| {
+        let lower = seeded(
+            &[],
+            &[(
+                "HKEY_CURRENT_USER\\Software\\App",
+                "Name",
+                REG_SZ,
+                regfile::string_data("read"),
+            )],
+        );
+        let mut conflict = ConflictPolicies::default();
+        conflict.add("fail").unwrap();
+        let options = RegFsOptions {
+            root: root.to_path_buf(),
+            backends: vec![BackendSpec::Overlay],
+            readonly: false,
+            conflict,
+            ..Default::default()
+        };
+        let regfs = RegFs::with_backend(&options, lower.clone());
+        regfs.record_content_id(&name, hash::fnv1a(&regfile::string_data("read")));
+        lower.set_value(app, "Name", REG_SZ, regfile::string_data("concurrent"));
+
+        let data = callback_data(&name, 0);
+        for notification in notifications {
+            if *notification == prjfs::sys::PRJ_NOTIFICATION_FILE_OVERWRITTEN {
+                fs::write(root.join(&name), content).unwrap();
+            }
+            let result = regfs
+                .notify(&data, false, *notification, std::ptr::null(), &parameters)
+                .unwrap();
+            assert_eq!(result, S_OK);
+        }
+        assert!(!regfs.take_overwritten(&name));
+        regfs.overlay().unwrap().read_typed_value(&name).unwrap().1
+    };
+    let overwritten = prjfs::sys::PRJ_NOTIFICATION_FILE_OVERWRITTEN;
+    let modified = prjfs::sys::PRJ_NOTIFICATION_FILE_HANDLE_CLOSED_FILE_MODIFIED;
+    let unmodified = prjfs::sys::PRJ_NOTIFICATION_FILE_HANDLE_CLOSED_NO_MODIFICATION;
+
+    // an edit in place still loses to the concurrent change
+    assert_eq!(
+        write_back(&regfile::string_data("saved"), &[modified]),
+        regfile::string_data("concurrent")
+    );
+    // truncated and closed: the value is emptied
+    assert_eq!(write_back(b"", &[overwritten, unmodified]), b"");
+    // truncated, then written, even shorter than what was there
+    assert_eq!(
+        write_back(&regfile::string_data("new"), &[overwritten, modified]),
+        regfile::string_data("new")
+    );
+}
+
+#[test]
+fn test_transformed_value_size_matches_content() {
+    use crate::transform::HexDump;
+    use winapi::um::winnt::REG_BINARY;
+
+    let backend = seeded(
+        &[],
+        &[
+            (
+                "HKEY_CURRENT_USER\\App",
+                "Blob",
+                REG_BINARY,
+                (0..40).collect(),
+            ),
+            ("HKEY_CURRENT_USER\\App", "Plain", REG_BINARY, vec![1, 2, 3]),
+        ],
+    );
+    let mut options = RegFsOptions::default();
+    options
+        .transformers
+        .add("HKEY_CURRENT_USER\\**\\Blob", Arc::new(HexDump));
+    let regfs = RegFs::with_backend(&options, backend);
+
+    let blob = Path::new("HKEY_CURRENT_USER\\App\\Blob");
+    let content = regfs
+        .read_projected_value(blob, &OpContext::none())
+        .unwrap()
+        .unwrap();
+    assert!(content.starts_with(b"00000000  00 01 02"));
+
+    let placeholder = regfs.placeholder_info(blob).unwrap();
+    assert_eq!(placeholder.FileBasicInfo.FileSize, content.len() as i64);
+    assert_eq!(
+        placeholder.VersionInfo.ContentID,
+        hash::content_id(&content)
+    );
+
+    let mut dirinfo = DirInfo::new("HKEY_CURRENT_USER\\App");
+    assert_eq!(
+        regfs.populate_dir_info_for_path(
+            "HKEY_CURRENT_USER\\App".into(),
+            &mut dirinfo,
+            "*".into(),
+            0
+        ),
+        Ok(true)
+    );
+    dirinfo.sort_entries_and_mark_filled(regfs.projfs());
+    let listed: Vec<(OsString, i64)> = std::iter::from_fn(|| {
+        dirinfo.current_is_valid().then(|| {
+            let entry = (
+                dirinfo.current_file_name().to_owned(),
+                dirinfo.current_basic_info().FileSize,
+            );
+            dirinfo.move_next();
+            entry
+        })
+    })
+    .collect();
+    assert_eq!(
+        listed,
+        vec![("Blob".into(), content.len() as i64), ("Plain".into(), 3),]
+    );
+
+    // writing the dump would need HexDump's inverse, which it has
+    let write = MutationRequest {
+        kind: MutationKind::ConvertToFull,
+        path: blob,
+        destination: None,
+        process: OsStr::new(""),
+        is_directory: false,
+    };
+    assert_eq!(regfs.decide(&write), Decision::Allow);
+}
+
+#[test]
+fn test_text_encoding_sizes() {
+    use crate::{regfile, transform::TextEncoding};
+    use winapi::um::winnt::{REG_BINARY, REG_SZ};
+
+    let backend = seeded(
+        &[],
+        &[
+            (
+                "HKEY_CURRENT_USER\\App",
+                "Name",
+                REG_SZ,
+                regfile::string_data("Grüße"),
+            ),
+            ("HKEY_CURRENT_USER\\App", "Blob", REG_BINARY, vec![1, 2, 3]),
+        ],
+    );
+    let name = Path::new("HKEY_CURRENT_USER\\App\\Name");
+
+    // the NUL goes, and UTF-16 has a BOM instead; UTF-8 takes two bytes for ü and ß
+    for (encoding, size) in [(TextEncoding::Utf16, 12), (TextEncoding::Utf8, 7)] {
+        let mut options = RegFsOptions::default();
+        options.transformers.set_text_encoding(encoding);
+        let regfs = RegFs::with_backend(&options, backend.clone());
+
+        let content = regfs
+            .read_projected_value(name, &OpContext::none())
+            .unwrap()
+            .unwrap();
+        assert_eq!(content.len(), size, "{}", encoding);
+        let placeholder = regfs.placeholder_info(name).unwrap();
+        assert_eq!(placeholder.FileBasicInfo.FileSize, size as i64);
+
+        let mut dirinfo = DirInfo::new("HKEY_CURRENT_USER\\App");
+        assert_eq!(
+            regfs.populate_dir_info_for_path(
+                "HKEY_CURRENT_USER\\App".into(),
+                &mut dirinfo,
+                "*".into(),
+                0
+            ),
+            Ok(true)
+        );
+        dirinfo.sort_entries_and_mark_filled(regfs.projfs());
+        let listed: Vec<(OsString, i64)> = std::iter::from_fn(|| {
+            dirinfo.current_is_valid().then(|| {
+                let entry = (
+                    dirinfo.current_file_name().to_owned(),
+                    dirinfo.current_basic_info().FileSize,
+                );
+                dirinfo.move_next();
+                entry
+            })
+        })
+        .collect();
+        assert_eq!(
+            listed,
+            vec![("Blob".into(), 3), ("Name".into(), size as i64)]
+        );
+    }
+}
+
+#[test]
+fn test_filtered_entry_is_consistently_invisible() {
+    use crate::filter::EntryFilter;
+
+    struct HideSecrets;
+
+    impl EntryFilter for HideSecrets {
+        fn include(&self, _parent: &Path, entry: &ProjectedEntry) -> bool {
+            !entry.name.to_string_lossy().starts_with("Secret")
+        }
+    }
+
+    let backend = seeded(
+        &["HKEY_CURRENT_USER\\App\\SecretKeys"],
+        &[
+            ("HKEY_CURRENT_USER\\App", "Name", 1, vec![b'a', 0]),
+            ("HKEY_CURRENT_USER\\App", "SecretToken", 1, vec![b'b', 0]),
+        ],
+    );
+    let options = RegFsOptions {
+        filter: Some(Arc::new(HideSecrets)),
+        ..Default::default()
+    };
+    let regfs = RegFs::with_backend(&options, backend.clone());
+
+    let mut dirinfo = DirInfo::new("HKEY_CURRENT_USER\\App");
+    assert_eq!(
+        regfs.populate_dir_info_for_path(
+            "HKEY_CURRENT_USER\\App".into(),
+            &mut dirinfo,
+            "*".into(),
+            0
+        ),
+        Ok(true)
+    );
+    dirinfo.sort_entries_and_mark_filled(regfs.projfs());
+    assert!(dirinfo.current_is_valid());
+    assert_eq!(dirinfo.current_file_name().to_owned(), "Name");
+    assert!(!dirinfo.move_next());
+
+    let query_with =
+        |regfs: &RegFs, path: &str| regfs.query_file_name(&callback_data(path, 0)).unwrap();
+    let query = |path: &str| query_with(&regfs, path);
+    for hidden in [
+        "HKEY_CURRENT_USER\\App\\SecretToken",
+        "HKEY_CURRENT_USER\\App\\SecretKeys",
+    ] {
+        assert!(
+            regfs.placeholder_info(hidden.as_ref()).is_none(),
+            "{}",
+            hidden
+        );
+        assert_eq!(
+            query(hidden),
+            HRESULT_FROM_WIN32(winerror::ERROR_FILE_NOT_FOUND)
+        );
+    }
+    assert!(regfs
+        .placeholder_info("HKEY_CURRENT_USER\\App\\Name".as_ref())
+        .is_some());
+    assert_eq!(query("HKEY_CURRENT_USER\\App\\Name"), S_OK);
+    // nor by a wildcard that only matches what's hidden
+    assert_eq!(
+        query("HKEY_CURRENT_USER\\App\\Secret*"),
+        HRESULT_FROM_WIN32(winerror::ERROR_FILE_NOT_FOUND)
+    );
+    assert_eq!(query("HKEY_CURRENT_USER\\App\\N*"), S_OK);
+
+    // the filter is given the value's size, which takes no read of it
+    let reads = backend.reads();
+    assert_eq!(query("HKEY_CURRENT_USER\\App\\Name"), S_OK);
+    assert_eq!(backend.reads(), reads);
+    // unless a transformer may make the file another size
+    let mut options = RegFsOptions {
+        filter: Some(Arc::new(HideSecrets)),
+        ..Default::default()
+    };
+    options
+        .transformers
+        .add("**\\Name", Arc::new(crate::transform::HexDump));
+    let transforming = RegFs::with_backend(&options, backend.clone());
+    assert_eq!(
+        query_with(&transforming, "HKEY_CURRENT_USER\\App\\Name"),
+        S_OK
+    );
+    assert_eq!(backend.reads(), reads + 1);
+}
+
+#[test]
+fn test_impersonation_fails_closed() {
+    use crate::regop::scratch::ScratchKey;
+
+    let scratch = match ScratchKey::try_empty() {
+        Some(scratch) => scratch,
+        None => {
+            eprintln!("test_impersonation_fails_closed: no registry, skipped");
+            return;
+        }
+    };
+    scratch.key.create_subkey("Child").unwrap();
+    let options = RegFsOptions {
+        impersonate: true,
+        ..Default::default()
+    };
+    let regfs = RegFs::with_backend(&options, Arc::new(RegOps::new()));
+    let populate = |regfs: &RegFs| {
+        let mut dirinfo = DirInfo::new(scratch.path());
+        regfs.populate_dir_info_for_path(scratch.path().into(), &mut dirinfo, "*".into(), 7)
+    };
+
+    // no triggering process to run as, so the key can't be opened
+    assert_eq!(populate(&regfs), Ok(false));
+
+    let data = PRJ_CALLBACK_DATA {
+        CommandId: 7,
+        TriggeringProcessId: std::process::id(),
+        ..Default::default()
+    };
+    regfs.track_process(&data);
+    assert_eq!(populate(&regfs), Ok(true));
+    regfs.end_command(7);
+    assert_eq!(populate(&regfs), Ok(false));
+
+    let regfs = RegFs::with_backend(&Default::default(), Arc::new(RegOps::new()));
+    assert_eq!(populate(&regfs), Ok(true));
+}
+
+#[cfg(test)]
+fn with_mock_projfs() -> (RegFs, Arc<crate::prj_compat::MockPrjApi>) {
+    let backend = seeded(
+        &[
+            "HKEY_CURRENT_USER\\App\\Keyboard",
+            "HKEY_CURRENT_USER\\App\\Console",
+        ],
+        &[
+            ("HKEY_CURRENT_USER\\App", "Blob", 3, b"0123456789".to_vec()),
+            ("HKEY_CURRENT_USER\\App", "bruh", 1, vec![]),
+        ],
+    );
+    let mock = Arc::new(crate::prj_compat::MockPrjApi::default());
+    let options = RegFsOptions {
+        prj_api: Some(mock.clone()),
+        ..Default::default()
+    };
+    (RegFs::with_backend(&options, backend), mock)
+}
+
+#[test]
+fn test_value_cache() {
+    use crate::memory::MemoryBackend;
+
+    let backend = Arc::new(MemoryBackend::new());
+    let key = Path::new("HKEY_CLASSES_ROOT\\.txt");
+    backend.set_value(key, "Icon", 3, b"icon".to_vec());
+    backend.set_value(key, "Big", 3, vec![7; 70_000]);
+    let mock = Arc::new(crate::prj_compat::MockPrjApi::default());
+    let options = RegFsOptions {
+        value_cache: true,
+        prj_api: Some(mock.clone()),
+        ..Default::default()
+    };
+    let regfs = RegFs::with_backend(&options, backend.clone());
+
+    // each time from a new data stream, so only --value-cache can spare the read
+    let streams = std::cell::Cell::new(0);
+    let hydrate = |name: &str| {
+        streams.set(streams.get() + 1);
+        let mut data = callback_data(key.join(name), 0);
+        data.DataStreamId.Data1 = streams.get();
+        assert_eq!(regfs.get_file_data(&data, 0, 100_000).unwrap(), S_OK);
+        mock.written.lock().unwrap().pop().unwrap().1
+    };
+
+    assert_eq!(hydrate("Icon"), b"icon");
+    let reads = backend.reads();
+    assert_eq!(hydrate("Icon"), b"icon");
+    assert_eq!(backend.reads(), reads);
+    let snapshot = regfs.metrics_snapshot();
+    assert_eq!(
+        (snapshot.value_cache_hits, snapshot.value_cache_misses),
+        (1, 1)
+    );
+    assert_eq!(regfs.dump()["caches"]["value_bytes"], 4);
+
+    // over --value-cache-max-value, read every time
+    hydrate("Big");
+    hydrate("Big");
+    assert_eq!(backend.reads(), reads + 2);
+
+    // a change the watchers report, or a write through the mount
+    backend.set_value(key, "Icon", 3, b"new".to_vec());
+    assert_eq!(hydrate("Icon"), b"new");
+    assert_eq!(backend.reads(), reads + 3);
+    regfs.forget_written(Some(&key.join("Icon")));
+    hydrate("Icon");
+    assert_eq!(backend.reads(), reads + 4);
+    hydrate("Icon");
+    assert_eq!(backend.reads(), reads + 4);
+}
+
+#[test]
+fn test_projfs_calls_go_through_the_api() {
+    let (regfs, mock) = with_mock_projfs();
+
+    let data = callback_data("HKEY_CURRENT_USER\\App\\bruh", 0);
+    assert_eq!(regfs.get_placeholder_info(&data).unwrap(), S_OK);
+    assert_eq!(mock.calls(), ["symlink HKEY_CURRENT_USER\\App\\bruh"]);
+    mock.calls.lock().unwrap().clear();
+
+    // sorted and matched by the api
+    let search = OsString::from("*O*").to_wstr();
+    let data = callback_data("HKEY_CURRENT_USER\\App", 0);
+    let guid = GUID::default();
+    assert_eq!(regfs.start_dir_enum(&data, &guid).unwrap(), S_OK);
+    assert_eq!(
+        regfs
+            .get_dir_enum(&data, &guid, search.as_ptr(), std::ptr::null_mut())
+            .unwrap(),
+        S_OK
+    );
+    assert_eq!(mock.calls(), ["fill Blob", "fill Console", "fill Keyboard"]);
+    mock.calls.lock().unwrap().clear();
+
+    // on the pool, after the callback returned
+    regfs.refresh_synthetic(Path::new(".regfs\\stats"));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while mock.calls().is_empty() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(mock.calls(), ["delete .regfs\\stats"]);
+}
+
+#[test]
+fn test_file_data_through_the_api() {
+    let (regfs, mock) = with_mock_projfs();
+    let mut data = callback_data("HKEY_CURRENT_USER\\App\\Blob", 0);
+    data.DataStreamId.Data1 = 1;
+
+    assert_eq!(regfs.get_file_data(&data, 2, 4).unwrap(), S_OK);
+    assert_eq!(mock.calls(), ["allocate 4", "write 2 4", "free"]);
+    assert_eq!(*mock.written.lock().unwrap(), [(2, b"2345".to_vec())]);
+    assert_eq!(regfs.metrics_snapshot().hydrated_bytes, 4);
+
+    // the buffer is still freed and the stream's slot released, and nothing was hydrated
+    mock.calls.lock().unwrap().clear();
+    mock.write_result
+        .store(winerror::E_ACCESSDENIED, Ordering::SeqCst);
+    assert_eq!(
+        regfs.get_file_data(&data, 0, 10).unwrap(),
+        winerror::E_ACCESSDENIED
+    );
+    assert_eq!(mock.calls(), ["allocate 10", "write 0 10", "free"]);
+    assert_eq!(regfs.metrics_snapshot().hydrated_bytes, 4);
+    assert_eq!(regfs.hydrations.pending(), 0);
+
+    mock.calls.lock().unwrap().clear();
+    mock.fail_allocation.store(true, Ordering::SeqCst);
+    assert_eq!(
+        regfs.get_file_data(&data, 0, 10).unwrap(),
+        winerror::E_OUTOFMEMORY
+    );
+    assert_eq!(mock.calls(), ["allocate 10"]);
+    assert_eq!(regfs.metrics_snapshot().hydrated_bytes, 4);
+    assert_eq!(regfs.hydrations.pending(), 0);
+    assert_eq!(mock.outstanding_buffers(), 0);
+}
+
+#[test]
+fn test_file_data_ranges() {
+    use crate::hydration::MAX_WRITE;
+
+    let (regfs, mock) = with_mock_projfs();
+    let mut data = callback_data("HKEY_CURRENT_USER\\App\\Blob", 0);
+    data.DataStreamId.Data1 = 1;
+
+    // at or past the end of the value, and nothing at all, write nothing
+    for (offset, length) in [(10, 4096), (12, 4096), (0, 0), (4, 0)] {
+        assert_eq!(regfs.get_file_data(&data, offset, length).unwrap(), S_OK);
+        assert!(mock.calls().is_empty(), "{} {}", offset, length);
+    }
+    assert_eq!(regfs.metrics_snapshot().hydrated_bytes, 0);
+    assert_eq!(regfs.hydrations.pending(), 0);
+
+    // the sector-sized read of the tail is what's left of it
+    assert_eq!(regfs.get_file_data(&data, 8, 4096).unwrap(), S_OK);
+    assert_eq!(mock.calls(), ["allocate 2", "write 8 2", "free"]);
+    assert_eq!(*mock.written.lock().unwrap(), [(8, b"89".to_vec())]);
+    assert_eq!(regfs.metrics_snapshot().hydrated_bytes, 2);
+
+    // a value of several megabytes goes in pieces of the write alignment, through one buffer
+    let big: Vec<u8> = (0..2 * MAX_WRITE + 5000).map(|n| n as u8).collect();
+    let backend = seeded(
+        &[],
+        &[(
+            "HKEY_LOCAL_MACHINE\\SOFTWARE\\Servicing",
+            "Blob",
+            3,
+            big.clone(),
+        )],
+    );
+    let mock = Arc::new(crate::prj_compat::MockPrjApi::default());
+    mock.write_alignment.store(4096, Ordering::SeqCst);
+    let options = RegFsOptions {
+        prj_api: Some(mock.clone()),
+        ..Default::default()
+    };
+    let regfs = RegFs::with_backend(&options, backend);
+    let mut data = callback_data("HKEY_LOCAL_MACHINE\\SOFTWARE\\Servicing\\Blob", 0);
+    data.DataStreamId.Data1 = 2;
+
+    let length = big.len() as u32 - 4096;
+    assert_eq!(regfs.get_file_data(&data, 4096, length).unwrap(), S_OK);
+    assert_eq!(
+        mock.calls(),
+        [
+            format!("allocate {}", MAX_WRITE),
+            format!("write 4096 {}", MAX_WRITE),
+            format!("write {} {}", 4096 + MAX_WRITE, MAX_WRITE),
+            format!("write {} 904", 4096 + 2 * MAX_WRITE),
+            "free".to_string(),
+        ]
+    );
+    let written: Vec<u8> = mock
+        .written
+        .lock()
+        .unwrap()
+        .iter()
+        .flat_map(|(_, content)| content.clone())
+        .collect();
+    assert!(written == big[4096..]);
+    assert_eq!(regfs.metrics_snapshot().hydrated_bytes, length as u64);
+
+    // a piece that fails ends the read, and the buffer is still freed
+    mock.calls.lock().unwrap().clear();
+    mock.write_result
+        .store(winerror::E_ACCESSDENIED, Ordering::SeqCst);
+    assert_eq!(
+        regfs.get_file_data(&data, 0, big.len() as u32).unwrap(),
+        winerror::E_ACCESSDENIED
+    );
+    assert_eq!(
+        mock.calls(),
+        [
+            format!("allocate {}", MAX_WRITE),
+            format!("write 0 {}", MAX_WRITE),
+            "free".to_string(),
+        ]
+    );
+    assert_eq!(mock.outstanding_buffers(), 0);
+    assert_eq!(regfs.hydrations.pending(), 0);
+}
+
+#[test]
+fn test_query_file_name() {
+    let nt = "HKEY_LOCAL_MACHINE\\SOFTWARE\\Microsoft\\Windows NT";
+    let backend = seeded(&[], &[(nt, "Build", 4, vec![0; 4])]);
+    backend.add_key(format!("{}\\CurrentVersion", nt));
+    let regfs = RegFs::with_backend(&RegFsOptions::default(), backend);
+
+    let query = |path: &str| {
+        let data = callback_data(path, 0);
+        regfs.query_file_name(&data).unwrap()
+    };
+    for found in [
+        "",
+        "HKEY_LOCAL_MACHINE",
+        "Windows NT\\Curr*",
+        "Windows NT\\currentversion",
+        "Windows NT\\Build",
+        "Windows NT\\B?ild",
+        "Windows NT\\*",
+    ] {
+        let path = found.replace("Windows NT", nt);
+        assert_eq!(query(&path), S_OK, "{}", path);
+    }
+
+    let not_found = HRESULT_FROM_WIN32(winerror::ERROR_FILE_NOT_FOUND);
+    for missing in [
+        "Windows NT\\Missing",
+        "Windows NT\\Miss*",
+        "Windows NT\\Build\\Curr*",
+        "Windows NT\\Missing\\Curr*",
+        "HKEY_LOCAL_MACHINE\\SYSTEM",
+        "Windows NT\\*.json",
+        "_sea*",
+    ] {
+        let path = missing.replace("Windows NT", nt);
+        assert_eq!(query(&path), not_found, "{}", path);
+    }
+}
+
+#[test]
+fn test_query_file_name_synthetic() {
+    let app = "HKEY_CURRENT_USER\\Software\\App";
+    let backend = seeded(&[], &[(app, "Name", 1, b"a\0".to_vec())]);
+    let options = RegFsOptions {
+        values_json: true,
+        search_dir: true,
+        ..Default::default()
+    };
+    let regfs = RegFs::with_backend(&options, backend);
+    let query = |path: &str| {
+        let data = callback_data(path, 0);
+        regfs.query_file_name(&data).unwrap()
+    };
+
+    // what only the projection has matches the same as what the registry has
+    for found in [
+        ".regfs\\con*",
+        ".regfs\\?tats",
+        ".re*",
+        "_sea*",
+        "HKEY_CURRENT_USER\\Software\\App\\*.json",
+        "HKEY_CURRENT_USER\\Software\\App\\_values.*",
+    ] {
+        assert_eq!(query(found), S_OK, "{}", found);
+    }
+    let not_found = HRESULT_FROM_WIN32(winerror::ERROR_FILE_NOT_FOUND);
+    for missing in [
+        ".regfs\\x*",
+        "_rec*",
+        "HKEY_CURRENT_USER\\Software\\App\\*.reg",
+        // nor does a key that isn't there
+        "HKEY_CURRENT_USER\\Software\\Missing\\*.json",
+    ] {
+        assert_eq!(query(missing), not_found, "{}", missing);
+    }
+}
+
+#[test]
+fn test_value_grown_since_its_placeholder() {
+    use crate::memory::MemoryBackend;
+
+    let backend = MemoryBackend::new();
+    let key = Path::new("HKEY_CURRENT_USER\\App");
+    backend.set_value(key, "Log", 3, b"0123456789".to_vec());
+    backend.grow_on_read(key, "Log", b"more".to_vec());
+    let mock = Arc::new(crate::prj_compat::MockPrjApi::default());
+    let options = RegFsOptions {
+        prj_api: Some(mock.clone()),
+        ..Default::default()
+    };
+    let regfs = RegFs::with_backend(&options, Arc::new(backend));
+
+    let mut data = callback_data("HKEY_CURRENT_USER\\App\\Log", 0);
+    data.DataStreamId.Data1 = 1;
+    assert_eq!(regfs.get_placeholder_info(&data).unwrap(), S_OK);
+    assert_eq!(regfs.advertised_size(&key.join("Log")), Some(10));
+
+    // 14 bytes by now, ProjFS only takes the 10 the placeholder says
+    assert_eq!(regfs.get_file_data(&data, 0, 4096).unwrap(), S_OK);
+    assert_eq!(*mock.written.lock().unwrap(), [(0, b"0123456789".to_vec())]);
+
+    // and the placeholder is updated with what the value has grown to since
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !mock.calls().iter().any(|call| call.starts_with("update")) {
+        assert!(Instant::now() < deadline, "{:?}", mock.calls());
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(mock
+        .calls()
+        .contains(&"update HKEY_CURRENT_USER\\App\\Log 18".to_string()));
+    while regfs.advertised_size(&key.join("Log")) != Some(18) {
+        assert!(Instant::now() < deadline);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn test_logs_never_show_value_data() {
+    use crate::{memory::MemoryBackend, redact, scratchdir::ScratchDir, transform::NumberText};
+    use winapi::um::winnt::{REG_BINARY, REG_DWORD};
+
+    redact::capture::start();
+    let secrets = [
+        "correct-horse-battery-staple",
+        "new-token-0xdeadbeef",
+        "pin-is-8675309",
+    ];
+
+    let root = ScratchDir::new("redact");
+    let app = Path::new("HKEY_CURRENT_USER\\Software\\Vault");
+    fs::create_dir_all(root.join(app)).unwrap();
+    let lower = Arc::new(MemoryBackend::new());
+    lower.set_value(app, "Token", REG_BINARY, secrets[0].as_bytes().to_vec());
+    lower.set_value(app, "Pin", REG_DWORD, 1234u32.to_le_bytes().to_vec());
+    let mut options = RegFsOptions {
+        root: root.to_path_buf(),
+        backends: vec![BackendSpec::Overlay],
+        ..Default::default()
+    };
+    options.transformers.add("**\\Pin", Arc::new(NumberText));
+    let regfs = RegFs::with_backend(&options, lower);
+
+    // list, project and read everything
+    let star = OsString::from("*").to_wstr();
+    let data = callback_data(app, 0);
+    let guid = GUID::default();
+    regfs.start_dir_enum(&data, &guid).unwrap();
+    regfs
+        .get_dir_enum(&data, &guid, star.as_ptr(), std::ptr::null_mut())
+        .unwrap();
+    regfs.end_dir_enum(&data, &guid).unwrap();
+    for name in ["Token", "Pin"] {
+        let data = callback_data(app.join(name), 0);
+        assert_eq!(regfs.get_placeholder_info(&data).unwrap(), S_OK);
+        assert_eq!(regfs.get_file_data(&data, 0, 4096).unwrap(), S_OK);
+    }
+
+    // a save that goes through and one that doesn't parse
+    fs::write(root.join(app).join("Token"), secrets[1]).unwrap();
+    regfs.record_change(Change::Modified(&app.join("Token")));
+    fs::write(root.join(app).join("Pin"), secrets[2]).unwrap();
+    regfs.record_change(Change::Modified(&app.join("Pin")));
+
+    let lines = redact::capture::lines();
+    let vault: Vec<&String> = lines.iter().filter(|line| line.contains("Vault")).collect();
+    assert!(vault.iter().any(|line| line.contains("written back")));
+    assert!(vault.iter().any(|line| line.contains("not a decimal")));
+    for line in &lines {
+        for secret in secrets {
+            assert!(!line.contains(secret), "{}", line);
+        }
+    }
+}
+
+#[test]
+fn test_rate_limited_process_is_turned_away() {
+    let backend = seeded(&[], &[("HKEY_CURRENT_USER\\App", "Name", 1, vec![])]);
+    let options = RegFsOptions {
+        rate_limits: vec![("Indexer.exe".into(), 1)],
+        ..Default::default()
+    };
+    let regfs = RegFs::with_backend(&options, backend);
+
+    let indexer = OsString::from("C:\\Tools\\indexer.exe").to_wstr();
+    let explorer = OsString::from("C:\\Windows\\explorer.exe").to_wstr();
+    let start = |process: &[u16]| {
+        let mut data = callback_data("HKEY_CURRENT_USER\\App", 0);
+        data.TriggeringProcessImageFileName = process.as_ptr();
+        regfs.start_dir_enum(&data, &GUID::default()).unwrap()
+    };
+
+    assert_eq!(start(&indexer), S_OK);
+    // the next turn is a second away, past what a callback is held for
+    assert_eq!(start(&indexer), HRESULT_FROM_WIN32(winerror::ERROR_RETRY));
+    for _ in 0..10 {
+        assert_eq!(start(&explorer), S_OK);
+    }
+
+    let snapshot = regfs.metrics_snapshot();
+    assert_eq!(snapshot.throttle_rejections, 1);
+    assert_eq!(snapshot.throttle_delays, 0);
+    assert_eq!(
+        regfs.status()["throttled"],
+        json!({ "indexer.exe": { "delayed": 0, "rejected": 1 } })
+    );
+}
+
+#[test]
+fn test_suspended_callbacks_are_turned_away() {
+    let (regfs, mock) = with_mock_projfs();
+    let retry = HRESULT_FROM_WIN32(winerror::ERROR_RETRY);
+    let mut file = callback_data("HKEY_CURRENT_USER\\App\\Blob", 0);
+    file.DataStreamId.Data1 = 1;
+    let dir = callback_data("HKEY_CURRENT_USER\\App", 0);
+    let star = OsString::from("*").to_wstr();
+    let guid = GUID::default();
+    let delete = MutationRequest {
+        kind: MutationKind::Delete,
+        path: Path::new("HKEY_CURRENT_USER\\App\\Blob"),
+        destination: None,
+        process: OsStr::new(""),
+        is_directory: false,
+    };
+
+    assert_eq!(regfs.start_dir_enum(&dir, &guid).unwrap(), S_OK);
+    assert_eq!(regfs.execute(ControlCommand::Suspend), "suspended");
+    assert_eq!(regfs.execute(ControlCommand::Suspend), "already suspended");
+    assert_eq!(regfs.get_placeholder_info(&file).unwrap(), retry);
+    assert_eq!(regfs.get_file_data(&file, 0, 10).unwrap(), retry);
+    assert_eq!(
+        regfs
+            .get_dir_enum(&dir, &guid, star.as_ptr(), std::ptr::null_mut())
+            .unwrap(),
+        retry
+    );
+    // nothing was read, so nothing went to ProjFS
+    assert!(mock.calls().is_empty());
+    assert_eq!(regfs.metrics_snapshot().hydrated_bytes, 0);
+    assert!(regfs.decide(&delete).is_deny());
+    assert_eq!(regfs.status()["suspended"], json!(true));
+    assert_eq!(regfs.metrics_snapshot().suspended, 1);
+
+    // a callback held while it's resumed goes through
+    let resumer = {
+        let regfs = regfs.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            regfs.execute(ControlCommand::Resume)
+        })
+    };
+    assert_eq!(regfs.get_placeholder_info(&file).unwrap(), S_OK);
+    assert_eq!(resumer.join().unwrap(), "resumed");
+    assert_eq!(regfs.execute(ControlCommand::Resume), "not suspended");
+
+    assert_eq!(regfs.get_file_data(&file, 0, 10).unwrap(), S_OK);
+    assert!(!regfs.decide(&delete).is_deny());
+    assert_eq!(regfs.status()["suspended"], json!(false));
+    assert_eq!(regfs.metrics_snapshot().suspended, 0);
+}
+
+#[test]
+fn test_max_entries_per_dir() {
+    use crate::{memory::MemoryBackend, prj_compat::MockPrjApi};
+
+    let backend = Arc::new(MemoryBackend::new());
+    for n in 0..10 {
+        backend.set_value(
+            "HKEY_CURRENT_USER\\Big",
+            format!("Value{:02}", n),
+            4,
+            vec![0; 4],
+        );
+    }
+    let mock = Arc::new(MockPrjApi::default());
+    let with_cap = |max: Option<usize>| {
+        let options = RegFsOptions {
+            max_entries_per_dir: max,
+            prj_api: Some(mock.clone()),
+            ..Default::default()
+        };
+        RegFs::with_backend(&options, backend.clone())
+    };
+    // what the last of `scans` fills of one enumeration served, each after a restart
+    let list = |regfs: &RegFs, search: &str, scans: usize| {
+        let search = OsString::from(search).to_wstr();
+        let mut data = callback_data("HKEY_CURRENT_USER\\Big", 0);
+        let guid = GUID::default();
+        regfs.start_dir_enum(&data, &guid).unwrap();
+        for _ in 0..scans {
+            mock.calls.lock().unwrap().clear();
+            regfs
+                .get_dir_enum(&data, &guid, search.as_ptr(), std::ptr::null_mut())
+                .unwrap();
+            data.Flags = prjfs::sys::PRJ_CB_DATA_FLAG_ENUM_RESTART_SCAN;
+        }
+        regfs.end_dir_enum(&data, &guid).unwrap();
+        mock.calls()
+    };
+
+    let regfs = with_cap(Some(4));
+    let capped = [
+        "fill __truncated__ (6 more entries)",
+        "fill Value00",
+        "fill Value01",
+        "fill Value02",
+        "fill Value03",
+    ];
+    assert_eq!(list(&regfs, "*", 1), capped);
+    // cut in the same place every time
+    assert_eq!(list(&regfs, "*", 3), capped);
+    // counted after the search, which the marker has to match too
+    assert_eq!(list(&regfs, "*9", 1), ["fill Value09"]);
+    assert_eq!(
+        list(&regfs, "*Value*", 1),
+        [
+            "fill Value00",
+            "fill Value01",
+            "fill Value02",
+            "fill Value03"
+        ]
+    );
+
+    // right at the cap nothing is cut
+    assert_eq!(list(&with_cap(Some(10)), "*", 1).len(), 10);
+    assert_eq!(
+        list(&with_cap(Some(9)), "*", 1)[0],
+        "fill __truncated__ (1 more entries)"
+    );
+    assert_eq!(list(&with_cap(None), "*", 1).len(), 10);
+
+    // what isn't listed can still be opened
+    let beyond = Path::new("HKEY_CURRENT_USER\\Big\\Value09");
+    assert!(regfs.placeholder_info(beyond).is_some());
+
+    // the marker is ours alone
+    let marker = Path::new("HKEY_CURRENT_USER\\Big\\__truncated__ (6 more entries)");
+    assert_eq!(regfs.synthetic(marker), Some(Synthetic::Truncated));
+    let placeholder = regfs.placeholder_info(marker).unwrap();
+    assert_eq!(placeholder.FileBasicInfo.IsDirectory, 0);
+    assert!(
+        String::from_utf8(regfs.synthetic_content(marker, Synthetic::Truncated))
+            .unwrap()
+            .starts_with("6 more entries")
+    );
+    assert!(with_cap(None).placeholder_info(marker).is_none());
+}
+
+#[test]
+fn test_trace_path_scopes_callbacks() {
+    use crate::redact::capture;
+    use winapi::um::winnt::REG_BINARY;
+
+    capture::start();
+    let backend = seeded(
+        &[],
+        &[
+            (
+                "HKEY_CURRENT_USER\\TracedKey\\Inner",
+                "Value",
+                REG_BINARY,
+                vec![1],
+            ),
+            ("HKEY_CURRENT_USER\\QuietKey", "Value", REG_BINARY, vec![2]),
+        ],
+    );
+    let options = RegFsOptions {
+        trace_paths: vec!["HKEY_CURRENT_USER\\TracedKey".into()],
+        ..Default::default()
+    };
+    let regfs = RegFs::with_backend(&options, backend);
+
+    let read = |path: &str| {
+        let mut data = callback_data(path, 0);
+        data.DataStreamId.Data1 = 1;
+        assert_eq!(regfs.get_placeholder_info(&data).unwrap(), S_OK);
+        assert_eq!(regfs.get_file_data(&data, 0, 1).unwrap(), S_OK);
+    };
+    let traced = |name: &str| -> Vec<String> {
+        capture::traced_lines()
+            .into_iter()
+            .filter(|line| line.contains(name))
+            .collect()
+    };
+
+    read("HKEY_CURRENT_USER\\TracedKey\\Inner\\Value");
+    read("HKEY_CURRENT_USER\\QuietKey\\Value");
+    // the callback from start to end, the cache's decision included
+    let lines = traced("TracedKey");
+    assert!(lines
+        .iter()
+        .any(|line| line.contains("----> get_file_data")));
+    assert!(lines
+        .iter()
+        .any(|line| line.contains("read from the backend")));
+    // the return line doesn't name the key, but it's in the same scope
+    assert!(capture::traced_lines()
+        .iter()
+        .any(|line| line.contains("<---- get_file_data: return")));
+    assert!(traced("QuietKey").is_empty());
+    assert!(!tracepath::in_scope());
+
+    // and from the control interface
+    regfs.execute(ControlCommand::Trace(TraceCommand::Add(
+        "HKEY_CURRENT_USER\\QuietKey".into(),
+    )));
+    read("HKEY_CURRENT_USER\\QuietKey\\Value");
+    assert!(traced("QuietKey")
+        .iter()
+        .any(|line| line.contains("served from the cache")));
+    assert_eq!(
+        regfs.execute(ControlCommand::Trace(TraceCommand::List)),
+        "HKEY_CURRENT_USER\\TracedKey\nHKEY_CURRENT_USER\\QuietKey\n"
+    );
+}
+
+#[test]
+fn test_recovers_lost_enumerations() {
+    use crate::{prj_compat::MockPrjApi, redact::capture};
+
+    capture::start();
+    let backend = seeded(
+        &[],
+        &[
+            ("HKEY_CURRENT_USER\\Lost", "One", 4, vec![0; 4]),
+            ("HKEY_CURRENT_USER\\Lost", "Two", 4, vec![0; 4]),
+        ],
+    );
+    let mock = Arc::new(MockPrjApi::default());
+    let provider = |recover: bool| {
+        let options = RegFsOptions {
+            recover_enumerations: recover,
+            prj_api: Some(mock.clone()),
+            ..Default::default()
+        };
+        RegFs::with_backend(&options, backend.clone())
+    };
+
+    let star = OsString::from("*").to_wstr();
+    let mut data = callback_data("HKEY_CURRENT_USER\\Lost", 0);
+    // never started, as after a restart of the provider
+    let lost = GUID {
+        Data1: 0x1057,
+        ..Default::default()
+    };
+    let list = |regfs: &RegFs, data: &PRJ_CALLBACK_DATA| {
+        mock.calls.lock().unwrap().clear();
+        let hr = regfs
+            .get_dir_enum(data, &lost, star.as_ptr(), std::ptr::null_mut())
+            .unwrap();
+        (hr, mock.calls())
+    };
+
+    // a continuation can't tell what the handle was already given
+    let regfs = provider(false);
+    assert_eq!(list(&regfs, &data), (winerror::E_INVALIDARG, vec![]));
+    assert!(regfs.sessions().is_empty());
+
+    // a restart scan starts over anyway
+    data.Flags = prjfs::sys::PRJ_CB_DATA_FLAG_ENUM_RESTART_SCAN;
+    assert_eq!(
+        list(&regfs, &data),
+        (S_OK, vec!["fill One".to_string(), "fill Two".to_string()])
+    );
+    assert!(capture::lines()
+        .iter()
+        .any(|line| line.contains("recovered the session") && line.contains("Lost")));
+    // and from then on it's a session like any other
+    data.Flags = 0;
+    assert_eq!(list(&regfs, &data), (S_OK, vec![]));
+    regfs.end_dir_enum(&data, &lost).unwrap();
+    assert!(regfs.sessions().is_empty());
+
+    // --recover-enumerations takes the continuation as a restart
+    let regfs = provider(true);
+    assert_eq!(
+        list(&regfs, &data),
+        (S_OK, vec!["fill One".to_string(), "fill Two".to_string()])
+    );
+}
+
+#[test]
+fn test_start_dir_enum_checks_the_path() {
+    let backend = seeded(
+        &["HKEY_CURRENT_USER\\Locked"],
+        &[("HKEY_CURRENT_USER\\App", "Name", 1, vec![])],
+    );
+    backend.set_last_write_time("HKEY_CURRENT_USER\\App", 42);
+    backend.deny_key("HKEY_CURRENT_USER\\Locked");
+    let regfs = RegFs::with_backend(&RegFsOptions::default(), backend);
+
+    let start = |path: &str| {
+        let data = callback_data(path, 0);
+        let hr = regfs.start_dir_enum(&data, &GUID::default()).unwrap();
+        let session = regfs
+            .lock_state()
+            .enum_sessions
+            .remove(&guid_key(&GUID::default()));
+        (hr, session)
+    };
+
+    // the key's time comes along, so the first fill doesn't look it up again
+    let (hr, session) = start("HKEY_CURRENT_USER\\App");
+    assert_eq!(hr, S_OK);
+    assert_eq!(
+        session.unwrap().dirinfo.lock().unwrap().key_time(),
+        Some(42)
+    );
+    let (hr, session) = start("");
+    assert_eq!(hr, S_OK);
+    assert!(session.is_some());
+
+    for (path, expected) in [
+        (
+            "HKEY_CURRENT_USER\\Missing",
+            HRESULT_FROM_WIN32(winerror::ERROR_FILE_NOT_FOUND),
+        ),
+        (
+            "HKEY_CURRENT_USER\\App\\Name",
+            HRESULT_FROM_WIN32(winerror::ERROR_DIRECTORY),
+        ),
+        (
+            "HKEY_CURRENT_USER\\Locked",
+            HRESULT_FROM_WIN32(winerror::ERROR_ACCESS_DENIED),
+        ),
+        (
+            ".regfs\\stats",
+            HRESULT_FROM_WIN32(winerror::ERROR_DIRECTORY),
+        ),
+    ] {
+        let (hr, session) = start(path);
+        assert_eq!(hr, expected, "{}", path);
+        assert!(session.is_none(), "{}", path);
+    }
+    assert_eq!(regfs.metrics_snapshot().enumerations, 2);
+}
+
+#[test]
+fn test_hide_empty_keys() {
+    use crate::prj_compat::MockPrjApi;
+
+    let backend = seeded(
+        &[
+            "HKEY_CURRENT_USER\\Root\\Empty",
+            "HKEY_CURRENT_USER\\Root\\Parent\\Empty",
+        ],
+        &[
+            ("HKEY_CURRENT_USER\\Root\\Full", "Value", 4, vec![0; 4]),
+            ("HKEY_CURRENT_USER\\Root\\Default", "", 1, vec![]),
+        ],
+    );
+    let mock = Arc::new(MockPrjApi::default());
+    let options = RegFsOptions {
+        hide_empty_keys: true,
+        prj_api: Some(mock.clone()),
+        ..Default::default()
+    };
+    let hiding = RegFs::with_backend(&options, backend.clone());
+
+    let list = |regfs: &RegFs, path: &str| {
+        let star = OsString::from("*").to_wstr();
+        let data = callback_data(path, 0);
+        mock.calls.lock().unwrap().clear();
+        assert_eq!(regfs.start_dir_enum(&data, &GUID::default()).unwrap(), S_OK);
+        regfs
+            .get_dir_enum(&data, &GUID::default(), star.as_ptr(), std::ptr::null_mut())
+            .unwrap();
+        regfs.end_dir_enum(&data, &GUID::default()).unwrap();
+        mock.calls()
+    };
+
+    // a key with only its default value isn't empty, nor is one with an empty subkey
+    assert_eq!(
+        list(&hiding, "HKEY_CURRENT_USER\\Root"),
+        ["fill Default", "fill Full", "fill Parent"]
+    );
+    assert!(list(&hiding, "HKEY_CURRENT_USER\\Root\\Parent").is_empty());
+    assert_eq!(hiding.dump()["caches"]["empty_keys"], 5);
+
+    // still there when asked for by name
+    let data = callback_data("HKEY_CURRENT_USER\\Root\\Empty", 0);
+    assert_eq!(hiding.get_placeholder_info(&data).unwrap(), S_OK);
+    assert!(list(&hiding, "HKEY_CURRENT_USER\\Root\\Empty").is_empty());
+
+    // and listed once something is in it
+    backend.set_value("HKEY_CURRENT_USER\\Root\\Empty", "Now", 4, vec![0; 4]);
+    assert_eq!(
+        list(&hiding, "HKEY_CURRENT_USER\\Root"),
+        ["fill Default", "fill Empty", "fill Full", "fill Parent"]
+    );
+    assert!(list(&hiding, "HKEY_CURRENT_USER\\Root\\Parent").is_empty());
+
+    // without the flag, nothing is hidden or asked for
+    let regfs = RegFs::with_backend(
+        &RegFsOptions {
+            prj_api: Some(mock.clone()),
+            ..Default::default()
+        },
+        backend,
+    );
+    assert_eq!(
+        list(&regfs, "HKEY_CURRENT_USER\\Root\\Parent"),
+        ["fill Empty"]
+    );
+    assert_eq!(regfs.dump()["caches"]["empty_keys"], 0);
+}
+
+#[test]
+fn test_synthetic_entries_are_hidden() {
+    use crate::{memory::MemoryBackend, prj_compat::MockPrjApi};
+    use winapi::um::winnt::{FILE_ATTRIBUTE_HIDDEN, FILE_ATTRIBUTE_SYSTEM};
+
+    let backend = Arc::new(MemoryBackend::new());
+    for n in 0..3 {
+        backend.set_value(
+            "HKEY_CURRENT_USER\\App",
+            format!("Value{}", n),
+            4,
+            vec![0; 4],
+        );
+    }
+    let mock = Arc::new(MockPrjApi::default());
+    let mount = |show_synthetic: bool| {
+        let options = RegFsOptions {
+            values_json: true,
+            max_entries_per_dir: Some(3),
+            search_dir: true,
+            recent_dir: true,
+            show_synthetic,
+            prj_api: Some(mock.clone()),
+            ..Default::default()
+        };
+        RegFs::with_backend(&options, backend.clone())
+    };
+    // the name and attributes of every entry one listing of `path` served, by name
+    let list = |regfs: &RegFs, path: &str| {
+        let star = OsString::from("*").to_wstr();
+        let data = callback_data(path, 0);
+        let guid = GUID::default();
+        mock.filled.lock().unwrap().clear();
+        regfs.start_dir_enum(&data, &guid).unwrap();
+        regfs
+            .get_dir_enum(&data, &guid, star.as_ptr(), std::ptr::null_mut())
+            .unwrap();
+        regfs.end_dir_enum(&data, &guid).unwrap();
+        let mut filled = mock.filled.lock().unwrap().clone();
+        filled.sort();
+        filled
+    };
+    let attributes = |filled: &[(String, u32)], name: &str| {
+        filled
+            .iter()
+            .find(|(filled, _)| filled == name)
+            .map(|(_, attributes)| *attributes)
+    };
+    let hidden = FILE_ATTRIBUTE_HIDDEN;
+
+    let regfs = mount(false);
+    let root = list(&regfs, "");
+    assert_eq!(
+        attributes(&root, ".regfs"),
+        Some(hidden | FILE_ATTRIBUTE_SYSTEM)
+    );
+    assert_eq!(attributes(&root, "_search"), Some(hidden));
+    assert_eq!(attributes(&root, "_recent"), Some(hidden));
+    assert_eq!(attributes(&root, "HKEY_CURRENT_USER"), Some(0));
+    let control = list(&regfs, ".regfs");
+    assert_eq!(attributes(&control, "control"), Some(hidden));
+    assert_eq!(attributes(&control, "stats"), Some(hidden));
+    // the generated files and the marker, but never a value
+    assert_eq!(
+        list(&regfs, "HKEY_CURRENT_USER\\App"),
+        [
+            ("Value0".to_string(), 0),
+            ("Value1".to_string(), 0),
+            ("__truncated__ (1 more entries)".to_string(), hidden),
+            ("_values.json".to_string(), hidden),
+        ]
+    );
+    // the same once opened by path
+    let placeholder = |regfs: &RegFs, path: &str| {
+        regfs
+            .placeholder_info(Path::new(path))
+            .unwrap()
+            .FileBasicInfo
+            .FileAttributes
+    };
+    assert_eq!(
+        placeholder(&regfs, ".regfs"),
+        hidden | FILE_ATTRIBUTE_SYSTEM
+    );
+    assert_eq!(placeholder(&regfs, ".regfs\\stats"), hidden);
+    assert_eq!(
+        placeholder(&regfs, "HKEY_CURRENT_USER\\App\\_values.json"),
+        hidden
+    );
+    assert_eq!(placeholder(&regfs, "HKEY_CURRENT_USER\\App\\Value2"), 0);
+
+    // --show-synthetic lists them as plain entries
+    let regfs = mount(true);
+    assert!(list(&regfs, "")
+        .iter()
+        .all(|(_, attributes)| *attributes == 0));
+    assert!(list(&regfs, "HKEY_CURRENT_USER\\App")
+        .iter()
+        .all(|(_, attributes)| *attributes == 0));
+    assert_eq!(placeholder(&regfs, ".regfs"), 0);
+}
+
+#[test]
+fn test_enum_cache() {
+    use crate::prj_compat::MockPrjApi;
+
+    let app = "HKEY_CURRENT_USER\\Software\\App";
+    let backend = seeded(&[], &[(app, "Name", 1, b"a\0".to_vec())]);
+    backend.add_key(format!("{}\\Sub", app));
+    backend.set_last_write_time(app, 10);
+    let mock = Arc::new(MockPrjApi::default());
+    let options = RegFsOptions {
+        enum_cache: true,
+        prj_api: Some(mock.clone()),
+        ..Default::default()
+    };
+    let regfs = RegFs::with_backend(&options, backend.clone());
+
+    // what was listed, after `cached` when it came from the cache
+    let list = |regfs: &RegFs, path: &str| {
+        let star = OsString::from("*").to_wstr();
+        let data = callback_data(path, 0);
+        mock.calls.lock().unwrap().clear();
+        assert_eq!(regfs.start_dir_enum(&data, &GUID::default()).unwrap(), S_OK);
+        regfs
+            .get_dir_enum(&data, &GUID::default(), star.as_ptr(), std::ptr::null_mut())
+            .unwrap();
+        let cached = regfs.dump()["sessions"][0]["cached"] == json!(true);
+        regfs.end_dir_enum(&data, &GUID::default()).unwrap();
+        let mut calls = mock.calls();
+        if cached {
+            calls.insert(0, "cached".to_string());
+        }
+        calls
+    };
+
+    assert_eq!(list(&regfs, app), ["fill Name", "fill Sub"]);
+    assert_eq!(list(&regfs, app), ["cached", "fill Name", "fill Sub"]);
+    assert_eq!(regfs.dump()["caches"]["listings"], 1);
+    assert_eq!(regfs.dump()["caches"]["listed_entries"], 2);
+
+    // the key's last write time moved without a word from the watchers
+    backend.set_last_write_time(app, 11);
+    assert_eq!(list(&regfs, app), ["fill Name", "fill Sub"]);
+    assert_eq!(list(&regfs, app)[0], "cached");
+    // and the watchers tell of a change that didn't move it
+    backend.set_value(app, "New", 1, b"n\0".to_vec());
+    assert_eq!(list(&regfs, app), ["fill Name", "fill New", "fill Sub"]);
+
+    // the root has listings of its own
+    list(&regfs, "");
+    assert_eq!(regfs.dump()["caches"]["listings"], 1);
+
+    let plain = RegFs::with_backend(
+        &RegFsOptions {
+            prj_api: Some(mock.clone()),
+            ..Default::default()
+        },
+        backend,
+    );
+    list(&plain, app);
+    assert_eq!(list(&plain, app), ["fill Name", "fill New", "fill Sub"]);
+    assert_eq!(plain.dump()["caches"]["listings"], 0);
+}
+
+#[test]
+fn test_long_value_names() {
+    use crate::{longnames, memory::MemoryBackend, prj_compat::MockPrjApi, scratchdir::ScratchDir};
+
+    let root = ScratchDir::new("long-names");
+    let app = Path::new("HKEY_CURRENT_USER\\Software\\App");
+    fs::create_dir_all(root.join(app)).unwrap();
+    let full = format!("{}End", "VeryLongName".repeat(30));
+    let lower = Arc::new(MemoryBackend::new());
+    lower.set_value(app, &full, 3, b"original".to_vec());
+    let mock = Arc::new(MockPrjApi::default());
+    let options = RegFsOptions {
+        root: root.to_path_buf(),
+        backends: vec![BackendSpec::Overlay],
+        values_json: true,
+        prj_api: Some(mock.clone()),
+        ..Default::default()
+    };
+    let regfs = RegFs::with_backend(&options, lower.clone());
+    let projected = longnames::project(full.as_ref()).unwrap();
+    let file = app.join(&projected);
+
+    // listed under a name the file system takes
+    let star = OsString::from("*").to_wstr();
+    let data = callback_data(app, 0);
+    regfs.start_dir_enum(&data, &GUID::default()).unwrap();
+    regfs
+        .get_dir_enum(&data, &GUID::default(), star.as_ptr(), std::ptr::null_mut())
+        .unwrap();
+    let mut listed = mock.calls();
+    listed.sort();
+    assert_eq!(
+        listed,
+        [
+            format!("fill {}", projected.to_string_lossy()),
+            "fill _values.json".to_string()
+        ]
+    );
+
+    // and read through it, by another mount too
+    for regfs in [regfs.clone(), RegFs::with_backend(&options, lower.clone())] {
+        let data = callback_data(&file, 0);
+        assert_eq!(regfs.get_placeholder_info(&data).unwrap(), S_OK);
+        assert_eq!(
+            regfs.read_projected_value(&file, &OpContext::none()),
+            Ok(Some(b"original".to_vec()))
+        );
+    }
+    // the sidecar has the real name
+    let json = regfs.synthetic_content(&app.join("_values.json"), Synthetic::ValuesJson);
+    let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    assert!(json.get(&full).is_some(), "{}", json);
+
+    // saved back to the value it came from
+    fs::write(root.join(&file), "changed").unwrap();
+    regfs.record_change(Change::Modified(&file));
+    assert_eq!(
+        regfs.regops().read_value(&app.join(&full)),
+        Some(b"changed".to_vec())
+    );
+    let values = regfs.regops().read_all_values(app).unwrap();
+    assert_eq!(values.len(), 1);
+    assert_eq!(values[0].0, *full);
+}
+
+#[test]
+fn test_paths_past_max_path() {
+    use crate::{memory::MemoryBackend, scratchdir::ScratchDir};
+
+    let root = ScratchDir::new("deep");
+    let mut key = PathBuf::from("HKEY_CURRENT_USER\\Software\\Scratch");
+    for level in 0..12 {
+        key.push(format!("Level{:02}-{}", level, "x".repeat(24)));
+    }
+    let file = key.join("Setting");
+    assert!(root.join(&file).as_os_str().len() > 300);
+
+    let lower = Arc::new(MemoryBackend::new());
+    lower.set_value(&key, "Setting", 3, b"deep".to_vec());
+    let options = RegFsOptions {
+        root: root.to_path_buf(),
+        backends: vec![BackendSpec::Overlay],
+        ..Default::default()
+    };
+    let regfs = RegFs::with_backend(&options, lower.clone());
+    let local = regfs.local_path(&file);
+    assert!(local.to_string_lossy().starts_with("\\\\?\\"));
+    fs::create_dir_all(regfs.local_path(&key)).unwrap();
+    fs::write(&local, "deep").unwrap();
+
+    // read back all the way down
+    let summary = regfs.hydrate(Path::new(""));
+    assert_eq!((summary.hydrated, summary.failed), (1, 0));
+    assert_eq!(summary.bytes, 4);
+    assert_eq!(regfs.dehydrate(Path::new("")).failed, 0);
+
+    // and saved back to the registry
+    fs::write(&local, "deeper").unwrap();
+    regfs.record_change(Change::Modified(&file));
+    assert_eq!(regfs.regops().read_value(&file), Some(b"deeper".to_vec()));
+}
+
+#[test]
+fn test_merge_virtualstore() {
+    use crate::{memory::MemoryBackend, scratchdir::ScratchDir};
+
+    let root = ScratchDir::new("virtualstore");
+    let scratch = Path::new("HKEY_LOCAL_MACHINE\\SOFTWARE\\Scratch");
+    let store = virtualstore::redirect(scratch).unwrap();
+    fs::create_dir_all(root.join(scratch)).unwrap();
+    let lower = Arc::new(MemoryBackend::new());
+    lower.set_value(scratch, "Setting", 3, b"machine".to_vec());
+    lower.set_value(scratch, "Other", 3, b"machine".to_vec());
+    lower.set_value(&store, "Setting", 3, b"store".to_vec());
+    let options = RegFsOptions {
+        root: root.to_path_buf(),
+        backends: vec![BackendSpec::Overlay],
+        merge_virtualstore: true,
+        ..Default::default()
+    };
+    let regfs = RegFs::with_backend(&options, lower.clone());
+
+    // what a legacy app reads
+    assert_eq!(
+        regfs.read_projected_value(&scratch.join("Setting"), &OpContext::none()),
+        Ok(Some(b"store".to_vec()))
+    );
+    assert_eq!(
+        regfs.read_projected_value(&scratch.join("Other"), &OpContext::none()),
+        Ok(Some(b"machine".to_vec()))
+    );
+    let meta = scratch.join(synthetic::META_JSON_FILE);
+    assert_eq!(regfs.synthetic(&meta), Some(Synthetic::MetaJson));
+    let json: serde_json::Value =
+        serde_json::from_slice(&regfs.synthetic_content(&meta, Synthetic::MetaJson)).unwrap();
+    assert_eq!(json["values"]["Setting"], "overrides");
+    assert_eq!(json["virtualstore"], store.to_string_lossy().as_ref());
+    assert_eq!(
+        regfs.synthetic(&Path::new("HKEY_LOCAL_MACHINE\\SYSTEM").join(synthetic::META_JSON_FILE)),
+        None
+    );
+
+    // written where the app's own write would land
+    fs::write(root.join(scratch).join("Other"), "changed").unwrap();
+    regfs.record_change(Change::Modified(&scratch.join("Other")));
+    let overlay = regfs.overlay().unwrap();
+    assert_eq!(
+        overlay.read_value(&store.join("Other")),
+        Some(b"changed".to_vec())
+    );
+    assert_eq!(
+        overlay.read_value(&scratch.join("Other")),
+        Some(b"machine".to_vec())
+    );
+    assert_eq!(
+        regfs.read_projected_value(&scratch.join("Other"), &OpContext::none()),
+        Ok(Some(b"changed".to_vec()))
+    );
+
+    // without the flag, HKLM is as it is
+    let regfs = RegFs::with_backend(
+        &RegFsOptions {
+            merge_virtualstore: false,
+            ..options
+        },
+        lower,
+    );
+    assert_eq!(
+        regfs.read_projected_value(&scratch.join("Setting"), &OpContext::none()),
+        Ok(Some(b"machine".to_vec()))
+    );
+    assert_eq!(regfs.synthetic(&meta), None);
+}
+
+#[test]
+fn test_users_hive_unloaded() {
+    let backend = seeded(&["HKEY_USERS\\S-1-5-21-1000"], &[]);
+    let options = RegFsOptions {
+        hide_empty_keys: true,
+        hives: vec![RootHive::Users],
+        ..Default::default()
+    };
+    let regfs = RegFs::with_backend(&options, backend.clone());
+    let sid = Path::new("HKEY_USERS\\S-1-5-21-1000");
+    assert!(regfs.is_empty_key(sid, 0));
+    assert_eq!(regfs.dump()["caches"]["empty_keys"], 1);
+
+    // HKEY_USERS is told, what's cached under it goes with the hive
+    backend.remove(sid);
+    assert_eq!(regfs.metrics_snapshot().registry_changes, 1);
+    assert_eq!(regfs.dump()["caches"]["empty_keys"], 0);
+    assert!(!regfs.is_empty_key(sid, 0));
+
+    // and loaded again, with something in it this time
+    backend.set_value(sid, "Value", 4, vec![0; 4]);
+    assert!(!regfs.is_empty_key(sid, 0));
+}
+
+#[test]
+fn test_user_classes() {
+    use crate::memory::MemoryBackend;
+
+    let backend = Arc::new(MemoryBackend::new());
+    let machine = Path::new("HKEY_LOCAL_MACHINE\\SOFTWARE\\Classes\\RegFs.Scratch");
+    let user = Path::new("HKEY_USERS\\S-1-5-21-1001_Classes\\RegFs.Scratch");
+    backend.set_value(machine, "EditFlags", 4, vec![0; 4]);
+    backend.set_value(machine, "FriendlyTypeName", 1, b"machine".to_vec());
+    backend.set_value(user, "FriendlyTypeName", 1, b"user".to_vec());
+    let options = RegFsOptions {
+        values_json: true,
+        user_classes: Some("S-1-5-21-1001".into()),
+        backends: vec![BackendSpec::Overlay],
+        readonly: false,
+        ..Default::default()
+    };
+    let regfs = RegFs::with_backend(&options, backend.clone());
+    let merged = Path::new("HKEY_CLASSES_ROOT (user S-1-5-21-1001)\\RegFs.Scratch");
+
+    assert_eq!(
+        regfs.read_projected_value(&merged.join("FriendlyTypeName"), &OpContext::none()),
+        Ok(Some(b"user".to_vec()))
+    );
+    assert_eq!(
+        regfs.read_projected_value(&merged.join("EditFlags"), &OpContext::none()),
+        Ok(Some(vec![0; 4]))
+    );
+    let file = merged.join(synthetic::VALUES_JSON_FILE);
+    let before = regfs.synthetic_content(&file, Synthetic::ValuesJson);
+
+    // a change to the user's hive is a change to the merged key
+    backend.set_value(user, "EditFlags", 4, vec![1, 0, 0, 0]);
+    assert_ne!(
+        regfs.synthetic_content(&file, Synthetic::ValuesJson),
+        before
+    );
+
+    // nothing under it is written, whatever the mode
+    let delete = MutationRequest {
+        kind: MutationKind::Delete,
+        path: &merged.join("EditFlags"),
+        destination: None,
+        process: OsStr::new(""),
+        is_directory: false,
+    };
+    assert!(regfs.decide(&delete).is_deny());
+    let elsewhere = MutationRequest {
+        path: &machine.join("EditFlags"),
+        ..delete
+    };
+    assert!(!regfs.decide(&elsewhere).is_deny());
+}
+
+#[test]
+fn test_bookmarks() {
+    use crate::memory::MemoryBackend;
+
+    let backend = Arc::new(MemoryBackend::new());
+    let run = Path::new("HKEY_CURRENT_USER\\Software\\Microsoft\\Windows\\CurrentVersion\\Run");
+    backend.set_value(run, "Updater", 1, b"updater.exe".to_vec());
+    let options = RegFsOptions {
+        values_json: true,
+        bookmarks: vec![format!("Run={}", run.display()).parse().unwrap()],
+        backends: vec![BackendSpec::Overlay],
+        readonly: false,
+        ..Default::default()
+    };
+    let regfs = RegFs::with_backend(&options, backend.clone());
+
+    assert_eq!(
+        regfs.read_projected_value("Run\\Updater".as_ref(), &OpContext::none()),
+        Ok(Some(b"updater.exe".to_vec()))
+    );
+    assert_eq!(
+        regfs.write_path("run\\Updater".as_ref()),
+        run.join("Updater")
+    );
+    assert!(regfs.is_bookmark("RUN".as_ref()));
+    assert!(!regfs.is_bookmark("Run\\Updater".as_ref()));
+
+    // a change to the key is a change to the bookmark
+    let file = Path::new("Run").join(synthetic::VALUES_JSON_FILE);
+    let before = regfs.synthetic_content(&file, Synthetic::ValuesJson);
+    backend.set_value(run, "Other", 1, b"other.exe".to_vec());
+    assert_ne!(
+        regfs.synthetic_content(&file, Synthetic::ValuesJson),
+        before
+    );
+
+    // the bookmark stays where it is, what's under it is the key's
+    let delete = MutationRequest {
+        kind: MutationKind::Delete,
+        path: Path::new("Run"),
+        destination: None,
+        process: OsStr::new(""),
+        is_directory: true,
+    };
+    assert!(regfs.decide(&delete).is_deny());
+    let under = MutationRequest {
+        path: Path::new("Run\\Updater"),
+        is_directory: false,
+        ..delete
+    };
+    assert!(!regfs.decide(&under).is_deny());
+}
+
+#[test]
+fn test_hive_aliases() {
+    use std::sync::mpsc;
+
+    let backend = seeded(
+        &[],
+        &[
+            (
+                "HKEY_LOCAL_MACHINE\\SOFTWARE\\App",
+                "Version",
+                1,
+                b"1".to_vec(),
+            ),
+            (
+                "HKEY_CURRENT_USER\\Software\\App",
+                "Name",
+                1,
+                b"app".to_vec(),
+            ),
+        ],
+    );
+    let (events, received) = mpsc::sync_channel(16);
+    let options = RegFsOptions {
+        hive_summary: true,
+        hive_aliases: vec![
+            "HKLM=machine".parse().unwrap(),
+            "HKCU=user".parse().unwrap(),
+        ],
+        backends: vec![BackendSpec::Overlay],
+        readonly: false,
+        events: Some(events),
+        ..Default::default()
+    };
+    let regfs = RegFs::with_backend(&options, backend);
+
+    // only the aliases are listed
+    let root = regfs.regops().enumerate_key("".into()).unwrap();
+    let names: Vec<_> = root.subkeys.iter().map(|key| key.name.clone()).collect();
+    assert!(names.contains(&"machine".into()));
+    assert!(names.contains(&"user".into()));
+    assert!(!names.contains(&"HKEY_LOCAL_MACHINE".into()));
+    assert!(!names.contains(&"HKEY_CURRENT_USER".into()));
+
+    // either name finds what's under the hive
+    for path in [
+        "machine\\SOFTWARE\\App\\Version",
+        "HKEY_LOCAL_MACHINE\\SOFTWARE\\App\\Version",
+    ] {
+        assert_eq!(
+            regfs.read_projected_value(path.as_ref(), &OpContext::none()),
+            Ok(Some(b"1".to_vec()))
+        );
+    }
+    assert!(regfs.placeholder_info("USER\\Software".as_ref()).is_some());
+    assert!(regfs
+        .placeholder_info("HKEY_CURRENT_USER\\Software".as_ref())
+        .is_some());
+
+    // what's reported outside the mount says the hive's own name
+    let file = Path::new("machine").join(synthetic::HIVE_SUMMARY_FILE);
+    let summary: serde_json::Value =
+        serde_json::from_slice(&regfs.synthetic_content(&file, Synthetic::HiveSummary)).unwrap();
+    assert_eq!(summary["hive"], "HKEY_LOCAL_MACHINE");
+    let delete = MutationRequest {
+        kind: MutationKind::Delete,
+        path: Path::new("machine"),
+        destination: None,
+        process: OsStr::new(""),
+        is_directory: true,
+    };
+    assert_eq!(regfs.decide(&delete), Decision::Deny("protected".into()));
+
+    regfs.record_change(Change::Deleted("user\\Software\\App\\Name".as_ref()));
+    let export = regfs.overlay().unwrap().changes().to_text();
+    assert!(export.contains("[HKEY_CURRENT_USER\\Software\\App]"));
+    assert!(!export.contains("user\\"));
+    assert_eq!(
+        received.try_iter().collect::<Vec<_>>(),
+        [RegFsEvent::WriteBackApplied {
+            path: "HKEY_CURRENT_USER\\Software\\App\\Name".into(),
+        }]
+    );
+}
+
+#[test]
+fn test_shadow_writes() {
+    use crate::{memory::MemoryBackend, scratchdir::ScratchDir};
+
+    let root = ScratchDir::new("shadow");
+    let app = Path::new("HKEY_CURRENT_USER\\Software\\App");
+    fs::create_dir_all(root.join(app)).unwrap();
+    let lower = Arc::new(MemoryBackend::new());
+    lower.set_value(app, "Name", 3, b"app".to_vec());
+    lower.set_value(app, "Old", 3, b"old".to_vec());
+    let staging = Arc::new(MemoryBackend::new());
+    let options = RegFsOptions::from_args(
+        ["--shadow-writes", "HKCU\\Software\\RegFsStaging"].map(String::from),
+    )
+    .unwrap();
+    let options = RegFsOptions {
+        root: root.to_path_buf(),
+        staging_registry: Some(staging.clone()),
+        ..options.build().unwrap()
+    };
+    let regfs = RegFs::with_backend(&options, lower.clone());
+    let staged = Path::new("HKEY_CURRENT_USER\\Software\\RegFsStaging").join(app);
+
+    fs::write(root.join(app).join("Name"), "changed").unwrap();
+    regfs.record_change(Change::Modified(&app.join("Name")));
+    regfs.record_change(Change::Created {
+        path: &app.join("Sub"),
+        is_directory: true,
+    });
+    regfs.record_change(Change::Deleted(&app.join("Old")));
+
+    // the staging key has the writes, the key they were made to is as it was
+    assert_eq!(
+        staging.read_value(&staged.join("Name")),
+        Some(b"changed".to_vec())
+    );
+    assert!(staging.does_key_exist(&staged.join("Sub")));
+    assert_eq!(lower.read_value(&app.join("Name")), Some(b"app".to_vec()));
+    assert_eq!(lower.read_value(&app.join("Old")), Some(b"old".to_vec()));
+
+    // and the mount reads what it wrote
+    assert_eq!(
+        regfs.read_projected_value(&app.join("Name"), &OpContext::none()),
+        Ok(Some(b"changed".to_vec()))
+    );
+    assert_eq!(
+        regfs.read_projected_value(&app.join("Old"), &OpContext::none()),
+        Ok(None)
+    );
+    assert!(regfs.regops().does_key_exist(&app.join("Sub")));
+
+    let file = root.join("staged.reg");
+    let response = regfs.execute(ControlCommand::ShadowExport(file.clone()));
+    assert!(response.starts_with("exported"), "{}", response);
+    let export = crate::regfile::read(&file).unwrap().to_text();
+    assert!(export.contains(
+        "[HKEY_CURRENT_USER\\Software\\RegFsStaging\\HKEY_CURRENT_USER\\Software\\App\\Sub]"
+    ));
+    assert!(export.contains(
+        "[HKEY_CURRENT_USER\\Software\\RegFsStaging\\Tombstones\\HKEY_CURRENT_USER\\Software\\App\\Old]"
+    ));
+
+    // without --shadow-writes there's nothing to export
+    let regfs = RegFs::with_backend(&RegFsOptions::default(), lower);
+    assert!(regfs
+        .execute(ControlCommand::ShadowExport(file))
+        .contains("not mounted with --shadow-writes"));
+}
+
+#[test]
+fn test_path_key_and_guid_key() {
+    for path in [
+        "",
+        "hkey_current_user\\Software\\.\\App",
+        "\\\\?\\HKEY_USERS//S-1-5-18\\",
+        "HKEY_LOCAL_MACHINE\\SOFTWARE\\ÄÖ Σ",
+    ] {
+        let normalized = paths::normalize(path).to_string_lossy().to_lowercase();
+        assert_eq!(path_key(path.as_ref()), normalized, "{:?}", path);
+    }
+
+    let guid = GUID {
+        Data1: 0x0102_0304,
+        Data2: 0x0506,
+        Data3: 0x0708,
+        Data4: [9, 10, 11, 12, 13, 14, 15, 16],
+    };
+    assert_eq!(guid_key(&guid).to_vec(), guid_to_bytes(&guid));
+}
+
+#[test]
+fn test_callback_allocations() {
+    use crate::{bench::allocations, memory::MemoryBackend};
+
+    let backend = MemoryBackend::new();
+    for n in 0..1000 {
+        backend.set_value(
+            "HKEY_CURRENT_USER\\Big",
+            format!("Value{:04}", n),
+            4,
+            vec![0; 4],
+        );
+    }
+    // the lookup runs on this thread, where it's counted along with the callback around it
+    let options = RegFsOptions {
+        registry_threads: 0,
+        ..Default::default()
+    };
+    let regfs = RegFs::with_backend(&options, Arc::new(backend));
+    let value = Path::new("HKEY_CURRENT_USER\\Big\\Value0500");
+    let data = callback_data(value, 0);
+
+    // the callback around a placeholder lookup costs the path as it came and as a PathBuf,
+    // the command's cancel flag and the keys its size and content id are remembered by (a
+    // buffer, the key and its lowercase copy each), not a conversion for every look at it;
+    // the first ones grow the buffers the thread keeps
+    regfs.get_placeholder_info(&data).unwrap();
+    let (lookup, placeholder) = allocations(|| regfs.placeholder_info(value));
+    assert!(placeholder.is_some());
+    assert_eq!(allocations(|| regfs.placeholder_info(value)).0, lookup);
+    let (callback, hr) = allocations(|| regfs.get_placeholder_info(&data).unwrap());
+    assert_eq!(hr, S_OK);
+    assert!(callback >= lookup, "{} under {}", callback, lookup);
+    assert!(callback - lookup <= 10, "{} over {}", callback, lookup);
+
+    // and a listing one per name it's given and one per entry, however often sorting
+    // compares them
+    let list = || {
+        let mut dirinfo = DirInfo::new("HKEY_CURRENT_USER\\Big");
+        let populated = regfs.populate_dir_info_for_path(
+            "HKEY_CURRENT_USER\\Big".into(),
+            &mut dirinfo,
+            "*".into(),
+            0,
+        );
+        assert_eq!(populated, Ok(true));
+        dirinfo.sort_entries_and_mark_filled(regfs.projfs());
+        dirinfo
+    };
+    list();
+    let (listing, _) = allocations(list);
+    assert!(listing < 4 * 1000, "{}", listing);
+
+    // a literal search folds the names as it goes
+    let search = Search::new(regfs.projfs(), "value0500".as_ref());
+    let (matching, matched) = allocations(|| search.matches("Value0500".as_ref()));
+    assert!(matched);
+    assert_eq!(matching, 0);
 }
@@ -1,188 +1,1606 @@
-use log::warn;
+use anyhow::{anyhow, Error, Result};
+use log::{trace, warn};
 use std::{
     collections::HashMap,
-    ffi::OsString,
-    path::{Component, Path, PathBuf},
+    ffi::{c_void, OsStr, OsString},
+    io,
+    os::windows::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Weak},
+    thread,
 };
-use winreg::RegKey;
+use thiserror::Error;
+use winapi::{
+    shared::{
+        minwindef::{FALSE, FILETIME, HKEY, REGSAM},
+        ntdef::{HANDLE, NTSTATUS},
+        winerror::{
+            ERROR_ACCESS_DENIED, ERROR_CHILD_MUST_BE_VOLATILE, ERROR_MORE_DATA,
+            ERROR_NO_MORE_ITEMS, ERROR_SUCCESS,
+        },
+    },
+    um::{
+        winnt::{
+            KEY_ALL_ACCESS, KEY_CREATE_SUB_KEY, KEY_ENUMERATE_SUB_KEYS, KEY_QUERY_VALUE,
+            KEY_SET_VALUE, REG_NOTIFY_CHANGE_NAME, REG_OPTION_NON_VOLATILE, REG_OPTION_OPEN_LINK,
+            REG_OPTION_VOLATILE,
+        },
+        winreg::{
+            RegCloseKey, RegCreateKeyExW, RegEnumKeyExW, RegEnumValueW, RegNotifyChangeKeyValue,
+            RegOpenKeyExW, RegQueryValueExW, HKEY_USERS,
+        },
+    },
+};
+use winreg::{RegKey, RegKeyMetadata};
 
-mod utils {
-    use std::path::{Component, Path};
+use crate::backend::{PathKind, RegistryBackend};
+use crate::impersonate::UserHive;
+use crate::opcontext::{Cancelled, OpContext, PAGE_SIZE};
+use crate::privileges;
+use crate::redact::Redacted;
+use crate::retry::RetryPolicy;
+use crate::times;
+use crate::watch::Watchers;
 
-    pub fn is_virtualization_root(path: &Path) -> bool {
-        if let Some(comp) = path.components().next() {
-            // some component, must be \ to be root
-            comp == Component::RootDir
-        } else {
-            // no components, still root
-            true
-        }
-    }
-}
+use self::paths::RegPath;
+
+mod backup;
+pub mod paths;
+pub mod rename;
+#[cfg(test)]
+pub mod scratch;
+mod write;
 
 #[derive(Default, Debug)]
 pub struct RegEntry {
     pub name: OsString,
     pub size: u64,
+    // only known for subkeys
+    pub last_write_time: i64,
 }
 
 impl RegEntry {
-    fn new<T: Into<OsString>>(name: T, size: u64) -> Self {
+    pub fn new<T: Into<OsString>>(name: T, size: u64) -> Self {
         RegEntry {
             name: name.into(),
             size,
+            last_write_time: 0,
         }
     }
 }
 
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyInfo {
+    pub subkeys: u32,
+    pub values: u32,
+    pub last_write_time: i64,
+    // gone at the next reboot
+    pub volatile: bool,
+    // longest subkey and value names in characters, without the NUL, and largest value in bytes
+    pub max_subkey_name: u32,
+    pub max_value_name: u32,
+    pub max_value_size: u32,
+}
+
+// only the first few failures of a listing are kept, the rest are just counted
+pub const MAX_ENTRY_ERRORS: usize = 8;
+
+#[derive(Debug)]
+pub struct EntryError {
+    pub is_subkey: bool,
+    // unknown when the registry failed before handing out the name
+    pub name: Option<OsString>,
+    pub error: io::Error,
+}
+
 #[derive(Default, Debug)]
 pub struct RegEntires {
     pub subkeys: Vec<RegEntry>,
     pub values: Vec<RegEntry>,
+    // some entries couldn't be read and are missing from the lists above
+    pub partial: bool,
+    pub failed: usize,
+    pub errors: Vec<EntryError>,
+}
+
+impl RegEntires {
+    pub fn record_error(&mut self, error: EntryError) {
+        self.partial = true;
+        self.failed += 1;
+        if self.errors.len() < MAX_ENTRY_ERRORS {
+            self.errors.push(error);
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum RegOpsError {
+    #[error("key [{0:?}] doesn't exist")]
+    KeyNotFound(PathBuf),
+    #[error("unable to open [{path:?}]: {error}")]
+    Open { path: PathBuf, error: io::Error },
+    #[error("[{0:?}] is under a volatile key, so it has to be volatile too")]
+    VolatileParent(PathBuf),
+    #[error("unable to query [{path:?}]: {error}")]
+    Query { path: PathBuf, error: io::Error },
+    #[error("unable to create [{path:?}]: {error}")]
+    Create { path: PathBuf, error: io::Error },
+    #[error("unable to write [{path:?}]: {error}")]
+    Write { path: PathBuf, error: io::Error },
+    #[error("unable to delete [{path:?}]: {error}")]
+    Delete { path: PathBuf, error: io::Error },
+    #[error("unable to rename [{path:?}]: {error}")]
+    Rename { path: PathBuf, error: io::Error },
+    #[error("[{path:?}] is too big to be copied, {keys} keys and {bytes} bytes of values so far")]
+    TooLarge {
+        path: PathBuf,
+        keys: usize,
+        bytes: u64,
+    },
+    #[error("the copy at [{0:?}] doesn't match what it was copied from")]
+    CopyMismatch(PathBuf),
+    #[error("unable to copy to [{path:?}]: {error}")]
+    Copy { path: PathBuf, error: io::Error },
+    #[error("copying to [{0:?}] was cancelled")]
+    Cancelled(PathBuf),
+}
+
+// limits for read_all_values; values past them are only reported with their size
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValueCaps {
+    pub per_value: Option<u64>,
+    pub total: Option<u64>,
+}
+
+impl ValueCaps {
+    fn admits(&self, size: u64, read: u64) -> bool {
+        self.per_value.map_or(true, |cap| size <= cap)
+            && self.total.map_or(true, |cap| read + size <= cap)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValueData {
+    Bytes(Vec<u8>),
+    // over one of the caps, the data wasn't read
+    Oversized(u64),
+}
+
+impl ValueData {
+    pub fn size(&self) -> u64 {
+        match self {
+            ValueData::Bytes(bytes) => bytes.len() as u64,
+            ValueData::Oversized(size) => *size,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RootHive {
+    ClassesRoot,
+    CurrentUser,
+    LocalMachine,
+    Users,
+    CurrentConfig,
+}
+
+impl RootHive {
+    pub const ALL: [RootHive; 5] = [
+        RootHive::ClassesRoot,
+        RootHive::CurrentUser,
+        RootHive::LocalMachine,
+        RootHive::Users,
+        RootHive::CurrentConfig,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RootHive::ClassesRoot => "HKEY_CLASSES_ROOT",
+            RootHive::CurrentUser => "HKEY_CURRENT_USER",
+            RootHive::LocalMachine => "HKEY_LOCAL_MACHINE",
+            RootHive::Users => "HKEY_USERS",
+            RootHive::CurrentConfig => "HKEY_CURRENT_CONFIG",
+        }
+    }
+
+    fn short_name(self) -> &'static str {
+        match self {
+            RootHive::ClassesRoot => "HKCR",
+            RootHive::CurrentUser => "HKCU",
+            RootHive::LocalMachine => "HKLM",
+            RootHive::Users => "HKU",
+            RootHive::CurrentConfig => "HKCC",
+        }
+    }
+
+    fn key(self) -> RegKey {
+        RegKey::predef(match self {
+            RootHive::ClassesRoot => winreg::enums::HKEY_CLASSES_ROOT,
+            RootHive::CurrentUser => winreg::enums::HKEY_CURRENT_USER,
+            RootHive::LocalMachine => winreg::enums::HKEY_LOCAL_MACHINE,
+            RootHive::Users => winreg::enums::HKEY_USERS,
+            RootHive::CurrentConfig => winreg::enums::HKEY_CURRENT_CONFIG,
+        })
+    }
+}
+
+impl FromStr for RootHive {
+    type Err = Error;
+
+    // full names or the usual abbreviations, in any case
+    fn from_str(name: &str) -> Result<Self> {
+        RootHive::ALL
+            .iter()
+            .copied()
+            .find(|hive| {
+                name.eq_ignore_ascii_case(hive.name())
+                    || name.eq_ignore_ascii_case(hive.short_name())
+            })
+            .ok_or_else(|| {
+                let valid: Vec<_> = RootHive::ALL
+                    .iter()
+                    .map(|hive| format!("{} ({})", hive.name(), hive.short_name()))
+                    .collect();
+                anyhow!("unknown hive [{}], valid hives: {}", name, valid.join(", "))
+            })
+    }
 }
 
 pub struct RegOps {
     keymap: HashMap<OsString, RegKey>,
+    retry: RetryPolicy,
+    // only told about HKEY_USERS itself, see watch_users()
+    watchers: Arc<Watchers>,
+    // --backup-semantics: keys are read as a backup would, see backup::open()
+    backup: bool,
+}
+
+// what a key is opened for, which decides the rights asked for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Enumerate,
+    Query,
+    Write,
+}
+
+impl Access {
+    // widest first
+    fn masks(self) -> &'static [REGSAM] {
+        match self {
+            Access::Enumerate => &[
+                KEY_ENUMERATE_SUB_KEYS | KEY_QUERY_VALUE,
+                KEY_ENUMERATE_SUB_KEYS,
+                KEY_QUERY_VALUE,
+            ],
+            Access::Query => &[KEY_QUERY_VALUE],
+            Access::Write => &[
+                KEY_CREATE_SUB_KEY | KEY_SET_VALUE,
+                KEY_CREATE_SUB_KEY,
+                KEY_SET_VALUE,
+            ],
+        }
+    }
+}
+
+// a key along with the rights it was opened with, which can be fewer than asked for
+pub struct OpenKey {
+    pub key: RegKey,
+    pub granted: REGSAM,
+}
+
+impl OpenKey {
+    pub fn allows(&self, rights: REGSAM) -> bool {
+        self.granted & rights == rights
+    }
+}
+
+impl Default for RegOps {
+    fn default() -> RegOps {
+        RegOps::new()
+    }
 }
 
 impl RegOps {
     pub fn new() -> RegOps {
-        let mut keymap = HashMap::new();
-        keymap.insert(
-            "HKEY_CLASSES_ROOT".into(),
-            RegKey::predef(winreg::enums::HKEY_CLASSES_ROOT),
-        );
-        keymap.insert(
-            "HKEY_CURRENT_USER".into(),
-            RegKey::predef(winreg::enums::HKEY_CURRENT_USER),
-        );
-        keymap.insert(
-            "HKEY_LOCAL_MACHINE".into(),
-            RegKey::predef(winreg::enums::HKEY_LOCAL_MACHINE),
-        );
-        keymap.insert(
-            "HKEY_USERS".into(),
-            RegKey::predef(winreg::enums::HKEY_USERS),
-        );
-        keymap.insert(
-            "HKEY_CURRENT_CONFIG".into(),
-            RegKey::predef(winreg::enums::HKEY_CURRENT_CONFIG),
-        );
-
-        RegOps { keymap }
-    }
-
-    pub fn enumerate_key(&self, path: OsString) -> Option<RegEntires> {
-        if utils::is_virtualization_root(path.as_ref()) {
+        RegOps::with_hives(&RootHive::ALL)
+    }
+
+    pub fn with_hives(hives: &[RootHive]) -> RegOps {
+        let keymap = hives
+            .iter()
+            .map(|hive| (hive.name().into(), hive.key()))
+            .collect();
+        let watchers = Arc::new(Watchers::default());
+        if hives.contains(&RootHive::Users) {
+            watch_users(Arc::downgrade(&watchers));
+        }
+
+        RegOps {
+            keymap,
+            retry: Default::default(),
+            watchers,
+            backup: false,
+        }
+    }
+
+    // SeBackupPrivilege has to be enabled already; writes are opened as usual
+    pub fn with_backup_semantics(hives: &[RootHive]) -> RegOps {
+        RegOps {
+            backup: true,
+            ..RegOps::with_hives(hives)
+        }
+    }
+}
+
+// users logging on and off, and `reg load`/`reg unload`, change which keys HKEY_USERS has
+// while the provider runs. The thread waits on the next change even once the backend is
+// gone, it only notices then
+fn watch_users(watchers: Weak<Watchers>) {
+    thread::spawn(move || loop {
+        let result = unsafe {
+            RegNotifyChangeKeyValue(
+                HKEY_USERS,
+                FALSE,
+                REG_NOTIFY_CHANGE_NAME,
+                std::ptr::null_mut(),
+                FALSE,
+            )
+        } as u32;
+        if result != ERROR_SUCCESS {
+            warn!(
+                "unable to watch HKEY_USERS: {}",
+                io::Error::from_raw_os_error(result as i32)
+            );
+            return;
+        }
+        match watchers.upgrade() {
+            Some(watchers) => {
+                watchers.notify(RootHive::Users.name().as_ref());
+            }
+            None => return,
+        }
+    });
+}
+
+impl RegistryBackend for RegOps {
+    fn enumerate_key_ctx(
+        &self,
+        path: OsString,
+        ctx: &OpContext,
+    ) -> Result<Option<RegEntires>, Cancelled> {
+        let path = RegPath::parse(path);
+
+        if path.is_root() {
             let subkeys = self
                 .keymap
                 .iter()
-                .map(|(n, _)| RegEntry::new(n, 0))
+                .map(|(n, hkey)| RegEntry {
+                    last_write_time: last_write_time(hkey).unwrap_or(0),
+                    ..RegEntry::new(n, 0)
+                })
                 .collect();
 
-            Some(RegEntires {
+            Ok(Some(RegEntires {
                 subkeys,
                 ..Default::default()
-            })
+            }))
         } else {
-            if let Some(subkey) = self.open_key_by_path(path.as_ref()) {
-                let subkeys: Vec<RegEntry> = subkey
-                    .enum_keys()
-                    .filter_map(|s| match s {
-                        Ok(s) => Some(RegEntry::new(s, 0)),
-                        Err(_) => None,
-                    })
-                    .collect();
-                let values: Vec<RegEntry> = subkey
-                    .enum_values()
-                    .filter_map(|s| match s {
-                        Ok((name, value)) => Some(RegEntry::new(name, value.bytes.len() as u64)),
-                        Err(_) => None,
-                    })
-                    .collect();
+            if let Some(subkey) = self.open_key_by_path(&path, Access::Enumerate, ctx) {
+                let mut entries = RegEntires::default();
+                if let Ok(info) = subkey.key.query_info() {
+                    entries.subkeys.reserve(info.sub_keys as usize);
+                    entries.values.reserve(info.values as usize);
+                }
+                // whatever the key didn't let us open it for is listed as a single error
+                let denied = |is_subkey| EntryError {
+                    is_subkey,
+                    name: None,
+                    error: io::Error::from_raw_os_error(ERROR_ACCESS_DENIED as i32),
+                };
 
-                Some(RegEntires { subkeys, values })
+                match subkey.allows(KEY_ENUMERATE_SUB_KEYS) {
+                    true => enum_subkeys(&subkey.key, ctx, &mut entries)?,
+                    false => entries.record_error(denied(true)),
+                }
+                match subkey.allows(KEY_QUERY_VALUE) {
+                    true => ctx.paginate(subkey.key.enum_values(), |s| match s {
+                        Ok((name, value)) => entries
+                            .values
+                            .push(RegEntry::new(name, value.bytes.len() as u64)),
+                        Err(error) => entries.record_error(EntryError {
+                            is_subkey: false,
+                            name: None,
+                            error,
+                        }),
+                    })?,
+                    false => entries.record_error(denied(false)),
+                }
+                paths::name_default_value(&mut entries.values, |entry| &mut entry.name);
+
+                Ok(Some(entries))
             } else {
-                None
+                Ok(None)
             }
         }
     }
 
-    pub fn read_value(&self, path: &Path) -> Option<Vec<u8>> {
-        let mut parts = path.components();
+    fn read_typed_value_ctx(
+        &self,
+        path: &Path,
+        ctx: &OpContext,
+    ) -> Result<Option<(u32, Vec<u8>)>, Cancelled> {
+        let (subkey, value) = match RegPath::parse(path).split_value() {
+            Some(split) => split,
+            None => return Ok(None),
+        };
+
+        let subkey = match self.open_key_by_path(&subkey, Access::Query, ctx) {
+            Some(subkey) => subkey.key,
+            None => return Ok(None),
+        };
+
+        // a single RegQueryValueEx can't be interrupted, so check on both sides of it
+        ctx.check()?;
+        let value = self
+            .retry
+            .run(ctx, "read_value", || {
+                query_value(&subkey, &value).or_else(|e| {
+                    match paths::default_value_fallback(&value) {
+                        Some(unnamed) if e.kind() == io::ErrorKind::NotFound => {
+                            query_value(&subkey, unnamed.as_ref())
+                        }
+                        _ => Err(e),
+                    }
+                })
+            })
+            .map_err(|e| {
+                if e.raw_os_error() == Some(ERROR_MORE_DATA as i32) {
+                    warn!(
+                        "read_value: [{:?}] kept growing while it was read, given up on",
+                        path
+                    );
+                }
+            })
+            .ok();
+        ctx.check()?;
+
+        match &value {
+            Some((vtype, data)) => trace!(
+                target: "regops",
+                "read_value: [{:?}] type {} {}",
+                path,
+                vtype,
+                Redacted(data)
+            ),
+            None => trace!(target: "regops", "read_value: [{:?}] not found", path),
+        }
+        Ok(value)
+    }
+
+    fn read_all_values(&self, path: &Path) -> Option<Vec<(OsString, u32, Vec<u8>)>> {
+        let values = RegOps::read_all_values(self, path, ValueCaps::default()).ok()?;
+
+        Some(
+            values
+                .into_iter()
+                .filter_map(|(name, vtype, data)| match data {
+                    ValueData::Bytes(bytes) => Some((name, vtype, bytes)),
+                    ValueData::Oversized(_) => None,
+                })
+                .collect(),
+        )
+    }
+
+    fn does_key_exist(&self, path: &Path) -> bool {
+        self.open_key_by_path(&RegPath::parse(path), Access::Enumerate, &OpContext::none())
+            .is_some()
+    }
 
-        if parts.clone().count() <= 1 {
-            // only root or empty
+    // sized, not read
+    fn does_value_exist(&self, path: &Path) -> Option<usize> {
+        let (subkey, value) = RegPath::parse(path).split_value()?;
+        let subkey = self.open_key_by_path(&subkey, Access::Query, &OpContext::none())?;
+        query_size(&subkey.key, &value)
+            .or_else(|e| match paths::default_value_fallback(&value) {
+                Some(unnamed) if e.kind() == io::ErrorKind::NotFound => {
+                    query_size(&subkey.key, unnamed.as_ref())
+                }
+                _ => Err(e),
+            })
+            .ok()
+    }
+
+    fn key_last_write_time(&self, path: &Path) -> Option<i64> {
+        let key =
+            self.open_key_by_path(&RegPath::parse(path), Access::Query, &OpContext::none())?;
+        Some(last_write_time(&key.key).unwrap_or(0))
+    }
+
+    // one RegQueryInfoKey instead of walking the key
+    fn key_info(&self, path: &Path) -> Option<KeyInfo> {
+        RegOps::key_info(self, path).ok()
+    }
+
+    // opened the way a listing would open it, as whoever the listing runs as
+    fn classify(&self, path: &Path, ctx: &OpContext) -> Result<PathKind, Cancelled> {
+        let path = RegPath::parse(path);
+        if path.is_root() {
+            return Ok(PathKind::Key(0));
+        }
+        match self.open_key(&path, Access::Enumerate, ctx) {
+            Ok(key) => Ok(PathKind::Key(last_write_time(&key.key).unwrap_or(0))),
+            Err(RegOpsError::Open { error, .. })
+                if error.kind() == io::ErrorKind::PermissionDenied =>
+            {
+                Ok(PathKind::Denied)
+            }
+            Err(_) => Ok(match self.read_typed_value_ctx(&path.to_path(), ctx)? {
+                Some(_) => PathKind::Value,
+                None => PathKind::Missing,
+            }),
+        }
+    }
+
+    fn watchers(&self) -> Option<&Watchers> {
+        Some(&self.watchers)
+    }
+
+    // the key opened as the link itself, not as what it points to
+    fn link_target(&self, path: &Path) -> Option<PathBuf> {
+        let path = RegPath::parse(path);
+        if path.keys.is_empty() {
             return None;
         }
+        let parent = self
+            .open_key(&path.parent()?, Access::Query, &OpContext::none())
+            .ok()?;
+        let name: Vec<u16> = path.file_name()?.encode_wide().chain(Some(0)).collect();
+        let mut link = std::ptr::null_mut();
+        let result = unsafe {
+            RegOpenKeyExW(
+                parent.key.raw_handle() as usize as HKEY,
+                name.as_ptr(),
+                REG_OPTION_OPEN_LINK,
+                KEY_QUERY_VALUE,
+                &mut link,
+            )
+        } as u32;
+        if result != ERROR_SUCCESS {
+            return None;
+        }
+        // closed on drop
+        let link = RegKey::predef(link as usize as _);
+
+        let value = link.get_raw_value(SYMBOLIC_LINK_VALUE).ok()?;
+        if !matches!(value.vtype, winreg::enums::REG_LINK) {
+            return None;
+        }
+        let units: Vec<u16> = value
+            .bytes
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .collect();
+        from_nt_path(&OsString::from_wide(&units))
+    }
+}
 
-        let value = parts.next_back().unwrap();
-        let subkey = parts.as_path();
+// what a link key holds its target in
+const SYMBOLIC_LINK_VALUE: &str = "SymbolicLinkValue";
 
-        self.open_key_by_path(subkey)
-            .and_then(|subkey| subkey.get_raw_value(value).ok())
-            .map(|value| value.bytes)
+// `\REGISTRY\MACHINE\...` and `\REGISTRY\USER\...`, the way links hold their target, under
+// the hive names of the mount
+fn from_nt_path(target: &OsStr) -> Option<PathBuf> {
+    let parts = paths::components(target);
+    match parts.first() {
+        Some(registry) if registry.to_string_lossy().eq_ignore_ascii_case("REGISTRY") => {}
+        _ => return None,
     }
+    let hive = match parts
+        .get(1)?
+        .to_string_lossy()
+        .to_ascii_uppercase()
+        .as_str()
+    {
+        "MACHINE" => RootHive::LocalMachine,
+        "USER" => RootHive::Users,
+        _ => return None,
+    };
+    Some(paths::join_parts(
+        std::iter::once(OsStr::new(hive.name()))
+            .chain(parts[2..].iter().map(|part| part.as_os_str())),
+    ))
+}
+
+// a value that grows between being sized and being read is sized again this many times
+// before the read gives up with ERROR_MORE_DATA
+const MAX_SIZE_RETRIES: u32 = 4;
 
-    pub fn does_key_exist(&self, path: &Path) -> bool {
-        self.open_key_by_path(path).is_some()
+// what one RegQueryValueEx said
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Queried {
+    Read { vtype: u32, len: usize },
+    // the size the value has now
+    MoreData(usize),
+    Failed(u32),
+}
+
+// reads into a buffer of the size `query` first answered with, and again into a bigger one
+// for as long as the value outgrows it
+fn read_sized<F>(size: usize, mut query: F) -> io::Result<(u32, Vec<u8>)>
+where
+    F: FnMut(&mut [u8]) -> Queried,
+{
+    let mut data = vec![0u8; size];
+    for _ in 0..=MAX_SIZE_RETRIES {
+        match query(&mut data) {
+            Queried::Read { vtype, len } => {
+                data.truncate(len);
+                return Ok((vtype, data));
+            }
+            Queried::MoreData(size) => data.resize(size, 0),
+            Queried::Failed(error) => return Err(io::Error::from_raw_os_error(error as i32)),
+        }
     }
+    Err(io::Error::from_raw_os_error(ERROR_MORE_DATA as i32))
+}
 
-    pub fn does_value_exist(&self, path: &Path) -> Option<usize> {
-        self.read_value(path).map(|bytes| bytes.len())
+// one RegQueryValueExW of `name`, NUL terminated, into `data`, or for its size alone
+fn query_once(hkey: HKEY, name: &[u16], data: Option<&mut [u8]>) -> Queried {
+    let mut vtype = 0;
+    let (buffer, mut len) = match data {
+        Some(data) => (data.as_mut_ptr(), data.len() as u32),
+        None => (std::ptr::null_mut(), 0),
+    };
+    let result = unsafe {
+        RegQueryValueExW(
+            hkey,
+            name.as_ptr(),
+            std::ptr::null_mut(),
+            &mut vtype,
+            buffer,
+            &mut len,
+        )
+    } as u32;
+    match result {
+        ERROR_SUCCESS => Queried::Read {
+            vtype,
+            len: len as usize,
+        },
+        ERROR_MORE_DATA => Queried::MoreData(len as usize),
+        error => Queried::Failed(error),
     }
+}
 
-    fn open_key_by_path(&self, path: &Path) -> Option<RegKey> {
-        if path.components().count() == 1 {
-            if let Some(hkey) = self.keymap.get(path.as_os_str()) {
-                Some(RegKey::predef(hkey.raw_handle()))
-            } else {
-                warn!("open_key_by_path: root key [{:?}] doesn't exist", path);
-                None
+// what the value's data takes, without reading it
+fn query_size(key: &RegKey, name: &OsStr) -> io::Result<usize> {
+    let name: Vec<u16> = name.encode_wide().chain(Some(0)).collect();
+    size_of(key.raw_handle() as usize as HKEY, &name)
+}
+
+fn size_of(hkey: HKEY, name: &[u16]) -> io::Result<usize> {
+    match query_once(hkey, name, None) {
+        Queried::Read { len, .. } | Queried::MoreData(len) => Ok(len),
+        Queried::Failed(error) => Err(io::Error::from_raw_os_error(error as i32)),
+    }
+}
+
+// get_raw_value() guesses a buffer and grows it for as long as it takes; this sizes the
+// value first and only grows the buffer a few times
+fn query_value(key: &RegKey, name: &OsStr) -> io::Result<(u32, Vec<u8>)> {
+    let hkey = key.raw_handle() as usize as HKEY;
+    let name: Vec<u16> = name.encode_wide().chain(Some(0)).collect();
+    let size = size_of(hkey, &name)?;
+    read_sized(size, |data| query_once(hkey, &name, Some(data)))
+}
+
+fn last_write_time(key: &RegKey) -> Option<i64> {
+    let info = key.query_info().ok()?;
+    Some(times::from_parts(
+        info.last_write_time.dwLowDateTime,
+        info.last_write_time.dwHighDateTime,
+    ))
+}
+
+// enum_keys() throws away the last write time RegEnumKeyExW hands out for free
+fn enum_subkeys(key: &RegKey, ctx: &OpContext, entries: &mut RegEntires) -> Result<(), Cancelled> {
+    let hkey = key.raw_handle() as usize as HKEY;
+    // key names are limited to 255 characters
+    let mut name = [0u16; 256];
+
+    for index in 0.. {
+        if index as usize % PAGE_SIZE == 0 {
+            ctx.check()?;
+        }
+
+        let mut len = name.len() as u32;
+        let mut time = FILETIME::default();
+        let result = unsafe {
+            RegEnumKeyExW(
+                hkey,
+                index,
+                name.as_mut_ptr(),
+                &mut len,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                &mut time,
+            )
+        } as u32;
+
+        match result {
+            ERROR_SUCCESS => entries.subkeys.push(RegEntry {
+                last_write_time: times::from_parts(time.dwLowDateTime, time.dwHighDateTime),
+                ..RegEntry::new(OsString::from_wide(&name[..len as usize]), 0)
+            }),
+            ERROR_NO_MORE_ITEMS => break,
+            // a name that didn't fit is that entry's problem only
+            ERROR_MORE_DATA => entries.record_error(EntryError {
+                is_subkey: true,
+                name: None,
+                error: io::Error::from_raw_os_error(ERROR_MORE_DATA as i32),
+            }),
+            // anything else (the key deleted under us, a bad handle) comes back at every
+            // index after it
+            error => {
+                entries.record_error(EntryError {
+                    is_subkey: true,
+                    name: None,
+                    error: io::Error::from_raw_os_error(error as i32),
+                });
+                break;
             }
-        } else {
-            let mut parts = path.components();
-            let rootkey = match parts.next() {
-                Some(Component::RootDir) => parts.next().map(|x| x.as_os_str()),
-                Some(Component::Normal(part)) => Some(part),
-                _ => None,
-            }?;
-            let subkey = parts.collect::<PathBuf>();
-            let root: &RegKey = self.keymap.get(rootkey)?;
+        }
+    }
+
+    Ok(())
+}
+
+// KEY_INFORMATION_CLASS and KEY_FLAGS_INFORMATION, which only ntdll has
+const KEY_FLAGS_INFORMATION_CLASS: u32 = 5;
+const REG_FLAG_VOLATILE: u32 = 0x1;
+
+#[repr(C)]
+#[derive(Default)]
+struct KeyFlagsInformation {
+    wow64_flags: u32,
+    key_flags: u32,
+    control_flags: u32,
+}
+
+#[link(name = "ntdll")]
+extern "system" {
+    fn NtQueryKey(
+        key: HANDLE,
+        class: u32,
+        information: *mut c_void,
+        length: u32,
+        result_length: *mut u32,
+    ) -> NTSTATUS;
+}
+
+// RegQueryInfoKey doesn't say; the predefined hive handles are never volatile
+fn is_volatile(key: &RegKey) -> bool {
+    let mut flags = KeyFlagsInformation::default();
+    let mut length = 0;
+    let status = unsafe {
+        NtQueryKey(
+            key.raw_handle() as usize as HANDLE,
+            KEY_FLAGS_INFORMATION_CLASS,
+            &mut flags as *mut KeyFlagsInformation as *mut c_void,
+            std::mem::size_of::<KeyFlagsInformation>() as u32,
+            &mut length,
+        )
+    };
+    status >= 0 && flags.key_flags & REG_FLAG_VOLATILE != 0
+}
+
+// value names are limited to 16383 characters
+const MAX_VALUE_NAME: usize = 16384;
+
+// enum_values() reads every value in full; this reuses one buffer and only grows it
+// for values within the caps
+fn enum_values(key: &RegKey, caps: ValueCaps) -> Vec<(OsString, u32, ValueData)> {
+    let hkey = key.raw_handle() as usize as HKEY;
+    let mut name = vec![0u16; MAX_VALUE_NAME];
+    let mut data = vec![0u8; 4096];
+    let mut values = Vec::new();
+    let mut read = 0;
+    let mut index = 0;
+
+    loop {
+        let mut name_len = name.len() as u32;
+        let mut vtype = 0;
+        let mut data_len = data.len() as u32;
+        let result = unsafe {
+            RegEnumValueW(
+                hkey,
+                index,
+                name.as_mut_ptr(),
+                &mut name_len,
+                std::ptr::null_mut(),
+                &mut vtype,
+                data.as_mut_ptr(),
+                &mut data_len,
+            )
+        } as u32;
+
+        match result {
+            ERROR_SUCCESS => {
+                let size = data_len as u64;
+                let value = match caps.admits(size, read) {
+                    true => {
+                        read += size;
+                        ValueData::Bytes(data[..data_len as usize].to_vec())
+                    }
+                    false => ValueData::Oversized(size),
+                };
+                values.push((
+                    OsString::from_wide(&name[..name_len as usize]),
+                    vtype,
+                    value,
+                ));
+            }
+            // data_len is the size it needs now, the name isn't filled in
+            ERROR_MORE_DATA if caps.admits(data_len as u64, read) => {
+                data.resize(data_len as usize, 0);
+                continue;
+            }
+            ERROR_MORE_DATA => {
+                let size = data_len;
+                let mut name_len = name.len() as u32;
+                let result = unsafe {
+                    RegEnumValueW(
+                        hkey,
+                        index,
+                        name.as_mut_ptr(),
+                        &mut name_len,
+                        std::ptr::null_mut(),
+                        &mut vtype,
+                        std::ptr::null_mut(),
+                        std::ptr::null_mut(),
+                    )
+                } as u32;
+                if result == ERROR_SUCCESS {
+                    values.push((
+                        OsString::from_wide(&name[..name_len as usize]),
+                        vtype,
+                        ValueData::Oversized(size as u64),
+                    ));
+                }
+            }
+            ERROR_NO_MORE_ITEMS => break,
+            // as for the subkeys, it won't get better at the next index
+            error => {
+                warn!(
+                    "enum_values: value {} failed: {}",
+                    index,
+                    io::Error::from_raw_os_error(error as i32)
+                );
+                break;
+            }
+        }
+        index += 1;
+    }
+
+    values
+}
+
+fn key_info_of(key: &RegKey, info: &RegKeyMetadata) -> KeyInfo {
+    KeyInfo {
+        subkeys: info.sub_keys,
+        values: info.values,
+        last_write_time: times::from_parts(
+            info.last_write_time.dwLowDateTime,
+            info.last_write_time.dwHighDateTime,
+        ),
+        volatile: is_volatile(key),
+        max_subkey_name: info.max_sub_key_len,
+        max_value_name: info.max_value_name_len,
+        max_value_size: info.max_value_len,
+    }
+}
 
-            root.open_subkey(subkey).ok()
+impl RegOps {
+    pub fn key_info(&self, path: &Path) -> Result<KeyInfo, RegOpsError> {
+        let ctx = OpContext::none();
+        let path = RegPath::parse(path);
+        let key = self.open_key(&path, Access::Query, &ctx)?.key;
+        let info = self
+            .retry
+            .run(&ctx, "key_info", || key.query_info())
+            .map_err(|error| RegOpsError::Query {
+                path: path.to_path(),
+                error,
+            })?;
+
+        Ok(key_info_of(&key, &info))
+    }
+
+    // opens the key once for all of its values, name, type and data together; whatever
+    // is over the caps comes with its size only
+    pub fn read_all_values(
+        &self,
+        path: &Path,
+        caps: ValueCaps,
+    ) -> Result<Vec<(OsString, u32, ValueData)>, RegOpsError> {
+        let key = self.open_key(&RegPath::parse(path), Access::Query, &OpContext::none())?;
+        let mut values = enum_values(&key.key, caps);
+        paths::name_default_value(&mut values, |(name, _, _)| name);
+        Ok(values)
+    }
+
+    // volatile keys are kept in memory only and are gone at the next reboot; the parent
+    // has to exist
+    pub fn create_key(&self, path: &Path, volatile: bool) -> Result<(), RegOpsError> {
+        let path = RegPath::parse(path);
+        let (parent, name) = path
+            .split_value()
+            .ok_or_else(|| RegOpsError::KeyNotFound(path.to_path()))?;
+        let parent = self.open_key(&parent, Access::Write, &OpContext::none())?;
+        if !parent.allows(KEY_CREATE_SUB_KEY) {
+            return Err(RegOpsError::Create {
+                path: path.to_path(),
+                error: io::Error::from_raw_os_error(ERROR_ACCESS_DENIED as i32),
+            });
+        }
+
+        let name: Vec<u16> = name.encode_wide().chain(Some(0)).collect();
+        let options = match volatile {
+            true => REG_OPTION_VOLATILE,
+            false => REG_OPTION_NON_VOLATILE,
+        };
+
+        let mut hkey = std::ptr::null_mut();
+        let result = unsafe {
+            RegCreateKeyExW(
+                parent.key.raw_handle() as usize as HKEY,
+                name.as_ptr(),
+                0,
+                std::ptr::null_mut(),
+                options,
+                KEY_QUERY_VALUE,
+                std::ptr::null_mut(),
+                &mut hkey,
+                std::ptr::null_mut(),
+            )
+        } as u32;
+
+        match result {
+            ERROR_SUCCESS => {
+                unsafe { RegCloseKey(hkey) };
+                Ok(())
+            }
+            ERROR_CHILD_MUST_BE_VOLATILE => Err(RegOpsError::VolatileParent(path.to_path())),
+            error => Err(RegOpsError::Create {
+                path: path.to_path(),
+                error: io::Error::from_raw_os_error(error as i32),
+            }),
         }
     }
+
+    // asks for the rights of the first mask and falls back to the next ones while access
+    // is denied, so a key that only lets us list subkeys (or only read values) still opens
+    fn open_key(
+        &self,
+        path: &RegPath,
+        access: Access,
+        ctx: &OpContext,
+    ) -> Result<OpenKey, RegOpsError> {
+        let not_found = || RegOpsError::KeyNotFound(path.to_path());
+        let hive = path.hive.as_ref().ok_or_else(not_found)?;
+        // only the keymap's own hives can be handed out as they are
+        let (root, predefined) = match (self.keymap.get(hive), ctx.user()) {
+            (None, _) => {
+                warn!("open_key: root key [{:?}] doesn't exist", hive);
+                return Err(not_found());
+            }
+            // without the caller's user nothing is opened on its behalf
+            (Some(_), Some(UserHive::Denied)) => {
+                return Err(RegOpsError::Open {
+                    path: path.to_path(),
+                    error: io::Error::from_raw_os_error(ERROR_ACCESS_DENIED as i32),
+                })
+            }
+            (Some(_), Some(UserHive::Process(user)))
+                if hive.as_os_str() == RootHive::CurrentUser.name() =>
+            {
+                (user, false)
+            }
+            (Some(root), _) => (root, true),
+        };
+
+        if path.keys.is_empty() && predefined {
+            return Ok(OpenKey {
+                key: RegKey::predef(root.raw_handle()),
+                granted: KEY_ALL_ACCESS,
+            });
+        }
+
+        // only reads are retried
+        let retry = match access {
+            Access::Write => RetryPolicy {
+                attempts: 1,
+                ..self.retry
+            },
+            _ => self.retry,
+        };
+        let mut denied = io::Error::from_raw_os_error(ERROR_ACCESS_DENIED as i32);
+        for &mask in access.masks() {
+            let opened = retry.run(ctx, "open_key", || match (self.backup, access) {
+                (true, Access::Enumerate | Access::Query) => {
+                    backup::open(root, &path.subkey(), mask)
+                }
+                _ => root.open_subkey_with_flags(path.subkey(), mask),
+            });
+            match opened {
+                Ok(key) => {
+                    trace!(target: "regops", "open_key: [{:?}] with {:#x}", path.to_path(), mask);
+                    return Ok(OpenKey { key, granted: mask });
+                }
+                Err(error) if error.kind() == io::ErrorKind::PermissionDenied => denied = error,
+                Err(error) if error.kind() == io::ErrorKind::NotFound => return Err(not_found()),
+                Err(error) => {
+                    return Err(RegOpsError::Open {
+                        path: path.to_path(),
+                        error,
+                    })
+                }
+            }
+        }
+
+        privileges::explain_denial(&path.to_path(), &denied);
+        Err(RegOpsError::Open {
+            path: path.to_path(),
+            error: denied,
+        })
+    }
+
+    pub fn open_key_by_path(
+        &self,
+        path: &RegPath,
+        access: Access,
+        ctx: &OpContext,
+    ) -> Option<OpenKey> {
+        self.open_key(path, access, ctx).ok()
+    }
+}
+
+#[cfg(test)]
+use self::scratch::ScratchKey;
+
+#[test]
+fn test_enum_subkeys_of_a_deleted_key() {
+    let scratch = ScratchKey::populated();
+    let (child, _) = scratch.key.create_subkey("Child").unwrap();
+    scratch.key.delete_subkey_all("Child").unwrap();
+
+    // ERROR_KEY_DELETED at the first index, and it stops there
+    let mut entries = RegEntires::default();
+    enum_subkeys(&child, &OpContext::none(), &mut entries).unwrap();
+    assert!(entries.subkeys.is_empty());
+    assert_eq!(entries.failed, 1);
+    assert!(enum_values(&child, ValueCaps::default()).is_empty());
 }
 
 #[test]
 fn test_enumerate_key() {
+    let scratch = ScratchKey::populated();
     let ops = RegOps::new();
-    let keys = ops
-        .enumerate_key("HKEY_LOCAL_MACHINE\\SOFTWARE\\Microsoft".into())
-        .unwrap();
+    let names = |list: Vec<RegEntry>| {
+        let mut names: Vec<_> = list.into_iter().map(|entry| entry.name).collect();
+        names.sort();
+        names
+    };
 
-    for key in keys.subkeys {
-        dbg!(key.name);
-    }
+    let entries = ops.enumerate_key(scratch.path().into()).unwrap();
+    assert!(!entries.partial);
+    assert!(entries.subkeys.iter().all(|key| key.last_write_time > 0));
+    assert_eq!(names(entries.subkeys), ["Child", "Empty"]);
+    let sizes: HashMap<_, _> = entries
+        .values
+        .iter()
+        .map(|value| (value.name.clone(), value.size))
+        .collect();
+    assert_eq!(sizes[&OsString::from("Number")], 4);
+    assert_eq!(sizes[&OsString::from("Blob")], 10000);
+    assert_eq!(
+        names(entries.values),
+        ["Big", "Blob", "Expand", "List", "Number", "Text"]
+    );
+
+    let nested = ops
+        .enumerate_key(scratch.path().join("Child\\Grandchild").into())
+        .unwrap();
+    assert!(nested.subkeys.is_empty());
+    assert_eq!(names(nested.values), ["Leaf"]);
+    let empty = ops
+        .enumerate_key(scratch.path().join("Empty").into())
+        .unwrap();
+    assert!(empty.subkeys.is_empty() && empty.values.is_empty());
+    assert!(ops
+        .enumerate_key(scratch.path().join("Missing").into())
+        .is_none());
 }
 
 #[test]
 fn test_does_key_exist() {
+    let scratch = ScratchKey::populated();
     let ops = RegOps::new();
 
-    assert!(ops.does_key_exist(
-        "HKEY_LOCAL_MACHINE\\SOFTWARE\\Microsoft\\Windows\\CurrentVersion".as_ref()
-    ));
+    assert!(ops.does_key_exist(&scratch.path()));
+    assert!(ops.does_key_exist(&scratch.path().join("child\\GRANDCHILD")));
+    assert!(!ops.does_key_exist(&scratch.path().join("Missing")));
+    // a value is not a key
+    assert!(!ops.does_key_exist(&scratch.path().join("Text")));
 }
 
 #[test]
 fn test_read_value() {
+    let scratch = ScratchKey::populated();
     let ops = RegOps::new();
+    let read = |name: &str| {
+        ops.read_typed_value_ctx(&scratch.path().join(name), &OpContext::none())
+            .unwrap()
+    };
+
     assert_eq!(ops.read_value("HKEY_LOCAL_MACHINE".as_ref()), None);
     assert_eq!(ops.read_value("".as_ref()), None);
     assert_eq!(
-        ops.read_value("HKEY_LOCAL_MACHINE\\SOFTWARE\\Microsoft\\Windows NT\\CurrentVersion\\CurrentMajorVersionNumber".as_ref()),
-        Some(vec![10, 0, 0, 0])
+        ops.read_value(&scratch.path().join("Number")),
+        Some(vec![42, 0, 0, 0])
+    );
+    assert_eq!(
+        read("Text"),
+        Some((
+            winreg::enums::REG_SZ as u32,
+            "hello\0"
+                .encode_utf16()
+                .flat_map(u16::to_le_bytes)
+                .collect()
+        ))
+    );
+    assert_eq!(
+        read("Big").map(|(vtype, _)| vtype),
+        Some(winreg::enums::REG_QWORD as u32)
+    );
+    assert_eq!(
+        read("Expand").map(|(vtype, _)| vtype),
+        Some(winreg::enums::REG_EXPAND_SZ as u32)
+    );
+    assert_eq!(
+        read("Child\\Grandchild\\Leaf"),
+        Some((winreg::enums::REG_DWORD as u32, vec![1, 0, 0, 0]))
+    );
+    assert_eq!(read("Missing"), None);
+    // a key is not a value
+    assert_eq!(read("Child"), None);
+}
+
+#[test]
+fn test_read_sized_follows_a_growing_value() {
+    // sized at 4, and 6 by the time it's read
+    let mut queries = 0;
+    let grown = read_sized(4, |data| {
+        queries += 1;
+        match data.len() >= 6 {
+            true => Queried::Read { vtype: 3, len: 6 },
+            false => Queried::MoreData(6),
+        }
+    });
+    assert_eq!(grown.unwrap(), (3, vec![0; 6]));
+    assert_eq!(queries, 2);
+
+    // for good
+    let mut queries = 0;
+    let error = read_sized(4, |data| {
+        queries += 1;
+        Queried::MoreData(data.len() * 2)
+    })
+    .unwrap_err();
+    assert_eq!(error.raw_os_error(), Some(ERROR_MORE_DATA as i32));
+    assert_eq!(queries, MAX_SIZE_RETRIES + 1);
+    let error = read_sized(4, |_| Queried::Failed(2)).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    // a value that shrank
+    assert_eq!(
+        read_sized(8, |_| Queried::Read { vtype: 4, len: 2 }).unwrap(),
+        (4, vec![0; 2])
+    );
+}
+
+#[test]
+fn test_root_hive_from_str() {
+    assert_eq!("hklm".parse::<RootHive>().unwrap(), RootHive::LocalMachine);
+    assert_eq!(
+        "HKEY_CURRENT_USER".parse::<RootHive>().unwrap(),
+        RootHive::CurrentUser
+    );
+
+    let error = "HKEY_PERFORMANCE_DATA".parse::<RootHive>().unwrap_err();
+    assert!(error.to_string().contains("HKEY_USERS (HKU)"));
+}
+
+#[test]
+fn test_reduced_hives() {
+    let scratch = ScratchKey::populated();
+    let ops = RegOps::with_hives(&[RootHive::LocalMachine, RootHive::CurrentUser]);
+
+    let mut names: Vec<_> = ops
+        .enumerate_key("".into())
+        .unwrap()
+        .subkeys
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["HKEY_CURRENT_USER", "HKEY_LOCAL_MACHINE"]);
+
+    assert!(ops.does_key_exist(&scratch.path()));
+    assert!(!ops.does_key_exist("HKEY_CLASSES_ROOT".as_ref()));
+    assert!(!ops.does_key_exist("HKEY_USERS\\.DEFAULT".as_ref()));
+    let without_user = RegOps::with_hives(&[RootHive::LocalMachine]);
+    assert!(!without_user.does_key_exist(&scratch.path()));
+}
+
+#[test]
+fn test_read_all_values_matches_read_value() {
+    let scratch = ScratchKey::populated();
+    let ops = RegOps::new();
+    let path = scratch.path();
+    let values = ops.read_all_values(&path, ValueCaps::default()).unwrap();
+    assert_eq!(values.len(), 6);
+    for (value, vtype, data) in &values {
+        let single = ops
+            .read_typed_value_ctx(&path.join(value), &OpContext::none())
+            .unwrap();
+        assert_eq!(data, &ValueData::Bytes(single.clone().unwrap().1));
+        assert_eq!(Some(*vtype), single.map(|(vtype, _)| vtype));
+    }
+
+    let find = |values: &[(OsString, u32, ValueData)], name: &str| {
+        values
+            .iter()
+            .find(|(value, _, _)| value == name)
+            .map(|(_, _, data)| data.clone())
+            .unwrap()
+    };
+    let caps = ValueCaps {
+        per_value: Some(100),
+        total: None,
+    };
+    let capped = ops.read_all_values(&path, caps).unwrap();
+    assert_eq!(find(&capped, "Blob"), ValueData::Oversized(10000));
+    assert_eq!(find(&capped, "Number"), ValueData::Bytes(vec![42, 0, 0, 0]));
+    let caps = ValueCaps {
+        per_value: None,
+        total: Some(0),
+    };
+    let capped = ops.read_all_values(&path, caps).unwrap();
+    assert!(capped
+        .iter()
+        .all(|(_, _, data)| matches!(data, ValueData::Oversized(_))));
+    assert_eq!(find(&capped, "Text").size(), 12);
+
+    drop(scratch);
+    assert!(matches!(
+        ops.read_all_values(&path, ValueCaps::default()),
+        Err(RegOpsError::KeyNotFound(_))
+    ));
+}
+
+#[test]
+fn test_create_key() {
+    let scratch = ScratchKey::empty();
+    let ops = RegOps::new();
+    let path = scratch.path();
+
+    ops.create_key(&path.join("Created"), false).unwrap();
+    assert!(ops.does_key_exist(&path.join("Created")));
+    assert!(!ops.key_info(&path.join("Created")).unwrap().volatile);
+    // already there is fine
+    ops.create_key(&path.join("Created"), false).unwrap();
+    // the parent has to exist
+    assert!(ops
+        .create_key(&path.join("Missing\\Created"), false)
+        .is_err());
+    assert!(!ops.does_key_exist(&path.join("Missing")));
+
+    let entries = ops.enumerate_key(path.clone().into()).unwrap();
+    assert_eq!(entries.subkeys.len(), 1);
+}
+
+#[test]
+fn test_volatile_key() {
+    let scratch = ScratchKey::empty();
+    let ops = RegOps::new();
+    let path = scratch.path().join("Volatile");
+
+    ops.create_key(&path, true).unwrap();
+    assert!(ops.key_info(&path).unwrap().volatile);
+    assert!(matches!(
+        ops.create_key(&path.join("Persistent"), false),
+        Err(RegOpsError::VolatileParent(_))
+    ));
+    ops.create_key(&path.join("Scratch"), true).unwrap();
+    assert!(ops.key_info(&path.join("Scratch")).unwrap().volatile);
+    assert!(!ops.key_info(&scratch.path()).unwrap().volatile);
+}
+
+#[test]
+fn test_open_key_with_fewer_rights() {
+    use crate::pipe::SecurityDescriptor;
+    use winapi::um::{
+        winnt::{DACL_SECURITY_INFORMATION, WRITE_DAC},
+        winreg::RegSetKeySecurity,
+    };
+    use winreg::enums::HKEY_CURRENT_USER;
+
+    let scratch = ScratchKey::empty();
+    scratch.key.create_subkey("Child").unwrap();
+    scratch.key.set_value("Hidden", &1u32).unwrap();
+    let name = scratch.name();
+    let hkcu = RegKey::predef(HKEY_CURRENT_USER);
+
+    // the owner can always change the DACL back, whatever it grants
+    let restrict = |sddl: &str| {
+        let key = hkcu.open_subkey_with_flags(name, WRITE_DAC).unwrap();
+        let security = SecurityDescriptor::from_sddl(sddl).unwrap();
+        let result = unsafe {
+            RegSetKeySecurity(
+                key.raw_handle() as usize as HKEY,
+                DACL_SECURITY_INFORMATION,
+                security.0,
+            )
+        };
+        assert_eq!(result as u32, ERROR_SUCCESS);
+    };
+    let ops = RegOps::new();
+    let path = scratch.path();
+    let names = |list: &[RegEntry]| list.iter().map(|e| e.name.clone()).collect::<Vec<_>>();
+
+    // KEY_ENUMERATE_SUB_KEYS only
+    restrict("D:P(A;;0x8;;;WD)");
+    let entries = ops.enumerate_key(path.clone().into()).unwrap();
+    assert_eq!(names(&entries.subkeys), ["Child"]);
+    assert!(entries.values.is_empty());
+    assert!(entries.partial && !entries.errors[0].is_subkey);
+    assert!(ops.read_value(&path.join("Hidden")).is_none());
+
+    // KEY_QUERY_VALUE only
+    restrict("D:P(A;;0x1;;;WD)");
+    let entries = ops.enumerate_key(path.clone().into()).unwrap();
+    assert!(entries.subkeys.is_empty());
+    assert_eq!(names(&entries.values), ["Hidden"]);
+    assert!(entries.partial && entries.errors[0].is_subkey);
+    assert_eq!(ops.read_value(&path.join("Hidden")).unwrap().len(), 4);
+
+    // nothing at all; dropping the scratch key gives the rights back
+    restrict("D:P(A;;KW;;;WD)");
+    assert!(ops.enumerate_key(path.clone().into()).is_none());
+    assert!(matches!(
+        ops.open_key(
+            &RegPath::parse(&path),
+            Access::Enumerate,
+            &OpContext::none()
+        ),
+        Err(RegOpsError::Open { .. })
+    ));
+    assert_eq!(
+        ops.classify(&path, &OpContext::none()),
+        Ok(PathKind::Denied)
+    );
+}
+
+#[test]
+fn test_classify() {
+    let scratch = ScratchKey::populated();
+    let ops = RegOps::new();
+    let classify = |path: &Path| ops.classify(path, &OpContext::none()).unwrap();
+
+    assert_eq!(classify(Path::new("")), PathKind::Key(0));
+    assert!(matches!(
+        classify(Path::new("HKEY_CURRENT_USER")),
+        PathKind::Key(_)
+    ));
+    assert_eq!(
+        classify(&scratch.path()),
+        PathKind::Key(ops.key_last_write_time(&scratch.path()).unwrap())
+    );
+    assert!(matches!(
+        classify(&scratch.path().join("Child\\Grandchild")),
+        PathKind::Key(_)
+    ));
+    assert_eq!(classify(&scratch.path().join("Text")), PathKind::Value);
+    assert_eq!(classify(&scratch.path().join("Missing")), PathKind::Missing);
+    assert_eq!(classify(Path::new("HKEY_NOWHERE")), PathKind::Missing);
+}
+
+#[test]
+fn test_from_nt_path() {
+    assert_eq!(
+        from_nt_path("\\REGISTRY\\MACHINE\\SOFTWARE\\Classes".as_ref()),
+        Some(PathBuf::from("HKEY_LOCAL_MACHINE\\SOFTWARE\\Classes"))
+    );
+    assert_eq!(
+        from_nt_path("\\Registry\\User\\S-1-5-18".as_ref()),
+        Some(PathBuf::from("HKEY_USERS\\S-1-5-18"))
     );
+    assert_eq!(from_nt_path("\\REGISTRY\\A\\B".as_ref()), None);
+    assert_eq!(from_nt_path("C:\\Windows".as_ref()), None);
+}
+
+// a pair of links pointing at each other; only an elevated test can make them
+#[test]
+fn test_link_cycle() {
+    use crate::links;
+    use winapi::um::winnt::{KEY_CREATE_LINK, REG_LINK, REG_OPTION_CREATE_LINK};
+    use winapi::um::winreg::RegSetValueExW;
+
+    #[link(name = "ntdll")]
+    extern "system" {
+        fn NtDeleteKey(key: HANDLE) -> NTSTATUS;
+    }
+
+    let base = format!("SOFTWARE\\regfs-test-links-{}", std::process::id());
+    let machine = RegKey::predef(winreg::enums::HKEY_LOCAL_MACHINE);
+    let scratch = match machine.create_subkey(&base) {
+        Ok((scratch, _)) => scratch,
+        Err(_) => {
+            eprintln!("test_link_cycle: not elevated, skipped");
+            return;
+        }
+    };
+    let create_link = |name: &str, target: &str| -> HKEY {
+        let name: Vec<u16> = OsStr::new(name).encode_wide().chain(Some(0)).collect();
+        let mut link = std::ptr::null_mut();
+        let result = unsafe {
+            RegCreateKeyExW(
+                scratch.raw_handle() as usize as HKEY,
+                name.as_ptr(),
+                0,
+                std::ptr::null_mut(),
+                REG_OPTION_CREATE_LINK | REG_OPTION_VOLATILE,
+                KEY_ALL_ACCESS | KEY_CREATE_LINK,
+                std::ptr::null_mut(),
+                &mut link,
+                std::ptr::null_mut(),
+            )
+        };
+        assert_eq!(result as u32, ERROR_SUCCESS);
+
+        // the target goes without its NUL
+        let target: Vec<u16> = format!("\\REGISTRY\\MACHINE\\{}\\{}", base, target)
+            .encode_utf16()
+            .collect();
+        let value: Vec<u16> = SYMBOLIC_LINK_VALUE.encode_utf16().chain(Some(0)).collect();
+        let result = unsafe {
+            RegSetValueExW(
+                link,
+                value.as_ptr(),
+                0,
+                REG_LINK,
+                target.as_ptr() as *const u8,
+                (target.len() * 2) as u32,
+            )
+        };
+        assert_eq!(result as u32, ERROR_SUCCESS);
+        link
+    };
+    let ping = create_link("Ping", "Pong");
+    let pong = create_link("Pong", "Ping");
+
+    let ops = RegOps::new();
+    let path = Path::new("HKEY_LOCAL_MACHINE").join(&base);
+    let target = ops.link_target(&path.join("Ping"));
+    let cycle = links::resolve(&ops, &path.join("Ping\\Deeper"));
+
+    // links are deleted through their own handle, not by name
+    for link in [ping, pong] {
+        unsafe {
+            NtDeleteKey(link as HANDLE);
+            RegCloseKey(link);
+        }
+    }
+    drop(scratch);
+    machine.delete_subkey_all(&base).unwrap();
+
+    assert_eq!(target, Some(path.join("Pong")));
+    assert_eq!(cycle.unwrap_err().at, path.join("Ping"));
+}
+
+#[test]
+fn test_watch_users() {
+    use std::{process::Command, sync::mpsc, sync::Mutex, time::Duration};
+
+    let scratch = match ScratchKey::try_empty() {
+        Some(scratch) => scratch,
+        None => {
+            eprintln!("test_watch_users: no registry, skipped");
+            return;
+        }
+    };
+    scratch.key.set_value("Value", &1u32).unwrap();
+    let reg = |args: &[&OsStr]| {
+        Command::new("reg")
+            .args(args)
+            .output()
+            .map_or(false, |output| output.status.success())
+    };
+    let name = format!("regfs-test-{}", std::process::id());
+    let file = std::env::temp_dir().join(&name);
+    let _ = std::fs::remove_file(&file);
+    let loaded = format!("HKEY_USERS\\{}", name);
+
+    let ops = RegOps::with_hives(&[RootHive::Users]);
+    let (sender, changes) = mpsc::channel();
+    let sender = Mutex::new(sender);
+    ops.watchers().unwrap().subscribe(
+        "HKEY_USERS".as_ref(),
+        Arc::new(move |path| {
+            let _ = sender.lock().unwrap().send(path.to_path_buf());
+        }),
+    );
+    // the watch thread has to be waiting before the load
+    thread::sleep(Duration::from_millis(200));
+
+    // both need administrators, like loading a hive by hand would
+    let saved = reg(&[
+        "save".as_ref(),
+        scratch.path().as_os_str(),
+        file.as_os_str(),
+    ]);
+    if !saved || !reg(&["load".as_ref(), loaded.as_ref(), file.as_os_str()]) {
+        eprintln!("test_watch_users: unable to load a hive, skipped");
+        let _ = std::fs::remove_file(&file);
+        return;
+    }
+    let listed = |ops: &RegOps| {
+        ops.enumerate_key("HKEY_USERS".into())
+            .unwrap()
+            .subkeys
+            .iter()
+            .any(|key| key.name == *name)
+    };
+    let on_load = changes.recv_timeout(Duration::from_secs(10));
+    let listed_loaded = listed(&ops);
+    let unloaded = reg(&["unload".as_ref(), loaded.as_ref()]);
+    let on_unload = changes.recv_timeout(Duration::from_secs(10));
+    let listed_unloaded = listed(&ops);
+    let _ = std::fs::remove_file(&file);
+
+    assert_eq!(on_load, Ok(PathBuf::from("HKEY_USERS")));
+    assert!(listed_loaded);
+    assert!(unloaded);
+    assert_eq!(on_unload, Ok(PathBuf::from("HKEY_USERS")));
+    assert!(!listed_unloaded);
+}
+
+#[test]
+fn test_key_info_counts() {
+    let scratch = ScratchKey::empty();
+    for subkey in ["A", "Longer", "C"] {
+        scratch.key.create_subkey(subkey).unwrap();
+    }
+    scratch.key.set_value("Number", &1u32).unwrap();
+    scratch
+        .key
+        .set_value("LongestName", &"twelve chars")
+        .unwrap();
+
+    let ops = RegOps::new();
+    let path = scratch.path();
+    let info = ops.key_info(&path).unwrap();
+    assert_eq!((info.subkeys, info.values), (3, 2));
+    assert_eq!(info.max_subkey_name, 6);
+    assert_eq!(info.max_value_name, 11);
+    // UTF-16 with the NUL
+    assert_eq!(info.max_value_size, 26);
+    assert!(info.last_write_time > 0 && !info.volatile);
+    assert_eq!(RegistryBackend::key_info(&ops, &path), Some(info));
+
+    assert!(matches!(
+        ops.key_info(&path.join("Missing")),
+        Err(RegOpsError::KeyNotFound(_))
+    ));
 }
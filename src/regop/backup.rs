@@ -0,0 +1,97 @@
+use std::{io, os::windows::ffi::OsStrExt, path::Path};
+use winapi::{
+    shared::{
+        minwindef::{HKEY, REGSAM},
+        winerror::{ERROR_FILE_NOT_FOUND, ERROR_SUCCESS},
+    },
+    um::{
+        winnt::{REG_CREATED_NEW_KEY, REG_OPTION_BACKUP_RESTORE},
+        winreg::{RegCloseKey, RegCreateKeyExW, RegDeleteKeyW, RegOpenKeyExW},
+    },
+};
+use winreg::RegKey;
+
+// `subkey` opened the way a backup reads it: with SeBackupPrivilege enabled, the key's DACL
+// isn't checked for reads. RegOpenKeyEx only takes REG_OPTION_BACKUP_RESTORE from Windows
+// 10 on; before that only RegCreateKeyEx does, which is only asked for a key that's there
+// (the open was refused, not missing), and a key it creates anyway is deleted again
+pub fn open(root: &RegKey, subkey: &Path, mask: REGSAM) -> io::Result<RegKey> {
+    let name: Vec<u16> = subkey.as_os_str().encode_wide().chain(Some(0)).collect();
+    let root = root.raw_handle() as usize as HKEY;
+
+    let mut hkey = std::ptr::null_mut();
+    let result = unsafe {
+        RegOpenKeyExW(
+            root,
+            name.as_ptr(),
+            REG_OPTION_BACKUP_RESTORE,
+            mask,
+            &mut hkey,
+        )
+    } as u32;
+    match result {
+        // closed on drop
+        ERROR_SUCCESS => return Ok(RegKey::predef(hkey as usize as _)),
+        ERROR_FILE_NOT_FOUND => return Err(io::Error::from_raw_os_error(result as i32)),
+        _ => {}
+    }
+
+    let mut disposition = 0;
+    let result = unsafe {
+        RegCreateKeyExW(
+            root,
+            name.as_ptr(),
+            0,
+            std::ptr::null_mut(),
+            REG_OPTION_BACKUP_RESTORE,
+            mask,
+            std::ptr::null_mut(),
+            &mut hkey,
+            &mut disposition,
+        )
+    } as u32;
+    if result != ERROR_SUCCESS {
+        return Err(io::Error::from_raw_os_error(result as i32));
+    }
+    if disposition == REG_CREATED_NEW_KEY {
+        unsafe {
+            RegCloseKey(hkey);
+            RegDeleteKeyW(root, name.as_ptr());
+        }
+        return Err(io::Error::from_raw_os_error(ERROR_FILE_NOT_FOUND as i32));
+    }
+    Ok(RegKey::predef(hkey as usize as _))
+}
+
+// SAM\SAM only lets SYSTEM in, administrators included; only an elevated test can read it
+#[test]
+fn test_backup_semantics() {
+    use super::{RegOps, RootHive};
+    use crate::backend::{PathKind, RegistryBackend};
+    use crate::opcontext::OpContext;
+    use crate::privileges;
+
+    if !privileges::enable_process_backup() {
+        eprintln!("test_backup_semantics: not elevated, skipped");
+        return;
+    }
+    let sam = Path::new("HKEY_LOCAL_MACHINE\\SAM\\SAM");
+    let ops = RegOps::with_hives(&[RootHive::LocalMachine]);
+    assert!(matches!(
+        ops.classify(sam, &OpContext::none()),
+        Ok(PathKind::Denied)
+    ));
+
+    let ops = RegOps::with_backup_semantics(&[RootHive::LocalMachine]);
+    let entries = ops.enumerate_key(sam.into()).unwrap();
+    assert!(entries
+        .subkeys
+        .iter()
+        .any(|key| key.name.eq_ignore_ascii_case("Domains")));
+    assert!(ops.read_value(&sam.join("C")).is_some());
+
+    // nothing is created by looking
+    let missing = sam.join(format!("regfs-test-{}", std::process::id()));
+    assert!(!ops.does_key_exist(&missing));
+    assert!(!ops.does_key_exist(&missing));
+}
@@ -0,0 +1,350 @@
+use std::{
+    ffi::{OsStr, OsString},
+    os::windows::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
+};
+
+// the unnamed value has no file name of its own, so it's listed as this, like regedit shows it
+pub const DEFAULT_VALUE: &str = "(default)";
+
+const SEPARATORS: [u16; 2] = [b'\\' as u16, b'/' as u16];
+const PREFIXES: [&str; 2] = ["\\\\?\\", "\\??\\"];
+
+// a path relative to the virtualization root: an optional hive followed by key components,
+// the last of which may also be read as a value name
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct RegPath {
+    pub hive: Option<OsString>,
+    pub keys: Vec<OsString>,
+}
+
+pub fn components<T: AsRef<OsStr>>(path: T) -> Vec<OsString> {
+    let units: Vec<u16> = path.as_ref().encode_wide().collect();
+    wide_parts(&units).map(OsString::from_wide).collect()
+}
+
+// the parts of a path as they came, for what only looks at them and needn't allocate
+pub fn wide_parts(units: &[u16]) -> impl Iterator<Item = &[u16]> {
+    // names are C strings as far as the registry is concerned, so anything after a NUL is dropped
+    let end = units.iter().position(|unit| *unit == 0);
+    let mut rest = &units[..end.unwrap_or(units.len())];
+    for prefix in PREFIXES.iter() {
+        let len = prefix.encode_utf16().count();
+        if rest.len() >= len && prefix.encode_utf16().eq(rest[..len].iter().copied()) {
+            rest = &rest[len..];
+            break;
+        }
+    }
+
+    rest.split(|unit| SEPARATORS.contains(unit))
+        .filter(|part| !part.is_empty() && *part != [b'.' as u16])
+}
+
+// the parts joined with `\`; collecting them into a PathBuf would take a name like `C:` for
+// a drive and drop everything before it
+pub fn join_parts<T: AsRef<OsStr>>(parts: impl IntoIterator<Item = T>) -> PathBuf {
+    let mut path = OsString::new();
+    for (index, part) in parts.into_iter().enumerate() {
+        if index > 0 {
+            path.push("\\");
+        }
+        path.push(part);
+    }
+    PathBuf::from(path)
+}
+
+pub fn normalize<T: AsRef<OsStr>>(path: T) -> PathBuf {
+    RegPath::parse(path).to_path()
+}
+
+impl RegPath {
+    pub fn parse<T: AsRef<OsStr>>(path: T) -> RegPath {
+        let mut parts = components(path).into_iter();
+
+        RegPath {
+            // hive names are ASCII and looked up case-insensitively
+            hive: parts.next().map(|hive| hive.to_ascii_uppercase()),
+            keys: parts.collect(),
+        }
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.hive.is_none()
+    }
+
+    pub fn is_hive(&self) -> bool {
+        self.hive.is_some() && self.keys.is_empty()
+    }
+
+    // the path below the hive, as taken by RegKey::open_subkey
+    pub fn subkey(&self) -> PathBuf {
+        join_parts(&self.keys)
+    }
+
+    pub fn to_path(&self) -> PathBuf {
+        join_parts(self.hive.iter().chain(self.keys.iter()))
+    }
+
+    pub fn parent(&self) -> Option<RegPath> {
+        let mut parent = self.clone();
+        if parent.keys.pop().is_none() {
+            parent.hive.take()?;
+        }
+        Some(parent)
+    }
+
+    pub fn file_name(&self) -> Option<&OsStr> {
+        self.keys
+            .last()
+            .or(self.hive.as_ref())
+            .map(|name| name.as_os_str())
+    }
+
+    // reads the path as a value inside its parent key; values can't live at the root
+    pub fn split_value(&self) -> Option<(RegPath, OsString)> {
+        let mut keys = self.keys.clone();
+        let value = keys.pop()?;
+
+        Some((
+            RegPath {
+                hive: self.hive.clone(),
+                keys,
+            },
+            value,
+        ))
+    }
+
+    pub fn join<T: AsRef<OsStr>>(&self, name: T) -> RegPath {
+        let mut path = self.clone();
+        for part in components(name) {
+            if path.hive.is_none() {
+                path.hive = Some(part.to_ascii_uppercase());
+            } else {
+                path.keys.push(part);
+            }
+        }
+        path
+    }
+}
+
+pub fn is_default_value<T: AsRef<OsStr>>(name: T) -> bool {
+    name.as_ref()
+        .to_string_lossy()
+        .eq_ignore_ascii_case(DEFAULT_VALUE)
+}
+
+// a listing's value names as files: the unnamed value becomes DEFAULT_VALUE, unless a value
+// is really called that, in which case that one keeps the name and the unnamed one is hidden
+pub fn name_default_value<T>(values: &mut Vec<T>, name: impl Fn(&mut T) -> &mut OsString) {
+    let taken = values.iter_mut().any(|value| is_default_value(name(value)));
+    values.retain_mut(|value| !(taken && name(value).is_empty()));
+    for value in values.iter_mut() {
+        let name = name(value);
+        if name.is_empty() {
+            *name = DEFAULT_VALUE.into();
+        }
+    }
+}
+
+// the other way, for a value that isn't there under its file name
+pub fn default_value_fallback(name: &OsStr) -> Option<OsString> {
+    is_default_value(name).then(OsString::new)
+}
+
+impl From<&Path> for RegPath {
+    fn from(path: &Path) -> Self {
+        RegPath::parse(path)
+    }
+}
+
+#[cfg(test)]
+fn parse(path: &str) -> (Option<String>, Vec<String>) {
+    let path = RegPath::parse(path);
+    (
+        path.hive.map(|hive| hive.to_string_lossy().into_owned()),
+        path.keys
+            .iter()
+            .map(|key| key.to_string_lossy().into_owned())
+            .collect(),
+    )
+}
+
+#[test]
+fn test_parse_empty_and_roots() {
+    for root in [
+        "",
+        "\\",
+        "/",
+        "\\\\",
+        "\\\\?\\",
+        "\\??\\",
+        ".",
+        "\0HKEY_USERS",
+    ] {
+        assert_eq!(parse(root), (None, vec![]), "[{:?}]", root);
+        assert!(RegPath::parse(root).is_root());
+    }
+}
+
+#[test]
+fn test_parse_bare_hive() {
+    for hive in [
+        "HKEY_USERS",
+        "hkey_users",
+        "\\HKEY_USERS",
+        "HKEY_USERS\\",
+        "\\\\?\\HKEY_USERS",
+    ] {
+        assert_eq!(
+            parse(hive),
+            (Some("HKEY_USERS".into()), vec![]),
+            "[{:?}]",
+            hive
+        );
+        assert!(RegPath::parse(hive).is_hive());
+    }
+}
+
+#[test]
+fn test_parse_separators() {
+    let expected = (
+        Some("HKEY_LOCAL_MACHINE".into()),
+        vec!["SOFTWARE".to_owned(), "Microsoft".to_owned()],
+    );
+
+    for path in [
+        "HKEY_LOCAL_MACHINE\\SOFTWARE\\Microsoft",
+        "HKEY_LOCAL_MACHINE\\SOFTWARE\\Microsoft\\",
+        "HKEY_LOCAL_MACHINE/SOFTWARE/Microsoft",
+        "HKEY_LOCAL_MACHINE\\\\SOFTWARE//Microsoft",
+        "\\HKEY_LOCAL_MACHINE\\.\\SOFTWARE\\Microsoft",
+        "\\\\?\\HKEY_LOCAL_MACHINE\\SOFTWARE\\Microsoft",
+        "\\??\\HKEY_LOCAL_MACHINE\\SOFTWARE\\Microsoft",
+    ] {
+        assert_eq!(parse(path), expected, "[{:?}]", path);
+    }
+}
+
+#[test]
+fn test_parse_embedded_nul() {
+    assert_eq!(
+        parse("HKEY_USERS\\.DEFAULT\0\\Control Panel"),
+        (Some("HKEY_USERS".into()), vec![".DEFAULT".to_owned()])
+    );
+}
+
+#[test]
+fn test_split_value() {
+    let (key, value) = RegPath::parse("HKEY_CURRENT_USER\\Console\\FontSize")
+        .split_value()
+        .unwrap();
+    assert_eq!(key.to_path(), PathBuf::from("HKEY_CURRENT_USER\\Console"));
+    assert_eq!(value, OsString::from("FontSize"));
+
+    let (key, value) = RegPath::parse("HKEY_CURRENT_USER\\Value")
+        .split_value()
+        .unwrap();
+    assert!(key.is_hive());
+    assert_eq!(value, OsString::from("Value"));
+
+    assert_eq!(RegPath::parse("HKEY_CURRENT_USER").split_value(), None);
+    assert_eq!(RegPath::parse("").split_value(), None);
+}
+
+#[test]
+fn test_join_and_parent() {
+    let root = RegPath::parse("");
+    let hive = root.join("hkey_users");
+    assert_eq!(hive, RegPath::parse("HKEY_USERS"));
+
+    let key = hive.join("S-1-5-18\\Software");
+    assert_eq!(
+        key.to_path(),
+        PathBuf::from("HKEY_USERS\\S-1-5-18\\Software")
+    );
+    assert_eq!(key.file_name(), Some(OsStr::new("Software")));
+    assert_eq!(key.parent().unwrap().parent(), Some(hive.clone()));
+    assert_eq!(hive.parent(), Some(root.clone()));
+    assert_eq!(root.parent(), None);
+    assert_eq!(
+        normalize("hkey_users//S-1-5-18\\"),
+        PathBuf::from("HKEY_USERS\\S-1-5-18")
+    );
+}
+
+#[test]
+fn test_default_value_name() {
+    let names = |values: &[&str]| {
+        let mut values: Vec<OsString> = values.iter().map(OsString::from).collect();
+        name_default_value(&mut values, |name| name);
+        values
+    };
+
+    assert_eq!(names(&["", "Path"]), ["(default)", "Path"]);
+    assert_eq!(names(&["Path"]), ["Path"]);
+    // a value really called that wins, whatever its case
+    assert_eq!(names(&["", "(Default)", "Path"]), ["(Default)", "Path"]);
+
+    assert_eq!(
+        default_value_fallback(OsStr::new("(DEFAULT)")),
+        Some(OsString::new())
+    );
+    assert_eq!(default_value_fallback(OsStr::new("default")), None);
+}
+
+#[test]
+fn test_drive_like_names() {
+    let path = RegPath::parse("HKEY_CURRENT_USER\\C:\\Windows");
+    assert_eq!(path.keys, ["C:", "Windows"]);
+    assert_eq!(
+        path.to_path().as_os_str(),
+        OsStr::new("HKEY_CURRENT_USER\\C:\\Windows")
+    );
+    assert_eq!(path.subkey().as_os_str(), OsStr::new("C:\\Windows"));
+}
+
+#[test]
+fn test_fuzz_paths() {
+    use crate::fuzz::{self, Rng};
+
+    let check = |units: &[u16]| {
+        let input = OsString::from_wide(units);
+        let path = RegPath::parse(&input);
+        for part in path.hive.iter().chain(path.keys.iter()) {
+            let part: Vec<u16> = part.encode_wide().collect();
+            assert!(!part.is_empty() && part != [b'.' as u16], "{:?}", input);
+            assert!(
+                !part
+                    .iter()
+                    .any(|unit| *unit == 0 || SEPARATORS.contains(unit)),
+                "{:?}",
+                input
+            );
+        }
+
+        // never longer than what it came from, and already as normal as it gets
+        let normalized = path.to_path();
+        assert!(normalized.as_os_str().encode_wide().count() <= units.len());
+        assert_eq!(RegPath::parse(&normalized), path, "{:?}", input);
+        assert_eq!(normalize(&normalized), normalized, "{:?}", input);
+
+        if let Some((key, value)) = path.split_value() {
+            assert_eq!(Some(value.as_os_str()), path.file_name());
+            assert_eq!(key.join(&value), path, "{:?}", input);
+        }
+        match path.parent() {
+            Some(parent) => assert_eq!(parent.join(path.file_name().unwrap()), path),
+            None => assert!(path.is_root()),
+        }
+    };
+
+    let corpus = fuzz::corpus();
+    for name in &corpus {
+        check(name);
+    }
+    let mut rng = Rng::new(fuzz::seed(), 0);
+    for _ in 0..fuzz::iterations() {
+        let (seed, other) = (rng.pick(&corpus), rng.pick(&corpus));
+        check(&rng.mutate(seed, other));
+    }
+}
@@ -0,0 +1,696 @@
+use log::{info, warn};
+use std::{
+    ffi::{c_void, OsStr, OsString},
+    io,
+    os::windows::ffi::OsStrExt,
+    path::Path,
+};
+use winapi::{
+    shared::{
+        minwindef::{DWORD, HKEY, LPDWORD},
+        winerror::{ERROR_ALREADY_EXISTS, ERROR_INVALID_PARAMETER, ERROR_MORE_DATA, ERROR_SUCCESS},
+    },
+    um::{
+        libloaderapi::{GetModuleHandleW, GetProcAddress},
+        winnt::{
+            DACL_SECURITY_INFORMATION, DELETE, KEY_READ, KEY_WRITE, LPCWSTR, READ_CONTROL,
+            REG_OPENED_EXISTING_KEY, REG_OPTION_NON_VOLATILE, REG_OPTION_VOLATILE, WRITE_DAC,
+        },
+        winreg::{
+            RegCloseKey, RegCopyTreeW, RegCreateKeyExW, RegGetKeySecurity, RegQueryInfoKeyW,
+            RegSetKeySecurity, RegSetValueExW,
+        },
+    },
+};
+use winreg::RegKey;
+
+use super::{enum_values, is_volatile, RegOps, RegOpsError, ValueCaps, ValueData};
+use crate::hash;
+use crate::opcontext::OpContext;
+use crate::regfs::path_key;
+use crate::regop::paths::RegPath;
+
+// how big a tree the copy fallback takes on, and whether it copies access rights too
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CopyLimits {
+    pub max_keys: usize,
+    pub max_bytes: u64,
+    // each key's DACL, where the caller may read and set it
+    pub security: bool,
+}
+
+impl Default for CopyLimits {
+    fn default() -> Self {
+        CopyLimits {
+            max_keys: 10_000,
+            max_bytes: 64 << 20,
+            security: false,
+        }
+    }
+}
+
+// what a tree holds, to tell a copy from the original: counts and a hash of every key's
+// relative path and class, and every value's name, type and data
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TreeDigest {
+    pub keys: usize,
+    pub values: usize,
+    pub bytes: u64,
+    pub hash: u64,
+}
+
+type RenameKey = unsafe extern "system" fn(HKEY, LPCWSTR, LPCWSTR) -> i32;
+
+// advapi32 only has RegRenameKey from Vista on, and it isn't in the import library
+fn rename_key_export() -> Option<RenameKey> {
+    let module: Vec<u16> = "advapi32.dll\0".encode_utf16().collect();
+    let module = unsafe { GetModuleHandleW(module.as_ptr()) };
+    if module.is_null() {
+        return None;
+    }
+    let address = unsafe { GetProcAddress(module, "RegRenameKey\0".as_ptr() as *const i8) };
+    (!address.is_null()).then(|| unsafe { std::mem::transmute::<_, RenameKey>(address) })
+}
+
+fn wide(name: &OsStr) -> Vec<u16> {
+    name.encode_wide().chain(Some(0)).collect()
+}
+
+fn failed(path: &RegPath, error: io::Error) -> RegOpsError {
+    RegOpsError::Rename {
+        path: path.to_path(),
+        error,
+    }
+}
+
+fn class_of(key: &RegKey) -> io::Result<Vec<u16>> {
+    let mut class = vec![0u16; 256];
+    loop {
+        let mut len = class.len() as DWORD;
+        let null = std::ptr::null_mut::<DWORD>() as LPDWORD;
+        let result = unsafe {
+            RegQueryInfoKeyW(
+                key.raw_handle() as usize as HKEY,
+                class.as_mut_ptr(),
+                &mut len,
+                null,
+                null,
+                null,
+                null,
+                null,
+                null,
+                null,
+                null,
+                std::ptr::null_mut(),
+            )
+        } as u32;
+        match result {
+            ERROR_SUCCESS => {
+                class.truncate(len as usize);
+                return Ok(class);
+            }
+            // too small for the class, which has no documented limit
+            ERROR_MORE_DATA if class.len() < 1 << 15 => class.resize(class.len() * 2, 0),
+            error => return Err(io::Error::from_raw_os_error(error as i32)),
+        }
+    }
+}
+
+fn security_of(key: &RegKey) -> io::Result<Vec<u8>> {
+    let hkey = key.raw_handle() as usize as HKEY;
+    let mut len = 0;
+    unsafe {
+        RegGetKeySecurity(
+            hkey,
+            DACL_SECURITY_INFORMATION,
+            std::ptr::null_mut(),
+            &mut len,
+        )
+    };
+    let mut security = vec![0u8; len as usize];
+    let result = unsafe {
+        RegGetKeySecurity(
+            hkey,
+            DACL_SECURITY_INFORMATION,
+            security.as_mut_ptr() as *mut c_void,
+            &mut len,
+        )
+    } as u32;
+    match result {
+        ERROR_SUCCESS => Ok(security),
+        error => Err(io::Error::from_raw_os_error(error as i32)),
+    }
+}
+
+// a subkey of `parent` with the class and volatility of `like`, which mustn't exist yet
+fn create_like(parent: &RegKey, name: &OsStr, like: &RegKey) -> io::Result<RegKey> {
+    let wide_name = wide(name);
+    let mut class = class_of(like)?;
+    class.push(0);
+    let options = match is_volatile(like) {
+        true => REG_OPTION_VOLATILE,
+        false => REG_OPTION_NON_VOLATILE,
+    };
+
+    let mut hkey = std::ptr::null_mut();
+    let mut disposition = 0;
+    let result = unsafe {
+        RegCreateKeyExW(
+            parent.raw_handle() as usize as HKEY,
+            wide_name.as_ptr(),
+            0,
+            class.as_mut_ptr(),
+            options,
+            KEY_READ | KEY_WRITE | WRITE_DAC,
+            std::ptr::null_mut(),
+            &mut hkey,
+            &mut disposition,
+        )
+    } as u32;
+    if result != ERROR_SUCCESS {
+        return Err(io::Error::from_raw_os_error(result as i32));
+    }
+    unsafe { RegCloseKey(hkey) };
+    if disposition == REG_OPENED_EXISTING_KEY {
+        return Err(io::Error::from_raw_os_error(ERROR_ALREADY_EXISTS as i32));
+    }
+    parent.open_subkey_with_flags(name, KEY_READ | KEY_WRITE | WRITE_DAC)
+}
+
+// any type, where RegValue only takes the ones winreg has names for
+pub(super) fn set_value(key: &RegKey, name: &OsStr, vtype: u32, data: &[u8]) -> io::Result<()> {
+    let name = wide(name);
+    let result = unsafe {
+        RegSetValueExW(
+            key.raw_handle() as usize as HKEY,
+            name.as_ptr(),
+            0,
+            vtype,
+            data.as_ptr(),
+            data.len() as DWORD,
+        )
+    } as u32;
+    match result {
+        ERROR_SUCCESS => Ok(()),
+        error => Err(io::Error::from_raw_os_error(error as i32)),
+    }
+}
+
+// stops with Interrupted once `ctx` is cancelled
+fn copy_tree(from: &RegKey, to: &RegKey, limits: &CopyLimits, ctx: &OpContext) -> io::Result<()> {
+    if ctx.check().is_err() {
+        return Err(io::ErrorKind::Interrupted.into());
+    }
+    if limits.security {
+        let mut security = security_of(from)?;
+        let result = unsafe {
+            RegSetKeySecurity(
+                to.raw_handle() as usize as HKEY,
+                DACL_SECURITY_INFORMATION,
+                security.as_mut_ptr() as *mut c_void,
+            )
+        } as u32;
+        if result != ERROR_SUCCESS {
+            return Err(io::Error::from_raw_os_error(result as i32));
+        }
+    }
+
+    for (name, vtype, data) in enum_values(from, ValueCaps::default()) {
+        let bytes = match data {
+            ValueData::Bytes(bytes) => bytes,
+            ValueData::Oversized(_) => unreachable!("read without caps"),
+        };
+        set_value(to, &name, vtype, &bytes)?;
+    }
+    for name in from.enum_keys() {
+        let name = OsString::from(name?);
+        let child = from.open_subkey_with_flags(&name, KEY_READ | READ_CONTROL)?;
+        let copy = create_like(to, &name, &child)?;
+        copy_tree(&child, &copy, limits, ctx)?;
+    }
+    Ok(())
+}
+
+// walks the whole tree; Err once it's over the limits
+pub fn digest(key: &RegKey, limits: &CopyLimits) -> io::Result<Result<TreeDigest, TreeDigest>> {
+    let mut digest = TreeDigest::default();
+    let mut bytes = Vec::new();
+    let over = digest_into(key, &mut Vec::new(), limits, &mut digest, &mut bytes)?;
+    digest.hash = hash::fnv1a(&bytes);
+    Ok(match over {
+        true => Err(digest),
+        false => Ok(digest),
+    })
+}
+
+// true when it stopped early, over the limits
+fn digest_into(
+    key: &RegKey,
+    path: &mut Vec<u16>,
+    limits: &CopyLimits,
+    digest: &mut TreeDigest,
+    bytes: &mut Vec<u8>,
+) -> io::Result<bool> {
+    digest.keys += 1;
+    let mut record = |units: &[u16]| {
+        bytes.extend(units.iter().flat_map(|unit| unit.to_le_bytes()));
+        bytes.extend([0, 0]);
+    };
+    record(path);
+    record(&class_of(key)?);
+
+    let mut values = enum_values(key, ValueCaps::default());
+    values.sort_by(|(a, _, _), (b, _, _)| a.cmp(b));
+    for (name, vtype, data) in values {
+        let data = match data {
+            ValueData::Bytes(data) => data,
+            ValueData::Oversized(_) => unreachable!("read without caps"),
+        };
+        digest.values += 1;
+        digest.bytes += data.len() as u64;
+        record(&name.encode_wide().collect::<Vec<_>>());
+        bytes.extend(vtype.to_le_bytes());
+        bytes.extend((data.len() as u64).to_le_bytes());
+        bytes.extend(data);
+    }
+    if digest.keys > limits.max_keys || digest.bytes > limits.max_bytes {
+        return Ok(true);
+    }
+
+    let mut names = key
+        .enum_keys()
+        .map(|name| name.map(OsString::from))
+        .collect::<io::Result<Vec<_>>>()?;
+    names.sort_by_key(|name| name.to_string_lossy().to_lowercase());
+    for name in names {
+        let child = key.open_subkey_with_flags(&name, KEY_READ)?;
+        let len = path.len();
+        path.push('\\' as u16);
+        path.extend(name.to_string_lossy().to_lowercase().encode_utf16());
+        let over = digest_into(&child, path, limits, digest, bytes)?;
+        path.truncate(len);
+        if over {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+impl RegOps {
+    // RegRenameKey where it can: same parent and an OS that has it. Otherwise (or when it
+    // fails, as it does for some keys) the tree is copied to `to`, the copy compared with
+    // the original, and only then the original deleted; a failed copy is deleted again
+    pub fn rename_key(
+        &self,
+        from: &Path,
+        to: &Path,
+        limits: CopyLimits,
+    ) -> Result<(), RegOpsError> {
+        let (from, to) = (RegPath::parse(from), RegPath::parse(to));
+        if from.is_hive() || to.is_hive() {
+            return Err(failed(
+                &from,
+                io::Error::from_raw_os_error(ERROR_INVALID_PARAMETER as i32),
+            ));
+        }
+        let (from_parent, name) = from
+            .split_value()
+            .ok_or_else(|| RegOpsError::KeyNotFound(from.to_path()))?;
+        let (to_parent, new_name) = to
+            .split_value()
+            .ok_or_else(|| RegOpsError::KeyNotFound(to.to_path()))?;
+        let source_parent = self.open_exact(&from_parent, KEY_READ | KEY_WRITE | DELETE)?;
+        let source = source_parent
+            .open_subkey_with_flags(&name, KEY_READ | READ_CONTROL)
+            .map_err(|_| RegOpsError::KeyNotFound(from.to_path()))?;
+        let target_parent = self.open_exact(&to_parent, KEY_READ | KEY_WRITE)?;
+        if target_parent.open_subkey(&new_name).is_ok() {
+            return Err(failed(
+                &to,
+                io::Error::from_raw_os_error(ERROR_ALREADY_EXISTS as i32),
+            ));
+        }
+
+        let same_parent = from.hive == to.hive
+            && from_parent.subkey().to_string_lossy().to_lowercase()
+                == to_parent.subkey().to_string_lossy().to_lowercase();
+        if let (true, Some(rename)) = (same_parent, rename_key_export()) {
+            let (old, new) = (wide(&name), wide(&new_name));
+            let result = unsafe {
+                rename(
+                    source_parent.raw_handle() as usize as HKEY,
+                    old.as_ptr(),
+                    new.as_ptr(),
+                )
+            } as u32;
+            if result == ERROR_SUCCESS {
+                return Ok(());
+            }
+            warn!(
+                target: "regops",
+                "rename_key: RegRenameKey [{:?}] failed with {}, copying it instead",
+                from.to_path(),
+                io::Error::from_raw_os_error(result as i32)
+            );
+        }
+
+        let original = digest(&source, &limits)
+            .map_err(|error| failed(&from, error))?
+            .map_err(|digest| RegOpsError::TooLarge {
+                path: from.to_path(),
+                keys: digest.keys,
+                bytes: digest.bytes,
+            })?;
+        info!(
+            target: "regops",
+            "rename_key: copying [{:?}] ({} keys, {} values, {} bytes) to [{:?}]",
+            from.to_path(),
+            original.keys,
+            original.values,
+            original.bytes,
+            to.to_path()
+        );
+
+        let copy =
+            create_like(&target_parent, &new_name, &source).map_err(|error| failed(&to, error))?;
+        let copied = copy_tree(&source, &copy, &limits, &OpContext::none())
+            .map_err(|error| failed(&to, error))
+            .and_then(
+                |()| match digest(&copy, &limits).map_err(|error| failed(&to, error))? {
+                    Ok(digest) if digest == original => Ok(()),
+                    _ => Err(RegOpsError::CopyMismatch(to.to_path())),
+                },
+            );
+        drop(copy);
+        if let Err(error) = copied {
+            if let Err(e) = target_parent.delete_subkey_all(&new_name) {
+                warn!(target: "regops", "rename_key: [{:?}] left behind: {}", to.to_path(), e);
+            }
+            return Err(error);
+        }
+
+        drop(source);
+        source_parent
+            .delete_subkey_all(&name)
+            .map_err(|error| failed(&from, error))
+    }
+
+    // the tree at `from` as a new key `to`, or in place of the one there with `force`: by
+    // RegCopyTree unless security is asked for, which it doesn't copy, otherwise (or when it
+    // fails) key by key. Either way the copy is compared with the original; a copy that
+    // fails or is cancelled is deleted again
+    pub fn copy_tree(
+        &self,
+        from: &Path,
+        to: &Path,
+        limits: CopyLimits,
+        force: bool,
+        ctx: &OpContext,
+    ) -> Result<TreeDigest, RegOpsError> {
+        let (from, to) = (RegPath::parse(from), RegPath::parse(to));
+        let (from_key, to_key) = (path_key(&from.to_path()), path_key(&to.to_path()));
+        if to.is_hive() || to_key == from_key || to_key.starts_with(&format!("{}\\", from_key)) {
+            return Err(RegOpsError::Copy {
+                path: to.to_path(),
+                error: io::Error::from_raw_os_error(ERROR_INVALID_PARAMETER as i32),
+            });
+        }
+        let source = self.open_exact(&from, KEY_READ | READ_CONTROL)?;
+        let (to_parent, new_name) = to
+            .split_value()
+            .ok_or_else(|| RegOpsError::KeyNotFound(to.to_path()))?;
+        let target_parent = self.open_exact(&to_parent, KEY_READ | KEY_WRITE)?;
+        let copy_failed = |error| RegOpsError::Copy {
+            path: to.to_path(),
+            error,
+        };
+        if target_parent.open_subkey(&new_name).is_ok() {
+            if !force {
+                return Err(copy_failed(io::Error::from_raw_os_error(
+                    ERROR_ALREADY_EXISTS as i32,
+                )));
+            }
+            target_parent
+                .delete_subkey_all(&new_name)
+                .map_err(copy_failed)?;
+        }
+
+        let original = digest(&source, &limits)
+            .map_err(|error| failed(&from, error))?
+            .map_err(|digest| RegOpsError::TooLarge {
+                path: from.to_path(),
+                keys: digest.keys,
+                bytes: digest.bytes,
+            })?;
+        ctx.check()
+            .map_err(|_| RegOpsError::Cancelled(to.to_path()))?;
+
+        let mut copy = create_like(&target_parent, &new_name, &source).map_err(copy_failed)?;
+        // security is only copied key by key
+        let mut copied = Err(io::ErrorKind::Unsupported.into());
+        if !limits.security {
+            let result = unsafe {
+                RegCopyTreeW(
+                    source.raw_handle() as usize as HKEY,
+                    std::ptr::null(),
+                    copy.raw_handle() as usize as HKEY,
+                )
+            } as u32;
+            copied = match result {
+                ERROR_SUCCESS => Ok(()),
+                error => Err(io::Error::from_raw_os_error(error as i32)),
+            };
+        }
+        if let Err(e) = &copied {
+            if !limits.security {
+                warn!(
+                    target: "regops",
+                    "copy_tree: RegCopyTree [{:?}] failed with {}, copying it key by key",
+                    from.to_path(),
+                    e
+                );
+                // whatever it got through before failing would be in the way
+                drop(copy);
+                let _ = target_parent.delete_subkey_all(&new_name);
+                copy = create_like(&target_parent, &new_name, &source).map_err(copy_failed)?;
+            }
+            copied = copy_tree(&source, &copy, &limits, ctx);
+        }
+        let verified = match copied {
+            Err(_) if ctx.check().is_err() => Err(RegOpsError::Cancelled(to.to_path())),
+            Err(error) => Err(copy_failed(error)),
+            Ok(()) => match digest(&copy, &limits) {
+                Ok(Ok(digest)) if digest == original => Ok(()),
+                Ok(_) => Err(RegOpsError::CopyMismatch(to.to_path())),
+                Err(error) => Err(copy_failed(error)),
+            },
+        };
+        drop(copy);
+        if let Err(error) = verified {
+            if let Err(e) = target_parent.delete_subkey_all(&new_name) {
+                warn!(target: "regops", "copy_tree: [{:?}] left behind: {}", to.to_path(), e);
+            }
+            return Err(error);
+        }
+
+        info!(
+            target: "regops",
+            "copy_tree: copied [{:?}] ({} keys, {} values, {} bytes) to [{:?}]",
+            from.to_path(),
+            original.keys,
+            original.values,
+            original.bytes,
+            to.to_path()
+        );
+        Ok(original)
+    }
+
+    // with exactly these rights, for the writes the fallbacks in open_key would only fail later
+    fn open_exact(&self, path: &RegPath, rights: u32) -> Result<RegKey, RegOpsError> {
+        let hive = path
+            .hive
+            .as_ref()
+            .and_then(|hive| self.keymap.get(hive))
+            .ok_or_else(|| RegOpsError::KeyNotFound(path.to_path()))?;
+        match hive.open_subkey_with_flags(path.subkey(), rights) {
+            Ok(key) => Ok(key),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                Err(RegOpsError::KeyNotFound(path.to_path()))
+            }
+            Err(error) => Err(RegOpsError::Open {
+                path: path.to_path(),
+                error,
+            }),
+        }
+    }
+}
+
+#[test]
+fn test_rename_key() {
+    use super::scratch::ScratchKey;
+    use winreg::{enums::*, RegValue};
+
+    let scratch = ScratchKey::populated();
+    let ops = RegOps::new();
+    let tree = scratch.key.create_subkey("Tree").unwrap().0;
+    // the types ScratchKey::populated() doesn't have
+    for (name, vtype, bytes) in [
+        ("None", REG_NONE, vec![1, 2, 3]),
+        ("BigEndian", REG_DWORD_BIG_ENDIAN, vec![0, 0, 0, 1]),
+        ("Resource", REG_RESOURCE_LIST, vec![0; 8]),
+        ("Descriptor", REG_FULL_RESOURCE_DESCRIPTOR, vec![0; 8]),
+        ("Requirements", REG_RESOURCE_REQUIREMENTS_LIST, vec![0; 8]),
+        (
+            "",
+            REG_SZ,
+            "default\0"
+                .encode_utf16()
+                .flat_map(u16::to_le_bytes)
+                .collect(),
+        ),
+    ] {
+        tree.set_raw_value(name, &RegValue { bytes, vtype })
+            .unwrap();
+    }
+    let nested = tree.create_subkey("A\\B\\C").unwrap().0;
+    nested.set_value("Deep", &7u32).unwrap();
+    drop((tree, nested));
+    let copy_of = |name: &str| {
+        let key = scratch.key.open_subkey(name).unwrap();
+        digest(&key, &CopyLimits::default()).unwrap().unwrap()
+    };
+    let tree = copy_of("Tree");
+    let child = copy_of("Child");
+
+    // to another parent, so RegRenameKey can't do it
+    scratch.key.create_subkey("Moved").unwrap();
+    ops.rename_key(
+        &scratch.path().join("Tree"),
+        &scratch.path().join("Moved\\Tree"),
+        CopyLimits::default(),
+    )
+    .unwrap();
+    assert!(scratch.key.open_subkey("Tree").is_err());
+    assert_eq!(copy_of("Moved\\Tree"), tree);
+    let moved = scratch.key.open_subkey("Moved\\Tree\\A\\B\\C").unwrap();
+    assert_eq!(moved.get_value::<u32, _>("Deep").unwrap(), 7);
+
+    // too big: nothing moves and nothing is left behind
+    let small = CopyLimits {
+        max_keys: 2,
+        ..Default::default()
+    };
+    let target = scratch.path().join("Moved\\Child");
+    assert!(matches!(
+        ops.rename_key(&scratch.path().join("Child"), &target, small),
+        Err(RegOpsError::TooLarge { .. })
+    ));
+    assert!(scratch.key.open_subkey("Moved\\Child").is_err());
+    assert_eq!(copy_of("Child"), child);
+
+    // in place, by RegRenameKey where there is one
+    ops.rename_key(
+        &scratch.path().join("Moved\\Tree"),
+        &scratch.path().join("Moved\\Renamed"),
+        CopyLimits::default(),
+    )
+    .unwrap();
+    assert_eq!(copy_of("Moved\\Renamed"), tree);
+    // never over a key that's there
+    assert!(ops
+        .rename_key(
+            &scratch.path().join("Empty"),
+            &scratch.path().join("Moved\\Renamed"),
+            CopyLimits::default(),
+        )
+        .is_err());
+    assert!(scratch.key.open_subkey("Empty").is_ok());
+}
+
+#[test]
+fn test_copy_tree() {
+    use super::scratch::ScratchKey;
+
+    let scratch = ScratchKey::populated();
+    let ops = RegOps::new();
+    let export = |name: &str| {
+        let key = scratch.key.open_subkey(name).unwrap();
+        digest(&key, &CopyLimits::default()).unwrap().unwrap()
+    };
+    scratch.key.create_subkey("Profiles\\Default").unwrap();
+    scratch
+        .key
+        .create_subkey("Profiles\\Default\\Child\\Grandchild")
+        .unwrap()
+        .0
+        .set_value("Leaf", &1u32)
+        .unwrap();
+    let original = export("Profiles\\Default");
+
+    // by RegCopyTree, and key by key for the security
+    let copied = ops
+        .copy_tree(
+            &scratch.path().join("Profiles\\Default"),
+            &scratch.path().join("Profiles\\Copy"),
+            CopyLimits::default(),
+            false,
+            &OpContext::none(),
+        )
+        .unwrap();
+    assert_eq!(copied, original);
+    assert_eq!(export("Profiles\\Copy"), original);
+    let secured = CopyLimits {
+        security: true,
+        ..Default::default()
+    };
+    ops.copy_tree(
+        &scratch.path().join("Profiles\\Default"),
+        &scratch.path().join("Secured"),
+        secured,
+        false,
+        &OpContext::none(),
+    )
+    .unwrap();
+    assert_eq!(export("Secured"), original);
+
+    // over a key that's there only with force, and never into itself
+    let whole = CopyLimits::default();
+    let onto = |force| {
+        ops.copy_tree(
+            &scratch.path().join("Child"),
+            &scratch.path().join("Profiles\\Copy"),
+            whole,
+            force,
+            &OpContext::none(),
+        )
+    };
+    assert!(matches!(onto(false), Err(RegOpsError::Copy { .. })));
+    assert_eq!(export("Profiles\\Copy"), original);
+    onto(true).unwrap();
+    assert_eq!(export("Profiles\\Copy"), export("Child"));
+    assert!(ops
+        .copy_tree(
+            &scratch.path().join("Profiles"),
+            &scratch.path().join("Profiles\\Default\\Again"),
+            whole,
+            false,
+            &OpContext::none(),
+        )
+        .is_err());
+
+    // cancelled: nothing left behind
+    let cancelled = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(true));
+    let metrics = crate::metrics::Metrics::default();
+    let ctx = OpContext::new(cancelled, None, &metrics);
+    assert!(matches!(
+        ops.copy_tree(
+            &scratch.path().join("Profiles\\Default"),
+            &scratch.path().join("Cancelled"),
+            whole,
+            false,
+            &ctx,
+        ),
+        Err(RegOpsError::Cancelled(_))
+    ));
+    assert!(scratch.key.open_subkey("Cancelled").is_err());
+}
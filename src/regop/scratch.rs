@@ -0,0 +1,138 @@
+use prjfs::guid::guid_to_bytes;
+use std::path::{Path, PathBuf};
+use winapi::{
+    shared::{guiddef::GUID, minwindef::HKEY},
+    um::{
+        combaseapi::CoCreateGuid,
+        winnt::{DACL_SECURITY_INFORMATION, WRITE_DAC},
+        winreg::RegSetKeySecurity,
+    },
+};
+use winreg::{
+    enums::{HKEY_CURRENT_USER, REG_BINARY, REG_EXPAND_SZ, REG_MULTI_SZ, REG_QWORD},
+    RegKey, RegValue,
+};
+
+use crate::control;
+use crate::pipe::SecurityDescriptor;
+
+const PARENT: &str = "Software\\regfs-test";
+
+fn utf16(text: &str) -> Vec<u8> {
+    text.encode_utf16()
+        .flat_map(|unit| unit.to_le_bytes())
+        .collect()
+}
+
+// HKEY_CURRENT_USER\Software\regfs-test\<guid>, so tests don't depend on what the machine
+// has installed; deleted on drop, failed assertions included
+pub struct ScratchKey {
+    name: String,
+    pub key: RegKey,
+}
+
+impl ScratchKey {
+    // an empty key
+    pub fn empty() -> ScratchKey {
+        ScratchKey::try_empty().expect("unable to create a scratch key")
+    }
+
+    // None where the registry can't be written, for what would rather skip than fail
+    pub fn try_empty() -> Option<ScratchKey> {
+        let mut guid = GUID::default();
+        if unsafe { CoCreateGuid(&mut guid) } != 0 {
+            return None;
+        }
+        let name = format!(
+            "{}\\{}",
+            PARENT,
+            control::guid_string(&guid_to_bytes(&guid))
+        );
+        let (key, _) = RegKey::predef(HKEY_CURRENT_USER)
+            .create_subkey(&name)
+            .ok()?;
+
+        Some(ScratchKey { name, key })
+    }
+
+    // one value of every common type and a couple of levels of subkeys:
+    //   Text, Expand, Number, Big, List, Blob (10000 bytes)
+    //   Child\Grandchild (with Leaf), Empty
+    pub fn populated() -> ScratchKey {
+        let scratch = ScratchKey::empty();
+        let key = &scratch.key;
+        key.set_value("Text", &"hello").unwrap();
+        key.set_raw_value(
+            "Expand",
+            &RegValue {
+                vtype: REG_EXPAND_SZ,
+                bytes: utf16("%SystemRoot%\0"),
+            },
+        )
+        .unwrap();
+        key.set_value("Number", &42u32).unwrap();
+        key.set_raw_value(
+            "Big",
+            &RegValue {
+                vtype: REG_QWORD,
+                bytes: u64::MAX.to_le_bytes().to_vec(),
+            },
+        )
+        .unwrap();
+        key.set_raw_value(
+            "List",
+            &RegValue {
+                vtype: REG_MULTI_SZ,
+                bytes: utf16("one\0two\0\0"),
+            },
+        )
+        .unwrap();
+        key.set_raw_value(
+            "Blob",
+            &RegValue {
+                vtype: REG_BINARY,
+                bytes: vec![7; 10000],
+            },
+        )
+        .unwrap();
+
+        let (child, _) = key.create_subkey("Child\\Grandchild").unwrap();
+        child.set_value("Leaf", &1u32).unwrap();
+        key.create_subkey("Empty").unwrap();
+
+        scratch
+    }
+
+    // under HKEY_CURRENT_USER
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // the way the mount spells it
+    pub fn path(&self) -> PathBuf {
+        Path::new("HKEY_CURRENT_USER").join(&self.name)
+    }
+}
+
+impl Drop for ScratchKey {
+    fn drop(&mut self) {
+        let hkcu = RegKey::predef(HKEY_CURRENT_USER);
+        if hkcu.delete_subkey_all(&self.name).is_err() {
+            // a test that restricted the key may have failed before giving the rights back;
+            // the owner can always change the DACL
+            if let Ok(key) = hkcu.open_subkey_with_flags(&self.name, WRITE_DAC) {
+                let security = SecurityDescriptor::from_sddl("D:P(A;;KA;;;WD)").unwrap();
+                unsafe {
+                    RegSetKeySecurity(
+                        key.raw_handle() as usize as HKEY,
+                        DACL_SECURITY_INFORMATION,
+                        security.0,
+                    )
+                };
+            }
+            let _ = hkcu.delete_subkey_all(&self.name);
+        }
+        // only goes once no other test is using it
+        let _ = hkcu.delete_subkey(PARENT);
+    }
+}
@@ -0,0 +1,127 @@
+use std::{io, path::Path};
+use winapi::um::winnt::{DELETE, KEY_QUERY_VALUE, KEY_SET_VALUE};
+use winreg::RegKey;
+
+use super::{paths, RegOps, RegOpsError};
+use crate::regop::paths::RegPath;
+
+impl RegOps {
+    fn hive_of(&self, path: &RegPath) -> Result<&RegKey, RegOpsError> {
+        path.hive
+            .as_ref()
+            .and_then(|hive| self.keymap.get(hive))
+            .ok_or_else(|| RegOpsError::KeyNotFound(path.to_path()))
+    }
+
+    // the key along with whatever is missing above it; one that's there is left as it is
+    pub fn create_key_all(&self, path: &Path) -> Result<(), RegOpsError> {
+        let path = RegPath::parse(path);
+        if path.keys.is_empty() {
+            return Ok(());
+        }
+        self.hive_of(&path)?
+            .create_subkey(path.subkey())
+            .map(drop)
+            .map_err(|error| RegOpsError::Create {
+                path: path.to_path(),
+                error,
+            })
+    }
+
+    // any type; the key has to exist. `(default)` is the unnamed value unless the key has
+    // one really called that, as when it's read
+    pub fn write_value(&self, path: &Path, data: &[u8], vtype: u32) -> Result<(), RegOpsError> {
+        let path = RegPath::parse(path);
+        let (key, name) = path
+            .split_value()
+            .ok_or_else(|| RegOpsError::KeyNotFound(path.to_path()))?;
+        let failed = |error| RegOpsError::Write {
+            path: path.to_path(),
+            error,
+        };
+        let key = match self
+            .hive_of(&key)?
+            .open_subkey_with_flags(key.subkey(), KEY_QUERY_VALUE | KEY_SET_VALUE)
+        {
+            Ok(key) => key,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                return Err(RegOpsError::KeyNotFound(key.to_path()))
+            }
+            Err(error) => return Err(failed(error)),
+        };
+        let name = match (
+            key.get_raw_value(&name).is_ok(),
+            paths::default_value_fallback(&name),
+        ) {
+            (false, Some(default)) => default,
+            _ => name,
+        };
+        super::rename::set_value(&key, &name, vtype, data).map_err(failed)
+    }
+
+    // the key with everything under it, or else the value; nothing there isn't an error
+    pub fn delete(&self, path: &Path) -> Result<(), RegOpsError> {
+        let path = RegPath::parse(path);
+        let (parent, name) = path
+            .split_value()
+            .ok_or_else(|| RegOpsError::KeyNotFound(path.to_path()))?;
+        let failed = |error| RegOpsError::Delete {
+            path: path.to_path(),
+            error,
+        };
+        let parent = match self
+            .hive_of(&parent)?
+            .open_subkey_with_flags(parent.subkey(), KEY_QUERY_VALUE | KEY_SET_VALUE | DELETE)
+        {
+            Ok(parent) => parent,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(failed(error)),
+        };
+
+        let result = match parent.open_subkey(&name) {
+            Ok(_) => parent.delete_subkey_all(&name),
+            Err(_) => parent.delete_value(
+                paths::default_value_fallback(&name)
+                    .filter(|_| parent.get_raw_value(&name).is_err())
+                    .unwrap_or(name),
+            ),
+        };
+        match result {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => Err(failed(error)),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[test]
+fn test_write_and_delete() {
+    use super::scratch::ScratchKey;
+    use winapi::um::winnt::{REG_BINARY, REG_DWORD};
+
+    let scratch = ScratchKey::empty();
+    let ops = RegOps::new();
+    let deep = scratch.path().join("A\\B\\C");
+
+    assert!(matches!(
+        ops.write_value(&deep.join("Number"), &[1, 0, 0, 0], REG_DWORD),
+        Err(RegOpsError::KeyNotFound(_))
+    ));
+    ops.create_key_all(&deep).unwrap();
+    ops.create_key_all(&deep).unwrap();
+    ops.write_value(&deep.join("Number"), &[1, 0, 0, 0], REG_DWORD)
+        .unwrap();
+    ops.write_value(&deep.join("(default)"), &[9], REG_BINARY)
+        .unwrap();
+    let key = scratch.key.open_subkey("A\\B\\C").unwrap();
+    assert_eq!(key.get_value::<u32, _>("Number").unwrap(), 1);
+    assert_eq!(key.get_raw_value("").unwrap().bytes, vec![9]);
+
+    ops.delete(&deep.join("Number")).unwrap();
+    assert!(key.get_raw_value("Number").is_err());
+    ops.delete(&deep.join("Number")).unwrap();
+    drop(key);
+
+    ops.delete(&scratch.path().join("A")).unwrap();
+    assert!(scratch.key.open_subkey("A").is_err());
+    ops.delete(&scratch.path().join("Missing\\Deeper")).unwrap();
+}
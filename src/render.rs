@@ -0,0 +1,310 @@
+use serde_json::{json, Map, Value};
+use std::{ffi::OsString, path::Path};
+use winapi::um::winnt::{
+    REG_BINARY, REG_DWORD, REG_DWORD_BIG_ENDIAN, REG_EXPAND_SZ, REG_FULL_RESOURCE_DESCRIPTOR,
+    REG_LINK, REG_MULTI_SZ, REG_NONE, REG_QWORD, REG_RESOURCE_LIST, REG_RESOURCE_REQUIREMENTS_LIST,
+    REG_SZ,
+};
+
+use crate::regop::KeyInfo;
+use crate::virtualstore::MergedEntry;
+
+pub fn type_name(vtype: u32) -> &'static str {
+    match vtype {
+        REG_NONE => "none",
+        REG_SZ => "sz",
+        REG_EXPAND_SZ => "expand_sz",
+        REG_BINARY => "binary",
+        REG_DWORD => "dword",
+        REG_DWORD_BIG_ENDIAN => "dword_big_endian",
+        REG_LINK => "link",
+        REG_MULTI_SZ => "multi_sz",
+        REG_RESOURCE_LIST => "resource_list",
+        REG_FULL_RESOURCE_DESCRIPTOR => "full_resource_descriptor",
+        REG_RESOURCE_REQUIREMENTS_LIST => "resource_requirements_list",
+        REG_QWORD => "qword",
+        _ => "unknown",
+    }
+}
+
+pub fn utf16_units(bytes: &[u8]) -> Vec<u16> {
+    bytes
+        .chunks_exact(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+        .collect()
+}
+
+pub fn decode_sz(bytes: &[u8]) -> String {
+    let mut units = utf16_units(bytes);
+    while units.last() == Some(&0) {
+        units.pop();
+    }
+
+    String::from_utf16_lossy(&units)
+}
+
+pub fn decode_multi_sz(bytes: &[u8]) -> Vec<String> {
+    let mut strings: Vec<String> = utf16_units(bytes)
+        .split(|unit| *unit == 0)
+        .map(String::from_utf16_lossy)
+        .collect();
+
+    // the list is terminated by an empty string
+    while strings.last().map(String::is_empty).unwrap_or(false) {
+        strings.pop();
+    }
+
+    strings
+}
+
+pub fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+
+    out
+}
+
+pub fn value_json(vtype: u32, bytes: &[u8]) -> Value {
+    let data = match vtype {
+        REG_SZ | REG_EXPAND_SZ => json!(decode_sz(bytes)),
+        REG_MULTI_SZ => json!(decode_multi_sz(bytes)),
+        REG_DWORD if bytes.len() == 4 => {
+            json!(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        }
+        REG_DWORD_BIG_ENDIAN if bytes.len() == 4 => {
+            json!(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        }
+        REG_QWORD if bytes.len() == 8 => {
+            let mut qword = [0u8; 8];
+            qword.copy_from_slice(bytes);
+            json!(u64::from_le_bytes(qword))
+        }
+        // binary and anything malformed for its declared type
+        _ => json!(base64(bytes)),
+    };
+
+    json!({ "type": type_name(vtype), "data": data })
+}
+
+// serde_json's Map is ordered, so identical values always serialize identically;
+// placeholder sizes depend on that
+pub fn values_json(values: &[(OsString, u32, Vec<u8>)], max_size: usize) -> Vec<u8> {
+    let mut sorted: Vec<_> = values.iter().collect();
+    sorted.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut map = Map::new();
+    let mut used = 0;
+
+    for (name, vtype, bytes) in sorted {
+        let name = name.to_string_lossy().into_owned();
+        let entry = value_json(*vtype, bytes);
+        let size = name.len() + entry.to_string().len();
+
+        let entry = if used + size > max_size {
+            json!({ "type": type_name(*vtype), "size": bytes.len(), "truncated": true })
+        } else {
+            used += size;
+            entry
+        };
+
+        map.insert(name, entry);
+    }
+
+    let mut out = serde_json::to_vec_pretty(&Value::Object(map)).unwrap_or_default();
+    out.push(b'\n');
+    out
+}
+
+// what --merge-virtualstore took from the VirtualStore for a key
+pub fn meta_json(virtualstore: &Path, merged: &[MergedEntry]) -> Vec<u8> {
+    let mut subkeys = Map::new();
+    let mut values = Map::new();
+    for entry in merged {
+        let map = match entry.is_key {
+            true => &mut subkeys,
+            false => &mut values,
+        };
+        map.insert(
+            entry.name.to_string_lossy().into_owned(),
+            json!(entry.merge.as_str()),
+        );
+    }
+
+    let meta = json!({
+        "virtualstore": virtualstore.to_string_lossy(),
+        "subkeys": subkeys,
+        "values": values,
+    });
+    let mut out = serde_json::to_vec_pretty(&meta).unwrap_or_default();
+    out.push(b'\n');
+    out
+}
+
+pub fn hive_summary_json(hive: &str, info: &KeyInfo, readonly: bool) -> Vec<u8> {
+    let summary = json!({
+        "hive": hive,
+        "subkeys": info.subkeys,
+        "values": info.values,
+        "last_write_time": info.last_write_time,
+        // only the local predefined hives can be mounted for now
+        "remote": false,
+        "offline": false,
+        "policy": if readonly { "read-only" } else { "read-write" },
+        "volatile": info.volatile,
+    });
+
+    let mut out = serde_json::to_vec_pretty(&summary).unwrap_or_default();
+    out.push(b'\n');
+    out
+}
+
+#[cfg(test)]
+fn utf16(text: &str) -> Vec<u8> {
+    text.encode_utf16()
+        .flat_map(|unit| unit.to_le_bytes())
+        .collect()
+}
+
+#[test]
+fn test_base64() {
+    assert_eq!(base64(b""), "");
+    assert_eq!(base64(b"f"), "Zg==");
+    assert_eq!(base64(b"fo"), "Zm8=");
+    assert_eq!(base64(b"foo"), "Zm9v");
+    assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+}
+
+#[test]
+fn test_decode_multi_sz() {
+    assert_eq!(decode_multi_sz(&utf16("a\0bc\0\0")), vec!["a", "bc"]);
+    assert_eq!(decode_multi_sz(&utf16("\0")), Vec::<String>::new());
+    assert_eq!(decode_multi_sz(&[]), Vec::<String>::new());
+}
+
+#[test]
+fn test_values_json_golden() {
+    let values = vec![
+        ("Path".into(), REG_EXPAND_SZ, utf16("%SystemRoot%\0")),
+        ("Timeout".into(), REG_DWORD, vec![30, 0, 0, 0]),
+        ("".into(), REG_SZ, utf16("default\0")),
+        ("Blob".into(), REG_BINARY, vec![0xde, 0xad, 0xbe, 0xef]),
+        ("Big".into(), REG_QWORD, vec![1, 0, 0, 0, 0, 0, 0, 1]),
+        ("List".into(), REG_MULTI_SZ, utf16("one\0two\0\0")),
+        ("Odd".into(), REG_DWORD, vec![1, 2]),
+    ];
+
+    let expected = r#"{
+  "": {
+    "data": "default",
+    "type": "sz"
+  },
+  "Big": {
+    "data": 72057594037927937,
+    "type": "qword"
+  },
+  "Blob": {
+    "data": "3q2+7w==",
+    "type": "binary"
+  },
+  "List": {
+    "data": [
+      "one",
+      "two"
+    ],
+    "type": "multi_sz"
+  },
+  "Odd": {
+    "data": "AQI=",
+    "type": "dword"
+  },
+  "Path": {
+    "data": "%SystemRoot%",
+    "type": "expand_sz"
+  },
+  "Timeout": {
+    "data": 30,
+    "type": "dword"
+  }
+}
+"#;
+
+    assert_eq!(
+        String::from_utf8(values_json(&values, 1 << 20)).unwrap(),
+        expected
+    );
+}
+
+#[test]
+fn test_values_json_size_cap() {
+    let values = vec![
+        ("A".into(), REG_BINARY, vec![0; 64]),
+        ("B".into(), REG_DWORD, vec![1, 0, 0, 0]),
+    ];
+
+    let expected = r#"{
+  "A": {
+    "size": 64,
+    "truncated": true,
+    "type": "binary"
+  },
+  "B": {
+    "data": 1,
+    "type": "dword"
+  }
+}
+"#;
+
+    assert_eq!(
+        String::from_utf8(values_json(&values, 48)).unwrap(),
+        expected
+    );
+}
+
+#[test]
+fn test_hive_summary_json() {
+    let info = KeyInfo {
+        subkeys: 7,
+        values: 0,
+        last_write_time: 133_000_000_000_000_000,
+        ..Default::default()
+    };
+
+    let expected = r#"{
+  "hive": "HKEY_LOCAL_MACHINE",
+  "last_write_time": 133000000000000000,
+  "offline": false,
+  "policy": "read-only",
+  "remote": false,
+  "subkeys": 7,
+  "values": 0,
+  "volatile": false
+}
+"#;
+
+    assert_eq!(
+        String::from_utf8(hive_summary_json("HKEY_LOCAL_MACHINE", &info, true)).unwrap(),
+        expected
+    );
+    assert!(
+        String::from_utf8(hive_summary_json("HKEY_USERS", &info, false))
+            .unwrap()
+            .contains(r#""policy": "read-write""#)
+    );
+}
@@ -0,0 +1,286 @@
+use log::{info, warn};
+use prjfs::conv::WStrExt;
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    fmt, fs,
+    path::{Path, PathBuf},
+    thread::{self, JoinHandle},
+};
+use winapi::shared::winerror::S_OK;
+
+use crate::hash;
+use crate::ondisk;
+use crate::regfs::RegFs;
+use crate::regop::RootHive;
+
+const PROGRESS_INTERVAL: usize = 500;
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResyncSummary {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+    pub conflicted: usize,
+}
+
+impl fmt::Display for ResyncSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "added {}, updated {}, removed {}, conflicted {}",
+            self.added, self.updated, self.removed, self.conflicted
+        )
+    }
+}
+
+struct Resync<'a> {
+    regfs: &'a RegFs,
+    summary: ResyncSummary,
+    visited: usize,
+    // false to only look at the one directory
+    recurse: bool,
+}
+
+impl RegFs {
+    pub fn spawn_resync(&self, path: PathBuf) -> JoinHandle<ResyncSummary> {
+        let regfs = self.clone();
+
+        thread::spawn(move || {
+            info!(target: "resync", "----> resync: [{:?}]", path);
+            let summary = regfs.resync(&path);
+            info!(target: "resync", "<---- resync: [{:?}] {}", path, summary);
+            summary
+        })
+    }
+
+    pub fn resync(&self, path: &Path) -> ResyncSummary {
+        let mut resync = Resync {
+            regfs: self,
+            summary: Default::default(),
+            visited: 0,
+            recurse: true,
+        };
+
+        // the hives are walked where they're listed
+        resync.walk(&self.mounted(path));
+        resync.summary
+    }
+
+    // a value's placeholder that says another size than the value has now; not on the
+    // callback's thread, the file system is still busy with the read that found out
+    pub fn spawn_update(&self, path: PathBuf) -> JoinHandle<ResyncSummary> {
+        let regfs = self.clone();
+
+        thread::spawn(move || {
+            let mut resync = Resync {
+                regfs: &regfs,
+                summary: Default::default(),
+                visited: 0,
+                recurse: false,
+            };
+            match regfs.placeholder_info(&path) {
+                Some(info) if info.FileBasicInfo.IsDirectory == 0 => resync.update(&path, info),
+                Some(_) => {}
+                None => resync.remove(&path),
+            }
+            info!(target: "resync", "[{:?}] changed size: {}", path, resync.summary);
+            resync.summary
+        })
+    }
+
+    // a hive loaded or unloaded under HKEY_USERS: the placeholders of unloaded ones are
+    // removed, and those loaded since the directory was last listed are added. What's
+    // under the hives that stayed is left alone
+    pub fn spawn_resync_users(&self) -> JoinHandle<ResyncSummary> {
+        let regfs = self.clone();
+
+        thread::spawn(move || {
+            let path = &regfs.mounted(Path::new(RootHive::Users.name()));
+            let mut resync = Resync {
+                regfs: &regfs,
+                summary: Default::default(),
+                visited: 0,
+                recurse: false,
+            };
+            resync.walk(path);
+            info!(target: "resync", "hives under [{:?}] changed: {}", path, resync.summary);
+            resync.summary
+        })
+    }
+}
+
+impl<'a> Resync<'a> {
+    fn walk(&mut self, relative: &Path) {
+        let local = self.regfs.local_path(relative);
+        let dir = match fs::read_dir(&local) {
+            Ok(dir) => dir,
+            Err(e) => {
+                // never expanded on disk, so there is nothing stale to fix
+                info!(target: "resync", "skipping [{:?}]: {}", relative, e);
+                return;
+            }
+        };
+
+        let mut local_names = HashSet::new();
+        let mut has_hydrated = false;
+
+        for entry in dir.flatten() {
+            let name = entry.file_name();
+            let child = relative.join(&name);
+            local_names.insert(name.to_string_lossy().to_lowercase());
+
+            if self.regfs.synthetic(&child).is_some() {
+                continue;
+            }
+
+            self.visited += 1;
+            if self.visited % PROGRESS_INTERVAL == 0 {
+                info!(
+                    target: "resync",
+                    "resync: {} entries visited, {}",
+                    self.visited, self.summary
+                );
+            }
+
+            if ondisk::state(&local.join(&name)) & ondisk::HYDRATED_STATES != 0 {
+                has_hydrated = true;
+            }
+
+            match self.regfs.placeholder_info(&child) {
+                None => self.remove(&child),
+                Some(info) if info.FileBasicInfo.IsDirectory != 0 => {
+                    if self.recurse {
+                        self.walk(&child)
+                    }
+                }
+                Some(info) => self.update(&child, info),
+            }
+        }
+
+        if has_hydrated {
+            self.add_missing(relative, &local_names);
+        }
+    }
+
+    fn remove(&mut self, path: &Path) {
+        let mut failure = 0;
+        let result = unsafe {
+            self.regfs.projfs().delete_file(
+                self.regfs.context(),
+                path.as_os_str().to_os_string().to_wstr().as_ptr(),
+                prjfs::sys::PRJ_UPDATE_ALLOW_DIRTY_METADATA
+                    | prjfs::sys::PRJ_UPDATE_ALLOW_TOMBSTONE,
+                &mut failure,
+            )
+        };
+
+        if result == S_OK {
+            self.regfs.forget_content_id(path);
+            self.summary.removed += 1;
+        } else {
+            self.conflict(path, "delete", result, failure);
+        }
+    }
+
+    fn update(&mut self, path: &Path, info: prjfs::sys::PRJ_PLACEHOLDER_INFO) {
+        let content_id = hash::content_id_of(&info);
+        if self.regfs.recorded_content_id(path) == Some(content_id) {
+            return;
+        }
+
+        let mut failure = 0;
+        let result = unsafe {
+            self.regfs.projfs().update_file_if_needed(
+                self.regfs.context(),
+                path.as_os_str().to_os_string().to_wstr().as_ptr(),
+                &info,
+                prjfs::sys::PRJ_UPDATE_ALLOW_DIRTY_METADATA,
+                &mut failure,
+            )
+        };
+
+        if result == S_OK {
+            self.regfs.record_content_id(path, content_id);
+            self.regfs
+                .record_size(path, info.FileBasicInfo.FileSize as u64);
+            self.summary.updated += 1;
+        } else {
+            self.conflict(path, "update", result, failure);
+        }
+    }
+
+    fn add_missing(&mut self, relative: &Path, local_names: &HashSet<String>) {
+        let entries = match self.regfs.regops().enumerate_key(relative.into()) {
+            Some(entries) => entries,
+            None => return,
+        };
+
+        let names: Vec<OsString> = entries
+            .subkeys
+            .into_iter()
+            .chain(entries.values)
+            .map(|entry| entry.name)
+            .filter(|name| !local_names.contains(&name.to_string_lossy().to_lowercase()))
+            .collect();
+
+        for name in names {
+            let child = relative.join(&name);
+            let info = match self.regfs.placeholder_info(&child) {
+                Some(info) => info,
+                None => continue,
+            };
+            let is_value = info.FileBasicInfo.IsDirectory == 0;
+            let content_id = hash::content_id_of(&info);
+
+            let result = self
+                .regfs
+                .write_placeholder_info(child.as_os_str().to_os_string().to_wstr().as_ptr(), info);
+
+            if result == S_OK {
+                if is_value {
+                    self.regfs.record_content_id(&child, content_id);
+                }
+                self.summary.added += 1;
+            } else {
+                warn!(
+                    target: "resync",
+                    "unable to create placeholder for [{:?}]: {:08x}",
+                    child, result
+                );
+            }
+        }
+    }
+
+    fn conflict(&mut self, path: &Path, action: &str, result: i32, failure: u32) {
+        if failure != 0 {
+            info!(
+                target: "resync",
+                "{} of [{:?}] skipped, local changes (0x{:x})",
+                action, path, failure
+            );
+            self.summary.conflicted += 1;
+        } else {
+            warn!(
+                target: "resync",
+                "{} of [{:?}] failed: {:08x}",
+                action, path, result
+            );
+        }
+    }
+}
+
+#[test]
+fn test_summary_display() {
+    let summary = ResyncSummary {
+        added: 1,
+        updated: 2,
+        removed: 3,
+        conflicted: 4,
+    };
+
+    assert_eq!(
+        summary.to_string(),
+        "added 1, updated 2, removed 3, conflicted 4"
+    );
+}
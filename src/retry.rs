@@ -0,0 +1,166 @@
+use log::debug;
+use std::{
+    io, thread,
+    time::{Duration, Instant},
+};
+use winapi::shared::winerror::{
+    ERROR_MORE_DATA, ERROR_NOT_ENOUGH_MEMORY, ERROR_NO_SYSTEM_RESOURCES, ERROR_OUTOFMEMORY,
+    RPC_S_CALL_FAILED, RPC_S_SERVER_UNAVAILABLE,
+};
+
+use crate::metrics::Metrics;
+use crate::opcontext::OpContext;
+
+// the system ran short on memory, a value changed size between sizing the buffer and
+// reading it, or the RPC to a remote registry dropped; anything else fails the same way twice
+const TRANSIENT_ERRORS: [u32; 6] = [
+    ERROR_NOT_ENOUGH_MEMORY,
+    ERROR_OUTOFMEMORY,
+    ERROR_NO_SYSTEM_RESOURCES,
+    ERROR_MORE_DATA,
+    RPC_S_SERVER_UNAVAILABLE,
+    RPC_S_CALL_FAILED,
+];
+
+pub fn is_transient(error: &io::Error) -> bool {
+    error
+        .raw_os_error()
+        .map(|code| TRANSIENT_ERRORS.contains(&(code as u32)))
+        .unwrap_or(false)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    // including the first one
+    pub attempts: u32,
+    // doubled after every failure
+    pub backoff: Duration,
+    // no retry starts once this much time has gone by
+    pub budget: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 3,
+            backoff: Duration::from_millis(5),
+            budget: Duration::from_millis(50),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // only for reads, `op` may run more than once
+    pub fn run<T, F>(&self, ctx: &OpContext, what: &str, mut op: F) -> io::Result<T>
+    where
+        F: FnMut() -> io::Result<T>,
+    {
+        let start = Instant::now();
+        let mut backoff = self.backoff;
+        let mut attempt = 1;
+
+        loop {
+            let error = match op() {
+                Ok(value) => return Ok(value),
+                Err(error) => error,
+            };
+
+            let retry = attempt < self.attempts
+                && is_transient(&error)
+                && start.elapsed() + backoff <= self.budget
+                && ctx.can_wait(backoff);
+            if !retry {
+                return Err(error);
+            }
+
+            debug!(
+                "{}: attempt {} failed with {}, retrying in {:?}",
+                what, attempt, error, backoff
+            );
+            if let Some(metrics) = ctx.metrics() {
+                Metrics::add(&metrics.registry_retries, 1);
+            }
+            thread::sleep(backoff);
+            attempt += 1;
+            backoff *= 2;
+        }
+    }
+}
+
+#[test]
+fn test_retry_policy() {
+    use std::{
+        cell::Cell,
+        sync::{atomic::AtomicBool, Arc},
+    };
+    use winapi::shared::winerror::{ERROR_ACCESS_DENIED, ERROR_FILE_NOT_FOUND};
+
+    // fails with `code` the first `failures` times it's called
+    let failing = |code: u32, failures: u32| {
+        let calls = Cell::new(0);
+        move || {
+            calls.set(calls.get() + 1);
+            match calls.get() <= failures {
+                true => Err(io::Error::from_raw_os_error(code as i32)),
+                false => Ok(calls.get()),
+            }
+        }
+    };
+    let code = |result: io::Result<u32>| result.unwrap_err().raw_os_error().unwrap() as u32;
+    let policy = RetryPolicy {
+        backoff: Duration::from_millis(1),
+        ..Default::default()
+    };
+    let metrics = Metrics::default();
+    let ctx = OpContext::new(Arc::new(AtomicBool::new(false)), None, &metrics);
+
+    // (error, failures) -> what the caller sees
+    assert_eq!(policy.run(&ctx, "t", failing(0, 0)).unwrap(), 1);
+    assert_eq!(
+        policy
+            .run(&ctx, "t", failing(ERROR_NOT_ENOUGH_MEMORY, 2))
+            .unwrap(),
+        3
+    );
+    assert_eq!(
+        policy
+            .run(&ctx, "t", failing(RPC_S_SERVER_UNAVAILABLE, 1))
+            .unwrap(),
+        2
+    );
+    assert_eq!(
+        code(policy.run(&ctx, "t", failing(ERROR_MORE_DATA, 3))),
+        ERROR_MORE_DATA
+    );
+    assert_eq!(metrics.snapshot().registry_retries, 5);
+
+    // not transient, so only tried once
+    for error in [ERROR_FILE_NOT_FOUND, ERROR_ACCESS_DENIED] {
+        assert_eq!(code(policy.run(&ctx, "t", failing(error, 1))), error);
+    }
+    assert_eq!(metrics.snapshot().registry_retries, 5);
+
+    // a budget smaller than the first backoff never retries
+    let hurried = RetryPolicy {
+        budget: Duration::from_micros(500),
+        ..policy
+    };
+    assert!(hurried
+        .run(&ctx, "t", failing(ERROR_OUTOFMEMORY, 1))
+        .is_err());
+
+    // neither does a callback that's out of time or cancelled
+    let expiring = OpContext::new(
+        Arc::new(AtomicBool::new(false)),
+        Some(Instant::now()),
+        &metrics,
+    );
+    assert!(policy
+        .run(&expiring, "t", failing(ERROR_OUTOFMEMORY, 1))
+        .is_err());
+    let cancelled = OpContext::new(Arc::new(AtomicBool::new(true)), None, &metrics);
+    assert!(policy
+        .run(&cancelled, "t", failing(ERROR_OUTOFMEMORY, 1))
+        .is_err());
+    assert_eq!(metrics.snapshot().registry_retries, 5);
+}
@@ -0,0 +1,34 @@
+use std::{
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+use crate::ondisk;
+
+// %TEMP%\regfs-<name>-<pid>, for tests that need a root or files on disk; emptied when it's
+// made, and removed on drop, failed assertions included, however deep it goes
+pub struct ScratchDir(PathBuf);
+
+impl ScratchDir {
+    pub fn new(name: &str) -> ScratchDir {
+        let path = std::env::temp_dir().join(format!("regfs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(ondisk::extended(&path));
+        fs::create_dir_all(&path).expect("unable to create a scratch directory");
+        ScratchDir(path)
+    }
+}
+
+impl Deref for ScratchDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(ondisk::extended(&self.0));
+    }
+}
@@ -0,0 +1,66 @@
+use std::{
+    ffi::{OsStr, OsString},
+    os::windows::ffi::OsStrExt,
+};
+
+use crate::prj_compat::PrjApi;
+
+// a directory enumeration's search expression, classified once instead of being
+// matched through PrjFileNameMatch for every single entry
+pub enum Search<'a> {
+    // "*" or empty, which is almost every listing
+    All,
+    // no wildcards, lowercased
+    Literal(String),
+    Wildcard(OsString, &'a dyn PrjApi),
+}
+
+impl<'a> Search<'a> {
+    pub fn new(api: &'a dyn PrjApi, expression: &OsStr) -> Search<'a> {
+        if expression.is_empty() || expression == "*" {
+            return Search::All;
+        }
+
+        if api.contains_wildcards(expression) {
+            Search::Wildcard(expression.to_os_string(), api)
+        } else {
+            Search::Literal(fold(expression).collect())
+        }
+    }
+
+    pub fn matches(&self, name: &OsStr) -> bool {
+        match self {
+            Search::All => true,
+            // compared as it's folded, a listing shouldn't allocate for every entry
+            Search::Literal(literal) => fold(name).eq(literal.chars()),
+            Search::Wildcard(expression, api) => api.file_name_match(name, expression),
+        }
+    }
+}
+
+fn fold(text: &OsStr) -> impl Iterator<Item = char> + '_ {
+    char::decode_utf16(text.encode_wide())
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .flat_map(char::to_lowercase)
+}
+
+#[test]
+fn test_search() {
+    let api = crate::prj_compat::ProjFsApi::detect();
+    let api: &dyn PrjApi = &api;
+    assert!(matches!(Search::new(api, "".as_ref()), Search::All));
+    assert!(matches!(Search::new(api, "*".as_ref()), Search::All));
+    assert!(matches!(
+        Search::new(api, "*.json".as_ref()),
+        Search::Wildcard(..)
+    ));
+
+    let literal = Search::new(api, "Control Panel".as_ref());
+    assert!(matches!(literal, Search::Literal(_)));
+    assert!(literal.matches("control panel".as_ref()));
+    assert!(!literal.matches("Control".as_ref()));
+
+    let wildcard = Search::new(api, "Con*".as_ref());
+    assert!(wildcard.matches("CONSOLE".as_ref()));
+    assert!(!wildcard.matches("Keyboard".as_ref()));
+}
@@ -0,0 +1,275 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::backend::RegistryBackend;
+use crate::opcontext::{Cancelled, OpContext};
+use crate::prj_compat::PrjApi;
+use crate::regop::paths;
+use crate::search::Search;
+
+// what a file name can't have, besides the separator between a hit's parts
+const ESCAPED: &str = "%~/\\:*?\"<>|";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SearchLimits {
+    // how many levels down it lists, the hives being the first
+    pub max_depth: usize,
+    // hits past this many end the search
+    pub max_results: usize,
+}
+
+impl Default for SearchLimits {
+    fn default() -> Self {
+        SearchLimits {
+            max_depth: 8,
+            max_results: 500,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchHit {
+    pub path: PathBuf,
+    pub is_key: bool,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SearchResults {
+    pub hits: Vec<SearchHit>,
+    // it stopped at max_results
+    pub truncated: bool,
+}
+
+// what a `_search\<pattern>` directory finds: with wildcards, the names they match;
+// without, every name that contains it, since wildcards can't be typed in a path
+pub struct Pattern<'a> {
+    search: Search<'a>,
+    contains: Option<String>,
+}
+
+impl<'a> Pattern<'a> {
+    pub fn new(api: &'a dyn PrjApi, pattern: &OsStr) -> Self {
+        match Search::new(api, pattern) {
+            Search::Literal(literal) => Pattern {
+                search: Search::All,
+                contains: Some(literal),
+            },
+            search => Pattern {
+                search,
+                contains: None,
+            },
+        }
+    }
+
+    pub fn matches(&self, name: &OsStr) -> bool {
+        match &self.contains {
+            Some(part) => name
+                .to_string_lossy()
+                .to_lowercase()
+                .contains(part.as_str()),
+            None => self.search.matches(name),
+        }
+    }
+}
+
+// breadth first from the root, so a search cut short keeps the shallowest hits; links
+// aren't followed, they can lead back up
+pub fn find(
+    backend: &dyn RegistryBackend,
+    pattern: &Pattern,
+    limits: SearchLimits,
+    ctx: &OpContext,
+) -> Result<SearchResults, Cancelled> {
+    let mut results = SearchResults::default();
+    let mut queue = VecDeque::from([(PathBuf::new(), 0)]);
+
+    while let Some((key, depth)) = queue.pop_front() {
+        ctx.check()?;
+        let entries = match backend.enumerate_key_ctx(key.clone().into(), ctx)? {
+            Some(entries) => entries,
+            None => continue,
+        };
+        let subkeys = entries.subkeys.iter().map(|subkey| (&subkey.name, true));
+        let values = entries.values.iter().map(|value| (&value.name, false));
+        for (name, is_key) in subkeys.chain(values) {
+            let path = key.join(name);
+            if pattern.matches(name) {
+                if results.hits.len() >= limits.max_results {
+                    results.truncated = true;
+                    return Ok(results);
+                }
+                results.hits.push(SearchHit {
+                    path: path.clone(),
+                    is_key,
+                });
+            }
+            if is_key && depth < limits.max_depth && backend.link_target(&path).is_none() {
+                queue.push_back((path, depth + 1));
+            }
+        }
+    }
+    Ok(results)
+}
+
+// a hit's file name: its path with `~` between the parts, and %XX for `%`, `~` and what a
+// file name can't have
+pub fn hit_name(path: &Path) -> OsString {
+    let parts: Vec<String> = paths::components(path)
+        .iter()
+        .map(|part| {
+            let mut escaped = String::new();
+            for c in part.to_string_lossy().chars() {
+                match ESCAPED.contains(c) || c < ' ' {
+                    true => escaped.push_str(&format!("%{:02X}", c as u32)),
+                    false => escaped.push(c),
+                }
+            }
+            escaped
+        })
+        .collect();
+    parts.join("~").into()
+}
+
+// and back
+pub fn hit_path(name: &OsStr) -> Option<PathBuf> {
+    let name = name.to_str()?;
+    let mut parts = Vec::new();
+    for part in name.split('~') {
+        let mut unescaped = String::new();
+        let mut chars = part.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                unescaped.push(c);
+                continue;
+            }
+            let hex: String = chars.by_ref().take(2).collect();
+            let code = u32::from_str_radix(&hex, 16)
+                .ok()
+                .filter(|_| hex.len() == 2)?;
+            unescaped.push(char::from_u32(code)?);
+        }
+        if unescaped.is_empty() {
+            return None;
+        }
+        parts.push(OsString::from(unescaped));
+    }
+    Some(paths::join_parts(parts.iter().map(OsString::as_os_str)))
+}
+
+// results by pattern directory, kept while an enumeration of it is open so its restart
+// scans (and other listings of it meanwhile) don't search again
+#[derive(Default)]
+pub struct SearchSessions(Mutex<HashMap<String, Arc<SearchResults>>>);
+
+impl SearchSessions {
+    pub fn get(&self, key: &str) -> Option<Arc<SearchResults>> {
+        self.0.lock().ok()?.get(key).cloned()
+    }
+
+    pub fn insert(&self, key: String, results: SearchResults) -> Arc<SearchResults> {
+        let results = Arc::new(results);
+        if let Ok(mut sessions) = self.0.lock() {
+            sessions.insert(key, results.clone());
+        }
+        results
+    }
+
+    pub fn remove(&self, key: &str) {
+        if let Ok(mut sessions) = self.0.lock() {
+            sessions.remove(key);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.lock().map_or(0, |sessions| sessions.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[test]
+fn test_hit_names() {
+    let path = Path::new("HKEY_CURRENT_USER\\Software\\100%~ready\\a:b");
+    let name = hit_name(path);
+    assert_eq!(name, "HKEY_CURRENT_USER~Software~100%25%7Eready~a%3Ab");
+    assert_eq!(hit_path(&name), Some(path.to_path_buf()));
+    assert_eq!(hit_path("HKEY_USERS~~x".as_ref()), None);
+    assert_eq!(hit_path("HKEY_USERS~100%2".as_ref()), None);
+}
+
+#[test]
+fn test_find() {
+    use crate::memory::MemoryBackend;
+
+    let memory = MemoryBackend::new();
+    memory.set_value(
+        "HKEY_CURRENT_USER\\Software\\Adobe\\Reader",
+        "AdobePath",
+        1,
+        vec![0; 2],
+    );
+    memory.add_key("HKEY_CURRENT_USER\\Software\\Classes\\Deep\\Deeper\\Adobe.Doc");
+    memory.add_link(
+        "HKEY_CURRENT_USER\\Software\\Loop",
+        "HKEY_CURRENT_USER\\Software",
+    );
+    let api = crate::prj_compat::ProjFsApi::detect();
+    let pattern = Pattern::new(&api, "adobe".as_ref());
+    let paths = |results: &SearchResults| -> Vec<String> {
+        results
+            .hits
+            .iter()
+            .map(|hit| hit.path.to_string_lossy().into_owned())
+            .collect()
+    };
+
+    // shallowest first, each once even with the link back up
+    let all = find(
+        &memory,
+        &pattern,
+        SearchLimits::default(),
+        &OpContext::none(),
+    )
+    .unwrap();
+    assert_eq!(
+        paths(&all),
+        [
+            "HKEY_CURRENT_USER\\Software\\Adobe",
+            "HKEY_CURRENT_USER\\Software\\Adobe\\Reader\\AdobePath",
+            "HKEY_CURRENT_USER\\Software\\Classes\\Deep\\Deeper\\Adobe.Doc",
+        ]
+    );
+    assert!(all.hits[0].is_key && !all.hits[1].is_key);
+    assert!(!all.truncated);
+
+    let shallow = SearchLimits {
+        max_depth: 3,
+        ..Default::default()
+    };
+    let found = find(&memory, &pattern, shallow, &OpContext::none()).unwrap();
+    assert_eq!(paths(&found), ["HKEY_CURRENT_USER\\Software\\Adobe"]);
+
+    let capped = SearchLimits {
+        max_results: 1,
+        ..Default::default()
+    };
+    let found = find(&memory, &pattern, capped, &OpContext::none()).unwrap();
+    assert_eq!(found.hits.len(), 1);
+    assert!(found.truncated);
+
+    let wildcard = Pattern::new(&api, "Adobe.D*".as_ref());
+    let found = find(
+        &memory,
+        &wildcard,
+        SearchLimits::default(),
+        &OpContext::none(),
+    )
+    .unwrap();
+    assert_eq!(found.hits.len(), 1);
+}
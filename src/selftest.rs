@@ -0,0 +1,260 @@
+use log::{error, info, warn};
+use std::{
+    ffi::OsString,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use crate::backend::{PathKind, RegistryBackend};
+use crate::doctor::Verdict;
+use crate::opcontext::{Cancelled, OpContext};
+use crate::regfs::RegFs;
+use crate::regop::RegEntires;
+
+// what probing one hive found, its worst step
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HiveProbe {
+    pub hive: OsString,
+    pub verdict: Verdict,
+    pub detail: String,
+}
+
+impl fmt::Display for HiveProbe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{}] {}: {}",
+            self.verdict,
+            self.hive.to_string_lossy(),
+            self.detail
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SelfTest {
+    pub hives: Vec<HiveProbe>,
+}
+
+impl SelfTest {
+    pub fn verdict(&self) -> Verdict {
+        self.hives
+            .iter()
+            .map(|probe| probe.verdict)
+            .max()
+            .unwrap_or(Verdict::Pass)
+    }
+
+    pub fn log(&self) {
+        for probe in &self.hives {
+            match probe.verdict {
+                Verdict::Pass => info!(target: "selftest", "{}", probe),
+                Verdict::Warn => warn!(target: "selftest", "{}", probe),
+                Verdict::Fail => error!(target: "selftest", "{}", probe),
+            }
+        }
+        match self.verdict() {
+            Verdict::Pass => info!(target: "selftest", "{}", self),
+            _ => warn!(target: "selftest", "{}", self),
+        }
+    }
+}
+
+impl fmt::Display for SelfTest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = |verdict| {
+            self.hives
+                .iter()
+                .filter(|probe| probe.verdict == verdict)
+                .count()
+        };
+        match (count(Verdict::Fail), count(Verdict::Warn)) {
+            (0, 0) => write!(
+                f,
+                "the mount is fully functional, {} hives",
+                self.hives.len()
+            ),
+            (failed, warned) => write!(
+                f,
+                "{} of {} hives failed, {} with warnings",
+                failed,
+                self.hives.len(),
+                warned
+            ),
+        }
+    }
+}
+
+// the smallest value a listing has, a read of it is cheap
+fn smallest_value(key: &Path, entries: &RegEntires) -> Option<PathBuf> {
+    entries
+        .values
+        .iter()
+        .min_by_key(|value| value.size)
+        .map(|value| key.join(&value.name))
+}
+
+// a shallow listing of `hive`, what the first of its subkeys is, and a read of one small value
+// (from the hive, or else that subkey); a hive that can't be listed fails, anything else that
+// goes wrong is a warning
+pub fn probe_hive(
+    backend: &dyn RegistryBackend,
+    hive: &Path,
+    ctx: &OpContext,
+) -> Result<HiveProbe, Cancelled> {
+    let probe = |verdict, detail: String| HiveProbe {
+        hive: hive.as_os_str().to_owned(),
+        verdict,
+        detail,
+    };
+    let entries = match backend.enumerate_key_ctx(hive.into(), ctx)? {
+        Some(entries) => entries,
+        None => return Ok(probe(Verdict::Fail, "can't be listed".into())),
+    };
+
+    let mut problems = Vec::new();
+    if entries.partial {
+        let first = entries
+            .errors
+            .first()
+            .map_or_else(String::new, |e| format!(": {}", e.error));
+        problems.push(format!(
+            "{} entries failed to list{}",
+            entries.failed, first
+        ));
+    }
+    let mut value = smallest_value(hive, &entries);
+    if let Some(subkey) = entries.subkeys.first() {
+        let child = hive.join(&subkey.name);
+        match backend.classify(&child, ctx)? {
+            PathKind::Key(_) if value.is_none() => {
+                value = backend
+                    .enumerate_key_ctx(child.clone().into(), ctx)?
+                    .and_then(|entries| smallest_value(&child, &entries));
+            }
+            PathKind::Key(_) => {}
+            PathKind::Denied => problems.push(format!("[{:?}] can't be opened", child)),
+            kind => problems.push(format!("[{:?}] was listed, but is {:?} now", child, kind)),
+        }
+    }
+    if let Some(value) = value {
+        if backend.read_value_ctx(&value, ctx)?.is_none() {
+            problems.push(format!("[{:?}] was listed, but can't be read", value));
+        }
+    }
+
+    let detail = format!(
+        "{} subkeys, {} values",
+        entries.subkeys.len(),
+        entries.values.len()
+    );
+    Ok(match problems.is_empty() {
+        true => probe(Verdict::Pass, detail),
+        false => probe(
+            Verdict::Warn,
+            format!("{}; {}", detail, problems.join("; ")),
+        ),
+    })
+}
+
+impl RegFs {
+    // every hive the mount shows, probed on the registry pool so one that hangs fails after
+    // --registry-timeout instead of holding up the rest
+    pub fn selftest(&self) -> SelfTest {
+        let timed_out = |hive: OsString| HiveProbe {
+            hive,
+            verdict: Verdict::Fail,
+            detail: "timed out, see --registry-timeout".into(),
+        };
+        let roots = self
+            .run_on_registry(|regfs, ctx| regfs.regops().enumerate_key_ctx(OsString::new(), ctx));
+        let hives = match roots {
+            Ok(Some(roots)) => roots.subkeys,
+            Ok(None) => Vec::new(),
+            Err(Cancelled) => {
+                return SelfTest {
+                    hives: vec![timed_out("(root)".into())],
+                }
+            }
+        };
+
+        let mut selftest = SelfTest::default();
+        for hive in hives {
+            let name = hive.name.clone();
+            let probe = self.run_on_registry(move |regfs, ctx| {
+                probe_hive(regfs.regops(), Path::new(&name), ctx)
+            });
+            selftest
+                .hives
+                .push(probe.unwrap_or_else(|_| timed_out(hive.name)));
+        }
+        selftest
+    }
+}
+
+#[test]
+fn test_probe_hive() {
+    use crate::memory::{LazyValue, MemoryBackend};
+
+    let memory = MemoryBackend::new();
+    memory.set_value("HKEY_CURRENT_USER\\Software", "Small", 4, vec![1, 0, 0, 0]);
+    memory.set_value("HKEY_LOCAL_MACHINE\\SOFTWARE", "Ok", 4, vec![1, 0, 0, 0]);
+    memory.add_key("HKEY_LOCAL_MACHINE\\SAM");
+    memory.fail_entry("HKEY_LOCAL_MACHINE", "SAM");
+    memory.add_key("HKEY_USERS\\S-1-5-18");
+    memory.deny_key("HKEY_USERS\\S-1-5-18");
+    memory.set_lazy_value(
+        "HKEY_CURRENT_CONFIG",
+        LazyValue {
+            name: "Lazy".into(),
+            vtype: 3,
+            size: 4,
+            hash: 0,
+        },
+    );
+    memory.deny_key("HKEY_CLASSES_ROOT");
+    let probe = |hive: &str| probe_hive(&memory, hive.as_ref(), &OpContext::none()).unwrap();
+
+    // the value is read from the first subkey when the hive has none
+    let ok = probe("HKEY_CURRENT_USER");
+    assert_eq!(ok.verdict, Verdict::Pass);
+    assert_eq!(
+        ok.to_string(),
+        "[pass] HKEY_CURRENT_USER: 1 subkeys, 0 values"
+    );
+
+    let partial = probe("HKEY_LOCAL_MACHINE");
+    assert_eq!(partial.verdict, Verdict::Warn);
+    assert!(partial.detail.contains("1 entries failed to list"));
+    let denied = probe("HKEY_USERS");
+    assert_eq!(denied.verdict, Verdict::Warn);
+    assert!(denied.detail.contains("can't be opened"));
+    let unreadable = probe("HKEY_CURRENT_CONFIG");
+    assert_eq!(unreadable.verdict, Verdict::Warn);
+    assert!(unreadable.detail.contains("can't be read"));
+    assert_eq!(probe("HKEY_CLASSES_ROOT").verdict, Verdict::Fail);
+}
+
+#[test]
+fn test_selftest() {
+    use crate::memory::MemoryBackend;
+    use std::sync::Arc;
+
+    let backend = Arc::new(MemoryBackend::new());
+    backend.set_value("HKEY_CURRENT_USER\\Software", "Small", 4, vec![1, 0, 0, 0]);
+    backend.add_key("HKEY_LOCAL_MACHINE\\SOFTWARE");
+    let regfs = RegFs::with_backend(&Default::default(), backend.clone());
+
+    let selftest = regfs.selftest();
+    assert_eq!(selftest.hives.len(), 2);
+    assert_eq!(selftest.verdict(), Verdict::Pass);
+    assert_eq!(
+        selftest.to_string(),
+        "the mount is fully functional, 2 hives"
+    );
+
+    backend.deny_key("HKEY_LOCAL_MACHINE");
+    let selftest = regfs.selftest();
+    assert_eq!(selftest.verdict(), Verdict::Fail);
+    assert_eq!(selftest.to_string(), "1 of 2 hives failed, 0 with warnings");
+}
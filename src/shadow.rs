@@ -0,0 +1,503 @@
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use std::{
+    ffi::OsString,
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use winapi::um::winnt::REG_DWORD;
+
+use crate::backend::RegistryBackend;
+use crate::copykey::CopySummary;
+use crate::memory::MemoryBackend;
+use crate::opcontext::{Cancelled, OpContext};
+use crate::overlay::{OverlayStats, OverlayStore};
+use crate::regfile::{self, RegFile, RegFileKey, RegFileValue};
+use crate::regfs::RegFs;
+use crate::regop::{paths, rename::CopyLimits, RegEntires, RegOps, RegOpsError, RootHive};
+
+// next to the hives under the staging root: a key for each deleted path, marked with DELETED
+const TOMBSTONES: &str = "Tombstones";
+// REG_DWORD, 1 for a deleted key and 2 for a deleted value
+const DELETED: &str = "Deleted";
+
+// what the staging key is written through: the live registry, or memory in tests
+pub trait StagingRegistry: RegistryBackend {
+    // along with whatever is missing above it
+    fn create_key_all(&self, path: &Path) -> Result<()>;
+
+    // the parent has to exist
+    fn create_key(&self, path: &Path) -> Result<()>;
+
+    // the key has to exist
+    fn write_value(&self, path: &Path, data: &[u8], vtype: u32) -> Result<()>;
+
+    // a key with everything under it, to a name that isn't taken under a parent that exists
+    fn rename_key(&self, from: &Path, to: &Path) -> Result<()>;
+
+    // the same as a new key `to`, or in place of the one there with `force`; a copy that
+    // fails or is cancelled leaves nothing behind
+    fn copy_tree(
+        &self,
+        from: &Path,
+        to: &Path,
+        force: bool,
+        ctx: &OpContext,
+    ) -> Result<CopySummary>;
+
+    // the key with everything under it, or the value; nothing there isn't an error
+    fn delete(&self, path: &Path) -> Result<()>;
+}
+
+// so options holding one can still be printed
+impl fmt::Debug for dyn StagingRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "StagingRegistry")
+    }
+}
+
+impl StagingRegistry for RegOps {
+    fn create_key_all(&self, path: &Path) -> Result<()> {
+        Ok(RegOps::create_key_all(self, path)?)
+    }
+
+    fn create_key(&self, path: &Path) -> Result<()> {
+        Ok(RegOps::create_key(self, path, false)?)
+    }
+
+    fn write_value(&self, path: &Path, data: &[u8], vtype: u32) -> Result<()> {
+        Ok(RegOps::write_value(self, path, data, vtype)?)
+    }
+
+    fn rename_key(&self, from: &Path, to: &Path) -> Result<()> {
+        Ok(RegOps::rename_key(self, from, to, CopyLimits::default())?)
+    }
+
+    fn copy_tree(
+        &self,
+        from: &Path,
+        to: &Path,
+        force: bool,
+        ctx: &OpContext,
+    ) -> Result<CopySummary> {
+        let digest = RegOps::copy_tree(self, from, to, CopyLimits::default(), force, ctx)?;
+        Ok(CopySummary {
+            keys: digest.keys,
+            values: digest.values,
+            bytes: digest.bytes,
+        })
+    }
+
+    fn delete(&self, path: &Path) -> Result<()> {
+        Ok(RegOps::delete(self, path)?)
+    }
+}
+
+impl StagingRegistry for MemoryBackend {
+    fn create_key_all(&self, path: &Path) -> Result<()> {
+        self.add_key(path);
+        Ok(())
+    }
+
+    fn create_key(&self, path: &Path) -> Result<()> {
+        match path.parent() {
+            Some(parent) if self.does_key_exist(parent) => {
+                self.add_key(path);
+                Ok(())
+            }
+            _ => Err(RegOpsError::KeyNotFound(path.parent().unwrap_or(path).to_path_buf()).into()),
+        }
+    }
+
+    fn write_value(&self, path: &Path, data: &[u8], vtype: u32) -> Result<()> {
+        match (path.parent(), path.file_name()) {
+            (Some(key), Some(name)) if self.does_key_exist(key) => {
+                self.set_value(key, name, vtype, data.to_vec());
+                Ok(())
+            }
+            _ => Err(RegOpsError::KeyNotFound(path.parent().unwrap_or(path).to_path_buf()).into()),
+        }
+    }
+
+    fn rename_key(&self, from: &Path, to: &Path) -> Result<()> {
+        let parent = to.parent().unwrap_or(to);
+        if !self.does_key_exist(from) {
+            return Err(RegOpsError::KeyNotFound(from.to_path_buf()).into());
+        }
+        if !self.does_key_exist(parent) {
+            return Err(RegOpsError::KeyNotFound(parent.to_path_buf()).into());
+        }
+        if self.does_key_exist(to) {
+            return Err(anyhow!("[{:?}] already exists", to));
+        }
+        copy_memory_key(self, from, to, &mut CopySummary::default());
+        self.remove(from);
+        Ok(())
+    }
+
+    fn copy_tree(
+        &self,
+        from: &Path,
+        to: &Path,
+        force: bool,
+        ctx: &OpContext,
+    ) -> Result<CopySummary> {
+        let parent = to.parent().unwrap_or(to);
+        if !self.does_key_exist(from) {
+            return Err(RegOpsError::KeyNotFound(from.to_path_buf()).into());
+        }
+        if !self.does_key_exist(parent) {
+            return Err(RegOpsError::KeyNotFound(parent.to_path_buf()).into());
+        }
+        if self.does_key_exist(to) && !force {
+            return Err(anyhow!("[{:?}] already exists", to));
+        }
+        ctx.check()?;
+        self.remove(to);
+        let mut summary = CopySummary::default();
+        copy_memory_key(self, from, to, &mut summary);
+        Ok(summary)
+    }
+
+    fn delete(&self, path: &Path) -> Result<()> {
+        self.remove(path);
+        Ok(())
+    }
+}
+
+fn copy_memory_key(memory: &MemoryBackend, from: &Path, to: &Path, summary: &mut CopySummary) {
+    memory.add_key(to);
+    summary.keys += 1;
+    for (name, vtype, data) in memory.read_all_values(from).unwrap_or_default() {
+        summary.values += 1;
+        summary.bytes += data.len() as u64;
+        memory.set_value(to, name, vtype, data);
+    }
+    for subkey in memory
+        .enumerate_key(from.into())
+        .map(|entries| entries.subkeys)
+        .unwrap_or_default()
+    {
+        copy_memory_key(
+            memory,
+            &from.join(&subkey.name),
+            &to.join(&subkey.name),
+            summary,
+        );
+    }
+}
+
+// the live registry, opened for the staging root's hive only
+pub fn live_registry(root: &Path) -> Arc<dyn StagingRegistry> {
+    let hives: Vec<RootHive> = paths::components(root)
+        .first()
+        .and_then(|hive| hive.to_str()?.parse().ok())
+        .into_iter()
+        .collect();
+    Arc::new(RegOps::with_hives(&hives))
+}
+
+// --shadow-writes: the overlay's writes go to `<root>\<path>` in the registry instead of
+// memory, and its deletions to `<root>\Tombstones\<path>`, so they outlive the mount and are
+// there for the next one; the keys they were written over are never touched
+pub struct ShadowStore {
+    registry: Arc<dyn StagingRegistry>,
+    root: PathBuf,
+}
+
+impl ShadowStore {
+    pub fn new(registry: Arc<dyn StagingRegistry>, root: &Path) -> Self {
+        ShadowStore {
+            registry,
+            root: root.to_path_buf(),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // None for what would be the tombstones themselves
+    fn staged(&self, path: &Path) -> Option<PathBuf> {
+        let parts = paths::components(path);
+        if parts
+            .first()
+            .map_or(false, |first| first.eq_ignore_ascii_case(TOMBSTONES))
+        {
+            return None;
+        }
+        Some(paths::join_parts(
+            paths::components(&self.root).into_iter().chain(parts),
+        ))
+    }
+
+    fn tombstone(&self, path: &Path) -> PathBuf {
+        paths::join_parts(
+            paths::components(&self.root)
+                .into_iter()
+                .chain([OsString::from(TOMBSTONES)])
+                .chain(paths::components(path)),
+        )
+    }
+
+    fn collect_tombstones(&self, path: &Path, under: &Path, found: &mut Vec<(PathBuf, bool)>) {
+        let entries = match self.registry.enumerate_key(path.into()) {
+            Some(entries) => entries,
+            None => return,
+        };
+        match self
+            .registry
+            .read_typed_value_ctx(&path.join(DELETED), &OpContext::none())
+            .ok()
+            .flatten()
+            .map(|(_, data)| data.first().copied())
+        {
+            Some(Some(1)) => found.push((under.to_path_buf(), true)),
+            Some(Some(2)) => found.push((under.to_path_buf(), false)),
+            _ => {}
+        }
+        for subkey in entries.subkeys {
+            self.collect_tombstones(
+                &path.join(&subkey.name),
+                &paths::join_parts(paths::components(under).into_iter().chain([subkey.name])),
+                found,
+            );
+        }
+    }
+
+    // the staging tree as it is, tombstones included, as a .reg file that recreates it
+    pub fn export(&self) -> RegFile {
+        let mut export = RegFile::default();
+        self.collect_key(&self.root, &mut export);
+        export
+    }
+
+    fn collect_key(&self, path: &Path, export: &mut RegFile) {
+        let entries = match self.registry.enumerate_key(path.into()) {
+            Some(entries) => entries,
+            None => return,
+        };
+        let values = self.registry.read_all_values(path).unwrap_or_default();
+        export.keys.push(RegFileKey {
+            path: path.to_path_buf(),
+            delete: false,
+            values: values
+                .into_iter()
+                .map(|(name, vtype, data)| RegFileValue {
+                    name: paths::default_value_fallback(&name).unwrap_or(name),
+                    data: Some((vtype, data)),
+                })
+                .collect(),
+        });
+        for subkey in entries.subkeys {
+            self.collect_key(&path.join(&subkey.name), export);
+        }
+    }
+
+    fn warn_failed(&self, what: &str, path: &Path, result: Result<()>) {
+        if let Err(e) = result {
+            warn!(target: "overlay", "unable to {} [{:?}] under the staging key: {}", what, path, e);
+        }
+    }
+}
+
+impl RegistryBackend for ShadowStore {
+    fn enumerate_key_ctx(
+        &self,
+        path: OsString,
+        ctx: &OpContext,
+    ) -> Result<Option<RegEntires>, Cancelled> {
+        let staged = match self.staged(path.as_ref()) {
+            Some(staged) => staged,
+            None => return Ok(None),
+        };
+        let mut entries = self.registry.enumerate_key_ctx(staged.into(), ctx)?;
+        if let (true, Some(entries)) = (paths::components(&path).is_empty(), &mut entries) {
+            entries
+                .subkeys
+                .retain(|subkey| !subkey.name.eq_ignore_ascii_case(TOMBSTONES));
+        }
+        Ok(entries)
+    }
+
+    fn read_typed_value_ctx(
+        &self,
+        path: &Path,
+        ctx: &OpContext,
+    ) -> Result<Option<(u32, Vec<u8>)>, Cancelled> {
+        match self.staged(path) {
+            Some(staged) => self.registry.read_typed_value_ctx(&staged, ctx),
+            None => Ok(None),
+        }
+    }
+
+    fn read_all_values(&self, path: &Path) -> Option<Vec<(OsString, u32, Vec<u8>)>> {
+        self.registry.read_all_values(&self.staged(path)?)
+    }
+
+    fn does_key_exist(&self, path: &Path) -> bool {
+        self.staged(path)
+            .map_or(false, |staged| self.registry.does_key_exist(&staged))
+    }
+
+    fn key_last_write_time(&self, path: &Path) -> Option<i64> {
+        self.registry.key_last_write_time(&self.staged(path)?)
+    }
+}
+
+impl OverlayStore for ShadowStore {
+    fn put_key(&self, path: &Path) {
+        if let Some(staged) = self.staged(path) {
+            self.warn_failed("create", path, self.registry.create_key_all(&staged));
+        }
+    }
+
+    fn put_value(&self, path: &Path, vtype: u32, data: Vec<u8>) {
+        let staged = match self.staged(path) {
+            Some(staged) => staged,
+            None => return,
+        };
+        let result = match staged.parent() {
+            Some(key) => self
+                .registry
+                .create_key_all(key)
+                .and_then(|()| self.registry.write_value(&staged, &data, vtype)),
+            None => Err(anyhow!("not a value")),
+        };
+        self.warn_failed("write", path, result);
+    }
+
+    fn remove_entry(&self, path: &Path) {
+        if let Some(staged) = self.staged(path) {
+            self.warn_failed("delete", path, self.registry.delete(&staged));
+        }
+    }
+
+    fn tombstones(&self) -> Vec<(PathBuf, bool)> {
+        let mut found = Vec::new();
+        self.collect_tombstones(&self.tombstone(Path::new("")), Path::new(""), &mut found);
+        found
+    }
+
+    fn add_tombstone(&self, path: &Path, is_key: bool) {
+        let tombstone = self.tombstone(path);
+        let marker: u32 = match is_key {
+            true => 1,
+            false => 2,
+        };
+        let result = self.registry.create_key_all(&tombstone).and_then(|()| {
+            self.registry
+                .write_value(&tombstone.join(DELETED), &marker.to_le_bytes(), REG_DWORD)
+        });
+        self.warn_failed("mark the deletion of", path, result);
+    }
+
+    fn discard(&self) {
+        info!(target: "overlay", "deleting the staging key [{:?}]", self.root);
+        self.warn_failed("delete", Path::new(""), self.registry.delete(&self.root));
+    }
+}
+
+impl RegFs {
+    // `shadow export <file.reg>`
+    pub fn shadow_export(&self, file: &Path) -> String {
+        let shadow = match self.shadow() {
+            Some(shadow) => shadow,
+            None => return "shadow: not mounted with --shadow-writes".to_string(),
+        };
+        let export = shadow.export();
+        match regfile::write(file, &export) {
+            Ok(()) => format!(
+                "exported {} under [{:?}] to {:?}",
+                OverlayStats::of(&export),
+                shadow.root(),
+                file
+            ),
+            Err(e) => format!("shadow export: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+const STAGING: &str = "HKEY_CURRENT_USER\\Software\\RegFsStaging";
+
+#[cfg(test)]
+fn staged(path: &str) -> PathBuf {
+    Path::new(STAGING).join(path)
+}
+
+#[test]
+fn test_shadow_store() {
+    use crate::overlay::OverlayBackend;
+
+    let lower = Arc::new(MemoryBackend::new());
+    lower.set_value("HKEY_CURRENT_USER\\Software\\App", "Name", 1, vec![1]);
+    lower.set_value("HKEY_CURRENT_USER\\Software\\App\\Sub", "Inner", 1, vec![2]);
+    let staging = Arc::new(MemoryBackend::new());
+    let shadow = || {
+        OverlayBackend::with_store(
+            lower.clone(),
+            Arc::new(ShadowStore::new(staging.clone(), STAGING.as_ref())),
+        )
+    };
+    let overlay = shadow();
+
+    // writes go under the staging root, by their whole path
+    let name = Path::new("HKEY_CURRENT_USER\\Software\\App\\Name");
+    overlay.set_value(name, 3, vec![9]);
+    overlay.create_key("HKEY_CURRENT_USER\\Software\\App\\New".as_ref());
+    assert_eq!(
+        staging.read_value(&staged("HKEY_CURRENT_USER\\Software\\App\\Name")),
+        Some(vec![9])
+    );
+    assert!(staging.does_key_exist(&staged("HKEY_CURRENT_USER\\Software\\App\\New")));
+    assert_eq!(lower.read_value(name), Some(vec![1]));
+    assert!(!lower.does_key_exist("HKEY_CURRENT_USER\\Software\\App\\New".as_ref()));
+
+    // and are what's read back, with the rest still coming from below
+    assert_eq!(overlay.read_value(name), Some(vec![9]));
+    let app = overlay
+        .enumerate_key("HKEY_CURRENT_USER\\Software\\App".into())
+        .unwrap();
+    assert_eq!(app.subkeys.len(), 2);
+    assert_eq!(
+        overlay.read_value("HKEY_CURRENT_USER\\Software\\App\\Sub\\Inner".as_ref()),
+        Some(vec![2])
+    );
+
+    // deletions leave a marker, and are still there for the next mount
+    let sub = Path::new("HKEY_CURRENT_USER\\Software\\App\\Sub");
+    overlay.delete(sub);
+    overlay.delete(name);
+    assert!(!overlay.does_key_exist(sub));
+    assert!(lower.does_key_exist(sub));
+    assert_eq!(
+        staging.read_value(&staged(
+            "Tombstones\\HKEY_CURRENT_USER\\Software\\App\\Sub\\Deleted"
+        )),
+        Some(vec![1, 0, 0, 0])
+    );
+    let overlay = shadow();
+    assert!(!overlay.does_key_exist(sub));
+    assert_eq!(overlay.read_value(name), None);
+    assert_eq!(overlay.stats().deletions, 2);
+    let root = overlay.enumerate_key("".into()).unwrap();
+    assert!(root.subkeys.iter().all(|key| key.name != TOMBSTONES));
+
+    let export = ShadowStore::new(staging.clone(), STAGING.as_ref())
+        .export()
+        .to_text();
+    assert!(export.contains(
+        "[HKEY_CURRENT_USER\\Software\\RegFsStaging\\HKEY_CURRENT_USER\\Software\\App\\New]"
+    ));
+    assert!(export.contains(
+        "[HKEY_CURRENT_USER\\Software\\RegFsStaging\\Tombstones\\HKEY_CURRENT_USER\\Software\\App\\Name]\r\n\"Deleted\"=dword:00000002"
+    ));
+    assert!(!export.contains("[-"));
+
+    // a reset takes the staging key with it
+    overlay.reset();
+    assert!(!staging.does_key_exist(STAGING.as_ref()));
+    assert_eq!(shadow().read_value(name), Some(vec![1]));
+}
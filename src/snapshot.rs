@@ -0,0 +1,352 @@
+use log::{info, warn};
+use std::{ffi::OsString, fmt, path::Path, sync::Arc};
+
+use crate::backend::RegistryBackend;
+use crate::hash::fnv1a;
+use crate::links::LinkWalk;
+use crate::memory::{LazyValue, MemoryBackend};
+use crate::opcontext::{Cancelled, OpContext};
+use crate::regop::{RegEntires, RootHive};
+
+const PROGRESS_INTERVAL: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotLimits {
+    // subkeys below this depth (the hive is 0) are left out
+    pub max_depth: usize,
+    // value data kept in memory; once it's used up the rest is captured lazily
+    pub max_bytes: u64,
+    // values bigger than this are always captured lazily
+    pub inline_size: usize,
+}
+
+impl Default for SnapshotLimits {
+    fn default() -> Self {
+        SnapshotLimits {
+            max_depth: 32,
+            max_bytes: 256 * 1024 * 1024,
+            inline_size: 64 * 1024,
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotSummary {
+    pub keys: usize,
+    pub values: usize,
+    pub lazy: usize,
+    pub bytes: u64,
+    // keys whose subkeys were cut off by the depth limit
+    pub truncated: usize,
+    // keys left out because a registry link led back up the tree
+    pub cycles: usize,
+}
+
+impl fmt::Display for SnapshotSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} keys, {} values ({} lazy, {} bytes in memory), {} truncated, {} link cycles",
+            self.keys, self.values, self.lazy, self.bytes, self.truncated, self.cycles
+        )
+    }
+}
+
+// --snapshot: the registry as it was at mount time. Large values are only hashed up
+// front, then fetched on first read and kept, as long as they haven't changed since.
+pub struct SnapshotBackend {
+    tree: MemoryBackend,
+    source: Arc<dyn RegistryBackend>,
+    summary: SnapshotSummary,
+}
+
+struct Capture<'a> {
+    source: &'a dyn RegistryBackend,
+    tree: MemoryBackend,
+    limits: SnapshotLimits,
+    summary: SnapshotSummary,
+    walk: LinkWalk,
+}
+
+impl Capture<'_> {
+    fn key(&mut self, path: &Path, last_write_time: i64, depth: usize) {
+        if let Err(cycle) = self.walk.enter(self.source, path) {
+            warn!(target: "snapshot", "skipping [{:?}]: {}", path, cycle);
+            self.summary.cycles += 1;
+            return;
+        }
+        self.key_entered(path, last_write_time, depth);
+        self.walk.leave();
+    }
+
+    fn key_entered(&mut self, path: &Path, last_write_time: i64, depth: usize) {
+        let entries = match self.source.enumerate_key(path.into()) {
+            Some(entries) => entries,
+            None => return,
+        };
+
+        self.tree.add_key(path);
+        self.tree.set_last_write_time(path, last_write_time);
+        self.summary.keys += 1;
+        if self.summary.keys % PROGRESS_INTERVAL == 0 {
+            info!(target: "snapshot", "snapshot: {}", self.summary);
+        }
+
+        self.values(path);
+
+        if depth >= self.limits.max_depth {
+            if !entries.subkeys.is_empty() {
+                self.summary.truncated += 1;
+            }
+            return;
+        }
+        for subkey in entries.subkeys {
+            self.key(&path.join(&subkey.name), subkey.last_write_time, depth + 1);
+        }
+    }
+
+    fn values(&mut self, path: &Path) {
+        for (name, vtype, data) in self.source.read_all_values(path).unwrap_or_default() {
+            self.summary.values += 1;
+
+            let size = data.len() as u64;
+            if data.len() > self.limits.inline_size
+                || self.summary.bytes + size > self.limits.max_bytes
+            {
+                self.summary.lazy += 1;
+                self.tree.set_lazy_value(
+                    path,
+                    LazyValue {
+                        name,
+                        vtype,
+                        size,
+                        hash: fnv1a(&data),
+                    },
+                );
+            } else {
+                self.summary.bytes += size;
+                self.tree.set_value(path, name, vtype, data);
+            }
+        }
+    }
+}
+
+impl SnapshotBackend {
+    pub fn capture(
+        source: Arc<dyn RegistryBackend>,
+        hives: &[RootHive],
+        limits: SnapshotLimits,
+    ) -> Self {
+        info!(target: "snapshot", "----> capture: {:?}", limits);
+        let mut capture = Capture {
+            source: source.as_ref(),
+            tree: MemoryBackend::new(),
+            limits,
+            summary: SnapshotSummary::default(),
+            walk: LinkWalk::default(),
+        };
+
+        for hive in hives {
+            let path = Path::new(hive.name());
+            let last_write_time = source.key_last_write_time(path).unwrap_or(0);
+            capture.key(path, last_write_time, 0);
+        }
+
+        let Capture { tree, summary, .. } = capture;
+        info!(target: "snapshot", "<---- capture: {}", summary);
+        SnapshotBackend {
+            tree,
+            source,
+            summary,
+        }
+    }
+
+    pub fn summary(&self) -> SnapshotSummary {
+        self.summary
+    }
+
+    // the live data, but only if it's still what was hashed at capture time
+    fn fetch(
+        &self,
+        path: &Path,
+        lazy: LazyValue,
+        ctx: &OpContext,
+    ) -> Result<Option<(u32, Vec<u8>)>, Cancelled> {
+        match self.source.read_typed_value_ctx(path, ctx)? {
+            Some((vtype, data)) if vtype == lazy.vtype && fnv1a(&data) == lazy.hash => {
+                let key = path.parent().unwrap_or_else(|| Path::new(""));
+                self.tree.set_value(key, lazy.name, vtype, data.clone());
+                Ok(Some((vtype, data)))
+            }
+            _ => {
+                warn!(target: "snapshot", "[{:?}] changed since the snapshot was taken", path);
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl RegistryBackend for SnapshotBackend {
+    fn enumerate_key_ctx(
+        &self,
+        path: OsString,
+        ctx: &OpContext,
+    ) -> Result<Option<RegEntires>, Cancelled> {
+        self.tree.enumerate_key_ctx(path, ctx)
+    }
+
+    fn read_typed_value_ctx(
+        &self,
+        path: &Path,
+        ctx: &OpContext,
+    ) -> Result<Option<(u32, Vec<u8>)>, Cancelled> {
+        match self.tree.lazy_value(path) {
+            Some(lazy) => self.fetch(path, lazy, ctx),
+            None => self.tree.read_typed_value_ctx(path, ctx),
+        }
+    }
+
+    fn read_all_values(&self, path: &Path) -> Option<Vec<(OsString, u32, Vec<u8>)>> {
+        let mut values = self.tree.read_all_values(path)?;
+        for lazy in self.tree.lazy_values(path) {
+            let name = lazy.name.clone();
+            if let Ok(Some((vtype, data))) = self.fetch(&path.join(&name), lazy, &OpContext::none())
+            {
+                values.push((name, vtype, data));
+            }
+        }
+        Some(values)
+    }
+
+    fn does_key_exist(&self, path: &Path) -> bool {
+        self.tree.does_key_exist(path)
+    }
+
+    fn key_last_write_time(&self, path: &Path) -> Option<i64> {
+        self.tree.key_last_write_time(path)
+    }
+
+    fn writable(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+fn source() -> Arc<MemoryBackend> {
+    let source = Arc::new(MemoryBackend::new());
+    source.set_value("HKEY_CURRENT_USER\\Software\\App", "Small", 1, vec![1, 2]);
+    source.set_value("HKEY_CURRENT_USER\\Software\\App", "Big", 3, vec![7; 32]);
+    source.set_value("HKEY_CURRENT_USER\\Software\\App", "Other", 3, vec![8; 32]);
+    source.add_key("HKEY_CURRENT_USER\\Software\\App\\Deep\\Deeper");
+    source
+}
+
+#[test]
+fn test_snapshot_is_frozen() {
+    let source = source();
+    let limits = SnapshotLimits {
+        inline_size: 16,
+        ..Default::default()
+    };
+    let snapshot = SnapshotBackend::capture(source.clone(), &[RootHive::CurrentUser], limits);
+    assert_eq!(snapshot.summary().keys, 5);
+    assert_eq!(snapshot.summary().values, 3);
+    assert_eq!(snapshot.summary().lazy, 2);
+    assert_eq!(snapshot.summary().bytes, 2);
+
+    source.set_value("HKEY_CURRENT_USER\\Software\\App", "Small", 1, vec![3]);
+    source.add_key("HKEY_CURRENT_USER\\Software\\New");
+    assert_eq!(
+        snapshot.read_value("HKEY_CURRENT_USER\\Software\\App\\Small".as_ref()),
+        Some(vec![1, 2])
+    );
+    assert!(!snapshot.does_key_exist("HKEY_CURRENT_USER\\Software\\New".as_ref()));
+
+    // lazy values list with their captured size before they are ever read
+    let entries = snapshot
+        .enumerate_key("HKEY_CURRENT_USER\\Software\\App".into())
+        .unwrap();
+    assert_eq!(entries.values.len(), 3);
+    assert!(entries.values.iter().any(|value| value.size == 32));
+
+    // fetched once, then pinned
+    let big = Path::new("HKEY_CURRENT_USER\\Software\\App\\Big");
+    let reads = source.reads();
+    assert_eq!(snapshot.read_value(big), Some(vec![7; 32]));
+    assert_eq!(source.reads(), reads + 1);
+    source.set_value("HKEY_CURRENT_USER\\Software\\App", "Big", 3, vec![9; 32]);
+    assert_eq!(snapshot.read_value(big), Some(vec![7; 32]));
+    assert_eq!(source.reads(), reads + 1);
+
+    // a lazy value that changed before its first read can't be served
+    source.set_value("HKEY_CURRENT_USER\\Software\\App", "Other", 3, vec![0; 32]);
+    assert_eq!(
+        snapshot.read_value("HKEY_CURRENT_USER\\Software\\App\\Other".as_ref()),
+        None
+    );
+}
+
+#[test]
+fn test_snapshot_limits() {
+    let limits = SnapshotLimits {
+        max_depth: 2,
+        max_bytes: 2,
+        ..Default::default()
+    };
+    let snapshot = SnapshotBackend::capture(source(), &[RootHive::CurrentUser], limits);
+    assert_eq!(snapshot.summary().truncated, 1);
+    assert!(snapshot.does_key_exist("HKEY_CURRENT_USER\\Software\\App".as_ref()));
+    assert!(!snapshot.does_key_exist("HKEY_CURRENT_USER\\Software\\App\\Deep".as_ref()));
+    // Big and Other are too large for the budget, Small still fits
+    assert_eq!(snapshot.summary().bytes, 2);
+    assert_eq!(snapshot.summary().lazy, 2);
+}
+
+#[test]
+fn test_snapshot_rejects_writes() {
+    use crate::options::RegFsOptions;
+    use crate::policy::{MutationKind, MutationRequest};
+    use crate::regfs::RegFs;
+
+    let options = RegFsOptions {
+        readonly: false,
+        ..Default::default()
+    };
+    let snapshot = SnapshotBackend::capture(source(), &[RootHive::CurrentUser], Default::default());
+    let regfs = RegFs::with_backend(&options, Arc::new(snapshot));
+
+    for kind in [
+        MutationKind::Rename,
+        MutationKind::Delete,
+        MutationKind::ConvertToFull,
+    ] {
+        let request = MutationRequest {
+            kind,
+            path: Path::new("HKEY_CURRENT_USER\\Software\\App\\Small"),
+            destination: None,
+            process: "".as_ref(),
+            is_directory: false,
+        };
+        assert!(regfs.decide(&request).is_deny());
+    }
+}
+
+#[test]
+fn test_snapshot_link_cycles() {
+    let memory = MemoryBackend::new();
+    memory.set_value("HKEY_CURRENT_USER\\Software\\App", "Small", 3, vec![1]);
+    // back up to where the capture already is
+    memory.add_link(
+        "HKEY_CURRENT_USER\\Software\\App\\Loop",
+        "HKEY_CURRENT_USER\\Software",
+    );
+
+    let snapshot = SnapshotBackend::capture(
+        Arc::new(memory),
+        &[RootHive::CurrentUser],
+        Default::default(),
+    );
+    assert_eq!(snapshot.summary().cycles, 1);
+    assert_eq!(snapshot.summary().values, 1);
+    assert!(!snapshot.does_key_exist("HKEY_CURRENT_USER\\Software\\App\\Loop".as_ref()));
+}
@@ -0,0 +1,453 @@
+// many threads calling into one RegFs at once, on the memory backend and without ProjFS;
+// every random choice comes from SEED (or STRESS_SEED), so a failure can be replayed
+use prjfs::conv::WStrExt;
+use prjfs::guid::guid_to_bytes;
+use prjfs::ProviderT;
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    fs,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::Duration,
+};
+use winapi::{
+    shared::{
+        guiddef::GUID,
+        winerror::{self, HRESULT_FROM_WIN32, S_OK},
+    },
+    um::{projectedfslib::PRJ_CALLBACK_DATA, winnt::HRESULT},
+};
+
+use crate::fixture::callback_data;
+use crate::fuzz::Rng;
+use crate::memory::MemoryBackend;
+use crate::opcontext::Cancelled;
+use crate::options::RegFsOptions;
+use crate::regfs::RegFs;
+use crate::trace::{CallKind, Record, Recorder, TraceEntry, Tracer};
+
+const SEED: u64 = 0x0123_4567_89ab_cdef;
+const THREADS: u32 = 8;
+// no scenario takes more than a second or two; past this something is stuck
+const WATCHDOG: Duration = Duration::from_secs(60);
+const KEY: &str = "HKEY_CURRENT_USER\\Stress";
+
+fn seed() -> u64 {
+    std::env::var("STRESS_SEED")
+        .ok()
+        .and_then(|seed| seed.parse().ok())
+        .unwrap_or(SEED)
+}
+
+// tells the watchdog a thread is done, even when it panicked
+struct Finished(mpsc::Sender<u32>, u32);
+
+impl Drop for Finished {
+    fn drop(&mut self) {
+        let _ = self.0.send(self.1);
+    }
+}
+
+struct Harness {
+    regfs: RegFs,
+    backend: Arc<MemoryBackend>,
+    trace: PathBuf,
+    // every callback made, to check the counters against
+    callbacks: AtomicU64,
+    seed: u64,
+}
+
+impl Harness {
+    fn new(name: &str, options: RegFsOptions) -> Harness {
+        let backend = Arc::new(MemoryBackend::new());
+        for key in 0..10 {
+            backend.add_key(format!("{}\\Key{}", KEY, key));
+        }
+        for value in 0..50 {
+            let data = format!("value {}\0", value)
+                .encode_utf16()
+                .flat_map(u16::to_le_bytes)
+                .collect();
+            backend.set_value(KEY, format!("Value{:02}", value), 1, data);
+        }
+
+        let trace = std::env::temp_dir().join(format!(
+            "regfs-stress-{}-{}.jsonl",
+            name,
+            std::process::id()
+        ));
+        let recorder = Recorder::create(&trace, false).unwrap();
+        let options = RegFsOptions {
+            tracer: Some(Arc::new(Tracer::Record(recorder))),
+            ..options
+        };
+
+        Harness {
+            regfs: RegFs::with_backend(&options, backend.clone()),
+            backend,
+            trace,
+            callbacks: AtomicU64::new(0),
+            seed: seed(),
+        }
+    }
+
+    // runs `work` on every thread with its own Rng, failing if they don't all finish in time
+    fn run<F>(&self, work: F)
+    where
+        F: Fn(&Harness, u32, &mut Rng) + Sync,
+    {
+        let (done, finished) = mpsc::channel();
+        thread::scope(|scope| {
+            for index in 0..THREADS {
+                let done = done.clone();
+                let work = &work;
+                scope.spawn(move || {
+                    let _finished = Finished(done, index);
+                    let mut rng = Rng::new(self.seed, index);
+                    work(self, index, &mut rng);
+                });
+            }
+            for _ in 0..THREADS {
+                // a deadlocked thread can't be joined, and the scope would wait for it forever;
+                // not `dump` either, which would wait on the same locks
+                if finished.recv_timeout(WATCHDOG).is_err() {
+                    eprintln!(
+                        "seed {}: threads still running after {:?}, deadlocked?",
+                        self.seed, WATCHDOG
+                    );
+                    std::process::abort();
+                }
+            }
+        });
+    }
+
+    fn call<F: FnOnce(&PRJ_CALLBACK_DATA) -> HRESULT>(
+        &self,
+        path: &str,
+        flags: u32,
+        command: i32,
+        stream: GUID,
+        f: F,
+    ) -> HRESULT {
+        let mut data = callback_data(path, command);
+        data.Flags = flags;
+        data.DataStreamId = stream;
+        self.callbacks.fetch_add(1, Ordering::Relaxed);
+        f(&data)
+    }
+
+    fn start(&self, guid: &GUID) -> HRESULT {
+        self.call(KEY, 0, 0, GUID::default(), |data| {
+            self.regfs.start_dir_enum(data, guid).unwrap()
+        })
+    }
+
+    fn list(&self, guid: &GUID, restart: bool) -> HRESULT {
+        let flags = match restart {
+            true => prjfs::sys::PRJ_CB_DATA_FLAG_ENUM_RESTART_SCAN,
+            false => 0,
+        };
+        let search = OsString::from("*").to_wstr();
+        self.call(KEY, flags, 0, GUID::default(), |data| {
+            self.regfs
+                .get_dir_enum(data, guid, search.as_ptr(), std::ptr::null_mut())
+                .unwrap()
+        })
+    }
+
+    fn end(&self, guid: &GUID) -> HRESULT {
+        self.call(KEY, 0, 0, GUID::default(), |data| {
+            self.regfs.end_dir_enum(data, guid).unwrap()
+        })
+    }
+
+    fn records(&self) -> Vec<Record> {
+        fs::read_to_string(&self.trace)
+            .unwrap()
+            .lines()
+            .map(|line| Record::from_json(&serde_json::from_str(line).unwrap()).unwrap())
+            .collect()
+    }
+
+    // what a single enumeration with nothing else going on lists
+    fn listing(&self) -> Vec<TraceEntry> {
+        let guid = GUID {
+            Data1: u32::MAX,
+            ..Default::default()
+        };
+        assert_eq!(self.start(&guid), S_OK);
+        assert_eq!(self.list(&guid, false), S_OK);
+        assert_eq!(self.end(&guid), S_OK);
+
+        let guid = guid_to_bytes(&guid);
+        let entries = self
+            .records()
+            .into_iter()
+            .find_map(|record| match record.call.kind {
+                CallKind::GetDirEnum { enumeration, .. } if enumeration == guid => {
+                    record.response.entries
+                }
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(entries.len(), 60);
+        entries
+    }
+
+    fn check_counters(&self) {
+        let snapshot = self.regfs.metrics_snapshot();
+        assert_eq!(
+            snapshot.callbacks,
+            self.callbacks.load(Ordering::Relaxed),
+            "seed {}",
+            self.seed
+        );
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.trace);
+    }
+}
+
+fn guid(data1: u32, data2: u16) -> GUID {
+    GUID {
+        Data1: data1,
+        Data2: data2,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_stress_enumerations() {
+    let harness = Harness::new("enum", RegFsOptions::default());
+    let listing = harness.listing();
+    let starts = AtomicU64::new(1);
+
+    harness.run(|harness, thread, rng| {
+        for iteration in 0..200 {
+            // a quarter of the time on one of a few GUIDs every thread uses, so restarts, ends
+            // and listings of the same session interleave
+            if rng.chance(25) {
+                let shared = guid(rng.below(4) as u32, 2);
+                let hr = match rng.below(3) {
+                    0 => {
+                        starts.fetch_add(1, Ordering::Relaxed);
+                        harness.start(&shared)
+                    }
+                    1 => harness.list(&shared, rng.chance(50)),
+                    _ => harness.end(&shared),
+                };
+                assert!(
+                    hr == S_OK || hr == winerror::E_INVALIDARG,
+                    "seed {}: {:08x}",
+                    harness.seed,
+                    hr
+                );
+                continue;
+            }
+
+            let own = guid(thread << 16 | iteration, 1);
+            starts.fetch_add(1, Ordering::Relaxed);
+            assert_eq!(harness.start(&own), S_OK);
+            for call in 0..=rng.below(3) {
+                // nobody else knows this GUID, so the session can't have gone missing
+                let restart = call > 0 && rng.chance(50);
+                assert_eq!(
+                    harness.list(&own, restart),
+                    S_OK,
+                    "seed {}: lost session",
+                    harness.seed
+                );
+            }
+            assert_eq!(harness.end(&own), S_OK);
+        }
+    });
+
+    for shared in 0..4 {
+        harness.end(&guid(shared, 2));
+    }
+    assert!(harness.regfs.sessions().is_empty());
+    harness.check_counters();
+    assert_eq!(
+        harness.regfs.metrics_snapshot().enumerations,
+        starts.load(Ordering::Relaxed)
+    );
+
+    // every private session listed each entry exactly once since its last restart
+    let private: HashSet<Vec<u8>> = (0..THREADS)
+        .flat_map(|thread| (0..200).map(move |iteration| guid(thread << 16 | iteration, 1)))
+        .map(|guid| guid_to_bytes(&guid))
+        .collect();
+    let mut listed: HashMap<Vec<u8>, Vec<TraceEntry>> = HashMap::new();
+    let mut sessions = 0;
+    for record in harness.records() {
+        let restart = record.call.flags & prjfs::sys::PRJ_CB_DATA_FLAG_ENUM_RESTART_SCAN != 0;
+        match record.call.kind {
+            CallKind::StartDirEnum { enumeration } if private.contains(&enumeration) => {
+                listed.insert(enumeration, Vec::new());
+            }
+            CallKind::GetDirEnum { enumeration, .. } if private.contains(&enumeration) => {
+                let entries = listed.get_mut(&enumeration).unwrap();
+                if restart {
+                    entries.clear();
+                }
+                entries.extend(record.response.entries.unwrap_or_default());
+            }
+            CallKind::EndDirEnum { enumeration } if private.contains(&enumeration) => {
+                let entries = listed.remove(&enumeration).unwrap();
+                assert_eq!(entries, listing, "seed {}", harness.seed);
+                sessions += 1;
+            }
+            _ => {}
+        }
+    }
+    assert!(listed.is_empty());
+    assert!(sessions > THREADS as usize * 100);
+}
+
+#[test]
+fn test_stress_placeholder_info() {
+    let harness = Harness::new("placeholder", RegFsOptions::default());
+    let value = format!("{}\\Value07", KEY);
+    let missing = format!("{}\\Missing", KEY);
+    let placeholder = |path: &str, command: i32| {
+        harness.call(path, 0, command, GUID::default(), |data| {
+            harness.regfs.get_placeholder_info(data).unwrap()
+        })
+    };
+    let not_found = placeholder(&missing, 0);
+    assert_eq!(
+        not_found,
+        HRESULT_FROM_WIN32(winerror::ERROR_FILE_NOT_FOUND)
+    );
+
+    harness.run(|_, thread, rng| {
+        for call in 0..500 {
+            let command = (thread * 1000 + call) as i32;
+            match rng.chance(80) {
+                true => assert_eq!(placeholder(&value, command), S_OK),
+                false => assert_eq!(placeholder(&missing, command), not_found),
+            }
+        }
+    });
+
+    harness.check_counters();
+    assert_eq!(harness.regfs.dump()["commands"], serde_json::json!([]));
+}
+
+#[test]
+fn test_stress_hydration_and_cancellation() {
+    let harness = Harness::new("hydration", RegFsOptions::default());
+    let next_command = AtomicU64::new(1);
+    let reads = AtomicU64::new(0);
+
+    harness.run(|harness, thread, rng| {
+        for call in 0..300 {
+            // a few threads only cancel whatever was issued last
+            if thread % 4 == 3 {
+                let last = next_command.load(Ordering::Relaxed) as i32;
+                let command = last - rng.below(4) as i32;
+                harness.call(KEY, 0, command, GUID::default(), |data| {
+                    harness.regfs.cancel_command(data).unwrap();
+                    S_OK
+                });
+                // not a callback trace_call counts
+                harness.callbacks.fetch_sub(1, Ordering::Relaxed);
+                continue;
+            }
+
+            let command = next_command.fetch_add(1, Ordering::Relaxed) as i32;
+            // a few streams reading the same values, so slots are shared
+            let stream = guid(thread << 16 | call, 3);
+            let path = format!("{}\\Value{:02}", KEY, rng.below(5));
+            reads.fetch_add(1, Ordering::Relaxed);
+            let hr = harness.call(&path, 0, command, stream, |data| {
+                harness.regfs.get_file_data(data, 0, 4096).unwrap()
+            });
+            assert!(
+                hr == S_OK || hr == Cancelled.to_hresult(),
+                "seed {}: {:08x}",
+                harness.seed,
+                hr
+            );
+        }
+    });
+
+    harness.check_counters();
+    let snapshot = harness.regfs.metrics_snapshot();
+    assert_eq!(
+        snapshot.cache_hits + snapshot.cache_misses,
+        reads.load(Ordering::Relaxed)
+    );
+    // every stream was served to the end or gave up, either way nothing is left behind
+    let dump = harness.regfs.dump();
+    assert_eq!(
+        dump["hydrations"],
+        serde_json::json!([]),
+        "seed {}",
+        harness.seed
+    );
+    assert_eq!(
+        dump["commands"],
+        serde_json::json!([]),
+        "seed {}",
+        harness.seed
+    );
+}
+
+#[test]
+fn test_stress_hydration_limit() {
+    use crate::hydrationlimit::HydrationLimits;
+
+    const LIMIT: usize = 3;
+    let options = RegFsOptions {
+        // on the callbacks' threads, so the pool doesn't limit them first
+        registry_threads: 0,
+        hydration_limits: HydrationLimits {
+            max: LIMIT,
+            wait: Duration::from_secs(5),
+            small: 16,
+        },
+        ..Default::default()
+    };
+    let harness = Harness::new("hydration-limit", options);
+    harness.backend.slow_reads(Duration::from_millis(2));
+    let next_command = AtomicU64::new(1);
+
+    harness.run(|harness, thread, rng| {
+        for call in 0..50 {
+            let command = next_command.fetch_add(1, Ordering::Relaxed) as i32;
+            // every read its own stream, so none of them shares another's
+            let stream = guid(thread << 16 | call, 4);
+            let path = format!("{}\\Value{:02}", KEY, rng.below(50));
+            let hr = harness.call(&path, 0, command, stream, |data| {
+                harness.regfs.get_file_data(data, 0, 4096).unwrap()
+            });
+            assert!(
+                hr == S_OK || hr == HRESULT_FROM_WIN32(winerror::ERROR_RETRY),
+                "seed {}: {:08x}",
+                harness.seed,
+                hr
+            );
+        }
+    });
+
+    harness.check_counters();
+    let peak = harness.backend.peak_concurrent_reads();
+    assert!(
+        peak <= LIMIT,
+        "seed {}: {} reads at once",
+        harness.seed,
+        peak
+    );
+    // more threads than turns, and they had to take them
+    assert!(harness.regfs.metrics_snapshot().hydration_waits > 0);
+    assert_eq!(harness.regfs.dump()["hydrations"], serde_json::json!([]));
+}
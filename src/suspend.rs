@@ -0,0 +1,98 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Condvar, Mutex,
+    },
+    time::Duration,
+};
+
+// how long a callback waits for a resume before it's turned away
+pub const WAIT: Duration = Duration::from_millis(250);
+
+// `suspend` and `resume`: while suspended, nothing is read from the registry or changed in
+// it, so a migration running meanwhile is never seen half done. Every callback asks, so the
+// flag is read without taking a lock; only the ones that have to wait take one
+#[derive(Default)]
+pub struct Suspension {
+    suspended: AtomicBool,
+    resumed: (Mutex<()>, Condvar),
+}
+
+impl Suspension {
+    pub fn is_suspended(&self) -> bool {
+        self.suspended.load(Ordering::Acquire)
+    }
+
+    // false when it already was
+    pub fn suspend(&self) -> bool {
+        !self.suspended.swap(true, Ordering::AcqRel)
+    }
+
+    // false when it wasn't suspended
+    pub fn resume(&self) -> bool {
+        let was = self.suspended.swap(false, Ordering::AcqRel);
+        let (lock, condvar) = &self.resumed;
+        // taken so a waiter can't miss the wakeup between its check and its wait
+        drop(lock.lock().unwrap_or_else(|e| e.into_inner()));
+        condvar.notify_all();
+        was
+    }
+
+    // true right away unless suspended, then whether it was resumed within `wait`
+    pub fn wait_for_resume(&self, wait: Duration) -> bool {
+        if !self.is_suspended() {
+            return true;
+        }
+
+        let (lock, condvar) = &self.resumed;
+        let guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+        let (_guard, _) = condvar
+            .wait_timeout_while(guard, wait, |_| self.is_suspended())
+            .unwrap_or_else(|e| e.into_inner());
+        !self.is_suspended()
+    }
+}
+
+#[test]
+fn test_suspend_and_resume() {
+    let suspension = Suspension::default();
+    assert!(suspension.wait_for_resume(Duration::ZERO));
+    assert!(!suspension.resume());
+
+    assert!(suspension.suspend());
+    assert!(!suspension.suspend());
+    assert!(suspension.is_suspended());
+    assert!(!suspension.wait_for_resume(Duration::from_millis(10)));
+
+    assert!(suspension.resume());
+    assert!(!suspension.is_suspended());
+    assert!(suspension.wait_for_resume(Duration::ZERO));
+}
+
+#[test]
+fn test_waiters_are_woken_by_a_resume() {
+    use std::{sync::Arc, thread, time::Instant};
+
+    let suspension = Arc::new(Suspension::default());
+    suspension.suspend();
+    let waiters: Vec<_> = (0..4)
+        .map(|_| {
+            let suspension = suspension.clone();
+            thread::spawn(move || {
+                let start = Instant::now();
+                (
+                    suspension.wait_for_resume(Duration::from_secs(10)),
+                    start.elapsed(),
+                )
+            })
+        })
+        .collect();
+
+    thread::sleep(Duration::from_millis(50));
+    suspension.resume();
+    for waiter in waiters {
+        let (resumed, waited) = waiter.join().unwrap();
+        assert!(resumed);
+        assert!(waited < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,257 @@
+use std::{
+    ffi::{OsStr, OsString},
+    path::{Component, Path},
+};
+use winapi::um::winnt::{FILE_ATTRIBUTE_HIDDEN, FILE_ATTRIBUTE_SYSTEM};
+
+pub const CONTROL_DIR: &str = ".regfs";
+pub const CONTROL_FILE: &str = "control";
+pub const STATS_FILE: &str = "stats";
+pub const VALUES_JSON_FILE: &str = "_values.json";
+pub const HIVE_SUMMARY_FILE: &str = "__hive__.json";
+pub const META_JSON_FILE: &str = "__meta__.json";
+// --search-dir: `_search\<pattern>\<hit>`
+pub const SEARCH_DIR: &str = "_search";
+// --recent-dir: `_recent\<change>`
+pub const RECENT_DIR: &str = "_recent";
+// `__truncated__ (<n> more entries)`
+const TRUNCATED_PREFIX: &str = "__truncated__ (";
+const TRUNCATED_SUFFIX: &str = " more entries)";
+
+// entries that only exist in the projection and must never be forwarded to RegOps
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Synthetic {
+    ControlDir,
+    ControlFile,
+    StatsFile,
+    // per key, only projected when enabled
+    ValuesJson,
+    // per hive, only projected when enabled
+    HiveSummary,
+    // per merged key, only with --merge-virtualstore
+    MetaJson,
+    // the last entry of a key listing cut short by --max-entries-per-dir
+    Truncated,
+    // --search-dir: the root of it, a pattern's results, and one of them
+    SearchDir,
+    SearchQuery,
+    SearchHit,
+    // --recent-dir: the root of it and one change
+    RecentDir,
+    RecentChange,
+}
+
+impl Synthetic {
+    pub fn from_path(path: &Path) -> Option<Synthetic> {
+        let mut parts = path.components().filter_map(|c| match c {
+            Component::Normal(part) => Some(part),
+            _ => None,
+        });
+
+        let first = parts.next()?;
+        if eq_ignore_case(first, SEARCH_DIR) {
+            return match (parts.next(), parts.next(), parts.next()) {
+                (None, _, _) => Some(Synthetic::SearchDir),
+                (Some(_), None, _) => Some(Synthetic::SearchQuery),
+                (Some(_), Some(_), None) => Some(Synthetic::SearchHit),
+                _ => None,
+            };
+        }
+        if eq_ignore_case(first, RECENT_DIR) {
+            return match (parts.next(), parts.next()) {
+                (None, _) => Some(Synthetic::RecentDir),
+                (Some(_), None) => Some(Synthetic::RecentChange),
+                _ => None,
+            };
+        }
+        if !eq_ignore_case(first, CONTROL_DIR) {
+            let second = parts.next();
+            if let (Some(name), None) = (second, parts.next()) {
+                if eq_ignore_case(name, HIVE_SUMMARY_FILE) {
+                    return Some(Synthetic::HiveSummary);
+                }
+            }
+
+            return match path.file_name() {
+                Some(name) if name != first && eq_ignore_case(name, VALUES_JSON_FILE) => {
+                    Some(Synthetic::ValuesJson)
+                }
+                Some(name) if name != first && eq_ignore_case(name, META_JSON_FILE) => {
+                    Some(Synthetic::MetaJson)
+                }
+                Some(name) if name != first && truncated_count(name).is_some() => {
+                    Some(Synthetic::Truncated)
+                }
+                _ => None,
+            };
+        }
+
+        let result = match parts.next() {
+            None => Synthetic::ControlDir,
+            Some(name) if eq_ignore_case(name, CONTROL_FILE) => Synthetic::ControlFile,
+            Some(name) if eq_ignore_case(name, STATS_FILE) => Synthetic::StatsFile,
+            Some(_) => return None,
+        };
+
+        if parts.next().is_some() {
+            return None;
+        }
+
+        Some(result)
+    }
+
+    pub fn is_directory(self) -> bool {
+        matches!(
+            self,
+            Synthetic::ControlDir
+                | Synthetic::SearchDir
+                | Synthetic::SearchQuery
+                | Synthetic::RecentDir
+        )
+    }
+
+    // what it's listed with unless --show-synthetic, so `dir` and `*` only see registry data
+    pub fn attributes(self) -> u32 {
+        match self {
+            Synthetic::ControlDir => FILE_ATTRIBUTE_HIDDEN | FILE_ATTRIBUTE_SYSTEM,
+            _ => FILE_ATTRIBUTE_HIDDEN,
+        }
+    }
+
+    // content is generated when the placeholder is created and must be refreshed
+    // once the file is closed, otherwise readers keep seeing the first snapshot
+    pub fn is_dynamic(self) -> bool {
+        matches!(
+            self,
+            Synthetic::StatsFile
+                | Synthetic::ValuesJson
+                | Synthetic::HiveSummary
+                | Synthetic::MetaJson
+        )
+    }
+}
+
+// the ones that aren't enabled are left out by RegFs::synthetic
+pub fn root_entries() -> Vec<OsString> {
+    vec![CONTROL_DIR.into(), SEARCH_DIR.into(), RECENT_DIR.into()]
+}
+
+pub fn control_dir_entries() -> Vec<(OsString, Synthetic)> {
+    vec![
+        (CONTROL_FILE.into(), Synthetic::ControlFile),
+        (STATS_FILE.into(), Synthetic::StatsFile),
+    ]
+}
+
+pub fn truncated_marker(more: usize) -> OsString {
+    format!("{}{}{}", TRUNCATED_PREFIX, more, TRUNCATED_SUFFIX).into()
+}
+
+// the `<n>` of a truncation marker
+pub fn truncated_count(name: &OsStr) -> Option<usize> {
+    let count = name
+        .to_str()?
+        .strip_prefix(TRUNCATED_PREFIX)?
+        .strip_suffix(TRUNCATED_SUFFIX)?;
+    match count.bytes().all(|digit| digit.is_ascii_digit()) {
+        true => count.parse().ok(),
+        false => None,
+    }
+}
+
+fn eq_ignore_case(name: &OsStr, expected: &str) -> bool {
+    name.to_str()
+        .map(|name| name.eq_ignore_ascii_case(expected))
+        .unwrap_or(false)
+}
+
+#[test]
+fn test_from_path() {
+    assert_eq!(
+        Synthetic::from_path(".regfs".as_ref()),
+        Some(Synthetic::ControlDir)
+    );
+    assert_eq!(
+        Synthetic::from_path(".REGFS\\Control".as_ref()),
+        Some(Synthetic::ControlFile)
+    );
+    assert_eq!(
+        Synthetic::from_path(".regfs\\stats".as_ref()),
+        Some(Synthetic::StatsFile)
+    );
+    assert_eq!(Synthetic::from_path(".regfs\\control\\x".as_ref()), None);
+    assert_eq!(Synthetic::from_path("HKEY_USERS\\.regfs".as_ref()), None);
+    assert_eq!(Synthetic::from_path("".as_ref()), None);
+    assert_eq!(
+        Synthetic::from_path("HKEY_USERS\\S-1-5-18\\_values.json".as_ref()),
+        Some(Synthetic::ValuesJson)
+    );
+    assert_eq!(Synthetic::from_path("_values.json".as_ref()), None);
+    assert_eq!(
+        Synthetic::from_path("HKEY_LOCAL_MACHINE\\SOFTWARE\\App\\__META__.json".as_ref()),
+        Some(Synthetic::MetaJson)
+    );
+    assert_eq!(
+        Synthetic::from_path("HKEY_LOCAL_MACHINE\\__HIVE__.json".as_ref()),
+        Some(Synthetic::HiveSummary)
+    );
+    assert_eq!(Synthetic::from_path("__hive__.json".as_ref()), None);
+    assert_eq!(
+        Synthetic::from_path("HKEY_CLASSES_ROOT\\__truncated__ (299990 more entries)".as_ref()),
+        Some(Synthetic::Truncated)
+    );
+    assert_eq!(
+        Synthetic::from_path("HKEY_CLASSES_ROOT\\__truncated__ (+5 more entries)".as_ref()),
+        None
+    );
+    assert_eq!(truncated_marker(12), "__truncated__ (12 more entries)");
+    assert_eq!(truncated_count(&truncated_marker(12)), Some(12));
+    assert_eq!(
+        Synthetic::from_path("HKEY_LOCAL_MACHINE\\SOFTWARE\\__hive__.json".as_ref()),
+        None
+    );
+    assert_eq!(
+        Synthetic::from_path("_SEARCH".as_ref()),
+        Some(Synthetic::SearchDir)
+    );
+    assert_eq!(
+        Synthetic::from_path("_search\\Adobe".as_ref()),
+        Some(Synthetic::SearchQuery)
+    );
+    assert_eq!(
+        Synthetic::from_path("_search\\Adobe\\HKEY_CURRENT_USER~Software~Adobe".as_ref()),
+        Some(Synthetic::SearchHit)
+    );
+    assert_eq!(Synthetic::from_path("_search\\Adobe\\x\\y".as_ref()), None);
+    assert_eq!(
+        Synthetic::from_path("_recent".as_ref()),
+        Some(Synthetic::RecentDir)
+    );
+    assert_eq!(
+        Synthetic::from_path("_recent\\9999999998~HKEY_USERS~x".as_ref()),
+        Some(Synthetic::RecentChange)
+    );
+    assert_eq!(Synthetic::from_path("_recent\\a\\b".as_ref()), None);
+}
+
+#[test]
+fn test_attributes() {
+    let hidden = FILE_ATTRIBUTE_HIDDEN;
+    for (path, attributes) in [
+        (".regfs", hidden | FILE_ATTRIBUTE_SYSTEM),
+        (".regfs\\control", hidden),
+        (".regfs\\stats", hidden),
+        ("HKEY_USERS\\S-1-5-18\\_values.json", hidden),
+        ("HKEY_LOCAL_MACHINE\\__hive__.json", hidden),
+        ("HKEY_LOCAL_MACHINE\\SOFTWARE\\App\\__meta__.json", hidden),
+        ("HKEY_CLASSES_ROOT\\__truncated__ (5 more entries)", hidden),
+        ("_search", hidden),
+        ("_search\\Adobe", hidden),
+        ("_search\\Adobe\\HKEY_CURRENT_USER~Software~Adobe", hidden),
+        ("_recent", hidden),
+        ("_recent\\9999999998~HKEY_USERS~x", hidden),
+    ] {
+        let synthetic = Synthetic::from_path(path.as_ref()).unwrap();
+        assert_eq!(synthetic.attributes(), attributes, "{}", path);
+    }
+}
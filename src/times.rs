@@ -0,0 +1,100 @@
+use anyhow::{anyhow, Error, Result};
+use std::{
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// 100ns intervals between 1601-01-01 and 1970-01-01
+const UNIX_EPOCH_AS_FILETIME: i64 = 116_444_736_000_000_000;
+
+// registry values have no timestamps of their own
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ValueTimes {
+    #[default]
+    Parent,
+    Mount,
+    None,
+}
+
+impl FromStr for ValueTimes {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        match text {
+            "parent" => Ok(ValueTimes::Parent),
+            "mount" => Ok(ValueTimes::Mount),
+            "none" => Ok(ValueTimes::None),
+            _ => Err(anyhow!(
+                "invalid value times [{}], expected parent, mount or none",
+                text
+            )),
+        }
+    }
+}
+
+impl ValueTimes {
+    pub fn key_time(self, last_write_time: i64) -> i64 {
+        match self {
+            ValueTimes::None => 0,
+            ValueTimes::Parent | ValueTimes::Mount => last_write_time,
+        }
+    }
+
+    pub fn value_time(self, parent_last_write_time: i64, mount_time: i64) -> i64 {
+        match self {
+            ValueTimes::Parent => parent_last_write_time,
+            ValueTimes::Mount => mount_time,
+            ValueTimes::None => 0,
+        }
+    }
+}
+
+pub fn to_filetime(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => UNIX_EPOCH_AS_FILETIME + (since.as_nanos() / 100) as i64,
+        Err(_) => 0,
+    }
+}
+
+pub fn from_parts(low: u32, high: u32) -> i64 {
+    ((high as i64) << 32) | low as i64
+}
+
+pub fn set_times(info: &mut prjfs::sys::PRJ_FILE_BASIC_INFO, time: i64) {
+    unsafe {
+        *info.CreationTime.QuadPart_mut() = time;
+        *info.LastAccessTime.QuadPart_mut() = time;
+        *info.LastWriteTime.QuadPart_mut() = time;
+        *info.ChangeTime.QuadPart_mut() = time;
+    }
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!("parent".parse::<ValueTimes>().unwrap(), ValueTimes::Parent);
+    assert_eq!("mount".parse::<ValueTimes>().unwrap(), ValueTimes::Mount);
+    assert_eq!("none".parse::<ValueTimes>().unwrap(), ValueTimes::None);
+    assert!("key".parse::<ValueTimes>().is_err());
+}
+
+#[test]
+fn test_emitted_filetimes() {
+    let parent = from_parts(0x89ab_cdef, 0x01d9_0000);
+    let mount = to_filetime(UNIX_EPOCH + std::time::Duration::from_secs(1));
+    assert_eq!(mount, UNIX_EPOCH_AS_FILETIME + 10_000_000);
+
+    for (policy, value, key) in [
+        (ValueTimes::Parent, parent, parent),
+        (ValueTimes::Mount, mount, parent),
+        (ValueTimes::None, 0, 0),
+    ] {
+        let mut info = prjfs::sys::PRJ_FILE_BASIC_INFO::default();
+        set_times(&mut info, policy.value_time(parent, mount));
+        unsafe {
+            assert_eq!(*info.CreationTime.QuadPart(), value);
+            assert_eq!(*info.LastWriteTime.QuadPart(), value);
+            assert_eq!(*info.ChangeTime.QuadPart(), value);
+        }
+        assert_eq!(policy.key_time(parent), key);
+    }
+}
@@ -0,0 +1,764 @@
+use anyhow::{anyhow, Context, Result};
+use log::{info, warn};
+use prjfs::conv::WStrExt;
+use prjfs::ProviderT;
+use serde_json::{json, Value};
+use std::{
+    ffi::OsString,
+    fmt,
+    fs::{self, File},
+    io::{LineWriter, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use winapi::{
+    shared::{
+        guiddef::GUID,
+        winerror::{self, HRESULT_FROM_WIN32, S_OK},
+    },
+    um::{
+        projectedfslib::{PRJ_CALLBACK_DATA, PRJ_NOTIFICATION_PARAMETERS},
+        winnt::HRESULT,
+    },
+};
+
+use crate::error::RegFsError;
+use crate::options::RegFsOptions;
+use crate::regfile::{from_hex_string, hex_string};
+use crate::regfs::{wstr_or_empty, RegFs};
+
+// what a callback was asked, besides the path, process and flags every one of them gets
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CallKind {
+    StartDirEnum {
+        enumeration: Vec<u8>,
+    },
+    EndDirEnum {
+        enumeration: Vec<u8>,
+    },
+    GetDirEnum {
+        enumeration: Vec<u8>,
+        search: String,
+    },
+    GetPlaceholderInfo,
+    GetFileData {
+        stream: Vec<u8>,
+        offset: u64,
+        length: u32,
+    },
+    Notify {
+        is_directory: bool,
+        notification: u32,
+        destination: String,
+    },
+    QueryFileName,
+}
+
+impl CallKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CallKind::StartDirEnum { .. } => "start_dir_enum",
+            CallKind::EndDirEnum { .. } => "end_dir_enum",
+            CallKind::GetDirEnum { .. } => "get_dir_enum",
+            CallKind::GetPlaceholderInfo => "get_placeholder_info",
+            CallKind::GetFileData { .. } => "get_file_data",
+            CallKind::Notify { .. } => "notify",
+            CallKind::QueryFileName => "query_file_name",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Call {
+    pub kind: CallKind,
+    pub path: String,
+    pub process: String,
+    pub flags: u32,
+}
+
+impl Call {
+    pub fn of(data: &PRJ_CALLBACK_DATA, kind: CallKind) -> Self {
+        Call {
+            kind,
+            path: wstr_or_empty(data.FilePathName).to_string_lossy().into(),
+            process: wstr_or_empty(data.TriggeringProcessImageFileName)
+                .to_string_lossy()
+                .into(),
+            flags: data.Flags,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub name: String,
+    pub is_directory: bool,
+    pub size: i64,
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.is_directory {
+            true => write!(f, "{}\\", self.name),
+            false => write!(f, "{} ({})", self.name, self.size),
+        }
+    }
+}
+
+// only what the callback had to answer is set
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub hr: HRESULT,
+    pub size: Option<u64>,
+    pub is_directory: Option<bool>,
+    // what fit in the enumeration buffer this time
+    pub entries: Option<Vec<TraceEntry>>,
+    // base64 of the file content, left out of redacted traces
+    pub data: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub seq: u64,
+    pub call: Call,
+    pub response: Response,
+}
+
+impl Record {
+    // one flat object per line, e.g. {"seq":3,"callback":"get_file_data","path":..,"hr":0,..}
+    pub fn to_json(&self, redact: bool) -> Value {
+        let mut json = json!({
+            "seq": self.seq,
+            "callback": self.call.kind.name(),
+            "path": self.call.path,
+            "process": self.call.process,
+            "flags": self.call.flags,
+            "hr": self.response.hr,
+        });
+
+        match &self.call.kind {
+            CallKind::StartDirEnum { enumeration } | CallKind::EndDirEnum { enumeration } => {
+                json["enumeration"] = json!(hex_string(enumeration));
+            }
+            CallKind::GetDirEnum {
+                enumeration,
+                search,
+            } => {
+                json["enumeration"] = json!(hex_string(enumeration));
+                json["search"] = json!(search);
+            }
+            CallKind::GetFileData {
+                stream,
+                offset,
+                length,
+            } => {
+                json["stream"] = json!(hex_string(stream));
+                json["offset"] = json!(offset);
+                json["length"] = json!(length);
+            }
+            CallKind::Notify {
+                is_directory,
+                notification,
+                destination,
+            } => {
+                json["is_directory"] = json!(is_directory);
+                json["notification"] = json!(notification);
+                json["destination"] = json!(destination);
+            }
+            CallKind::GetPlaceholderInfo | CallKind::QueryFileName => {}
+        }
+
+        let response = &self.response;
+        if let Some(size) = response.size {
+            json["size"] = json!(size);
+        }
+        if let Some(is_directory) = response.is_directory {
+            json["directory"] = json!(is_directory);
+        }
+        if let Some(entries) = &response.entries {
+            let entries: Vec<Value> = entries
+                .iter()
+                .map(|entry| json!([entry.name, entry.is_directory, entry.size]))
+                .collect();
+            json["entries"] = json!(entries);
+        }
+        if let (Some(data), false) = (&response.data, redact) {
+            json["data"] = json!(data);
+        }
+        json
+    }
+
+    pub fn from_json(json: &Value) -> Result<Self> {
+        let text = |field: &str| {
+            json[field]
+                .as_str()
+                .map(String::from)
+                .ok_or_else(|| anyhow!("missing [{}]", field))
+        };
+        let number = |field: &str| {
+            json[field]
+                .as_u64()
+                .ok_or_else(|| anyhow!("missing [{}]", field))
+        };
+        let bytes = |field: &str| {
+            from_hex_string(&text(field)?).ok_or_else(|| anyhow!("invalid [{}]", field))
+        };
+
+        let kind = match text("callback")?.as_str() {
+            "start_dir_enum" => CallKind::StartDirEnum {
+                enumeration: bytes("enumeration")?,
+            },
+            "end_dir_enum" => CallKind::EndDirEnum {
+                enumeration: bytes("enumeration")?,
+            },
+            "get_dir_enum" => CallKind::GetDirEnum {
+                enumeration: bytes("enumeration")?,
+                search: text("search")?,
+            },
+            "get_placeholder_info" => CallKind::GetPlaceholderInfo,
+            "get_file_data" => CallKind::GetFileData {
+                stream: bytes("stream")?,
+                offset: number("offset")?,
+                length: number("length")? as u32,
+            },
+            "notify" => CallKind::Notify {
+                is_directory: json["is_directory"].as_bool().unwrap_or(false),
+                notification: number("notification")? as u32,
+                destination: text("destination")?,
+            },
+            "query_file_name" => CallKind::QueryFileName,
+            callback => return Err(anyhow!("unknown callback [{}]", callback)),
+        };
+
+        let entries = match json["entries"].as_array() {
+            Some(entries) => Some(
+                entries
+                    .iter()
+                    .map(
+                        |entry| match (entry[0].as_str(), entry[1].as_bool(), entry[2].as_i64()) {
+                            (Some(name), Some(is_directory), Some(size)) => Ok(TraceEntry {
+                                name: name.into(),
+                                is_directory,
+                                size,
+                            }),
+                            _ => Err(anyhow!("invalid entry {}", entry)),
+                        },
+                    )
+                    .collect::<Result<Vec<_>>>()?,
+            ),
+            None => None,
+        };
+
+        Ok(Record {
+            seq: number("seq")?,
+            call: Call {
+                kind,
+                path: text("path")?,
+                process: text("process").unwrap_or_default(),
+                flags: json["flags"].as_u64().unwrap_or(0) as u32,
+            },
+            response: Response {
+                hr: json["hr"].as_i64().ok_or_else(|| anyhow!("missing [hr]"))? as HRESULT,
+                size: json["size"].as_u64(),
+                is_directory: json["directory"].as_bool(),
+                entries,
+                data: json["data"].as_str().map(String::from),
+            },
+        })
+    }
+}
+
+// --record: every callback and what it answered, one JSON line each
+#[derive(Debug)]
+pub struct Recorder {
+    out: Mutex<LineWriter<File>>,
+    seq: AtomicU64,
+    redact: bool,
+}
+
+impl Recorder {
+    pub fn create(path: &Path, redact: bool) -> Result<Self> {
+        let file = File::create(path).with_context(|| format!("unable to create {:?}", path))?;
+        Ok(Recorder {
+            out: Mutex::new(LineWriter::new(file)),
+            seq: AtomicU64::new(0),
+            redact,
+        })
+    }
+
+    fn record(&self, call: Call, response: Response) {
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        // numbered under the lock so the file is in order
+        let record = Record {
+            seq: self.seq.fetch_add(1, Ordering::Relaxed) + 1,
+            call,
+            response,
+        };
+        if let Err(e) = writeln!(out, "{}", record.to_json(self.redact)) {
+            warn!(target: "trace", "unable to record callback {}: {}", record.seq, e);
+        }
+    }
+}
+
+// the side of a replay the RegFs talks to while a recorded callback runs again
+#[derive(Debug, Default)]
+pub struct Replayer {
+    response: Mutex<Option<Response>>,
+    // how many entries the recorded enumeration buffer took
+    capacity: AtomicUsize,
+}
+
+// where a RegFs reports the callbacks it answered
+#[derive(Debug)]
+pub enum Tracer {
+    Record(Recorder),
+    // the responses are compared to a trace instead
+    Replay(Replayer),
+}
+
+impl Tracer {
+    pub fn record(&self, call: Call, response: Response) {
+        match self {
+            Tracer::Record(recorder) => recorder.record(call, response),
+            Tracer::Replay(replayer) => {
+                *replayer.response.lock().unwrap_or_else(|e| e.into_inner()) = Some(response)
+            }
+        }
+    }
+
+    // stands in for PrjFillDirEntryBuffer in a dry run, `served` entries in
+    pub fn fill_dir_entry(&self, served: usize) -> HRESULT {
+        match self {
+            Tracer::Replay(replayer) if served >= replayer.capacity.load(Ordering::Relaxed) => {
+                HRESULT_FROM_WIN32(winerror::ERROR_INSUFFICIENT_BUFFER)
+            }
+            _ => S_OK,
+        }
+    }
+}
+
+pub fn hresult_of(result: &Result<HRESULT, RegFsError>) -> HRESULT {
+    match result {
+        Ok(hr) => *hr,
+        Err(e) => e.to_hresult(),
+    }
+}
+
+// the other half of prjfs::guid::guid_to_bytes
+fn guid(bytes: &[u8]) -> GUID {
+    let mut guid = GUID::default();
+    if bytes.len() == std::mem::size_of::<GUID>() {
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                &mut guid as *mut GUID as *mut u8,
+                bytes.len(),
+            );
+        }
+    }
+    guid
+}
+
+pub fn read(path: &Path) -> Result<Vec<Record>> {
+    let text = fs::read_to_string(path).with_context(|| format!("unable to read {:?}", path))?;
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str(line)
+                .map_err(anyhow::Error::from)
+                .and_then(|json| Record::from_json(&json))
+                .with_context(|| format!("{:?}, line {}", path, index + 1))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub seq: u64,
+    pub callback: &'static str,
+    pub path: String,
+    pub field: &'static str,
+    pub recorded: String,
+    pub replayed: String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "#{} {} [{}]: {} was {}, now {}",
+            self.seq, self.callback, self.path, self.field, self.recorded, self.replayed
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ReplayReport {
+    pub calls: usize,
+    pub divergences: Vec<Divergence>,
+}
+
+impl fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} callbacks replayed, {} divergences",
+            self.calls,
+            self.divergences.len()
+        )
+    }
+}
+
+fn describe<T: fmt::Display>(value: &Option<T>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => "nothing".to_string(),
+    }
+}
+
+fn describe_entries(entries: &Option<Vec<TraceEntry>>) -> Option<String> {
+    entries.as_ref().map(|entries| {
+        let entries: Vec<String> = entries.iter().map(TraceEntry::to_string).collect();
+        format!("[{}]", entries.join(", "))
+    })
+}
+
+fn compare(record: &Record, replayed: &Response) -> Vec<Divergence> {
+    let recorded = &record.response;
+    let divergence = |field, recorded: String, replayed: String| Divergence {
+        seq: record.seq,
+        callback: record.call.kind.name(),
+        path: record.call.path.clone(),
+        field,
+        recorded,
+        replayed,
+    };
+
+    let mut divergences = Vec::new();
+    if recorded.hr != replayed.hr {
+        divergences.push(divergence(
+            "hr",
+            format!("{:08x}", recorded.hr),
+            format!("{:08x}", replayed.hr),
+        ));
+    }
+    // a field the trace doesn't have isn't compared, e.g. redacted data
+    if recorded.size.is_some() && recorded.size != replayed.size {
+        divergences.push(divergence(
+            "size",
+            describe(&recorded.size),
+            describe(&replayed.size),
+        ));
+    }
+    if recorded.is_directory.is_some() && recorded.is_directory != replayed.is_directory {
+        divergences.push(divergence(
+            "directory",
+            describe(&recorded.is_directory),
+            describe(&replayed.is_directory),
+        ));
+    }
+    if recorded.entries.is_some() && recorded.entries != replayed.entries {
+        divergences.push(divergence(
+            "entries",
+            describe(&describe_entries(&recorded.entries)),
+            describe(&describe_entries(&replayed.entries)),
+        ));
+    }
+    if recorded.data.is_some() && recorded.data != replayed.data {
+        divergences.push(divergence(
+            "data",
+            describe(&recorded.data),
+            describe(&replayed.data),
+        ));
+    }
+    divergences
+}
+
+// runs a callback the way ProjFS would have, from what the trace says it got
+fn drive(regfs: &RegFs, record: &Record) {
+    let call = &record.call;
+    let path = OsString::from(&call.path).to_wstr();
+    let process = OsString::from(&call.process).to_wstr();
+    let mut data = PRJ_CALLBACK_DATA {
+        FilePathName: path.as_ptr(),
+        TriggeringProcessImageFileName: process.as_ptr(),
+        Flags: call.flags,
+        CommandId: record.seq as i32,
+        ..Default::default()
+    };
+
+    let result = match &call.kind {
+        CallKind::StartDirEnum { enumeration } => regfs.start_dir_enum(&data, &guid(enumeration)),
+        CallKind::EndDirEnum { enumeration } => regfs.end_dir_enum(&data, &guid(enumeration)),
+        CallKind::GetDirEnum {
+            enumeration,
+            search,
+        } => {
+            let search = OsString::from(search).to_wstr();
+            regfs.get_dir_enum(
+                &data,
+                &guid(enumeration),
+                search.as_ptr(),
+                std::ptr::null_mut(),
+            )
+        }
+        CallKind::GetPlaceholderInfo => regfs.get_placeholder_info(&data),
+        CallKind::GetFileData {
+            stream,
+            offset,
+            length,
+        } => {
+            data.DataStreamId = guid(stream);
+            regfs.get_file_data(&data, *offset, *length)
+        }
+        CallKind::Notify {
+            is_directory,
+            notification,
+            destination,
+        } => {
+            let destination = OsString::from(destination).to_wstr();
+            // none of the notifications we handle read their parameters
+            let parameters: PRJ_NOTIFICATION_PARAMETERS = unsafe { std::mem::zeroed() };
+            regfs.notify(
+                &data,
+                *is_directory,
+                *notification,
+                destination.as_ptr(),
+                &parameters,
+            )
+        }
+        CallKind::QueryFileName => regfs.query_file_name(&data),
+    };
+
+    if let Err(e) = result {
+        warn!(target: "trace", "replay: #{} {}: {}", record.seq, call.kind.name(), e);
+    }
+}
+
+// `regfs replay`: the trace's callbacks run one after the other against the options'
+// backend, and every answer that isn't what was recorded is reported
+pub fn replay(mut options: RegFsOptions, records: &[Record]) -> ReplayReport {
+    info!(target: "trace", "----> replay: {} callbacks", records.len());
+    let tracer = Arc::new(Tracer::Replay(Replayer::default()));
+    options.tracer = Some(tracer.clone());
+    // completions would need ProjFS
+    options.async_callbacks = false;
+    let regfs = RegFs::new(&options);
+    let replayer = match tracer.as_ref() {
+        Tracer::Replay(replayer) => replayer,
+        Tracer::Record(_) => unreachable!(),
+    };
+
+    let mut report = ReplayReport::default();
+    for record in records {
+        let capacity = record.response.entries.as_ref().map_or(0, Vec::len);
+        replayer.capacity.store(capacity, Ordering::Relaxed);
+
+        drive(&regfs, record);
+        report.calls += 1;
+
+        let replayed = replayer
+            .response
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+        match replayed {
+            Some(replayed) => report.divergences.extend(compare(record, &replayed)),
+            None => report.divergences.push(Divergence {
+                seq: record.seq,
+                callback: record.call.kind.name(),
+                path: record.call.path.clone(),
+                field: "response",
+                recorded: format!("{:08x}", record.response.hr),
+                replayed: "nothing".to_string(),
+            }),
+        }
+    }
+
+    info!(target: "trace", "<---- replay: {}", report);
+    report
+}
+
+#[cfg(test)]
+fn session() -> Vec<Record> {
+    let record = |kind, path: &str| Record {
+        seq: 0,
+        call: Call {
+            kind,
+            path: path.into(),
+            process: "explorer.exe".into(),
+            flags: 0,
+        },
+        response: Response::default(),
+    };
+    let app = "HKEY_CURRENT_USER\\Software\\App";
+    let name = "HKEY_CURRENT_USER\\Software\\App\\Name";
+    let enumeration = vec![1; 16];
+
+    vec![
+        record(
+            CallKind::StartDirEnum {
+                enumeration: enumeration.clone(),
+            },
+            app,
+        ),
+        record(
+            CallKind::GetDirEnum {
+                enumeration: enumeration.clone(),
+                search: "*".into(),
+            },
+            app,
+        ),
+        record(
+            CallKind::GetDirEnum {
+                enumeration: enumeration.clone(),
+                search: "*".into(),
+            },
+            app,
+        ),
+        record(CallKind::EndDirEnum { enumeration }, app),
+        record(CallKind::GetPlaceholderInfo, name),
+        record(
+            CallKind::GetFileData {
+                stream: vec![2; 16],
+                offset: 0,
+                length: 6,
+            },
+            name,
+        ),
+        record(
+            CallKind::GetPlaceholderInfo,
+            "HKEY_CURRENT_USER\\Software\\Missing",
+        ),
+        record(
+            CallKind::Notify {
+                is_directory: false,
+                notification: prjfs::sys::PRJ_NOTIFICATION_PRE_DELETE,
+                destination: String::new(),
+            },
+            name,
+        ),
+        record(CallKind::QueryFileName, name),
+    ]
+}
+
+#[cfg(test)]
+fn record_session(backend: Arc<crate::memory::MemoryBackend>, redact: bool) -> String {
+    let path = std::env::temp_dir().join(format!(
+        "regfs-trace-{}-{}.jsonl",
+        std::process::id(),
+        redact
+    ));
+    let options = RegFsOptions {
+        backend: Some(backend),
+        record: Some(path.clone()),
+        record_redact: redact,
+        ..Default::default()
+    }
+    .build()
+    .unwrap();
+
+    let regfs = RegFs::new(&options);
+    for record in session() {
+        drive(&regfs, &record);
+    }
+    let text = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    text
+}
+
+#[test]
+fn test_record_and_replay() {
+    use crate::memory::MemoryBackend;
+    use crate::regfile::string_data;
+
+    let mock = Arc::new(MemoryBackend::new());
+    mock.set_value(
+        "HKEY_CURRENT_USER\\Software\\App",
+        "Name",
+        1,
+        string_data("hi"),
+    );
+
+    let text = record_session(mock.clone(), false);
+    let records: Vec<Record> = text
+        .lines()
+        .map(|line| Record::from_json(&serde_json::from_str(line).unwrap()).unwrap())
+        .collect();
+    assert_eq!(records.len(), 9);
+    assert_eq!(records[0].seq, 1);
+    let name = TraceEntry {
+        name: "Name".into(),
+        is_directory: false,
+        size: 6,
+    };
+    assert_eq!(records[1].response.entries, Some(vec![name]));
+    assert_eq!(records[2].response.entries, Some(vec![]));
+    assert_eq!(records[5].response.size, Some(6));
+    assert_eq!(
+        records[6].response.hr,
+        HRESULT_FROM_WIN32(winerror::ERROR_FILE_NOT_FOUND)
+    );
+    // the mount is read-only
+    assert_eq!(
+        records[7].response.hr,
+        HRESULT_FROM_WIN32(winerror::ERROR_ACCESS_DENIED)
+    );
+    for record in &records {
+        assert_eq!(Record::from_json(&record.to_json(false)).unwrap(), *record);
+    }
+
+    let options = || RegFsOptions {
+        backend: Some(mock.clone()),
+        ..Default::default()
+    };
+    let report = replay(options(), &records);
+    assert_eq!(report.calls, 9);
+    assert_eq!(report.divergences, vec![]);
+
+    // a different registry is told apart
+    mock.set_value(
+        "HKEY_CURRENT_USER\\Software\\App",
+        "Name",
+        1,
+        string_data("bye"),
+    );
+    let report = replay(options(), &records);
+    let fields: Vec<&str> = report
+        .divergences
+        .iter()
+        .map(|divergence| divergence.field)
+        .collect();
+    assert_eq!(fields, vec!["entries", "size", "size", "data"]);
+}
+
+#[test]
+fn test_redacted_trace() {
+    use crate::memory::MemoryBackend;
+
+    let mock = Arc::new(MemoryBackend::new());
+    mock.set_value(
+        "HKEY_CURRENT_USER\\Software\\App",
+        "Name",
+        1,
+        b"secret".to_vec(),
+    );
+
+    let text = record_session(mock.clone(), true);
+    assert!(!text.contains("\"data\""));
+    assert!(text.contains("\"size\":6"));
+
+    let records: Vec<Record> = text
+        .lines()
+        .map(|line| Record::from_json(&serde_json::from_str(line).unwrap()).unwrap())
+        .collect();
+    let options = RegFsOptions {
+        backend: Some(mock),
+        ..Default::default()
+    };
+    assert_eq!(replay(options, &records).divergences, vec![]);
+}
@@ -0,0 +1,273 @@
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use std::{
+    cell::Cell,
+    ffi::OsStr,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        RwLock,
+    },
+};
+
+use crate::regop::paths;
+
+// the level of everything that isn't traced, once ScopedLogger is the logger
+static BASE_LEVEL: AtomicUsize = AtomicUsize::new(NOT_INSTALLED);
+const NOT_INSTALLED: usize = usize::MAX;
+
+thread_local! {
+    static IN_SCOPE: Cell<bool> = const { Cell::new(false) };
+}
+
+fn fold(path: &Path) -> Vec<String> {
+    paths::components(path)
+        .iter()
+        .map(|part| part.to_string_lossy().to_lowercase())
+        .collect()
+}
+
+// --trace-path: callbacks on these keys and everything under them log at every level
+#[derive(Default)]
+pub struct TracePaths {
+    prefixes: RwLock<Vec<(PathBuf, Vec<String>)>>,
+}
+
+impl TracePaths {
+    pub fn new(prefixes: &[PathBuf]) -> Self {
+        let paths = TracePaths::default();
+        for prefix in prefixes {
+            paths.add(prefix);
+        }
+        paths
+    }
+
+    // false if it was traced already
+    pub fn add(&self, prefix: &Path) -> bool {
+        let folded = fold(prefix);
+        let mut prefixes = self.prefixes.write().unwrap_or_else(|e| e.into_inner());
+        if prefixes.iter().any(|(_, traced)| *traced == folded) {
+            return false;
+        }
+        prefixes.push((paths::normalize(prefix), folded));
+        elevate(true);
+        true
+    }
+
+    // false if it wasn't traced
+    pub fn remove(&self, prefix: &Path) -> bool {
+        let folded = fold(prefix);
+        let mut prefixes = self.prefixes.write().unwrap_or_else(|e| e.into_inner());
+        let before = prefixes.len();
+        prefixes.retain(|(_, traced)| *traced != folded);
+        elevate(!prefixes.is_empty());
+        prefixes.len() < before
+    }
+
+    pub fn clear(&self) {
+        self.prefixes
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        elevate(false);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.prefixes
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_empty()
+    }
+
+    pub fn list(&self) -> Vec<PathBuf> {
+        let prefixes = self.prefixes.read().unwrap_or_else(|e| e.into_inner());
+        prefixes.iter().map(|(prefix, _)| prefix.clone()).collect()
+    }
+
+    // whole components only: HKEY_CURRENT_USER\App doesn't cover HKEY_CURRENT_USER\Apple
+    pub fn covers(&self, path: &Path) -> bool {
+        let prefixes = self.prefixes.read().unwrap_or_else(|e| e.into_inner());
+        if prefixes.is_empty() {
+            return false;
+        }
+        let path = fold(path);
+        prefixes.iter().any(|(_, traced)| path.starts_with(traced))
+    }
+
+    // until the scope is dropped, whatever this thread logs is logged in full if the
+    // callback's path (or where it's being renamed to) is traced
+    pub fn enter(&self, path: &OsStr, destination: Option<&OsStr>) -> Scope {
+        let traced = self.covers(path.as_ref())
+            || destination.map_or(false, |destination| self.covers(destination.as_ref()));
+        Scope(IN_SCOPE.with(|scope| scope.replace(traced)))
+    }
+}
+
+// puts back whatever scope the thread was in before
+pub struct Scope(bool);
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        IN_SCOPE.with(|scope| scope.set(self.0));
+    }
+}
+
+// for a callback with nothing to check against
+pub fn untraced() -> Scope {
+    Scope(IN_SCOPE.with(|scope| scope.replace(false)))
+}
+
+pub fn in_scope() -> bool {
+    IN_SCOPE.with(Cell::get)
+}
+
+// records below the base level are only built at all while something is traced
+fn elevate(traced: bool) {
+    let base = BASE_LEVEL.load(Ordering::Relaxed);
+    if base == NOT_INSTALLED {
+        return;
+    }
+    match traced {
+        true => log::set_max_level(LevelFilter::Trace),
+        false => log::set_max_level(LevelFilter::iter().nth(base).unwrap_or(LevelFilter::Info)),
+    }
+}
+
+// logs through `inner` as usual, and everything `inner` would filter out as well while the
+// thread is in a traced scope
+pub struct ScopedLogger<L> {
+    inner: L,
+}
+
+impl<L: Log + 'static> ScopedLogger<L> {
+    // `level` is the one for whatever isn't traced
+    pub fn install(inner: L, level: LevelFilter) -> Result<(), SetLoggerError> {
+        log::set_boxed_logger(Box::new(ScopedLogger { inner }))?;
+        BASE_LEVEL.store(level as usize, Ordering::Relaxed);
+        log::set_max_level(level);
+        Ok(())
+    }
+}
+
+impl<L: Log> Log for ScopedLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata) || in_scope()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.inner.enabled(record.metadata()) {
+            self.inner.log(record);
+        } else if in_scope() {
+            // the inner logger would drop it, filter and all, so it's written here
+            let _ = writeln!(
+                io::stderr(),
+                "[{} {} traced] {}",
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+#[test]
+fn test_trace_paths() {
+    let paths = TracePaths::new(&["hkey_local_machine\\SOFTWARE\\MyApp\\".into()]);
+    assert_eq!(
+        paths.list(),
+        [PathBuf::from("HKEY_LOCAL_MACHINE\\SOFTWARE\\MyApp")]
+    );
+    assert!(!paths.add("HKEY_LOCAL_MACHINE\\software\\myapp".as_ref()));
+
+    for covered in [
+        "HKEY_LOCAL_MACHINE\\SOFTWARE\\MyApp",
+        "HKEY_LOCAL_MACHINE\\SOFTWARE\\MyApp\\Settings\\Theme",
+        "\\\\?\\HKEY_LOCAL_MACHINE\\software\\MYAPP\\Settings",
+    ] {
+        assert!(paths.covers(covered.as_ref()), "{}", covered);
+    }
+    for outside in [
+        "HKEY_LOCAL_MACHINE\\SOFTWARE\\MyApplication",
+        "HKEY_LOCAL_MACHINE\\SOFTWARE",
+        "HKEY_CURRENT_USER\\SOFTWARE\\MyApp",
+        "",
+    ] {
+        assert!(!paths.covers(outside.as_ref()), "{}", outside);
+    }
+
+    // a rename is traced if either end is
+    assert!(!in_scope());
+    {
+        let _scope = paths.enter(
+            OsStr::new("HKEY_CURRENT_USER\\Temp"),
+            Some(OsStr::new("HKEY_LOCAL_MACHINE\\SOFTWARE\\MyApp\\Moved")),
+        );
+        assert!(in_scope());
+        {
+            let _inner = paths.enter(OsStr::new("HKEY_CURRENT_USER\\Temp"), None);
+            assert!(!in_scope());
+        }
+        assert!(in_scope());
+    }
+    assert!(!in_scope());
+
+    assert!(paths.remove("HKEY_LOCAL_MACHINE\\SOFTWARE\\MyApp".as_ref()));
+    assert!(!paths.remove("HKEY_LOCAL_MACHINE\\SOFTWARE\\MyApp".as_ref()));
+    assert!(!paths.covers("HKEY_LOCAL_MACHINE\\SOFTWARE\\MyApp".as_ref()));
+}
+
+#[test]
+fn test_scoped_logger() {
+    use std::sync::{Arc, Mutex};
+
+    // what an env_logger set to info would let through
+    #[derive(Clone, Default)]
+    struct Info(Arc<Mutex<Vec<String>>>);
+
+    impl Log for Info {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.level() <= log::Level::Info
+        }
+        fn log(&self, record: &Record) {
+            if self.enabled(record.metadata()) {
+                self.0.lock().unwrap().push(record.args().to_string());
+            }
+        }
+        fn flush(&self) {}
+    }
+
+    let inner = Info::default();
+    let logger = ScopedLogger {
+        inner: inner.clone(),
+    };
+    let debug = |message: &str| {
+        logger.log(
+            &Record::builder()
+                .level(log::Level::Debug)
+                .args(format_args!("{}", message))
+                .build(),
+        )
+    };
+    let metadata = Metadata::builder().level(log::Level::Debug).build();
+
+    assert!(!logger.enabled(&metadata));
+    let paths = TracePaths::new(&["HKEY_CURRENT_USER\\Traced".into()]);
+    let scope = paths.enter(OsStr::new("HKEY_CURRENT_USER\\Traced\\Value"), None);
+    assert!(logger.enabled(&metadata));
+    debug("in scope");
+    drop(scope);
+    assert!(!logger.enabled(&metadata));
+
+    // what the inner logger takes goes to it, traced or not
+    logger.log(
+        &Record::builder()
+            .level(log::Level::Info)
+            .args(format_args!("info"))
+            .build(),
+    );
+    assert_eq!(*inner.0.lock().unwrap(), ["info"]);
+}
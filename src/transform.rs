@@ -0,0 +1,882 @@
+use anyhow::anyhow;
+use log::warn;
+use std::{borrow::Cow, fmt, path::Path, str::FromStr, sync::Arc};
+use thiserror::Error;
+use winapi::um::winnt::{
+    REG_BINARY, REG_DWORD, REG_DWORD_BIG_ENDIAN, REG_EXPAND_SZ, REG_MULTI_SZ, REG_QWORD, REG_SZ,
+};
+
+use crate::redact::Redacted;
+use crate::regop::paths;
+use crate::render;
+
+// projects a value as something other than its raw data
+pub trait ValueTransformer: Send + Sync {
+    // None leaves the value as it is
+    fn transform<'a>(&self, path: &Path, vtype: u32, raw: &'a [u8]) -> Option<Cow<'a, [u8]>>;
+
+    // a transformed value can only be written through the mount when this is true
+    fn invertible(&self) -> bool {
+        false
+    }
+
+    // projected bytes back to registry data, for write-back
+    fn inverse(&self, _path: &Path, _vtype: u32, _projected: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+// a path pattern, one component at a time: `*` and `?` within a component, `**` for
+// any number of them; case-insensitive like the registry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathGlob(Vec<Vec<char>>);
+
+impl PathGlob {
+    pub fn new(pattern: &str) -> PathGlob {
+        PathGlob(
+            pattern
+                .split(['\\', '/'])
+                .filter(|part| !part.is_empty())
+                .map(|part| part.to_lowercase().chars().collect())
+                .collect(),
+        )
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        let parts: Vec<Vec<char>> = paths::components(path)
+            .iter()
+            .map(|part| part.to_string_lossy().to_lowercase().chars().collect())
+            .collect();
+
+        matches_parts(&self.0, &parts)
+    }
+}
+
+fn matches_parts(pattern: &[Vec<char>], parts: &[Vec<char>]) -> bool {
+    match pattern.split_first() {
+        None => parts.is_empty(),
+        Some((first, rest)) if *first == ['*', '*'] => {
+            (0..=parts.len()).any(|skip| matches_parts(rest, &parts[skip..]))
+        }
+        Some((first, rest)) => match parts.split_first() {
+            Some((part, parts)) => wildcard(first, part) && matches_parts(rest, parts),
+            None => false,
+        },
+    }
+}
+
+fn wildcard(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, _) => name.is_empty(),
+        (Some(('*', rest)), _) => (0..=name.len()).any(|skip| wildcard(rest, &name[skip..])),
+        (Some(('?', rest)), Some((_, name))) => wildcard(rest, name),
+        (Some((p, rest)), Some((n, name))) if p == n => wildcard(rest, name),
+        _ => false,
+    }
+}
+
+#[derive(Clone)]
+struct Scoped {
+    scope: PathGlob,
+    transformer: Arc<dyn ValueTransformer>,
+}
+
+// the first scope that matches a value's path decides its transformer; string values go
+// through the --text-encoding instead, when there is one
+#[derive(Clone, Default)]
+pub struct Transformers {
+    scoped: Vec<Scoped>,
+    text: Option<TextEncoding>,
+}
+
+impl fmt::Debug for Transformers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Transformers")
+            .field(
+                "scopes",
+                &self
+                    .scoped
+                    .iter()
+                    .map(|scoped| &scoped.scope)
+                    .collect::<Vec<_>>(),
+            )
+            .field("text", &self.text)
+            .finish()
+    }
+}
+
+impl Transformers {
+    pub fn add(&mut self, scope: &str, transformer: Arc<dyn ValueTransformer>) {
+        self.scoped.push(Scoped {
+            scope: PathGlob::new(scope),
+            transformer,
+        });
+    }
+
+    pub fn set_text_encoding(&mut self, encoding: TextEncoding) {
+        self.text = Some(encoding);
+    }
+
+    pub fn text_encoding(&self) -> Option<TextEncoding> {
+        self.text
+    }
+
+    pub fn find(&self, path: &Path) -> Option<&dyn ValueTransformer> {
+        self.scoped
+            .iter()
+            .find(|scoped| scoped.scope.matches(path))
+            .map(|scoped| scoped.transformer.as_ref())
+    }
+
+    // what decides the file of a value of that type
+    pub fn find_for(&self, path: &Path, vtype: u32) -> Option<&dyn ValueTransformer> {
+        match &self.text {
+            Some(text) if is_string(vtype) => Some(text),
+            _ => self.find(path),
+        }
+    }
+
+    // nothing is ever transformed, whatever the path
+    pub fn is_empty(&self) -> bool {
+        self.text.is_none() && self.scoped.is_empty()
+    }
+
+    // whether the file might not hold the value's data as it is, and so not be its size
+    pub fn may_transform(&self, path: &Path) -> bool {
+        self.text.is_some() || self.find(path).is_some()
+    }
+
+    // which scope decides the value's transformer, for caches of what its file holds
+    pub fn scope_of(&self, path: &Path) -> Option<usize> {
+        self.scoped
+            .iter()
+            .position(|scoped| scoped.scope.matches(path))
+    }
+
+    // what the value's file holds
+    pub fn apply(&self, path: &Path, vtype: u32, raw: Vec<u8>) -> Vec<u8> {
+        let projected = self
+            .find_for(path, vtype)
+            .and_then(|transformer| transformer.transform(path, vtype, &raw))
+            .map(Cow::into_owned);
+
+        projected.unwrap_or(raw)
+    }
+}
+
+// --hex-dump: REG_BINARY values as 16 bytes per line, offset first
+pub struct HexDump;
+
+impl ValueTransformer for HexDump {
+    fn transform<'a>(&self, _path: &Path, vtype: u32, raw: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        if vtype != REG_BINARY {
+            return None;
+        }
+
+        let mut dump = String::new();
+        for (line, chunk) in raw.chunks(16).enumerate() {
+            let bytes: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+            dump += &format!("{:08x}  {}\n", line * 16, bytes.join(" "));
+        }
+        Some(Cow::Owned(dump.into_bytes()))
+    }
+
+    fn invertible(&self) -> bool {
+        true
+    }
+
+    fn inverse(&self, _path: &Path, _vtype: u32, projected: &[u8]) -> Option<Vec<u8>> {
+        let dump = std::str::from_utf8(projected).ok()?;
+        let mut raw = Vec::new();
+
+        for line in dump.lines().filter(|line| !line.trim().is_empty()) {
+            // the offset is only there for people
+            for byte in line.split_whitespace().skip(1) {
+                raw.push(u8::from_str_radix(byte, 16).ok()?);
+            }
+        }
+        Some(raw)
+    }
+}
+
+// --numbers-as-text: DWORDs and QWORDs as their decimal number and a line break, so they can
+// be edited with any text editor
+pub struct NumberText;
+
+// the text is kept for the caller, but never displayed: it's what someone typed into a value
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum NumberError {
+    #[error("no number")]
+    Empty(String),
+    #[error("not a decimal or 0x prefixed hex number")]
+    Invalid(String),
+    #[error("doesn't fit in a {1}")]
+    OutOfRange(String, &'static str),
+}
+
+// what people leave in a hand edited file: surrounding whitespace, any line ending, a BOM
+// and a `# comment` after the number
+pub fn parse_number(text: &str, vtype: u32) -> Result<u64, NumberError> {
+    let (max, type_name) = match vtype {
+        REG_QWORD => (u64::MAX, "qword"),
+        _ => (u32::MAX as u64, "dword"),
+    };
+    let number = text.trim_start_matches('\u{feff}');
+    let number = number.split('#').next().unwrap_or_default().trim();
+    if number.is_empty() {
+        return Err(NumberError::Empty(text.into()));
+    }
+
+    let (digits, radix) = match number.strip_prefix("0x").or(number.strip_prefix("0X")) {
+        Some(hex) => (hex, 16),
+        None => (number, 10),
+    };
+    // from_str_radix takes a sign, the registry has none
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return Err(NumberError::Invalid(text.into()));
+    }
+    match u64::from_str_radix(digits, radix) {
+        Ok(value) if value <= max => Ok(value),
+        _ => Err(NumberError::OutOfRange(text.into(), type_name)),
+    }
+}
+
+impl ValueTransformer for NumberText {
+    fn transform<'a>(&self, _path: &Path, vtype: u32, raw: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        let number = match (vtype, raw.len()) {
+            (REG_DWORD, 4) => u32::from_le_bytes(raw.try_into().ok()?) as u64,
+            (REG_DWORD_BIG_ENDIAN, 4) => u32::from_be_bytes(raw.try_into().ok()?) as u64,
+            (REG_QWORD, 8) => u64::from_le_bytes(raw.try_into().ok()?),
+            // anything malformed stays as it is
+            _ => return None,
+        };
+        Some(Cow::Owned(format!("{}\n", number).into_bytes()))
+    }
+
+    fn invertible(&self) -> bool {
+        true
+    }
+
+    fn inverse(&self, path: &Path, vtype: u32, projected: &[u8]) -> Option<Vec<u8>> {
+        if !matches!(vtype, REG_DWORD | REG_DWORD_BIG_ENDIAN | REG_QWORD) {
+            return Some(projected.to_vec());
+        }
+
+        let text = String::from_utf8_lossy(projected);
+        let number = match parse_number(&text, vtype) {
+            Ok(number) => number,
+            Err(e) => {
+                warn!(target: "overlay", "[{:?}]: {} in {}", path, e, Redacted(projected));
+                return None;
+            }
+        };
+        Some(match vtype {
+            REG_DWORD => (number as u32).to_le_bytes().to_vec(),
+            REG_DWORD_BIG_ENDIAN => (number as u32).to_be_bytes().to_vec(),
+            _ => number.to_le_bytes().to_vec(),
+        })
+    }
+}
+
+// --multi-sz-as-text: REG_MULTI_SZ values one string per line. Blank lines are skipped, so
+// an empty string in the list is written as `\0` (and a string that is `\0` as `\\0`); an
+// empty file is an empty list
+pub struct MultiSzText;
+
+// the empty string is `\0`; `\0` itself, `\\0` and so on get one more backslash
+fn escape_line(string: &str) -> Cow<'_, str> {
+    match string.strip_suffix('0') {
+        _ if string.is_empty() => Cow::Borrowed("\\0"),
+        Some(slashes) if !slashes.is_empty() && slashes.chars().all(|c| c == '\\') => {
+            Cow::Owned(format!("\\{}", string))
+        }
+        _ => Cow::Borrowed(string),
+    }
+}
+
+fn unescape_line(line: &str) -> &str {
+    match line.strip_suffix('0') {
+        Some("\\") => "",
+        Some(slashes) if !slashes.is_empty() && slashes.chars().all(|c| c == '\\') => &line[1..],
+        _ => line,
+    }
+}
+
+// the text a MultiSzText file holds back to its strings
+pub fn parse_multi_sz(text: &str) -> Vec<&str> {
+    let text = text.trim_start_matches('\u{feff}');
+    // what most editors add at the end
+    let text = text
+        .strip_suffix("\r\n")
+        .or(text.strip_suffix('\n'))
+        .unwrap_or(text);
+
+    text.split('\n')
+        .map(|line| line.strip_suffix('\r').unwrap_or(line))
+        .filter(|line| !line.is_empty())
+        .map(unescape_line)
+        .collect()
+}
+
+// each string NUL terminated, then the NUL that ends the list
+pub fn encode_multi_sz<S: AsRef<str>>(strings: &[S]) -> Vec<u8> {
+    let mut units = Vec::new();
+    for string in strings {
+        units.extend(string.as_ref().encode_utf16());
+        units.push(0);
+    }
+    units.push(0);
+
+    units.iter().flat_map(|unit| unit.to_le_bytes()).collect()
+}
+
+impl ValueTransformer for MultiSzText {
+    fn transform<'a>(&self, _path: &Path, vtype: u32, raw: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        if vtype != REG_MULTI_SZ || raw.len() % 2 != 0 {
+            return None;
+        }
+
+        let mut text = String::new();
+        for string in render::decode_multi_sz(raw) {
+            text += &escape_line(&string);
+            text.push('\n');
+        }
+        Some(Cow::Owned(text.into_bytes()))
+    }
+
+    fn invertible(&self) -> bool {
+        true
+    }
+
+    fn inverse(&self, path: &Path, vtype: u32, projected: &[u8]) -> Option<Vec<u8>> {
+        if vtype != REG_MULTI_SZ {
+            return Some(projected.to_vec());
+        }
+
+        match std::str::from_utf8(projected) {
+            Ok(text) => Some(encode_multi_sz(&parse_multi_sz(text))),
+            Err(e) => {
+                warn!(target: "overlay", "[{:?}] is not UTF-8: {}", path, e);
+                None
+            }
+        }
+    }
+}
+
+fn is_string(vtype: u32) -> bool {
+    matches!(vtype, REG_SZ | REG_EXPAND_SZ | REG_MULTI_SZ)
+}
+
+// --text-encoding: REG_SZ, REG_EXPAND_SZ and REG_MULTI_SZ values as text files, without the
+// NUL that ends them. UTF-16 is the data as the registry keeps it behind a BOM; what's
+// written back is read as either one, by its BOM, and as the mount's without one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextEncoding {
+    Utf8,
+    Utf16,
+}
+
+const UTF8_BOM: &[u8] = &[0xef, 0xbb, 0xbf];
+const UTF16_BOM: &[u8] = &[0xff, 0xfe];
+
+impl FromStr for TextEncoding {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> anyhow::Result<Self> {
+        match text {
+            "utf8" => Ok(TextEncoding::Utf8),
+            "utf16" => Ok(TextEncoding::Utf16),
+            _ => Err(anyhow!(
+                "invalid text encoding [{}], expected utf8 or utf16",
+                text
+            )),
+        }
+    }
+}
+
+impl fmt::Display for TextEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TextEncoding::Utf8 => write!(f, "utf8"),
+            TextEncoding::Utf16 => write!(f, "utf16"),
+        }
+    }
+}
+
+impl TextEncoding {
+    // the UTF-16 units a file holds, whichever encoding its BOM says it's in
+    fn decode(self, projected: &[u8]) -> Option<Vec<u16>> {
+        let (encoding, text) = match (
+            projected.strip_prefix(UTF16_BOM),
+            projected.strip_prefix(UTF8_BOM),
+        ) {
+            (Some(text), _) => (TextEncoding::Utf16, text),
+            (_, Some(text)) => (TextEncoding::Utf8, text),
+            _ => (self, projected),
+        };
+
+        match encoding {
+            TextEncoding::Utf8 => Some(std::str::from_utf8(text).ok()?.encode_utf16().collect()),
+            TextEncoding::Utf16 if text.len() % 2 == 0 => Some(render::utf16_units(text)),
+            TextEncoding::Utf16 => None,
+        }
+    }
+}
+
+impl ValueTransformer for TextEncoding {
+    fn transform<'a>(&self, _path: &Path, vtype: u32, raw: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        if !is_string(vtype) || raw.len() % 2 != 0 {
+            return None;
+        }
+        // a REG_MULTI_SZ keeps the NULs between its strings, only the list's own goes
+        let text = raw.strip_suffix(&[0, 0]).unwrap_or(raw);
+
+        match self {
+            // anything that isn't UTF-16 stays as it is
+            TextEncoding::Utf8 => Some(Cow::Owned(
+                String::from_utf16(&render::utf16_units(text))
+                    .ok()?
+                    .into_bytes(),
+            )),
+            TextEncoding::Utf16 => Some(Cow::Owned([UTF16_BOM, text].concat())),
+        }
+    }
+
+    fn invertible(&self) -> bool {
+        true
+    }
+
+    fn inverse(&self, path: &Path, vtype: u32, projected: &[u8]) -> Option<Vec<u8>> {
+        if !is_string(vtype) {
+            return Some(projected.to_vec());
+        }
+
+        let mut units = match self.decode(projected) {
+            Some(units) => units,
+            None => {
+                warn!(target: "overlay", "[{:?}] is neither UTF-8 nor UTF-16", path);
+                return None;
+            }
+        };
+        units.push(0);
+        Some(units.iter().flat_map(|unit| unit.to_le_bytes()).collect())
+    }
+}
+
+#[test]
+fn test_path_glob() {
+    let glob = PathGlob::new("HKEY_CURRENT_USER\\Software\\*\\Blob?");
+    assert!(glob.matches("hkey_current_user\\SOFTWARE\\App\\Blob1".as_ref()));
+    assert!(!glob.matches("HKEY_CURRENT_USER\\Software\\App\\Blob".as_ref()));
+    assert!(!glob.matches("HKEY_CURRENT_USER\\Software\\App\\Sub\\Blob1".as_ref()));
+
+    let glob = PathGlob::new("HKEY_LOCAL_MACHINE\\**\\*.bin");
+    assert!(glob.matches("HKEY_LOCAL_MACHINE\\x.bin".as_ref()));
+    assert!(glob.matches("HKEY_LOCAL_MACHINE\\a\\b\\c\\x.BIN".as_ref()));
+    assert!(!glob.matches("HKEY_USERS\\x.bin".as_ref()));
+}
+
+#[test]
+fn test_hex_dump_round_trip() {
+    let raw: Vec<u8> = (0..20).collect();
+    let path = Path::new("HKEY_CURRENT_USER\\Blob");
+
+    let dump = HexDump.transform(path, REG_BINARY, &raw).unwrap();
+    assert_eq!(
+        std::str::from_utf8(&dump).unwrap(),
+        "00000000  00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f\n\
+         00000010  10 11 12 13\n"
+    );
+    assert_eq!(HexDump.inverse(path, REG_BINARY, &dump).unwrap(), raw);
+    assert!(HexDump.transform(path, 1, &raw).is_none());
+
+    let mut transformers = Transformers::default();
+    transformers.add("HKEY_CURRENT_USER\\Blob", Arc::new(HexDump));
+    assert_eq!(transformers.apply(path, REG_BINARY, raw.clone()), *dump);
+    assert_eq!(
+        transformers.apply("HKEY_CURRENT_USER\\Other".as_ref(), REG_BINARY, raw.clone()),
+        raw
+    );
+}
+
+#[test]
+fn test_parse_number() {
+    let dword = |text: &str| parse_number(text, REG_DWORD);
+
+    for text in [
+        "30",
+        "0x1e",
+        "0X1E",
+        "  30\r\n",
+        "30\n",
+        "\t30 ",
+        "30 # comment",
+        "30# comment # more",
+        "0x1E  # hex\r\n",
+        "\u{feff}30\r\n",
+        "030",
+    ] {
+        assert_eq!(dword(text), Ok(30), "{:?}", text);
+    }
+    assert_eq!(dword("0"), Ok(0));
+    assert_eq!(dword("4294967295"), Ok(u32::MAX as u64));
+    assert_eq!(dword("0xffffffff"), Ok(u32::MAX as u64));
+    assert_eq!(
+        parse_number("18446744073709551615", REG_QWORD),
+        Ok(u64::MAX)
+    );
+    assert_eq!(
+        parse_number("0x100000000", REG_QWORD),
+        Ok(u32::MAX as u64 + 1)
+    );
+
+    for text in ["", "  \r\n", "# only a comment"] {
+        assert_eq!(dword(text), Err(NumberError::Empty(text.into())));
+    }
+    for text in [
+        "-1", "+30", "30 31", "3 0", "1e3", "30.0", "0x", "0x-1", "x1e", "0b11", "thirty", "1_000",
+        "0x1g", "30;",
+    ] {
+        assert_eq!(
+            dword(text),
+            Err(NumberError::Invalid(text.into())),
+            "{:?}",
+            text
+        );
+    }
+    for text in ["4294967296", "0x100000000", "99999999999999999999"] {
+        assert_eq!(
+            dword(text),
+            Err(NumberError::OutOfRange(text.into(), "dword"))
+        );
+    }
+    assert_eq!(
+        parse_number("18446744073709551616", REG_QWORD),
+        Err(NumberError::OutOfRange(
+            "18446744073709551616".into(),
+            "qword"
+        ))
+    );
+}
+
+#[test]
+fn test_number_text_round_trip() {
+    let path = Path::new("HKEY_CURRENT_USER\\Timeout");
+
+    for (vtype, raw) in [
+        (REG_DWORD, 30u32.to_le_bytes().to_vec()),
+        (REG_DWORD, u32::MAX.to_le_bytes().to_vec()),
+        (REG_DWORD_BIG_ENDIAN, 30u32.to_be_bytes().to_vec()),
+        (REG_QWORD, u64::MAX.to_le_bytes().to_vec()),
+    ] {
+        let text = NumberText.transform(path, vtype, &raw).unwrap();
+        assert_eq!(NumberText.inverse(path, vtype, &text).unwrap(), raw);
+    }
+    assert_eq!(
+        &*NumberText
+            .transform(path, REG_DWORD, &[30, 0, 0, 0])
+            .unwrap(),
+        b"30\n"
+    );
+    assert_eq!(
+        NumberText.inverse(path, REG_DWORD, b"0x1e # was 20\r\n"),
+        Some(vec![30, 0, 0, 0])
+    );
+    assert_eq!(NumberText.inverse(path, REG_DWORD, b"4294967296"), None);
+
+    // malformed data and other types are left alone
+    assert!(NumberText.transform(path, REG_DWORD, &[1, 2]).is_none());
+    assert!(NumberText.transform(path, REG_BINARY, &[1, 2]).is_none());
+    assert_eq!(
+        NumberText.inverse(path, REG_BINARY, b"30"),
+        Some(b"30".to_vec())
+    );
+}
+
+#[test]
+fn test_multi_sz_text() {
+    let path = Path::new("HKEY_CURRENT_USER\\Paths");
+    let inverse = |text: &str| {
+        let raw = MultiSzText
+            .inverse(path, REG_MULTI_SZ, text.as_bytes())
+            .unwrap();
+        render::decode_multi_sz(&raw)
+    };
+
+    // Unix and Windows line endings, with or without the last one
+    for text in ["a\nb\n", "a\nb", "a\r\nb\r\n", "a\r\nb", "a\r\n\r\nb\n"] {
+        assert_eq!(inverse(text), ["a", "b"], "{:?}", text);
+    }
+    assert_eq!(
+        MultiSzText.inverse(path, REG_MULTI_SZ, b"a\nb\n").unwrap(),
+        [b'a', 0, 0, 0, b'b', 0, 0, 0, 0, 0]
+    );
+
+    // an empty file is an empty list, only the terminating NUL
+    assert_eq!(
+        MultiSzText.inverse(path, REG_MULTI_SZ, b"").unwrap(),
+        [0, 0]
+    );
+    assert!(inverse("\n").is_empty());
+    assert_eq!(inverse("\\0\n"), [""]);
+    assert_eq!(inverse("a\n\\0\nb\n"), ["a", "", "b"]);
+    assert_eq!(inverse("\\\\0\n\\\\\\0\n0\n"), ["\\0", "\\\\0", "0"]);
+    assert_eq!(inverse("\u{feff}C:\\Windows\r\n"), ["C:\\Windows"]);
+    assert_eq!(inverse("Grüße\n日本語\n🎉\n"), ["Grüße", "日本語", "🎉"]);
+    assert!(MultiSzText
+        .inverse(path, REG_MULTI_SZ, &[b'a', 0xff, b'\n'])
+        .is_none());
+
+    for strings in [
+        vec!["a", "", "b"],
+        vec!["", "\\0", "\\\\0", "0", "\\"],
+        vec!["C:\\Windows", "\\\\server\\share", "Grüße 🎉"],
+        vec![],
+    ] {
+        let raw = encode_multi_sz(&strings);
+        let text = MultiSzText.transform(path, REG_MULTI_SZ, &raw).unwrap();
+        assert_eq!(
+            MultiSzText.inverse(path, REG_MULTI_SZ, &text).unwrap(),
+            raw,
+            "{:?}",
+            strings
+        );
+    }
+    assert_eq!(
+        &*MultiSzText
+            .transform(path, REG_MULTI_SZ, &encode_multi_sz(&["a", "", "b"]))
+            .unwrap(),
+        b"a\n\\0\nb\n"
+    );
+    assert!(MultiSzText.transform(path, REG_BINARY, &[0, 0]).is_none());
+}
+
+// (name, type, data) of every value the render goldens cover
+#[cfg(test)]
+fn golden_fixtures() -> Vec<(&'static str, u32, Vec<u8>)> {
+    use winapi::um::winnt::{REG_EXPAND_SZ, REG_NONE, REG_SZ};
+
+    let utf16 = |text: &str| -> Vec<u8> {
+        text.encode_utf16()
+            .flat_map(|unit| unit.to_le_bytes())
+            .collect()
+    };
+    vec![
+        (
+            "sz",
+            REG_SZ,
+            utf16("Hello, world! Grüße aus der Registry\0"),
+        ),
+        (
+            "expand_sz",
+            REG_EXPAND_SZ,
+            utf16("%SystemRoot%\\System32;%USERPROFILE%\\bin\0"),
+        ),
+        ("dword", REG_DWORD, 0xdeadbeefu32.to_le_bytes().to_vec()),
+        ("dword_short", REG_DWORD, vec![1, 2]),
+        (
+            "dword_big_endian",
+            REG_DWORD_BIG_ENDIAN,
+            256u32.to_be_bytes().to_vec(),
+        ),
+        ("qword", REG_QWORD, u64::MAX.to_le_bytes().to_vec()),
+        (
+            "multi_sz",
+            REG_MULTI_SZ,
+            utf16("first string\0\0\\0\0C:\\Program Files\\App\0last\0\0"),
+        ),
+        ("multi_sz_empty", REG_MULTI_SZ, utf16("\0")),
+        ("binary", REG_BINARY, (0..40).collect()),
+        ("binary_empty", REG_BINARY, vec![]),
+        ("none", REG_NONE, vec![0xff, 0x00, 0x7f]),
+    ]
+}
+
+// every --*-as-text mode and --text-encoding, and none for the data as it is
+#[cfg(test)]
+fn golden_modes() -> Vec<(&'static str, Transformers)> {
+    let mode = |transformer: Arc<dyn ValueTransformer>| {
+        let mut transformers = Transformers::default();
+        transformers.add("**", transformer);
+        transformers
+    };
+    let text = |encoding| {
+        let mut transformers = Transformers::default();
+        transformers.set_text_encoding(encoding);
+        transformers
+    };
+    vec![
+        ("raw", Transformers::default()),
+        ("hex_dump", mode(Arc::new(HexDump))),
+        ("numbers_as_text", mode(Arc::new(NumberText))),
+        ("multi_sz_as_text", mode(Arc::new(MultiSzText))),
+        ("text_utf8", text(TextEncoding::Utf8)),
+        ("text_utf16", text(TextEncoding::Utf16)),
+    ]
+}
+
+// fixtures/render/<mode>/<value>; UPDATE_GOLDENS=1 rewrites them from what the code renders
+#[test]
+fn test_render_goldens() {
+    use crate::hydration::file_range;
+    use std::fs;
+
+    let goldens = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/render");
+    let update = std::env::var("UPDATE_GOLDENS").is_ok_and(|update| update == "1");
+
+    for (mode, transformers) in golden_modes() {
+        for (name, vtype, raw) in golden_fixtures() {
+            let path = Path::new("HKEY_CURRENT_USER\\Golden").join(name);
+            let golden = goldens.join(mode).join(name);
+            // what get_file_data serves
+            let rendered = transformers.apply(&path, vtype, raw);
+
+            if update {
+                fs::create_dir_all(golden.parent().unwrap()).unwrap();
+                fs::write(&golden, &rendered).unwrap();
+                continue;
+            }
+            let expected = fs::read(&golden)
+                .unwrap_or_else(|e| panic!("{:?}: {} (UPDATE_GOLDENS=1 creates it)", golden, e));
+            assert_eq!(
+                String::from_utf8_lossy(&rendered),
+                String::from_utf8_lossy(&expected),
+                "{} {}",
+                mode,
+                name
+            );
+            assert_eq!(rendered, expected, "{} {}", mode, name);
+
+            // a read of part of the file is that part of the whole rendering
+            let end = rendered.len().min(50);
+            let start = end.min(10);
+            assert_eq!(
+                file_range(&rendered, 10, 40),
+                &rendered[start..end],
+                "{} {}",
+                mode,
+                name
+            );
+            assert_eq!(file_range(&rendered, 0, u32::MAX), &rendered[..]);
+        }
+    }
+}
+
+#[test]
+fn test_text_encoding() {
+    use winapi::um::winnt::REG_SZ;
+
+    let path = Path::new("HKEY_CURRENT_USER\\Greeting");
+    let raw = crate::regfile::string_data("Grüße 🎉");
+    let utf8 = "Grüße 🎉".as_bytes();
+    let utf16 = TextEncoding::Utf16.transform(path, REG_SZ, &raw).unwrap();
+    assert_eq!(utf16[..2], *UTF16_BOM);
+    assert_eq!(utf16[2..], raw[..raw.len() - 2]);
+    assert_eq!(
+        &*TextEncoding::Utf8.transform(path, REG_SZ, &raw).unwrap(),
+        utf8
+    );
+
+    // either mount takes either encoding, by its BOM, and its own without one
+    let with_bom = [UTF8_BOM, utf8].concat();
+    for encoding in [TextEncoding::Utf8, TextEncoding::Utf16] {
+        assert_eq!(encoding.inverse(path, REG_SZ, &utf16).unwrap(), raw);
+        assert_eq!(encoding.inverse(path, REG_SZ, &with_bom).unwrap(), raw);
+        assert_eq!(encoding.inverse(path, REG_SZ, b"").unwrap(), [0, 0]);
+        assert_eq!(encoding.inverse(path, REG_BINARY, b"ab").unwrap(), b"ab");
+    }
+    assert_eq!(TextEncoding::Utf8.inverse(path, REG_SZ, utf8).unwrap(), raw);
+    assert_eq!(
+        TextEncoding::Utf16
+            .inverse(path, REG_SZ, &utf16[2..])
+            .unwrap(),
+        raw
+    );
+    assert!(TextEncoding::Utf8.inverse(path, REG_SZ, &[0xff]).is_none());
+    assert!(TextEncoding::Utf16
+        .inverse(path, REG_SZ, &[0xff, 0xfe, b'a'])
+        .is_none());
+
+    // the strings of a list stay apart, and an unpaired surrogate is left as it was
+    let list = encode_multi_sz(&["a", "b"]);
+    let text = TextEncoding::Utf8
+        .transform(path, REG_MULTI_SZ, &list)
+        .unwrap();
+    assert_eq!(&*text, b"a\0b");
+    assert_eq!(
+        TextEncoding::Utf8
+            .inverse(path, REG_MULTI_SZ, &text)
+            .unwrap(),
+        list
+    );
+    assert!(TextEncoding::Utf8
+        .transform(path, REG_SZ, &[0x00, 0xd8])
+        .is_none());
+    assert!(TextEncoding::Utf16
+        .transform(path, REG_DWORD, &[0, 0, 0, 0])
+        .is_none());
+
+    // the encoding decides string values whatever scope they're in
+    let mut transformers = Transformers::default();
+    transformers.add("**", Arc::new(HexDump));
+    transformers.set_text_encoding(TextEncoding::Utf8);
+    assert_eq!(transformers.apply(path, REG_SZ, raw.clone()), utf8);
+    assert!(transformers.find_for(path, REG_BINARY).is_some());
+    assert_eq!(transformers.apply(path, REG_BINARY, vec![1]).len(), 13);
+
+    assert_eq!(
+        "utf16".parse::<TextEncoding>().unwrap(),
+        TextEncoding::Utf16
+    );
+    assert!("utf-8".parse::<TextEncoding>().is_err());
+}
+
+#[test]
+fn test_fuzz_multi_sz_text() {
+    use crate::fuzz::{self, Rng};
+
+    let path = Path::new("HKEY_CURRENT_USER\\Fuzz");
+    let corpus = fuzz::corpus();
+    let strings = fuzz::corpus_strings();
+    let mut rng = Rng::new(fuzz::seed(), 0);
+
+    for _ in 0..fuzz::iterations() {
+        // any text is some list, any data some text, and neither grows much
+        let (seed, other) = (rng.pick(&strings), rng.pick(&strings));
+        let text = rng.text(seed, other);
+        let raw = MultiSzText
+            .inverse(path, REG_MULTI_SZ, text.as_bytes())
+            .unwrap();
+        assert!(raw.len() <= 2 * text.len() + 2, "{:?}", text);
+
+        let (seed, other) = (rng.pick(&corpus), rng.pick(&corpus));
+        let raw: Vec<u8> = rng
+            .mutate(seed, other)
+            .iter()
+            .flat_map(|unit| unit.to_le_bytes())
+            .collect();
+        let text = MultiSzText.transform(path, REG_MULTI_SZ, &raw).unwrap();
+        assert!(text.len() <= 2 * raw.len(), "{:?}", raw);
+
+        // and a list of strings comes back as it was, as long as none of them has a line break
+        // or a NUL in it, and it doesn't end in empty strings the registry would drop anyway
+        let mut list: Vec<String> = (0..rng.below(5))
+            .map(|_| {
+                let (seed, other) = (rng.pick(&strings), rng.pick(&strings));
+                rng.text(seed, other)
+                    .replace(['\n', '\r', '\0', '\u{feff}'], "")
+            })
+            .collect();
+        while list.last().is_some_and(String::is_empty) {
+            list.pop();
+        }
+        let raw = encode_multi_sz(&list);
+        let text = MultiSzText.transform(path, REG_MULTI_SZ, &raw).unwrap();
+        assert_eq!(
+            MultiSzText.inverse(path, REG_MULTI_SZ, &text).unwrap(),
+            raw,
+            "{:?}",
+            list
+        );
+    }
+}
@@ -0,0 +1,315 @@
+use std::{
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::backend::{classify_by_lookup, PathKind, RegistryBackend};
+use crate::opcontext::{Cancelled, OpContext};
+use crate::regop::{paths, KeyInfo, RegEntires, RegEntry};
+use crate::virtualstore::{merge_entries, merge_values, strip};
+use crate::watch::Watchers;
+
+const MACHINE: [&str; 3] = ["HKEY_LOCAL_MACHINE", "SOFTWARE", "Classes"];
+
+// the root a user's view of HKEY_CLASSES_ROOT is listed as
+pub fn root_name(sid: &str) -> String {
+    format!("HKEY_CLASSES_ROOT (user {})", sid)
+}
+
+// --user-classes <SID>: HKEY_CLASSES_ROOT the way that user's processes see it, their
+// HKEY_USERS\<SID>_Classes laid over HKLM\SOFTWARE\Classes, next to the hives. The real
+// HKEY_CLASSES_ROOT is that merge for whoever started the provider
+pub struct UserClasses {
+    inner: Arc<dyn RegistryBackend>,
+    root: String,
+    user: [String; 2],
+}
+
+impl UserClasses {
+    pub fn new(inner: Arc<dyn RegistryBackend>, sid: &str) -> Self {
+        UserClasses {
+            inner,
+            root: root_name(sid),
+            user: ["HKEY_USERS".into(), format!("{}_Classes", sid)],
+        }
+    }
+
+    fn under_root<'a>(&self, parts: &'a [OsString]) -> Option<&'a [OsString]> {
+        strip(parts, &[self.root.as_str()])
+    }
+
+    // the user's key and the machine's behind a path under the root
+    pub fn sources(&self, path: &Path) -> Option<(PathBuf, PathBuf)> {
+        let parts = paths::components(path);
+        let rest = self.under_root(&parts)?;
+        let join = |prefix: &[&str]| {
+            paths::join_parts(
+                prefix
+                    .iter()
+                    .map(OsStr::new)
+                    .chain(rest.iter().map(|part| part.as_os_str())),
+            )
+        };
+        let user: Vec<&str> = self.user.iter().map(String::as_str).collect();
+        Some((join(&user), join(&MACHINE)))
+    }
+
+    // and back, for a change to either
+    pub fn merged_path(&self, path: &Path) -> Option<PathBuf> {
+        let parts = paths::components(path);
+        let user: Vec<&str> = self.user.iter().map(String::as_str).collect();
+        let rest = strip(&parts, &user).or_else(|| strip(&parts, &MACHINE))?;
+        Some(paths::join_parts(
+            [OsStr::new(&self.root)]
+                .into_iter()
+                .chain(rest.iter().map(|part| part.as_os_str())),
+        ))
+    }
+
+    // the user's copy first, as HKEY_CLASSES_ROOT reads it
+    fn first<T>(
+        &self,
+        path: &Path,
+        read: impl Fn(&Path) -> Result<Option<T>, Cancelled>,
+    ) -> Result<Option<T>, Cancelled> {
+        match self.sources(path) {
+            Some((user, machine)) => match read(&user)? {
+                Some(found) => Ok(Some(found)),
+                None => read(&machine),
+            },
+            None => read(path),
+        }
+    }
+}
+
+impl RegistryBackend for UserClasses {
+    fn enumerate_key_ctx(
+        &self,
+        path: OsString,
+        ctx: &OpContext,
+    ) -> Result<Option<RegEntires>, Cancelled> {
+        if let Some((user, machine)) = self.sources(path.as_ref()) {
+            let machine = self.inner.enumerate_key_ctx(machine.into(), ctx)?;
+            let user = self.inner.enumerate_key_ctx(user.into(), ctx)?;
+            return Ok(merge_entries(machine, user).map(|(entries, _)| entries));
+        }
+
+        let mut entries = self.inner.enumerate_key_ctx(path.clone(), ctx)?;
+        if let (true, Some(entries)) = (paths::components(&path).is_empty(), &mut entries) {
+            let root = Path::new(&self.root);
+            entries.subkeys.push(RegEntry {
+                last_write_time: self.key_last_write_time(root).unwrap_or(0),
+                ..RegEntry::new(&self.root, 0)
+            });
+        }
+        Ok(entries)
+    }
+
+    fn read_typed_value_ctx(
+        &self,
+        path: &Path,
+        ctx: &OpContext,
+    ) -> Result<Option<(u32, Vec<u8>)>, Cancelled> {
+        self.first(path, |path| self.inner.read_typed_value_ctx(path, ctx))
+    }
+
+    fn read_value_ctx(&self, path: &Path, ctx: &OpContext) -> Result<Option<Vec<u8>>, Cancelled> {
+        self.first(path, |path| self.inner.read_value_ctx(path, ctx))
+    }
+
+    fn read_all_values(&self, path: &Path) -> Option<Vec<(OsString, u32, Vec<u8>)>> {
+        match self.sources(path) {
+            Some((user, machine)) => merge_values(
+                self.inner.read_all_values(&machine),
+                self.inner.read_all_values(&user),
+            ),
+            None => self.inner.read_all_values(path),
+        }
+    }
+
+    fn does_key_exist(&self, path: &Path) -> bool {
+        match self.sources(path) {
+            Some((user, machine)) => {
+                self.inner.does_key_exist(&user) || self.inner.does_key_exist(&machine)
+            }
+            None => self.inner.does_key_exist(path),
+        }
+    }
+
+    fn key_last_write_time(&self, path: &Path) -> Option<i64> {
+        let (user, machine) = match self.sources(path) {
+            Some(sources) => sources,
+            None => return self.inner.key_last_write_time(path),
+        };
+        match (
+            self.inner.key_last_write_time(&user),
+            self.inner.key_last_write_time(&machine),
+        ) {
+            (Some(user), Some(machine)) => Some(user.max(machine)),
+            (user, machine) => user.or(machine),
+        }
+    }
+
+    fn key_info(&self, path: &Path) -> Option<KeyInfo> {
+        let (user, machine) = match self.sources(path) {
+            Some(sources) => sources,
+            None => return self.inner.key_info(path),
+        };
+        if !self.inner.does_key_exist(&user) {
+            return self.inner.key_info(&machine);
+        }
+
+        let entries = self.enumerate_key(path.into())?;
+        Some(KeyInfo {
+            subkeys: entries.subkeys.len() as u32,
+            values: entries.values.len() as u32,
+            last_write_time: self.key_last_write_time(path)?,
+            ..self.inner.key_info(&machine).unwrap_or_default()
+        })
+    }
+
+    fn watchers(&self) -> Option<&Watchers> {
+        self.inner.watchers()
+    }
+
+    fn link_target(&self, path: &Path) -> Option<PathBuf> {
+        match self.sources(path) {
+            Some((user, machine)) => self
+                .inner
+                .link_target(&user)
+                .or_else(|| self.inner.link_target(&machine)),
+            None => self.inner.link_target(path),
+        }
+    }
+
+    fn writable(&self) -> bool {
+        self.inner.writable()
+    }
+
+    fn value_type(&self, path: &Path) -> Option<u32> {
+        match self.sources(path) {
+            Some((user, machine)) => self
+                .inner
+                .value_type(&user)
+                .or_else(|| self.inner.value_type(&machine)),
+            None => self.inner.value_type(path),
+        }
+    }
+
+    fn does_value_exist(&self, path: &Path) -> Option<usize> {
+        match self.sources(path) {
+            Some((user, machine)) => self
+                .inner
+                .does_value_exist(&user)
+                .or_else(|| self.inner.does_value_exist(&machine)),
+            None => self.inner.does_value_exist(path),
+        }
+    }
+
+    fn classify(&self, path: &Path, ctx: &OpContext) -> Result<PathKind, Cancelled> {
+        match self.sources(path) {
+            Some(_) => classify_by_lookup(self, path, ctx),
+            None => self.inner.classify(path, ctx),
+        }
+    }
+}
+
+#[test]
+fn test_sources() {
+    use crate::memory::MemoryBackend;
+
+    let classes = UserClasses::new(Arc::new(MemoryBackend::new()), "S-1-5-21-1001");
+    assert_eq!(
+        classes.sources("hkey_classes_root (USER s-1-5-21-1001)\\.txt".as_ref()),
+        Some((
+            PathBuf::from("HKEY_USERS\\S-1-5-21-1001_Classes\\.txt"),
+            PathBuf::from("HKEY_LOCAL_MACHINE\\SOFTWARE\\Classes\\.txt")
+        ))
+    );
+    assert_eq!(
+        classes.sources("HKEY_CLASSES_ROOT (user S-1-5-21-1001)".as_ref()),
+        Some((
+            PathBuf::from("HKEY_USERS\\S-1-5-21-1001_Classes"),
+            PathBuf::from("HKEY_LOCAL_MACHINE\\SOFTWARE\\Classes")
+        ))
+    );
+    assert_eq!(classes.sources("HKEY_CLASSES_ROOT\\.txt".as_ref()), None);
+    assert_eq!(classes.sources("".as_ref()), None);
+
+    let merged = PathBuf::from("HKEY_CLASSES_ROOT (user S-1-5-21-1001)\\.txt");
+    assert_eq!(
+        classes.merged_path("HKEY_USERS\\S-1-5-21-1001_Classes\\.txt".as_ref()),
+        Some(merged.clone())
+    );
+    assert_eq!(
+        classes.merged_path("HKEY_LOCAL_MACHINE\\Software\\Classes\\.txt".as_ref()),
+        Some(merged)
+    );
+    assert_eq!(
+        classes.merged_path("HKEY_USERS\\S-1-5-21-1002_Classes\\.txt".as_ref()),
+        None
+    );
+}
+
+#[test]
+fn test_merged_classes() {
+    use crate::memory::MemoryBackend;
+
+    let memory = Arc::new(MemoryBackend::new());
+    let machine = Path::new("HKEY_LOCAL_MACHINE\\SOFTWARE\\Classes\\RegFs.Scratch");
+    let user = Path::new("HKEY_USERS\\S-1-5-21-1001_Classes\\RegFs.Scratch");
+    memory.set_value(machine, "", 1, b"machine".to_vec());
+    memory.set_value(machine, "FriendlyTypeName", 1, b"machine".to_vec());
+    memory.set_value(
+        machine.join("shell\\open\\command"),
+        "",
+        1,
+        b"open".to_vec(),
+    );
+    memory.set_value(user, "", 1, b"user".to_vec());
+    memory.set_value(user.join("shell\\edit\\command"), "", 1, b"edit".to_vec());
+    memory.add_key("HKEY_CURRENT_USER\\Software");
+    let classes = UserClasses::new(memory.clone(), "S-1-5-21-1001");
+    let merged = Path::new("HKEY_CLASSES_ROOT (user S-1-5-21-1001)\\RegFs.Scratch");
+
+    // a root of its own, next to the others
+    let root = classes.enumerate_key("".into()).unwrap();
+    let roots: Vec<_> = root.subkeys.iter().map(|key| key.name.clone()).collect();
+    assert!(roots.contains(&"HKEY_CLASSES_ROOT (user S-1-5-21-1001)".into()));
+    assert!(roots.contains(&"HKEY_CURRENT_USER".into()));
+
+    // the user's default value wins, the rest comes from both, each once
+    let entries = classes.enumerate_key(merged.into()).unwrap();
+    assert_eq!(entries.values.len(), 2);
+    let shell = classes.enumerate_key(merged.join("shell").into()).unwrap();
+    let mut verbs: Vec<_> = shell.subkeys.iter().map(|key| key.name.clone()).collect();
+    verbs.sort();
+    assert_eq!(verbs, ["edit", "open"]);
+    assert_eq!(
+        classes.read_value(&merged.join(paths::DEFAULT_VALUE)),
+        Some(b"user".to_vec())
+    );
+    assert_eq!(
+        classes.read_value(&merged.join("FriendlyTypeName")),
+        Some(b"machine".to_vec())
+    );
+    assert_eq!(
+        classes.read_value(&merged.join("shell\\open\\command\\(default)")),
+        Some(b"open".to_vec())
+    );
+    let values = classes.read_all_values(merged).unwrap();
+    assert!(values.contains(&("".into(), 1, b"user".to_vec())));
+    assert_eq!(values.len(), 2);
+    assert_eq!(classes.key_info(merged).unwrap().subkeys, 1);
+    assert!(matches!(
+        classes.classify(&merged.join("shell\\edit"), &OpContext::none()),
+        Ok(PathKind::Key(_))
+    ));
+
+    // only in the machine's, or nowhere
+    let machine_only = merged.with_file_name("RegFs.MachineOnly");
+    memory.add_key(machine.with_file_name("RegFs.MachineOnly"));
+    assert!(classes.does_key_exist(&machine_only));
+    assert!(!classes.does_key_exist(&merged.with_file_name("RegFs.Missing")));
+}
@@ -0,0 +1,216 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use crate::metrics::Metrics;
+use crate::regfs::path_key;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValueCacheLimits {
+    // over every value kept
+    pub max_bytes: usize,
+    // bigger ones are always read again
+    pub max_value_size: usize,
+}
+
+impl Default for ValueCacheLimits {
+    fn default() -> Self {
+        ValueCacheLimits {
+            max_bytes: 16 << 20,
+            max_value_size: 64 << 10,
+        }
+    }
+}
+
+struct Cached {
+    bytes: Arc<Vec<u8>>,
+    // when it was last handed out, the least recent goes first
+    used: u64,
+}
+
+#[derive(Default)]
+struct Values {
+    // by path_key and the transformer scope the bytes were rendered with
+    values: HashMap<(String, Option<usize>), Cached>,
+    bytes: usize,
+    clock: u64,
+    // moves on every invalidation, so a read that started before one isn't kept
+    generation: u64,
+}
+
+impl Values {
+    fn remove(&mut self, key: &(String, Option<usize>)) {
+        if let Some(cached) = self.values.remove(key) {
+            self.bytes -= cached.bytes.len();
+        }
+    }
+}
+
+// --value-cache: the file content of small values, kept across hydrations until a change
+// to them is reported or written
+pub struct ValueCache {
+    limits: ValueCacheLimits,
+    values: Mutex<Values>,
+}
+
+impl ValueCache {
+    pub fn new(limits: ValueCacheLimits) -> Self {
+        ValueCache {
+            limits,
+            values: Mutex::new(Default::default()),
+        }
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.values
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .generation
+    }
+
+    pub fn get(&self, path: &Path, mode: Option<usize>, metrics: &Metrics) -> Option<Arc<Vec<u8>>> {
+        let mut guard = self.values.lock().unwrap_or_else(|e| e.into_inner());
+        let values = &mut *guard;
+        values.clock += 1;
+
+        match values.values.get_mut(&(path_key(path), mode)) {
+            Some(cached) => {
+                cached.used = values.clock;
+                Metrics::add(&metrics.value_cache_hits, 1);
+                Some(cached.bytes.clone())
+            }
+            None => {
+                Metrics::add(&metrics.value_cache_misses, 1);
+                None
+            }
+        }
+    }
+
+    // `generation` is what generation() said before the value was read
+    pub fn insert(
+        &self,
+        path: &Path,
+        mode: Option<usize>,
+        bytes: Arc<Vec<u8>>,
+        generation: u64,
+        metrics: &Metrics,
+    ) {
+        if bytes.len() > self.limits.max_value_size || bytes.len() > self.limits.max_bytes {
+            return;
+        }
+        let mut guard = self.values.lock().unwrap_or_else(|e| e.into_inner());
+        let values = &mut *guard;
+        if values.generation != generation {
+            return;
+        }
+        values.clock += 1;
+
+        let key = (path_key(path), mode);
+        values.remove(&key);
+        values.bytes += bytes.len();
+        let used = values.clock;
+        values.values.insert(key, Cached { bytes, used });
+
+        while values.bytes > self.limits.max_bytes {
+            let oldest = values
+                .values
+                .iter()
+                .min_by_key(|(_, cached)| cached.used)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(oldest) => {
+                    values.remove(&oldest);
+                    Metrics::add(&metrics.value_cache_evictions, 1);
+                }
+                None => break,
+            }
+        }
+    }
+
+    // the value at `path`, or the values of the key there and of the keys under it
+    pub fn invalidate(&self, path: &Path) {
+        let key = path_key(path);
+        let children = format!("{}\\", key);
+        let mut guard = self.values.lock().unwrap_or_else(|e| e.into_inner());
+        guard.generation += 1;
+        let stale: Vec<(String, Option<usize>)> = guard
+            .values
+            .keys()
+            .filter(|(cached, _)| *cached == key || cached.starts_with(&children))
+            .cloned()
+            .collect();
+        for stale in &stale {
+            guard.remove(stale);
+        }
+    }
+
+    pub fn clear(&self) {
+        let mut guard = self.values.lock().unwrap_or_else(|e| e.into_inner());
+        let generation = guard.generation + 1;
+        *guard = Values {
+            generation,
+            ..Default::default()
+        };
+    }
+
+    // values and bytes kept
+    pub fn sizes(&self) -> (usize, usize) {
+        let guard = self.values.lock().unwrap_or_else(|e| e.into_inner());
+        (guard.values.len(), guard.bytes)
+    }
+}
+
+#[test]
+fn test_value_cache() {
+    let cache = ValueCache::new(ValueCacheLimits {
+        max_bytes: 10,
+        max_value_size: 6,
+    });
+    let metrics = Metrics::default();
+    let app = Path::new("HKEY_CURRENT_USER\\Software\\App");
+    let bytes = |len: usize| Arc::new(vec![b'x'; len]);
+
+    assert!(cache.get(&app.join("Name"), None, &metrics).is_none());
+    cache.insert(&app.join("Name"), None, bytes(4), 0, &metrics);
+    assert_eq!(cache.get(&app.join("NAME"), None, &metrics), Some(bytes(4)));
+    // rendered another way, it's another entry
+    assert!(cache.get(&app.join("Name"), Some(0), &metrics).is_none());
+
+    // too big for one entry
+    cache.insert(&app.join("Big"), None, bytes(7), 0, &metrics);
+    assert_eq!(cache.sizes(), (1, 4));
+
+    // past max_bytes, the least recently used go
+    cache.insert(&app.join("Other"), None, bytes(4), 0, &metrics);
+    assert!(cache.get(&app.join("Name"), None, &metrics).is_some());
+    cache.insert(&app.join("Third"), None, bytes(4), 0, &metrics);
+    assert_eq!(cache.sizes(), (2, 8));
+    assert!(cache.get(&app.join("Other"), None, &metrics).is_none());
+    let snapshot = metrics.snapshot();
+    assert_eq!(
+        (
+            snapshot.value_cache_hits,
+            snapshot.value_cache_misses,
+            snapshot.value_cache_evictions
+        ),
+        (2, 3, 1)
+    );
+
+    // a change to the key drops its values, and what was read before it isn't kept
+    let generation = cache.generation();
+    cache.invalidate(app);
+    assert_eq!(cache.sizes(), (0, 0));
+    cache.insert(&app.join("Name"), None, bytes(4), generation, &metrics);
+    assert_eq!(cache.sizes(), (0, 0));
+    cache.insert(
+        &app.join("Name"),
+        None,
+        bytes(4),
+        cache.generation(),
+        &metrics,
+    );
+    cache.invalidate(&app.join("Name"));
+    assert_eq!(cache.sizes(), (0, 0));
+}
@@ -0,0 +1,264 @@
+use std::{
+    ffi::OsString,
+    io,
+    os::windows::ffi::{OsStrExt, OsStringExt},
+    path::{Component, Path, PathBuf, Prefix},
+};
+use thiserror::Error;
+use winapi::um::{
+    fileapi::{GetDriveTypeW, GetVolumeInformationW, GetVolumePathNameW},
+    winbase::{DRIVE_CDROM, DRIVE_REMOTE, DRIVE_REMOVABLE},
+    winnt::FILE_SUPPORTS_REPARSE_POINTS,
+};
+
+use crate::pipe;
+
+// what GetVolumeInformationW and GetDriveTypeW say about the volume a root is on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VolumeInfo {
+    pub filesystem: String,
+    // FILE_SUPPORTS_REPARSE_POINTS and the rest
+    pub flags: u32,
+    // DRIVE_FIXED, DRIVE_REMOTE, ...
+    pub drive_type: u32,
+}
+
+// what checking a root asks of the system, a fake in tests
+pub trait RootProbe {
+    fn volume(&self, root: &Path) -> io::Result<VolumeInfo>;
+
+    // whether `dir` has the instance stream a provider leaves on its root
+    fn is_regfs_root(&self, dir: &Path) -> bool;
+}
+
+pub struct LiveProbe;
+
+impl RootProbe for LiveProbe {
+    fn volume(&self, root: &Path) -> io::Result<VolumeInfo> {
+        let path: Vec<u16> = root.as_os_str().encode_wide().chain(Some(0)).collect();
+        let mut volume = vec![0u16; path.len() + 1];
+        if unsafe { GetVolumePathNameW(path.as_ptr(), volume.as_mut_ptr(), volume.len() as u32) }
+            == 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut name = [0u16; 261];
+        let mut flags = 0;
+        let found = unsafe {
+            GetVolumeInformationW(
+                volume.as_ptr(),
+                std::ptr::null_mut(),
+                0,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                &mut flags,
+                name.as_mut_ptr(),
+                name.len() as u32,
+            )
+        };
+        if found == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let len = name
+            .iter()
+            .position(|&unit| unit == 0)
+            .unwrap_or(name.len());
+        Ok(VolumeInfo {
+            filesystem: OsString::from_wide(&name[..len]).to_string_lossy().into(),
+            flags,
+            drive_type: unsafe { GetDriveTypeW(volume.as_ptr()) },
+        })
+    }
+
+    fn is_regfs_root(&self, dir: &Path) -> bool {
+        pipe::find_instance(dir).is_ok()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum RootError {
+    #[error("--root {path:?}: unable to look up its volume: {source}")]
+    Volume { path: PathBuf, source: io::Error },
+    #[error("--root {0:?} is on a network drive, ProjFS only virtualizes local volumes")]
+    Network(PathBuf),
+    #[error("--root {0:?} is on a removable drive, pick one on a fixed local disk")]
+    Removable(PathBuf),
+    #[error("--root {path:?} is on {filesystem}, which has no reparse points; ProjFS needs NTFS")]
+    NoReparsePoints { path: PathBuf, filesystem: String },
+    #[error("--root {path:?} is inside {outer:?}, the root of another regfs mount")]
+    Nested { path: PathBuf, outer: PathBuf },
+}
+
+// `root` as an absolute extended-length path (`\\?\C:\...` or `\\?\UNC\server\share\...`),
+// worked out from the text alone: relative to `current_dir`, forward slashes turned around
+// and `.` and `..` resolved
+pub fn canonicalize(root: &Path, current_dir: &Path) -> PathBuf {
+    let text = root.to_string_lossy().replace('/', "\\");
+    let absolute = current_dir.join(text);
+
+    let mut prefix = String::new();
+    let mut parts: Vec<OsString> = Vec::new();
+    for component in absolute.components() {
+        match component {
+            Component::Prefix(found) => {
+                prefix = match found.kind() {
+                    Prefix::Disk(letter) | Prefix::VerbatimDisk(letter) => {
+                        format!("\\\\?\\{}:", (letter as char).to_ascii_uppercase())
+                    }
+                    Prefix::UNC(server, share) | Prefix::VerbatimUNC(server, share) => format!(
+                        "\\\\?\\UNC\\{}\\{}",
+                        server.to_string_lossy(),
+                        share.to_string_lossy()
+                    ),
+                    Prefix::Verbatim(name) => format!("\\\\?\\{}", name.to_string_lossy()),
+                    Prefix::DeviceNS(name) => format!("\\\\.\\{}", name.to_string_lossy()),
+                }
+            }
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir => {
+                parts.pop();
+            }
+            Component::Normal(part) => parts.push(part.to_owned()),
+        }
+    }
+
+    let mut canonical = OsString::from(prefix);
+    if parts.is_empty() {
+        canonical.push("\\");
+    }
+    for part in parts {
+        canonical.push("\\");
+        canonical.push(part);
+    }
+    canonical.into()
+}
+
+// the root a mount goes on, canonical, or why ProjFS would fail on it later and less clearly
+pub fn resolve(
+    root: &Path,
+    current_dir: &Path,
+    probe: &dyn RootProbe,
+) -> Result<PathBuf, RootError> {
+    let path = canonicalize(root, current_dir);
+    let volume = probe.volume(&path).map_err(|source| RootError::Volume {
+        path: path.clone(),
+        source,
+    })?;
+    match volume.drive_type {
+        DRIVE_REMOTE => return Err(RootError::Network(path)),
+        DRIVE_REMOVABLE | DRIVE_CDROM => return Err(RootError::Removable(path)),
+        _ => {}
+    }
+    if volume.flags & FILE_SUPPORTS_REPARSE_POINTS == 0 {
+        return Err(RootError::NoReparsePoints {
+            path,
+            filesystem: volume.filesystem,
+        });
+    }
+    // a root of its own is fine, that's a provider mounting there again
+    if let Some(outer) = path
+        .ancestors()
+        .skip(1)
+        .find(|dir| probe.is_regfs_root(dir))
+    {
+        return Err(RootError::Nested {
+            outer: outer.to_path_buf(),
+            path,
+        });
+    }
+    Ok(path)
+}
+
+#[test]
+fn test_canonicalize() {
+    let cwd = Path::new("C:\\work\\regfs");
+    let canonical = |root: &str| canonicalize(root.as_ref(), cwd);
+
+    assert_eq!(canonical("..\\test"), Path::new("\\\\?\\C:\\work\\test"));
+    assert_eq!(
+        canonical("../test/./mnt"),
+        Path::new("\\\\?\\C:\\work\\test\\mnt")
+    );
+    assert_eq!(canonical("d:\\mnt\\"), Path::new("\\\\?\\D:\\mnt"));
+    assert_eq!(canonical("D:/"), Path::new("\\\\?\\D:\\"));
+    assert_eq!(canonical("\\\\?\\D:\\mnt"), Path::new("\\\\?\\D:\\mnt"));
+    assert_eq!(
+        canonical("\\\\server\\share\\mnt"),
+        Path::new("\\\\?\\UNC\\server\\share\\mnt")
+    );
+}
+
+#[cfg(test)]
+struct FakeProbe {
+    volume: Result<VolumeInfo, io::ErrorKind>,
+    roots: Vec<PathBuf>,
+}
+
+#[cfg(test)]
+impl RootProbe for FakeProbe {
+    fn volume(&self, _root: &Path) -> io::Result<VolumeInfo> {
+        self.volume.clone().map_err(io::Error::from)
+    }
+
+    fn is_regfs_root(&self, dir: &Path) -> bool {
+        self.roots.iter().any(|root| root == dir)
+    }
+}
+
+#[test]
+fn test_resolve() {
+    use winapi::um::winbase::DRIVE_FIXED;
+
+    let cwd = Path::new("C:\\work");
+    let ntfs = VolumeInfo {
+        filesystem: "NTFS".into(),
+        flags: FILE_SUPPORTS_REPARSE_POINTS,
+        drive_type: DRIVE_FIXED,
+    };
+    let check = |volume: Result<VolumeInfo, io::ErrorKind>, roots: &[&str]| {
+        let probe = FakeProbe {
+            volume,
+            roots: roots.iter().map(PathBuf::from).collect(),
+        };
+        resolve("mnt\\regfs".as_ref(), cwd, &probe)
+    };
+    let with = |drive_type, flags| {
+        Ok(VolumeInfo {
+            drive_type,
+            flags,
+            ..ntfs.clone()
+        })
+    };
+
+    assert_eq!(
+        check(Ok(ntfs.clone()), &[]).unwrap(),
+        Path::new("\\\\?\\C:\\work\\mnt\\regfs")
+    );
+    assert!(matches!(
+        check(with(DRIVE_REMOTE, FILE_SUPPORTS_REPARSE_POINTS), &[]),
+        Err(RootError::Network(_))
+    ));
+    assert!(matches!(
+        check(with(DRIVE_REMOVABLE, FILE_SUPPORTS_REPARSE_POINTS), &[]),
+        Err(RootError::Removable(_))
+    ));
+    let fat32 = Ok(VolumeInfo {
+        filesystem: "FAT32".into(),
+        flags: 0,
+        drive_type: DRIVE_FIXED,
+    });
+    let error = check(fat32, &[]).unwrap_err();
+    assert!(error.to_string().contains("is on FAT32"), "{}", error);
+    assert!(matches!(
+        check(Err(io::ErrorKind::NotFound), &[]),
+        Err(RootError::Volume { .. })
+    ));
+
+    // inside another mount, but not that mount itself
+    assert!(matches!(
+        check(Ok(ntfs.clone()), &["\\\\?\\C:\\work\\mnt"]),
+        Err(RootError::Nested { outer, .. }) if outer == Path::new("\\\\?\\C:\\work\\mnt")
+    ));
+    assert!(check(Ok(ntfs), &["\\\\?\\C:\\work\\mnt\\regfs"]).is_ok());
+}
@@ -0,0 +1,417 @@
+use std::{
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::backend::{classify_by_lookup, PathKind, RegistryBackend};
+use crate::opcontext::{Cancelled, OpContext};
+use crate::regop::{paths, KeyInfo, RegEntires, RegEntry};
+use crate::watch::Watchers;
+
+// legacy 32-bit apps without elevation that write under HKLM\SOFTWARE land under the
+// user's VirtualStore instead, and read it back from there first
+const MACHINE: [&str; 2] = ["HKEY_LOCAL_MACHINE", "SOFTWARE"];
+const STORE: [&str; 6] = [
+    "HKEY_CURRENT_USER",
+    "Software",
+    "Classes",
+    "VirtualStore",
+    "MACHINE",
+    "SOFTWARE",
+];
+
+pub fn strip<'a>(parts: &'a [OsString], prefix: &[&str]) -> Option<&'a [OsString]> {
+    let matches = parts.len() >= prefix.len()
+        && prefix
+            .iter()
+            .zip(parts)
+            .all(|(expected, part)| part.to_string_lossy().eq_ignore_ascii_case(expected));
+    matches.then(|| &parts[prefix.len()..])
+}
+
+// the VirtualStore path standing in for one under HKLM\SOFTWARE
+pub fn redirect(path: &Path) -> Option<PathBuf> {
+    let parts = paths::components(path);
+    let rest = strip(&parts, &MACHINE)?;
+    Some(paths::join_parts(
+        STORE
+            .iter()
+            .map(OsStr::new)
+            .chain(rest.iter().map(|part| part.as_os_str())),
+    ))
+}
+
+// and back, for a change seen under the VirtualStore
+pub fn machine_path(path: &Path) -> Option<PathBuf> {
+    let parts = paths::components(path);
+    let rest = strip(&parts, &STORE)?;
+    Some(paths::join_parts(
+        MACHINE
+            .iter()
+            .map(OsStr::new)
+            .chain(rest.iter().map(|part| part.as_os_str())),
+    ))
+}
+
+fn find<'a>(entries: &'a mut [RegEntry], name: &OsStr) -> Option<&'a mut RegEntry> {
+    let name = name.to_string_lossy().to_lowercase();
+    entries
+        .iter_mut()
+        .find(|entry| entry.name.to_string_lossy().to_lowercase() == name)
+}
+
+// what the VirtualStore did to an entry of a merged key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Merge {
+    // only there
+    Added,
+    // a value in both places, the VirtualStore one is shown
+    Overrides,
+    // a subkey in both places, listed with the entries of both
+    Combined,
+}
+
+impl Merge {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Merge::Added => "added",
+            Merge::Overrides => "overrides",
+            Merge::Combined => "merged",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergedEntry {
+    pub name: OsString,
+    pub is_key: bool,
+    pub merge: Merge,
+}
+
+// `above` laid over `below`: a value in both is the one from `above`, a subkey in both is
+// listed once. Also what `above` added to the listing
+pub fn merge_entries(
+    below: Option<RegEntires>,
+    above: Option<RegEntires>,
+) -> Option<(RegEntires, Vec<MergedEntry>)> {
+    let (mut entries, above) = match (below, above) {
+        (below, None) => return below.map(|entries| (entries, Vec::new())),
+        (below, Some(above)) => (below.unwrap_or_default(), above),
+    };
+
+    let mut merged = Vec::new();
+    for subkey in above.subkeys {
+        let merge = match find(&mut entries.subkeys, &subkey.name) {
+            Some(existing) => {
+                existing.last_write_time = existing.last_write_time.max(subkey.last_write_time);
+                Merge::Combined
+            }
+            None => {
+                entries.subkeys.push(subkey.clone());
+                Merge::Added
+            }
+        };
+        merged.push(MergedEntry {
+            name: subkey.name,
+            is_key: true,
+            merge,
+        });
+    }
+    for value in above.values {
+        let merge = match find(&mut entries.values, &value.name) {
+            Some(existing) => {
+                *existing = value.clone();
+                Merge::Overrides
+            }
+            None => {
+                entries.values.push(value.clone());
+                Merge::Added
+            }
+        };
+        merged.push(MergedEntry {
+            name: value.name,
+            is_key: false,
+            merge,
+        });
+    }
+
+    entries.partial |= above.partial;
+    entries.failed += above.failed;
+    Some((entries, merged))
+}
+
+// the same for read_all_values()
+pub fn merge_values(
+    below: Option<Vec<(OsString, u32, Vec<u8>)>>,
+    above: Option<Vec<(OsString, u32, Vec<u8>)>>,
+) -> Option<Vec<(OsString, u32, Vec<u8>)>> {
+    let (mut values, above) = match (below, above) {
+        (below, None) => return below,
+        (below, Some(above)) => (below.unwrap_or_default(), above),
+    };
+
+    for (name, vtype, data) in above {
+        let folded = name.to_string_lossy().to_lowercase();
+        values.retain(|(existing, _, _)| existing.to_string_lossy().to_lowercase() != folded);
+        values.push((name, vtype, data));
+    }
+    Some(values)
+}
+
+// --merge-virtualstore: HKLM\SOFTWARE the way those apps see it, with the VirtualStore
+// copy of each key laid over it
+pub struct VirtualStore {
+    inner: Arc<dyn RegistryBackend>,
+}
+
+impl VirtualStore {
+    pub fn new(inner: Arc<dyn RegistryBackend>) -> Self {
+        VirtualStore { inner }
+    }
+
+    // where a path is read from: its VirtualStore copy if there is one
+    pub fn resolve(&self, path: &Path) -> PathBuf {
+        match redirect(path) {
+            Some(store)
+                if self.inner.does_value_exist(&store).is_some()
+                    || self.inner.does_key_exist(&store) =>
+            {
+                store
+            }
+            _ => path.to_path_buf(),
+        }
+    }
+
+    // the key's merged listing, along with what came from the VirtualStore
+    pub fn merge(
+        &self,
+        path: &Path,
+        ctx: &OpContext,
+    ) -> Result<Option<(RegEntires, Vec<MergedEntry>)>, Cancelled> {
+        let machine = self.inner.enumerate_key_ctx(path.into(), ctx)?;
+        let store = match redirect(path) {
+            Some(store) => self.inner.enumerate_key_ctx(store.into(), ctx)?,
+            None => None,
+        };
+        Ok(merge_entries(machine, store))
+    }
+
+    // the VirtualStore copy first, as the OS reads it
+    fn first<T>(
+        &self,
+        path: &Path,
+        read: impl Fn(&Path) -> Result<Option<T>, Cancelled>,
+    ) -> Result<Option<T>, Cancelled> {
+        if let Some(store) = redirect(path) {
+            if let Some(found) = read(&store)? {
+                return Ok(Some(found));
+            }
+        }
+        read(path)
+    }
+}
+
+impl RegistryBackend for VirtualStore {
+    fn enumerate_key_ctx(
+        &self,
+        path: OsString,
+        ctx: &OpContext,
+    ) -> Result<Option<RegEntires>, Cancelled> {
+        Ok(self.merge(path.as_ref(), ctx)?.map(|(entries, _)| entries))
+    }
+
+    fn read_typed_value_ctx(
+        &self,
+        path: &Path,
+        ctx: &OpContext,
+    ) -> Result<Option<(u32, Vec<u8>)>, Cancelled> {
+        self.first(path, |path| self.inner.read_typed_value_ctx(path, ctx))
+    }
+
+    fn read_value_ctx(&self, path: &Path, ctx: &OpContext) -> Result<Option<Vec<u8>>, Cancelled> {
+        self.first(path, |path| self.inner.read_value_ctx(path, ctx))
+    }
+
+    fn read_all_values(&self, path: &Path) -> Option<Vec<(OsString, u32, Vec<u8>)>> {
+        let machine = self.inner.read_all_values(path);
+        match redirect(path) {
+            Some(store) => merge_values(machine, self.inner.read_all_values(&store)),
+            None => machine,
+        }
+    }
+
+    fn does_key_exist(&self, path: &Path) -> bool {
+        self.inner.does_key_exist(path)
+            || redirect(path).map_or(false, |store| self.inner.does_key_exist(&store))
+    }
+
+    fn key_last_write_time(&self, path: &Path) -> Option<i64> {
+        let machine = self.inner.key_last_write_time(path);
+        let store = redirect(path).and_then(|store| self.inner.key_last_write_time(&store));
+        match (machine, store) {
+            (Some(machine), Some(store)) => Some(machine.max(store)),
+            (machine, store) => machine.or(store),
+        }
+    }
+
+    fn key_info(&self, path: &Path) -> Option<KeyInfo> {
+        let store = match redirect(path) {
+            Some(store) => store,
+            None => return self.inner.key_info(path),
+        };
+        if !self.inner.does_key_exist(&store) {
+            return self.inner.key_info(path);
+        }
+
+        let entries = self.enumerate_key(path.into())?;
+        Some(KeyInfo {
+            subkeys: entries.subkeys.len() as u32,
+            values: entries.values.len() as u32,
+            last_write_time: self.key_last_write_time(path)?,
+            ..self.inner.key_info(path).unwrap_or_default()
+        })
+    }
+
+    fn watchers(&self) -> Option<&Watchers> {
+        self.inner.watchers()
+    }
+
+    fn link_target(&self, path: &Path) -> Option<PathBuf> {
+        self.inner.link_target(path)
+    }
+
+    fn writable(&self) -> bool {
+        self.inner.writable()
+    }
+
+    fn value_type(&self, path: &Path) -> Option<u32> {
+        match redirect(path).and_then(|store| self.inner.value_type(&store)) {
+            Some(vtype) => Some(vtype),
+            None => self.inner.value_type(path),
+        }
+    }
+
+    fn does_value_exist(&self, path: &Path) -> Option<usize> {
+        match redirect(path).and_then(|store| self.inner.does_value_exist(&store)) {
+            Some(size) => Some(size),
+            None => self.inner.does_value_exist(path),
+        }
+    }
+
+    fn classify(&self, path: &Path, ctx: &OpContext) -> Result<PathKind, Cancelled> {
+        match redirect(path) {
+            Some(_) => classify_by_lookup(self, path, ctx),
+            None => self.inner.classify(path, ctx),
+        }
+    }
+}
+
+#[test]
+fn test_redirect() {
+    assert_eq!(
+        redirect("HKEY_LOCAL_MACHINE\\software\\App\\Settings".as_ref()),
+        Some(PathBuf::from(
+            "HKEY_CURRENT_USER\\Software\\Classes\\VirtualStore\\MACHINE\\SOFTWARE\\App\\Settings"
+        ))
+    );
+    assert_eq!(
+        redirect("HKEY_LOCAL_MACHINE\\SOFTWARE".as_ref()),
+        Some(PathBuf::from(
+            "HKEY_CURRENT_USER\\Software\\Classes\\VirtualStore\\MACHINE\\SOFTWARE"
+        ))
+    );
+    assert_eq!(redirect("HKEY_LOCAL_MACHINE\\SYSTEM\\App".as_ref()), None);
+    assert_eq!(redirect("HKEY_LOCAL_MACHINE".as_ref()), None);
+    assert_eq!(redirect("HKEY_CURRENT_USER\\SOFTWARE\\App".as_ref()), None);
+
+    assert_eq!(
+        machine_path(
+            "HKEY_CURRENT_USER\\Software\\Classes\\VirtualStore\\MACHINE\\SOFTWARE\\App".as_ref()
+        ),
+        Some(PathBuf::from("HKEY_LOCAL_MACHINE\\SOFTWARE\\App"))
+    );
+    assert_eq!(
+        machine_path("HKEY_CURRENT_USER\\Software\\Classes".as_ref()),
+        None
+    );
+}
+
+#[test]
+fn test_merged_read() {
+    use crate::memory::MemoryBackend;
+
+    let memory = Arc::new(MemoryBackend::new());
+    let machine = Path::new("HKEY_LOCAL_MACHINE\\SOFTWARE\\Scratch");
+    let store = redirect(machine).unwrap();
+    memory.set_value(machine, "Setting", 3, b"machine".to_vec());
+    memory.set_value(machine, "Untouched", 3, b"machine".to_vec());
+    memory.add_key(machine.join("Shared"));
+    memory.set_value(&store, "setting", 3, b"store".to_vec());
+    memory.set_value(&store, "Extra", 3, b"store".to_vec());
+    memory.add_key(store.join("Shared"));
+    memory.set_value(store.join("Only"), "Deep", 3, b"deep".to_vec());
+    let merged = VirtualStore::new(memory.clone());
+
+    let (entries, annotations) = merged.merge(machine, &OpContext::none()).unwrap().unwrap();
+    let mut values: Vec<_> = entries
+        .values
+        .iter()
+        .map(|value| value.name.clone())
+        .collect();
+    values.sort();
+    assert_eq!(values, ["Extra", "Untouched", "setting"]);
+    let mut subkeys: Vec<_> = entries.subkeys.iter().map(|key| key.name.clone()).collect();
+    subkeys.sort();
+    assert_eq!(subkeys, ["Only", "Shared"]);
+    let merge_of = |name: &str| {
+        annotations
+            .iter()
+            .find(|entry| entry.name == name)
+            .map(|entry| entry.merge)
+    };
+    assert_eq!(merge_of("setting"), Some(Merge::Overrides));
+    assert_eq!(merge_of("Extra"), Some(Merge::Added));
+    assert_eq!(merge_of("Shared"), Some(Merge::Combined));
+    assert_eq!(merge_of("Only"), Some(Merge::Added));
+    assert_eq!(merge_of("Untouched"), None);
+
+    // the VirtualStore wins
+    assert_eq!(
+        merged.read_value(&machine.join("Setting")),
+        Some(b"store".to_vec())
+    );
+    assert_eq!(
+        merged.read_value(&machine.join("Untouched")),
+        Some(b"machine".to_vec())
+    );
+    assert_eq!(
+        merged.read_value(&machine.join("Only\\Deep")),
+        Some(b"deep".to_vec())
+    );
+    assert!(merged.does_key_exist(&machine.join("Only")));
+    assert!(matches!(
+        merged.classify(&machine.join("Only"), &OpContext::none()),
+        Ok(PathKind::Key(_))
+    ));
+    let values = merged.read_all_values(machine).unwrap();
+    assert_eq!(values.len(), 3);
+    assert!(values.contains(&("setting".into(), 3, b"store".to_vec())));
+
+    // the rest of the registry is as it was
+    memory.set_value(
+        "HKEY_LOCAL_MACHINE\\SYSTEM",
+        "Setting",
+        3,
+        b"system".to_vec(),
+    );
+    assert_eq!(
+        merged.read_value("HKEY_LOCAL_MACHINE\\SYSTEM\\Setting".as_ref()),
+        Some(b"system".to_vec())
+    );
+    let (_, annotations) = merged
+        .merge("HKEY_LOCAL_MACHINE\\SYSTEM".as_ref(), &OpContext::none())
+        .unwrap()
+        .unwrap();
+    assert!(annotations.is_empty());
+}
@@ -0,0 +1,134 @@
+use std::{
+    ffi::OsString,
+    fmt,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use crate::regop::paths;
+
+pub type Callback = Arc<dyn Fn(&Path) + Send + Sync>;
+
+struct Subscriber {
+    id: u64,
+    subtree: Vec<OsString>,
+    callback: Callback,
+}
+
+// change notifications of one backend, fanned out to every provider that mounted it
+#[derive(Default)]
+pub struct Watchers {
+    next_id: AtomicU64,
+    subscribers: RwLock<Vec<Subscriber>>,
+}
+
+impl fmt::Debug for Watchers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let subscribers = self.subscribers.read().unwrap_or_else(|e| e.into_inner());
+        write!(f, "Watchers({} subscribers)", subscribers.len())
+    }
+}
+
+fn folded(path: &Path) -> Vec<OsString> {
+    paths::components(path)
+        .into_iter()
+        .map(|part| part.to_string_lossy().to_lowercase().into())
+        .collect()
+}
+
+impl Watchers {
+    pub fn subscribe(&self, subtree: &Path, callback: Callback) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.subscribers
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(Subscriber {
+                id,
+                subtree: folded(subtree),
+                callback,
+            });
+        id
+    }
+
+    pub fn unsubscribe(&self, id: u64) {
+        self.subscribers
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|subscriber| subscriber.id != id);
+    }
+
+    pub fn subtree(&self, id: u64) -> Option<String> {
+        self.subscribers
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .find(|subscriber| subscriber.id == id)
+            .map(|subscriber| {
+                let parts: Vec<_> = subscriber
+                    .subtree
+                    .iter()
+                    .map(|part| part.to_string_lossy())
+                    .collect();
+                parts.join("\\")
+            })
+    }
+
+    // returns how many subscribers were told about the change
+    pub fn notify(&self, path: &Path) -> usize {
+        let changed = folded(path);
+        // called without the lock held, a callback may well unsubscribe
+        let callbacks: Vec<Callback> = self
+            .subscribers
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .filter(|subscriber| changed.starts_with(&subscriber.subtree))
+            .map(|subscriber| subscriber.callback.clone())
+            .collect();
+
+        for callback in &callbacks {
+            callback(path);
+        }
+        callbacks.len()
+    }
+}
+
+#[test]
+fn test_overlapping_subtrees() {
+    use std::sync::atomic::AtomicUsize;
+
+    let watchers = Watchers::default();
+    let hive = Arc::new(AtomicUsize::new(0));
+    let software = Arc::new(AtomicUsize::new(0));
+
+    let counter = hive.clone();
+    watchers.subscribe(
+        "HKEY_LOCAL_MACHINE".as_ref(),
+        Arc::new(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }),
+    );
+    let counter = software.clone();
+    let id = watchers.subscribe(
+        "HKEY_LOCAL_MACHINE\\SOFTWARE".as_ref(),
+        Arc::new(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }),
+    );
+
+    assert_eq!(
+        watchers.notify("hkey_local_machine\\software\\x".as_ref()),
+        2
+    );
+    assert_eq!(watchers.notify("HKEY_LOCAL_MACHINE\\SYSTEM".as_ref()), 1);
+    assert_eq!(watchers.notify("HKEY_USERS".as_ref()), 0);
+
+    watchers.unsubscribe(id);
+    assert_eq!(watchers.notify("HKEY_LOCAL_MACHINE\\SOFTWARE".as_ref()), 1);
+
+    assert_eq!(hive.load(Ordering::SeqCst), 3);
+    assert_eq!(software.load(Ordering::SeqCst), 1);
+}
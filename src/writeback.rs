@@ -0,0 +1,683 @@
+use log::{info, warn};
+use std::{ffi::OsStr, path::Path};
+use winapi::{
+    shared::winerror::{
+        self, ERROR_ACCESS_DENIED, ERROR_INVALID_DATA, ERROR_PATH_NOT_FOUND, HRESULT_FROM_WIN32,
+        S_OK,
+    },
+    um::winnt::{HRESULT, REG_BINARY},
+};
+
+use crate::events::RegFsEvent;
+use crate::hash;
+use crate::opcontext::OpContext;
+use crate::overlay::Change;
+use crate::policy::{MutationKind, MutationRequest};
+use crate::redact::Redacted;
+use crate::regfs::RegFs;
+use crate::regop::{paths, RegOpsError};
+use crate::render;
+use crate::shadow::StagingRegistry;
+
+// what ProjFS would have been answered had it asked; a failed write only goes to the log
+fn hresult_of(error: &anyhow::Error) -> HRESULT {
+    match error.downcast_ref::<RegOpsError>() {
+        Some(RegOpsError::KeyNotFound(_)) => HRESULT_FROM_WIN32(ERROR_PATH_NOT_FOUND),
+        Some(
+            RegOpsError::Open { error, .. }
+            | RegOpsError::Create { error, .. }
+            | RegOpsError::Write { error, .. }
+            | RegOpsError::Rename { error, .. },
+        ) => error
+            .raw_os_error()
+            .map_or(winerror::E_FAIL, |code| HRESULT_FROM_WIN32(code as u32)),
+        _ => winerror::E_FAIL,
+    }
+}
+
+// a value under another name or in another key, over whatever value was there
+fn rename_value(writer: &dyn StagingRegistry, from: &Path, to: &Path) -> anyhow::Result<()> {
+    let (vtype, data) = writer
+        .read_typed_value_ctx(from, &OpContext::none())
+        .ok()
+        .flatten()
+        .ok_or_else(|| anyhow::anyhow!("no value at [{:?}]", from))?;
+    writer.write_value(to, &data, vtype)?;
+    writer.delete(from)
+}
+
+impl RegFs {
+    // a value's file was closed with something written to it: the overlay takes it when
+    // there's one, or else it's written back to the registry
+    pub fn file_saved(
+        &self,
+        path: &Path,
+        overwritten: bool,
+        process: &OsStr,
+        process_id: u32,
+    ) -> HRESULT {
+        match (self.overlay(), overwritten) {
+            (Some(_), true) => self.record_change(Change::Overwritten(path)),
+            (Some(_), false) => self.record_change(Change::Modified(path)),
+            (None, _) => return self.write_back(path, overwritten, process, process_id),
+        }
+        S_OK
+    }
+
+    // whether a save of this file would go to the registry, so the policies are asked about
+    // that before it's written to at all
+    pub fn writes_back(&self, path: &Path) -> bool {
+        self.writer().is_some() && !self.readonly() && self.synthetic(path).is_none()
+    }
+
+    // a directory made under a key is a new key there; a file only becomes a value once
+    // something is written to it
+    pub fn file_created(
+        &self,
+        path: &Path,
+        is_directory: bool,
+        process: &OsStr,
+        process_id: u32,
+    ) -> HRESULT {
+        match (self.overlay(), is_directory) {
+            (Some(_), _) => self.record_change(Change::Created { path, is_directory }),
+            (None, true) => return self.write_back_key(path, process, process_id),
+            (None, false) => {}
+        }
+        S_OK
+    }
+
+    // the parent key has to be there, and a hive can't be made
+    pub fn write_back_key(&self, path: &Path, process: &OsStr, process_id: u32) -> HRESULT {
+        let writer = match self.writer() {
+            Some(writer) if self.writes_back(path) => writer,
+            _ => return S_OK,
+        };
+        if process_id == std::process::id() {
+            warn!(
+                target: "writeback",
+                "[{:?}] was created by the provider itself, not written back",
+                path
+            );
+            return S_OK;
+        }
+        if paths::components(path).len() < 2 {
+            warn!(target: "writeback", "[{:?}] would be a new hive, refused", path);
+            return HRESULT_FROM_WIN32(ERROR_ACCESS_DENIED);
+        }
+        let denied = self.pre_mutation(&MutationRequest {
+            kind: MutationKind::WriteBack,
+            path,
+            destination: None,
+            process,
+            is_directory: true,
+        });
+        if denied != S_OK {
+            return denied;
+        }
+
+        let key = self.write_path(path);
+        if let Err(e) = writer.create_key(&key) {
+            warn!(target: "writeback", "[{:?}] not created: {}", path, e);
+            return hresult_of(&e);
+        }
+
+        info!(target: "writeback", "[{:?}] created as [{:?}]", path, key);
+        // the next listing of the parent has it
+        self.forget_written(path.parent());
+        self.emit(RegFsEvent::WriteBackApplied {
+            path: path.to_path_buf(),
+        });
+        S_OK
+    }
+
+    // asked before the rename, so one the registry can't take (see RegOps::rename_key) is
+    // refused instead of leaving the files and the registry apart; moved out of the mount,
+    // it stays in the registry
+    pub fn rename_back(
+        &self,
+        from: &Path,
+        to: &Path,
+        is_directory: bool,
+        process_id: u32,
+    ) -> HRESULT {
+        if to.as_os_str().is_empty() {
+            return S_OK;
+        }
+        let writer = match self.writer() {
+            Some(writer) if self.writes_back(from) && self.writes_back(to) => writer,
+            _ => return S_OK,
+        };
+        if process_id == std::process::id() {
+            warn!(
+                target: "writeback",
+                "[{:?}] was renamed by the provider itself, not written back",
+                from
+            );
+            return S_OK;
+        }
+
+        let (source, destination) = (self.write_path(from), self.write_path(to));
+        let renamed = match is_directory {
+            true => writer.rename_key(&source, &destination),
+            false => rename_value(writer, &source, &destination),
+        };
+        if let Err(e) = renamed {
+            warn!(target: "writeback", "[{:?}] not renamed to [{:?}]: {}", from, to, e);
+            return hresult_of(&e);
+        }
+
+        info!(target: "writeback", "[{:?}] renamed to [{:?}]", source, destination);
+        self.forget_written(Some(from));
+        self.forget_written(Some(to));
+        self.emit(RegFsEvent::WriteBackApplied {
+            path: to.to_path_buf(),
+        });
+        S_OK
+    }
+
+    // the file replaces the value, which keeps its type; read-only, denied or in conflict
+    // with the registry (--conflict fail), the change stays on disk only
+    pub fn write_back(
+        &self,
+        path: &Path,
+        overwritten: bool,
+        process: &OsStr,
+        process_id: u32,
+    ) -> HRESULT {
+        let writer = match self.writer() {
+            Some(writer) if !self.readonly() => writer,
+            _ => return S_OK,
+        };
+        // the provider's own writes under the root (hydrate, materialize) came from the
+        // registry in the first place
+        if process_id == std::process::id() {
+            warn!(
+                target: "writeback",
+                "[{:?}] was written by the provider itself, not written back",
+                path
+            );
+            return S_OK;
+        }
+        let denied = self.pre_mutation(&MutationRequest {
+            kind: MutationKind::WriteBack,
+            path,
+            destination: None,
+            process,
+            is_directory: false,
+        });
+        if denied != S_OK {
+            return denied;
+        }
+
+        let vtype = writer
+            .value_type(&self.registry_path(path))
+            .unwrap_or(REG_BINARY);
+        let (vtype, data) = match self.written_value(path, vtype) {
+            Some(value) => value,
+            None => return HRESULT_FROM_WIN32(ERROR_INVALID_DATA),
+        };
+        // as with the overlay, a file opened to be overwritten isn't checked for conflicts
+        if !overwritten && !self.resolve_conflict(writer, path) {
+            return HRESULT_FROM_WIN32(ERROR_ACCESS_DENIED);
+        }
+        let value = self.write_path(path);
+        if let Err(e) = writer.write_value(&value, &data, vtype) {
+            warn!(target: "writeback", "[{:?}] not written back: {}", path, e);
+            return hresult_of(&e);
+        }
+
+        info!(
+            target: "writeback",
+            "[{:?}] written back to [{:?}] as a {}: {}",
+            path,
+            value,
+            render::type_name(vtype),
+            Redacted(&data)
+        );
+        // what the file was saved against from now on
+        if let Ok(Some(projected)) = self.read_projected_value(path, &OpContext::none()) {
+            self.record_content_id(path, hash::fnv1a(&projected));
+        }
+        self.forget_written(Some(path));
+        self.emit(RegFsEvent::WriteBackApplied {
+            path: path.to_path_buf(),
+        });
+        S_OK
+    }
+}
+
+#[cfg(test)]
+fn saved(root: &Path, path: &Path, contents: &[u8]) {
+    std::fs::create_dir_all(root.join(path.parent().unwrap())).unwrap();
+    std::fs::write(root.join(path), contents).unwrap();
+}
+
+#[test]
+fn test_write_back() {
+    use crate::backend::RegistryBackend;
+    use crate::memory::MemoryBackend;
+    use crate::options::RegFsOptions;
+    use std::sync::Arc;
+    use winapi::um::winnt::{REG_DWORD, REG_SZ};
+
+    let root = std::env::temp_dir().join(format!("regfs-writeback-{}", std::process::id()));
+    let app = Path::new("HKEY_CURRENT_USER\\Software\\App");
+    let lower = Arc::new(MemoryBackend::new());
+    lower.set_value(app, "Number", REG_DWORD, vec![1, 0, 0, 0]);
+    lower.set_value(app, "Name", REG_SZ, vec![b'a', 0]);
+    let options = RegFsOptions {
+        root: root.clone(),
+        readonly: false,
+        ..Default::default()
+    };
+    let regfs = RegFs::with_writer(&options, lower.clone(), Some(lower.clone()));
+
+    // the type is the value's, and a new one is binary
+    saved(&root, &app.join("Number"), &[2, 0, 0, 0]);
+    assert_eq!(
+        regfs.file_saved(&app.join("Number"), false, "".as_ref(), 0),
+        S_OK
+    );
+    saved(&root, &app.join("Fresh"), b"new");
+    assert_eq!(
+        regfs.file_saved(&app.join("Fresh"), true, "".as_ref(), 0),
+        S_OK
+    );
+    assert_eq!(lower.value_type(&app.join("Number")), Some(REG_DWORD));
+    assert_eq!(
+        lower.read_value(&app.join("Number")),
+        Some(vec![2, 0, 0, 0])
+    );
+    assert_eq!(lower.value_type(&app.join("Fresh")), Some(REG_BINARY));
+    assert_eq!(lower.read_value(&app.join("Fresh")), Some(b"new".to_vec()));
+
+    // a value that can't hold what was written is left alone
+    saved(&root, &app.join("Number"), &[3]);
+    assert_eq!(
+        regfs.file_saved(&app.join("Number"), false, "".as_ref(), 0),
+        HRESULT_FROM_WIN32(ERROR_INVALID_DATA)
+    );
+    assert_eq!(
+        lower.read_value(&app.join("Number")),
+        Some(vec![2, 0, 0, 0])
+    );
+
+    // nor is a key that isn't there created for it
+    let orphan = app.join("Missing\\Value");
+    saved(&root, &orphan, b"orphan");
+    assert_eq!(
+        regfs.file_saved(&orphan, false, "".as_ref(), 0),
+        HRESULT_FROM_WIN32(ERROR_PATH_NOT_FOUND)
+    );
+    assert!(!lower.does_key_exist(&app.join("Missing")));
+
+    // the provider's own writes, a read-only mount and one without a writer don't go through
+    saved(&root, &app.join("Name"), &[b'b', 0]);
+    assert_eq!(
+        regfs.file_saved(&app.join("Name"), false, "".as_ref(), std::process::id()),
+        S_OK
+    );
+    let readonly = RegFsOptions {
+        readonly: true,
+        ..options.clone()
+    };
+    RegFs::with_writer(&readonly, lower.clone(), Some(lower.clone())).file_saved(
+        &app.join("Name"),
+        false,
+        "".as_ref(),
+        0,
+    );
+    RegFs::with_backend(&options, lower.clone()).file_saved(
+        &app.join("Name"),
+        false,
+        "".as_ref(),
+        0,
+    );
+    assert_eq!(lower.read_value(&app.join("Name")), Some(vec![b'a', 0]));
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_write_back_keys() {
+    use crate::backend::RegistryBackend;
+    use crate::fixture::callback_data;
+    use crate::memory::MemoryBackend;
+    use crate::options::RegFsOptions;
+    use crate::prj_compat::MockPrjApi;
+    use prjfs::{conv::WStrExt, ProviderT};
+    use std::{ffi::OsString, sync::Arc};
+    use winapi::shared::guiddef::GUID;
+
+    let app = Path::new("HKEY_CURRENT_USER\\Software\\App");
+    let lower = Arc::new(MemoryBackend::new());
+    lower.set_value(app, "Name", 1, b"a\0".to_vec());
+    let mock = Arc::new(MockPrjApi::default());
+    let options = RegFsOptions {
+        readonly: false,
+        enum_cache: true,
+        prj_api: Some(mock.clone()),
+        ..Default::default()
+    };
+    let regfs = RegFs::with_writer(&options, lower.clone(), Some(lower.clone()));
+    let list = || {
+        let star = OsString::from("*").to_wstr();
+        let data = callback_data(app, 0);
+        mock.calls.lock().unwrap().clear();
+        regfs.start_dir_enum(&data, &GUID::default()).unwrap();
+        regfs
+            .get_dir_enum(&data, &GUID::default(), star.as_ptr(), std::ptr::null_mut())
+            .unwrap();
+        regfs.end_dir_enum(&data, &GUID::default()).unwrap();
+        mock.calls()
+    };
+    assert_eq!(list(), ["fill Name"]);
+    assert_eq!(regfs.dump()["caches"]["listings"], 1);
+
+    // and the next listing has it
+    assert_eq!(
+        regfs.file_created(&app.join("New"), true, "".as_ref(), 0),
+        S_OK
+    );
+    assert!(lower.does_key_exist(&app.join("New")));
+    assert_eq!(list(), ["fill Name", "fill New"]);
+
+    // no new hives, nor keys without a parent
+    assert_eq!(
+        regfs.file_created(Path::new("HKEY_NEW"), true, "".as_ref(), 0),
+        HRESULT_FROM_WIN32(ERROR_ACCESS_DENIED)
+    );
+    assert!(!lower.does_key_exist(Path::new("HKEY_NEW")));
+    assert_eq!(
+        regfs.file_created(&app.join("Missing\\Deeper"), true, "".as_ref(), 0),
+        HRESULT_FROM_WIN32(ERROR_PATH_NOT_FOUND)
+    );
+    assert!(!lower.does_key_exist(&app.join("Missing")));
+
+    // a file waits for what's written to it, and a read-only mount creates nothing
+    assert_eq!(
+        regfs.file_created(&app.join("File"), false, "".as_ref(), 0),
+        S_OK
+    );
+    assert_eq!(lower.read_value(&app.join("File")), None);
+    let readonly = RegFsOptions {
+        readonly: true,
+        ..options.clone()
+    };
+    RegFs::with_writer(&readonly, lower.clone(), Some(lower.clone())).file_created(
+        &app.join("Other"),
+        true,
+        "".as_ref(),
+        0,
+    );
+    regfs.file_created(&app.join("Other"), true, "".as_ref(), std::process::id());
+    assert!(!lower.does_key_exist(&app.join("Other")));
+}
+
+#[test]
+fn test_rename_back() {
+    use crate::backend::RegistryBackend;
+    use crate::memory::MemoryBackend;
+    use crate::options::RegFsOptions;
+    use std::sync::Arc;
+    use winapi::um::winnt::{REG_DWORD, REG_SZ};
+
+    let software = Path::new("HKEY_CURRENT_USER\\Software");
+    let app = software.join("App");
+    let lower = Arc::new(MemoryBackend::new());
+    lower.set_value(&app, "Name", REG_SZ, vec![b'a', 0]);
+    lower.set_value(
+        app.join("Nested\\Deeper"),
+        "Count",
+        REG_DWORD,
+        vec![1, 0, 0, 0],
+    );
+    lower.add_key(software.join("Taken"));
+    let options = RegFsOptions {
+        readonly: false,
+        ..Default::default()
+    };
+    let regfs = RegFs::with_writer(&options, lower.clone(), Some(lower.clone()));
+
+    // a key with everything under it, and a value, types and all
+    assert_eq!(
+        regfs.rename_back(&app, &software.join("Moved"), true, 0),
+        S_OK
+    );
+    let moved = software.join("Moved");
+    assert!(!lower.does_key_exist(&app));
+    assert_eq!(lower.value_type(&moved.join("Name")), Some(REG_SZ));
+    assert_eq!(
+        lower.read_value(&moved.join("Nested\\Deeper\\Count")),
+        Some(vec![1, 0, 0, 0])
+    );
+    assert_eq!(
+        regfs.rename_back(&moved.join("Name"), &moved.join("Nested\\Title"), false, 0),
+        S_OK
+    );
+    assert_eq!(lower.read_value(&moved.join("Name")), None);
+    assert_eq!(
+        lower.read_value(&moved.join("Nested\\Title")),
+        Some(vec![b'a', 0])
+    );
+
+    // refused when the registry can't take it, and nothing moves
+    assert_eq!(
+        regfs.rename_back(&moved, &software.join("Missing\\Moved"), true, 0),
+        HRESULT_FROM_WIN32(ERROR_PATH_NOT_FOUND)
+    );
+    assert_ne!(
+        regfs.rename_back(&moved, &software.join("Taken"), true, 0),
+        S_OK
+    );
+    assert!(lower.does_key_exist(&moved.join("Nested\\Deeper")));
+
+    // out of the mount, by the provider itself, or read-only: the registry keeps it
+    assert_eq!(regfs.rename_back(&moved, Path::new(""), true, 0), S_OK);
+    regfs.rename_back(&moved, &software.join("Mine"), true, std::process::id());
+    let readonly = RegFsOptions {
+        readonly: true,
+        ..options.clone()
+    };
+    RegFs::with_writer(&readonly, lower.clone(), Some(lower.clone())).rename_back(
+        &moved,
+        &software.join("Other"),
+        true,
+        0,
+    );
+    assert!(lower.does_key_exist(&moved));
+    assert!(!lower.does_key_exist(&software.join("Mine")));
+    assert!(!lower.does_key_exist(&software.join("Other")));
+}
+
+#[test]
+fn test_write_back_policies() {
+    use crate::backend::RegistryBackend;
+    use crate::conflict::ConflictPolicies;
+    use crate::fixture::callback_data;
+    use crate::memory::MemoryBackend;
+    use crate::options::RegFsOptions;
+    use crate::policy::{Decision, MutationPolicy};
+    use crate::regfile;
+    use prjfs::{conv::WStrExt, ProviderT};
+    use std::{ffi::OsString, sync::Arc};
+    use winapi::um::winnt::REG_SZ;
+
+    struct DenySecret;
+
+    impl MutationPolicy for DenySecret {
+        fn decide(&self, request: &MutationRequest) -> Decision {
+            let secret = Path::new("HKEY_CURRENT_USER\\Software\\Secret");
+            match request.kind == MutationKind::WriteBack && request.path.starts_with(secret) {
+                true => Decision::Deny("secret".into()),
+                false => Decision::Allow,
+            }
+        }
+    }
+
+    let root = std::env::temp_dir().join(format!("regfs-writeback-policy-{}", std::process::id()));
+    let app = Path::new("HKEY_CURRENT_USER\\Software\\App");
+    let secret = Path::new("HKEY_CURRENT_USER\\Software\\Secret");
+    let lower = Arc::new(MemoryBackend::new());
+    lower.set_value(app, "Name", REG_SZ, regfile::string_data("read"));
+    lower.set_value(secret, "Name", REG_SZ, regfile::string_data("read"));
+    let mut conflict = ConflictPolicies::default();
+    conflict.add("fail").unwrap();
+    let options = RegFsOptions {
+        root: root.clone(),
+        readonly: false,
+        allowed_processes: vec!["regedit.exe".into()],
+        policy: Some(Arc::new(DenySecret)),
+        conflict,
+        ..Default::default()
+    };
+    let regfs = RegFs::with_writer(&options, lower.clone(), Some(lower.clone()));
+    let regedit = OsStr::new("C:\\Windows\\regedit.exe");
+    let cmd = OsStr::new("C:\\Windows\\System32\\cmd.exe");
+    let denied = HRESULT_FROM_WIN32(ERROR_ACCESS_DENIED);
+
+    // the built-in policies, then the embedder's, for values and keys alike
+    saved(&root, &app.join("Name"), &regfile::string_data("saved"));
+    assert_eq!(regfs.file_saved(&app.join("Name"), false, cmd, 0), denied);
+    saved(&root, &secret.join("Name"), &regfile::string_data("saved"));
+    assert_eq!(
+        regfs.file_saved(&secret.join("Name"), false, regedit, 0),
+        denied
+    );
+    assert_eq!(
+        regfs.file_created(&secret.join("New"), true, regedit, 0),
+        denied
+    );
+    assert_eq!(
+        lower.read_value(&secret.join("Name")),
+        Some(regfile::string_data("read"))
+    );
+    assert_eq!(regfs.file_created(&app.join("New"), true, cmd, 0), denied);
+    assert!(!lower.does_key_exist(&secret.join("New")));
+    assert!(!lower.does_key_exist(&app.join("New")));
+    assert_eq!(regfs.metrics_snapshot().denied_operations, 4);
+    assert_eq!(regfs.file_saved(&app.join("Name"), false, regedit, 0), S_OK);
+    assert_eq!(
+        lower.read_value(&app.join("Name")),
+        Some(regfile::string_data("saved"))
+    );
+
+    // the value changed since it was saved against it, and --conflict fail keeps it
+    lower.set_value(app, "Name", REG_SZ, regfile::string_data("concurrent"));
+    saved(&root, &app.join("Name"), &regfile::string_data("again"));
+    assert_eq!(
+        regfs.file_saved(&app.join("Name"), false, regedit, 0),
+        denied
+    );
+    assert_eq!(
+        lower.read_value(&app.join("Name")),
+        Some(regfile::string_data("concurrent"))
+    );
+    // unless the file was opened to be overwritten
+    assert_eq!(regfs.file_saved(&app.join("Name"), true, regedit, 0), S_OK);
+    assert_eq!(
+        lower.read_value(&app.join("Name")),
+        Some(regfile::string_data("again"))
+    );
+
+    // and a placeholder that would be written back is refused before it's written to
+    let convert = |regfs: &RegFs, path: &Path| {
+        let process = OsString::from(regedit).to_wstr();
+        let destination = OsString::new().to_wstr();
+        let mut data = callback_data(path, 0);
+        data.TriggeringProcessImageFileName = process.as_ptr();
+        let parameters = unsafe { std::mem::zeroed() };
+        regfs
+            .notify(
+                &data,
+                false,
+                prjfs::sys::PRJ_NOTIFICATION_FILE_PRE_CONVERT_TO_FULL,
+                destination.as_ptr(),
+                &parameters,
+            )
+            .unwrap()
+    };
+    assert_eq!(convert(&regfs, &secret.join("Name")), denied);
+    assert_eq!(convert(&regfs, &app.join("Name")), S_OK);
+    // without a writer it's only converted, which the policy lets through
+    let plain = RegFs::with_backend(&options, lower.clone());
+    assert_eq!(convert(&plain, &secret.join("Name")), S_OK);
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_write_back_to_the_registry() {
+    use crate::backend::RegistryBackend;
+    use crate::options::RegFsOptions;
+    use crate::pipe::SecurityDescriptor;
+    use crate::regop::{scratch::ScratchKey, RegOps};
+    use std::sync::Arc;
+    use winapi::{
+        shared::minwindef::HKEY,
+        um::{
+            winnt::{DACL_SECURITY_INFORMATION, REG_DWORD, WRITE_DAC},
+            winreg::RegSetKeySecurity,
+        },
+    };
+    use winreg::{enums::HKEY_CURRENT_USER, RegKey};
+
+    let scratch = ScratchKey::empty();
+    scratch.key.set_value("Number", &1u32).unwrap();
+    let root = std::env::temp_dir().join(format!("regfs-writeback-live-{}", std::process::id()));
+    let ops = Arc::new(RegOps::new());
+    let options = RegFsOptions {
+        root: root.clone(),
+        readonly: false,
+        ..Default::default()
+    };
+    let regfs = RegFs::with_writer(&options, ops.clone(), Some(ops.clone()));
+
+    let number = scratch.path().join("Number");
+    saved(&root, &number, &7u32.to_le_bytes());
+    assert_eq!(regfs.file_saved(&number, false, "".as_ref(), 0), S_OK);
+    assert_eq!(scratch.key.get_value::<u32, _>("Number").unwrap(), 7);
+    assert_eq!(ops.value_type(&number), Some(REG_DWORD));
+
+    let orphan = scratch.path().join("Missing\\Value");
+    saved(&root, &orphan, b"orphan");
+    assert_eq!(
+        regfs.file_saved(&orphan, false, "".as_ref(), 0),
+        HRESULT_FROM_WIN32(ERROR_PATH_NOT_FOUND)
+    );
+    assert!(scratch.key.open_subkey("Missing").is_err());
+
+    let created = scratch.path().join("Created");
+    assert_eq!(regfs.file_created(&created, true, "".as_ref(), 0), S_OK);
+    assert!(scratch.key.open_subkey("Created").is_ok());
+
+    // to another parent, which RegRenameKey can't do, so it's copied over
+    scratch.key.create_subkey("Parent").unwrap();
+    assert_eq!(
+        regfs.rename_back(&created, &scratch.path().join("Parent\\Created"), true, 0),
+        S_OK
+    );
+    assert!(scratch.key.open_subkey("Created").is_err());
+    assert!(scratch.key.open_subkey("Parent\\Created").is_ok());
+
+    // read-only to everyone; dropping the scratch key gives the rights back
+    let key = RegKey::predef(HKEY_CURRENT_USER)
+        .open_subkey_with_flags(scratch.name(), WRITE_DAC)
+        .unwrap();
+    let security = SecurityDescriptor::from_sddl("D:P(A;;KR;;;WD)").unwrap();
+    let result = unsafe {
+        RegSetKeySecurity(
+            key.raw_handle() as usize as HKEY,
+            DACL_SECURITY_INFORMATION,
+            security.0,
+        )
+    };
+    assert_eq!(result, 0);
+    assert_eq!(
+        regfs.file_created(&scratch.path().join("Denied"), true, "".as_ref(), 0),
+        HRESULT_FROM_WIN32(ERROR_ACCESS_DENIED)
+    );
+
+    std::fs::remove_dir_all(&root).unwrap();
+}